@@ -0,0 +1,191 @@
+use actix::prelude::*;
+use actix_lua::testing::ScriptVm;
+use actix_lua::{LuaActorBuilder, LuaMessage};
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::future::{loop_fn, Loop};
+use futures::Future;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+fn flat_table(keys: usize) -> LuaMessage {
+    let table: HashMap<String, LuaMessage> = (0..keys)
+        .map(|i| (format!("key{}", i), LuaMessage::from(i as i64)))
+        .collect();
+    LuaMessage::from(table)
+}
+
+fn nested_table(depth: usize) -> LuaMessage {
+    (0..depth).fold(LuaMessage::from("leaf"), |inner, i| {
+        let mut table = HashMap::new();
+        table.insert("inner".to_string(), inner);
+        table.insert("depth".to_string(), LuaMessage::from(i as i64));
+        LuaMessage::from(table)
+    })
+}
+
+// keyed "1" to "len", converted to a Lua sequence
+fn array(len: usize) -> LuaMessage {
+    let table: HashMap<String, LuaMessage> = (1..=len)
+        .map(|i| (i.to_string(), LuaMessage::from(i as i64)))
+        .collect();
+    LuaMessage::from(table)
+}
+
+fn conversions(c: &mut Criterion) {
+    let vm = ScriptVm::new(&LuaActorBuilder::new()).unwrap();
+    let workloads = vec![
+        ("flat 1k keys", flat_table(1000)),
+        ("nested 100 deep", nested_table(100)),
+        ("array 10k", array(10_000)),
+        ("string 1MB", LuaMessage::from("x".repeat(1 << 20))),
+    ];
+    let mut group = c.benchmark_group("round trip");
+    for (name, msg) in &workloads {
+        group.bench_function(*name, |b| b.iter(|| vm.round_trip(msg).unwrap()));
+    }
+    group.finish();
+}
+
+fn handle(c: &mut Criterion) {
+    let builder = LuaActorBuilder::new().on_handle_with_lua(r#"return ctx.msg"#);
+    let mut vm = ScriptVm::new(&builder).unwrap();
+    let msg = flat_table(10);
+    c.bench_function("handle echo", |b| {
+        b.iter(|| vm.handle(msg.clone()).unwrap())
+    });
+}
+
+// a handler reading 3 keys of a 50k keys message
+fn selective_reads(c: &mut Criterion) {
+    let script = r#"return ctx.msg.key0 + ctx.msg.key25000 + ctx.msg.key49999"#;
+    let msg = flat_table(50_000);
+    let mut group = c.benchmark_group("read 3 of 50k keys");
+    for lazy in [false, true] {
+        let builder = LuaActorBuilder::new()
+            .on_handle_with_lua(script)
+            .with_lazy_tables(lazy);
+        let mut vm = ScriptVm::new(&builder).unwrap();
+        let name = if lazy { "lazy" } else { "eager" };
+        group.bench_function(name, |b| b.iter(|| vm.handle(msg.clone()).unwrap()));
+    }
+    group.finish();
+}
+
+// send `iters` messages one after the other to an echo actor
+fn actor_round_trips(iters: u64) -> Duration {
+    let system = System::new("bench");
+    let addr = LuaActorBuilder::new()
+        .on_handle_with_lua(r#"return ctx.msg"#)
+        .build()
+        .unwrap()
+        .start();
+    let msg = flat_table(10);
+    let start = Instant::now();
+    Arbiter::spawn(
+        loop_fn(0, move |i| {
+            addr.send(msg.clone()).map(move |_| {
+                if i + 1 < iters {
+                    Loop::Continue(i + 1)
+                } else {
+                    Loop::Break(())
+                }
+            })
+        })
+        .map(|_| System::current().stop())
+        .map_err(|e| panic!("actor dead {}", e)),
+    );
+    system.run();
+    start.elapsed()
+}
+
+fn actor(c: &mut Criterion) {
+    c.bench_function("actor echo", |b| b.iter_custom(actor_round_trips));
+}
+
+// run `iters` handlers suspending in `ctx.send` to an echo actor, with `pool_size` coroutines
+// kept for reuse
+fn send_round_trips(pool_size: usize, iters: u64) -> Duration {
+    let system = System::new("bench");
+    let echo = LuaActorBuilder::new()
+        .on_handle_with_lua(r#"return ctx.msg"#)
+        .build()
+        .unwrap()
+        .start();
+    let mut actor = LuaActorBuilder::new()
+        .on_handle_with_lua(r#"return ctx.send("echo", ctx.msg)"#)
+        .with_coroutine_pool_size(pool_size)
+        .build()
+        .unwrap();
+    actor.add_lua_recipient("echo", &echo).unwrap();
+    let addr = actor.start();
+    let start = Instant::now();
+    Arbiter::spawn(
+        loop_fn(0, move |i| {
+            addr.send(LuaMessage::from(i as i64)).map(move |_| {
+                if i + 1 < iters {
+                    Loop::Continue(i + 1)
+                } else {
+                    Loop::Break(())
+                }
+            })
+        })
+        .map(|_| System::current().stop())
+        .map_err(|e| panic!("actor dead {}", e)),
+    );
+    system.run();
+    start.elapsed()
+}
+
+fn coroutine_pool(c: &mut Criterion) {
+    let mut group = c.benchmark_group("send round trip");
+    for size in [0, 16] {
+        group.bench_function(format!("pool size {}", size), |b| {
+            b.iter_custom(|iters| send_round_trips(size, iters))
+        });
+    }
+    group.finish();
+}
+
+// a handler big enough for parsing to show, built from source and from bytecode
+fn build(c: &mut Criterion) {
+    let script: String = (0..200)
+        .map(|i| {
+            format!(
+                "if ctx.msg == {} then return {{ n = {}, s = \"{}\" }} end\n",
+                i,
+                i * 2,
+                i
+            )
+        })
+        .collect();
+    let bytecode = LuaActorBuilder::compile(&script).unwrap();
+    let mut group = c.benchmark_group("build");
+    group.bench_function("source", |b| {
+        b.iter(|| {
+            LuaActorBuilder::new()
+                .on_handle_with_lua(&script)
+                .build()
+                .unwrap()
+        })
+    });
+    group.bench_function("bytecode", |b| {
+        b.iter(|| {
+            LuaActorBuilder::new()
+                .on_handle_with_bytecode(&bytecode)
+                .build()
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    conversions,
+    handle,
+    selective_reads,
+    actor,
+    coroutine_pool,
+    build
+);
+criterion_main!(benches);
@@ -1,13 +1,51 @@
+use ::actix::dev::{MessageResponse, ResponseChannel};
 use ::actix::prelude::*;
 use ::actix::ActorContext;
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll};
 use rlua::Error as LuaError;
-use rlua::{FromLua, Function, Lua, MultiValue, ToLua, Value};
+use rlua::Lua;
 
-use crate::message::LuaMessage;
-use std::cell::RefCell;
+use crate::breaker::{BreakerConfig, CircuitBreaker};
+use crate::build_error::LuaActorBuildError;
+use crate::codec::{StateCodec, StateError, StateSubsystem};
+use crate::correlation::{Correlations, DEFAULT_MAX_CORRELATED};
+use crate::cron::CronExpr;
+use crate::dataset::Datasets;
+use crate::error_bursts::ErrorBursts;
+use crate::export::DEFAULT_MAX_EXPORT_SIZE;
+use crate::gc::GcWatermark;
+use crate::handoff::{Children, Held};
+use crate::idempotency::Idempotency;
+use crate::ids::IdSource;
+use crate::interval::{Interval, Intervals, TickPolicy};
+use crate::message::{ConversionLimits, LuaMessage};
+use crate::opaque::{OpaqueHandle, OpaqueRegistry};
+use crate::outbox::Outboxes;
+use crate::pause::{Buffered, Pause, PauseOverflow, DEFAULT_PAUSE_BUFFER};
+use crate::ping::Liveness;
+use crate::rate_limit::InboundLimit;
+use crate::receipts::{
+    receipt, DeliverReceipts, DeliveryStatus, Issued, Receipts, DEFAULT_MAX_RECEIPTS,
+};
+use crate::recipients::{Aliases, Recipients};
+use crate::runtime::{
+    api_manifest, new_vm, ActorApi, ApiConfig, ApiEntry, LuaRuntime, DEFAULT_COROUTINE_POOL_SIZE,
+};
+use crate::schedule::{unix_millis, Schedules};
+use crate::source::{ScriptSource, SourcedHooks};
+use crate::state_diff::{truncated, StateDiff};
+use crate::state_quota::StateQuota;
+use crate::warnings::Warnings;
+use crate::watch::Watches;
+use crate::watchdog::Watchdog;
+use crate::workflow::Workflows;
+use std::any::Any;
 use std::collections::HashMap;
 use std::str;
-use std::time::Duration;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 /// Top level struct which holds a lua state for itself.
 ///
@@ -19,32 +57,451 @@ use std::time::Duration;
 /// ### `ctx.msg`
 /// The message sent to Lua actor.
 ///
+/// ### `ctx.reply_expected`
+/// `true` if the sender is waiting for the return value of the `handle` script, i.e. the message
+/// was sent with `send`. `false` for `do_send`, `ctx.notify`, `ctx.notify_later` and in the
+/// `started`/`stopped` hooks.
+///
+/// When a script resumes after `ctx.send`, `ctx.reply_expected` is always `false`:
+/// the reply of the original message was already sent when the coroutine yielded.
+///
+/// The return value is not converted to `LuaMessage` when no reply is expected.
+///
+/// ### `on_workflow_complete(value)`
+/// A `handle` invocation without a reply expected, e.g. for `do_send`, which suspends in
+/// `ctx.send` is a workflow: nobody reads the value it finally returns. When it does, the
+/// global function `on_workflow_complete(value)` is called with that value if the scripts
+/// defined it, with `ctx.msg` still the message which started the workflow. The workflows which
+/// completed or failed are counted, see [`GetWorkflowStats`].
+///
+/// ### `ctx.null`
+/// Represents a `LuaMessage::Nil` value in a table. Lua tables can't store `nil`, so `Nil` values of
+/// a `LuaMessage::Table` are converted to `ctx.null` and `ctx.null` is converted back to `Nil`,
+/// keeping the key present. `ctx.null` is a light userdata, so it's truthy: test a field with
+/// `ctx.is_null(t.field)`, which is true for `nil` and `ctx.null`, rather than `if t.field`.
+///
+/// ### `ctx.ns(name)`
+/// A table of `ctx.state` reserved to `name`, so independently written scripts can use the same
+/// keys. Namespaces are stored in `ctx.state.__ns` and included in state snapshots.
+///
+/// ### `ctx.util.opairs(t)`
+/// `pairs(t)` in the same order in every run: booleans, numbers, then strings, each in ascending
+/// order. `LuaMessage::Table` is a hash map, so the fields of `ctx.msg` have no order of their
+/// own, and `pairs` visits them in an order which changes between runs. Use `opairs` to build
+/// e.g. reports or the canonical form of a message.
+///
+/// ### `ctx.register_class(name, class)`
+/// Metatables are stripped when tables are converted to `LuaMessage`. Instances of a registered
+/// `class`, i.e. tables with `class` as metatable, are converted with a `__class = name` field
+/// instead, and get `class` back when converted to Lua by an actor which registered `name`, e.g.
+/// in `ctx.msg`, in the result of `ctx.send` or when the state is restored. Other actors receive
+/// the `__class` field as is.
+///
+/// ### `ctx.math`
+/// `ctx.math.checked_add(a, b)`, `checked_sub` and `checked_mul` return `value, false`, or
+/// `nil, true` if the integer operation overflows. `ctx.math.clamp(x, lo, hi)` bounds `x`.
+/// See also [`LuaActorBuilder::with_overflow_errors`].
+///
+/// ### `ctx.scratch`
+/// A table for temporary data, empty at the start of every invocation and cleared once it
+/// completes. It's kept while the invocation is suspended in `ctx.send`. Cleared tables are
+/// reused instead of being reallocated, so `ctx.scratch` must not be stored in `ctx.state`:
+/// in debug builds, the next invocation raises an error if it is.
+///
 /// ### `ctx.notify(msg)`
 /// Send message `msg` to self.
 ///
+/// With [`LuaActorBuilder::with_notify_coalescing`], notifications beyond the limit of an
+/// invocation are buffered and delivered later as a single `{__coalesced = {...}}` message.
+///
 /// ### `ctx.notify_later(msg, seconds)`
 /// Send message `msg` to self after specified period of time.
 ///
+/// ### Durations
+/// `ctx.notify_later` and `ctx.run_interval` take durations in seconds, fractions allowed.
+/// Zero means the next turn of the event loop, except for `ctx.run_interval` periods which must
+/// be positive. Negative, NaN and infinite values, and values above 30 days (configurable with
+/// [`LuaActorBuilder::with_max_duration`]), raise a Lua error.
+///
+/// ### `local id = ctx.run_interval(seconds, msg[, policy])`
+/// Send the table `msg` to self every `seconds` until `ctx.cancel_interval(id)`. Ticks are
+/// copies of `msg` with a `missed` field. `policy` decides what happens to the ticks due while
+/// the actor is busy:
+///
+/// * `"coalesce"` (default): at most one tick is pending, `missed` counts the ticks it replaces.
+/// * `"all"`: every tick is delivered, back to back once the actor is free.
+/// * `"skip"`: the ticks due while one is pending are dropped, `missed` is always 0.
+///
+/// Intervals are cancelled on `ctx.restart`.
+///
+/// ### `local name = ctx.schedule(cron_expr, msg[, {name = ..., utc_offset = minutes}])`
+/// Send `msg` to self at the times matching the cron expression `cron_expr`: minute, hour,
+/// day-of-month, month and day-of-week (0 and 7 are Sunday), each `*` or a list of values,
+/// ranges `a-b` and steps `*/n`, `a-b/n` or `a/n`. If both day fields are restricted, a day
+/// matches if either does. Times are UTC, or `utc_offset` minutes east of UTC. A fixed offset
+/// has no daylight saving time, so no fire time is skipped or repeated. Fire times missed while
+/// the actor is busy are skipped.
+///
+/// Scheduling under an existing `name` replaces that schedule, a name is generated otherwise.
+/// `ctx.unschedule(name)` removes it, returning whether it existed. Schedules survive
+/// `ctx.restart` and end with the actor.
+///
 /// ### `local result = ctx.send(recipient, msg)`
 /// Send message `msg` to `recipient asynchronously and wait for response.
 ///
-/// Calling `ctx.send` yield the current coroutine and returns a `ThreadYield(thread_id)` message.
-/// LuaActor will wait for the response and resume the yielded coroutine once the response is returned.
+/// Calling `ctx.send` yield the current coroutine and the caller of the script gets a `Nil`
+/// reply. LuaActor will wait for the response and resume the yielded coroutine once the response
+/// is returned.
+///
+/// The actor keeps handling messages while the coroutine is suspended: other messages,
+/// notifications, intervals and the replies of other sends may run before `ctx.send` returns,
+/// and see `ctx.state` as the coroutine left it. Once the reply arrived, the coroutine resumes
+/// before the actor handles any message received after the reply, including `QueryLuaState`
+/// and `UpdateLuaState`. When several replies are ready, their coroutines resume in the order of
+/// the sends. A coroutine is resumed once with its reply, and with an `actor restarted` error
+/// if `ctx.restart` runs first.
 ///
 /// Equivalent to `actix::Recipient.send`.
 ///
+/// With [`LuaActorBuilder::with_rich_send_results`], the result is an envelope:
+/// `{ok = true, value = ...}` or `{ok = false, kind = ..., detail = ...}`, and
+/// `ctx.send_ok(recipient, msg)` returns the value or raises an error.
+///
+/// ### `local reply = ctx.send_correlated(recipient, msg[, opts])`
+/// Send a copy of the table `msg` with a new id in `opts.id_field` (default `correlation_id`)
+/// and wait for the first message carrying the id in the same field, which resumes the
+/// coroutine instead of running `handle`. For recipients which reply with `do_send`.
+///
+/// After `opts.timeout` seconds, the coroutine resumes with the timeout error of `ctx.send`.
+/// At most [`LuaActorBuilder::with_max_correlated`] requests can be pending.
+///
+/// ### `local msg = ctx.wait_for(types[, timeout])`
+/// Wait for the next table message whose `type` field is one of the strings `types`, which
+/// resumes the coroutine instead of running `handle`. Coroutines waiting for the same type get
+/// one message each, oldest first. After `timeout` seconds, the coroutine resumes with the
+/// timeout error of `ctx.send`.
+///
+/// ### `local g = ctx.spawn_group()`
+/// A group of coroutines. `g:spawn(fn)` runs `fn` in a new coroutine once the current one
+/// yields or returns. `local results = g:join([timeout])` waits until all the members finished,
+/// returning their results in spawn order, `{ok = true, value = ...}` or
+/// `{ok = false, error = ...}`. After `timeout` seconds, the unfinished members keep running and
+/// their results are `{ok = false, error = "timeout"}`.
+///
+/// `g:cancel()` cancels the unfinished members: the suspended ones resume with a `"cancelled"`
+/// error, and so does the next `ctx.send`, `ctx.send_correlated`, `ctx.wait_for` or `g:join` of
+/// the others. The groups are cancelled when the actor stops, after the `stopped` hook, and the
+/// members still running are reported to the `internal_error` hook.
+///
+/// ### `local ok, ... = ctx.try(f, ...)`
+/// `pcall` for functions which yield, e.g. in `ctx.send`, which the `pcall` of rlua doesn't
+/// allow. Members catch their cancellation with it.
+///
 /// ### `ctx.do_send(recipient, msg)`
 /// Send message `msg` to `recipient`.
 ///
 /// Equivalent to `actix::Recipient.do_send`.
 ///
+/// ### `ctx.do_send_confirmed(recipient, msg)`
+/// `ctx.do_send` which returns the id of a receipt, without waiting for the response. Once the
+/// invocation returned, the global function `on_delivery_receipt(receipt, status)` is called
+/// if the scripts defined it, `status` telling whether `msg` reached the mailbox of
+/// `recipient`: `"accepted"`, `"mailbox_full"` or `"closed"`, the latter when the recipient
+/// stopped or doesn't exist. `msg` is dropped unless it was accepted, which doesn't mean it
+/// was handled. The receipts are counted, see [`GetDeliveryStats`], and at most 1024 wait for
+/// `on_delivery_receipt`, see [`LuaActorBuilder::with_max_receipts`]. Outboxes and the queues
+/// of `ctx.replace_actor` don't apply.
+///
+/// ### `ctx.set_outbox(recipient[, opts])`, `ctx.outbox_depth(recipient)`
+/// Buffer the `ctx.do_send` to `recipient` while it's disconnected or missing, e.g. during a
+/// rolling restart, instead of losing them. `opts` is `{capacity = 1000, retry_interval = 1}`
+/// by default. The buffered messages are retried in order every `retry_interval` seconds, and
+/// as soon as a recipient or an alias is added with [`AddRecipient`] or [`SetAlias`]; later
+/// messages wait for them. Messages beyond `capacity` are dropped with an error log.
+/// `ctx.outbox_depth(recipient)` is the number of buffered messages.
+///
+/// ### `ctx.watch(recipient)`, `ctx.unwatch(recipient)`
+/// Watch whether `recipient` is running. When it changes, the global function
+/// `on_recipient_status(recipient, connected)` is called if the scripts defined it, otherwise
+/// the `handle` script gets the message `{__watch = {name = recipient, connected = ...}}`.
+/// Watched recipients are checked together every second, see
+/// [`LuaActorBuilder::with_watch_interval`]. Only the recipients added with
+/// [`add_lua_recipient`](#method.add_lua_recipient) can be checked, the others are assumed to
+/// be running. Watches end when the recipient is removed, or the actor stops or restarts.
+///
+/// ### `ctx.alias(alias, target)`, `ctx.unalias(alias)`, `ctx.resolve(alias)`
+/// Make `ctx.send` and `ctx.do_send` to `alias` go to the recipient `target`, which doesn't
+/// have to be added yet. Aliases can't refer to other aliases. `ctx.resolve` returns the
+/// target of `alias`, `nil` if it's not an alias. Aliases are resolved when `ctx.send` is
+/// called, changing one doesn't affect the sends in flight. Use [`SetAlias`] to change an alias
+/// from Rust.
+///
+/// ### `local summary = ctx.replace_actor(name, script_path[, opts])`
+/// Replace the child `name` added with [`LuaActorBuilder::with_child`] by one running the
+/// `handle` script `script_path`. The messages to `name` are held until the old child replied to
+/// the pending `ctx.send`, or `opts.drain_timeout` seconds (default 5), then delivered in order
+/// to the replacement and the old child is stopped.
+///
+/// Resumes with `{drained = n, queued = m, old_stopped = bool}`.
+///
 /// ### `ctx.terminate()`
 /// Terminate actor execution.
 ///
+/// The `stopped` hook is aborted after 5 seconds, see
+/// [`LuaActorBuilder::with_stopped_deadline`].
+///
+/// ### `ctx.restart([keep_state])`
+/// Restart the actor once the current invocation returns: the VM is rebuilt from the scripts and
+/// the `started` hook runs again. `ctx.state` is kept if `keep_state` is `true`.
+///
+/// The scripts changed in their source, see [`LuaActorBuilder::with_script_source`], are
+/// reloaded by the call, which raises an error if they can't be. Before the previous VM is
+/// dropped, the coroutines suspended in `ctx.send` and similar functions are resumed with an
+/// `actor restarted` error, so their `pcall`s can clean up.
+/// Messages are queued during the restart and handled by the new VM.
+/// Not available for actors built with `build_with_vm`, since their VM can't be rebuilt.
+///
+/// ### `ctx.deprecated(msg)`
+/// Warn that a deprecated API is used. Like the built-in warnings, e.g. for scripts replacing
+/// `ctx.state` instead of assigning its fields, the same warning is emitted at most once per
+/// interval, see [`LuaActorBuilder::with_warning_interval`].
+///
+/// ### `ctx.last_diff()`, `ctx.no_diff(path)`
+/// With [`LuaActorBuilder::with_state_diffing`], the changes made to `ctx.state` by the last
+/// message, `nil` otherwise. `ctx.no_diff(path)` skips `path` of `ctx.state` and everything
+/// under it in the diffs.
+///
+/// ### `ctx.state_version`, `ctx.migration(from_version, f)`
+/// Set by the hook of [`LuaActorBuilder::on_migrations`]: the version of `ctx.state` the
+/// scripts expect, and the function migrating a state of `from_version` to the next version.
+/// Restored and kept states are migrated before `started` runs.
+///
+/// ### `ctx.export_state([path])`, `ctx.import_state(path, blob[, options])`
+/// Copy a subtree of `ctx.state` to another actor: `ctx.export_state("users.alice")` encodes
+/// the value at the path, or the whole state without a path, as an opaque string which can be
+/// sent in a message. `ctx.import_state(path, blob)` replaces the value at `path` with it, or
+/// merges its tables into the value with `{merge = "merge"}`. A blob exported from an older
+/// `ctx.state_version` runs through the migrations first. Blobs above
+/// [`LuaActorBuilder::with_max_export_size`] raise an error with their size.
+///
+/// ### Read-only state
+/// With [`LuaActorBuilder::with_read_only_state`], `ctx.state` is frozen once `started` ran:
+/// assigning a field of `ctx.state` or of a table in it raises `state is read-only in this
+/// actor`. Replacing `ctx.state` itself is undone with a warning.
+///
+/// ### `ctx.help([name])`
+/// Return a table describing the available `ctx` functions, keyed by name, or the entry of the
+/// function `name`. Each entry has a `signature`, a `description` and its `availability`.
+/// See [`api_manifest`](#method.api_manifest).
+///
+/// ### `ctx.breaker_state(recipient)`
+/// With [`LuaActorBuilder::with_circuit_breaker`], the state of the circuit breaker of `recipient`:
+/// `"closed"`, `"open"` or `"half_open"`. `nil` if circuit breakers are not enabled.
+///
+/// ### `ctx.runtime.dependencies`
+/// With [`LuaActorBuilder::require_recipients`], a table of the required recipients, `true` for
+/// the ones which were added before `started` ran.
+///
+/// ### `ctx.release(handle)`
+/// Drop the Rust value of an opaque handle, see [`register_opaque`](#method.register_opaque).
+/// Opaque handles can be stored in `ctx.state` and sent with `ctx.notify`, but `ctx.send` and
+/// `ctx.do_send` raise a Lua error for messages containing them. The values are dropped when
+/// the actor stops.
+///
+/// ### `declare_global(name, value)`
+/// Declare a global variable. With [`LuaActorBuilder::with_strict_globals`], reading an undefined
+/// global or assigning a global which is not declared raises an error.
+///
+/// ### Recipient names
+/// Recipient names must be 1 to 128 bytes (configurable with
+/// [`LuaActorBuilder::with_max_recipient_name_len`]) of ASCII letters, digits and `_-.:/`.
+/// `self` is reserved. `ctx.send` and `ctx.do_send` raise a Lua error for invalid names.
+///
 /// [`LuaActorBuilder`]: struct.LuaActorBuilder.html
+/// [`SetAlias`]: struct.SetAlias.html
+/// [`AddRecipient`]: struct.AddRecipient.html
+/// [`GetDeliveryStats`]: struct.GetDeliveryStats.html
+/// [`GetWorkflowStats`]: struct.GetWorkflowStats.html
+/// [`LuaActorBuilder::with_max_receipts`]: struct.LuaActorBuilder.html#method.with_max_receipts
+/// [`LuaActorBuilder::with_strict_globals`]: struct.LuaActorBuilder.html#method.with_strict_globals
+/// [`LuaActorBuilder::with_max_recipient_name_len`]: struct.LuaActorBuilder.html#method.with_max_recipient_name_len
+/// [`LuaActorBuilder::with_max_duration`]: struct.LuaActorBuilder.html#method.with_max_duration
+/// [`LuaActorBuilder::with_notify_coalescing`]: struct.LuaActorBuilder.html#method.with_notify_coalescing
+/// [`LuaActorBuilder::with_circuit_breaker`]: struct.LuaActorBuilder.html#method.with_circuit_breaker
+/// [`LuaActorBuilder::with_rich_send_results`]: struct.LuaActorBuilder.html#method.with_rich_send_results
+/// [`LuaActorBuilder::with_max_correlated`]: struct.LuaActorBuilder.html#method.with_max_correlated
+/// [`LuaActorBuilder::with_child`]: struct.LuaActorBuilder.html#method.with_child
+/// [`LuaActorBuilder::require_recipients`]: struct.LuaActorBuilder.html#method.require_recipients
+/// [`LuaActorBuilder::with_warning_interval`]: struct.LuaActorBuilder.html#method.with_warning_interval
+/// [`LuaActorBuilder::with_watch_interval`]: struct.LuaActorBuilder.html#method.with_watch_interval
+/// [`LuaActorBuilder::with_overflow_errors`]: struct.LuaActorBuilder.html#method.with_overflow_errors
+/// [`LuaActorBuilder::with_stopped_deadline`]: struct.LuaActorBuilder.html#method.with_stopped_deadline
+/// [`LuaActorBuilder::with_state_diffing`]: struct.LuaActorBuilder.html#method.with_state_diffing
+/// [`LuaActorBuilder::with_read_only_state`]: struct.LuaActorBuilder.html#method.with_read_only_state
+/// [`LuaActorBuilder::on_migrations`]: struct.LuaActorBuilder.html#method.on_migrations
 pub struct LuaActor {
-    vm: Lua,
-    pub recipients: HashMap<String, Recipient<LuaMessage>>,
+    pub(crate) runtime: LuaRuntime,
+    pub recipients: Recipients,
+    pub(crate) aliases: Aliases,
+    pub(crate) outboxes: Outboxes,
+    pub(crate) name: String,
+    pub(crate) watchdog: Option<Watchdog>,
+    pub(crate) strict_globals: bool,
+    pub(crate) notify_coalescing: Option<NotifyCoalescing>,
+    pub(crate) circuit_breaker: Option<BreakerConfig>,
+    pub(crate) rich_send_results: bool,
+    pub(crate) send_timeout: Option<Duration>,
+    pub(crate) dependencies: Option<Dependencies>,
+    pub(crate) conversion_limits: ConversionLimits,
+    pub(crate) warnings: Warnings,
+    pub(crate) error_bursts: ErrorBursts,
+    pub(crate) idempotency: Option<Idempotency>,
+    pub(crate) stopped_deadline: Duration,
+    pub(crate) coroutine_pool_size: usize,
+    pub(crate) max_correlated: usize,
+    pub(crate) max_export_size: usize,
+    pub(crate) correlations: Correlations,
+    pub(crate) state_diffing: bool,
+    pub(crate) read_only_state: bool,
+    pub(crate) frozen_msg: bool,
+    pub(crate) datasets: Datasets,
+    pub(crate) children: Children,
+    pub(crate) inbound: Option<InboundLimit>,
+    pub(crate) pause: Pause,
+    pub(crate) receipts: Receipts,
+    pub(crate) workflows: Workflows,
+    pub(crate) ids: IdSource,
+    pub(crate) liveness: Liveness,
+    pub(crate) deep_ping: bool,
+    // coroutines suspended in `ctx.send` waiting for their reply, see `resume_sends`
+    pending_sends: Vec<PendingSend>,
+    resuming_sends: bool,
+    // incremented each time the VM is rebuilt
+    pub(crate) vm_generation: u64,
+    pub(crate) gc_watermark: Option<GcWatermark>,
+    pub(crate) state_quota: Option<StateQuota>,
+    script_source: Option<Arc<dyn ScriptSource>>,
+    pub(crate) missing_handler: MissingHandler,
+    // the reply to the messages arriving once the actor is stopping, and the recipient they're
+    // passed to, see `suppress`
+    pub(crate) terminal_reply: LuaMessage,
+    pub(crate) unprocessed_sink: Option<String>,
+    // see `LuaActorBuilder::with_build_info` and `LuaActorBuilder::with_log_prefix`
+    pub(crate) build_info: LuaMessage,
+    pub(crate) log_prefix: String,
+    // whether the scripts define `handle`, checked when the actor starts
+    has_handler: bool,
+    opaque: OpaqueRegistry,
+    pub(crate) intervals: Intervals,
+    pub(crate) schedules: Schedules,
+    pub(crate) watches: Watches,
+    breakers: HashMap<String, CircuitBreaker>,
+    // `None` if the VM can't be rebuilt, i.e. it was provided by the user
+    scripts: Option<Scripts>,
+    restarting: bool,
+    // an invocation is running in the VM, see `invoke_hook`
+    vm_busy: bool,
+    // set while the `internal_error` hook runs, its own errors are only logged
+    reporting_internal_error: bool,
+    // notifications waiting for the next flush
+    coalesced: Vec<LuaMessage>,
+    flush_scheduled: bool,
+    // waiting for the dependencies before running `started`
+    pub(crate) starting: bool,
+    // messages received while starting, with the channel of the reply if one is expected
+    deferred: Vec<(LuaMessage, Option<DeferredReply>)>,
+}
+
+// Configured by `LuaActorBuilder::with_notify_coalescing`.
+#[derive(Clone)]
+pub(crate) struct NotifyCoalescing {
+    pub max_per_invocation: usize,
+    pub flush_interval: Duration,
+}
+
+// Configured by `LuaActorBuilder::require_recipients`.
+#[derive(Clone)]
+pub(crate) struct Dependencies {
+    pub names: Vec<String>,
+    pub timeout: Duration,
+}
+
+// How often the dependencies are checked while starting.
+const DEPENDENCY_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Recipient names which can't be used with `try_add_recipients`.
+const RESERVED_RECIPIENT_NAMES: &[&str] = &["self"];
+
+pub(crate) const DEFAULT_MAX_RECIPIENT_NAME_LEN: usize = 128;
+
+pub(crate) const DEFAULT_NAME: &str = "LuaActor";
+
+pub(crate) const DEFAULT_STOPPED_DEADLINE: Duration = Duration::from_secs(5);
+
+pub(crate) fn validate_recipient_name(name: &str, max_len: usize) -> Result<(), LuaError> {
+    let invalid = |rule: String| {
+        Err(LuaError::RuntimeError(format!(
+            "invalid recipient name: {}",
+            rule
+        )))
+    };
+
+    if name.is_empty() {
+        return invalid("name is empty".to_string());
+    }
+    // don't echo oversized names, they would pollute the logs
+    if name.len() > max_len {
+        return invalid(format!("name is longer than {} bytes", max_len));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_-.:/".contains(c))
+    {
+        return invalid(format!(
+            "{:?} contains characters other than ASCII letters, digits and `_-.:/`",
+            name
+        ));
+    }
+    if RESERVED_RECIPIENT_NAMES.contains(&name) {
+        return invalid(format!("{:?} is reserved", name));
+    }
+    Ok(())
+}
+
+// Scripts kept to rebuild the VM on `ctx.restart`.
+#[derive(Clone)]
+pub(crate) struct Scripts {
+    started: Option<Vec<u8>>,
+    handle: Option<Vec<u8>>,
+    stopped: Option<Vec<u8>>,
+    internal_error: Option<Vec<u8>>,
+    migrations: Option<Vec<u8>>,
+    state_quota_exceeded: Option<Vec<u8>>,
+    restarting: Option<Vec<u8>>,
+    // the hooks to load again if they changed in their source
+    sourced: Option<SourcedHooks>,
+}
+
+impl Scripts {
+    // Replace the hooks which changed in their source.
+    fn refresh(&mut self) -> Result<(), LuaError> {
+        let changed = match self.sourced {
+            Some(ref mut sourced) => sourced.refresh()?,
+            None => return Ok(()),
+        };
+        for (hook, script) in changed {
+            match hook {
+                "started" => self.started = Some(script.into_bytes()),
+                "handle" => self.handle = Some(script.into_bytes()),
+                "internal_error" => self.internal_error = Some(script.into_bytes()),
+                "migrations" => self.migrations = Some(script.into_bytes()),
+                "state_quota_exceeded" => self.state_quota_exceeded = Some(script.into_bytes()),
+                "restarting" => self.restarting = Some(script.into_bytes()),
+                _ => self.stopped = Some(script.into_bytes()),
+            }
+        }
+        Ok(())
+    }
 }
 
 impl LuaActor {
@@ -54,39 +511,86 @@ impl LuaActor {
         handle: Option<String>,
         stopped: Option<String>,
     ) -> Result<LuaActor, LuaError> {
-        let prelude = include_str!("lua/prelude.lua");
-        vm.context(|ctx| {
-            ctx.load(prelude).set_name("Prelude")?.exec()?;
-            {
-                let load: Function = ctx.globals().get("__load")?;
-                if let Some(script) = started {
-                    let res = load.call::<(String, String), ()>((script, "started".to_string()));
-
-                    if let Err(e) = res {
-                        return Result::Err(e);
-                    }
-                }
-                if let Some(script) = handle {
-                    let res = load.call::<(String, String), ()>((script, "handle".to_string()));
-
-                    if let Err(e) = res {
-                        return Result::Err(e);
-                    }
-                }
-                if let Some(script) = stopped {
-                    let res = load.call::<(String, String), ()>((script, "stopped".to_string()));
+        Self::new_with_runtime(
+            LuaRuntime::new(vm),
+            started.map(String::into_bytes),
+            handle.map(String::into_bytes),
+            stopped.map(String::into_bytes),
+        )
+        .map_err(LuaError::from)
+    }
 
-                    if let Err(e) = res {
-                        return Result::Err(e);
-                    }
-                }
-            }
-            Ok(())
-        })?;
+    // `new_with_vm`, the scripts being Lua source or bytecode.
+    pub(crate) fn new_with_runtime(
+        runtime: LuaRuntime,
+        started: Option<Vec<u8>>,
+        handle: Option<Vec<u8>>,
+        stopped: Option<Vec<u8>>,
+    ) -> Result<LuaActor, LuaActorBuildError> {
+        runtime.load_scripts(started, handle, stopped)?;
+        runtime.install_help(api_manifest(&ApiConfig::default()))?;
+        let opaque = OpaqueRegistry::new();
+        runtime.install_opaque(&opaque)?;
 
         Result::Ok(LuaActor {
-            vm,
-            recipients: HashMap::new(),
+            runtime,
+            recipients: Recipients::new(),
+            aliases: Aliases::default(),
+            outboxes: Outboxes::default(),
+            name: DEFAULT_NAME.to_string(),
+            watchdog: None,
+            strict_globals: false,
+            notify_coalescing: None,
+            circuit_breaker: None,
+            rich_send_results: false,
+            send_timeout: None,
+            dependencies: None,
+            conversion_limits: ConversionLimits::default(),
+            warnings: Warnings::default(),
+            error_bursts: ErrorBursts::default(),
+            idempotency: None,
+            stopped_deadline: DEFAULT_STOPPED_DEADLINE,
+            coroutine_pool_size: DEFAULT_COROUTINE_POOL_SIZE,
+            max_correlated: DEFAULT_MAX_CORRELATED,
+            max_export_size: DEFAULT_MAX_EXPORT_SIZE,
+            correlations: Correlations::default(),
+            state_diffing: false,
+            read_only_state: false,
+            frozen_msg: false,
+            datasets: Datasets::default(),
+            children: Children::default(),
+            inbound: None,
+            pause: Pause::new(DEFAULT_PAUSE_BUFFER, PauseOverflow::DropNewest),
+            receipts: Receipts::new(DEFAULT_MAX_RECEIPTS),
+            workflows: Workflows::default(),
+            ids: IdSource::default(),
+            liveness: Liveness::new(),
+            deep_ping: false,
+            pending_sends: Vec::new(),
+            resuming_sends: false,
+            vm_generation: 0,
+            gc_watermark: None,
+            state_quota: None,
+            script_source: None,
+            missing_handler: MissingHandler::ReturnNil,
+            terminal_reply: LuaMessage::Nil,
+            unprocessed_sink: None,
+            build_info: LuaMessage::Nil,
+            log_prefix: format!("LuaActor {}", DEFAULT_NAME),
+            has_handler: true,
+            opaque,
+            intervals: Intervals::default(),
+            schedules: Schedules::default(),
+            watches: Watches::default(),
+            breakers: HashMap::new(),
+            scripts: None,
+            restarting: false,
+            vm_busy: false,
+            reporting_internal_error: false,
+            coalesced: Vec::new(),
+            flush_scheduled: false,
+            starting: false,
+            deferred: Vec::new(),
         })
     }
 
@@ -95,528 +599,6037 @@ impl LuaActor {
         handle: Option<String>,
         stopped: Option<String>,
     ) -> Result<LuaActor, LuaError> {
-        let vm = Lua::new();
-        Self::new_with_vm(vm, started, handle, stopped)
+        Self::new_restartable(
+            LuaRuntime::new(new_vm(false)),
+            started.map(String::into_bytes),
+            handle.map(String::into_bytes),
+            stopped.map(String::into_bytes),
+        )
+        .map_err(LuaError::from)
+    }
+
+    // `new`, in the VM of `runtime`, which `ctx.restart` rebuilds with the same settings.
+    pub(crate) fn new_restartable(
+        runtime: LuaRuntime,
+        started: Option<Vec<u8>>,
+        handle: Option<Vec<u8>>,
+        stopped: Option<Vec<u8>>,
+    ) -> Result<LuaActor, LuaActorBuildError> {
+        let scripts = Scripts {
+            started: started.clone(),
+            handle: handle.clone(),
+            stopped: stopped.clone(),
+            internal_error: None,
+            migrations: None,
+            state_quota_exceeded: None,
+            restarting: None,
+            sourced: None,
+        };
+        let mut actor = Self::new_with_runtime(runtime, started, handle, stopped)?;
+        actor.scripts = Some(scripts);
+        actor.runtime.install_help(actor.api_manifest())?;
+        Ok(actor)
+    }
+
+    // Load the `internal_error` hook, see `LuaActorBuilder::on_internal_error`.
+    pub(crate) fn set_internal_error_hook(&mut self, script: Vec<u8>) -> Result<(), LuaError> {
+        self.runtime.load_script("internal_error", script.clone())?;
+        if let Some(ref mut scripts) = self.scripts {
+            scripts.internal_error = Some(script);
+        }
+        Ok(())
+    }
+
+    // Load the `migrations` hook, see `LuaActorBuilder::on_migrations`.
+    pub(crate) fn set_migrations_hook(&mut self, script: Vec<u8>) -> Result<(), LuaError> {
+        self.runtime.load_script("migrations", script.clone())?;
+        if let Some(ref mut scripts) = self.scripts {
+            scripts.migrations = Some(script);
+        }
+        Ok(())
+    }
+
+    // Load the `state_quota_exceeded` hook, see `LuaActorBuilder::on_state_quota_exceeded`.
+    pub(crate) fn set_state_quota_exceeded_hook(
+        &mut self,
+        script: Vec<u8>,
+    ) -> Result<(), LuaError> {
+        self.runtime
+            .load_script("state_quota_exceeded", script.clone())?;
+        if let Some(ref mut scripts) = self.scripts {
+            scripts.state_quota_exceeded = Some(script);
+        }
+        Ok(())
+    }
+
+    // Load the `restarting` hook, see `LuaActorBuilder::on_restarting`.
+    pub(crate) fn set_restarting_hook(&mut self, script: Vec<u8>) -> Result<(), LuaError> {
+        self.runtime.load_script("restarting", script.clone())?;
+        if let Some(ref mut scripts) = self.scripts {
+            scripts.restarting = Some(script);
+        }
+        Ok(())
+    }
+
+    // Log a non-fatal error of `subsystem` and pass it to the `internal_error` hook as
+    // `{subsystem = ..., message = ..., detail = {...}, build = {...}}`, `build` being set with
+    // `LuaActorBuilder::with_build_info`. Errors of the hook are only logged.
+    pub(crate) fn report_internal_error(
+        &mut self,
+        ctx: &mut Context<Self>,
+        subsystem: &str,
+        message: String,
+        detail: HashMap<String, LuaMessage>,
+    ) {
+        log::error!("{} {}", self.log_prefix, message);
+        if self.reporting_internal_error || !self.runtime.has_script("internal_error") {
+            return;
+        }
+        let mut event = HashMap::new();
+        event.insert("subsystem".to_string(), LuaMessage::from(subsystem));
+        event.insert("message".to_string(), LuaMessage::from(message));
+        event.insert("detail".to_string(), LuaMessage::from(detail));
+        if self.build_info != LuaMessage::Nil {
+            event.insert("build".to_string(), self.build_info.clone());
+        }
+        self.reporting_internal_error = true;
+        let res = self.invoke_hook(
+            ctx,
+            "internal_error",
+            "__run",
+            vec![
+                LuaMessage::from("internal_error"),
+                LuaMessage::from(event),
+                LuaMessage::from(false),
+            ],
+            false,
+        );
+        self.reporting_internal_error = false;
+        if let Err(e) = res {
+            log::error!("{} internal_error hook failed: {}", self.log_prefix, e);
+        }
+    }
+
+    // Load the modules passed to `require` from `source`, and the `hooks` loaded from it again
+    // on restart if their version changed.
+    pub(crate) fn set_script_source(
+        &mut self,
+        source: Arc<dyn ScriptSource>,
+        hooks: Vec<(&'static str, String)>,
+    ) -> Result<(), LuaError> {
+        self.runtime.install_script_source(source.clone())?;
+        if let Some(ref mut scripts) = self.scripts {
+            let hooks = hooks
+                .into_iter()
+                .map(|(hook, name)| {
+                    let version = source.version(&name);
+                    (hook, name, version)
+                })
+                .collect();
+            scripts.sourced = Some(SourcedHooks {
+                source: source.clone(),
+                hooks,
+            });
+        }
+        self.script_source = Some(source);
+        Ok(())
+    }
+
+    // Whether `ctx.restart` can rebuild the VM, i.e. it wasn't provided by the user.
+    pub(crate) fn restartable(&self) -> bool {
+        self.scripts.is_some()
+    }
+
+    // The hooks loaded in the VM, with their script name and version if they were loaded from
+    // a `ScriptSource`.
+    pub(crate) fn loaded_scripts(&self) -> HashMap<String, LuaMessage> {
+        let sourced = self.scripts.as_ref().and_then(|s| s.sourced.as_ref());
+        [
+            "started",
+            "handle",
+            "stopped",
+            "internal_error",
+            "migrations",
+            "state_quota_exceeded",
+            "restarting",
+        ]
+        .iter()
+        .filter(|hook| self.runtime.has_script(hook))
+        .map(|hook| {
+            let mut script = HashMap::new();
+            match sourced.and_then(|s| s.hooks.iter().find(|(h, _, _)| h == hook)) {
+                Some((_, name, version)) => {
+                    script.insert("source".to_string(), LuaMessage::from("script_source"));
+                    script.insert("name".to_string(), LuaMessage::from(name.as_str()));
+                    script.insert(
+                        "version".to_string(),
+                        version.clone().map_or(LuaMessage::Nil, LuaMessage::from),
+                    );
+                }
+                None => {
+                    script.insert("source".to_string(), LuaMessage::from("builder"));
+                }
+            }
+            (hook.to_string(), LuaMessage::from(script))
+        })
+        .collect()
     }
 
     /// Add a recipient to the actor's recipient list.
     /// You can send message to the recipient via `name` with the context API `ctx.send(name, message)`
+    ///
+    /// Panics if `name` is not a valid recipient name, see [`try_add_recipients`](#method.try_add_recipients).
+    #[deprecated(note = "panics on invalid names, use try_add_recipients or add_lua_recipient")]
     pub fn add_recipients(
         &mut self,
         name: &str,
         rec: Recipient<LuaMessage>,
     ) -> Option<Recipient<LuaMessage>> {
-        self.recipients.insert(name.to_string(), rec)
+        match self.try_add_recipients(name, rec) {
+            Ok(prev) => prev,
+            Err(e) => panic!("{}", e),
+        }
     }
-}
 
-// Remove all `self` usage with a independent function `invoke`.
-fn invoke(
-    self_addr: &Recipient<SendAttempt>,
-    ctx: &mut Context<LuaActor>,
-    vm: &mut Lua,
-    recs: &mut HashMap<String, Recipient<LuaMessage>>,
-    func_name: &str,
-    args: Vec<LuaMessage>,
-) -> Result<LuaMessage, LuaError> {
-    // `ctx` is used in multiple closure in the lua scope.
-    // to create multiple borrow in closures, we use RefCell to move the borrow-checking to runtime.
-    // Voliating the check will result in panic. Which shouldn't happend(I think) since lua is single-threaded.
-    let ctx = RefCell::new(ctx);
-    let recs = RefCell::new(recs);
-
-    vm.context(|lua_ctx| {
-        let iter = args
-            .into_iter()
-            .map(|msg| msg.to_lua(lua_ctx).unwrap())
-            .collect();
-        let args = MultiValue::from_vec(iter);
-        // We can't create a function with references to `self` and is 'static since `self` already owns Lua.
-        // A function within Lua owning `self` creates self-borrowing cycle.
-        //
-        // Also, Lua requires all values passed to it is 'static because we can't know when will Lua GC our value.
-        // Therefore, we use scope to make sure these APIs are temporary and don't have to deal with 'static lifetime.
-        //
-        // (Quote from: https://github.com/kyren/rlua/issues/56#issuecomment-363928738
-        // When the scope ends, the Lua function is 100% guaranteed (afaict!) to be "invalidated".
-        // This means that calling the function will cause an immediate Lua error with a message like "error, call of invalidated function".)
-        //
-        // for reference, check https://github.com/kyren/rlua/issues/73#issuecomment-370222198
-        lua_ctx.scope(|scope| {
-            let globals = lua_ctx.globals();
+    /// Add a recipient to the actor's recipient list.
+    ///
+    /// Returns an error naming the broken rule if `name` is empty, too long, contains characters
+    /// other than ASCII letters, digits and `_-.:/`, or is reserved.
+    pub fn try_add_recipients(
+        &mut self,
+        name: &str,
+        rec: Recipient<LuaMessage>,
+    ) -> Result<Option<Recipient<LuaMessage>>, LuaError> {
+        self.recipients.try_insert(name.to_string(), rec)
+    }
 
-            let notify = scope.create_function_mut(|_, msg: LuaMessage| {
-                let mut ctx = ctx.borrow_mut();
-                ctx.notify(msg);
-                Ok(())
-            })?;
-            globals.set("notify", notify)?;
+    /// Add a `LuaActor` to the actor's recipient list.
+    ///
+    /// Same as `try_add_recipients`, but with [`LuaActorBuilder::with_rich_send_results`],
+    /// `ctx.send` can tell a missing `handle` script or a script error of the recipient from its
+    /// result.
+    ///
+    /// [`LuaActorBuilder::with_rich_send_results`]: struct.LuaActorBuilder.html#method.with_rich_send_results
+    pub fn add_lua_recipient(
+        &mut self,
+        name: &str,
+        addr: &Addr<LuaActor>,
+    ) -> Result<Option<Recipient<LuaMessage>>, LuaError> {
+        self.recipients.insert_lua(name.to_string(), addr)
+    }
 
-            let notify_later = scope.create_function_mut(|_, (msg, secs): (LuaMessage, u64)| {
-                let mut ctx = ctx.borrow_mut();
-                ctx.notify_later(msg, Duration::new(secs, 0));
-                Ok(())
-            })?;
-            globals.set("notify_later", notify_later)?;
+    /// Name of the actor used in diagnostics.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-            let do_send =
-                scope.create_function_mut(|_, (recipient_name, msg): (String, LuaMessage)| {
-                    let recs = recs.borrow_mut();
-                    let rec = recs.get(&recipient_name);
+    /// The `ctx` functions available to the scripts of this actor, as listed by `ctx.help()`.
+    pub fn api_manifest(&self) -> Vec<ApiEntry> {
+        api_manifest(&ApiConfig {
+            restartable: self.scripts.is_some(),
+            circuit_breaker: self.circuit_breaker.is_some(),
+            rich_send_results: self.rich_send_results,
+        })
+    }
 
-                    // TODO: error handling?
-                    if let Some(r) = rec {
-                        r.do_send(msg).unwrap();
-                    }
-                    Ok(())
-                })?;
-            globals.set("do_send", do_send)?;
-
-            let send = scope.create_function_mut(
-                |_, (recipient_name, msg, cb_thread_id): (String, LuaMessage, i64)| {
-                    // we can't create a lua function which owns `self`
-                    // but `self` is needed for resolving `send` future.
-                    //
-                    // The workaround is we notify ourself with a `SendAttempt` Message
-                    // and resolving `send` future in the `handle` function.
-                    self_addr
-                        .do_send(SendAttempt {
-                            recipient_name,
-                            msg,
-                            cb_thread_id,
-                        })
-                        .unwrap();
+    /// Store a Rust value which can't be converted to `LuaMessage`, returning an
+    /// `LuaMessage::Opaque` handle to pass to the scripts.
+    ///
+    /// See [`OpaqueRegistry`](struct.OpaqueRegistry.html).
+    pub fn register_opaque<T: Any + Send>(&self, value: T) -> LuaMessage {
+        LuaMessage::Opaque(self.opaque.register(value).0)
+    }
 
-                    Ok(())
-                },
-            )?;
-            globals.set("send", send)?;
+    /// Remove the value of an `Opaque` handle, `None` if `handle` is not a registered handle.
+    pub fn take_opaque(&self, handle: &LuaMessage) -> Option<Box<dyn Any + Send>> {
+        match handle {
+            LuaMessage::Opaque(id) => self.opaque.take(OpaqueHandle(*id)),
+            _ => None,
+        }
+    }
 
-            let terminate = scope.create_function_mut(|_, _: LuaMessage| {
-                let mut ctx = ctx.borrow_mut();
-                ctx.terminate();
-                Ok(())
-            })?;
-            globals.set("terminate", terminate)?;
+    /// Registry of the values of the `Opaque` handles of this actor.
+    pub fn opaque_registry(&self) -> &OpaqueRegistry {
+        &self.opaque
+    }
 
-            let lua_handle: Result<Function, LuaError> = globals.get(func_name);
-            if let Ok(f) = lua_handle {
-                match f.call::<MultiValue, Value>(args) {
-                    Err(e) => panic!("{:?}", e),
-                    Ok(ret) => Ok(LuaMessage::from_lua(ret, lua_ctx).unwrap()),
-                }
+    // Call `func_name` in the VM, registering the invocation to the watchdog if enabled.
+    //
+    // Every handler enters the VM through here, one invocation at a time: the invocation borrows
+    // the actor mutably, and the `ctx` API only gets its fields, so a callback can't handle
+    // another message before the invocation returns. Futures spawned or waited on with `ctx` run
+    // once the handler returned. Code which needs the VM during an invocation, e.g. to deliver a
+    // reply, must go through the actor's mailbox or `ctx` futures.
+    //
+    // `ctx.send` suspends the coroutine of the handler and returns, the mailbox stays live
+    // while the reply is pending and `resume_sends` resumes the coroutine once it arrived.
+    pub(crate) fn invoke_hook(
+        &mut self,
+        ctx: &mut Context<Self>,
+        hook: &str,
+        func_name: &str,
+        args: Vec<LuaMessage>,
+        reply_expected: bool,
+    ) -> Result<LuaMessage, LuaError> {
+        self.resume_sends(ctx);
+        let _guard = self
+            .watchdog
+            .as_ref()
+            .map(|w| w.register(&self.name, &self.log_prefix, hook));
+        let mut restart = None;
+        let mut api = ContextApi {
+            ctx,
+            name: &self.name,
+            log_prefix: &self.log_prefix,
+            warnings: &mut self.warnings,
+            recipients: &self.recipients,
+            aliases: &mut self.aliases,
+            outboxes: &mut self.outboxes,
+            intervals: &mut self.intervals,
+            schedules: &mut self.schedules,
+            watches: &mut self.watches,
+            restart: self.scripts.as_mut().map(|scripts| (&mut restart, scripts)),
+            circuit_breaker: self.circuit_breaker.is_some(),
+            breakers: &self.breakers,
+            coalescing: self.notify_coalescing.as_ref(),
+            coalesced: &mut self.coalesced,
+            notified: 0,
+            vm_generation: self.vm_generation,
+            correlations: &mut self.correlations,
+            children: &mut self.children,
+            receipts: &mut self.receipts,
+            ids: &mut self.ids,
+            unprocessed_sink: self.unprocessed_sink.as_deref(),
+        };
+        debug_assert!(!self.vm_busy, "LuaActor re-entered the VM in `{}`", hook);
+        self.vm_busy = true;
+        // `stopped` has its own deadline
+        if hook != "stopped" {
+            self.runtime.arm_handle_timeout();
+        }
+        let res = self
+            .runtime
+            .call_hook(&mut api, func_name, args, reply_expected)
+            .map(|outcome| outcome.into_message());
+        self.runtime.disarm_handle_timeout();
+        self.vm_busy = false;
+        drop(_guard);
+        if let Ok((completed, failed)) = self.runtime.take_finished_workflows() {
+            self.workflows.record(completed, failed);
+        }
+
+        if !self.coalesced.is_empty() && !self.flush_scheduled {
+            if let Some(ref coalescing) = self.notify_coalescing {
+                self.flush_scheduled = true;
+                ctx.run_later(coalescing.flush_interval, |act, ctx| {
+                    act.flush_coalesced(ctx)
+                });
+            }
+        }
+        self.check_gc_watermark(ctx);
+        if hook == "started" || hook == "handle" || hook == "resume" {
+            self.check_state_quota(ctx);
+        }
+
+        if let Some(keep_state) = restart {
+            if self.restarting
+                || hook == "stopped"
+                || hook == "internal_error"
+                || hook == "migrations"
+                || hook == "state_quota_exceeded"
+                || hook == "restarting"
+                || hook == "health"
+                || hook == "restart"
+            {
+                log::warn!(
+                    "{} ignored ctx.restart() called in the `{}` hook",
+                    self.log_prefix,
+                    if self.restarting { "started" } else { hook }
+                );
             } else {
-                // return nil if handle is not defined
-                Ok(LuaMessage::Nil)
+                self.restart(keep_state, ctx);
             }
-        })
-    })
-}
+        }
+        res
+    }
 
-impl Actor for LuaActor {
-    type Context = Context<Self>;
+    // Deliver the buffered notifications as a single `{__coalesced = {...}}` message.
+    fn flush_coalesced(&mut self, ctx: &mut Context<Self>) {
+        self.flush_scheduled = false;
+        let batch: HashMap<String, LuaMessage> = self
+            .coalesced
+            .drain(..)
+            .enumerate()
+            .map(|(i, msg)| ((i + 1).to_string(), msg))
+            .collect();
+        let mut msg = HashMap::new();
+        msg.insert("__coalesced".to_string(), LuaMessage::from(batch));
+        ctx.notify(Notification(LuaMessage::from(msg)));
+    }
 
-    fn started(&mut self, ctx: &mut Context<Self>) {
-        if let Err(e) = invoke(
-            &ctx.address().recipient(),
+    // Rebuild the VM from the scripts reloaded by `ctx.restart` and run the `started` hook
+    // again.
+    //
+    // Suspended coroutines are failed with an "actor restarted" error in the previous VM.
+    fn restart(&mut self, keep_state: bool, ctx: &mut Context<Self>) {
+        let scripts = match self.scripts {
+            Some(ref scripts) => scripts.clone(),
+            None => return,
+        };
+        let internal_error = scripts.internal_error;
+        let migrations = scripts.migrations;
+        let state_quota_exceeded = scripts.state_quota_exceeded;
+        let restarting = scripts.restarting;
+        let mut runtime = LuaRuntime::new(new_vm(self.runtime.sandboxed));
+        runtime.sandboxed = self.runtime.sandboxed;
+        runtime.package_path = self.runtime.package_path.clone();
+        runtime.package_cpath = self.runtime.package_cpath.clone();
+        runtime.preloaded = self.runtime.preloaded.clone();
+        runtime.globals = self.runtime.globals.clone();
+        runtime.functions = self.runtime.functions.clone();
+        runtime.max_recipient_name_len = self.runtime.max_recipient_name_len;
+        runtime.max_duration = self.runtime.max_duration;
+        runtime.set_handle_timeout(self.runtime.handle_timeout);
+        runtime.isolated_hooks = self.runtime.isolated_hooks;
+        runtime.lazy_tables = self.runtime.lazy_tables;
+        runtime.compat_shims = self.runtime.compat_shims;
+        runtime.set_memory_limit(self.runtime.memory_limit);
+        if let Err(e) = runtime
+            .load_scripts(scripts.started, scripts.handle, scripts.stopped)
+            .map_err(LuaError::from)
+            .and_then(|_| runtime.install_help(self.api_manifest()))
+            .and_then(|_| runtime.set_rich_send_results(self.rich_send_results))
+            .and_then(|_| runtime.set_frozen_msg(self.frozen_msg))
+            .and_then(|_| runtime.vm.context(|ctx| self.datasets.install_all(ctx)))
+            .and_then(|_| runtime.set_coroutine_pool_size(self.coroutine_pool_size))
+            .and_then(|_| runtime.set_max_correlated(self.max_correlated))
+            .and_then(|_| runtime.set_max_export_size(self.max_export_size))
+            .and_then(|_| runtime.install_opaque(&self.opaque))
+            .and_then(|_| runtime.set_conversion_limits(&self.conversion_limits))
+            .and_then(|_| runtime.set_build_info(&self.build_info))
+            .and_then(|_| runtime.set_paused(self.pause.is_paused()))
+            .and_then(|_| match self.script_source {
+                Some(ref source) => runtime.install_script_source(source.clone()),
+                None => Ok(()),
+            })
+            .and_then(|_| match internal_error {
+                Some(script) => runtime.load_script("internal_error", script),
+                None => Ok(()),
+            })
+            .and_then(|_| match migrations {
+                Some(script) => runtime.load_script("migrations", script),
+                None => Ok(()),
+            })
+            .and_then(|_| match state_quota_exceeded {
+                Some(script) => runtime.load_script("state_quota_exceeded", script),
+                None => Ok(()),
+            })
+            .and_then(|_| match restarting {
+                Some(script) => runtime.load_script("restarting", script),
+                None => Ok(()),
+            })
+        {
+            // reported by the previous VM, which keeps running
+            self.report_internal_error(
+                ctx,
+                "restart",
+                format!("failed to restart: {}", e),
+                error_detail(&e),
+            );
+            return;
+        }
+        // their error handlers may still change the state
+        if let Err(e) = self.invoke_hook(ctx, "restart", "__fail_threads", vec![], false) {
+            log::error!(
+                "{} failed to end the suspended coroutines on restart: {}",
+                self.log_prefix,
+                e
+            );
+        }
+        let state = if keep_state {
+            match self.runtime.get_state() {
+                Ok(state) => Some(state),
+                Err(e) => {
+                    self.report_internal_error(
+                        ctx,
+                        "restart",
+                        format!("failed to keep state on restart: {}", e),
+                        error_detail(&e),
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        self.runtime = runtime;
+        // the suspended coroutines ended with the previous VM
+        self.vm_generation += 1;
+        self.pending_sends.clear();
+        self.correlations.clear(ctx);
+        // `started` creates them again
+        self.intervals.clear();
+        self.watches.clear(ctx);
+        if state.is_none() {
+            // the handles were dropped with the state
+            self.opaque.clear();
+        }
+        if self.strict_globals {
+            if let Err(e) = self.runtime.install_strict_globals() {
+                self.report_internal_error(
+                    ctx,
+                    "restart",
+                    format!("failed to restart: {}", e),
+                    error_detail(&e),
+                );
+            }
+        }
+        if let Some(state) = state {
+            if let Err(e) = self.runtime.set_state(state) {
+                self.report_internal_error(
+                    ctx,
+                    "restart",
+                    format!("failed to keep state on restart: {}", e),
+                    error_detail(&e),
+                );
+            }
+        }
+        if let Err(e) = self.runtime.set_dependencies(&self.dependency_status()) {
+            self.report_internal_error(
+                ctx,
+                "restart",
+                format!("failed to restart: {}", e),
+                error_detail(&e),
+            );
+        }
+
+        self.restarting = true;
+        self.run_started(ctx);
+        self.restarting = false;
+    }
+
+    fn run_started(&mut self, ctx: &mut Context<Self>) {
+        if self.runtime.has_script("migrations") && !self.migrate_state(ctx) {
+            return;
+        }
+        if let Err(e) = self.invoke_hook(
             ctx,
-            &mut self.vm,
-            &mut self.recipients,
+            "started",
             "__run",
-            vec![LuaMessage::from("started")],
+            vec![
+                LuaMessage::from("started"),
+                LuaMessage::Nil,
+                LuaMessage::from(false),
+            ],
+            false,
         ) {
-            panic!("lua actor started failed {:?}", e);
+            self.fail_start(ctx, "started", &e);
+            return;
+        }
+        if self.read_only_state {
+            if let Err(e) = self.runtime.freeze_state() {
+                self.report_internal_error(
+                    ctx,
+                    "state",
+                    format!("failed to make the state read-only: {}", e),
+                    error_detail(&e),
+                );
+            }
         }
     }
 
-    fn stopped(&mut self, ctx: &mut Context<Self>) {
-        if let Err(e) = invoke(
-            &ctx.address().recipient(),
+    // Bring `ctx.state` to the version declared by the `migrations` hook, see
+    // `LuaActorBuilder::on_migrations`. Like errors of `started`, failures stop the actor,
+    // returning `false`.
+    fn migrate_state(&mut self, ctx: &mut Context<Self>) -> bool {
+        if let Err(e) = self.invoke_hook(
             ctx,
-            &mut self.vm,
-            &mut self.recipients,
+            "migrations",
             "__run",
-            vec![LuaMessage::from("stopped")],
+            vec![
+                LuaMessage::from("__migrate"),
+                LuaMessage::Nil,
+                LuaMessage::from(false),
+            ],
+            false,
         ) {
-            panic!("lua actor stopped failed {:?}", e);
+            self.fail_start(ctx, "state migration", &e);
+            return false;
         }
+        true
     }
-}
 
-struct SendAttempt {
-    recipient_name: String,
-    msg: LuaMessage,
-    cb_thread_id: i64,
-}
+    // Log an error of the `started` or `migrations` hook and stop the actor.
+    fn fail_start(&mut self, ctx: &mut Context<Self>, what: &str, e: &LuaError) {
+        log::error!("{} {} failed, stopping: {}", self.log_prefix, what, e);
+        ctx.stop();
+    }
 
-impl Message for SendAttempt {
-    type Result = LuaMessage;
-}
+    // Whether each dependency is a recipient of the actor.
+    fn dependency_status(&self) -> Vec<(String, bool)> {
+        match self.dependencies {
+            Some(ref deps) => deps
+                .names
+                .iter()
+                .map(|name| (name.clone(), self.recipients.contains_key(name)))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
 
-struct SendAttemptResult {
-    msg: LuaMessage,
-    cb_thread_id: i64,
-}
+    // Run `started` once every dependency is a recipient, or stop the actor after `deadline`.
+    fn wait_for_dependencies(&mut self, deadline: Instant, ctx: &mut Context<Self>) {
+        let status = self.dependency_status();
+        let missing: Vec<&str> = status
+            .iter()
+            .filter(|(_, connected)| !connected)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if missing.is_empty() {
+            self.starting = false;
+            if let Err(e) = self.runtime.set_dependencies(&status) {
+                self.fail_start(ctx, "started", &e);
+                return;
+            }
+            self.run_started(ctx);
+            for (msg, tx) in std::mem::take(&mut self.deferred) {
+                self.dispatch(msg, tx.is_some(), ctx, move |res| {
+                    if let Some(tx) = tx {
+                        tx.reply(res);
+                    }
+                });
+            }
+        } else if Instant::now() >= deadline {
+            let e = LuaError::RuntimeError(format!("missing dependencies: {}", missing.join(", ")));
+            self.fail_start(ctx, "started", &e);
+        } else {
+            ctx.run_later(DEPENDENCY_RETRY_INTERVAL, move |act, ctx| {
+                act.wait_for_dependencies(deadline, ctx)
+            });
+        }
+    }
 
-impl Message for SendAttemptResult {
-    type Result = LuaMessage;
-}
+    /// Encode `ctx.state` with `codec`.
+    pub fn snapshot_state(&self, codec: &dyn StateCodec) -> Result<Vec<u8>, StateError> {
+        let state = self.runtime.get_state()?;
+        codec
+            .encode(&state)
+            .map_err(StateError::codec(StateSubsystem::Snapshot))
+    }
 
-impl Handler<LuaMessage> for LuaActor {
-    type Result = LuaMessage;
+    /// Replace `ctx.state` with a snapshot created by [`snapshot_state`](#method.snapshot_state).
+    ///
+    /// If the snapshot can't be decoded, `ctx.state` is left untouched and an error is returned.
+    pub fn restore_state(
+        &mut self,
+        codec: &dyn StateCodec,
+        bytes: &[u8],
+    ) -> Result<(), StateError> {
+        let state = codec
+            .decode(bytes)
+            .map_err(StateError::codec(StateSubsystem::Restore))?;
+        Ok(self.runtime.set_state(state)?)
+    }
+}
 
-    fn handle(&mut self, msg: LuaMessage, ctx: &mut Context<Self>) -> Self::Result {
-        if let Ok(res) = invoke(
-            &ctx.address().recipient(),
-            ctx,
-            &mut self.vm,
-            &mut self.recipients,
-            "__run",
-            vec![LuaMessage::from("handle"), msg],
-        ) {
-            res
-        } else {
-            LuaMessage::Nil
+// Send `msg` to `recipient_name`, through its outbox if it has one.
+pub(crate) fn deliver(
+    recipients: &Recipients,
+    aliases: &Aliases,
+    outboxes: &mut Outboxes,
+    log_prefix: &str,
+    recipient_name: &str,
+    msg: LuaMessage,
+    ctx: &mut Context<LuaActor>,
+) {
+    let rec = recipients.get(aliases.resolve(recipient_name));
+    if outboxes.contains(recipient_name) {
+        outboxes.send(recipient_name, rec, msg, log_prefix, ctx);
+        return;
+    }
+    match rec {
+        Some(r) => {
+            if let Err(e) = r.do_send(msg) {
+                log::warn!(
+                    "{} can't send a message to {}: {}",
+                    log_prefix,
+                    recipient_name,
+                    e
+                );
+            }
         }
+        None => log::warn!(
+            "{} can't send a message to unknown recipient {}",
+            log_prefix,
+            recipient_name
+        ),
     }
 }
 
-impl Handler<SendAttemptResult> for LuaActor {
-    type Result = LuaMessage;
-
-    fn handle(&mut self, result: SendAttemptResult, ctx: &mut Context<Self>) -> Self::Result {
-        if let Ok(res) = invoke(
-            &ctx.address().recipient(),
-            ctx,
-            &mut self.vm,
-            &mut self.recipients,
-            "__resume",
-            vec![LuaMessage::from(result.cb_thread_id), result.msg],
-        ) {
-            res
-        } else {
-            LuaMessage::Nil
+// Pass `msg` to the unprocessed sink `sink`, if there is one, see
+// `LuaActorBuilder::with_unprocessed_sink`.
+fn pass_to_sink(
+    recipients: &Recipients,
+    aliases: &Aliases,
+    sink: Option<&str>,
+    log_prefix: &str,
+    msg: LuaMessage,
+) {
+    if let Some(name) = sink {
+        match recipients.get(aliases.resolve(name)) {
+            Some(rec) => {
+                if let Err(e) = rec.do_send(msg) {
+                    log::warn!(
+                        "{} can't pass an unprocessed message to {}: {}",
+                        log_prefix,
+                        name,
+                        e
+                    );
+                }
+            }
+            None => log::warn!(
+                "{} can't pass an unprocessed message to unknown recipient {}",
+                log_prefix,
+                name
+            ),
         }
     }
 }
 
-impl Handler<SendAttempt> for LuaActor {
-    type Result = LuaMessage;
+// `ActorApi` backed by the actix context of the running actor.
+struct ContextApi<'a> {
+    ctx: &'a mut Context<LuaActor>,
+    name: &'a str,
+    log_prefix: &'a str,
+    warnings: &'a mut Warnings,
+    recipients: &'a Recipients,
+    aliases: &'a mut Aliases,
+    outboxes: &'a mut Outboxes,
+    intervals: &'a mut Intervals,
+    schedules: &'a mut Schedules,
+    watches: &'a mut Watches,
+    // `None` if the actor can't be restarted
+    restart: Option<(&'a mut Option<bool>, &'a mut Scripts)>,
+    circuit_breaker: bool,
+    breakers: &'a HashMap<String, CircuitBreaker>,
+    coalescing: Option<&'a NotifyCoalescing>,
+    coalesced: &'a mut Vec<LuaMessage>,
+    // notifications delivered individually in this invocation
+    notified: usize,
+    vm_generation: u64,
+    correlations: &'a mut Correlations,
+    children: &'a mut Children,
+    receipts: &'a mut Receipts,
+    ids: &'a mut IdSource,
+    unprocessed_sink: Option<&'a str>,
+}
 
-    fn handle(&mut self, attempt: SendAttempt, ctx: &mut Context<Self>) -> Self::Result {
-        let rec = &self.recipients[&attempt.recipient_name];
-        let self_addr = ctx.address().clone();
-        rec.send(attempt.msg.clone())
-            .into_actor(self)
-            .then(move |res, _, _| {
-                match res {
-                    Ok(msg) => self_addr.do_send(SendAttemptResult {
-                        msg,
-                        cb_thread_id: attempt.cb_thread_id,
-                    }),
-                    _ => {
-                        panic!("send attempt failed: {:?}", res);
-                    }
-                };
-                actix::fut::ok(())
-            })
-            .wait(ctx);
+impl<'a> ActorApi for ContextApi<'a> {
+    fn notify(&mut self, msg: LuaMessage) {
+        if let Some(coalescing) = self.coalescing {
+            // keep buffering until the batch is flushed to preserve the order
+            if self.notified >= coalescing.max_per_invocation || !self.coalesced.is_empty() {
+                self.coalesced.push(msg);
+                return;
+            }
+            self.notified += 1;
+        }
+        self.ctx.notify(Notification(msg));
+    }
 
-        LuaMessage::Nil
+    fn notify_later(&mut self, msg: LuaMessage, after: Duration) {
+        self.ctx.notify_later(Notification(msg), after);
+    }
+
+    fn run_interval(
+        &mut self,
+        period: Duration,
+        msg: HashMap<String, LuaMessage>,
+        policy: TickPolicy,
+    ) -> i64 {
+        let id = self
+            .intervals
+            .insert(Interval::new(period, msg, policy, Instant::now()));
+        LuaActor::schedule_interval(id, period, self.ctx);
+        id
+    }
+
+    fn cancel_interval(&mut self, id: i64) {
+        self.intervals.remove(id);
+    }
+
+    fn schedule(&mut self, name: Option<String>, cron: CronExpr, msg: LuaMessage) -> String {
+        let name = name.unwrap_or_else(|| self.schedules.generate_name());
+        let now = unix_millis(SystemTime::now());
+        let (id, delay) = self.schedules.insert(name.clone(), cron, msg, now);
+        LuaActor::arm_schedule(name.clone(), id, delay, self.ctx);
+        name
+    }
+
+    fn unschedule(&mut self, name: &str) -> bool {
+        self.schedules.remove(name)
+    }
+
+    fn watch(&mut self, recipient_name: &str) -> Result<(), LuaError> {
+        match self
+            .recipients
+            .connected(self.aliases.resolve(recipient_name))
+        {
+            Some(connected) => {
+                self.watches.watch(recipient_name, connected, self.ctx);
+                Ok(())
+            }
+            None => Err(LuaError::RuntimeError(format!(
+                "ctx.watch: unknown recipient {}",
+                recipient_name
+            ))),
+        }
+    }
+
+    fn unwatch(&mut self, recipient_name: &str) {
+        self.watches.unwatch(recipient_name, self.ctx);
+    }
+
+    fn do_send(&mut self, recipient_name: &str, msg: LuaMessage) {
+        let target = self.aliases.resolve(recipient_name);
+        if self.children.replacing(target) {
+            let held = Held::DoSend(recipient_name.to_string(), msg);
+            if self.children.hold(target, held).is_err() {
+                self.warnings.warn_once(
+                    self.name,
+                    "handoff_queue_full",
+                    "messages for a child being replaced were rejected, its queue is full",
+                    Instant::now(),
+                );
+            }
+            return;
+        }
+        deliver(
+            self.recipients,
+            self.aliases,
+            self.outboxes,
+            self.log_prefix,
+            recipient_name,
+            msg,
+            self.ctx,
+        );
+    }
+
+    fn do_send_confirmed(&mut self, recipient_name: &str, msg: LuaMessage) -> i64 {
+        let rec = self.recipients.get(self.aliases.resolve(recipient_name));
+        let status = DeliveryStatus::of(rec.map(|r| r.try_send(msg)));
+        let (id, issued) = self.receipts.issue(status);
+        match issued {
+            Issued::First => self.ctx.notify(DeliverReceipts),
+            Issued::Queued => {}
+            Issued::Overflowed => pass_to_sink(
+                self.recipients,
+                self.aliases,
+                self.unprocessed_sink,
+                self.log_prefix,
+                receipt(id, status),
+            ),
+        }
+        id
+    }
+
+    fn set_outbox(&mut self, recipient_name: &str, capacity: usize, retry_interval: Duration) {
+        self.outboxes.set(recipient_name, capacity, retry_interval);
+    }
+
+    fn outbox_depth(&mut self, recipient_name: &str) -> usize {
+        self.outboxes.depth(recipient_name)
+    }
+
+    fn send(&mut self, recipient_name: String, msg: LuaMessage, cb_thread_id: i64) {
+        // we can't create a lua function which owns `self`
+        // but `self` is needed for resolving `send` future.
+        //
+        // The workaround is we notify ourself with a `SendAttempt` Message
+        // and resolving `send` future in the `handle` function.
+        //
+        // Aliases are resolved now, they may change before the attempt is handled.
+        let recipient_name = self.aliases.resolve(&recipient_name).to_string();
+        self.ctx.address().do_send(SendAttempt {
+            recipient_name,
+            msg,
+            cb_thread_id,
+            vm_generation: self.vm_generation,
+        });
+    }
+
+    fn correlate(&mut self, timeout: Option<Duration>) -> i64 {
+        let id = self.ids.correlation_id();
+        if let Some(timeout) = timeout {
+            self.correlations.schedule(self.ctx, id, timeout);
+        }
+        id
+    }
+
+    fn uncorrelate(&mut self, id: i64) {
+        self.correlations.cancel(self.ctx, id);
+    }
+
+    fn replace_actor(
+        &mut self,
+        name: &str,
+        script_path: &str,
+        drain_timeout: Duration,
+        max_queued: usize,
+        cb_thread_id: i64,
+    ) -> Result<(), LuaError> {
+        let name = self.aliases.resolve(name).to_string();
+        self.children.replace(
+            &name,
+            script_path,
+            drain_timeout,
+            max_queued,
+            cb_thread_id,
+            self.vm_generation,
+        )?;
+        self.ctx.run_later(Duration::from_secs(0), move |act, ctx| {
+            act.check_handoff(ctx, name)
+        });
+        Ok(())
+    }
+
+    fn terminate(&mut self) {
+        self.ctx.terminate();
+    }
+
+    fn restart(&mut self, keep_state: bool) -> Result<(), LuaError> {
+        match self.restart {
+            Some((ref mut restart, ref mut scripts)) => {
+                // reload the changed scripts now, so the script knows if they can't be
+                scripts.refresh().map_err(|e| {
+                    LuaError::RuntimeError(format!("ctx.restart can't reload the scripts: {}", e))
+                })?;
+                **restart = Some(keep_state);
+                Ok(())
+            }
+            None => Err(LuaError::RuntimeError(
+                "ctx.restart is not available for actors built with build_with_vm".to_string(),
+            )),
+        }
+    }
+
+    fn breaker_state(&mut self, recipient_name: &str) -> Option<&'static str> {
+        if !self.circuit_breaker {
+            return None;
+        }
+        Some(
+            self.breakers
+                .get(self.aliases.resolve(recipient_name))
+                .map_or("closed", |b| b.state_name()),
+        )
+    }
+
+    fn alias(&mut self, alias: String, target: String) -> Result<(), LuaError> {
+        self.aliases.set(alias, target).map(|_| ())
+    }
+
+    fn unalias(&mut self, alias: &str) {
+        self.aliases.remove(alias);
+    }
+
+    fn resolve(&mut self, alias: &str) -> Option<String> {
+        self.aliases.get(alias).map(str::to_string)
+    }
+
+    fn warn(&mut self, key: &str, message: &str) {
+        self.warnings
+            .warn_once(self.name, key, message, Instant::now());
+    }
+
+    fn uuid(&mut self) -> String {
+        self.ids.uuid()
+    }
+}
+
+impl Actor for LuaActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        self.liveness.start();
+        // the children are recipients of the `started` hook
+        self.children.start(&mut self.recipients);
+        self.has_handler = self.runtime.has_script("handle");
+        self.warn_config();
+        if !self.has_handler && self.missing_handler == MissingHandler::Error {
+            log::warn!(
+                "{} has no handle script, messages are answered with a no_handler error",
+                self.log_prefix
+            );
+        }
+        match self.dependencies {
+            Some(ref deps) => {
+                self.starting = true;
+                let deadline = Instant::now() + deps.timeout;
+                self.wait_for_dependencies(deadline, ctx);
+            }
+            None => self.run_started(ctx),
+        }
+    }
+
+    fn stopped(&mut self, ctx: &mut Context<Self>) {
+        // the windows still open won't end
+        self.error_bursts.flush(&self.name, None);
+        self.schedules.clear();
+        let buffered = self.outboxes.buffered();
+        if buffered > 0 {
+            log::warn!(
+                "{} dropped {} messages buffered in outboxes on stop",
+                self.log_prefix,
+                buffered
+            );
+        }
+        if !self.coalesced.is_empty() {
+            log::warn!(
+                "{} dropped {} coalesced notifications on stop",
+                self.log_prefix,
+                self.coalesced.len()
+            );
+        }
+        if self.pause.buffered() > 0 {
+            log::warn!(
+                "{} dropped {} messages buffered while paused on stop",
+                self.log_prefix,
+                self.pause.buffered()
+            );
+        }
+        // a `stopped` script which never returns must not keep the actor alive
+        let aborted = self
+            .runtime
+            .set_deadline(Instant::now() + self.stopped_deadline);
+        let res = self.invoke_hook(
+            ctx,
+            "stopped",
+            "__run",
+            vec![
+                LuaMessage::from("stopped"),
+                LuaMessage::Nil,
+                LuaMessage::from(false),
+            ],
+            false,
+        );
+        self.runtime.clear_deadline();
+        match res {
+            Err(_) if aborted.load(Ordering::SeqCst) => {
+                let message = format!(
+                    "stopped hook aborted after its deadline of {:?}",
+                    self.stopped_deadline
+                );
+                self.warnings
+                    .warn_once(&self.name, "stopped_deadline", &message, Instant::now());
+            }
+            Err(e) => log::error!("{} stopped failed: {}", self.log_prefix, e),
+            Ok(_) => {}
+        }
+        match self.invoke_hook(ctx, "stopped", "__cancel_groups", vec![], true) {
+            Ok(LuaMessage::Integer(abandoned)) if abandoned > 0 => {
+                let mut detail = HashMap::new();
+                detail.insert("abandoned".to_string(), LuaMessage::from(abandoned));
+                self.report_internal_error(
+                    ctx,
+                    "spawn_group",
+                    format!(
+                        "cancelled {} ctx.spawn_group members still running on stop",
+                        abandoned
+                    ),
+                    detail,
+                );
+            }
+            Ok(_) => {}
+            Err(e) => log::error!(
+                "{} failed to cancel the ctx.spawn_group members: {}",
+                self.log_prefix,
+                e
+            ),
+        }
+        self.opaque.clear();
+        self.watches.clear(ctx);
+        self.correlations.clear(ctx);
+        if let Err(e) = self.runtime.clear_correlations() {
+            log::error!(
+                "{} failed to drop the pending ctx.send_correlated: {}",
+                self.log_prefix,
+                e
+            );
+        }
+    }
+}
+
+// Started again by an `actix::Supervisor` once it stopped, see `LuaActorBuilder::on_restarting`.
+// Unlike `ctx.restart`, the VM is kept, and `started` runs again in it.
+impl Supervised for LuaActor {
+    fn restarting(&mut self, ctx: &mut Context<Self>) {
+        // the futures of the previous context were dropped, so the suspended coroutines won't
+        // be resumed and the intervals won't fire
+        self.vm_generation += 1;
+        self.pending_sends.clear();
+        self.intervals.clear();
+        if let Err(e) = self.runtime.clear_threads() {
+            log::error!(
+                "{} failed to drop the suspended coroutines: {}",
+                self.log_prefix,
+                e
+            );
+        }
+        let res = self.invoke_hook(
+            ctx,
+            "restarting",
+            "__run",
+            vec![
+                LuaMessage::from("restarting"),
+                LuaMessage::Nil,
+                LuaMessage::from(false),
+            ],
+            false,
+        );
+        if let Err(e) = res {
+            log::error!("{} restarting failed: {}", self.log_prefix, e);
+        }
+    }
+}
+
+pub(crate) struct SendAttempt {
+    recipient_name: String,
+    msg: LuaMessage,
+    pub cb_thread_id: i64,
+    // the coroutine is gone if the VM was rebuilt since
+    pub vm_generation: u64,
+}
+
+impl Message for SendAttempt {
+    type Result = LuaMessage;
+}
+
+// A `ctx.send` waiting for its reply.
+struct PendingSend {
+    recipient_name: String,
+    cb_thread_id: i64,
+    fut: SendFuture,
+}
+
+// Polls the replies of `ctx.send` while some are pending, for the actors which don't receive
+// other messages meanwhile.
+struct PollSends;
+
+impl ActorFuture for PollSends {
+    type Item = ();
+    type Error = ();
+    type Actor = LuaActor;
+
+    fn poll(&mut self, act: &mut LuaActor, ctx: &mut Context<LuaActor>) -> Poll<(), ()> {
+        act.resume_sends(ctx);
+        if act.pending_sends.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl LuaActor {
+    // Resume the coroutines whose `ctx.send` got its reply, in the order of the sends.
+    //
+    // The context of the actor polls its futures after the mailbox, so the replies are polled
+    // here before each invocation and query: a coroutine resumes before the messages received
+    // after its reply.
+    pub(crate) fn resume_sends(&mut self, ctx: &mut Context<Self>) {
+        // a resumed coroutine runs in `invoke_hook`, which calls this again
+        if self.resuming_sends {
+            return;
+        }
+        self.resuming_sends = true;
+        loop {
+            let mut replied = None;
+            for (i, pending) in self.pending_sends.iter_mut().enumerate() {
+                match pending.fut.poll() {
+                    Ok(Async::NotReady) => continue,
+                    Ok(Async::Ready(res)) => replied = Some((i, Ok(res))),
+                    Err(e) => replied = Some((i, Err(e))),
+                }
+                break;
+            }
+            match replied {
+                Some((i, res)) => {
+                    // each coroutine is resumed once, with the reply of its own send
+                    let pending = self.pending_sends.remove(i);
+                    self.resume_send(ctx, pending, res);
+                }
+                None => break,
+            }
+        }
+        self.resuming_sends = false;
+    }
+
+    fn resume_send(
+        &mut self,
+        ctx: &mut Context<Self>,
+        pending: PendingSend,
+        res: Result<Result<LuaMessage, HandleError>, MailboxError>,
+    ) {
+        self.children.drained(&pending.recipient_name);
+        if let Some(breaker) = self.breakers.get_mut(&pending.recipient_name) {
+            match res {
+                // the recipient failed to handle the message
+                Err(_) | Ok(Err(_)) => breaker.record_failure(Instant::now()),
+                Ok(Ok(_)) => breaker.record_success(),
+            }
+        }
+        let msg = if self.rich_send_results {
+            send_envelope(res)
+        } else {
+            match res {
+                // handler errors are only reported with rich results
+                Ok(msg) => msg.unwrap_or(LuaMessage::Nil),
+                Err(e) => {
+                    let err = send_failure(&pending.recipient_name, &e);
+                    self.resume_with(ctx, pending.cb_thread_id, vec![LuaMessage::Nil, err]);
+                    return;
+                }
+            }
+        };
+        self.resume(ctx, msg, pending.cb_thread_id);
+    }
+
+    // Whether a `ctx.send` to `recipient_name` waits for its reply.
+    pub(crate) fn awaits_reply_from(&self, recipient_name: &str) -> bool {
+        self.pending_sends
+            .iter()
+            .any(|p| p.recipient_name == recipient_name)
+    }
+
+    pub(crate) fn resume(&mut self, ctx: &mut Context<Self>, msg: LuaMessage, cb_thread_id: i64) {
+        self.resume_with(ctx, cb_thread_id, vec![msg]);
+    }
+
+    // Resume the coroutine `cb_thread_id`, its `ctx` call returns `values`.
+    pub(crate) fn resume_with(
+        &mut self,
+        ctx: &mut Context<Self>,
+        cb_thread_id: i64,
+        values: Vec<LuaMessage>,
+    ) {
+        if self.pause.holds_resumes() {
+            self.hold(Buffered::Resume(cb_thread_id, values));
+            return;
+        }
+        let origin = if self.state_diffing {
+            let values: Vec<_> = values.iter().map(truncated).collect();
+            Some(format!("reply {}", values.join(", ")))
+        } else {
+            None
+        };
+        let mut args = vec![LuaMessage::from(cb_thread_id)];
+        args.extend(values);
+        let res = self.diffing_state(origin, |act| {
+            act.invoke_hook(ctx, "resume", "__resume", args, false)
+        });
+        // nobody waits for the result of a resumed coroutine, see `handle_message`
+        if let Err(e) = res {
+            self.record_error("handle", &e.to_string(), ctx);
+        }
+    }
+}
+
+/// Response of `Handler<LuaMessage>`.
+///
+/// The `handle` script is not run in `Handler::handle` but when actix hands the response channel
+/// to `MessageResponse::handle`, so the script knows if the sender expects a reply.
+/// The invocation is scheduled with `ctx.wait`, no other message is processed before it finishes.
+pub struct LuaReply {
+    msg: LuaMessage,
+}
+
+impl MessageResponse<LuaActor, LuaMessage> for LuaReply {
+    fn handle<R: ResponseChannel<LuaMessage>>(self, ctx: &mut Context<LuaActor>, tx: Option<R>) {
+        let msg = self.msg;
+        ctx.wait(
+            actix::fut::ok(())
+                .map(move |_, act: &mut LuaActor, ctx| act.receive(msg, false, tx, ctx)),
+        );
+    }
+}
+
+// A message of the actor to itself, sent by `ctx.notify` and `ctx.notify_later`. It's handled
+// like a `LuaMessage` sent with `do_send`, except while paused, see `PauseProcessing`.
+pub(crate) struct Notification(pub LuaMessage);
+
+impl Message for Notification {
+    type Result = ();
+}
+
+impl Handler<Notification> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, notification: Notification, ctx: &mut Context<Self>) {
+        let msg = notification.0;
+        ctx.wait(
+            actix::fut::ok(())
+                .map(move |_, act: &mut LuaActor, ctx| act.receive::<()>(msg, true, None, ctx)),
+        );
+    }
+}
+
+impl LuaActor {
+    // Handle a message of the mailbox or a notification, once `started` ran, unless the actor
+    // is paused or the message is above the inbound rate limit.
+    pub(crate) fn receive<R: ReplyTx>(
+        &mut self,
+        msg: LuaMessage,
+        notification: bool,
+        tx: Option<R>,
+        ctx: &mut Context<Self>,
+    ) {
+        if self.starting {
+            // reply once `started` ran
+            self.deferred.push((msg, tx.map(ReplyTx::defer)));
+            return;
+        }
+        if self.pause.holds(notification) {
+            self.hold(Buffered::Message(msg, tx.map(ReplyTx::defer)));
+            return;
+        }
+        let (msg, tx) = match self.limit_inbound(msg, tx, ctx) {
+            Some(admitted) => admitted,
+            None => return,
+        };
+        let reply_expected = tx.is_some();
+        self.dispatch(msg, reply_expected, ctx, move |res| {
+            if let Some(tx) = tx {
+                tx.reply(res);
+            }
+        });
+    }
+}
+
+// The channel of the reply to a message of the mailbox, a `LuaMessage` or a `TryLuaMessage`.
+pub(crate) trait ReplyTx: 'static {
+    // Send the reply of the `handle` script, or of the recipient the message was forwarded to.
+    fn reply(self, res: LuaMessage);
+
+    // Reply to a message which won't be handled, e.g. `kind` "paused".
+    fn reject(self, kind: &str, retry_after: Option<Duration>, rich: bool);
+
+    // The channel of a reply sent later, e.g. once `started` ran. The reply is forwarded outside
+    // of the actor's context, so it arrives even if the actor stopped in the meantime.
+    fn defer(self) -> DeferredReply;
+}
+
+impl<R: ResponseChannel<LuaMessage>> ReplyTx for R {
+    fn reply(self, res: LuaMessage) {
+        self.send(res);
+    }
+
+    fn reject(self, kind: &str, retry_after: Option<Duration>, rich: bool) {
+        self.send(send_error(kind, retry_after, rich));
+    }
+
+    fn defer(self) -> DeferredReply {
+        let (tx, rx) = oneshot::channel();
+        Arbiter::spawn(rx.map(move |res| self.send(res)).map_err(|_| ()));
+        DeferredReply::Message(tx)
+    }
+}
+
+// The reply to a `TryLuaMessage`, the errors of the `handle` script are returned as
+// `HandleError::Error`.
+pub(crate) struct TryReply<R>(pub R);
+
+impl<R: ResponseChannel<TryLuaMessage>> ReplyTx for TryReply<R> {
+    fn reply(self, res: LuaMessage) {
+        self.0.send(match res {
+            LuaMessage::Error { message, .. } => Err(HandleError::Error(message)),
+            res => Ok(res),
+        });
+    }
+
+    fn reject(self, kind: &str, _: Option<Duration>, _: bool) {
+        self.0.send(Err(HandleError::Error(kind.to_string())));
+    }
+
+    fn defer(self) -> DeferredReply {
+        let (tx, rx) = oneshot::channel();
+        Arbiter::spawn(rx.map(move |res| self.0.send(res)).map_err(|_| ()));
+        DeferredReply::Try(tx)
+    }
+}
+
+// A reply channel kept while the message waits, e.g. for `started`.
+pub(crate) enum DeferredReply {
+    Message(oneshot::Sender<LuaMessage>),
+    Try(oneshot::Sender<Result<LuaMessage, HandleError>>),
+}
+
+impl ReplyTx for DeferredReply {
+    fn reply(self, res: LuaMessage) {
+        match self {
+            DeferredReply::Message(tx) => tx.reply(res),
+            DeferredReply::Try(tx) => TryReply(tx).reply(res),
+        }
+    }
+
+    fn reject(self, kind: &str, retry_after: Option<Duration>, rich: bool) {
+        match self {
+            DeferredReply::Message(tx) => tx.reject(kind, retry_after, rich),
+            DeferredReply::Try(tx) => TryReply(tx).reject(kind, retry_after, rich),
+        }
+    }
+
+    fn defer(self) -> DeferredReply {
+        self
+    }
+}
+
+impl LuaActor {
+    // The recipient to forward the messages to, if there is no `handle` script and
+    // `MissingHandler::Forward` is set.
+    fn forward_target(&self) -> Option<Recipient<LuaMessage>> {
+        match self.missing_handler {
+            MissingHandler::Forward(ref name) if !self.has_handler => {
+                let rec = self.recipients.get(self.aliases.resolve(name)).cloned();
+                if rec.is_none() {
+                    log::warn!(
+                        "{} can't forward a message to unknown recipient {}",
+                        self.log_prefix,
+                        name
+                    );
+                }
+                rec
+            }
+            _ => None,
+        }
+    }
+
+    // Whether `ctx.terminate()` or `ctx.stop()` was called, after which the messages still
+    // delivered are not handled.
+    pub(crate) fn terminal(ctx: &Context<Self>) -> bool {
+        matches!(ctx.state(), ActorState::Stopping | ActorState::Stopped)
+    }
+
+    // Pass a message arriving once the actor is stopping to the unprocessed sink, returning
+    // the terminal reply.
+    fn suppress(&mut self, msg: LuaMessage) -> LuaMessage {
+        self.pass_unprocessed(msg);
+        self.terminal_reply.clone()
+    }
+
+    // Pass a message which won't be handled to the unprocessed sink, if there is one.
+    pub(crate) fn pass_unprocessed(&mut self, msg: LuaMessage) {
+        pass_to_sink(
+            &self.recipients,
+            &self.aliases,
+            self.unprocessed_sink.as_deref(),
+            &self.log_prefix,
+            msg,
+        );
+    }
+
+    // Handle a message of the mailbox, or forward it, passing the result to `reply`.
+    pub(crate) fn dispatch<F>(
+        &mut self,
+        msg: LuaMessage,
+        reply_expected: bool,
+        ctx: &mut Context<Self>,
+        reply: F,
+    ) where
+        F: FnOnce(LuaMessage) + 'static,
+    {
+        if Self::terminal(ctx) {
+            reply(self.suppress(msg));
+            return;
+        }
+        match self.forward_target() {
+            Some(rec) => {
+                ctx.spawn(actix::fut::wrap_future(rec.send(msg)).then(
+                    move |res, act: &mut LuaActor, ctx| {
+                        match res {
+                            Ok(res) => reply(res),
+                            Err(e) => {
+                                reply(send_error("send_failed", None, act.rich_send_results));
+                                let mut detail = HashMap::new();
+                                detail.insert("error".to_string(), LuaMessage::from(e.to_string()));
+                                act.report_internal_error(
+                                    ctx,
+                                    "forward",
+                                    format!("failed to forward a message: {}", e),
+                                    detail,
+                                );
+                            }
+                        }
+                        actix::fut::ok(())
+                    },
+                ));
+            }
+            None => reply(self.handle_received(msg, reply_expected, ctx)),
+        }
+    }
+
+    // Run the `handle` script for a message of the mailbox, unless it's a duplicate.
+    fn handle_received(
+        &mut self,
+        msg: LuaMessage,
+        reply_expected: bool,
+        ctx: &mut Context<Self>,
+    ) -> LuaMessage {
+        if !self.has_handler && self.missing_handler != MissingHandler::ReturnNil {
+            return send_error("no_handler", None, self.rich_send_results);
+        }
+        let key = self.idempotency.as_ref().and_then(|i| i.key(&msg));
+        if let (Some(key), Some(idempotency)) = (&key, self.idempotency.as_mut()) {
+            if let Some(reply) = idempotency.duplicate(key, Instant::now()) {
+                return reply;
+            }
+        }
+        let res = self.handle_message(msg, reply_expected, ctx);
+        if let (Some(key), Some(idempotency)) = (key, self.idempotency.as_mut()) {
+            idempotency.record(key, &res, Instant::now());
+        }
+        res
+    }
+
+    // Run the `handle` script. Script errors are logged and the reply is `LuaMessage::Error`.
+    pub(crate) fn handle_message(
+        &mut self,
+        msg: LuaMessage,
+        reply_expected: bool,
+        ctx: &mut Context<Self>,
+    ) -> LuaMessage {
+        self.liveness.handled();
+        match self.try_handle_message(msg, reply_expected, ctx) {
+            Ok(res) => res,
+            Err(e) => {
+                self.record_error("handle", &e.to_string(), ctx);
+                LuaMessage::from_script_error(&e)
+            }
+        }
+    }
+
+    fn try_handle_message(
+        &mut self,
+        msg: LuaMessage,
+        reply_expected: bool,
+        ctx: &mut Context<Self>,
+    ) -> Result<LuaMessage, LuaError> {
+        if Self::terminal(ctx) {
+            return Ok(self.suppress(msg));
+        }
+        let origin = if self.state_diffing {
+            Some(format!("message {}", truncated(&msg)))
+        } else {
+            None
+        };
+        self.diffing_state(origin, |act| {
+            act.invoke_hook(
+                ctx,
+                "handle",
+                "__run",
+                vec![
+                    LuaMessage::from("handle"),
+                    msg,
+                    LuaMessage::from(reply_expected),
+                ],
+                reply_expected,
+            )
+        })
+    }
+
+    // Log an error returned to the caller, identical errors within a window are counted and
+    // reported together, see `LuaActorBuilder::with_error_coalescing`.
+    pub(crate) fn record_error(&mut self, hook: &str, error: &str, ctx: &mut Context<Self>) {
+        self.liveness.error();
+        if self.error_bursts.record(hook, error, Instant::now()) {
+            ctx.run_later(self.error_bursts.window, |act, _| {
+                act.error_bursts.flush(&act.name, Some(Instant::now()))
+            });
+        }
+    }
+
+    // Run `f`, logging the changes it made to `ctx.state` if `origin` is set, see
+    // `LuaActorBuilder::with_state_diffing`.
+    fn diffing_state<R, F>(&mut self, origin: Option<String>, f: F) -> R
+    where
+        F: FnOnce(&mut Self) -> R,
+    {
+        let origin = match origin {
+            Some(origin) => origin,
+            None => return f(self),
+        };
+        let before = self.runtime.get_state();
+        let res = f(self);
+        let diff = before.and_then(|before| {
+            let after = self.runtime.get_state()?;
+            let diff = StateDiff::new(&before, &after, &self.runtime.no_diff_paths()?);
+            self.runtime.set_last_diff(diff.to_message())?;
+            Ok(diff)
+        });
+        match diff {
+            Ok(ref diff) if diff.is_empty() => {}
+            Ok(diff) => log::info!(
+                target: "actix_lua::state_diff",
+                "{} {} changed ctx.state: {}",
+                self.log_prefix,
+                origin,
+                diff
+            ),
+            Err(e) => {
+                self.warnings.warn_once(
+                    &self.name,
+                    "state_diff",
+                    &format!("failed to diff ctx.state: {}", e),
+                    Instant::now(),
+                );
+            }
+        }
+        res
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use futures_timer::Delay;
-    use std::collections::HashMap;
-    use std::time::Duration;
-    use tokio::prelude::Future;
+impl Handler<LuaMessage> for LuaActor {
+    type Result = LuaReply;
+
+    fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> Self::Result {
+        LuaReply { msg }
+    }
+}
+
+/// Message handled like `LuaMessage`, except that failures are returned instead of panicking
+/// the actor.
+///
+/// Used for `ctx.send` to recipients added with `LuaActor::add_lua_recipient` when rich send
+/// results are enabled.
+pub struct TryLuaMessage(pub LuaMessage);
+
+impl Message for TryLuaMessage {
+    type Result = Result<LuaMessage, HandleError>;
+}
+
+/// Add a recipient to a running `LuaActor`, see `LuaActor::try_add_recipients`.
+///
+/// Returns the previous recipient with the same name.
+pub struct AddRecipient {
+    pub name: String,
+    pub recipient: Recipient<LuaMessage>,
+}
+
+impl Message for AddRecipient {
+    type Result = Result<Option<Recipient<LuaMessage>>, LuaError>;
+}
+
+impl Handler<AddRecipient> for LuaActor {
+    type Result = Result<Option<Recipient<LuaMessage>>, LuaError>;
+
+    fn handle(&mut self, msg: AddRecipient, ctx: &mut Context<Self>) -> Self::Result {
+        let previous = self.try_add_recipients(&msg.name, msg.recipient)?;
+        self.flush_outboxes(ctx);
+        Ok(previous)
+    }
+}
+
+/// Point the alias `alias` of a running `LuaActor` to the recipient `target`, see `ctx.alias`.
+///
+/// Returns the previous target.
+pub struct SetAlias {
+    pub alias: String,
+    pub target: String,
+}
+
+impl Message for SetAlias {
+    type Result = Result<Option<String>, LuaError>;
+}
+
+impl Handler<SetAlias> for LuaActor {
+    type Result = Result<Option<String>, LuaError>;
+
+    fn handle(&mut self, msg: SetAlias, ctx: &mut Context<Self>) -> Self::Result {
+        let max_len = self.runtime.max_recipient_name_len;
+        validate_recipient_name(&msg.alias, max_len)?;
+        validate_recipient_name(&msg.target, max_len)?;
+        let previous = self.aliases.set(msg.alias, msg.target)?;
+        self.flush_outboxes(ctx);
+        Ok(previous)
+    }
+}
+
+/// Why a `LuaActor` failed to handle a `TryLuaMessage`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandleError {
+    /// the actor has no `handle` script
+    NoHandler,
+    /// the `handle` script raised an error
+    Error(String),
+}
+
+/// What a `LuaActor` without a `handle` script does with the messages it receives, see
+/// `LuaActorBuilder::with_missing_handler`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MissingHandler {
+    /// reply `nil`
+    ReturnNil,
+    /// reply `{error = "no_handler"}`, or `{ok = false, kind = "no_handler"}` with rich send
+    /// results. `TryLuaMessage` fails with `HandleError::NoHandler`.
+    Error,
+    /// forward the messages unchanged to the recipient with this name, replying with its reply
+    Forward(String),
+}
+
+impl Handler<TryLuaMessage> for LuaActor {
+    type Result = TryLuaReply;
+
+    fn handle(&mut self, msg: TryLuaMessage, _: &mut Context<Self>) -> Self::Result {
+        TryLuaReply { msg: msg.0 }
+    }
+}
+
+/// Response of `Handler<TryLuaMessage>`.
+///
+/// The message is received like a `LuaMessage`, see `LuaReply`: it waits for `started`, is
+/// buffered while paused, counts toward the inbound rate limit and is deduplicated with
+/// `LuaActorBuilder::with_idempotency`.
+pub struct TryLuaReply {
+    msg: LuaMessage,
+}
+
+impl MessageResponse<LuaActor, TryLuaMessage> for TryLuaReply {
+    fn handle<R: ResponseChannel<TryLuaMessage>>(self, ctx: &mut Context<LuaActor>, tx: Option<R>) {
+        let msg = self.msg;
+        ctx.wait(actix::fut::ok(()).map(move |_, act: &mut LuaActor, ctx| {
+            if act.has_handler {
+                act.receive(msg, false, tx.map(TryReply), ctx);
+                return;
+            }
+            let res = match act.forward_target() {
+                Some(rec) => {
+                    let req = rec.send(msg).map_err(|e| {
+                        HandleError::Error(format!("failed to forward the message: {}", e))
+                    });
+                    Arbiter::spawn(req.then(move |res| {
+                        if let Some(tx) = tx {
+                            tx.send(res);
+                        }
+                        Ok(())
+                    }));
+                    return;
+                }
+                None => Err(HandleError::NoHandler),
+            };
+            if let Some(tx) = tx {
+                tx.send(res);
+            }
+        }));
+    }
+}
+
+// `detail` of an internal error caused by a Lua error.
+fn error_detail(e: &LuaError) -> HashMap<String, LuaMessage> {
+    let mut detail = HashMap::new();
+    detail.insert("error".to_string(), LuaMessage::from(e.to_string()));
+    detail
+}
+
+// Result of a `ctx.send` which failed, `{ok = false, kind = error}` with rich send results.
+pub(crate) fn send_error(error: &str, retry_after: Option<Duration>, rich: bool) -> LuaMessage {
+    let mut t = HashMap::new();
+    if rich {
+        t.insert("ok".to_string(), LuaMessage::from(false));
+        t.insert("kind".to_string(), LuaMessage::from(error));
+    } else {
+        t.insert("error".to_string(), LuaMessage::from(error));
+    }
+    if let Some(retry_after) = retry_after {
+        t.insert(
+            "retry_after_ms".to_string(),
+            LuaMessage::from(retry_after.as_millis() as i64),
+        );
+    }
+    LuaMessage::from(t)
+}
+
+// The error returned by `ctx.send` as `nil, err` when the message can't be delivered.
+fn send_failure(recipient_name: &str, e: &MailboxError) -> LuaMessage {
+    let (kind, message) = match e {
+        MailboxError::Closed => (
+            "closed",
+            format!("the mailbox of {} is closed", recipient_name),
+        ),
+        MailboxError::Timeout => (
+            "timeout",
+            format!("{} didn't reply in time", recipient_name),
+        ),
+    };
+    let mut t = HashMap::new();
+    t.insert("kind".to_string(), LuaMessage::from(kind));
+    t.insert("message".to_string(), LuaMessage::from(message));
+    LuaMessage::from(t)
+}
+
+// The error returned by `ctx.send` as `nil, err` while the circuit breaker of the recipient is
+// open.
+fn circuit_open(recipient_name: &str, retry_after: Duration) -> LuaMessage {
+    let mut t = HashMap::new();
+    t.insert("kind".to_string(), LuaMessage::from("circuit_open"));
+    t.insert(
+        "message".to_string(),
+        LuaMessage::from(format!("the circuit breaker of {} is open", recipient_name)),
+    );
+    t.insert(
+        "retry_after_ms".to_string(),
+        LuaMessage::from(retry_after.as_millis() as i64),
+    );
+    LuaMessage::from(t)
+}
+
+// The error returned by `ctx.send` as `nil, err` when there is no recipient with this name.
+fn unknown_recipient(recipient_name: &str) -> LuaMessage {
+    let mut t = HashMap::new();
+    t.insert("kind".to_string(), LuaMessage::from("unknown_recipient"));
+    t.insert(
+        "message".to_string(),
+        LuaMessage::from(format!("no recipient named {}", recipient_name)),
+    );
+    LuaMessage::from(t)
+}
+
+// The envelope returned by `ctx.send` with rich send results.
+fn send_envelope(res: Result<Result<LuaMessage, HandleError>, MailboxError>) -> LuaMessage {
+    let (kind, detail) = match res {
+        // the reply of a failed `handle` script, sent with `LuaMessage`
+        Ok(Ok(LuaMessage::Error { message, .. })) => ("error", Some(message)),
+        Ok(Ok(value)) => {
+            let mut t = HashMap::new();
+            t.insert("ok".to_string(), LuaMessage::from(true));
+            // leave `value` absent for nil, it would be `ctx.null` otherwise
+            if value != LuaMessage::Nil {
+                t.insert("value".to_string(), value);
+            }
+            return LuaMessage::from(t);
+        }
+        Ok(Err(HandleError::NoHandler)) => ("no_handler", None),
+        Ok(Err(HandleError::Error(detail))) => ("error", Some(detail)),
+        Err(MailboxError::Timeout) => ("timeout", None),
+        Err(MailboxError::Closed) => ("mailbox_closed", None),
+    };
+    let mut msg = send_error(kind, None, true);
+    if let (LuaMessage::Table(ref mut t), Some(detail)) = (&mut msg, detail) {
+        t.insert("detail".to_string(), LuaMessage::from(detail));
+    }
+    msg
+}
+
+// `Send` as the actor may be built on another thread, see `LuaActorBuilder::spawn`.
+type SendFuture =
+    Box<dyn Future<Item = Result<LuaMessage, HandleError>, Error = MailboxError> + Send>;
+
+impl Handler<SendAttempt> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, attempt: SendAttempt, ctx: &mut Context<Self>) -> Self::Result {
+        if self.children.replacing(&attempt.recipient_name) {
+            let name = attempt.recipient_name.clone();
+            if let Err(held) = self.children.hold(&name, Held::Send(attempt)) {
+                self.reject_held(ctx, held);
+            }
+            return LuaMessage::Nil;
+        }
+        let SendAttempt {
+            recipient_name,
+            msg,
+            cb_thread_id,
+            vm_generation,
+        } = attempt;
+        let current = vm_generation == self.vm_generation;
+
+        if let Some(ref config) = self.circuit_breaker {
+            // a probe without a send timeout gets as long as the cooldown
+            let probe_timeout = self.send_timeout.unwrap_or(config.cooldown);
+            let breaker = self
+                .breakers
+                .entry(recipient_name.clone())
+                .or_insert_with(|| CircuitBreaker::new(config.clone(), probe_timeout));
+            if let Err(retry_after) = breaker.check(Instant::now()) {
+                if current {
+                    // the replies which arrived before go first
+                    self.resume_sends(ctx);
+                    if self.rich_send_results {
+                        let msg = send_error("circuit_open", Some(retry_after), true);
+                        self.resume(ctx, msg, cb_thread_id);
+                    } else {
+                        let err = circuit_open(&recipient_name, retry_after);
+                        self.resume_with(ctx, cb_thread_id, vec![LuaMessage::Nil, err]);
+                    }
+                }
+                return LuaMessage::Nil;
+            }
+        }
+
+        let timeout = self.send_timeout;
+        let fut: Option<SendFuture> = match self.recipients.get_try(&recipient_name) {
+            // only rich results need the reason of a failure
+            Some(rec) if self.rich_send_results => {
+                let req = rec.send(TryLuaMessage(msg));
+                Some(match timeout {
+                    Some(timeout) => Box::new(req.timeout(timeout)),
+                    None => Box::new(req),
+                })
+            }
+            _ => self.recipients.get(&recipient_name).map(|rec| {
+                let req = rec.send(msg);
+                let fut: SendFuture = match timeout {
+                    Some(timeout) => Box::new(req.timeout(timeout).map(Ok)),
+                    None => Box::new(req.map(Ok)),
+                };
+                fut
+            }),
+        };
+        let fut = match fut {
+            Some(fut) => fut,
+            // e.g. a recipient removed since, or a name which was never added
+            None => {
+                if current {
+                    self.resume_sends(ctx);
+                    if self.rich_send_results {
+                        let msg = send_error("unknown_recipient", None, true);
+                        self.resume(ctx, msg, cb_thread_id);
+                    } else {
+                        let err = unknown_recipient(&recipient_name);
+                        self.resume_with(ctx, cb_thread_id, vec![LuaMessage::Nil, err]);
+                    }
+                }
+                return LuaMessage::Nil;
+            }
+        };
+        if !current {
+            // the coroutine was dropped, only the message is delivered
+            return LuaMessage::Nil;
+        }
+        if self.pending_sends.is_empty() {
+            ctx.spawn(PollSends);
+        }
+        self.pending_sends.push(PendingSend {
+            recipient_name,
+            cb_thread_id,
+            fut,
+        });
+
+        LuaMessage::Nil
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_timer::Delay;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tokio::prelude::Future;
+
+    use crate::builder::LuaActorBuilder;
+    use crate::gc::GetLuaMemory;
+    use crate::message::{ConversionOptions, ZeroBased};
+    use crate::runtime::{HookOutcome, RETURN_CONVERSIONS};
+    use rlua::Value;
+
+    fn lua_actor_with_handle(script: &str) -> LuaActor {
+        LuaActorBuilder::new()
+            .on_handle_with_lua(script)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn lua_actor_basic() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(r#"return ctx.msg + 1"#).start();
+
+        let l = lua_addr.send(LuaMessage::from(1));
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res, LuaMessage::from(2));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_syntax_error() {
+        let res = LuaActorBuilder::new()
+            .on_handle_with_lua(r"return 1+")
+            .build();
+
+        if res.is_ok() {
+            panic!("should return Err(syntax_error)");
+        }
+    }
+
+    #[test]
+    fn lua_actor_user_error() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        if ctx.msg == 0 then
+            error("foo")
+        end
+        return ctx.msg
+        "#,
+        )
+        .start();
+
+        // the actor survives the error
+        let l = lua_addr
+            .send(LuaMessage::from(0))
+            .join(lua_addr.send(LuaMessage::from(1)))
+            .map(|(error, res)| {
+                assert!(error.is_error());
+                match error {
+                    LuaMessage::Error { message, traceback } => {
+                        assert!(message.contains("foo"), "{}", message);
+                        assert!(traceback.is_some());
+                    }
+                    error => panic!("expected an error, got {:?}", error),
+                }
+                assert_eq!(res, LuaMessage::from(1));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_return_function() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        if ctx.msg == 0 then
+            return function() end
+        end
+        return ctx.msg
+        "#,
+        )
+        .start();
+
+        // the reply can't be converted, the actor survives
+        let l = lua_addr
+            .send(LuaMessage::from(0))
+            .join(lua_addr.send(LuaMessage::from(1)))
+            .map(|(error, res)| {
+                match error {
+                    LuaMessage::Error { message, .. } => {
+                        assert!(message.contains("function"), "{}", message)
+                    }
+                    error => panic!("expected an error, got {:?}", error),
+                }
+                assert_eq!(res, LuaMessage::from(1));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_handle_timeout() {
+        let system = System::new("test");
+
+        let lua_addr = LuaActorBuilder::new()
+            .with_handle_timeout(Duration::from_millis(100))
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "loop" then
+                    while true do end
+                end
+                return ctx.msg
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let start = Instant::now();
+        let l = lua_addr
+            .send(LuaMessage::from("loop"))
+            .join(lua_addr.send(LuaMessage::from(1)))
+            .map(move |(error, res)| {
+                assert!(start.elapsed() < Duration::from_secs(1));
+                match error {
+                    LuaMessage::Error { message, .. } => {
+                        assert!(message.contains("timeout"), "{}", message)
+                    }
+                    error => panic!("expected an error, got {:?}", error),
+                }
+                // the actor survives the timeout
+                assert_eq!(res, LuaMessage::from(1));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_return_table() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        return {x = 1}
+        "#,
+        )
+        .start();
+
+        let l = lua_addr.send(LuaMessage::from(3));
+        Arbiter::spawn(
+            l.map(|res| {
+                let mut t = HashMap::new();
+                t.insert("x".to_string(), LuaMessage::from(1));
+
+                assert_eq!(res, LuaMessage::from(t));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_array() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        table.insert(ctx.msg, "b")
+        return ctx.msg
+        "#,
+        )
+        .start();
+
+        let l = lua_addr.send(LuaMessage::from(vec![
+            LuaMessage::from(1),
+            LuaMessage::from("a"),
+        ]));
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(
+                    res,
+                    LuaMessage::Array(vec![
+                        LuaMessage::from(1),
+                        LuaMessage::from("a"),
+                        LuaMessage::from("b"),
+                    ])
+                );
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_state() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        if not ctx.state.x then ctx.state.x = 0 end
+
+        ctx.state.x = ctx.state.x + 1
+        return ctx.state.x
+        "#,
+        )
+        .start();
+
+        let l = lua_addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(move |res| {
+                assert_eq!(res, LuaMessage::from(1));
+                let l2 = lua_addr.send(LuaMessage::Nil);
+                Arbiter::spawn(
+                    l2.map(|res| {
+                        assert_eq!(res, LuaMessage::from(2));
+                        System::current().stop();
+                    })
+                    .map_err(|e| println!("actor dead {}", e)),
+                );
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_notify() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+            ctx.notify(100)
+            "#,
+            )
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == 100 then
+                ctx.state.notified = ctx.msg
+            end
+
+            return ctx.msg + ctx.state.notified
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let delay = Delay::new(Duration::from_secs(1)).map(move |()| {
+            let l = addr.send(LuaMessage::from(1));
+            Arbiter::spawn(
+                l.map(|res| {
+                    assert_eq!(res, LuaMessage::from(101));
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+            )
+        });
+        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_notify_later() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+            ctx.notify_later(100, 1)
+            "#,
+            )
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == 100 then
+                ctx.state.notified = ctx.msg
+            end
+
+            return ctx.msg + ctx.state.notified
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let delay = Delay::new(Duration::from_secs(2)).map(move |()| {
+            let l2 = addr.send(LuaMessage::from(1));
+            Arbiter::spawn(
+                l2.map(|res| {
+                    assert_eq!(res, LuaMessage::from(101));
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+            )
+        });
+        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_send() {
+        use std::mem::discriminant;
+        let system = System::new("test");
+
+        struct Callback;
+        impl Actor for Callback {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Callback {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                // check msg type
+                assert_eq!(
+                    discriminant(&msg),
+                    discriminant(&LuaMessage::String("foo".to_string()))
+                );
+                if let LuaMessage::String(s) = msg {
+                    assert_eq!(s, "Hello");
+                    System::current().stop();
+                    LuaMessage::Boolean(true)
+                } else {
+                    unimplemented!()
+                }
+            }
+        }
+        let callback_addr = Callback.start();
+
+        let mut actor = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+            local result = ctx.send("callback", "Hello")
+            print("result", "=", result)
+            "#,
+            )
+            .build()
+            .unwrap();
+
+        actor
+            .try_add_recipients("callback", callback_addr.recipient())
+            .unwrap();
+        actor.start();
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_thread_yield() {
+        use std::mem::discriminant;
+        struct Callback;
+        impl Actor for Callback {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Callback {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, _: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                LuaMessage::Nil
+            }
+        }
+
+        let system = System::new("test");
+
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local result = ctx.send("callback", "Hello")
+            print(result)
+            return result
+            "#,
+            )
+            .build()
+            .unwrap();
+
+        actor
+            .try_add_recipients("callback", Callback.start().recipient())
+            .unwrap();
+
+        let addr = actor.start();
+
+        let l = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(move |res| {
+                assert_eq!(
+                    discriminant(&res),
+                    discriminant(&HookOutcome::Suspended(0).into_message())
+                );
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_thread_yield_and_callback_message() {
+        use std::mem::discriminant;
+
+        struct Callback;
+        impl Actor for Callback {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Callback {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                // check msg type
+                assert_eq!(
+                    discriminant(&msg),
+                    discriminant(&LuaMessage::String("foo".to_string()))
+                );
+                if let LuaMessage::String(s) = msg {
+                    assert_eq!(s, "Hello");
+                    LuaMessage::String(format!("{} from callback", s))
+                } else {
+                    unimplemented!()
+                }
+            }
+        }
+
+        struct Check;
+        impl Actor for Check {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Check {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                // check msg type
+                assert_eq!(
+                    discriminant(&msg),
+                    discriminant(&LuaMessage::String("foo".to_string()))
+                );
+                if let LuaMessage::String(s) = msg {
+                    assert_eq!(s, "Hello from callback");
+                    System::current().stop();
+                    LuaMessage::Nil
+                } else {
+                    unimplemented!()
+                }
+            }
+        }
+
+        let system = System::new("test");
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local result = ctx.send("callback", ctx.msg)
+            print("send result", "=", result)
+            ctx.send("check", result)
+            "#,
+            )
+            .build()
+            .unwrap();
+
+        actor
+            .try_add_recipients("callback", Callback.start().recipient())
+            .unwrap();
+        actor
+            .try_add_recipients("check", Check.start().recipient())
+            .unwrap();
+
+        let addr = actor.start();
+
+        let l = addr.send(LuaMessage::String("Hello".to_string()));
+        Arbiter::spawn(
+            l.map(move |res| {
+                assert_eq!(
+                    discriminant(&res),
+                    discriminant(&HookOutcome::Suspended(0).into_message())
+                );
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_do_send() {
+        use std::mem::discriminant;
+
+        struct Check;
+        impl Actor for Check {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Check {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                // check msg type
+                assert_eq!(
+                    discriminant(&msg),
+                    discriminant(&LuaMessage::String("foo".to_string()))
+                );
+                if let LuaMessage::String(s) = msg {
+                    assert_eq!(s, "Hello");
+                    System::current().stop();
+                    LuaMessage::Nil
+                } else {
+                    unimplemented!()
+                }
+            }
+        }
+        let system = System::new("test");
+
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local result = ctx.do_send("check", "Hello")
+            print("new actor addr name", rec, result)
+            return ctx.msg
+            "#,
+            )
+            .build()
+            .unwrap();
+        actor
+            .try_add_recipients("check", Check.start().recipient())
+            .unwrap();
+        let addr = actor.start();
+
+        let l = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res, LuaMessage::Nil);
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_terminate() {
+        // TODO: validate on_stopped is called
+        let system = System::new("test");
+
+        let _ = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+            ctx.terminate()
+            "#,
+            )
+            .on_stopped_with_lua(r#"print("stopped")"#)
+            .build()
+            .unwrap()
+            .start();
+        let delay = Delay::new(Duration::from_secs(1)).map(move |()| {
+            System::current().stop();
+        });
+        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_require() {
+        let system = System::new("test");
+
+        // each actor has its own search path
+        let actor = |path| {
+            LuaActorBuilder::new()
+                .on_handle_with_lua(
+                    r#"
+                if ctx.msg == "restart" then
+                    ctx.restart()
+                    return
+                end
+                local ok, m = pcall(require, ctx.msg.module)
+                return ok and m.incr(ctx.msg.value) or package.path
+            "#,
+                )
+                .with_package_path(path)
+        };
+        let src = actor("./src/?.lua").build().unwrap().start();
+        let lua = actor("./src/lua/?.lua").build().unwrap().start();
+        assert!(actor("./src/?.lua").with_sandbox(true).build().is_err());
+
+        let restarted = src.clone();
+        let l = src
+            .send(crate::lua_msg!({ module: "lua/test/module", value: 1 }))
+            .join3(
+                lua.send(crate::lua_msg!({ module: "test/module", value: 1 })),
+                lua.send(crate::lua_msg!({ module: "lua/test/module", value: 1 })),
+            )
+            .and_then(move |(src, lua, missing)| {
+                assert_eq!(src, LuaMessage::from(2));
+                assert_eq!(lua, LuaMessage::from(2));
+                assert_eq!(missing, LuaMessage::from("./src/lua/?.lua"));
+                // the rebuilt VM has the same search path
+                restarted
+                    .send(LuaMessage::from("restart"))
+                    .and_then(move |_| {
+                        restarted.send(crate::lua_msg!({ module: "lua/test/module", value: 2 }))
+                    })
+                    .map(|res| assert_eq!(res, LuaMessage::from(3)))
+            })
+            .map(|_| System::current().stop());
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_preload_module() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "restart" then
+                    ctx.restart()
+                    return
+                end
+                local loads = __loads or 0
+                local greet = require("greet")
+                local math = require("util.math")
+                return { greet(math.double(ctx.msg)), require("greet") == greet, __loads - loads }
+            "#,
+            )
+            .preload_module(
+                "greet",
+                r#"
+                __loads = (__loads or 0) + 1
+                return function (n) return "hello " .. n end
+            "#,
+            )
+            .preload_module(
+                "util.math",
+                r#"return { double = function (n) return n * 2 end }"#,
+            )
+            .with_sandbox(true)
+            .build()
+            .unwrap()
+            .start();
+        match LuaActorBuilder::new()
+            .preload_module("broken", "return {")
+            .build()
+        {
+            Err(e) => assert!(e.to_string().contains("preloaded module broken"), "{}", e),
+            Ok(_) => panic!("built with a syntax error"),
+        }
+
+        let restarted = addr.clone();
+        let l = addr
+            .send(LuaMessage::from(2))
+            .join(addr.send(LuaMessage::from(3)))
+            .and_then(move |(first, second)| {
+                // loaded once, by the first `require`
+                assert_eq!(first, crate::lua_msg!(["hello 4", true, 1]));
+                assert_eq!(second, crate::lua_msg!(["hello 6", true, 0]));
+                restarted
+                    .send(LuaMessage::from("restart"))
+                    .and_then(move |_| restarted.send(LuaMessage::from(1)))
+                    .map(|res| assert_eq!(res, crate::lua_msg!(["hello 2", true, 1])))
+            })
+            .map(|_| System::current().stop());
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_with_global() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_global("threshold", LuaMessage::from(5))
+            .with_global("threshold", LuaMessage::from(10))
+            .with_global(
+                "config",
+                crate::lua_msg!({ api_key: "secret", flags: ["beta"] }),
+            )
+            .with_strict_globals(true)
+            .on_started_with_lua(r#"ctx.state.key = config.api_key"#)
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "restart" then
+                    ctx.restart()
+                    return
+                end
+                return { ctx.msg + threshold, ctx.state.key, config.flags[1] }
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let restarted = addr.clone();
+        let l = addr
+            .send(LuaMessage::from(1))
+            .and_then(move |res| {
+                assert_eq!(res, crate::lua_msg!([11, "secret", "beta"]));
+                restarted
+                    .send(LuaMessage::from("restart"))
+                    .and_then(move |_| restarted.send(LuaMessage::from(2)))
+            })
+            .map(|res| {
+                assert_eq!(res, crate::lua_msg!([12, "secret", "beta"]));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_compat_shims() {
+        let system = System::new("test");
+
+        // written for Lua 5.1
+        let script = r#"
+            local t = { 3, 1, 2 }
+            table.sort(t)
+            local a, b = unpack(t)
+            local n = loadstring("return " .. table.getn(t))()
+            return { a + b, n, math.pow(2, 3), math.mod(7, 3), ctx.msg / 2 }
+        "#;
+        let actor = |compat| {
+            LuaActorBuilder::new()
+                .with_compat_shims(compat)
+                .on_handle_with_lua(script)
+                .build()
+                .unwrap()
+                .start()
+        };
+
+        let l = actor(true)
+            .send(LuaMessage::from(4))
+            .join(actor(false).send(LuaMessage::from(4)))
+            .map(|(shimmed, plain)| {
+                // the integral floats of `math.pow` and `/` are integers
+                assert_eq!(shimmed, crate::lua_msg!([3, 3, 8, 1, 2]));
+                match plain {
+                    LuaMessage::Error { message, .. } => assert!(
+                        message.contains("unpack is a Lua 5.1 function, enable compat shims"),
+                        "{}",
+                        message
+                    ),
+                    res => panic!("expected an error, got {:?}", res),
+                }
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_sandbox() {
+        let system = System::new("test");
+
+        let actor = |sandbox| {
+            LuaActorBuilder::new()
+                .on_handle_with_lua(
+                    r#"
+                if ctx.msg == "restart" then
+                    ctx.restart()
+                    return
+                end
+                package.preload.greeting = function () return "hi" end
+                local bytecode = string.dump(function () return 1 end)
+                return {
+                    os = os == nil,
+                    io = io == nil,
+                    debug = debug == nil,
+                    dofile = dofile == nil,
+                    loadlib = package.loadlib == nil,
+                    module = not pcall(require, "lua/test/module"),
+                    preload = require("greeting"),
+                    bytecode = load(bytecode) == nil,
+                    source = load("return 1")(),
+                    stdlib = string.rep("a", 2) .. table.concat({1, 2}) .. math.max(1, 3),
+                }
+                "#,
+                )
+                .with_sandbox(sandbox)
+        };
+        let sandboxed = actor(true).build().unwrap().start();
+        let unsandboxed = actor(false)
+            .with_package_path("./src/?.lua")
+            .build()
+            .unwrap()
+            .start();
+        assert!(actor(true).build_with_vm(Lua::new()).is_err());
+
+        let expected = crate::lua_msg!({
+            os: true,
+            io: true,
+            debug: true,
+            dofile: true,
+            loadlib: true,
+            module: true,
+            preload: "hi",
+            bytecode: true,
+            source: 1,
+            stdlib: "aa123",
+        });
+        let restarted = sandboxed.clone();
+        let l = sandboxed
+            .send(LuaMessage::from("check"))
+            .join(unsandboxed.send(LuaMessage::from("check")))
+            .and_then(move |(sandboxed, unsandboxed)| {
+                assert_eq!(sandboxed, expected);
+                assert_eq!(unsandboxed.get("os"), Some(&LuaMessage::from(false)));
+                assert_eq!(unsandboxed.get("module"), Some(&LuaMessage::from(false)));
+                assert_eq!(unsandboxed.get("stdlib"), Some(&LuaMessage::from("aa123")));
+                // the rebuilt VM is sandboxed too
+                restarted
+                    .send(LuaMessage::from("restart"))
+                    .and_then(move |_| restarted.send(LuaMessage::from("check")))
+                    .map(move |res| assert_eq!(res, expected))
+            })
+            .map(|_| System::current().stop());
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_with_vm() {
+        let system = System::new("test");
+
+        let vm = Lua::new();
+        vm.context(|ctx| {
+            ctx.globals()
+                .set(
+                    "greet",
+                    ctx.create_function(|_, name: String| Ok(format!("Hello, {}!", name)))
+                        .unwrap(),
+                )
+                .unwrap();
+        });
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            return greet(ctx.msg)
+            "#,
+            )
+            .build_with_vm(vm)
+            .unwrap()
+            .start();
+
+        let l = addr.send(LuaMessage::from("World"));
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res, LuaMessage::from("Hello, World!"));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_with_function() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_function("greet", |args: Vec<LuaMessage>| match args.as_slice() {
+                [LuaMessage::String(name)] => Ok(LuaMessage::from(format!("Hello, {}!", name))),
+                _ => Err(format!(
+                    "greet: expected a name, got {} arguments",
+                    args.len()
+                )),
+            })
+            .with_function("sum", |args: Vec<LuaMessage>| {
+                let mut sum = 0;
+                for arg in args {
+                    match arg {
+                        LuaMessage::Integer(n) => sum += n,
+                        _ => return Err("sum: expected integers"),
+                    }
+                }
+                Ok(LuaMessage::from(sum))
+            })
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == "errors" then
+                local _, greet_err = pcall(greet, "a", "b")
+                local _, sum_err = pcall(sum, 1, "2")
+                return tostring(greet_err) .. "; " .. tostring(sum_err)
+            end
+            return greet(ctx.msg) .. " " .. sum(1, 2, 3)
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from("World"))
+            .join(addr.send(LuaMessage::from("errors")));
+        Arbiter::spawn(
+            l.map(|(greeting, errors)| {
+                assert_eq!(greeting, LuaMessage::from("Hello, World! 6"));
+                if let LuaMessage::String(errors) = errors {
+                    assert!(errors.contains("greet: expected a name, got 2 arguments"));
+                    assert!(errors.contains("sum: expected integers"));
+                } else {
+                    panic!("expected the error messages, got {:?}", errors);
+                }
+                System::current().stop();
+            })
+            .map_err(|e| panic!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_reply_expected() {
+        struct Check;
+        impl Actor for Check {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Check {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                let mut t = HashMap::new();
+                t.insert("do_send".to_string(), LuaMessage::from(false));
+                t.insert("send".to_string(), LuaMessage::from(true));
+                assert_eq!(msg, LuaMessage::from(t));
+                System::current().stop();
+                LuaMessage::Nil
+            }
+        }
+
+        let system = System::new("test");
+
+        let mut sibling = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            ctx.state[ctx.msg] = ctx.reply_expected
+            if ctx.state.send ~= nil and ctx.state.do_send ~= nil then
+                ctx.do_send("check", ctx.state)
+            end
+            "#,
+            )
+            .build()
+            .unwrap();
+        sibling
+            .try_add_recipients("check", Check.start().recipient())
+            .unwrap();
+
+        let mut actor = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+            ctx.do_send("sibling", "do_send")
+            ctx.send("sibling", "send")
+            "#,
+            )
+            .build()
+            .unwrap();
+        actor
+            .try_add_recipients("sibling", sibling.start().recipient())
+            .unwrap();
+        actor.start();
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_skip_conversion_without_reply() {
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(r#"return { value = ctx.reply_expected }"#).start();
+        let before = RETURN_CONVERSIONS.with(|c| c.get());
+
+        addr.do_send(LuaMessage::Nil);
+        addr.do_send(LuaMessage::Nil);
+        let l = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(move |res| {
+                let mut t = HashMap::new();
+                t.insert("value".to_string(), LuaMessage::from(true));
+                assert_eq!(res, LuaMessage::from(t));
+                // only the `send` result is converted
+                assert_eq!(RETURN_CONVERSIONS.with(|c| c.get()) - before, 1);
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_recipient_names() {
+        struct Callback;
+        impl Actor for Callback {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Callback {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, _: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                LuaMessage::Nil
+            }
+        }
+
+        let _system = System::new("test");
+        let rec = Callback.start().recipient::<LuaMessage>();
+
+        let mut actor = LuaActorBuilder::new()
+            .with_max_recipient_name_len(16)
+            .build()
+            .unwrap();
+
+        for name in &[
+            "callback",
+            "db-1",
+            "primary.db",
+            "svc:a_b",
+            "lua/test/module",
+        ] {
+            assert!(actor.try_add_recipients(name, rec.clone()).is_ok());
+        }
+
+        let rejected = |name: &str, rule: &str| {
+            let mut actor = LuaActorBuilder::new()
+                .with_max_recipient_name_len(16)
+                .build()
+                .unwrap();
+            match actor.try_add_recipients(name, rec.clone()) {
+                Err(LuaError::RuntimeError(e)) => assert!(e.contains(rule), "{}", e),
+                res => panic!("{:?} should be rejected, got {:?}", name, res.is_ok()),
+            }
+            assert!(actor.recipients.is_empty());
+        };
+        rejected("", "empty");
+        rejected("a_very_long_recipient_name", "longer than 16 bytes");
+        rejected("bad\nname", "characters");
+        rejected("bad name", "characters");
+        rejected("self", "reserved");
+    }
+
+    #[test]
+    fn lua_actor_invalid_recipient_name_from_lua() {
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(
+            r#"
+            local ok, err = pcall(ctx.do_send, "bad\nname", 1)
+            assert(not ok)
+            return tostring(err)
+            "#,
+        )
+        .start();
+
+        let l = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(|res| {
+                if let LuaMessage::String(s) = res {
+                    assert!(s.contains("invalid recipient name"), "{}", s);
+                } else {
+                    panic!("unexpected result {:?}", res);
+                }
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    // XOR every byte so the snapshot is not readable without the codec
+    struct XorCodec;
+
+    impl StateCodec for XorCodec {
+        fn encode(&self, msg: &LuaMessage) -> Result<Vec<u8>, LuaError> {
+            let text = match msg {
+                LuaMessage::Table(t) => t
+                    .iter()
+                    .map(|(k, v)| match v {
+                        LuaMessage::Integer(n) => Ok(format!("{}={}", k, n)),
+                        _ => Err(LuaError::RuntimeError("unsupported".to_string())),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join(","),
+                _ => return Err(LuaError::RuntimeError("unsupported".to_string())),
+            };
+            Ok(text.bytes().map(|b| b ^ 0x5a).collect())
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<LuaMessage, LuaError> {
+            let text = String::from_utf8(bytes.iter().map(|b| b ^ 0x5a).collect())
+                .map_err(LuaError::external)?;
+            let mut t = HashMap::new();
+            for pair in text.split(',').filter(|p| !p.is_empty()) {
+                let mut kv = pair.splitn(2, '=');
+                let k = kv.next().unwrap();
+                let v = kv
+                    .next()
+                    .and_then(|v| v.parse::<i64>().ok())
+                    .ok_or_else(|| LuaError::RuntimeError(format!("corrupt entry {:?}", pair)))?;
+                t.insert(k.to_string(), LuaMessage::from(v));
+            }
+            Ok(LuaMessage::from(t))
+        }
+    }
+
+    #[test]
+    fn lua_actor_state_snapshot() {
+        let actor = lua_actor_with_handle(r#"return ctx.state.x"#);
+        actor
+            .runtime
+            .vm
+            .context(|ctx| ctx.load("ctx.state.x = 42").exec())
+            .unwrap();
+
+        let snapshot = actor.snapshot_state(&XorCodec).unwrap();
+        assert!(!String::from_utf8_lossy(&snapshot).contains("x=42"));
+
+        let mut restored = lua_actor_with_handle(r#"return ctx.state.x"#);
+        restored.restore_state(&XorCodec, &snapshot).unwrap();
+
+        let system = System::new("test");
+        let l = restored.start().send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res, LuaMessage::from(42));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_restore_corrupt_state() {
+        let mut actor = lua_actor_with_handle(r#"return ctx.state.x"#);
+        actor
+            .runtime
+            .vm
+            .context(|ctx| ctx.load("ctx.state.x = 1").exec())
+            .unwrap();
+
+        let corrupt: Vec<u8> = b"x=oops".iter().map(|b| b ^ 0x5a).collect();
+        match actor.restore_state(&XorCodec, &corrupt) {
+            Err(StateError::Codec {
+                subsystem: StateSubsystem::Restore,
+                error,
+            }) => assert!(error.to_string().contains("corrupt entry"), "{}", error),
+            res => panic!("corrupt snapshot should fail, got {:?}", res),
+        }
+
+        // state is left untouched
+        let x = actor
+            .runtime
+            .vm
+            .context(|ctx| ctx.load("return ctx.state.x").eval::<i64>())
+            .unwrap();
+        assert_eq!(x, 1);
+    }
+
+    #[test]
+    fn lua_actor_build_async() {
+        let system = System::new("test");
+
+        let builder = || {
+            LuaActorBuilder::new()
+                .on_started_with_lua(r#"ctx.state.started = true"#)
+                .on_handle("src/lua/test/test.lua")
+        };
+
+        let sync_addr = builder().build().unwrap().start();
+        let fut = builder()
+            .build_async()
+            .map_err(|e| panic!("build failed {:?}", e))
+            .and_then(move |actor| {
+                let async_addr = actor.start();
+                sync_addr
+                    .send(LuaMessage::from(1))
+                    .join(async_addr.send(LuaMessage::from(1)))
+                    .map_err(|e| println!("actor dead {}", e))
+            })
+            .map(|(sync_res, async_res)| {
+                assert_eq!(sync_res, LuaMessage::from(421));
+                assert_eq!(sync_res, async_res);
+                System::current().stop();
+            });
+        Arbiter::spawn(fut);
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_build_async_does_not_block() {
+        use std::time::Instant;
+
+        let system = System::new("test");
+
+        let huge = "x = 1 + 2\n".repeat(500_000);
+        let start = Instant::now();
+        let build = LuaActorBuilder::new()
+            .on_handle_with_lua(&huge)
+            .build_async()
+            .map(|_| ())
+            .map_err(|e| panic!("build failed {:?}", e));
+        let timer = Delay::new(Duration::from_millis(50))
+            .map(move |()| {
+                // the timer fires on schedule while the script compiles
+                assert!(start.elapsed() < Duration::from_millis(500));
+            })
+            .map_err(|e| panic!("timer failed {:?}", e));
+        Arbiter::spawn(timer.join(build).map(|_| System::current().stop()));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_strict_globals() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_strict_globals(true)
+            .on_started_with_lua(
+                r#"
+            declare_global("counter", 0)
+            "#,
+            )
+            .on_handle_with_lua(
+                r#"
+            local read_ok, read_err = pcall(function () return undefined_var end)
+            local write_ok, write_err = pcall(function () leaked = 1 end)
+            counter = counter + 1
+            declare_global("later")
+            later = "declared"
+            return {
+                read_ok = read_ok,
+                read_err = read_err,
+                write_ok = write_ok,
+                write_err = write_err,
+                counter = counter,
+                later = later,
+            }
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(|res| {
+                let t = match res {
+                    LuaMessage::Table(t) => t,
+                    res => panic!("unexpected result {:?}", res),
+                };
+                assert_eq!(t["read_ok"], LuaMessage::from(false));
+                assert_eq!(
+                    t["read_err"],
+                    LuaMessage::from(r#"[string "handle"]:2: undefined global 'undefined_var'"#)
+                );
+                assert_eq!(t["write_ok"], LuaMessage::from(false));
+                assert_eq!(
+                    t["write_err"],
+                    LuaMessage::from(
+                        r#"[string "handle"]:3: assignment to undeclared global 'leaked'"#
+                    )
+                );
+                assert_eq!(t["counter"], LuaMessage::from(1));
+                assert_eq!(t["later"], LuaMessage::from("declared"));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_globals_not_strict_by_default() {
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(
+            r#"
+            leaked = (leaked or 0) + 1
+            return leaked
+            "#,
+        )
+        .start();
+
+        let l = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res, LuaMessage::from(1));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_watchdog() {
+        use crate::watchdog;
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+
+        let system = System::new("test");
+
+        let vm = Lua::new();
+        vm.context(|ctx| {
+            ctx.globals()
+                .set(
+                    "block",
+                    ctx.create_function(|_, ms: u64| {
+                        thread::sleep(Duration::from_millis(ms));
+                        Ok(())
+                    })
+                    .unwrap(),
+                )
+                .unwrap();
+        });
+
+        let stuck = Arc::new(Mutex::new(vec![]));
+        let stuck2 = stuck.clone();
+        let addr = LuaActorBuilder::new()
+            .with_name("watched")
+            .with_watchdog(Duration::from_millis(100))
+            .with_watchdog_callback(move |s| stuck2.lock().unwrap().push(s.clone()))
+            .on_handle_with_lua(r#"block(ctx.msg)"#)
+            .build_with_vm(vm)
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from(10))
+            .and_then(move |_| {
+                // normal operation reports nothing and leaves nothing registered
+                assert!(!watchdog::is_registered("watched"));
+                assert!(stuck.lock().unwrap().is_empty());
+                addr.send(LuaMessage::from(300)).map(|_| stuck)
+            })
+            .map(|stuck| {
+                assert!(!watchdog::is_registered("watched"));
+                let stuck = stuck.lock().unwrap();
+                assert_eq!(stuck.len(), 1);
+                assert_eq!(stuck[0].actor, "watched");
+                assert_eq!(stuck[0].hook, "handle");
+                // reported once, while the hook was blocked
+                assert!(stuck[0].elapsed >= Duration::from_millis(100));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    fn restart_actor(keep_state: bool) -> Addr<LuaActor> {
+        LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.state.started = (ctx.state.started or 0) + 1"#)
+            .on_handle_with_lua(&format!(
+                r#"
+            if ctx.msg == "poison" then
+                ctx.restart({})
+                return
+            end
+            ctx.state.count = (ctx.state.count or 0) + 1
+            return ctx.state
+            "#,
+                keep_state
+            ))
+            .build()
+            .unwrap()
+            .start()
+    }
+
+    fn state(started: i64, count: i64) -> LuaMessage {
+        let mut t = HashMap::new();
+        t.insert("started".to_string(), LuaMessage::from(started));
+        t.insert("count".to_string(), LuaMessage::from(count));
+        LuaMessage::from(t)
+    }
+
+    #[test]
+    fn lua_actor_restart() {
+        let system = System::new("test");
+
+        let addr = restart_actor(false);
+        let first = addr.send(LuaMessage::Nil);
+        // queued behind the restart
+        addr.do_send(LuaMessage::from("poison"));
+        let after = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            first
+                .join(after)
+                .map(|(first, after)| {
+                    assert_eq!(first, state(1, 1));
+                    assert_eq!(after, state(1, 1));
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_restart_keep_state() {
+        let system = System::new("test");
+
+        let addr = restart_actor(true);
+        let first = addr.send(LuaMessage::Nil);
+        addr.do_send(LuaMessage::from("poison"));
+        let after = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            first
+                .join(after)
+                .map(|(first, after)| {
+                    assert_eq!(first, state(1, 1));
+                    assert_eq!(after, state(2, 2));
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_restart_fails_suspended() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "wait" then
+                    local ok, err = ctx.try(ctx.send, "slow", 1)
+                    ctx.state.failed = err
+                    return
+                elseif ctx.msg == "poison" then
+                    ctx.restart(true)
+                    return
+                end
+                return ctx.state.failed
+                "#,
+            )
+            .with_recipient(
+                "slow",
+                Child {
+                    name: "slow",
+                    delay: Duration::from_secs(1),
+                }
+                .start()
+                .recipient(),
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        addr.do_send(LuaMessage::from("wait"));
+        addr.do_send(LuaMessage::from("poison"));
+        let l = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(|res| {
+                // the error handler ran before the state was kept
+                assert_eq!(res, LuaMessage::from("actor restarted"));
+                System::current().stop();
+            })
+            .map_err(|e| panic!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_restart_with_vm() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return tostring(select(2, pcall(ctx.restart)))"#)
+            .build_with_vm(Lua::new())
+            .unwrap()
+            .start();
+
+        let l = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(
+                    res,
+                    LuaMessage::from(
+                        "runtime error: ctx.restart is not available for actors built with build_with_vm"
+                    )
+                );
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_schedule_restart() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "schedule" then
+                    return ctx.schedule("0 0 * * *", "midnight", {name = "nightly"})
+                elseif ctx.msg == "restart" then
+                    ctx.restart()
+                else
+                    return ctx.unschedule(ctx.msg)
+                end
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let scheduled = addr.send(LuaMessage::from("schedule"));
+        addr.do_send(LuaMessage::from("restart"));
+        // the schedule survives the restart
+        let removed = addr.send(LuaMessage::from("nightly"));
+        let again = addr.send(LuaMessage::from("nightly"));
+        Arbiter::spawn(
+            scheduled
+                .join3(removed, again)
+                .map(|(scheduled, removed, again)| {
+                    assert_eq!(scheduled, LuaMessage::from("nightly"));
+                    assert_eq!(removed, LuaMessage::from(true));
+                    assert_eq!(again, LuaMessage::from(false));
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_conversion_bridge() {
+        let system = System::new("test");
+
+        let vm = Lua::new();
+        vm.context(|ctx| {
+            let tag = ctx
+                .create_function(|ctx, value: Value| {
+                    let mut msg = LuaMessage::from_lua_value(value, ctx, None)?;
+                    if let LuaMessage::Table(ref mut t) = msg {
+                        t.insert("tagged".to_string(), LuaMessage::from(true));
+                    }
+                    msg.to_lua_value(ctx, None)
+                })
+                .unwrap();
+            ctx.globals().set("tag", tag).unwrap();
+        });
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return tag({ x = ctx.msg })"#)
+            .build_with_vm(vm)
+            .unwrap()
+            .start();
+
+        let l = addr.send(LuaMessage::from(1));
+        Arbiter::spawn(
+            l.map(|res| {
+                let mut t = HashMap::new();
+                t.insert("x".to_string(), LuaMessage::from(1));
+                t.insert("tagged".to_string(), LuaMessage::from(true));
+                assert_eq!(res, LuaMessage::from(t));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_conversion_options() {
+        let system = System::new("test");
+
+        let zero_based = || LuaActorBuilder::new().on_handle_with_lua(r#"return {[0] = "a", "b"}"#);
+        let addr = LuaActorBuilder::new()
+            .with_conversion_options(ConversionOptions {
+                zero_based: ZeroBased::Shift,
+                max_gap: 0,
+            })
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "get" then
+                    return ctx.state.results
+                end
+                ctx.state.results = {
+                    own = {[0] = "x"},
+                    inherited = ctx.send("inherited", 1),
+                    strict = ctx.send("strict", 1),
+                }
+                "#,
+            )
+            .with_child("inherited", zero_based())
+            .with_child(
+                "strict",
+                zero_based().with_conversion_options(ConversionOptions::default()),
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        addr.do_send(LuaMessage::from("run"));
+        // the replies of the children arrive meanwhile
+        let l = Delay::new(Duration::from_millis(100))
+            .then(move |_| addr.send(LuaMessage::from("get")));
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res.get("own"), Some(&crate::lua_msg!(["x"])));
+                assert_eq!(res.get("inherited"), Some(&crate::lua_msg!(["a", "b"])));
+                let mut t = HashMap::new();
+                t.insert("0".to_string(), LuaMessage::from("a"));
+                t.insert("1".to_string(), LuaMessage::from("b"));
+                assert_eq!(res.get("strict"), Some(&LuaMessage::from(t)));
+                System::current().stop();
+            })
+            .map_err(|e| panic!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_null() {
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(
+            r#"
+            assert(ctx.msg.present == ctx.null)
+            assert(ctx.msg.absent == nil)
+            -- the sentinel is truthy
+            assert(ctx.msg.present)
+            assert(ctx.is_null(ctx.msg.present) and ctx.is_null(ctx.msg.absent))
+            assert(not ctx.is_null(false) and not ctx.is_null(0))
+            return { echo = ctx.msg.present, explicit = ctx.null }
+            "#,
+        )
+        .start();
+
+        let mut t = HashMap::new();
+        t.insert("present".to_string(), LuaMessage::Nil);
+        let l = addr.send(LuaMessage::from(t));
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res.get("echo"), Some(&LuaMessage::Nil));
+                assert_eq!(res.get("explicit"), Some(&LuaMessage::Nil));
+                assert_eq!(res.get("absent"), None);
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_notify_coalescing() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                local s = ctx.state
+                if ctx.msg == "start" then
+                    s.individual = 0
+                    s.batches = 0
+                    s.next = 11
+                    s.ordered = true
+                    for i = 1, 500 do
+                        ctx.notify(i)
+                    end
+                elseif ctx.msg == "get" then
+                    return { individual = s.individual, batches = s.batches, next = s.next, ordered = s.ordered }
+                elseif type(ctx.msg) == "table" then
+                    s.batches = s.batches + 1
+                    local i = 1
+                    while ctx.msg.__coalesced[tostring(i)] ~= nil do
+                        s.ordered = s.ordered and ctx.msg.__coalesced[tostring(i)] == s.next
+                        s.next = s.next + 1
+                        i = i + 1
+                    end
+                else
+                    -- actix doesn't keep the order of notifications, only check they come first
+                    s.individual = s.individual + 1
+                    s.ordered = s.ordered and ctx.msg <= 10
+                end
+                "#,
+            )
+            .with_notify_coalescing(10, Duration::from_millis(10))
+            .build()
+            .unwrap()
+            .start();
+
+        addr.do_send(LuaMessage::from("start"));
+        let delay = Delay::new(Duration::from_millis(300)).map(move |()| {
+            let l = addr.send(LuaMessage::from("get"));
+            Arbiter::spawn(
+                l.map(|res| {
+                    assert_eq!(res.get("individual"), Some(&LuaMessage::from(10)));
+                    assert_eq!(res.get("batches"), Some(&LuaMessage::from(1)));
+                    assert_eq!(res.get("next"), Some(&LuaMessage::from(501)));
+                    assert_eq!(res.get("ordered"), Some(&LuaMessage::from(true)));
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+            )
+        });
+        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_api_manifest() {
+        let names = |actor: &LuaActor| -> Vec<&'static str> {
+            actor.api_manifest().iter().map(|e| e.name).collect()
+        };
+        let restartable = LuaActorBuilder::new().build().unwrap();
+        let with_vm = LuaActorBuilder::new().build_with_vm(Lua::new()).unwrap();
+
+        let mut missing: Vec<_> = names(&restartable)
+            .into_iter()
+            .filter(|n| !names(&with_vm).contains(n))
+            .collect();
+        missing.sort();
+        assert_eq!(missing, vec!["restart"]);
+
+        let with_breaker = LuaActorBuilder::new()
+            .with_circuit_breaker(1, Duration::from_secs(1), Duration::from_secs(1))
+            .build()
+            .unwrap();
+        let mut added: Vec<_> = names(&with_breaker)
+            .into_iter()
+            .filter(|n| !names(&restartable).contains(n))
+            .collect();
+        added.sort();
+        assert_eq!(added, vec!["breaker_state"]);
+        assert!(names(&with_vm)
+            .iter()
+            .all(|n| names(&restartable).contains(n)));
+    }
+
+    #[test]
+    fn lua_actor_api_manifest_matches_ctx() {
+        let builder = LuaActorBuilder::new()
+            .with_circuit_breaker(1, Duration::from_secs(1), Duration::from_secs(1))
+            .with_rich_send_results(true)
+            .on_handle_with_lua(
+                r#"
+                local names = {}
+                for name, value in pairs(ctx) do
+                    if type(value) == "function" or name == "util" or name == "math"
+                        or name == "str" then
+                        names[#names + 1] = name
+                    end
+                end
+                table.sort(names)
+                return table.concat(names, ",")
+                "#,
+            );
+        let mut manifest: Vec<_> = builder
+            .clone()
+            .build()
+            .unwrap()
+            .api_manifest()
+            .iter()
+            .map(|e| e.name)
+            .collect();
+        manifest.sort();
+        let mut vm = crate::testing::ScriptVm::new(&builder).unwrap();
+        assert_eq!(
+            vm.handle(LuaMessage::Nil).unwrap(),
+            LuaMessage::from(manifest.join(","))
+        );
+    }
+
+    #[test]
+    fn lua_actor_help() {
+        let system = System::new("test");
+
+        let script = r#"
+            local all = ctx.help()
+            return {
+                send = ctx.help("send").signature,
+                unknown = ctx.help("unknown") == nil,
+                restart = all.restart ~= nil,
+            }
+        "#;
+        let restartable = lua_actor_with_handle(script).start();
+        let with_vm = LuaActorBuilder::new()
+            .on_handle_with_lua(script)
+            .build_with_vm(Lua::new())
+            .unwrap()
+            .start();
+
+        let l = restartable
+            .send(LuaMessage::Nil)
+            .join(with_vm.send(LuaMessage::Nil));
+        Arbiter::spawn(
+            l.map(|(restartable, with_vm)| {
+                assert_eq!(
+                    restartable.get("send"),
+                    Some(&LuaMessage::from(
+                        "local result, err = ctx.send(recipient, msg)"
+                    ))
+                );
+                assert_eq!(restartable.get("unknown"), Some(&LuaMessage::from(true)));
+                assert_eq!(restartable.get("restart"), Some(&LuaMessage::from(true)));
+                assert_eq!(with_vm.get("restart"), Some(&LuaMessage::from(false)));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_circuit_breaker() {
+        struct Dead;
+        impl Actor for Dead {
+            type Context = Context<Self>;
+
+            fn started(&mut self, ctx: &mut Context<Self>) {
+                ctx.stop();
+            }
+        }
+
+        impl Handler<LuaMessage> for Dead {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> Self::Result {
+                msg
+            }
+        }
+
+        let system = System::new("test");
+
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "get" then
+                    local s = ctx.state
+                    return { a = s.a, b = s.b, c = s.c, retry = s.retry, state = ctx.breaker_state("dead") }
+                end
+                -- the same `nil, err` as without a circuit breaker
+                ctx.state.a = select(2, ctx.send("dead", 1)).kind
+                ctx.state.b = select(2, ctx.send("dead", 2)).kind
+                local res, c = ctx.send("dead", 3)
+                ctx.state.c = res == nil and c.kind
+                ctx.state.retry = c.retry_after_ms > 0
+                "#,
+            )
+            .with_circuit_breaker(2, Duration::from_secs(10), Duration::from_secs(10))
+            .build()
+            .unwrap();
+        actor
+            .try_add_recipients("dead", Dead.start().recipient())
+            .unwrap();
+        let addr = actor.start();
+
+        addr.do_send(LuaMessage::from("go"));
+        let delay = Delay::new(Duration::from_millis(500)).map(move |()| {
+            let l = addr.send(LuaMessage::from("get"));
+            Arbiter::spawn(
+                l.map(|res| {
+                    assert_eq!(res.get("a"), Some(&LuaMessage::from("closed")));
+                    assert_eq!(res.get("b"), Some(&LuaMessage::from("closed")));
+                    assert_eq!(res.get("c"), Some(&LuaMessage::from("circuit_open")));
+                    assert_eq!(res.get("retry"), Some(&LuaMessage::from(true)));
+                    assert_eq!(res.get("state"), Some(&LuaMessage::from("open")));
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+            )
+        });
+        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_circuit_breaker_handle_errors() {
+        let system = System::new("test");
+
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                local s = ctx.state
+                if ctx.msg == "get" then
+                    return { a = s.a, b = s.b, c = s.c, state = ctx.breaker_state("broken") }
+                end
+                s.a = ctx.send("broken", 1).kind
+                s.b = ctx.send("broken", 2).kind
+                s.c = ctx.send("broken", 3).kind
+                "#,
+            )
+            .with_rich_send_results(true)
+            .with_circuit_breaker(2, Duration::from_secs(10), Duration::from_secs(10))
+            .build()
+            .unwrap();
+        actor
+            .add_lua_recipient("broken", &lua_actor_with_handle(r#"error("boom")"#).start())
+            .unwrap();
+        let addr = actor.start();
+
+        addr.do_send(LuaMessage::from("go"));
+        let delay = Delay::new(Duration::from_millis(500)).map(move |()| {
+            let l = addr.send(LuaMessage::from("get"));
+            Arbiter::spawn(
+                l.map(|res| {
+                    // the errors of the recipient count as failures
+                    assert_eq!(res.get("a"), Some(&LuaMessage::from("error")));
+                    assert_eq!(res.get("b"), Some(&LuaMessage::from("error")));
+                    assert_eq!(res.get("c"), Some(&LuaMessage::from("circuit_open")));
+                    assert_eq!(res.get("state"), Some(&LuaMessage::from("open")));
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+            )
+        });
+        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_rich_send_results() {
+        struct Dead;
+        impl Actor for Dead {
+            type Context = Context<Self>;
+
+            fn started(&mut self, ctx: &mut Context<Self>) {
+                ctx.stop();
+            }
+        }
+
+        impl Handler<LuaMessage> for Dead {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> Self::Result {
+                msg
+            }
+        }
+
+        // replies after a second
+        struct Slow;
+        impl Actor for Slow {
+            type Context = Context<Self>;
+        }
+
+        struct Later(LuaMessage);
+
+        impl MessageResponse<Slow, LuaMessage> for Later {
+            fn handle<R: ResponseChannel<LuaMessage>>(
+                self,
+                ctx: &mut Context<Slow>,
+                tx: Option<R>,
+            ) {
+                ctx.run_later(Duration::from_secs(1), move |_, _| {
+                    if let Some(tx) = tx {
+                        tx.send(self.0);
+                    }
+                });
+            }
+        }
+
+        impl Handler<LuaMessage> for Slow {
+            type Result = Later;
+
+            fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> Self::Result {
+                Later(msg)
+            }
+        }
+
+        let system = System::new("test");
+
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                local s = ctx.state
+                if ctx.msg == "get" then
+                    return s
+                end
+                local nil_reply = ctx.send("nil", 1)
+                s.nil_ok = nil_reply.ok
+                s.nil_value = nil_reply.value == nil
+                s.value = ctx.send_ok("value", 1)
+                s.no_handler = ctx.send("empty", 1).kind
+                local broken = ctx.send("broken", 1)
+                s.error = broken.kind
+                s.detail = string.find(broken.detail, "boom") ~= nil
+                s.mailbox_closed = ctx.send("dead", 1).kind
+                s.timeout = ctx.send("slow", 1).kind
+                "#,
+            )
+            .with_rich_send_results(true)
+            .with_send_timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+
+        let child = |script: &str| lua_actor_with_handle(script).start();
+        actor
+            .add_lua_recipient("nil", &child("return nil"))
+            .unwrap();
+        actor
+            .add_lua_recipient("value", &child("return ctx.msg + 1"))
+            .unwrap();
+        actor
+            .add_lua_recipient("broken", &child(r#"error("boom")"#))
+            .unwrap();
+        actor
+            .add_lua_recipient("empty", &LuaActor::new(None, None, None).unwrap().start())
+            .unwrap();
+        actor
+            .try_add_recipients("dead", Dead.start().recipient())
+            .unwrap();
+        actor
+            .try_add_recipients("slow", Slow.start().recipient())
+            .unwrap();
+        let addr = actor.start();
+
+        addr.do_send(LuaMessage::from("go"));
+        let delay = Delay::new(Duration::from_millis(500)).map(move |()| {
+            let l = addr.send(LuaMessage::from("get"));
+            Arbiter::spawn(
+                l.map(|res| {
+                    let field = |k: &str| res.get(k).cloned();
+                    assert_eq!(field("nil_ok"), Some(LuaMessage::from(true)));
+                    assert_eq!(field("nil_value"), Some(LuaMessage::from(true)));
+                    assert_eq!(field("value"), Some(LuaMessage::from(2)));
+                    assert_eq!(field("no_handler"), Some(LuaMessage::from("no_handler")));
+                    assert_eq!(field("error"), Some(LuaMessage::from("error")));
+                    assert_eq!(field("detail"), Some(LuaMessage::from(true)));
+                    assert_eq!(
+                        field("mailbox_closed"),
+                        Some(LuaMessage::from("mailbox_closed"))
+                    );
+                    assert_eq!(field("timeout"), Some(LuaMessage::from("timeout")));
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+            )
+        });
+        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_send_failure() {
+        let system = System::new("test");
+
+        let child = lua_actor_with_handle(
+            r#"
+            if ctx.msg == "die" then
+                ctx.terminate()
+            end
+            return ctx.msg
+            "#,
+        )
+        .start();
+        let mut actor = lua_actor_with_handle(
+            r#"
+            local s = ctx.state
+            if ctx.msg == "get" then
+                return s
+            end
+            local res, err = ctx.send("child", 1)
+            s.res = res == nil
+            s.kind = err.kind
+            s.message = err.message
+            "#,
+        );
+        actor.add_lua_recipient("child", &child).unwrap();
+        let addr = actor.start();
+
+        let l = child
+            .send(LuaMessage::from("die"))
+            .then(move |_| addr.send(LuaMessage::from("go")).map(|_| addr))
+            .and_then(|addr| {
+                Delay::new(Duration::from_millis(100))
+                    .map_err(|e| panic!("{}", e))
+                    .and_then(move |_| addr.send(LuaMessage::from("get")))
+            })
+            .map(|res| {
+                // the parent survived the failed send
+                assert_eq!(res.get("res"), Some(&LuaMessage::from(true)));
+                assert_eq!(res.get("kind"), Some(&LuaMessage::from("closed")));
+                assert_eq!(
+                    res.get("message"),
+                    Some(&LuaMessage::from("the mailbox of child is closed"))
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_do_send_closed() {
+        let system = System::new("test");
+
+        let child = lua_actor_with_handle(r#"ctx.terminate()"#).start();
+        let mut actor = lua_actor_with_handle(
+            r#"
+            if ctx.msg == "get" then
+                return ctx.state.sent
+            end
+            ctx.do_send("child", 1)
+            ctx.do_send("nobody", 1)
+            ctx.state.sent = true
+            "#,
+        );
+        actor.add_lua_recipient("child", &child).unwrap();
+        let addr = actor.start();
+
+        let l = child
+            .send(LuaMessage::from("die"))
+            .then(|_| Delay::new(Duration::from_millis(50)).map_err(|e| panic!("{}", e)))
+            .and_then(move |_| {
+                addr.send(LuaMessage::from("go"))
+                    .and_then(move |_| addr.send(LuaMessage::from("get")))
+            })
+            .map(|res| {
+                // the parent survived sending to a stopped and an unknown recipient
+                assert_eq!(res, LuaMessage::from(true));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_send_unknown_recipient() {
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(
+            r#"
+            local s = ctx.state
+            if ctx.msg == "get" then
+                return s
+            end
+            local res, err = ctx.send("nobody", 1)
+            s.res = res == nil
+            s.kind = err.kind
+            s.message = err.message
+            "#,
+        )
+        .start();
+
+        let l = addr
+            .send(LuaMessage::from("go"))
+            .and_then(move |_| addr.send(LuaMessage::from("get")))
+            .map(|res| {
+                // the actor survived the send
+                assert_eq!(res.get("res"), Some(&LuaMessage::from(true)));
+                assert_eq!(
+                    res.get("kind"),
+                    Some(&LuaMessage::from("unknown_recipient"))
+                );
+                assert_eq!(
+                    res.get("message"),
+                    Some(&LuaMessage::from("no recipient named nobody"))
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_send_ok_error() {
+        let system = System::new("test");
+
+        let received = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                local ok, err = ctx.try(ctx.send_ok, "broken", 1)
+                ctx.do_send("probe", err)
+                "#,
+            )
+            .with_rich_send_results(true)
+            .with_recipient("probe", Probe(received.clone()).start().recipient())
+            .build()
+            .unwrap();
+        let broken = lua_actor_with_handle(r#"error("boom")"#).start();
+        actor.add_lua_recipient("broken", &broken).unwrap();
+
+        actor.start().do_send(LuaMessage::Nil);
+        let l = Delay::new(Duration::from_millis(100)).then(move |_| {
+            let received = received.lock().unwrap();
+            match received.first() {
+                Some(LuaMessage::String(err)) => {
+                    assert!(err.contains("ctx.send to broken failed: error"), "{}", err)
+                }
+                err => panic!("expected an error, got {:?}", err),
+            }
+            System::current().stop();
+            Ok::<(), ()>(())
+        });
+        Arbiter::spawn(l);
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_supervised() {
+        let system = System::new("test");
+
+        let actor = LuaActorBuilder::new()
+            .on_started_with_lua(r#"starts = (starts or 0) + 1"#)
+            .on_restarting_with_lua(
+                r#"
+                restarts = (restarts or 0) + 1
+                ctx.state = {}
+                "#,
+            )
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "stop" then
+                    ctx.terminate()
+                elseif ctx.msg == "set" then
+                    ctx.state.stale = true
+                else
+                    return { starts = starts, restarts = restarts or 0, stale = ctx.state.stale or false }
+                end
+                "#,
+            )
+            .build()
+            .unwrap();
+        let addr = Supervisor::start(|_| actor);
+
+        addr.do_send(LuaMessage::from("set"));
+        let l = addr
+            .send(LuaMessage::from("count"))
+            .and_then(move |before| {
+                assert_eq!(
+                    before,
+                    crate::lua_msg!({ starts: 1, restarts: 0, stale: true })
+                );
+                addr.do_send(LuaMessage::from("stop"));
+                // the messages received while the actor stops are dropped
+                Delay::new(Duration::from_millis(50))
+                    .map_err(|e| panic!("{}", e))
+                    .and_then(move |_| addr.send(LuaMessage::from("count")))
+            })
+            .map(|after| {
+                assert_eq!(
+                    after,
+                    crate::lua_msg!({ starts: 2, restarts: 1, stale: false })
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_opairs() {
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(
+            r#"
+            if ctx.msg.echo then
+                return ctx.msg.echo
+            end
+            local fields, count = {}, 0
+            for k, v in ctx.util.opairs(ctx.msg.fields) do
+                fields[#fields + 1] = k .. "=" .. (type(v) == "table" and "{}" or tostring(v))
+            end
+            -- `pairs` visits the same fields, and nothing else
+            for _ in pairs(ctx.msg.fields) do
+                count = count + 1
+            end
+            local mixed = {}
+            for k in ctx.util.opairs({ b = 1, [10] = 1, a = 1, [2] = 1, [true] = 1, [1.5] = 1 }) do
+                mixed[#mixed + 1] = tostring(k)
+            end
+            return { fields = table.concat(fields, ","), count = count, mixed = table.concat(mixed, ",") }
+            "#,
+        )
+        .start();
+
+        let fields = crate::lua_msg!({ zeta: 1, alpha: 2, mid: 3, beta: { nested: true } });
+        let l = addr
+            .send(crate::lua_msg!({ fields: fields.clone() }))
+            .join(addr.send(crate::lua_msg!({ echo: fields.clone() })));
+        Arbiter::spawn(
+            l.map(move |(res, echo)| {
+                assert_eq!(
+                    res.get("fields"),
+                    Some(&LuaMessage::from("alpha=2,beta={},mid=3,zeta=1"))
+                );
+                assert_eq!(res.get("count"), Some(&LuaMessage::from(4)));
+                assert_eq!(
+                    res.get("mixed"),
+                    Some(&LuaMessage::from("true,1.5,2,10,a,b"))
+                );
+                // the round trip keeps the fields
+                assert_eq!(echo, fields);
+                System::current().stop();
+            })
+            .map_err(|e| panic!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_state_namespaces() {
+        let script = r#"
+            -- two components using the same keys
+            local chat = ctx.ns("chat")
+            chat.count = (chat.count or 0) + 1
+            local presence = ctx.ns("presence")
+            presence.count = (presence.count or 0) + 10
+            -- shared data
+            ctx.state.total = (ctx.state.total or 0) + 1
+            return { chat = ctx.ns("chat").count, presence = ctx.ns("presence").count, total = ctx.state.total }
+        "#;
+        let actor = lua_actor_with_handle(script);
+        actor
+            .runtime
+            .vm
+            .context(|ctx| ctx.load("ctx.ns('chat').count = 4").exec())
+            .unwrap();
+
+        // namespaces are part of the state
+        let state = actor.runtime.get_state().unwrap();
+        let ns = state.get("__ns").and_then(|ns| ns.get("chat")).cloned();
+        let mut chat = HashMap::new();
+        chat.insert("count".to_string(), LuaMessage::from(4));
+        assert_eq!(ns, Some(LuaMessage::from(chat)));
+        let restored = lua_actor_with_handle(script);
+        restored.runtime.set_state(state).unwrap();
+
+        let system = System::new("test");
+        let l = restored.start().send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res.get("chat"), Some(&LuaMessage::from(5)));
+                assert_eq!(res.get("presence"), Some(&LuaMessage::from(10)));
+                assert_eq!(res.get("total"), Some(&LuaMessage::from(1)));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_opaque_handles() {
+        let system = System::new("test");
+
+        struct Conn {
+            name: String,
+        }
+
+        let vm = Lua::new();
+        vm.context(|ctx| {
+            let globals = ctx.globals();
+            let open = ctx
+                .create_function(|ctx, name: String| {
+                    let registry = OpaqueRegistry::from_context(ctx)?;
+                    Ok(registry.register(Conn { name }))
+                })
+                .unwrap();
+            let query = ctx
+                .create_function(|ctx, handle: OpaqueHandle| {
+                    let registry = OpaqueRegistry::from_context(ctx)?;
+                    Ok(registry.with(handle, |conn: &mut Conn| format!("rows of {}", conn.name)))
+                })
+                .unwrap();
+            globals.set("open", open).unwrap();
+            globals.set("query", query).unwrap();
+        });
+
+        let actor = LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.state.conn = open("db")"#)
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "query" then
+                    return query(ctx.state.conn)
+                elseif ctx.msg == "send" then
+                    local ok, err = pcall(ctx.do_send, "other", { conn = ctx.state.conn })
+                    assert(not ok)
+                    return tostring(err)
+                elseif ctx.msg == "notify" then
+                    ctx.notify({ conn = ctx.state.conn })
+                elseif type(ctx.msg) == "table" then
+                    ctx.state.notified = query(ctx.msg.conn)
+                elseif ctx.msg == "notified" then
+                    return ctx.state.notified
+                elseif ctx.msg == "release" then
+                    ctx.release(ctx.state.conn)
+                    return query(ctx.state.conn)
+                end
+                "#,
+            )
+            .build_with_vm(vm)
+            .unwrap();
+
+        // values registered from Rust
+        let handle = actor.register_opaque(42u8);
+        assert_eq!(handle, LuaMessage::Opaque(1));
+        let value = actor.take_opaque(&handle).unwrap();
+        assert_eq!(value.downcast_ref::<u8>(), Some(&42));
+        assert!(actor.take_opaque(&handle).is_none());
+
+        let registry = actor.opaque_registry().clone();
+        let addr = actor.start();
+        let send = move |msg: &str| addr.send(LuaMessage::from(msg));
+
+        let l = send("query")
+            .join(send("send"))
+            .and_then(move |(query, send_err)| {
+                // the handle stored in state is valid across invocations
+                assert_eq!(query, LuaMessage::from("rows of db"));
+                if let LuaMessage::String(s) = send_err {
+                    assert!(s.contains("opaque handles can't be sent to other actors"));
+                } else {
+                    panic!("unexpected result {:?}", send_err);
+                }
+                send("notify")
+                    .and_then(|_| Delay::new(Duration::from_millis(50)).then(|_| Ok(())))
+                    .and_then(move |_| send("notified").map(move |notified| (notified, send)))
+            })
+            .and_then(move |(notified, send)| {
+                // the notification to self kept the handle
+                assert_eq!(notified, LuaMessage::from("rows of db"));
+                assert_eq!(registry.len(), 1);
+                send("release").map(move |released| (released, registry))
+            })
+            .map(|(released, registry)| {
+                assert_eq!(released, LuaMessage::Nil);
+                assert!(registry.is_empty());
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_opaque_cleanup_on_stop() {
+        let system = System::new("test");
+
+        let actor = lua_actor_with_handle(r#"ctx.terminate()"#);
+        let registry = actor.opaque_registry().clone();
+        actor.register_opaque("connection".to_string());
+        assert_eq!(registry.len(), 1);
+
+        let addr = actor.start();
+        addr.do_send(LuaMessage::Nil);
+        let l = Delay::new(Duration::from_millis(50)).map(move |_| {
+            assert!(registry.is_empty());
+            System::current().stop();
+        });
+        Arbiter::spawn(l.map_err(|e| println!("timer failed {}", e)));
+
+        system.run();
+    }
+
+    // records the messages it receives
+    struct Probe(Arc<std::sync::Mutex<Vec<LuaMessage>>>);
+
+    impl Actor for Probe {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<LuaMessage> for Probe {
+        type Result = LuaMessage;
+
+        fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> LuaMessage {
+            self.0.lock().unwrap().push(msg);
+            LuaMessage::Nil
+        }
+    }
+
+    struct Echo;
+
+    impl Actor for Echo {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<LuaMessage> for Echo {
+        type Result = LuaMessage;
+
+        fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> Self::Result {
+            msg
+        }
+    }
+
+    #[test]
+    fn lua_actor_required_recipients() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .require_recipients(&["db"], Duration::from_secs(5))
+            .on_started_with_lua(
+                r#"
+                ctx.state.started = true
+                ctx.state.db = ctx.runtime.dependencies.db
+                "#,
+            )
+            .on_handle_with_lua(r#"return { started = ctx.state.started, db = ctx.state.db }"#)
+            .spawn()
+            .unwrap();
+
+        // the dependency connects later
+        let start = Instant::now();
+        let db = Echo.start().recipient();
+        let addr2 = addr.clone();
+        Arbiter::spawn(
+            Delay::new(Duration::from_millis(300))
+                .map_err(|_| ())
+                .and_then(move |_| {
+                    addr2
+                        .send(AddRecipient {
+                            name: "db".to_string(),
+                            recipient: db,
+                        })
+                        .map(|res| assert!(res.unwrap().is_none()))
+                        .map_err(|e| println!("actor dead {}", e))
+                }),
+        );
+
+        // handled after `started`
+        let l = addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(move |res| {
+                assert!(start.elapsed() >= Duration::from_millis(300));
+                assert_eq!(res.get("started"), Some(&LuaMessage::from(true)));
+                assert_eq!(res.get("db"), Some(&LuaMessage::from(true)));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_required_recipients_try() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .require_recipients(&["db"], Duration::from_secs(5))
+            .on_started_with_lua(r#"ctx.state.started = true"#)
+            .on_handle_with_lua(r#"return ctx.state.started"#)
+            .spawn()
+            .unwrap();
+
+        // sent during the dependency wait, handled after `started`
+        let l = addr
+            .send(TryLuaMessage(LuaMessage::Nil))
+            .join(
+                Delay::new(Duration::from_millis(100))
+                    .then({
+                        let addr = addr.clone();
+                        move |_| {
+                            addr.send(AddRecipient {
+                                name: "db".to_string(),
+                                recipient: Echo.start().recipient(),
+                            })
+                        }
+                    })
+                    .map(|res| assert!(res.unwrap().is_none())),
+            )
+            .map(|(res, _)| {
+                assert_eq!(res, Ok(LuaMessage::from(true)));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_missing_required_recipients() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .require_recipients(&["db", "auth"], Duration::from_millis(100))
+            .with_recipient("db", Echo.start().recipient())
+            .spawn()
+            .unwrap();
+
+        // the actor stops, dropping the message received before `started`
+        let l = addr.send(LuaMessage::Nil).then(|res| {
+            assert!(res.is_err(), "{:?}", res);
+            System::current().stop();
+            Ok::<(), ()>(())
+        });
+        Arbiter::spawn(l);
+
+        system.run();
+    }
+
+    const MIGRATIONS_V3: &str = r#"
+        ctx.state_version = 3
+        ctx.migration(1, function (state)
+            state.log = "1->2"
+            state.count = state.counter
+            state.counter = nil
+            return state
+        end)
+        ctx.migration(2, function (state)
+            return { total = state.count, log = state.log .. ",2->3" }
+        end)
+    "#;
+
+    #[test]
+    fn lua_actor_state_migrations() {
+        let system = System::new("test");
+
+        let builder = LuaActorBuilder::new()
+            .on_migrations_with_lua(MIGRATIONS_V3)
+            .on_started_with_lua(r#"ctx.state.started = ctx.state.__state_version"#)
+            .on_handle_with_lua(r#"return ctx.state"#);
+        // a checkpoint of the first version, without `__state_version`
+        let restored = builder.clone().build().unwrap();
+        restored
+            .runtime
+            .set_state(crate::lua_msg!({ counter: 5 }))
+            .unwrap();
+        let restored = restored.start();
+        let fresh = builder.build().unwrap().start();
+
+        let l = restored
+            .send(LuaMessage::Nil)
+            .join(fresh.send(LuaMessage::Nil))
+            .map(|(restored, fresh)| {
+                crate::assert_lua_eq!(
+                    restored,
+                    crate::lua_msg!({ total: 5, log: "1->2,2->3", __state_version: 3, started: 3 })
+                );
+                crate::assert_lua_eq!(fresh, crate::lua_msg!({ __state_version: 3, started: 3 }));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_missing_state_migration() {
+        let system = System::new("test");
+
+        let actor = LuaActorBuilder::new()
+            .on_migrations_with_lua(
+                r#"
+                ctx.state_version = 3
+                ctx.migration(1, function (state) return state end)
+                "#,
+            )
+            .build()
+            .unwrap();
+        actor
+            .runtime
+            .set_state(crate::lua_msg!({ counter: 5, __state_version: 2 }))
+            .unwrap();
+
+        // the actor stops
+        let l = actor.start().send(LuaMessage::Nil).then(|res| {
+            assert!(res.is_err(), "{:?}", res);
+            System::current().stop();
+            Ok::<(), ()>(())
+        });
+        Arbiter::spawn(l);
+
+        system.run();
+    }
+
+    // Exports `users.alice` to the actor "b" with the merge mode of the message.
+    fn exporting_actor(b: &Addr<LuaActor>) -> LuaActorBuilder {
+        LuaActorBuilder::new()
+            .with_recipient("b", b.clone().recipient())
+            .with_max_export_size(256)
+            .on_started_with_lua(
+                r#"ctx.state.users = { alice = { age = 31, tags = { "admin" } } }"#,
+            )
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "big" then
+                    ctx.state.users.alice.bio = string.rep("x", 1000)
+                    local ok, err = pcall(ctx.export_state, "users.alice")
+                    return { ok, err }
+                end
+                ctx.do_send("b", { merge = ctx.msg, blob = ctx.export_state("users.alice") })
+                "#,
+            )
+    }
+
+    // Imports the blobs of `exporting_actor` into a fresh `users.alice`, replying to "imports"
+    // with the users after each import, by merge mode.
+    const IMPORT: &str = r#"
+        if ctx.msg == "imports" then
+            return ctx.state.imports
+        end
+        ctx.state.users = { alice = { age = 30, city = "Paris" }, bob = { age = 40 } }
+        ctx.import_state("users.alice", ctx.msg.blob, { merge = ctx.msg.merge })
+        ctx.state.imports = ctx.state.imports or {}
+        ctx.state.imports[ctx.msg.merge] = ctx.state.users
+    "#;
+
+    #[test]
+    fn lua_actor_state_export() {
+        let system = System::new("test");
+
+        let b = LuaActorBuilder::new()
+            .on_handle_with_lua(IMPORT)
+            .build()
+            .unwrap()
+            .start();
+        let a = exporting_actor(&b).build().unwrap().start();
+
+        let l = a
+            .send(LuaMessage::from("replace"))
+            .join(a.send(LuaMessage::from("merge")))
+            .and_then(move |_| {
+                a.send(LuaMessage::from("big"))
+                    .join(b.send(LuaMessage::from("imports")))
+            })
+            .map(|(big, imports)| {
+                crate::assert_lua_eq!(
+                    imports,
+                    crate::lua_msg!({
+                        replace: {
+                            alice: { age: 31, tags: ["admin"] },
+                            bob: { age: 40 },
+                        },
+                        merge: {
+                            alice: { age: 31, city: "Paris", tags: ["admin"] },
+                            bob: { age: 40 },
+                        },
+                    })
+                );
+                assert_eq!(
+                    big,
+                    crate::lua_msg!([
+                        false,
+                        "ctx.export_state: runtime error: the export is 1416 bytes, above the limit of 256 bytes",
+                    ])
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_state_import_migrations() {
+        let system = System::new("test");
+
+        let b = LuaActorBuilder::new()
+            .on_migrations_with_lua(
+                r#"
+                ctx.state_version = 2
+                ctx.migration(1, function (state)
+                    local alice = state.users.alice
+                    alice.roles, alice.tags = alice.tags, nil
+                    return state
+                end)
+                "#,
+            )
+            .on_handle_with_lua(IMPORT)
+            .build()
+            .unwrap()
+            .start();
+        let a = exporting_actor(&b)
+            .on_migrations_with_lua(r#"ctx.state_version = 1"#)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = a
+            .send(LuaMessage::from("merge"))
+            .and_then(move |_| b.send(LuaMessage::from("imports")))
+            .map(|imports| {
+                crate::assert_lua_eq!(
+                    imports,
+                    crate::lua_msg!({
+                        merge: {
+                            alice: { age: 31, city: "Paris", roles: ["admin"] },
+                            bob: { age: 40 },
+                        },
+                    })
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_max_table_entries() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_max_table_entries(Some(100_000))
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "big" then
+                    local t = {}
+                    for i = 1, 200000 do t[i] = i end
+                    return t
+                end
+                if type(ctx.msg) == "table" then
+                    return "received"
+                end
+                return ctx.msg
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let mut big = HashMap::new();
+        for i in 0..200_000 {
+            big.insert(i.to_string(), LuaMessage::from(i));
+        }
+        let to_lua = addr.send(TryLuaMessage(LuaMessage::from(big)));
+        let from_lua = addr.send(TryLuaMessage(LuaMessage::from("big")));
+        let l = to_lua
+            .join(from_lua)
+            .and_then(move |(to_lua, from_lua)| {
+                for res in [to_lua, from_lua] {
+                    match res {
+                        Err(HandleError::Error(e)) => {
+                            assert!(e.contains("more than 100000 entries"), "{}", e)
+                        }
+                        res => panic!("expected an error, got {:?}", res),
+                    }
+                }
+                // still responsive
+                addr.send(LuaMessage::from("ping"))
+            })
+            .map(|res| {
+                assert_eq!(res, LuaMessage::from("ping"));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    // replies with its name after `delay`
+    struct Child {
+        name: &'static str,
+        delay: Duration,
+    }
+
+    impl Actor for Child {
+        type Context = Context<Self>;
+    }
+
+    struct Delayed(LuaMessage, Duration);
+
+    impl MessageResponse<Child, LuaMessage> for Delayed {
+        fn handle<R: ResponseChannel<LuaMessage>>(self, ctx: &mut Context<Child>, tx: Option<R>) {
+            let msg = self.0;
+            ctx.run_later(self.1, move |_, _| {
+                if let Some(tx) = tx {
+                    tx.send(msg);
+                }
+            });
+        }
+    }
+
+    impl Handler<LuaMessage> for Child {
+        type Result = Delayed;
+
+        fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> Self::Result {
+            match msg {
+                LuaMessage::Integer(n) => {
+                    Delayed(LuaMessage::from(format!("{}{}", self.name, n)), self.delay)
+                }
+                _ => unimplemented!(),
+            }
+        }
+    }
+
+    #[test]
+    fn lua_actor_aliases() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+                ctx.alias("primary", "a")
+                ctx.state.resolved = ctx.resolve("primary")
+                ctx.state.unknown = ctx.resolve("a")
+                ctx.state.chained = tostring(select(2, pcall(ctx.alias, "secondary", "primary")))
+                ctx.state.log = ""
+                "#,
+            )
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "get" then
+                    return ctx.state
+                end
+                local reply = ctx.send("primary", ctx.msg)
+                ctx.state.log = ctx.state.log .. reply .. ","
+                "#,
+            )
+            .with_recipient(
+                "a",
+                Child {
+                    name: "a",
+                    delay: Duration::from_millis(200),
+                }
+                .start()
+                .recipient(),
+            )
+            .with_recipient(
+                "b",
+                Child {
+                    name: "b",
+                    delay: Duration::from_millis(0),
+                }
+                .start()
+                .recipient(),
+            )
+            .spawn()
+            .unwrap();
+
+        // the send of 1 is in flight when the alias changes
+        addr.do_send(LuaMessage::from(1));
+        let set = addr.send(SetAlias {
+            alias: "primary".to_string(),
+            target: "b".to_string(),
+        });
+        addr.do_send(LuaMessage::from(2));
+
+        let l = set
+            .and_then(|prev| {
+                assert_eq!(prev.unwrap(), Some("a".to_string()));
+                Delay::new(Duration::from_millis(500)).then(|_| Ok(()))
+            })
+            .and_then(move |_| addr.send(LuaMessage::from("get")))
+            .map(|state| {
+                assert_eq!(state.get("resolved"), Some(&LuaMessage::from("a")));
+                assert_eq!(state.get("unknown"), None);
+                match state.get("chained") {
+                    Some(LuaMessage::String(e)) => {
+                        assert!(e.contains("aliases can't refer to aliases"), "{}", e)
+                    }
+                    res => panic!("unexpected {:?}", res),
+                }
+                // the sends overlap, the faster reply resumes first
+                assert_eq!(state.get("log"), Some(&LuaMessage::from("b2,a1,")));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_send_reply_after_restart() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "get" then
+                    return ctx.state
+                elseif ctx.msg == "restart" then
+                    ctx.restart(true)
+                    return
+                end
+                local reply = ctx.send(ctx.msg == "old" and "slow" or "fast", 1)
+                ctx.state.log = (ctx.state.log or "") .. ctx.msg .. ":" .. reply .. ","
+                "#,
+            )
+            .with_recipient(
+                "slow",
+                Child {
+                    name: "slow",
+                    delay: Duration::from_millis(100),
+                }
+                .start()
+                .recipient(),
+            )
+            .with_recipient(
+                "fast",
+                Child {
+                    name: "fast",
+                    delay: Duration::from_millis(200),
+                }
+                .start()
+                .recipient(),
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        // thread ids start over in the new VM, the coroutine suspended in "new" has the id of
+        // the one dropped in "old", whose reply arrives first
+        addr.do_send(LuaMessage::from("old"));
+        let l = Delay::new(Duration::from_millis(50))
+            .then({
+                let addr = addr.clone();
+                move |_| {
+                    addr.do_send(LuaMessage::from("restart"));
+                    addr.do_send(LuaMessage::from("new"));
+                    Delay::new(Duration::from_millis(400))
+                }
+            })
+            .then(move |_| addr.send(LuaMessage::from("get")))
+            .map(|state| {
+                assert_eq!(state.get("log"), Some(&LuaMessage::from("new:fast1,")));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
 
-    use crate::builder::LuaActorBuilder;
+    #[test]
+    fn lua_actor_send_correlated() {
+        // replies with `do_send` after `delay`, copying the request id
+        struct Legacy {
+            origin: Arc<std::sync::Mutex<Option<Recipient<LuaMessage>>>>,
+            delay: Duration,
+        }
 
-    fn lua_actor_with_handle(script: &str) -> LuaActor {
-        LuaActorBuilder::new()
-            .on_handle_with_lua(script)
+        impl Actor for Legacy {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Legacy {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, ctx: &mut Context<Self>) -> LuaMessage {
+                let origin = self.origin.lock().unwrap().clone().unwrap();
+                ctx.run_later(self.delay, move |_, _| {
+                    let n = match msg.get("n") {
+                        Some(LuaMessage::Integer(n)) => *n,
+                        n => panic!("unexpected {:?}", n),
+                    };
+                    let reply =
+                        crate::lua_msg!({ req_id: (msg.get("req_id").unwrap().clone()), double: (n * 2) });
+                    origin.do_send(reply).unwrap();
+                });
+                LuaMessage::Nil
+            }
+        }
+
+        let system = System::new("test");
+
+        let origin = Arc::new(std::sync::Mutex::new(None));
+        let legacy = |delay| {
+            Legacy {
+                origin: origin.clone(),
+                delay: Duration::from_millis(delay),
+            }
+            .start()
+            .recipient()
+        };
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.state.log = {}"#)
+            .on_handle_with_lua(
+                r#"
+                local log = ctx.state.log
+                if ctx.msg == "get" then
+                    return ctx.state
+                elseif ctx.msg.n ~= nil then
+                    local reply = ctx.send_correlated(ctx.msg.to, { n = ctx.msg.n },
+                        { id_field = "req_id", timeout = ctx.msg.timeout })
+                    log[#log + 1] = ctx.msg.n .. "=" .. (reply.double or reply.error)
+                else
+                    -- the late reply, its request timed out
+                    log[#log + 1] = "late " .. ctx.msg.double
+                end
+                "#,
+            )
+            .with_recipient("fast", legacy(10))
+            .with_recipient("slow", legacy(200))
             .build()
             .unwrap()
+            .start();
+        *origin.lock().unwrap() = Some(addr.clone().recipient());
+
+        addr.do_send(crate::lua_msg!({ n: 1, to: "slow", timeout: 0.05 }));
+        addr.do_send(crate::lua_msg!({ n: 2, to: "fast", timeout: 1 }));
+        addr.do_send(crate::lua_msg!({ n: 3, to: "fast" }));
+
+        let l = Delay::new(Duration::from_millis(400))
+            .then(move |_| addr.send(LuaMessage::from("get")))
+            .map(|state| {
+                assert_eq!(
+                    state.get("log"),
+                    Some(&crate::lua_msg!(["2=4", "3=6", "1=timeout", "late 2"]))
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
     }
 
     #[test]
-    fn lua_actor_basic() {
+    fn lua_actor_send_correlated_timeout_error() {
         let system = System::new("test");
 
-        let lua_addr = lua_actor_with_handle(r#"return ctx.msg + 1"#).start();
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "go" then
+                    ctx.send_correlated("sink", {}, { id_field = "req_id", timeout = 0.05 })
+                    error("boom")
+                end
+                return "alive"
+                "#,
+            )
+            .build()
+            .unwrap();
+        actor
+            .add_lua_recipient("sink", &lua_actor_with_handle("return nil").start())
+            .unwrap();
+        let addr = actor.start();
 
-        let l = lua_addr.send(LuaMessage::from(1));
-        Arbiter::spawn(
-            l.map(|res| {
-                assert_eq!(res, LuaMessage::from(2));
+        addr.do_send(LuaMessage::from("go"));
+        // the coroutine resumed by the timeout fails, the actor keeps running
+        let l = Delay::new(Duration::from_millis(200))
+            .then(move |_| addr.send(LuaMessage::from("ping")))
+            .map(|res| {
+                assert_eq!(res, LuaMessage::from("alive"));
                 System::current().stop();
-            })
-            .map_err(|e| println!("actor dead {}", e)),
-        );
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
 
         system.run();
     }
 
     #[test]
-    fn lua_actor_syntax_error() {
-        let res = LuaActorBuilder::new()
-            .on_handle_with_lua(r"return 1+")
-            .build();
+    fn lua_actor_wait_for() {
+        let system = System::new("test");
 
-        if let Ok(_) = res {
-            panic!("should return Err(syntax_error)");
-        }
+        let workflow = || {
+            LuaActorBuilder::new()
+                .on_started_with_lua(r#"ctx.state.log = {}"#)
+                .on_handle_with_lua(
+                    r#"
+                    local log = ctx.state.log
+                    if ctx.msg == "get" then
+                        return ctx.state
+                    elseif ctx.msg.type == "checkout" then
+                        log[#log + 1] = "checkout " .. ctx.msg.order
+                        local reply = ctx.wait_for({"payment_confirmed", "payment_failed"},
+                            ctx.msg.timeout)
+                        log[#log + 1] = ctx.msg.order .. " " .. (reply.type or reply.error)
+                    else
+                        log[#log + 1] = "other " .. ctx.msg.type
+                    end
+                    "#,
+                )
+                .build()
+                .unwrap()
+                .start()
+        };
+        let log = |addr: Addr<LuaActor>| {
+            addr.send(LuaMessage::from("get"))
+                .map(|state| state.get("log").cloned().unwrap())
+        };
+
+        // the confirmation arrives after unrelated messages
+        let confirmed = workflow();
+        confirmed.do_send(crate::lua_msg!({ type: "checkout", order: 1 }));
+        confirmed.do_send(crate::lua_msg!({ type: "ping" }));
+        confirmed.do_send(crate::lua_msg!({ type: "refund" }));
+        confirmed.do_send(crate::lua_msg!({ type: "payment_confirmed" }));
+
+        // the confirmation arrives too late and goes to `handle`
+        let timed_out = workflow();
+        timed_out.do_send(crate::lua_msg!({ type: "checkout", order: 1, timeout: 0.05 }));
+
+        // one message each, oldest waiter first
+        let waiters = workflow();
+        waiters.do_send(crate::lua_msg!({ type: "checkout", order: 1 }));
+        waiters.do_send(crate::lua_msg!({ type: "checkout", order: 2 }));
+        waiters.do_send(crate::lua_msg!({ type: "payment_failed" }));
+        waiters.do_send(crate::lua_msg!({ type: "payment_confirmed" }));
+
+        let late = timed_out.clone();
+        let l = Delay::new(Duration::from_millis(200))
+            .then(move |_| {
+                late.do_send(crate::lua_msg!({ type: "payment_confirmed" }));
+                log(confirmed).join3(log(timed_out), log(waiters))
+            })
+            .map(|(confirmed, timed_out, waiters)| {
+                assert_eq!(
+                    confirmed,
+                    crate::lua_msg!([
+                        "checkout 1",
+                        "other ping",
+                        "other refund",
+                        "1 payment_confirmed"
+                    ])
+                );
+                assert_eq!(
+                    timed_out,
+                    crate::lua_msg!(["checkout 1", "1 timeout", "other payment_confirmed"])
+                );
+                assert_eq!(
+                    waiters,
+                    crate::lua_msg!([
+                        "checkout 1",
+                        "checkout 2",
+                        "1 payment_failed",
+                        "2 payment_confirmed"
+                    ])
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    fn spawn_group_actor(script: &str) -> LuaActorBuilder {
+        LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.state.log = {}"#)
+            .on_handle_with_lua(script)
+            .with_recipient(
+                "fast",
+                Child {
+                    name: "fast",
+                    delay: Duration::from_millis(10),
+                }
+                .start()
+                .recipient(),
+            )
+            .with_recipient(
+                "slow",
+                Child {
+                    name: "slow",
+                    delay: Duration::from_millis(200),
+                }
+                .start()
+                .recipient(),
+            )
     }
 
-    #[should_panic]
     #[test]
-    fn lua_actor_user_error() {
+    fn lua_actor_spawn_group_join() {
         let system = System::new("test");
 
-        let lua_addr = lua_actor_with_handle(
+        let addr = spawn_group_actor(
             r#"
-        print("before")
-        error("foo")
-        print("after")
-        "#,
+            if ctx.msg == "get" then
+                return ctx.state
+            end
+            local g = ctx.spawn_group()
+            g:spawn(function () return ctx.send("slow", 1) end)
+            g:spawn(function () error("boom", 0) end)
+            g:spawn(function () return ctx.send("fast", 3) end)
+            ctx.state.results = g:join()
+            -- finished members join without waiting
+            ctx.state.again = g:join()
+            local timed_out = ctx.spawn_group()
+            timed_out:spawn(function () return ctx.send("slow", 4) end)
+            ctx.state.timed_out = timed_out:join(0.05)
+            "#,
         )
+        .build()
+        .unwrap()
         .start();
 
-        let l = lua_addr.send(LuaMessage::from(0));
-        Arbiter::spawn(
-            l.map(|_| {
-                // it should panic
+        addr.do_send(LuaMessage::from("run"));
+        let l = Delay::new(Duration::from_millis(400))
+            .then(move |_| addr.send(LuaMessage::from("get")))
+            .map(|state| {
+                let results = crate::lua_msg!([
+                    { ok: true, value: "slow1" },
+                    { ok: false, error: "boom" },
+                    { ok: true, value: "fast3" }
+                ]);
+                assert_eq!(state.get("results"), Some(&results));
+                assert_eq!(state.get("again"), Some(&results));
+                assert_eq!(
+                    state.get("timed_out"),
+                    Some(&crate::lua_msg!([{ ok: false, error: "timeout" }]))
+                );
                 System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_spawn_group_cancel() {
+        let system = System::new("test");
+
+        let addr = spawn_group_actor(
+            r#"
+            local log = ctx.state.log
+            if ctx.msg == "get" then
+                return { log = log, results = ctx.state.results }
+            elseif ctx.msg == "cancel" then
+                ctx.state.g:cancel()
+                return
+            end
+            local g = ctx.spawn_group()
+            ctx.state.g = g
+            g:spawn(function ()
+                local ok, err = ctx.try(ctx.send, "slow", 1)
+                log[#log + 1] = "a " .. err
+                -- the next yield point raises again
+                ctx.send("fast", 2)
+                log[#log + 1] = "a unreachable"
+            end)
+            g:spawn(function ()
+                ctx.send("slow", 3)
+                log[#log + 1] = "b unreachable"
+            end)
+            g:spawn(function ()
+                return ctx.send("fast", 4)
+            end)
+            ctx.state.results = g:join()
+            "#,
+        )
+        .build()
+        .unwrap()
+        .start();
+
+        addr.do_send(LuaMessage::from("run"));
+        let l = Delay::new(Duration::from_millis(50))
+            .then({
+                let addr = addr.clone();
+                move |_| addr.send(LuaMessage::from("cancel"))
             })
-            .map_err(|e| println!("actor dead {}", e)),
-        );
+            // the replies of the slow sends arrive after the cancellation
+            .and_then(|_| Delay::new(Duration::from_millis(300)).then(|_| Ok(())))
+            .and_then(move |_| addr.send(LuaMessage::from("get")))
+            .map(|state| {
+                assert_eq!(state.get("log"), Some(&crate::lua_msg!(["a cancelled"])));
+                assert_eq!(
+                    state.get("results"),
+                    Some(&crate::lua_msg!([
+                        { ok: false, error: "cancelled" },
+                        { ok: false, error: "cancelled" },
+                        { ok: true, value: "fast4" }
+                    ]))
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
 
         system.run();
     }
 
     #[test]
-    fn lua_actor_return_table() {
+    fn lua_actor_spawn_group_stop() {
         let system = System::new("test");
 
-        let lua_addr = lua_actor_with_handle(
+        let received = Arc::new(std::sync::Mutex::new(vec![]));
+        let addr = spawn_group_actor(
             r#"
-        return {x = 1}
-        "#,
+            if ctx.msg == "stop" then
+                ctx.terminate()
+                return
+            end
+            local g = ctx.spawn_group()
+            g:spawn(function ()
+                local ok, err = ctx.try(ctx.send, "slow", 1)
+                ctx.do_send("probe", "a " .. err)
+            end)
+            g:spawn(function () ctx.send("slow", 2) end)
+            g:spawn(function () return "done" end)
+            "#,
         )
+        .on_internal_error_with_lua(r#"ctx.do_send("probe", ctx.msg)"#)
+        .with_recipient("probe", Probe(received.clone()).start().recipient())
+        .build()
+        .unwrap()
         .start();
 
-        let l = lua_addr.send(LuaMessage::from(3));
-        Arbiter::spawn(
-            l.map(|res| {
-                let mut t = HashMap::new();
-                t.insert("x".to_string(), LuaMessage::from(1));
+        addr.do_send(LuaMessage::from("run"));
+        addr.do_send(LuaMessage::from("stop"));
+        let l = Delay::new(Duration::from_millis(100)).then(move |_| {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 2, "{:?}", received);
+            assert_eq!(received[0], LuaMessage::from("a cancelled"));
+            assert_eq!(
+                received[1].get("subsystem"),
+                Some(&LuaMessage::from("spawn_group"))
+            );
+            assert_eq!(
+                received[1].get("detail"),
+                Some(&crate::lua_msg!({ abandoned: 2 }))
+            );
+            System::current().stop();
+            Ok::<(), ()>(())
+        });
+        Arbiter::spawn(l);
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_build_info() {
+        let system = System::new("test");
+
+        let received = Arc::new(std::sync::Mutex::new(vec![]));
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == "stop" then
+                ctx.spawn_group():spawn(function () ctx.send("slow", 1) end)
+                ctx.terminate()
+                return
+            end
+            local ok, err = pcall(function () ctx.build.version = "2.0.0" end)
+            local child = ctx.send("worker", "version")
+            ctx.do_send("probe", {
+                version = ctx.build.version,
+                read_only = not ok and err:find("ctx.build is read-only", 1, true) ~= nil,
+                child = child,
+            })
+            "#,
+            )
+            .on_internal_error_with_lua(r#"ctx.do_send("probe", ctx.msg)"#)
+            .with_build_info(crate::lua_msg!({ version: "1.2.3" }))
+            .with_child(
+                "worker",
+                LuaActorBuilder::new().on_handle_with_lua("return ctx.build.version"),
+            )
+            .with_recipient(
+                "slow",
+                Child {
+                    name: "slow",
+                    delay: Duration::from_secs(1),
+                }
+                .start()
+                .recipient(),
+            )
+            .with_recipient("probe", Probe(received.clone()).start().recipient())
+            .build()
+            .unwrap()
+            .start();
+        let plain = lua_actor_with_handle("return next(ctx.build) == nil").start();
+
+        addr.do_send(LuaMessage::from("info"));
+        let l = Delay::new(Duration::from_millis(100))
+            .then(move |_| {
+                addr.do_send(LuaMessage::from("stop"));
+                plain.send(LuaMessage::from("empty?"))
+            })
+            .and_then(|empty| Delay::new(Duration::from_millis(100)).then(move |_| Ok(empty)))
+            .map(move |empty| {
+                assert_eq!(empty, LuaMessage::from(true));
+                let received = received.lock().unwrap();
+                assert_eq!(received.len(), 2, "{:?}", received);
+                assert_eq!(
+                    received[0],
+                    crate::lua_msg!({ version: "1.2.3", read_only: true, child: "1.2.3" })
+                );
+                assert_eq!(
+                    received[1].get("subsystem"),
+                    Some(&LuaMessage::from("spawn_group"))
+                );
+                assert_eq!(
+                    received[1].get("build"),
+                    Some(&crate::lua_msg!({ version: "1.2.3" }))
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_warnings() {
+        use std::sync::{Arc, Mutex};
+
+        let system = System::new("test");
+
+        let captured = Arc::new(Mutex::new(vec![]));
+        let captured2 = captured.clone();
+        let addr = LuaActorBuilder::new()
+            .with_name("warned")
+            .with_warning_interval(Duration::from_millis(200))
+            .with_warning_callback(move |w| captured2.lock().unwrap().push(w.clone()))
+            .on_handle_with_lua(
+                r#"
+                ctx.deprecated("old_call is deprecated, use new_call")
+                if ctx.msg == "replace" then
+                    ctx.state = {}
+                end
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        for _ in 0..3 {
+            addr.do_send(LuaMessage::Nil);
+        }
+        addr.do_send(LuaMessage::from("replace"));
+        addr.do_send(LuaMessage::from("replace"));
+        let l = addr
+            .send(LuaMessage::Nil)
+            .and_then(|_| Delay::new(Duration::from_millis(250)).then(|_| Ok(())))
+            .and_then(move |_| addr.send(LuaMessage::Nil))
+            .map(move |_| {
+                let captured = captured.lock().unwrap();
+                let keys: Vec<_> = captured.iter().map(|w| w.key.as_str()).collect();
+                assert_eq!(
+                    keys,
+                    vec![
+                        "deprecated: old_call is deprecated, use new_call",
+                        "state_replaced",
+                        // after the interval
+                        "deprecated: old_call is deprecated, use new_call",
+                    ]
+                );
+                assert_eq!(captured[0].actor, "warned");
+                assert_eq!(captured[0].message, "old_call is deprecated, use new_call");
+                assert!(captured[1].message.contains("ctx.state was replaced"));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_ping_pong_stress() {
+        use futures::future::{self, Loop};
+
+        // concurrent chains of round trips, more would flood the mailboxes
+        const CHAINS: i64 = 200;
+        const ROUND_TRIPS: i64 = 25;
+        // sends to `peer`, notifies itself
+        const PING: &str = r#"
+            if ctx.msg == "get" then
+                return ctx.state
+            end
+            if ctx.msg.notified then
+                ctx.state.notified = (ctx.state.notified or 0) + 1
+                return
+            end
+            ctx.notify({notified = true})
+            local n = ctx.send("peer", {n = ctx.msg.n})
+            assert(n == ctx.msg.n)
+            ctx.state.replies = (ctx.state.replies or 0) + 1
+        "#;
+        // replies, and sends the next hop of the chain back without waiting
+        const PONG: &str = r#"
+            if ctx.msg == "get" then
+                return ctx.state
+            end
+            ctx.state.handled = (ctx.state.handled or 0) + 1
+            if ctx.msg.n > 0 then
+                ctx.do_send("peer", {n = ctx.msg.n - 1})
+            end
+            return ctx.msg.n
+        "#;
+
+        let system = System::new("test");
+
+        let b = LuaActorBuilder::new()
+            .on_handle_with_lua(PONG)
+            .build()
+            .unwrap()
+            .start();
+        let mut a = LuaActorBuilder::new()
+            .on_handle_with_lua(PING)
+            .build()
+            .unwrap();
+        a.add_lua_recipient("peer", &b).unwrap();
+        let a = a.start();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let poll = a.clone();
+        let start = a.clone();
+        let l = b
+            .send(AddRecipient {
+                name: "peer".to_string(),
+                recipient: a.clone().recipient(),
+            })
+            .map(move |_| {
+                // interleaving the sends, send results, notifications and messages of both actors
+                for _ in 0..CHAINS {
+                    start.do_send(crate::lua_msg!({ n: ROUND_TRIPS - 1 }));
+                }
+            })
+            .and_then(move |_| {
+                future::loop_fn((), move |_| {
+                    let poll = poll.clone();
+                    Delay::new(Duration::from_millis(20))
+                        .then(move |_| poll.send(LuaMessage::from("get")))
+                        .map(move |state| match state.get("replies") {
+                            Some(LuaMessage::Integer(n)) if *n == ROUND_TRIPS * CHAINS => {
+                                Loop::Break(())
+                            }
+                            _ if Instant::now() > deadline => {
+                                panic!("chains didn't complete: {:?}", state)
+                            }
+                            _ => Loop::Continue(()),
+                        })
+                })
+            })
+            .and_then(move |_| {
+                a.send(LuaMessage::from("get"))
+                    .join(b.send(LuaMessage::from("get")))
+            })
+            .map(|(a, b)| {
+                assert_eq!(
+                    a.get("notified"),
+                    Some(&LuaMessage::from(ROUND_TRIPS * CHAINS))
+                );
+                assert_eq!(
+                    b.get("handled"),
+                    Some(&LuaMessage::from(ROUND_TRIPS * CHAINS))
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_missing_handler() {
+        let system = System::new("test");
+
+        let nil = LuaActorBuilder::new().build().unwrap().start();
+        let error = LuaActorBuilder::new()
+            .with_missing_handler(MissingHandler::Error)
+            .build()
+            .unwrap()
+            .start();
+        let backend = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return {got = ctx.msg}"#)
+            .build()
+            .unwrap()
+            .start();
+        let proxy = LuaActorBuilder::new()
+            .with_missing_handler(MissingHandler::Forward("backend".to_string()))
+            .with_recipient("backend", backend.recipient())
+            .build()
+            .unwrap()
+            .start();
+
+        let original = crate::lua_msg!({id: 1, tags: ["a", "b"]});
+        let expected = crate::lua_msg!({got: {id: 1, tags: ["a", "b"]}});
+
+        let l = nil
+            .send(LuaMessage::from(1))
+            .join(nil.send(TryLuaMessage(LuaMessage::from(1))))
+            .join(error.send(LuaMessage::from(1)))
+            .join(error.send(TryLuaMessage(LuaMessage::from(1))))
+            .join(proxy.send(original.clone()))
+            .join(proxy.send(TryLuaMessage(original)))
+            .map(
+                move |(((((nil, nil_try), error), error_try), forwarded), forwarded_try)| {
+                    assert_eq!(nil, LuaMessage::Nil);
+                    assert_eq!(nil_try, Ok(LuaMessage::Nil));
+                    assert_eq!(error.get("error"), Some(&LuaMessage::from("no_handler")));
+                    assert_eq!(error_try, Err(HandleError::NoHandler));
+                    assert_eq!(forwarded, expected);
+                    assert_eq!(forwarded_try, Ok(expected));
+                    System::current().stop();
+                },
+            );
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_terminal_reply() {
+        let system = System::new("test");
+
+        let handled = Arc::new(std::sync::Mutex::new(vec![]));
+        let unprocessed = Arc::new(std::sync::Mutex::new(vec![]));
+        // the messages wait for the dependency, the first one stops the actor
+        let addr = LuaActorBuilder::new()
+            .require_recipients(&["db"], Duration::from_secs(5))
+            .on_handle_with_lua(
+                r#"
+                ctx.do_send("handled", ctx.msg)
+                if ctx.msg == 1 then
+                    ctx.terminate()
+                end
+                return "done"
+                "#,
+            )
+            .with_terminal_reply(crate::lua_msg!({ error: "stopping" }))
+            .with_unprocessed_sink("sink")
+            .with_recipient("handled", Probe(handled.clone()).start().recipient())
+            .with_recipient("sink", Probe(unprocessed.clone()).start().recipient())
+            .build()
+            .unwrap()
+            .start();
+
+        let replies = addr.send(LuaMessage::from(1)).join3(
+            addr.send(LuaMessage::from(2)),
+            addr.send(LuaMessage::from(3)),
+        );
+        let l = addr
+            .send(AddRecipient {
+                name: "db".to_string(),
+                recipient: Echo.start().recipient(),
+            })
+            .and_then(|_| replies)
+            .and_then(|replies| Delay::new(Duration::from_millis(50)).then(move |_| Ok(replies)))
+            .map(move |(a, b, c)| {
+                assert_eq!(a, LuaMessage::from("done"));
+                assert_eq!(b, crate::lua_msg!({ error: "stopping" }));
+                assert_eq!(c, crate::lua_msg!({ error: "stopping" }));
+                assert_eq!(*handled.lock().unwrap(), vec![LuaMessage::from(1)]);
+                assert_eq!(
+                    *unprocessed.lock().unwrap(),
+                    vec![LuaMessage::from(2), LuaMessage::from(3)]
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_stopped_deadline() {
+        use std::sync::{Arc, Mutex};
+
+        let system = System::new("test");
+        let captured = Arc::new(Mutex::new(vec![]));
+        let captured2 = captured.clone();
+        let addr = LuaActorBuilder::new()
+            .with_name("stuck")
+            .with_stopped_deadline(Duration::from_millis(200))
+            .with_warning_callback(move |w| captured2.lock().unwrap().push(w.clone()))
+            .on_handle_with_lua(r#"ctx.terminate()"#)
+            .on_stopped_with_lua(r#"while true do end"#)
+            .build()
+            .unwrap()
+            .start();
 
-                assert_eq!(res, LuaMessage::from(t));
-                System::current().stop();
-            })
-            .map_err(|e| println!("actor dead {}", e)),
-        );
+        let start = Instant::now();
+        let l = addr
+            .send(LuaMessage::Nil)
+            .and_then(|_| Delay::new(Duration::from_millis(50)).then(|_| Ok(())))
+            .map(|_| System::current().stop());
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
 
         system.run();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        let captured = captured.lock().unwrap();
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0].actor, "stuck");
+        assert_eq!(captured[0].key, "stopped_deadline");
     }
 
-    #[test]
-    fn lua_actor_state() {
+    // Run an interval of 200ms whose first tick blocks the actor for 3.5 periods, returning the
+    // number of ticks handled and the sum of their `missed` field.
+    fn interval_ticks(policy: &str) -> (i64, i64) {
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+
         let system = System::new("test");
 
-        let lua_addr = lua_actor_with_handle(
-            r#"
-        if not ctx.state.x then ctx.state.x = 0 end
+        let vm = Lua::new();
+        vm.context(|ctx| {
+            ctx.globals()
+                .set(
+                    "block",
+                    ctx.create_function(|_, ms: u64| {
+                        thread::sleep(Duration::from_millis(ms));
+                        Ok(())
+                    })
+                    .unwrap(),
+                )
+                .unwrap();
+        });
 
-        ctx.state.x = ctx.state.x + 1
-        return ctx.state.x
-        "#,
-        )
-        .start();
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(&format!(
+                r#"
+                ctx.state.ticks = 0
+                ctx.state.missed = 0
+                ctx.state.id = ctx.run_interval(0.2, {{tick = true}}, "{}")
+                "#,
+                policy
+            ))
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "get" then
+                    ctx.cancel_interval(ctx.state.id)
+                    return {ticks = ctx.state.ticks, missed = ctx.state.missed}
+                end
+                assert(ctx.msg.tick)
+                ctx.state.ticks = ctx.state.ticks + 1
+                ctx.state.missed = ctx.state.missed + ctx.msg.missed
+                if ctx.state.ticks == 1 then
+                    block(700)
+                end
+                "#,
+            )
+            .build_with_vm(vm)
+            .unwrap()
+            .start();
 
-        let l = lua_addr.send(LuaMessage::Nil);
-        Arbiter::spawn(
-            l.map(move |res| {
-                assert_eq!(res, LuaMessage::from(1));
-                let l2 = lua_addr.send(LuaMessage::Nil);
-                Arbiter::spawn(
-                    l2.map(|res| {
-                        assert_eq!(res, LuaMessage::from(2));
+        let result = Arc::new(Mutex::new(vec![]));
+        let result2 = result.clone();
+        let get = move |addr: Addr<LuaActor>| {
+            addr.send(LuaMessage::from("get")).map(move |res| {
+                let count = |key| match res.get(key) {
+                    Some(LuaMessage::Integer(n)) => *n,
+                    _ => panic!("unexpected result {:?}", res),
+                };
+                (count("ticks"), count("missed"))
+            })
+        };
+        let addr2 = addr.clone();
+        let l = Delay::new(Duration::from_millis(950))
+            .then(|_| Ok(()))
+            .and_then(move |_| get(addr))
+            .and_then(move |first| {
+                // cancelled, no more ticks
+                Delay::new(Duration::from_millis(300))
+                    .then(|_| Ok(()))
+                    .and_then(move |_| get(addr2))
+                    .map(move |second| {
+                        assert_eq!(first, second);
+                        result2.lock().unwrap().push(first);
                         System::current().stop();
                     })
-                    .map_err(|e| println!("actor dead {}", e)),
-                );
-            })
-            .map_err(|e| println!("actor dead {}", e)),
-        );
+            });
+        Arbiter::spawn(l.map_err(|e: MailboxError| println!("actor dead {}", e)));
 
         system.run();
+        let ticks = result.lock().unwrap()[0];
+        ticks
     }
 
     #[test]
-    fn lua_actor_notify() {
+    fn lua_actor_interval_all() {
+        // the tick at 200ms blocks until 900ms, the 3 ticks due meanwhile are delivered
+        assert_eq!(interval_ticks("all"), (4, 0));
+    }
+
+    #[test]
+    fn lua_actor_interval_coalesce() {
+        assert_eq!(interval_ticks("coalesce"), (2, 2));
+    }
+
+    #[test]
+    fn lua_actor_interval_skip() {
+        assert_eq!(interval_ticks("skip"), (2, 0));
+    }
+
+    const POINT: &str = r#"
+        Point = {}
+        Point.__index = Point
+        function Point.new(x, y)
+            return setmetatable({x = x, y = y}, Point)
+        end
+        function Point:sum()
+            return self.x + self.y
+        end
+        ctx.register_class("Point", Point)
+    "#;
+
+    #[test]
+    fn lua_actor_class_round_trip() {
         let system = System::new("test");
 
-        let addr = LuaActorBuilder::new()
-            .on_started_with_lua(
+        let child = LuaActorBuilder::new()
+            .on_started_with_lua(POINT)
+            .on_handle_with_lua(
                 r#"
-            ctx.notify(100)
-            "#,
+                assert(ctx.msg:sum() == 3)
+                return ctx.msg
+                "#,
             )
+            .build()
+            .unwrap()
+            .start();
+        // didn't register the class
+        let plain = lua_actor_with_handle(
+            r#"return {class = ctx.msg.__class, stripped = getmetatable(ctx.msg) == nil}"#,
+        )
+        .start();
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(POINT)
             .on_handle_with_lua(
                 r#"
-            if ctx.msg == 100 then
-                ctx.state.notified = ctx.msg
-            end
-
-            return ctx.msg + ctx.state.notified
-            "#,
+                if ctx.msg == "get" then
+                    return ctx.state.result
+                end
+                local p = Point.new(1, 2)
+                local echoed = ctx.send("child", p)
+                local plain = ctx.send("plain", p)
+                local unregistered = ctx.send("plain", setmetatable({}, {}))
+                ctx.state.result = {
+                    sum = echoed:sum(),
+                    tagged = rawget(echoed, "__class") ~= nil,
+                    plain = plain,
+                    unregistered = unregistered,
+                }
+                "#,
             )
+            .with_recipient("child", child.recipient())
+            .with_recipient("plain", plain.recipient())
             .build()
             .unwrap()
             .start();
 
-        let delay = Delay::new(Duration::from_secs(1)).map(move |()| {
-            let l = addr.send(LuaMessage::from(1));
-            Arbiter::spawn(
-                l.map(|res| {
-                    assert_eq!(res, LuaMessage::from(101));
-                    System::current().stop();
-                })
-                .map_err(|e| println!("actor dead {}", e)),
-            )
-        });
-        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+        let l = addr
+            .send(LuaMessage::Nil)
+            // wait for the replies
+            .and_then(|_| Delay::new(Duration::from_millis(100)).then(|_| Ok(())))
+            .and_then(move |_| addr.send(LuaMessage::from("get")));
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res.get("sum"), Some(&LuaMessage::from(3)), "{:?}", res);
+                // the marker is removed once the metatable is attached
+                assert_eq!(res.get("tagged"), Some(&LuaMessage::from(false)));
+                let plain = res.get("plain").unwrap();
+                assert_eq!(plain.get("class"), Some(&LuaMessage::from("Point")));
+                assert_eq!(plain.get("stripped"), Some(&LuaMessage::from(true)));
+                assert_eq!(res.get("unregistered").unwrap().get("class"), None);
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
 
         system.run();
     }
 
     #[test]
-    fn lua_actor_notify_later() {
+    fn lua_actor_class_restart() {
         let system = System::new("test");
 
         let addr = LuaActorBuilder::new()
-            .on_started_with_lua(
-                r#"
-            ctx.notify_later(100, 1)
-            "#,
-            )
+            .on_started_with_lua(&format!(
+                "{}\nctx.state.p = ctx.state.p or Point.new(1, 2)",
+                POINT
+            ))
             .on_handle_with_lua(
                 r#"
-            if ctx.msg == 100 then
-                ctx.state.notified = ctx.msg
-            end
-
-            return ctx.msg + ctx.state.notified
-            "#,
+                if ctx.msg == "restart" then
+                    ctx.state.p.x = 10
+                    ctx.restart(true)
+                    return
+                end
+                return ctx.state.p:sum()
+                "#,
             )
             .build()
             .unwrap()
             .start();
 
-        let delay = Delay::new(Duration::from_secs(2)).map(move |()| {
-            let l2 = addr.send(LuaMessage::from(1));
-            Arbiter::spawn(
-                l2.map(|res| {
-                    assert_eq!(res, LuaMessage::from(101));
-                    System::current().stop();
-                })
-                .map_err(|e| println!("actor dead {}", e)),
-            )
-        });
-        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+        let l = addr
+            .send(LuaMessage::from("restart"))
+            .and_then(move |_| addr.send(LuaMessage::Nil));
+        Arbiter::spawn(
+            l.map(|res| {
+                // the state was restored before `started` registered the class
+                assert_eq!(res, LuaMessage::from(12));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
 
         system.run();
     }
 
+    #[cfg(feature = "json")]
     #[test]
-    fn lua_actor_send() {
-        use std::mem::discriminant;
-        let system = System::new("test");
-
-        struct Callback;
-        impl Actor for Callback {
-            type Context = Context<Self>;
-        }
-
-        impl Handler<LuaMessage> for Callback {
-            type Result = LuaMessage;
+    fn lua_actor_class_snapshot() {
+        use crate::codec::JsonCodec;
 
-            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
-                // check msg type
-                assert_eq!(
-                    discriminant(&msg),
-                    discriminant(&LuaMessage::String("foo".to_string()))
-                );
-                if let LuaMessage::String(s) = msg {
-                    assert_eq!(s, "Hello");
-                    System::current().stop();
-                    LuaMessage::Boolean(true)
-                } else {
-                    unimplemented!()
-                }
-            }
-        }
-        let callback_addr = Callback.start();
+        let actor = lua_actor_with_handle("");
+        actor
+            .runtime
+            .vm
+            .context(|ctx| {
+                ctx.load(POINT).exec()?;
+                ctx.load("ctx.state.p = Point.new(1, 2)").exec()
+            })
+            .unwrap();
+        let snapshot = actor.snapshot_state(&JsonCodec).unwrap();
 
-        let mut actor = LuaActorBuilder::new()
-            .on_started_with_lua(
-                r#"
-            local result = ctx.send("callback", "Hello")
-            print("result", "=", result)
-            "#,
-            )
+        let mut restored = LuaActorBuilder::new()
+            .on_started_with_lua(POINT)
+            .on_handle_with_lua(r#"return ctx.state.p:sum()"#)
             .build()
             .unwrap();
+        restored.restore_state(&JsonCodec, &snapshot).unwrap();
+
+        let system = System::new("test");
+        let l = restored.start().send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res, LuaMessage::from(3));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
 
-        actor.add_recipients("callback", callback_addr.recipient());
-        actor.start();
         system.run();
     }
 
     #[test]
-    fn lua_actor_thread_yield() {
-        use std::mem::discriminant;
-        struct Callback;
-        impl Actor for Callback {
-            type Context = Context<Self>;
-        }
-
-        impl Handler<LuaMessage> for Callback {
-            type Result = LuaMessage;
-
-            fn handle(&mut self, _: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
-                LuaMessage::Nil
-            }
-        }
-
+    fn lua_actor_watch() {
         let system = System::new("test");
 
+        let a = lua_actor_with_handle(r#"ctx.terminate()"#).start();
+        let b = lua_actor_with_handle(r#"ctx.terminate()"#).start();
         let mut actor = LuaActorBuilder::new()
+            .with_watch_interval(Duration::from_millis(50))
+            .on_started_with_lua(
+                r#"
+                ctx.state.events = ""
+                function on_recipient_status(name, connected)
+                    ctx.state.events = ctx.state.events .. name .. "=" .. tostring(connected) .. ","
+                end
+                ctx.watch("a")
+                ctx.watch("b")
+                ctx.state.unknown = tostring(select(2, pcall(ctx.watch, "c")))
+                "#,
+            )
             .on_handle_with_lua(
                 r#"
-            local result = ctx.send("callback", "Hello")
-            print(result)
-            return result
-            "#,
+                if ctx.msg == "unwatch" then
+                    ctx.unwatch("b")
+                end
+                return ctx.state
+                "#,
             )
             .build()
             .unwrap();
-
-        actor.add_recipients("callback", Callback.start().recipient());
-
+        actor.add_lua_recipient("a", &a).unwrap();
+        actor.add_lua_recipient("b", &b).unwrap();
         let addr = actor.start();
 
-        let l = addr.send(LuaMessage::Nil);
+        let l = addr
+            .send(LuaMessage::from("unwatch"))
+            .and_then(move |_| {
+                a.do_send(LuaMessage::Nil);
+                b.do_send(LuaMessage::Nil);
+                Delay::new(Duration::from_millis(200)).then(|_| Ok(()))
+            })
+            .and_then(move |_| addr.send(LuaMessage::Nil));
         Arbiter::spawn(
-            l.map(move |res| {
+            l.map(|state| {
+                assert_eq!(state.get("events"), Some(&LuaMessage::from("a=false,")));
                 assert_eq!(
-                    discriminant(&res),
-                    discriminant(&LuaMessage::ThreadYield("foo".to_string()))
+                    state.get("unknown"),
+                    Some(&LuaMessage::from(
+                        "runtime error: ctx.watch: unknown recipient c"
+                    ))
                 );
                 System::current().stop();
             })
@@ -627,80 +6640,111 @@ mod tests {
     }
 
     #[test]
-    fn lua_actor_thread_yield_and_callback_message() {
-        use std::mem::discriminant;
-
-        struct Callback;
-        impl Actor for Callback {
-            type Context = Context<Self>;
-        }
-
-        impl Handler<LuaMessage> for Callback {
-            type Result = LuaMessage;
-
-            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
-                // check msg type
-                assert_eq!(
-                    discriminant(&msg),
-                    discriminant(&LuaMessage::String("foo".to_string()))
-                );
-                if let LuaMessage::String(s) = msg {
-                    assert_eq!(s, "Hello");
-                    LuaMessage::String(format!("{} from callback", s))
-                } else {
-                    unimplemented!()
-                }
-            }
-        }
-
-        struct Check;
-        impl Actor for Check {
-            type Context = Context<Self>;
-        }
+    fn lua_actor_outbox() {
+        let system = System::new("test");
 
-        impl Handler<LuaMessage> for Check {
-            type Result = LuaMessage;
+        let collector = || {
+            lua_actor_with_handle(
+                r#"
+                if ctx.msg == "stop" then
+                    ctx.terminate()
+                elseif ctx.msg == "get" then
+                    return ctx.state.got
+                else
+                    ctx.state.got = ctx.state.got or {}
+                    table.insert(ctx.state.got, ctx.msg)
+                end
+                "#,
+            )
+            .start()
+        };
+        let child = collector();
+        let mut actor = LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.set_outbox("child", {retry_interval = 0.05})"#)
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "send" then
+                    for i = 1, 5 do
+                        ctx.do_send("child", i)
+                    end
+                end
+                return ctx.outbox_depth("child")
+                "#,
+            )
+            .build()
+            .unwrap();
+        actor
+            .try_add_recipients("child", child.clone().recipient())
+            .unwrap();
+        let addr = actor.start();
 
-            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
-                // check msg type
-                assert_eq!(
-                    discriminant(&msg),
-                    discriminant(&LuaMessage::String("foo".to_string()))
-                );
-                if let LuaMessage::String(s) = msg {
-                    assert_eq!(s, "Hello from callback");
-                    System::current().stop();
-                    LuaMessage::Nil
-                } else {
-                    unimplemented!()
-                }
-            }
-        }
+        let l = child
+            .send(LuaMessage::from("stop"))
+            .and_then(|_| Delay::new(Duration::from_millis(50)).then(|_| Ok(())))
+            .and_then({
+                let addr = addr.clone();
+                move |_| addr.send(LuaMessage::from("send"))
+            })
+            .and_then(move |depth| {
+                assert_eq!(depth, LuaMessage::from(5));
+                let replacement = collector();
+                addr.send(AddRecipient {
+                    name: "child".to_string(),
+                    recipient: replacement.clone().recipient(),
+                })
+                .map(|res| assert!(res.unwrap().is_some()))
+                .and_then(|_| Delay::new(Duration::from_millis(100)).then(|_| Ok(())))
+                .and_then(move |_| {
+                    replacement
+                        .send(LuaMessage::from("get"))
+                        .join(addr.send(LuaMessage::from("depth")))
+                })
+            })
+            .map(|(got, depth)| {
+                assert_eq!(got, crate::lua_msg!([1, 2, 3, 4, 5]));
+                assert_eq!(depth, LuaMessage::from(0));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
 
+    #[test]
+    fn lua_actor_watch_handle() {
         let system = System::new("test");
+
+        let child = lua_actor_with_handle(r#"ctx.terminate()"#).start();
         let mut actor = LuaActorBuilder::new()
+            .with_watch_interval(Duration::from_millis(50))
+            .on_started_with_lua(r#"ctx.watch("child")"#)
             .on_handle_with_lua(
                 r#"
-            local result = ctx.send("callback", ctx.msg)
-            print("send result", "=", result)
-            ctx.send("check", result)
-            "#,
+                if ctx.msg.__watch then
+                    ctx.state.event = ctx.msg.__watch
+                end
+                return ctx.state.event
+                "#,
             )
             .build()
             .unwrap();
-
-        actor.add_recipients("callback", Callback.start().recipient());
-        actor.add_recipients("check", Check.start().recipient());
-
+        actor.add_lua_recipient("child", &child).unwrap();
         let addr = actor.start();
 
-        let l = addr.send(LuaMessage::String("Hello".to_string()));
+        let empty = || LuaMessage::from(HashMap::<String, LuaMessage>::new());
+        // stop the child once `started` watched it
+        let l = addr
+            .send(empty())
+            .and_then(move |_| {
+                child.do_send(LuaMessage::Nil);
+                Delay::new(Duration::from_millis(200)).then(|_| Ok(()))
+            })
+            .and_then(move |_| addr.send(empty()));
         Arbiter::spawn(
-            l.map(move |res| {
-                assert_eq!(
-                    discriminant(&res),
-                    discriminant(&LuaMessage::ThreadYield("foo".to_string()))
-                );
+            l.map(|event| {
+                assert_eq!(event.get("name"), Some(&LuaMessage::from("child")));
+                assert_eq!(event.get("connected"), Some(&LuaMessage::from(false)));
+                System::current().stop();
             })
             .map_err(|e| println!("actor dead {}", e)),
         );
@@ -709,51 +6753,133 @@ mod tests {
     }
 
     #[test]
-    fn lua_actor_do_send() {
-        use std::mem::discriminant;
-
-        struct Check;
-        impl Actor for Check {
-            type Context = Context<Self>;
-        }
-
-        impl Handler<LuaMessage> for Check {
-            type Result = LuaMessage;
-
-            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
-                // check msg type
-                assert_eq!(
-                    discriminant(&msg),
-                    discriminant(&LuaMessage::String("foo".to_string()))
-                );
-                if let LuaMessage::String(s) = msg {
-                    assert_eq!(s, "Hello");
-                    System::current().stop();
-                    LuaMessage::Nil
-                } else {
-                    unimplemented!()
-                }
-            }
-        }
+    fn lua_actor_watch_handle_error() {
         let system = System::new("test");
 
+        let child = lua_actor_with_handle(r#"ctx.terminate()"#).start();
         let mut actor = LuaActorBuilder::new()
+            .with_watch_interval(Duration::from_millis(50))
+            .on_started_with_lua(r#"ctx.watch("child")"#)
             .on_handle_with_lua(
                 r#"
-            local result = ctx.do_send("check", "Hello")
-            print("new actor addr name", rec, result)
-            return ctx.msg
-            "#,
+                if ctx.msg.__watch then
+                    error("boom")
+                end
+                return "alive"
+                "#,
             )
             .build()
             .unwrap();
-        actor.add_recipients("check", Check.start().recipient());
+        actor.add_lua_recipient("child", &child).unwrap();
         let addr = actor.start();
 
-        let l = addr.send(LuaMessage::Nil);
+        let empty = || LuaMessage::from(HashMap::<String, LuaMessage>::new());
+        // the failed `handle` of the event doesn't stop the actor
+        let l = addr
+            .send(empty())
+            .and_then(move |_| {
+                child.do_send(LuaMessage::Nil);
+                Delay::new(Duration::from_millis(200)).then(|_| Ok(()))
+            })
+            .and_then(move |_| addr.send(empty()))
+            .map(|res| {
+                assert_eq!(res, LuaMessage::from("alive"));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_idempotency_try() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_idempotency("msg_id", Duration::from_secs(10), true)
+            .on_started_with_lua(r#"ctx.state.runs = 0"#)
+            .on_handle_with_lua(
+                r#"
+                ctx.state.runs = ctx.state.runs + 1
+                return ctx.state.runs
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let msg = || TryLuaMessage(crate::lua_msg!({ msg_id: "a" }));
+        let l = addr
+            .send(msg())
+            .and_then(move |first| addr.send(msg()).map(move |second| (first, second)))
+            .map(|(first, second)| {
+                // the duplicate gets the cached reply without running `handle`
+                assert_eq!(first, Ok(LuaMessage::from(1)));
+                assert_eq!(second, Ok(LuaMessage::from(1)));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_idempotency() {
+        let system = System::new("test");
+
+        let msg = |id: Option<&str>| {
+            let mut meta = HashMap::new();
+            if let Some(id) = id {
+                meta.insert("msg_id".to_string(), LuaMessage::from(id));
+            }
+            let mut msg = HashMap::new();
+            msg.insert("meta".to_string(), LuaMessage::from(meta));
+            LuaMessage::from(msg)
+        };
+        let build = |cache_results| {
+            LuaActorBuilder::new()
+                .with_idempotency("meta.msg_id", Duration::from_millis(200), cache_results)
+                .on_started_with_lua(r#"ctx.state.runs = 0"#)
+                .on_handle_with_lua(
+                    r#"
+                    ctx.state.runs = ctx.state.runs + 1
+                    return ctx.state.runs
+                    "#,
+                )
+                .build()
+                .unwrap()
+                .start()
+        };
+        let addr = build(true);
+        let uncached = build(false);
+
+        let l = addr
+            .send(msg(Some("a")))
+            .join4(
+                addr.send(msg(Some("a"))),
+                // no key
+                addr.send(msg(None)),
+                addr.send(msg(Some("b"))),
+            )
+            .join(
+                uncached
+                    .send(msg(Some("a")))
+                    .join(uncached.send(msg(Some("a")))),
+            )
+            .and_then(|res| Delay::new(Duration::from_millis(250)).then(|_| Ok(res)))
+            .and_then(move |res| addr.send(msg(Some("a"))).map(|again| (res, again)));
         Arbiter::spawn(
-            l.map(|res| {
-                assert_eq!(res, LuaMessage::Nil);
+            l.map(|(((first, duplicate, keyless, other), uncached), again)| {
+                assert_eq!(first, LuaMessage::from(1));
+                // the handler ran once
+                assert_eq!(duplicate, LuaMessage::from(1));
+                assert_eq!(keyless, LuaMessage::from(2));
+                assert_eq!(other, LuaMessage::from(3));
+                assert_eq!(uncached.0, LuaMessage::from(1));
+                assert_eq!(uncached.1.get("duplicate"), Some(&LuaMessage::from(true)));
+                // after the window
+                assert_eq!(again, LuaMessage::from(4));
+                System::current().stop();
             })
             .map_err(|e| println!("actor dead {}", e)),
         );
@@ -762,91 +6888,496 @@ mod tests {
     }
 
     #[test]
-    fn lua_actor_terminate() {
-        // TODO: validate on_stopped is called
+    fn lua_actor_state_diffing() {
         let system = System::new("test");
 
-        let _ = LuaActorBuilder::new()
+        let script = r#"
+            if ctx.msg == "init" then
+                ctx.no_diff("cache")
+                ctx.state.count = 1
+                ctx.state.gone = true
+                ctx.state.cache = {}
+                ctx.ns("m").hits = 0
+            elseif ctx.msg == "change" then
+                ctx.state.count = 2
+                ctx.state.gone = nil
+                ctx.state.cache.x = 1
+                ctx.state.added = ctx.null
+                ctx.ns("m").hits = 1
+            else
+                return ctx.last_diff()
+            end
+        "#;
+        let diffing = LuaActorBuilder::new()
+            .with_state_diffing(true)
+            .on_handle_with_lua(script)
+            .build()
+            .unwrap()
+            .start();
+        let plain = lua_actor_with_handle(script).start();
+
+        let l = diffing
+            .send(LuaMessage::from("init"))
+            .and_then(move |_| {
+                diffing
+                    .send(LuaMessage::from("change"))
+                    .and_then(move |_| diffing.send(LuaMessage::from("diff")))
+            })
+            .join(
+                plain
+                    .send(LuaMessage::from("init"))
+                    .and_then(move |_| plain.send(LuaMessage::from("diff"))),
+            )
+            .map(|(diff, plain)| {
+                crate::assert_lua_eq!(
+                    diff,
+                    crate::lua_msg!([
+                        {op: "changed", path: "__ns.m.hits", old: 0, new: 1},
+                        {op: "added", path: "added", new: nil},
+                        {op: "changed", path: "count", old: 1, new: 2},
+                        {op: "removed", path: "gone", old: true},
+                    ])
+                );
+                assert_eq!(plain, LuaMessage::Nil);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_read_only_state() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_read_only_state(true)
             .on_started_with_lua(
                 r#"
-            ctx.terminate()
-            "#,
+                ctx.state.count = 1
+                ctx.state.nested = { items = { 1, 2 } }
+                "#,
+            )
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "replace" then
+                    ctx.state = {}
+                    return
+                end
+                local function write(f)
+                    local ok, err = pcall(f)
+                    return not ok and err:find("state is read-only in this actor", 1, true) ~= nil
+                end
+                local seen = 0
+                for _, v in ipairs(ctx.state.nested.items) do
+                    seen = seen + v
+                end
+                local state = ctx.state
+                return {
+                    count = state.count,
+                    len = #state.nested.items,
+                    seen = seen,
+                    top = write(function () state.count = 2 end),
+                    new_field = write(function () state.other = 1 end),
+                    nested = write(function () state.nested.items[1] = 3 end),
+                    insert = write(function () table.insert(state.nested.items, 3) end),
+                    rawset = write(function () rawset(state.nested, "x", 1) end),
+                }
+                "#,
             )
-            .on_stopped_with_lua(r#"print("stopped")"#)
             .build()
             .unwrap()
             .start();
-        let delay = Delay::new(Duration::from_secs(1)).map(move |()| {
-            System::current().stop();
-        });
-        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+
+        let l = addr
+            .send(LuaMessage::from("replace"))
+            .and_then(move |_| addr.send(LuaMessage::Nil))
+            .map(|res| {
+                crate::assert_lua_eq!(
+                    res,
+                    crate::lua_msg!({
+                        count: 1,
+                        len: 2,
+                        seen: 3,
+                        top: true,
+                        new_field: true,
+                        nested: true,
+                        insert: true,
+                        rawset: true,
+                    })
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
 
         system.run();
     }
 
-    use std::env;
+    #[test]
+    fn lua_actor_error_coalescing() {
+        use std::sync::{Arc, Mutex};
+
+        let system = System::new("test");
+        let captured = Arc::new(Mutex::new(vec![]));
+        let captured2 = captured.clone();
+        let addr = LuaActorBuilder::new()
+            .with_error_coalescing(Duration::from_millis(200))
+            .with_error_burst_callback(move |b| captured2.lock().unwrap().push(b.clone()))
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "other" then error("other") end
+                error("poison")
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let l = futures::future::lazy(move || {
+            let mut sends: Vec<_> = (0..100)
+                .map(|_| addr.send(TryLuaMessage(LuaMessage::from("poison"))))
+                .collect();
+            sends.push(addr.send(TryLuaMessage(LuaMessage::from("other"))));
+            futures::future::join_all(sends)
+        })
+        .map(|results| {
+            // every caller gets its error
+            assert!(results
+                .iter()
+                .all(|r| matches!(r, Err(HandleError::Error(_)))));
+        })
+        .and_then(|_| Delay::new(Duration::from_millis(400)).then(|_| Ok(())))
+        .map(|_| System::current().stop());
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+        let mut bursts: Vec<_> = captured
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|b| (b.count, b.hook.clone(), b.error.contains("poison")))
+            .collect();
+        bursts.sort();
+        assert_eq!(
+            bursts,
+            vec![
+                (1, "handle".to_string(), false),
+                (100, "handle".to_string(), true)
+            ]
+        );
+    }
 
     #[test]
-    fn lua_actor_require() {
+    fn lua_actor_gc_watermark() {
+        use futures::Stream;
         let system = System::new("test");
-        env::set_var("LUA_PATH", "./src/?.lua;;");
 
+        // without the watermark, the garbage of 1000 messages exceeds the memory limit
         let addr = LuaActorBuilder::new()
+            .on_started_with_lua(r#"collectgarbage("stop")"#)
             .on_handle_with_lua(
                 r#"
-                local m = require('lua/test/module')
-                return m.incr(ctx.msg)
-            "#,
+                local garbage = {}
+                for i = 1, 100 do
+                    garbage[i] = string.rep("x", 100) .. i
+                end
+                return ctx.runtime.gc and ctx.runtime.gc.collections or 0
+                "#,
             )
+            .with_memory_limit(1024 * 1024)
+            .with_gc_watermark(0.5)
             .build()
             .unwrap()
             .start();
-        let l = addr.send(LuaMessage::from(1));
-        Arbiter::spawn(
-            l.map(|res| {
-                assert_eq!(res, LuaMessage::from(2));
+
+        let l = futures::stream::iter_ok::<_, MailboxError>(0..1000)
+            .fold(LuaMessage::Nil, move |_, i| addr.send(LuaMessage::from(i)))
+            .map(|collections| {
+                match collections {
+                    LuaMessage::Integer(n) => assert!(n > 0, "{}", n),
+                    other => panic!("unexpected reply {:?}", other),
+                }
                 System::current().stop();
-            })
-            .map_err(|e| println!("actor dead {}", e)),
-        );
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
 
         system.run();
     }
 
     #[test]
-    fn lua_actor_with_vm() {
+    fn lua_actor_gc_watermark_leak() {
+        use futures::Stream;
         let system = System::new("test");
 
-        let vm = Lua::new();
-        vm.context(|ctx| {
-            ctx.globals()
-                .set(
-                    "greet",
-                    ctx.create_function(|_, name: String| Ok(format!("Hello, {}!", name)))
-                        .unwrap(),
-                )
-                .unwrap();
-        });
-
         let addr = LuaActorBuilder::new()
             .on_handle_with_lua(
                 r#"
-            return greet(ctx.msg)
-            "#,
+                if ctx.msg == "leak" then
+                    return ctx.state.leak
+                end
+                ctx.state.kept = ctx.state.kept or {}
+                table.insert(ctx.state.kept, string.rep("x", 1000) .. ctx.msg)
+                "#,
             )
-            .build_with_vm(vm)
+            .on_internal_error_with_lua(
+                r#"
+                if ctx.msg.subsystem == "gc" then
+                    ctx.state.leak = ctx.msg.detail
+                end
+                "#,
+            )
+            .with_memory_limit(1024 * 1024)
+            .with_gc_watermark(0.3)
+            .build()
             .unwrap()
             .start();
 
-        let l = addr.send(LuaMessage::from("World"));
-        Arbiter::spawn(
-            l.map(|res| {
-                assert_eq!(res, LuaMessage::from("Hello, World!"));
+        let l = futures::stream::iter_ok::<_, MailboxError>(0..400)
+            .for_each({
+                let addr = addr.clone();
+                move |i| addr.send(LuaMessage::from(i)).map(|_| ())
+            })
+            .and_then(|_| Delay::new(Duration::from_millis(50)).then(|_| Ok(())))
+            .and_then(move |_| addr.send(LuaMessage::from("leak")))
+            .map(|leak| {
+                assert_eq!(leak.get("attempts"), Some(&LuaMessage::from(3)));
+                assert_eq!(leak.get("limit"), Some(&LuaMessage::from(1024 * 1024)));
                 System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_memory_limit() {
+        let system = System::new("test");
+
+        let actor = |limit: Option<usize>| {
+            let builder = LuaActorBuilder::new().on_handle_with_lua(
+                r#"
+                if ctx.msg == "grow" then
+                    local t = {}
+                    for i = 1, 100000 do
+                        t[i] = "value " .. i
+                    end
+                    return #t
+                end
+                return ctx.msg
+                "#,
+            );
+            match limit {
+                Some(limit) => builder.with_memory_limit(limit),
+                None => builder,
+            }
+            .build()
+            .unwrap()
+            .start()
+        };
+        let limited = actor(Some(1024 * 1024));
+        let unlimited = actor(None);
+
+        let l = limited
+            .send(LuaMessage::from("grow"))
+            .join(unlimited.send(LuaMessage::from("grow")))
+            .and_then(move |(failed, grown)| {
+                match failed {
+                    LuaMessage::Error { ref message, .. } => {
+                        assert!(message.contains("not enough memory"), "{}", message)
+                    }
+                    other => panic!("unexpected reply {:?}", other),
+                }
+                assert_eq!(grown, LuaMessage::from(100_000));
+                // the limit stays, the next messages are handled
+                limited
+                    .send(LuaMessage::from("ping"))
+                    .join(limited.send(GetLuaMemory))
+                    .join(unlimited.send(GetLuaMemory))
             })
-            .map_err(|e| println!("actor dead {}", e)),
+            .map(|((pong, memory), unlimited)| {
+                assert_eq!(pong, LuaMessage::from("ping"));
+                assert_eq!(memory.get("limit"), Some(&LuaMessage::from(1024 * 1024)));
+                match memory.get("used") {
+                    Some(&LuaMessage::Integer(used)) => assert!(used <= 1024 * 1024, "{}", used),
+                    other => panic!("unexpected used memory {:?}", other),
+                }
+                assert_eq!(unlimited.get("limit"), None);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_gc_watermark_requires_memory_limit() {
+        let res = LuaActorBuilder::new().with_gc_watermark(0.8).build();
+        assert!(format!("{:?}", res.err()).contains("requires with_memory_limit"));
+        let res = LuaActorBuilder::new()
+            .with_memory_limit(1024 * 1024)
+            .with_gc_watermark(1.5)
+            .build();
+        assert!(format!("{:?}", res.err()).contains("invalid GC watermark"));
+    }
+
+    fn isolated_hooks_actor(isolated: bool) -> Addr<LuaActor> {
+        LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+                function helper() return "helper" end
+                ctx.shared.helper = helper
+                declare_global("counter", 0)
+                "#,
+            )
+            .on_handle_with_lua(
+                r#"
+                counter = counter + 1
+                local local_helper = helper and helper()
+                return { local_helper = local_helper, shared = ctx.shared.helper(), counter = counter,
+                    string = string.upper("x") }
+                "#,
+            )
+            .with_isolated_hooks(isolated)
+            .build()
+            .unwrap()
+            .start()
+    }
+
+    #[test]
+    fn lua_actor_isolated_hooks() {
+        let system = System::new("test");
+
+        let isolated = isolated_hooks_actor(true);
+        let shared = isolated_hooks_actor(false);
+        let l = isolated
+            .send(LuaMessage::Nil)
+            .and_then(move |_| isolated.send(LuaMessage::Nil))
+            .join(shared.send(LuaMessage::Nil))
+            .map(|(isolated, shared)| {
+                assert_eq!(
+                    isolated,
+                    crate::lua_msg!({ shared: "helper", counter: 2, string: "X" })
+                );
+                assert_eq!(
+                    shared,
+                    crate::lua_msg!({ local_helper: "helper", shared: "helper", counter: 1, string: "X" })
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_isolated_hooks_strict_globals() {
+        let mut vm = crate::testing::ScriptVm::new(
+            &LuaActorBuilder::new()
+                .on_handle_with_lua("x = 1")
+                .with_isolated_hooks(true)
+                .with_strict_globals(true),
+        )
+        .unwrap();
+        let err = vm.handle(LuaMessage::Nil).unwrap_err();
+        assert!(
+            format!("{:?}", err).contains("assignment to undeclared global 'x'"),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn lua_actor_msg_is_a_copy() {
+        let system = System::new("test");
+
+        let child = lua_actor_with_handle(
+            r#"
+            if ctx.msg == "get" then
+                return ctx.state.got
+            end
+            ctx.state.got = ctx.msg
+            "#,
+        )
+        .start();
+        let mut actor = lua_actor_with_handle(
+            r#"
+            ctx.msg.seen = true
+            ctx.msg.nested.n = 2
+            ctx.do_send("child", ctx.msg)
+            return ctx.msg
+            "#,
         );
+        actor
+            .try_add_recipients("child", child.clone().recipient())
+            .unwrap();
+        let addr = actor.start();
+
+        let msg = crate::lua_msg!({ nested: { n: 1 } });
+        let l = addr
+            .send(msg.clone())
+            .and_then(move |reply| child.send(LuaMessage::from("get")).map(|got| (reply, got)))
+            .map(move |(reply, got)| {
+                let mutated = crate::lua_msg!({ seen: true, nested: { n: 2 } });
+                assert_eq!(reply, mutated);
+                assert_eq!(got, mutated);
+                assert_eq!(msg, crate::lua_msg!({ nested: { n: 1 } }));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
 
         system.run();
     }
+
+    #[test]
+    fn lua_actor_frozen_msg() {
+        let mut vm = crate::testing::ScriptVm::new(
+            &LuaActorBuilder::new()
+                .on_handle_with_lua(
+                    r#"
+                    if ctx.msg.op == "write" then
+                        ctx.msg.x = 1
+                    elseif ctx.msg.op == "nested" then
+                        local ok, err = pcall(function () ctx.msg.nested.x = 1 end)
+                        local ok2, err2 = pcall(rawset, ctx.msg, "x", 1)
+                        local keys = 0
+                        for _ in pairs(ctx.msg.nested) do
+                            keys = keys + 1
+                        end
+                        return { ok = ok or ok2, err = tostring(err), keys = keys }
+                    else
+                        return ctx.msg
+                    end
+                    "#,
+                )
+                .with_frozen_msg(true),
+        )
+        .unwrap();
+
+        let err = vm.handle(crate::lua_msg!({ op: "write" })).unwrap_err();
+        assert!(
+            format!("{:?}", err).contains("ctx.msg is read-only in this actor"),
+            "{:?}",
+            err
+        );
+
+        let res = vm
+            .handle(crate::lua_msg!({ op: "nested", nested: { a: 1, b: 2, c: 3 } }))
+            .unwrap();
+        assert_eq!(res.get("ok"), Some(&LuaMessage::from(false)));
+        assert_eq!(res.get("keys"), Some(&LuaMessage::from(3)));
+        match res.get("err") {
+            Some(LuaMessage::String(err)) => {
+                assert!(
+                    err.contains("ctx.msg is read-only in this actor"),
+                    "{}",
+                    err
+                )
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        // the views convert to the messages behind them
+        let msg = crate::lua_msg!({ op: "echo", nested: { x: 1 } });
+        assert_eq!(vm.handle(msg.clone()).unwrap(), msg);
+    }
 }
@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Configured by `LuaActorBuilder::with_circuit_breaker`.
+#[derive(Clone)]
+pub(crate) struct BreakerConfig {
+    pub failures: usize,
+    pub window: Duration,
+    pub cooldown: Duration,
+}
+
+enum State {
+    // times of the failures within the window
+    Closed(VecDeque<Instant>),
+    Open { until: Instant },
+    // a probe request is in flight, counted as failed if it didn't complete by the deadline
+    HalfOpen { deadline: Instant },
+}
+
+// Circuit breaker of a recipient of `ctx.send`.
+//
+// Time is passed in by the caller so transitions can be tested without waiting.
+pub(crate) struct CircuitBreaker {
+    config: BreakerConfig,
+    probe_timeout: Duration,
+    state: State,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig, probe_timeout: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            config,
+            probe_timeout,
+            state: State::Closed(VecDeque::new()),
+        }
+    }
+
+    // Check if a request can be sent at `now`, returning how long to wait before retrying if not.
+    //
+    // Once the cooldown elapsed, a single probe request is allowed. A probe which neither
+    // succeeded nor failed within the probe timeout, e.g. because its reply was dropped, failed.
+    pub fn check(&mut self, now: Instant) -> Result<(), Duration> {
+        match self.state {
+            State::Closed(_) => Ok(()),
+            State::Open { until } if now >= until => {
+                self.state = State::HalfOpen {
+                    deadline: now + self.probe_timeout,
+                };
+                Ok(())
+            }
+            State::Open { until } => Err(until - now),
+            State::HalfOpen { deadline } if now >= deadline => {
+                self.state = State::Open {
+                    until: deadline + self.config.cooldown,
+                };
+                self.check(now)
+            }
+            State::HalfOpen { deadline } => Err(deadline - now + self.config.cooldown),
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.state = State::Closed(VecDeque::new());
+    }
+
+    pub fn record_failure(&mut self, now: Instant) {
+        let open = match self.state {
+            State::Closed(ref mut failures) => {
+                failures.push_back(now);
+                while let Some(&t) = failures.front() {
+                    if now.duration_since(t) > self.config.window {
+                        failures.pop_front();
+                    } else {
+                        break;
+                    }
+                }
+                failures.len() >= self.config.failures
+            }
+            // the probe failed
+            State::HalfOpen { .. } => true,
+            State::Open { .. } => false,
+        };
+        if open {
+            self.state = State::Open {
+                until: now + self.config.cooldown,
+            };
+        }
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            State::Closed(_) => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen { .. } => "half_open",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(
+            BreakerConfig {
+                failures: 3,
+                window: Duration::from_secs(10),
+                cooldown: Duration::from_secs(5),
+            },
+            Duration::from_secs(2),
+        )
+    }
+
+    #[test]
+    fn breaker_transitions() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut b = breaker();
+
+        b.record_failure(at(0));
+        b.record_failure(at(1));
+        assert_eq!(b.state_name(), "closed");
+        assert_eq!(b.check(at(1)), Ok(()));
+
+        b.record_failure(at(2));
+        assert_eq!(b.state_name(), "open");
+        assert_eq!(b.check(at(4)), Err(Duration::from_secs(3)));
+
+        // a single probe after the cooldown
+        assert_eq!(b.check(at(7)), Ok(()));
+        assert_eq!(b.state_name(), "half_open");
+        assert!(b.check(at(7)).is_err());
+
+        b.record_success();
+        assert_eq!(b.state_name(), "closed");
+        assert_eq!(b.check(at(8)), Ok(()));
+    }
+
+    #[test]
+    fn breaker_failed_probe() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut b = breaker();
+
+        for t in 0..3 {
+            b.record_failure(at(t));
+        }
+        assert_eq!(b.check(at(8)), Ok(()));
+        b.record_failure(at(8));
+        assert_eq!(b.state_name(), "open");
+        assert_eq!(b.check(at(9)), Err(Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn breaker_window() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut b = breaker();
+
+        // failures older than the window are forgotten
+        b.record_failure(at(0));
+        b.record_failure(at(1));
+        b.record_failure(at(20));
+        assert_eq!(b.state_name(), "closed");
+        b.record_failure(at(21));
+        b.record_failure(at(22));
+        assert_eq!(b.state_name(), "open");
+    }
+
+    #[test]
+    fn breaker_probe_timeout() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut b = breaker();
+
+        for t in 0..3 {
+            b.record_failure(at(t));
+        }
+        assert_eq!(b.check(at(8)), Ok(()));
+        // the probe may still complete
+        assert_eq!(b.check(at(9)), Err(Duration::from_secs(6)));
+        assert_eq!(b.state_name(), "half_open");
+
+        // the probe timed out at 10, the breaker is open until 15
+        assert_eq!(b.check(at(12)), Err(Duration::from_secs(3)));
+        assert_eq!(b.state_name(), "open");
+        assert_eq!(b.check(at(15)), Ok(()));
+        assert_eq!(b.state_name(), "half_open");
+    }
+}
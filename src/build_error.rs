@@ -0,0 +1,76 @@
+use rlua::Error as LuaError;
+use std::fmt;
+
+/// A lifecycle script which failed to load, see `LuaActorBuildError::Scripts`.
+#[derive(Debug, Clone)]
+pub struct ScriptError {
+    /// `"started"`, `"handle"` or `"stopped"`
+    pub hook: &'static str,
+    /// the name of the chunk in the positions of the errors, e.g. `[string "handle"]:1:`
+    pub chunk: String,
+    pub error: LuaError,
+}
+
+/// Why `LuaActorBuilder::build` failed.
+#[derive(Debug, Clone)]
+pub enum LuaActorBuildError {
+    /// the `started`, `handle` and `stopped` scripts which couldn't be read or compiled, in
+    /// this order. All of them are loaded before failing, so each broken script is reported.
+    Scripts(Vec<ScriptError>),
+    /// any other failure, e.g. an invalid option
+    Other(LuaError),
+}
+
+impl fmt::Display for LuaActorBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LuaActorBuildError::Scripts(errors) => {
+                write!(f, "failed to load the scripts:")?;
+                for e in errors {
+                    write!(f, "\n{} ({}): {}", e.hook, e.chunk, e.error)?;
+                }
+                Ok(())
+            }
+            LuaActorBuildError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for LuaActorBuildError {}
+
+impl From<LuaError> for LuaActorBuildError {
+    fn from(e: LuaError) -> LuaActorBuildError {
+        LuaActorBuildError::Other(e)
+    }
+}
+
+// A single broken script keeps its error, several are reported in one `RuntimeError`.
+impl From<LuaActorBuildError> for LuaError {
+    fn from(e: LuaActorBuildError) -> LuaError {
+        match e {
+            LuaActorBuildError::Scripts(mut errors) if errors.len() == 1 => errors.remove(0).error,
+            LuaActorBuildError::Scripts(_) => LuaError::RuntimeError(e.to_string()),
+            LuaActorBuildError::Other(e) => e,
+        }
+    }
+}
+
+// Merge the errors of the scripts which couldn't be read with the result of loading the other
+// ones, in the order of the hooks.
+pub(crate) fn merge_script_errors<T>(
+    mut errors: Vec<ScriptError>,
+    res: Result<T, LuaActorBuildError>,
+) -> Result<T, LuaActorBuildError> {
+    match res {
+        Ok(value) if errors.is_empty() => return Ok(value),
+        Ok(_) => {}
+        Err(LuaActorBuildError::Scripts(loaded)) => errors.extend(loaded),
+        Err(e) => return Err(e),
+    }
+    errors.sort_by_key(|e| {
+        ["started", "handle", "stopped"]
+            .iter()
+            .position(|h| *h == e.hook)
+    });
+    Err(LuaActorBuildError::Scripts(errors))
+}
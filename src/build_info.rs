@@ -0,0 +1,56 @@
+use crate::message::LuaMessage;
+
+// Prefix of the lines logged by an actor, see `LuaActorBuilder::with_log_prefix`.
+pub(crate) const DEFAULT_LOG_PREFIX: &str = "LuaActor {actor}";
+
+// Expand the placeholders of a log prefix template: `{actor}` is the name of the actor and
+// `{build.<field>}` a field of its build info, empty if it has none. Other braces are kept.
+pub(crate) fn format_log_prefix(template: &str, actor: &str, build: &LuaMessage) -> String {
+    let mut prefix = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        prefix.push_str(&rest[..start]);
+        let placeholder = &rest[start + 1..end];
+        if placeholder == "actor" {
+            prefix.push_str(actor);
+        } else if let Some(field) = placeholder.strip_prefix("build.") {
+            match build.get(field) {
+                Some(LuaMessage::String(s)) => prefix.push_str(s),
+                Some(LuaMessage::Nil) | None => {}
+                Some(value) => prefix.push_str(&value.to_string()),
+            }
+        } else {
+            prefix.push_str(&rest[start..=end]);
+        }
+        rest = &rest[end + 1..];
+    }
+    prefix.push_str(rest);
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lua_msg;
+
+    #[test]
+    fn log_prefix_placeholders() {
+        let build = lua_msg!({ version: "1.2.3", git: "abc123", build: 42 });
+        let format = |template| format_log_prefix(template, "worker", &build);
+
+        assert_eq!(format(DEFAULT_LOG_PREFIX), "LuaActor worker");
+        assert_eq!(format("[{actor}@{build.version}]"), "[worker@1.2.3]");
+        assert_eq!(format("{build.git}/{build.build}"), "abc123/42");
+        // unknown fields are empty, other placeholders are kept
+        assert_eq!(format("{actor}{build.missing}"), "worker");
+        assert_eq!(format("{other} {actor"), "{other} {actor");
+        assert_eq!(
+            format_log_prefix("{actor}@{build.version}", "a", &LuaMessage::Nil),
+            "a@"
+        );
+    }
+}
@@ -1,23 +1,209 @@
-use std::fs::File;
-use std::io::prelude::*;
+use std::fmt;
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::actor::LuaActor;
-use rlua::{Error as LuaError, Lua};
+use crate::actor::{
+    validate_recipient_name, Dependencies, LuaActor, MissingHandler, NotifyCoalescing,
+    DEFAULT_MAX_RECIPIENT_NAME_LEN, DEFAULT_NAME, DEFAULT_STOPPED_DEADLINE,
+};
+use crate::breaker::BreakerConfig;
+use crate::build_error::{merge_script_errors, LuaActorBuildError, ScriptError};
+use crate::build_info::{format_log_prefix, DEFAULT_LOG_PREFIX};
+use crate::correlation::DEFAULT_MAX_CORRELATED;
+use crate::dataset::SharedDataset;
+use crate::error_bursts::{ErrorBurst, ErrorBursts};
+use crate::export::DEFAULT_MAX_EXPORT_SIZE;
+use crate::gc::GcWatermark;
+use crate::idempotency::Idempotency;
+use crate::ids::IdSource;
+use crate::message::{ConversionLimits, ConversionOptions, LuaMessage};
+use crate::pause::{Pause, PauseOverflow, DEFAULT_PAUSE_BUFFER};
+use crate::pool::LuaActorPool;
+use crate::rate_limit::{InboundConfig, InboundLimit, ShedPolicy};
+use crate::receipts::{Receipts, DEFAULT_MAX_RECEIPTS};
+use crate::runtime::{
+    new_vm, ApiConfig, LuaRuntime, RustFunction, DEFAULT_COROUTINE_POOL_SIZE, DEFAULT_MAX_DURATION,
+};
+use crate::source::{FileSource, ScriptSource};
+use crate::state_quota::{StateQuota, StateQuotaPolicy};
+use crate::sync_actor::SyncLuaActor;
+use crate::warnings::{Warning, Warnings};
+use crate::watch::DEFAULT_WATCH_INTERVAL;
+use crate::watchdog::{StuckInvocation, Watchdog};
+use actix::dev::channel;
+use actix::msgs::Execute;
+use actix::prelude::*;
+use futures::sync::oneshot;
+use futures::Future;
+use rlua::{Error as LuaError, Function, Lua, StdLib, String as LuaString, Table};
 
 /// `LuaActorBuilder` creates a new `LuaActor` with given Lua script.
+#[derive(Clone)]
 pub struct LuaActorBuilder {
-    started: Option<String>,
-    handle: Option<String>,
-    stopped: Option<String>,
+    started: Option<Script>,
+    handle: Option<Script>,
+    stopped: Option<Script>,
+    internal_error: Option<Script>,
+    migrations: Option<Script>,
+    state_quota_exceeded: Option<Script>,
+    restarting: Option<Script>,
+    max_recipient_name_len: usize,
+    max_duration: Duration,
+    handle_timeout: Option<Duration>,
+    strict_globals: bool,
+    name: String,
+    watchdog: Option<Watchdog>,
+    notify_coalescing: Option<NotifyCoalescing>,
+    circuit_breaker: Option<BreakerConfig>,
+    inbound_rate_limit: Option<(f64, f64, ShedPolicy)>,
+    inbound_cost_field: Option<String>,
+    rich_send_results: bool,
+    send_timeout: Option<Duration>,
+    recipients: Vec<(String, Recipient<LuaMessage>)>,
+    dependencies: Option<Dependencies>,
+    conversion_limits: ConversionLimits,
+    conversion_options: Option<ConversionOptions>,
+    warnings: Warnings,
+    error_bursts: ErrorBursts,
+    watch_interval: Duration,
+    idempotency: Option<Idempotency>,
+    stopped_deadline: Duration,
+    coroutine_pool_size: usize,
+    max_correlated: usize,
+    max_export_size: usize,
+    state_diffing: bool,
+    read_only_state: bool,
+    frozen_msg: bool,
+    lazy_tables: bool,
+    compat_shims: bool,
+    id_seed: Option<u64>,
+    deep_ping: bool,
+    datasets: Vec<(String, SharedDataset)>,
+    children: Vec<(String, LuaActorBuilder)>,
+    memory_limit: Option<usize>,
+    gc_watermark: Option<f64>,
+    state_quota: Option<(usize, StateQuotaPolicy)>,
+    pause_buffer: (usize, PauseOverflow),
+    max_receipts: usize,
+    isolated_hooks: bool,
+    sandbox: bool,
+    package_path: Option<String>,
+    package_cpath: Option<String>,
+    preloaded: Vec<(String, String)>,
+    globals: Vec<(String, LuaMessage)>,
+    functions: Vec<(String, RustFunction)>,
+    script_source: Option<Arc<dyn ScriptSource>>,
+    missing_handler: MissingHandler,
+    terminal_reply: LuaMessage,
+    unprocessed_sink: Option<String>,
+    build_info: LuaMessage,
+    log_prefix: String,
+}
+
+const DEFAULT_WATCHDOG_THRESHOLD: Duration = Duration::from_secs(1);
+
+// Same as the capacity of `Actor::start`.
+const DEFAULT_MAILBOX_CAPACITY: usize = 16;
+
+// Files are read from the script source when the actor is built.
+#[derive(Clone)]
+enum Script {
+    File(String),
+    Lua(String),
+    // a precompiled chunk, see `LuaActorBuilder::compile`
+    Bytecode(Vec<u8>),
+    // a reader failed, `build` returns the error
+    Unreadable(String),
+}
+
+impl Script {
+    fn read<R: Read>(mut reader: R) -> Script {
+        let mut script = String::new();
+        match reader.read_to_string(&mut script) {
+            Ok(_) => Script::Lua(script),
+            Err(e) => Script::Unreadable(e.to_string()),
+        }
+    }
+
+    fn load(&self, source: &dyn ScriptSource) -> Result<Vec<u8>, LuaError> {
+        match self {
+            Script::File(name) => Ok(source.load(name)?.source.into_bytes()),
+            Script::Lua(script) => Ok(script.clone().into_bytes()),
+            Script::Bytecode(chunk) => Ok(chunk.clone()),
+            Script::Unreadable(e) => Err(LuaError::RuntimeError(format!(
+                "failed to read script: {}",
+                e
+            ))),
+        }
+    }
 }
 
 impl Default for LuaActorBuilder {
     fn default() -> LuaActorBuilder {
-        let noop = Some("return".to_string());
+        let noop = Some(Script::Lua("return".to_string()));
         LuaActorBuilder {
             started: noop.clone(),
-            handle: noop.clone(),
+            // a no-op unless `with_missing_handler` is set, see `load_scripts`
+            handle: None,
             stopped: noop.clone(),
+            internal_error: None,
+            migrations: None,
+            state_quota_exceeded: None,
+            restarting: None,
+            max_recipient_name_len: DEFAULT_MAX_RECIPIENT_NAME_LEN,
+            max_duration: DEFAULT_MAX_DURATION,
+            handle_timeout: None,
+            strict_globals: false,
+            name: DEFAULT_NAME.to_string(),
+            watchdog: None,
+            notify_coalescing: None,
+            circuit_breaker: None,
+            inbound_rate_limit: None,
+            inbound_cost_field: None,
+            rich_send_results: false,
+            send_timeout: None,
+            recipients: Vec::new(),
+            dependencies: None,
+            conversion_limits: ConversionLimits::default(),
+            conversion_options: None,
+            warnings: Warnings::default(),
+            error_bursts: ErrorBursts::default(),
+            watch_interval: DEFAULT_WATCH_INTERVAL,
+            idempotency: None,
+            stopped_deadline: DEFAULT_STOPPED_DEADLINE,
+            coroutine_pool_size: DEFAULT_COROUTINE_POOL_SIZE,
+            max_correlated: DEFAULT_MAX_CORRELATED,
+            max_export_size: DEFAULT_MAX_EXPORT_SIZE,
+            state_diffing: false,
+            read_only_state: false,
+            frozen_msg: false,
+            lazy_tables: false,
+            compat_shims: false,
+            id_seed: None,
+            deep_ping: false,
+            datasets: Vec::new(),
+            children: Vec::new(),
+            memory_limit: None,
+            gc_watermark: None,
+            state_quota: None,
+            pause_buffer: (DEFAULT_PAUSE_BUFFER, PauseOverflow::DropNewest),
+            max_receipts: DEFAULT_MAX_RECEIPTS,
+            isolated_hooks: false,
+            sandbox: false,
+            package_path: None,
+            package_cpath: None,
+            preloaded: Vec::new(),
+            globals: Vec::new(),
+            functions: Vec::new(),
+            script_source: None,
+            missing_handler: MissingHandler::ReturnNil,
+            terminal_reply: LuaMessage::Nil,
+            unprocessed_sink: None,
+            build_info: LuaMessage::Nil,
+            log_prefix: DEFAULT_LOG_PREFIX.to_string(),
         }
     }
 }
@@ -28,76 +214,1246 @@ impl LuaActorBuilder {
         LuaActorBuilder::default()
     }
 
-    /// create a `started` hook with given lua file
+    /// create a `started` hook with given lua file, the file is read when the actor is built
     pub fn on_started(mut self, filename: &str) -> Self {
-        self.started = Some(read_to_string(filename));
+        self.started = Some(Script::File(filename.to_string()));
         self
     }
 
     /// create a `started` hook with given lua script
     pub fn on_started_with_lua(mut self, script: &str) -> Self {
-        self.started = Some(script.to_string());
+        self.started = Some(Script::Lua(script.to_string()));
+        self
+    }
+
+    /// create a `started` hook with the lua script read from `reader`
+    ///
+    /// The script is read now, `build` returns the error if it fails.
+    pub fn on_started_with_reader<R: Read>(mut self, reader: R) -> Self {
+        self.started = Some(Script::read(reader));
         self
     }
 
-    /// handle message with given lua file
+    /// create a `started` hook with a chunk precompiled by `LuaActorBuilder::compile`
+    ///
+    /// Loading bytecode skips parsing the script, which speeds up building many actors with the
+    /// same scripts. `build` returns an error for invalid bytecode, or bytecode of another Lua
+    /// version, but the chunks must come from `compile`: Lua doesn't verify bytecode, and a
+    /// forged chunk can crash the VM. Sandboxed actors only load Lua source.
+    pub fn on_started_with_bytecode(mut self, chunk: &[u8]) -> Self {
+        self.started = Some(Script::Bytecode(chunk.to_vec()));
+        self
+    }
+
+    /// handle message with given lua file, the file is read when the actor is built
     pub fn on_handle(mut self, filename: &str) -> Self {
-        self.handle = Some(read_to_string(filename));
+        self.handle = Some(Script::File(filename.to_string()));
         self
     }
 
     /// handle message with given lua script
     pub fn on_handle_with_lua(mut self, script: &str) -> Self {
-        self.handle = Some(script.to_string());
+        self.handle = Some(Script::Lua(script.to_string()));
         self
     }
 
-    /// create a `stopped` hook with given lua file.
+    /// handle message with the lua script read from `reader`, see `on_started_with_reader`
+    pub fn on_handle_with_reader<R: Read>(mut self, reader: R) -> Self {
+        self.handle = Some(Script::read(reader));
+        self
+    }
+
+    /// handle message with a chunk precompiled by `LuaActorBuilder::compile`, see
+    /// `on_started_with_bytecode`
+    pub fn on_handle_with_bytecode(mut self, chunk: &[u8]) -> Self {
+        self.handle = Some(Script::Bytecode(chunk.to_vec()));
+        self
+    }
+
+    /// create a `stopped` hook with given lua file, the file is read when the actor is built
     pub fn on_stopped(mut self, filename: &str) -> Self {
-        self.stopped = Some(read_to_string(filename));
+        self.stopped = Some(Script::File(filename.to_string()));
         self
     }
 
     /// create a `stopped` hook with given lua script
     pub fn on_stopped_with_lua(mut self, script: &str) -> Self {
-        self.stopped = Some(script.to_string());
+        self.stopped = Some(Script::Lua(script.to_string()));
+        self
+    }
+
+    /// create a `stopped` hook with the lua script read from `reader`, see
+    /// `on_started_with_reader`
+    pub fn on_stopped_with_reader<R: Read>(mut self, reader: R) -> Self {
+        self.stopped = Some(Script::read(reader));
+        self
+    }
+
+    /// create a `stopped` hook with a chunk precompiled by `LuaActorBuilder::compile`, see
+    /// `on_started_with_bytecode`
+    pub fn on_stopped_with_bytecode(mut self, chunk: &[u8]) -> Self {
+        self.stopped = Some(Script::Bytecode(chunk.to_vec()));
+        self
+    }
+
+    /// compile a lua script to bytecode for `on_handle_with_bytecode` and the other
+    /// `*_with_bytecode` setters, returning the syntax errors
+    ///
+    /// The chunk keeps its debug information, errors raised by it are reported in
+    /// `[string "compiled"]`.
+    pub fn compile(source: &str) -> Result<Vec<u8>, LuaError> {
+        Lua::new_with(StdLib::STRING).context(|ctx| {
+            let chunk = ctx.load(source).set_name("compiled")?.into_function()?;
+            let string: Table = ctx.globals().get("string")?;
+            let dump: Function = string.get("dump")?;
+            let bytecode: LuaString = dump.call(chunk)?;
+            Ok(bytecode.as_bytes().to_vec())
+        })
+    }
+
+    /// create an `internal_error` hook with given lua script file
+    ///
+    /// The hook is called with `ctx.msg` set to `{subsystem = ..., message = ..., detail = {...}}`,
+    /// plus `build` with `with_build_info`, when a non-fatal error occurs outside of the scripts,
+    /// e.g. the state can't be kept on restart (`restart`) or a message can't be forwarded
+    /// (`forward`). Errors raised by the hook are only logged, and `ctx.restart()` is ignored in
+    /// it.
+    pub fn on_internal_error(mut self, filename: &str) -> Self {
+        self.internal_error = Some(Script::File(filename.to_string()));
+        self
+    }
+
+    /// create an `internal_error` hook with given lua script, see `on_internal_error`
+    pub fn on_internal_error_with_lua(mut self, script: &str) -> Self {
+        self.internal_error = Some(Script::Lua(script.to_string()));
+        self
+    }
+
+    /// create a `migrations` hook with given lua script file
+    ///
+    /// The hook declares the version of `ctx.state` expected by the scripts with
+    /// `ctx.state_version = n`, and the migration from each previous version with
+    /// `ctx.migration(from_version, function (old_state) return new_state end)`.
+    ///
+    /// It runs before each `started`, i.e. when the actor starts with a state restored with
+    /// `LuaActor::restore_state` and when it restarts with `ctx.restart(true)`, possibly with
+    /// reloaded scripts. The migrations from the version stored in `ctx.state.__state_version`
+    /// (1 if the state has none) run in order, and the new version is stored. A new, empty
+    /// state is stamped with the declared version.
+    ///
+    /// A missing migration, a state newer than the scripts or an error of a migration stops the
+    /// actor, like an error of `started`. The other `ctx` functions are not available to the
+    /// migrations.
+    pub fn on_migrations(mut self, filename: &str) -> Self {
+        self.migrations = Some(Script::File(filename.to_string()));
+        self
+    }
+
+    /// create a `migrations` hook with given lua script, see `on_migrations`
+    pub fn on_migrations_with_lua(mut self, script: &str) -> Self {
+        self.migrations = Some(Script::Lua(script.to_string()));
+        self
+    }
+
+    /// create a `state_quota_exceeded` hook with given lua script file
+    ///
+    /// The hook is called with `ctx.msg` set to `{size = bytes, quota = bytes}` when an
+    /// invocation leaves `ctx.state` above the quota of `with_state_quota`, so the script can
+    /// prune it, e.g. drop the oldest entries of a cache. Errors raised by the hook are only
+    /// logged, and `ctx.restart()` is ignored in it.
+    pub fn on_state_quota_exceeded(mut self, filename: &str) -> Self {
+        self.state_quota_exceeded = Some(Script::File(filename.to_string()));
+        self
+    }
+
+    /// create a `state_quota_exceeded` hook with given lua script, see
+    /// `on_state_quota_exceeded`
+    pub fn on_state_quota_exceeded_with_lua(mut self, script: &str) -> Self {
+        self.state_quota_exceeded = Some(Script::Lua(script.to_string()));
+        self
+    }
+
+    /// create a `restarting` hook with given lua script file
+    ///
+    /// The hook runs when an `actix::Supervisor` restarts the actor after it stopped, before
+    /// `started` runs again in the same VM, e.g. to reset `ctx.state`. The coroutines suspended
+    /// before the stop are dropped, and so are the intervals, the watches and the pending
+    /// `ctx.send_correlated`. Errors raised by the hook are only logged, and `ctx.restart()` is
+    /// ignored in it.
+    pub fn on_restarting(mut self, filename: &str) -> Self {
+        self.restarting = Some(Script::File(filename.to_string()));
+        self
+    }
+
+    /// create a `restarting` hook with given lua script, see `on_restarting`
+    pub fn on_restarting_with_lua(mut self, script: &str) -> Self {
+        self.restarting = Some(Script::Lua(script.to_string()));
+        self
+    }
+
+    /// set the maximum length in bytes of recipient names, default to 128
+    pub fn with_max_recipient_name_len(mut self, len: usize) -> Self {
+        self.max_recipient_name_len = len;
+        self
+    }
+
+    /// set the longest duration accepted by `ctx.notify_later` and `ctx.run_interval`, default
+    /// to 30 days
+    ///
+    /// Durations are passed in seconds: zero means the next turn of the event loop, negative,
+    /// NaN and infinite values, and values above `max`, raise a Lua error.
+    pub fn with_max_duration(mut self, max: Duration) -> Self {
+        self.max_duration = max;
+        self
+    }
+
+    /// abort the invocations of the scripts running longer than `timeout`, disabled by default
+    ///
+    /// The timeout applies to each invocation of `started`, `handle`, a resumed coroutine or
+    /// another hook, except `stopped`, see `with_stopped_deadline`. The time waiting for a
+    /// `ctx.send` reply doesn't count. The clock is checked every 1000 Lua instructions, and
+    /// past the timeout the script raises an error which goes through the usual error path:
+    /// the sender gets a `LuaMessage::Error` and the actor handles the next messages.
+    pub fn with_handle_timeout(mut self, timeout: Duration) -> Self {
+        self.handle_timeout = Some(timeout);
+        self
+    }
+
+    /// raise errors on reads of undefined globals and assignments of undeclared globals
+    ///
+    /// Globals defined before the actor is built, such as the ones from a VM passed to
+    /// `build_with_vm`, are declared. Use `declare_global(name, value)` to declare new globals in scripts.
+    pub fn with_strict_globals(mut self, strict: bool) -> Self {
+        self.strict_globals = strict;
+        self
+    }
+
+    /// set the name of the actor used in diagnostics, default to `LuaActor`
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// set the build info of the host application, e.g. its version and git hash, available
+    /// to the scripts as the read-only `ctx.build`, default to an empty table
+    ///
+    /// `build` must be a table, typically of strings filled from `env!` values. The children
+    /// added with `with_child` inherit it unless they have their own. It's also passed to the
+    /// `internal_error` hook, in `GetEffectiveConfig` and to the log prefix, see
+    /// `with_log_prefix`.
+    pub fn with_build_info(mut self, build: LuaMessage) -> Self {
+        self.build_info = build;
+        self
+    }
+
+    /// set the prefix of the lines logged by the actor, default to `"LuaActor {actor}"`
+    ///
+    /// `{actor}` is replaced by the name of the actor and `{build.<field>}` by a field of the
+    /// build info of `with_build_info`, e.g. `"[{actor}@{build.version}]"`. The prefix is
+    /// expanded when the actor is built, missing fields are empty.
+    pub fn with_log_prefix(mut self, template: &str) -> Self {
+        self.log_prefix = template.to_string();
+        self
+    }
+
+    /// log a warning when a hook doesn't return within `threshold`
+    ///
+    /// A watchdog thread, shared by all actors of the process, checks running hooks periodically.
+    /// It can't interrupt a stuck hook, but makes Rust functions blocking the actor visible.
+    pub fn with_watchdog(mut self, threshold: Duration) -> Self {
+        let callback = self.watchdog.and_then(|w| w.callback);
+        self.watchdog = Some(Watchdog {
+            threshold,
+            callback,
+        });
+        self
+    }
+
+    /// call `callback` from the watchdog thread when a hook is stuck, see `with_watchdog`
+    pub fn with_watchdog_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&StuckInvocation) + Send + Sync + 'static,
+    {
+        let threshold = self
+            .watchdog
+            .map(|w| w.threshold)
+            .unwrap_or(DEFAULT_WATCHDOG_THRESHOLD);
+        self.watchdog = Some(Watchdog {
+            threshold,
+            callback: Some(Arc::new(callback)),
+        });
+        self
+    }
+
+    /// buffer `ctx.notify` calls beyond `max_per_invocation` in a single invocation
+    ///
+    /// Buffered messages are delivered in order after `flush_interval` as one message
+    /// `{__coalesced = {["1"] = msg1, ["2"] = msg2, ...}}`.
+    /// Notifications of later invocations are buffered until the batch is delivered.
+    /// Buffered messages are dropped if the actor stops before the flush.
+    pub fn with_notify_coalescing(
+        mut self,
+        max_per_invocation: usize,
+        flush_interval: Duration,
+    ) -> Self {
+        self.notify_coalescing = Some(NotifyCoalescing {
+            max_per_invocation,
+            flush_interval,
+        });
+        self
+    }
+
+    /// stop sending to a recipient after `failures` failed `ctx.send` calls within `window`
+    ///
+    /// While the breaker of a recipient is open, `ctx.send` returns `nil` and
+    /// `{kind = "circuit_open", message = ..., retry_after_ms = ...}` without sending.
+    /// Once `cooldown` elapsed, one request is let through: the breaker closes if it succeeds
+    /// and opens again otherwise, or if it didn't complete within the send timeout (`cooldown`
+    /// without `with_send_timeout`). Sends fail when the recipient is unreachable or times out,
+    /// and with `with_rich_send_results` when it fails to handle the message.
+    /// Failed sends return `nil` and the same error as without a circuit breaker, e.g.
+    /// `{kind = "closed", message = ...}`.
+    pub fn with_circuit_breaker(
+        mut self,
+        failures: usize,
+        window: Duration,
+        cooldown: Duration,
+    ) -> Self {
+        self.circuit_breaker = Some(BreakerConfig {
+            failures,
+            window,
+            cooldown,
+        });
+        self
+    }
+
+    /// handle up to `rate` messages per second, with bursts of up to `burst` messages
+    ///
+    /// The limit is a token bucket checked before the `handle` script runs, `policy` tells
+    /// what to do with the messages above it:
+    /// * `ShedPolicy::Reject` replies `{error = "rate_limited", retry_after_ms = ...}`.
+    /// * `ShedPolicy::Delay { max_queued }` queues the messages and handles them in order at
+    ///   the limited rate, the messages beyond `max_queued` are rejected.
+    /// * `ShedPolicy::DropOldest { max_queued }` queues the messages likewise but drops the
+    ///   oldest queued message to make room, for `do_send` traffic where the latest messages
+    ///   matter most. A dropped message expecting a reply gets the `rate_limited` error.
+    ///
+    /// Only the `LuaMessage`s and `TryLuaMessage`s of the mailbox are limited, a rejected
+    /// `TryLuaMessage` fails with `HandleError::Error("rate_limited")`. Each message costs one
+    /// token, see `with_inbound_cost_field`. Send `GetInboundStats` to read the available tokens and the
+    /// number of rejected, dropped and delayed messages.
+    pub fn with_inbound_rate_limit(mut self, rate: f64, burst: f64, policy: ShedPolicy) -> Self {
+        self.inbound_rate_limit = Some((rate, burst, policy));
+        self
+    }
+
+    /// take the cost of a message for `with_inbound_rate_limit` from its `field`, default to 1
+    ///
+    /// The cost is capped to the burst of the limit.
+    pub fn with_inbound_cost_field(mut self, field: &str) -> Self {
+        self.inbound_cost_field = Some(field.to_string());
+        self
+    }
+
+    /// make `ctx.send` return `{ok = true, value = ...}` or `{ok = false, kind = ..., detail = ...}`
+    ///
+    /// `kind` is `no_handler` or `error` (with the error in `detail`) for recipients added with
+    /// `LuaActor::add_lua_recipient`, `timeout`, `mailbox_closed`, or `circuit_open`.
+    /// `value` is absent if the recipient returned nil. Also defines
+    /// `ctx.send_ok(recipient, msg)`, which returns the value or raises an error.
+    pub fn with_rich_send_results(mut self, rich: bool) -> Self {
+        self.rich_send_results = rich;
+        self
+    }
+
+    /// fail `ctx.send` calls which didn't get a response within `timeout`
+    ///
+    /// A timed out send returns `nil` and `{kind = "timeout", message = ...}`, or
+    /// `{ok = false, kind = "timeout"}` with rich send results.
+    pub fn with_send_timeout(mut self, timeout: Duration) -> Self {
+        self.send_timeout = Some(timeout);
+        self
+    }
+
+    /// add a recipient to the built actor, see `LuaActor::try_add_recipients`
+    ///
+    /// Building fails if `name` is not a valid recipient name.
+    pub fn with_recipient(mut self, name: &str, rec: Recipient<LuaMessage>) -> Self {
+        self.recipients.push((name.to_string(), rec));
+        self
+    }
+
+    /// wait for the recipients `names` to be added before running the `started` hook
+    ///
+    /// The recipients are checked every 10ms. If some are still missing after `timeout`, the
+    /// actor stops like for a failed `started` hook, logging an error listing the missing names.
+    /// `LuaMessage`s received before `started` ran are handled after it.
+    /// The status of each recipient is available to the scripts in `ctx.runtime.dependencies`.
+    pub fn require_recipients(mut self, names: &[&str], timeout: Duration) -> Self {
+        self.dependencies = Some(Dependencies {
+            names: names.iter().map(|name| name.to_string()).collect(),
+            timeout,
+        });
+        self
+    }
+
+    /// limit the number of entries of the tables passed to or returned by the scripts
+    ///
+    /// Entries of nested tables are counted. Converting a bigger table fails with an error
+    /// giving the path of the table where the limit was reached. Defaults to 100 000, `None`
+    /// disables the limit. Send large data in several messages instead.
+    pub fn with_max_table_entries(mut self, max: Option<usize>) -> Self {
+        self.conversion_limits.max_table_entries = max;
+        self
+    }
+
+    /// choose which tables passed to or returned by the scripts are converted to
+    /// `LuaMessage::Array`, see `ConversionOptions`
+    ///
+    /// By default, only the tables keyed by exactly the integers `1` to `n` are. The children
+    /// added with `with_child` inherit the options unless they have their own.
+    pub fn with_conversion_options(mut self, options: ConversionOptions) -> Self {
+        self.conversion_options = Some(options);
+        self
+    }
+
+    /// fail to convert integral floats too large to be represented exactly
+    ///
+    /// Lua integer arithmetic wraps around on overflow and mixing integers with floats converts
+    /// them to floats, e.g. `math.maxinteger * 2.0`. With overflow errors, floats with integral
+    /// values beyond 2^53 passed to or returned by the scripts raise an error instead of silently
+    /// carrying a value which likely lost precision. Integers are always converted exactly.
+    /// Scripts can use `ctx.math.checked_add`, `checked_sub` and `checked_mul` to detect integer
+    /// overflows.
+    pub fn with_overflow_errors(mut self, enabled: bool) -> Self {
+        self.conversion_limits.overflow_errors = enabled;
+        self
+    }
+
+    /// emit each warning about deprecated or misused APIs at most once per `interval`
+    ///
+    /// Defaults to a minute. Warnings are logged and passed to the callback set with
+    /// `with_warning_callback`.
+    pub fn with_warning_interval(mut self, interval: Duration) -> Self {
+        self.warnings.interval = interval;
+        self
+    }
+
+    /// call `callback` with each emitted warning, see `with_warning_interval`
+    pub fn with_warning_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&Warning) + Send + Sync + 'static,
+    {
+        self.warnings.callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// count the identical errors of a hook within `window` instead of logging each of them,
+    /// default to 10 seconds
+    ///
+    /// Errors of the `handle` script returned to the callers, e.g. for `TryLuaMessage`, are
+    /// logged when they first occur. When the window ends, the number of occurrences is
+    /// logged if the error repeated, and passed to the callback of
+    /// `with_error_burst_callback`. Errors are identical if their text is, which includes the
+    /// chunk and line where they were raised.
+    pub fn with_error_coalescing(mut self, window: Duration) -> Self {
+        self.error_bursts.window = window;
+        self
+    }
+
+    /// call `callback` with each burst of identical errors when its window ends, see
+    /// `with_error_coalescing`
+    pub fn with_error_burst_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&ErrorBurst) + Send + Sync + 'static,
+    {
+        self.error_bursts.callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// check the recipients watched with `ctx.watch` every `interval`, default to a second
+    pub fn with_watch_interval(mut self, interval: Duration) -> Self {
+        self.watch_interval = interval;
+        self
+    }
+
+    /// handle messages with the same key only once within `window`
+    ///
+    /// The key is the string or number at the dotted `key_path` of table messages, e.g.
+    /// `"meta.msg_id"`. Duplicates don't run the `handle` script: the reply is the result of the
+    /// first message if `cache_results` is set (`nil` if it was sent without expecting a reply),
+    /// `{duplicate = true}` otherwise. Messages without a key are always handled. Keys are
+    /// forgotten once `window` elapsed since the first message.
+    pub fn with_idempotency(
+        mut self,
+        key_path: &str,
+        window: Duration,
+        cache_results: bool,
+    ) -> Self {
+        self.idempotency = Some(Idempotency::new(key_path, window, cache_results));
+        self
+    }
+
+    /// abort the `stopped` hook if it runs longer than `deadline`, default to 5 seconds
+    ///
+    /// The abort is reported as a `stopped_deadline` warning, see `with_warning_callback`, and
+    /// the actor stops anyway.
+    pub fn with_stopped_deadline(mut self, deadline: Duration) -> Self {
+        self.stopped_deadline = deadline;
+        self
+    }
+
+    /// keep up to `size` coroutines which finished their script to run the next invocations,
+    /// default to 16
+    ///
+    /// Each invocation of a hook runs in a coroutine, `0` creates a new one every time.
+    pub fn with_coroutine_pool_size(mut self, size: usize) -> Self {
+        self.coroutine_pool_size = size;
+        self
+    }
+
+    /// allow up to `max` pending `ctx.send_correlated`, default to 1024
+    ///
+    /// `ctx.send_correlated` raises an error once `max` requests wait for their reply.
+    pub fn with_max_correlated(mut self, max: usize) -> Self {
+        self.max_correlated = max;
+        self
+    }
+
+    /// limit the blobs of `ctx.export_state` and `ctx.import_state` to `bytes`, default to 1 MiB
+    ///
+    /// `ctx.export_state` raises an error with the size of the blob above the limit. The value
+    /// exported is also subject to the conversion limits, e.g. `with_max_table_entries`.
+    pub fn with_max_export_size(mut self, bytes: usize) -> Self {
+        self.max_export_size = bytes;
+        self
+    }
+
+    /// log the changes made to `ctx.state` by each message, default to `false`
+    ///
+    /// `ctx.state` is converted before and after the `handle` script runs, and after it resumes
+    /// from `ctx.send`, within the conversion limits of the actor. The added, removed and
+    /// changed paths, e.g. `count` or `__ns.name.key` for namespaces, are logged with the
+    /// `actix_lua::state_diff` target, and returned by `ctx.last_diff()` as
+    /// `{{op = "added" | "removed" | "changed", path = ..., old = ..., new = ...}, ...}`.
+    /// At most 32 changes are kept, the others are counted in `omitted`.
+    ///
+    /// Paths marked with `ctx.no_diff(path)`, and everything under them, are skipped.
+    pub fn with_state_diffing(mut self, enabled: bool) -> Self {
+        self.state_diffing = enabled;
+        self
+    }
+
+    /// freeze `ctx.state` once the `started` hook ran, default to `false`
+    ///
+    /// `started` builds the state, afterwards assigning a field of `ctx.state` or of a table in
+    /// it, including with `rawset` and `table.insert`, raises `state is read-only in this
+    /// actor`. Reads, `pairs`, `ipairs` and `#` work as usual. `ctx.notify`, `ctx.send` and
+    /// intervals are not affected, and the state is frozen again after a restart.
+    ///
+    /// Namespaces used after `started` must be created in `started`, since `ctx.ns` adds them
+    /// to `ctx.state`.
+    pub fn with_read_only_state(mut self, enabled: bool) -> Self {
+        self.read_only_state = enabled;
+        self
+    }
+
+    /// make `ctx.msg` read-only, default to `false`
+    ///
+    /// `ctx.msg` is converted from the `LuaMessage` for each invocation: by default it's a
+    /// copy the scripts may modify, e.g. to annotate it before forwarding it with
+    /// `ctx.do_send`, without affecting the sender's message. With frozen messages, assigning
+    /// a field of `ctx.msg` or of a nested table raises `ctx.msg is read-only in this actor`,
+    /// to catch accidental changes. Frozen messages can still be read, iterated with `pairs`
+    /// and sent.
+    pub fn with_frozen_msg(mut self, frozen: bool) -> Self {
+        self.frozen_msg = frozen;
+        self
+    }
+
+    /// convert the tables of `ctx.msg` to Lua on access, default to `false`
+    ///
+    /// By default the message is converted to Lua tables before each invocation of `handle`,
+    /// which is wasted work for a handler reading a few fields of a large message. With lazy
+    /// tables, `ctx.msg` and its nested tables are converted when they're first read: reading
+    /// `ctx.msg.header.id` converts `header` and `id` only. Iterating a table with `pairs` or
+    /// `next`, taking its length, assigning one of its fields or changing its metatable
+    /// converts its entries at that point.
+    ///
+    /// The scripts can't tell lazy tables from converted ones, the conversion limits are
+    /// checked before `handle` runs and fail like for converted tables. Messages returned or
+    /// sent unmodified are copied without converting them back. Only `ctx.msg` is lazy, the
+    /// replies resuming `ctx.send` and the instances of the classes registered with
+    /// `ctx.register_class` are converted.
+    pub fn with_lazy_tables(mut self, lazy: bool) -> Self {
+        self.lazy_tables = lazy;
+        self
+    }
+
+    /// define the Lua 5.1 functions removed since, for scripts written for Lua 5.1 and LuaJIT,
+    /// default to `false`
+    ///
+    /// The shims are `unpack`, `loadstring`, `table.getn`, `table.maxn`, `math.pow`,
+    /// `math.mod`, `math.log10` and `string.gfind`. `setfenv`, `getfenv` and `module` have no
+    /// equivalent and are not defined. Without the shims, calling one of these functions
+    /// raises an error with a hint to enable them.
+    ///
+    /// The integral floats passed by the scripts are also converted like integers, see
+    /// `ConversionLimits::integral_floats`, so `{1.0, 2.0}` is the `Array` `[1, 2]`, like on
+    /// Lua builds where every number is a float.
+    pub fn with_compat_shims(mut self, enabled: bool) -> Self {
+        self.compat_shims = enabled;
+        self.conversion_limits.integral_floats = enabled;
+        self
+    }
+
+    /// generate the ids of the actor from `seed`, so they are the same in every run, e.g. to
+    /// replay a scenario while debugging
+    ///
+    /// The ids are the uuids of `ctx.uuid()` and the correlation ids of `ctx.send_correlated`
+    /// and `ctx.wait_for`. Without a seed, the uuids are random. The children of `with_child`
+    /// without a seed of their own get one from the actor's ids, so a whole tree is
+    /// reproducible. The receipt ids of `ctx.do_send_confirmed` are counters, the same in
+    /// every run anyway.
+    pub fn with_id_seed(mut self, seed: u64) -> Self {
+        self.id_seed = Some(seed);
+        self
+    }
+
+    /// also call the global function `health()` of the scripts when answering a `Ping`,
+    /// disabled by default
+    ///
+    /// Its result is added to the reply as `health`. It runs with a deadline of 100ms, past
+    /// which it's aborted and the reply has `health_error` instead, so a hung `health()`
+    /// doesn't block the probe.
+    pub fn with_deep_ping(mut self, deep: bool) -> Self {
+        self.deep_ping = deep;
+        self
+    }
+
+    /// start the actor built by `builder` with this one, as its recipient `name`
+    ///
+    /// The child is built with the actor and started before its `started` hook.
+    /// `ctx.replace_actor(name, script_path[, {drain_timeout = 5, max_queued = 1024}])` starts
+    /// a replacement built by `builder` with the `handle` script `script_path` and holds the
+    /// messages for `name` until the child replied to the pending `ctx.send` of the actor, or
+    /// `drain_timeout` seconds. Then `name` refers to the replacement, which receives the held
+    /// messages in order, and the old child is stopped unless the drain timed out.
+    pub fn with_child(mut self, name: &str, builder: LuaActorBuilder) -> Self {
+        self.children.push((name.to_string(), builder));
+        self
+    }
+
+    /// share the read-only table `dataset` with the scripts as `ctx.data.<name>`
+    ///
+    /// The entries stay in Rust, shared by all the actors built with the same dataset:
+    /// `ctx.data.<name>.get(key)` converts the value of `key`, and
+    /// `for key, value in ctx.data.<name>.iter_prefix(prefix) do` the entries whose key starts
+    /// with `prefix`, in key order. Send `SetDataset` to replace the dataset of a running
+    /// actor.
+    pub fn with_dataset(mut self, name: &str, dataset: SharedDataset) -> Self {
+        self.datasets.push((name.to_string(), dataset));
+        self
+    }
+
+    /// limit the memory used by the Lua VM to `bytes`, unlimited by default
+    ///
+    /// Allocations above the limit fail with a memory error, failing the invocation which
+    /// made them. The limit applies to the whole VM for the lifetime of the actor, send
+    /// `GetLuaMemory` to read the memory used.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// collect all the garbage of the VM when it uses more than `fraction` of the memory
+    /// limit, disabled by default
+    ///
+    /// The memory used is checked after each invocation. Above the watermark, a full
+    /// collection runs on the next turn of the event loop, once the invocation replied.
+    /// `ctx.runtime.gc` is then set to `{collections = n, reclaimed = bytes}`, the totals
+    /// since the actor was built. After 3 consecutive collections leaving the memory above
+    /// the watermark, the scripts are likely keeping everything they allocate: the error
+    /// is logged and passed to the `internal_error` hook with the `gc` subsystem and
+    /// `{used = bytes, limit = bytes, attempts = 3}` as detail.
+    ///
+    /// Requires `with_memory_limit`, `fraction` must be within `(0, 1]`.
+    pub fn with_gc_watermark(mut self, fraction: f64) -> Self {
+        self.gc_watermark = Some(fraction);
+        self
+    }
+
+    /// limit the estimated size of `ctx.state` to `bytes`, disabled by default
+    ///
+    /// The size is estimated after each invocation of `started`, `handle` and the resumed
+    /// coroutines, walking the whole state: the check costs time proportional to the size of
+    /// the state. Above the quota, the `state_quota_exceeded` hook runs if there is one, then
+    /// `policy` applies if the state is still above:
+    ///
+    /// * `StateQuotaPolicy::Warn` logs a warning, see `with_warning_callback`.
+    /// * `StateQuotaPolicy::RejectWrites` also makes the writes of new keys to `ctx.state` and
+    ///   its tables raise an error until the state is back under the quota. Updates and
+    ///   deletes of the existing keys are allowed, so the scripts can shrink it.
+    /// * `StateQuotaPolicy::Stop` logs an error and stops the actor.
+    ///
+    /// The estimate is approximate but stable, it only depends on the content of the state:
+    ///
+    /// * a string counts its length in bytes,
+    /// * a number and any value other than a string, a boolean or a table count 8 bytes,
+    /// * a boolean counts 1 byte,
+    /// * a table counts 40 bytes, plus 16 bytes per entry and the size of its keys and values.
+    ///   A table referenced several times is counted once.
+    ///
+    /// The scripts read the estimate with `ctx.state_size()`, and Rust code with
+    /// `GetStateSize`.
+    pub fn with_state_quota(mut self, bytes: usize, policy: StateQuotaPolicy) -> Self {
+        self.state_quota = Some((bytes, policy));
+        self
+    }
+
+    /// set how many messages are buffered while the actor is paused with `PauseProcessing`,
+    /// default to 1024 with `PauseOverflow::DropNewest`
+    ///
+    /// Beyond `max_buffered`, `overflow` chooses the message to drop, which is passed to the
+    /// unprocessed sink of `with_unprocessed_sink` if there is one. The replies to `ctx.send`
+    /// held with `drain_current: false` don't count and are never dropped.
+    pub fn with_pause_buffer(mut self, max_buffered: usize, overflow: PauseOverflow) -> Self {
+        self.pause_buffer = (max_buffered, overflow);
+        self
+    }
+
+    /// set how many receipts of `ctx.do_send_confirmed` can wait for `on_delivery_receipt`,
+    /// default to 1024
+    ///
+    /// Beyond `max_outstanding`, the message is still sent but its receipt
+    /// `{receipt = id, status = status}` is passed to the unprocessed sink of
+    /// `with_unprocessed_sink`, if there is one, instead of `on_delivery_receipt`.
+    pub fn with_max_receipts(mut self, max_outstanding: usize) -> Self {
+        self.max_receipts = max_outstanding;
+        self
+    }
+
+    /// load each hook in its own environment, default to `false`
+    ///
+    /// By default the hooks share the globals, e.g. a function defined at the top of `started`
+    /// can be called from `handle`. With isolated hooks, the globals assigned by a hook are
+    /// only visible to it, and reads of the other globals fall back to the shared ones.
+    /// Values meant to be shared go in `ctx.shared`, or in globals declared with
+    /// `declare_global`, which are assigned in the shared globals.
+    pub fn with_isolated_hooks(mut self, isolated: bool) -> Self {
+        self.isolated_hooks = isolated;
+        self
+    }
+
+    /// run the scripts in a VM without access to the system, default to `false`, e.g. for
+    /// scripts submitted by users
+    ///
+    /// The VM only has the `base`, `coroutine`, `table`, `string`, `utf8`, `math` and
+    /// `package` libraries: `io`, `os` and `debug` are absent. `dofile`, `loadfile` and
+    /// `package.loadlib` are removed, `load` only accepts source code, and `require` only
+    /// finds the modules of `package.preload` and of `with_script_source`. The VM rebuilt by
+    /// `ctx.restart` is sandboxed too.
+    ///
+    /// Can't be used with `build_with_vm`, which takes the VM as it is.
+    pub fn with_sandbox(mut self, sandbox: bool) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// set `package.path`, where `require` looks for Lua modules, instead of `LUA_PATH`
+    ///
+    /// Set when the VM is created, before the scripts load, and for the VM rebuilt by
+    /// `ctx.restart`. Unlike `LUA_PATH` it only applies to this actor, e.g.
+    /// `"./scripts/?.lua;./scripts/?/init.lua"`.
+    ///
+    /// Can't be used with `with_sandbox`, which doesn't look for modules in files.
+    pub fn with_package_path(mut self, path: &str) -> Self {
+        self.package_path = Some(path.to_string());
+        self
+    }
+
+    /// set `package.cpath`, where `require` looks for C modules, instead of `LUA_CPATH`
+    ///
+    /// Like `with_package_path`, and can't be used with `with_sandbox` either.
+    pub fn with_package_cpath(mut self, cpath: &str) -> Self {
+        self.package_cpath = Some(cpath.to_string());
+        self
+    }
+
+    /// make `require(name)` load the Lua module `source`, e.g. embedded with `include_str!`
+    ///
+    /// The module is set in `package.preload`, so it doesn't need a file, and runs on the first
+    /// `require`. It's compiled by `build`, which fails on syntax errors naming the module.
+    /// Works with `with_sandbox`. Preloading `name` again replaces its source.
+    pub fn preload_module(mut self, name: &str, source: &str) -> Self {
+        self.preloaded.retain(|(preloaded, _)| preloaded != name);
+        self.preloaded.push((name.to_string(), source.to_string()));
+        self
+    }
+
+    /// set the global variable `name` to `value`, e.g. configuration for the scripts
+    ///
+    /// Globals are set before the prelude and the scripts load, so `started` can read them, and
+    /// again when the actor restarts. Tables are converted like messages. Setting `name` again
+    /// replaces its value, and the globals of the prelude, e.g. `ctx`, can't be replaced.
+    pub fn with_global(mut self, name: &str, value: LuaMessage) -> Self {
+        self.globals.retain(|(global, _)| global != name);
+        self.globals.push((name.to_string(), value));
+        self
+    }
+
+    /// set the global function `name` to the Rust function `f`, without `build_with_vm`
+    ///
+    /// The arguments of a call are converted to `LuaMessage`s like messages, and the result is
+    /// converted back to Lua. An `Err` raises a Lua error with its message. Functions are set
+    /// with the globals, before the scripts load and again when the actor restarts, so they
+    /// must be `Send + Sync`. Registering `name` again replaces its function.
+    pub fn with_function<F, E>(mut self, name: &str, f: F) -> Self
+    where
+        F: Fn(Vec<LuaMessage>) -> Result<LuaMessage, E> + Send + Sync + 'static,
+        E: fmt::Display,
+    {
+        self.functions.retain(|(function, _)| function != name);
+        self.functions.push((
+            name.to_string(),
+            Arc::new(move |args| f(args).map_err(|e| e.to_string())),
+        ));
+        self
+    }
+
+    // The runtime of the actor in `vm`, before the scripts load.
+    fn runtime(&self, vm: Lua) -> Result<LuaRuntime, LuaError> {
+        if self.sandbox && (self.package_path.is_some() || self.package_cpath.is_some()) {
+            return Err(LuaError::RuntimeError(
+                "with_package_path and with_package_cpath can't be used with with_sandbox"
+                    .to_string(),
+            ));
+        }
+        let mut runtime = LuaRuntime::new(vm);
+        runtime.sandboxed = self.sandbox;
+        runtime.package_path = self.package_path.clone();
+        runtime.package_cpath = self.package_cpath.clone();
+        runtime.preloaded = self.preloaded.clone();
+        runtime.globals = self.globals.clone();
+        runtime.functions = self.functions.clone();
+        runtime.compat_shims = self.compat_shims;
+        Ok(runtime)
+    }
+
+    // Configuration of the `ctx` API of the actors built with `build`.
+    pub(crate) fn api_config(&self) -> ApiConfig {
+        ApiConfig {
+            restartable: true,
+            circuit_breaker: self.circuit_breaker.is_some(),
+            rich_send_results: self.rich_send_results,
+        }
+    }
+
+    /// load the scripts passed to `on_started`, `on_handle` and `on_stopped`, and the modules
+    /// passed to `require`, from `source` instead of files
+    ///
+    /// `require` only searches `package.preload` and `source`, with the module name as the
+    /// script name. When the actor restarts, the hooks whose version changed in `source` are
+    /// loaded again, see `ScriptSource::version`.
+    pub fn with_script_source(mut self, source: Arc<dyn ScriptSource>) -> Self {
+        self.script_source = Some(source);
+        self
+    }
+
+    // Source of the `started`, `handle` and `stopped` scripts, `None` for the ones which
+    // couldn't be read, with their errors.
+    fn load_scripts(&self) -> ([Option<Vec<u8>>; 3], Vec<ScriptError>) {
+        let source = self.source();
+        let mut errors = Vec::new();
+        let mut load = |hook: &'static str, script: &Option<Script>| {
+            let script = script.as_ref()?;
+            match script.load(source) {
+                Ok(script) => Some(script),
+                Err(error) => {
+                    errors.push(ScriptError {
+                        hook,
+                        chunk: hook.to_string(),
+                        error,
+                    });
+                    None
+                }
+            }
+        };
+        let started = load("started", &self.started);
+        let handle = match (&self.missing_handler, &self.handle) {
+            (MissingHandler::ReturnNil, None) => Some(b"return".to_vec()),
+            (_, handle) => load("handle", handle),
+        };
+        let stopped = load("stopped", &self.stopped);
+        ([started, handle, stopped], errors)
+    }
+
+    fn source(&self) -> &dyn ScriptSource {
+        match self.script_source {
+            Some(ref source) => &**source,
+            None => &FileSource,
+        }
+    }
+
+    /// what to do with the messages if there is no `handle` script, default to
+    /// `MissingHandler::ReturnNil`
+    ///
+    /// Unless it's `ReturnNil`, an actor built without `on_handle` or `on_handle_with_lua` has
+    /// no `handle` script. With `MissingHandler::Error`, a warning is logged when the actor
+    /// starts.
+    pub fn with_missing_handler(mut self, policy: MissingHandler) -> Self {
+        self.missing_handler = policy;
+        self
+    }
+
+    /// the reply to the messages still delivered after `ctx.terminate()` or `ctx.stop()`,
+    /// default to `Nil`, e.g. `lua_msg!({error: "stopping"})`
+    ///
+    /// These messages are not handled: the reply is sent without entering the VM. The
+    /// `stopped` hook runs as usual.
+    pub fn with_terminal_reply(mut self, reply: LuaMessage) -> Self {
+        self.terminal_reply = reply;
+        self
+    }
+
+    /// the recipient of the messages not handled because the actor is stopping, see
+    /// `with_terminal_reply`
+    ///
+    /// The name is resolved when a message arrives, like the recipients of `ctx.send`.
+    pub fn with_unprocessed_sink(mut self, name: &str) -> Self {
+        self.unprocessed_sink = Some(name.to_string());
         self
     }
 
     /// build the actor with a preconfigured lua VM
     ///
     /// It's important to use the `rlua` interface exported by `actix-lua` with `use actix_lua::dev::rlua::*`
-    pub fn build_with_vm(self, vm: Lua) -> Result<LuaActor, LuaError> {
-        LuaActor::new_with_vm(
-            vm,
-            self.started.clone(),
-            self.handle.clone(),
-            self.stopped.clone(),
-        )
+    pub fn build_with_vm(self, vm: Lua) -> Result<LuaActor, LuaActorBuildError> {
+        if self.sandbox {
+            return Err(LuaError::RuntimeError(
+                "with_sandbox can't be used with build_with_vm".to_string(),
+            )
+            .into());
+        }
+        let runtime = self.runtime(vm)?;
+        let (hooks, errors) = self.load_scripts();
+        let [started, handle, stopped] = hooks.clone();
+        let actor = LuaActor::new_with_runtime(runtime, started, handle, stopped);
+        let actor = merge_script_errors(errors, actor)?;
+        Ok(self.configure(actor, hooks)?)
     }
 
     /// build the actor
-    pub fn build(self) -> Result<LuaActor, LuaError> {
-        LuaActor::new(
-            self.started.clone(),
-            self.handle.clone(),
-            self.stopped.clone(),
-        )
+    ///
+    /// The `started`, `handle` and `stopped` scripts are all loaded before failing, so the
+    /// error lists each one which couldn't be read or compiled.
+    pub fn build(self) -> Result<LuaActor, LuaActorBuildError> {
+        let runtime = self.runtime(new_vm(self.sandbox))?;
+        let (hooks, errors) = self.load_scripts();
+        let [started, handle, stopped] = hooks.clone();
+        let actor = LuaActor::new_restartable(runtime, started, handle, stopped);
+        let actor = merge_script_errors(errors, actor)?;
+        Ok(self.configure(actor, hooks)?)
+    }
+
+    /// build `size` actors and start them in the current arbiter, behind a `LuaActorPool`
+    /// routing the messages to them in turn
+    ///
+    /// All the actors are built before starting any, so the error is the one of the first
+    /// failed build.
+    pub fn build_pool(self, size: usize) -> Result<LuaActorPool, LuaActorBuildError> {
+        LuaActorPool::new(&self, size, false)
+    }
+
+    /// `build_pool`, starting each actor on an arbiter of its own, so that the scripts run in
+    /// parallel
+    pub fn build_pool_on_arbiters(self, size: usize) -> Result<LuaActorPool, LuaActorBuildError> {
+        LuaActorPool::new(&self, size, true)
+    }
+
+    /// build a `SyncLuaActor`, to start in a `SyncArbiter`
+    ///
+    /// `with_child` and `require_recipients` can't be used, the actor has no arbiter to start
+    /// the children or to wait for the recipients on.
+    pub fn build_sync(self) -> Result<SyncLuaActor, LuaActorBuildError> {
+        if !self.children.is_empty() || self.dependencies.is_some() {
+            return Err(LuaError::RuntimeError(
+                "with_child and require_recipients can't be used with build_sync".to_string(),
+            )
+            .into());
+        }
+        let actor = self.build()?;
+        Ok(SyncLuaActor::new(actor)?)
+    }
+
+    fn configure(
+        self,
+        mut actor: LuaActor,
+        hooks: [Option<Vec<u8>>; 3],
+    ) -> Result<LuaActor, LuaError> {
+        if self.isolated_hooks {
+            // load the hooks again, each in its own environment
+            actor.runtime.set_isolated_hooks(true)?;
+            for (name, script) in ["started", "handle", "stopped"].iter().zip(hooks) {
+                if let Some(script) = script {
+                    actor.runtime.load_script(name, script)?;
+                }
+            }
+        }
+        if let Some(ref script) = self.internal_error {
+            actor.set_internal_error_hook(script.load(self.source())?)?;
+        }
+        if let Some(ref script) = self.migrations {
+            actor.set_migrations_hook(script.load(self.source())?)?;
+        }
+        if let Some(ref script) = self.state_quota_exceeded {
+            actor.set_state_quota_exceeded_hook(script.load(self.source())?)?;
+        }
+        if let Some(ref script) = self.restarting {
+            actor.set_restarting_hook(script.load(self.source())?)?;
+        }
+        if let Some((bytes, policy)) = self.state_quota {
+            actor.state_quota = Some(StateQuota::new(bytes, policy));
+        }
+        actor.pause = Pause::new(self.pause_buffer.0, self.pause_buffer.1);
+        actor.receipts = Receipts::new(self.max_receipts);
+        actor.ids = IdSource::new(self.id_seed);
+        actor.deep_ping = self.deep_ping;
+        actor.runtime.max_recipient_name_len = self.max_recipient_name_len;
+        actor
+            .recipients
+            .set_max_name_len(self.max_recipient_name_len);
+        actor.runtime.max_duration = self.max_duration;
+        actor.runtime.set_handle_timeout(self.handle_timeout);
+        if self.memory_limit.is_some() {
+            actor.runtime.set_memory_limit(self.memory_limit);
+        }
+        if let Some(fraction) = self.gc_watermark {
+            if self.memory_limit.is_none() {
+                return Err(LuaError::RuntimeError(
+                    "with_gc_watermark requires with_memory_limit".to_string(),
+                ));
+            }
+            if !(fraction > 0.0 && fraction <= 1.0) {
+                return Err(LuaError::RuntimeError(format!(
+                    "invalid GC watermark {}, expected a fraction within (0, 1]",
+                    fraction
+                )));
+            }
+            actor.gc_watermark = Some(GcWatermark::new(fraction));
+        }
+        match self.build_info {
+            LuaMessage::Nil | LuaMessage::Table(_) | LuaMessage::Array(_) => {}
+            ref build => {
+                return Err(LuaError::RuntimeError(format!(
+                    "invalid build info {}, expected a table",
+                    build
+                )))
+            }
+        }
+        actor.runtime.set_build_info(&self.build_info)?;
+        actor.log_prefix = format_log_prefix(&self.log_prefix, &self.name, &self.build_info);
+        actor.build_info = self.build_info.clone();
+        actor.name = self.name;
+        actor.watchdog = self.watchdog;
+        actor.notify_coalescing = self.notify_coalescing;
+        actor.circuit_breaker = self.circuit_breaker;
+        if let Some((rate, burst, policy)) = self.inbound_rate_limit {
+            if !(rate > 0.0 && burst >= 1.0) {
+                return Err(LuaError::RuntimeError(format!(
+                    "invalid inbound rate limit {}/s with a burst of {}, expected a positive rate and a burst of at least 1",
+                    rate, burst
+                )));
+            }
+            let config = InboundConfig {
+                rate,
+                burst,
+                policy,
+                cost_field: self.inbound_cost_field,
+            };
+            actor.inbound = Some(InboundLimit::new(config, Instant::now()));
+        }
+        actor.rich_send_results = self.rich_send_results;
+        actor.send_timeout = self.send_timeout;
+        actor
+            .runtime
+            .set_rich_send_results(self.rich_send_results)?;
+        actor.runtime.install_help(actor.api_manifest())?;
+        if self.strict_globals {
+            actor.strict_globals = true;
+            actor.runtime.install_strict_globals()?;
+        }
+        for (name, rec) in self.recipients {
+            actor.try_add_recipients(&name, rec)?;
+        }
+        if let Some(ref deps) = self.dependencies {
+            for name in &deps.names {
+                validate_recipient_name(name, self.max_recipient_name_len)?;
+            }
+        }
+        actor.dependencies = self.dependencies;
+        let mut conversion_limits = self.conversion_limits;
+        if let Some(ref options) = self.conversion_options {
+            conversion_limits.options = options.clone();
+        }
+        actor.runtime.set_conversion_limits(&conversion_limits)?;
+        actor.conversion_limits = conversion_limits;
+        actor.warnings = self.warnings;
+        actor.warnings.log_prefix = actor.log_prefix.clone();
+        actor.error_bursts = self.error_bursts;
+        actor.error_bursts.log_prefix = actor.log_prefix.clone();
+        actor.watches.interval = self.watch_interval;
+        actor.idempotency = self.idempotency;
+        actor.stopped_deadline = self.stopped_deadline;
+        actor.coroutine_pool_size = self.coroutine_pool_size;
+        actor.max_correlated = self.max_correlated;
+        actor.max_export_size = self.max_export_size;
+        actor.state_diffing = self.state_diffing;
+        actor.read_only_state = self.read_only_state;
+        actor.frozen_msg = self.frozen_msg;
+        for (name, dataset) in self.datasets {
+            actor.set_dataset(&name, dataset)?;
+        }
+        for (name, mut builder) in self.children {
+            validate_recipient_name(&name, self.max_recipient_name_len)?;
+            if builder.build_info == LuaMessage::Nil {
+                builder.build_info = self.build_info.clone();
+            }
+            if builder.id_seed.is_none() {
+                builder.id_seed = actor.ids.child_seed();
+            }
+            if builder.conversion_options.is_none() {
+                builder.conversion_options = self.conversion_options.clone();
+            }
+            actor.children.add(name, builder)?;
+        }
+        actor.runtime.set_frozen_msg(self.frozen_msg)?;
+        actor.runtime.set_lazy_tables(self.lazy_tables)?;
+        actor
+            .runtime
+            .set_coroutine_pool_size(self.coroutine_pool_size)?;
+        actor.runtime.set_max_correlated(self.max_correlated)?;
+        actor.runtime.set_max_export_size(self.max_export_size)?;
+        actor.missing_handler = self.missing_handler;
+        if let Some(ref name) = self.unprocessed_sink {
+            validate_recipient_name(name, self.max_recipient_name_len)?;
+        }
+        actor.terminal_reply = self.terminal_reply;
+        actor.unprocessed_sink = self.unprocessed_sink;
+        if let Some(source) = self.script_source {
+            let hooks = vec![
+                ("started", self.started),
+                ("handle", self.handle),
+                ("stopped", self.stopped),
+                ("internal_error", self.internal_error),
+                ("migrations", self.migrations),
+                ("state_quota_exceeded", self.state_quota_exceeded),
+                ("restarting", self.restarting),
+            ]
+            .into_iter()
+            .filter_map(|(hook, script)| match script {
+                Some(Script::File(name)) => Some((hook, name)),
+                _ => None,
+            })
+            .collect();
+            actor.set_script_source(source, hooks)?;
+        }
+        Ok(actor)
+    }
+
+    /// build the actor and start it on the current arbiter
+    ///
+    /// Recipients declared with `with_recipient` are added before the `started` hook runs.
+    pub fn spawn(self) -> Result<Addr<LuaActor>, LuaActorBuildError> {
+        Ok(self.build()?.start())
+    }
+
+    /// build the actor on the current thread and start it on `arbiter`, see `spawn`
+    pub fn spawn_on(self, arbiter: &Addr<Arbiter>) -> Result<Addr<LuaActor>, LuaActorBuildError> {
+        let actor = self.build()?;
+        let (tx, rx) = channel::channel(DEFAULT_MAILBOX_CAPACITY);
+        arbiter.do_send(Execute::new(move || -> Result<(), ()> {
+            let ctx = Context::with_receiver(rx);
+            Arbiter::spawn(ctx.into_future(actor));
+            Ok(())
+        }));
+        Ok(Addr::new(tx))
+    }
+
+    /// build the actor on a separate thread
+    ///
+    /// Script files are read and compiled without blocking the current thread.
+    /// The resolved actor is the same as the one returned by `build`.
+    ///
+    /// Builds run one at a time on a thread shared by the process, so spawning many actors
+    /// doesn't start as many threads.
+    pub fn build_async(self) -> impl Future<Item = LuaActor, Error = LuaActorBuildError> {
+        let (tx, rx) = oneshot::channel();
+        let job: BuildJob = Box::new(move || {
+            let _ = tx.send(self.build());
+        });
+        // the job is dropped with its reply channel if the thread is gone
+        let _ = build_thread().send(job);
+        rx.then(|res| match res {
+            Ok(res) => res,
+            Err(_) => Err(LuaActorBuildError::Other(LuaError::RuntimeError(
+                "LuaActor build panicked".to_string(),
+            ))),
+        })
     }
 }
 
-fn read_to_string(filename: &str) -> String {
-    let mut f = File::open(filename).expect("File not found");
-    let mut body = String::new();
-    f.read_to_string(&mut body).expect("Failed to read file");
+type BuildJob = Box<dyn FnOnce() + Send>;
 
-    body
+fn build_thread() -> &'static mpsc::Sender<BuildJob> {
+    static BUILDS: OnceLock<mpsc::Sender<BuildJob>> = OnceLock::new();
+
+    BUILDS.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<BuildJob>();
+        thread::Builder::new()
+            .name("actix-lua-build".to_string())
+            .spawn(move || {
+                for job in rx {
+                    // a panicking build fails its own future, not the next builds
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                }
+            })
+            .expect("failed to start the build thread");
+        tx
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures_timer::Delay;
     use std::mem::discriminant;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn build_async_script_error() {
+        let res = LuaActorBuilder::new()
+            .on_handle_with_lua(r"return 1 +")
+            .build_async()
+            .wait();
+
+        assert!(matches!(res, Err(LuaActorBuildError::Scripts(_))));
+    }
+
+    #[test]
+    fn build_async_concurrent() {
+        let builds: Vec<_> = (0..16)
+            .map(|i| {
+                LuaActorBuilder::new()
+                    .on_handle_with_lua(&format!("return {}", i))
+                    .build_async()
+            })
+            .collect();
+
+        // queued on the shared build thread, each resolves with its own actor
+        let actors = futures::future::join_all(builds).wait().unwrap();
+        assert_eq!(actors.len(), 16);
+    }
 
     #[test]
     fn build_script_error() {
@@ -108,7 +1464,7 @@ mod tests {
         if let Err(e) = res {
             assert_eq!(
                 discriminant(&LuaError::RuntimeError("unexpected symbol".to_string())),
-                discriminant(&e)
+                discriminant(&LuaError::from(e))
             );
         // ok
         } else {
@@ -116,4 +1472,232 @@ mod tests {
         }
     }
 
+    #[test]
+    fn build_script_errors() {
+        let errors = match LuaActorBuilder::new()
+            .on_started_with_lua(r"local x = ")
+            .on_handle_with_lua(r"return ctx.msg")
+            .on_stopped("src/missing.lua")
+            .build()
+        {
+            Err(LuaActorBuildError::Scripts(errors)) => errors,
+            Err(e) => panic!("expected script errors, got {}", e),
+            Ok(_) => panic!("should return error"),
+        };
+        let hooks: Vec<_> = errors.iter().map(|e| (e.hook, e.chunk.as_str())).collect();
+        assert_eq!(hooks, vec![("started", "started"), ("stopped", "stopped")]);
+        assert!(errors[0]
+            .error
+            .to_string()
+            .contains(r#"[string "started"]:1:"#));
+
+        let res = LuaActorBuilder::new()
+            .on_started_with_lua(r"local x = ")
+            .on_handle_with_lua(r"return 1 +")
+            .build();
+        let e = match res {
+            Err(e) => e,
+            Ok(_) => panic!("should return error"),
+        };
+        let msg = e.to_string();
+        assert!(msg.starts_with("failed to load the scripts:"), "{}", msg);
+        assert!(msg.contains("\nstarted (started): "), "{}", msg);
+        assert!(msg.contains("\nhandle (handle): "), "{}", msg);
+        // both are kept in the `LuaError`
+        assert_eq!(
+            LuaError::from(e).to_string(),
+            format!("runtime error: {}", msg)
+        );
+
+        // other errors aren't about the scripts
+        let res = LuaActorBuilder::new()
+            .with_gc_watermark(0.5)
+            .on_handle_with_lua(r"return 1 +")
+            .build();
+        assert!(matches!(res, Err(LuaActorBuildError::Scripts(_))));
+        let res = LuaActorBuilder::new().with_gc_watermark(0.5).build();
+        assert!(matches!(res, Err(LuaActorBuildError::Other(_))));
+    }
+
+    struct Counter(Arc<AtomicUsize>);
+
+    impl Actor for Counter {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<LuaMessage> for Counter {
+        type Result = LuaMessage;
+
+        fn handle(&mut self, _: LuaMessage, _: &mut Context<Self>) -> Self::Result {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            LuaMessage::Nil
+        }
+    }
+
+    #[test]
+    fn spawn_with_recipients() {
+        let system = System::new("test");
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = Counter(count.clone()).start();
+
+        for _ in 0..100 {
+            LuaActorBuilder::new()
+                .on_started_with_lua(r#"ctx.do_send("counter", 1)"#)
+                .with_recipient("counter", counter.clone().recipient())
+                .spawn()
+                .unwrap();
+        }
+
+        let delay = Delay::new(Duration::from_millis(200)).map(move |()| {
+            assert_eq!(count.load(Ordering::SeqCst), 100);
+            System::current().stop();
+        });
+        Arbiter::spawn(delay.map_err(|e| println!("timer failed {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn build_with_reader() {
+        use std::io::{self, Cursor};
+
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("bundle corrupted"))
+            }
+        }
+
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_reader(Cursor::new("ctx.state.n = 1"))
+            .on_handle_with_reader("return ctx.msg + ctx.state.n".as_bytes())
+            .on_stopped_with_reader(Cursor::new(b"return".to_vec()))
+            .build()
+            .unwrap()
+            .start();
+        for builder in [
+            LuaActorBuilder::new().on_handle_with_reader(Broken),
+            LuaActorBuilder::new().on_started_with_reader(Broken),
+            LuaActorBuilder::new().on_handle("src/lua/test/missing.lua"),
+        ] {
+            match builder.build() {
+                Err(e) => assert!(e.to_string().contains("failed to read script"), "{}", e),
+                Ok(_) => panic!("built with an unreadable script"),
+            }
+        }
+
+        let l = addr.send(LuaMessage::from(1)).map(|res| {
+            assert_eq!(res, LuaMessage::from(2));
+            System::current().stop();
+        });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn build_with_bytecode() {
+        let system = System::new("test");
+
+        let started = LuaActorBuilder::compile("ctx.state.n = 1").unwrap();
+        let handle = LuaActorBuilder::compile(
+            r#"
+            if ctx.msg == "restart" then
+                ctx.restart()
+                return
+            end
+            ctx.state.n = ctx.state.n + 1
+            return ctx.msg + ctx.state.n
+            "#,
+        )
+        .unwrap();
+        assert!(LuaActorBuilder::compile("return 1 +").is_err());
+        let addr = LuaActorBuilder::new()
+            .on_started_with_bytecode(&started)
+            .on_handle_with_bytecode(&handle)
+            .on_stopped_with_bytecode(&LuaActorBuilder::compile("return").unwrap())
+            .build()
+            .unwrap()
+            .start();
+
+        // the byte after the signature is the version
+        let mut other_version = handle.clone();
+        other_version[4] = 0x51;
+        for (builder, error) in [
+            (
+                LuaActorBuilder::new().on_handle_with_bytecode(&other_version),
+                "version mismatch",
+            ),
+            (
+                LuaActorBuilder::new().on_handle_with_bytecode(&handle[..handle.len() / 2]),
+                "truncated",
+            ),
+            (
+                LuaActorBuilder::new()
+                    .with_sandbox(true)
+                    .on_handle_with_bytecode(&handle),
+                "binary chunk",
+            ),
+        ] {
+            match builder.build() {
+                Err(e) => assert!(e.to_string().contains(error), "{}", e),
+                Ok(_) => panic!("built with invalid bytecode"),
+            }
+        }
+
+        let restarted = addr.clone();
+        let l = addr
+            .send(LuaMessage::from(10))
+            .and_then(move |res| {
+                assert_eq!(res, LuaMessage::from(12));
+                // the VM is rebuilt from the bytecode
+                restarted
+                    .send(LuaMessage::from("restart"))
+                    .and_then(move |_| restarted.send(LuaMessage::from(10)))
+            })
+            .map(|res| {
+                assert_eq!(res, LuaMessage::from(12));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn with_recipient_invalid_name() {
+        let system = System::new("test");
+        let counter = Counter(Arc::new(AtomicUsize::new(0))).start();
+
+        let res = LuaActorBuilder::new()
+            .with_recipient("self", counter.recipient())
+            .build();
+        assert!(res.is_err());
+
+        System::current().stop();
+        system.run();
+    }
+
+    #[test]
+    fn spawn_on_arbiter() {
+        let system = System::new("test");
+        let arbiter = Arbiter::new("lua");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return ctx.msg + 1"#)
+            .spawn_on(&arbiter)
+            .unwrap();
+        Arbiter::spawn(
+            addr.send(LuaMessage::from(1))
+                .map(|res| {
+                    assert_eq!(res, LuaMessage::from(2));
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
 }
@@ -0,0 +1,319 @@
+use ::actix::prelude::*;
+use futures::Future;
+
+use crate::actor::{HandleError, LuaActor, TryLuaMessage};
+use crate::message::LuaMessage;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+/// Why a call made with `LuaAddrExt` failed.
+#[derive(Debug)]
+pub enum CallError {
+    /// the message couldn't be delivered or the reply didn't arrive in time
+    Mailbox(MailboxError),
+    /// the actor failed to handle the message
+    Script(HandleError),
+    /// the reply isn't of the requested type
+    Conversion {
+        expected: &'static str,
+        actual: LuaMessage,
+    },
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CallError::Mailbox(e) => write!(f, "{}", e),
+            CallError::Script(HandleError::NoHandler) => {
+                write!(f, "the actor has no handle script")
+            }
+            CallError::Script(HandleError::Error(e)) => write!(f, "handle script failed: {}", e),
+            CallError::Conversion { expected, actual } => {
+                write!(f, "expected {} reply, got {}", expected, actual)
+            }
+        }
+    }
+}
+
+pub type CallFuture<T> = Box<dyn Future<Item = T, Error = CallError>>;
+
+/// Send a message to a `LuaActor` and convert the reply, e.g. `addr.call_i64(msg)` resolves to
+/// the integer returned by the `handle` script.
+///
+/// Calls through an `Addr<LuaActor>` send a `TryLuaMessage`, so errors of the `handle` script
+/// are returned as `CallError::Script`. A `Recipient<LuaMessage>` can only send `LuaMessage`,
+/// whose script errors stop the actor: they are returned as `CallError::Mailbox`.
+pub trait LuaAddrExt {
+    /// send `msg`, failing with `MailboxError::Timeout` if the reply takes longer than `timeout`
+    fn call_with_timeout(
+        &self,
+        msg: LuaMessage,
+        timeout: Option<Duration>,
+    ) -> CallFuture<LuaMessage>;
+
+    fn call(&self, msg: LuaMessage) -> CallFuture<LuaMessage> {
+        self.call_with_timeout(msg, None)
+    }
+
+    /// the reply must be a string
+    fn call_str(&self, msg: LuaMessage) -> CallFuture<String> {
+        convert(self.call(msg), "a string", to_str)
+    }
+
+    fn call_str_with_timeout(&self, msg: LuaMessage, timeout: Duration) -> CallFuture<String> {
+        convert(
+            self.call_with_timeout(msg, Some(timeout)),
+            "a string",
+            to_str,
+        )
+    }
+
+    /// the reply must be an integer, or a float with an integer value like `math.tointeger`
+    fn call_i64(&self, msg: LuaMessage) -> CallFuture<i64> {
+        convert(self.call(msg), "an integer", to_i64)
+    }
+
+    fn call_i64_with_timeout(&self, msg: LuaMessage, timeout: Duration) -> CallFuture<i64> {
+        convert(
+            self.call_with_timeout(msg, Some(timeout)),
+            "an integer",
+            to_i64,
+        )
+    }
+
+    /// the reply must be a number
+    fn call_f64(&self, msg: LuaMessage) -> CallFuture<f64> {
+        convert(self.call(msg), "a number", to_f64)
+    }
+
+    fn call_f64_with_timeout(&self, msg: LuaMessage, timeout: Duration) -> CallFuture<f64> {
+        convert(
+            self.call_with_timeout(msg, Some(timeout)),
+            "a number",
+            to_f64,
+        )
+    }
+
+    /// the reply must be a boolean
+    fn call_bool(&self, msg: LuaMessage) -> CallFuture<bool> {
+        convert(self.call(msg), "a boolean", to_bool)
+    }
+
+    fn call_bool_with_timeout(&self, msg: LuaMessage, timeout: Duration) -> CallFuture<bool> {
+        convert(
+            self.call_with_timeout(msg, Some(timeout)),
+            "a boolean",
+            to_bool,
+        )
+    }
+
+    /// the reply must be a table
+    fn call_table(&self, msg: LuaMessage) -> CallFuture<HashMap<String, LuaMessage>> {
+        convert(self.call(msg), "a table", to_table)
+    }
+
+    fn call_table_with_timeout(
+        &self,
+        msg: LuaMessage,
+        timeout: Duration,
+    ) -> CallFuture<HashMap<String, LuaMessage>> {
+        convert(
+            self.call_with_timeout(msg, Some(timeout)),
+            "a table",
+            to_table,
+        )
+    }
+}
+
+impl LuaAddrExt for Addr<LuaActor> {
+    fn call_with_timeout(
+        &self,
+        msg: LuaMessage,
+        timeout: Option<Duration>,
+    ) -> CallFuture<LuaMessage> {
+        let mut req = self.send(TryLuaMessage(msg));
+        if let Some(timeout) = timeout {
+            req = req.timeout(timeout);
+        }
+        Box::new(
+            req.map_err(CallError::Mailbox)
+                .and_then(|res| res.map_err(CallError::Script)),
+        )
+    }
+}
+
+impl LuaAddrExt for Recipient<LuaMessage> {
+    fn call_with_timeout(
+        &self,
+        msg: LuaMessage,
+        timeout: Option<Duration>,
+    ) -> CallFuture<LuaMessage> {
+        let mut req = self.send(msg);
+        if let Some(timeout) = timeout {
+            req = req.timeout(timeout);
+        }
+        Box::new(req.map_err(CallError::Mailbox))
+    }
+}
+
+fn convert<T: 'static>(
+    reply: CallFuture<LuaMessage>,
+    expected: &'static str,
+    to: fn(LuaMessage) -> Result<T, LuaMessage>,
+) -> CallFuture<T> {
+    Box::new(
+        reply.and_then(move |msg| {
+            to(msg).map_err(|actual| CallError::Conversion { expected, actual })
+        }),
+    )
+}
+
+fn to_str(msg: LuaMessage) -> Result<String, LuaMessage> {
+    match msg {
+        LuaMessage::String(s) => Ok(s),
+        msg => Err(msg),
+    }
+}
+
+fn to_i64(msg: LuaMessage) -> Result<i64, LuaMessage> {
+    match msg {
+        LuaMessage::Integer(n) => Ok(n),
+        // 2^63 itself isn't representable
+        LuaMessage::Number(n)
+            if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 =>
+        {
+            Ok(n as i64)
+        }
+        msg => Err(msg),
+    }
+}
+
+fn to_f64(msg: LuaMessage) -> Result<f64, LuaMessage> {
+    match msg {
+        LuaMessage::Number(n) => Ok(n),
+        LuaMessage::Integer(n) => Ok(n as f64),
+        msg => Err(msg),
+    }
+}
+
+fn to_bool(msg: LuaMessage) -> Result<bool, LuaMessage> {
+    match msg {
+        LuaMessage::Boolean(b) => Ok(b),
+        msg => Err(msg),
+    }
+}
+
+fn to_table(msg: LuaMessage) -> Result<HashMap<String, LuaMessage>, LuaMessage> {
+    match msg {
+        LuaMessage::Table(t) => Ok(t),
+        msg => Err(msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LuaActorBuilder;
+    use futures::future;
+
+    const SCRIPT: &str = r#"
+        if ctx.msg == "str" then return "hello"
+        elseif ctx.msg == "int" then return 6 / 2
+        elseif ctx.msg == "float" then return 1.5
+        elseif ctx.msg == "bool" then return true
+        elseif ctx.msg == "table" then return {x = 1}
+        elseif ctx.msg == "fail" then error("boom")
+        elseif ctx.msg == "slow" then
+            local start = os.clock()
+            while os.clock() - start < 0.2 do end
+            return "late"
+        end
+    "#;
+
+    fn run<F>(f: F)
+    where
+        F: FnOnce(Addr<LuaActor>) -> Box<dyn Future<Item = (), Error = ()>> + 'static,
+    {
+        let system = System::new("test");
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(SCRIPT)
+            .build()
+            .unwrap()
+            .start();
+        Arbiter::spawn(f(addr).map(|_| System::current().stop()));
+        system.run();
+    }
+
+    #[test]
+    fn call_typed_replies() {
+        run(|addr| {
+            let recipient = addr.clone().recipient();
+            Box::new(
+                addr.call_str(LuaMessage::from("str"))
+                    .join5(
+                        addr.call_i64(LuaMessage::from("int")),
+                        addr.call_f64(LuaMessage::from("float")),
+                        addr.call_bool(LuaMessage::from("bool")),
+                        addr.call_table(LuaMessage::from("table")),
+                    )
+                    .join(
+                        recipient
+                            .call_f64_with_timeout(LuaMessage::from("int"), Duration::from_secs(1)),
+                    )
+                    .map(|((s, i, f, b, t), n)| {
+                        assert_eq!(s, "hello");
+                        assert_eq!(i, 3);
+                        assert_eq!(f, 1.5);
+                        assert!(b);
+                        assert_eq!(t.get("x"), Some(&LuaMessage::from(1)));
+                        assert_eq!(n, 3.0);
+                    })
+                    .map_err(|e| panic!("call failed: {}", e)),
+            )
+        });
+    }
+
+    #[test]
+    fn call_errors() {
+        run(|addr| {
+            // blocks another thread, so the timeout can expire
+            let slow = LuaActorBuilder::new()
+                .on_handle_with_lua(SCRIPT)
+                .spawn_on(&Arbiter::new("slow"))
+                .unwrap();
+            let calls = vec![
+                addr.call_i64(LuaMessage::from("float")),
+                addr.call_i64(LuaMessage::from("str")),
+                addr.call_i64(LuaMessage::from("fail")),
+                slow.call_i64_with_timeout(LuaMessage::from("slow"), Duration::from_millis(50)),
+            ];
+            Box::new(
+                future::join_all(calls.into_iter().map(|call| call.then(Ok))).map(
+                    |results: Vec<Result<i64, CallError>>| {
+                        let errors: Vec<_> = results.into_iter().map(|r| r.unwrap_err()).collect();
+                        match &errors[0] {
+                            CallError::Conversion { expected, actual } => {
+                                assert_eq!(*expected, "an integer");
+                                assert_eq!(actual, &LuaMessage::from(1.5));
+                            }
+                            e => panic!("unexpected {:?}", e),
+                        }
+                        assert_eq!(
+                            errors[1].to_string(),
+                            "expected an integer reply, got \"hello\""
+                        );
+                        match &errors[2] {
+                            CallError::Script(HandleError::Error(e)) => assert!(e.contains("boom")),
+                            e => panic!("unexpected {:?}", e),
+                        }
+                        match &errors[3] {
+                            CallError::Mailbox(MailboxError::Timeout) => {}
+                            e => panic!("unexpected {:?}", e),
+                        }
+                    },
+                ),
+            )
+        });
+    }
+}
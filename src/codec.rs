@@ -0,0 +1,212 @@
+use crate::message::LuaMessage;
+use rlua::Error as LuaError;
+use std::fmt;
+
+/// Encode/decode `LuaMessage` to/from bytes.
+///
+/// Used by `LuaActor::snapshot_state` and `LuaActor::restore_state`. Encryption or compression
+/// can be added by wrapping one of the provided codecs (`JsonCodec` with the `json` feature,
+/// `BincodeCodec` with the `bincode` feature).
+///
+/// The errors of a codec are returned as `StateError::Codec`, with the subsystem which used it.
+pub trait StateCodec {
+    fn encode(&self, msg: &LuaMessage) -> Result<Vec<u8>, LuaError>;
+    fn decode(&self, bytes: &[u8]) -> Result<LuaMessage, LuaError>;
+}
+
+/// What used the `StateCodec` which failed, see `StateError::Codec`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StateSubsystem {
+    /// `LuaActor::snapshot_state`
+    Snapshot,
+    /// `LuaActor::restore_state`
+    Restore,
+    /// `SharedDataset::decode`
+    Dataset,
+}
+
+impl fmt::Display for StateSubsystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            StateSubsystem::Snapshot => "snapshot",
+            StateSubsystem::Restore => "restore",
+            StateSubsystem::Dataset => "dataset",
+        })
+    }
+}
+
+/// Why encoding or decoding a state failed.
+#[derive(Debug, Clone)]
+pub enum StateError {
+    /// the codec failed, e.g. on a corrupt snapshot
+    Codec {
+        subsystem: StateSubsystem,
+        error: LuaError,
+    },
+    /// any other failure, e.g. the decoded state is not a table
+    Other(LuaError),
+}
+
+impl StateError {
+    pub(crate) fn codec(subsystem: StateSubsystem) -> impl FnOnce(LuaError) -> StateError {
+        move |error| StateError::Codec { subsystem, error }
+    }
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Codec { subsystem, error } => {
+                write!(f, "{}: state codec failed: {}", subsystem, error)
+            }
+            StateError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl From<LuaError> for StateError {
+    fn from(e: LuaError) -> StateError {
+        StateError::Other(e)
+    }
+}
+
+impl From<StateError> for LuaError {
+    fn from(e: StateError) -> LuaError {
+        match e {
+            StateError::Codec { .. } => LuaError::RuntimeError(e.to_string()),
+            StateError::Other(e) => e,
+        }
+    }
+}
+
+/// JSON codec. Tables become objects, arrays become arrays and `Nil` becomes `null`.
+///
+/// Integers are decoded as `Integer`, other numbers as `Number`.
+#[cfg(feature = "json")]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl JsonCodec {
+    fn to_json(msg: &LuaMessage) -> Result<serde_json::Value, LuaError> {
+        use serde_json::Value;
+
+        Ok(match msg {
+            LuaMessage::String(s) => Value::String(s.clone()),
+            LuaMessage::Integer(n) => Value::from(*n),
+            LuaMessage::Number(n) => serde_json::Number::from_f64(*n)
+                .map(Value::Number)
+                .ok_or_else(|| LuaError::RuntimeError(format!("can't encode {} as JSON", n)))?,
+            LuaMessage::Boolean(b) => Value::Bool(*b),
+            LuaMessage::Nil => Value::Null,
+            LuaMessage::Table(t) => {
+                let mut obj = serde_json::Map::new();
+                for (k, v) in t {
+                    obj.insert(k.clone(), Self::to_json(v)?);
+                }
+                Value::Object(obj)
+            }
+            LuaMessage::Array(a) => {
+                Value::Array(a.iter().map(Self::to_json).collect::<Result<_, _>>()?)
+            }
+            #[cfg(feature = "legacy-thread-yield")]
+            #[allow(deprecated)]
+            LuaMessage::ThreadYield(_) => {
+                return Err(LuaError::RuntimeError(
+                    "can't encode ThreadYield as JSON".to_string(),
+                ))
+            }
+            LuaMessage::Error { message, traceback } => {
+                let mut obj = serde_json::Map::new();
+                obj.insert("message".to_string(), Value::String(message.clone()));
+                if let Some(traceback) = traceback {
+                    obj.insert("traceback".to_string(), Value::String(traceback.clone()));
+                }
+                Value::Object(obj)
+            }
+            LuaMessage::Opaque(_) => {
+                return Err(LuaError::RuntimeError(
+                    "can't encode Opaque as JSON".to_string(),
+                ))
+            }
+        })
+    }
+
+    pub(crate) fn from_json(v: serde_json::Value) -> LuaMessage {
+        use serde_json::Value;
+
+        match v {
+            Value::String(s) => LuaMessage::String(s),
+            Value::Number(n) => match n.as_i64() {
+                Some(i) => LuaMessage::Integer(i),
+                None => LuaMessage::Number(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            Value::Bool(b) => LuaMessage::Boolean(b),
+            Value::Null => LuaMessage::Nil,
+            Value::Array(a) => LuaMessage::Array(a.into_iter().map(Self::from_json).collect()),
+            Value::Object(o) => LuaMessage::Table(
+                o.into_iter()
+                    .map(|(k, v)| (k, Self::from_json(v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl StateCodec for JsonCodec {
+    fn encode(&self, msg: &LuaMessage) -> Result<Vec<u8>, LuaError> {
+        serde_json::to_vec(&Self::to_json(msg)?).map_err(LuaError::external)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<LuaMessage, LuaError> {
+        let v = serde_json::from_slice(bytes).map_err(LuaError::external)?;
+        Ok(Self::from_json(v))
+    }
+}
+
+/// Compact binary codec using `bincode`.
+#[cfg(feature = "bincode")]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl StateCodec for BincodeCodec {
+    fn encode(&self, msg: &LuaMessage) -> Result<Vec<u8>, LuaError> {
+        bincode::serialize(msg).map_err(LuaError::external)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<LuaMessage, LuaError> {
+        bincode::deserialize(bytes).map_err(LuaError::external)
+    }
+}
+
+#[cfg(all(test, any(feature = "json", feature = "bincode")))]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn sample() -> LuaMessage {
+        let mut t = HashMap::new();
+        t.insert("s".to_string(), LuaMessage::from("foo"));
+        t.insert("i".to_string(), LuaMessage::from(42));
+        t.insert("n".to_string(), LuaMessage::from(0.5));
+        t.insert("b".to_string(), LuaMessage::from(true));
+        LuaMessage::from(t)
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_round_trip() {
+        let bytes = JsonCodec.encode(&sample()).unwrap();
+        assert_eq!(JsonCodec.decode(&bytes).unwrap(), sample());
+        assert!(JsonCodec.decode(b"{").is_err());
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_round_trip() {
+        let bytes = BincodeCodec.encode(&sample()).unwrap();
+        assert_eq!(BincodeCodec.decode(&bytes).unwrap(), sample());
+    }
+}
@@ -0,0 +1,330 @@
+use ::actix::dev::{MessageResponse, ResponseChannel};
+use ::actix::prelude::*;
+use futures::sync::oneshot;
+use futures::Future;
+
+use crate::actor::LuaActor;
+use crate::message::LuaMessage;
+
+/// Wrap a `String`, `i64` or `bool` to send it to a `LuaActor` without converting it to
+/// `LuaMessage` at the call site. Requires the `compat-handlers` feature.
+///
+/// The value is converted to `LuaMessage` and received like one, then handled by the `handle`
+/// script. The result is converted back to the type of the sent value:
+///
+/// * `nil` becomes `None`.
+/// * A value of another type (e.g. a table returned for a `String` message, or a float for an
+///   `i64` message) becomes `None` and a warning is logged.
+///
+/// ```
+/// # extern crate actix;
+/// # extern crate actix_lua;
+/// use actix::prelude::*;
+/// use actix_lua::{CompatMessage, LuaActorBuilder};
+///
+/// let addr = LuaActorBuilder::new()
+///     .on_handle_with_lua(r#"return ctx.msg .. "!""#)
+///     .build()
+///     .unwrap()
+///     .start();
+/// let res = addr.send(CompatMessage("hello".to_string()));
+/// ```
+pub struct CompatMessage<T>(pub T);
+
+impl Message for CompatMessage<String> {
+    type Result = Option<String>;
+}
+
+impl Message for CompatMessage<i64> {
+    type Result = Option<i64>;
+}
+
+impl Message for CompatMessage<bool> {
+    type Result = Option<bool>;
+}
+
+// Convert the result of the `handle` script with `convert`, logging results of other types.
+fn compat_reply<T>(
+    log_prefix: &str,
+    res: LuaMessage,
+    type_name: &str,
+    convert: fn(LuaMessage) -> Result<T, LuaMessage>,
+) -> Option<T> {
+    match res {
+        LuaMessage::Nil => None,
+        res => match convert(res) {
+            Ok(v) => Some(v),
+            Err(res) => {
+                log::warn!(
+                    "{} returned {:?} which can't be converted to {}",
+                    log_prefix,
+                    res,
+                    type_name
+                );
+                None
+            }
+        },
+    }
+}
+
+/// Response of `Handler<CompatMessage<T>>`.
+///
+/// The message is received like a `LuaMessage`, see `LuaReply`: it waits for `started`, is
+/// buffered while paused and counts toward the inbound rate limit. `ctx.reply_expected` is
+/// false if it was sent with `do_send`.
+pub struct CompatReply<T> {
+    msg: LuaMessage,
+    type_name: &'static str,
+    convert: fn(LuaMessage) -> Result<T, LuaMessage>,
+}
+
+impl<T: 'static> MessageResponse<LuaActor, CompatMessage<T>> for CompatReply<T>
+where
+    CompatMessage<T>: Message<Result = Option<T>>,
+{
+    fn handle<R: ResponseChannel<CompatMessage<T>>>(
+        self,
+        ctx: &mut Context<LuaActor>,
+        tx: Option<R>,
+    ) {
+        let CompatReply {
+            msg,
+            type_name,
+            convert,
+        } = self;
+        ctx.wait(actix::fut::ok(()).map(move |_, act: &mut LuaActor, ctx| {
+            // convert the reply on its way back to the sender
+            let tx = tx.map(|tx| {
+                let log_prefix = act.log_prefix.clone();
+                let (lua_tx, rx) = oneshot::channel();
+                Arbiter::spawn(
+                    rx.map(move |res| tx.send(compat_reply(&log_prefix, res, type_name, convert)))
+                        .map_err(|_| ()),
+                );
+                lua_tx
+            });
+            act.receive(msg, false, tx, ctx)
+        }));
+    }
+}
+
+impl Handler<CompatMessage<String>> for LuaActor {
+    type Result = CompatReply<String>;
+
+    fn handle(&mut self, msg: CompatMessage<String>, _: &mut Context<Self>) -> Self::Result {
+        CompatReply {
+            msg: LuaMessage::from(msg.0),
+            type_name: "String",
+            convert: |res| match res {
+                LuaMessage::String(s) => Ok(s),
+                res => Err(res),
+            },
+        }
+    }
+}
+
+impl Handler<CompatMessage<i64>> for LuaActor {
+    type Result = CompatReply<i64>;
+
+    fn handle(&mut self, msg: CompatMessage<i64>, _: &mut Context<Self>) -> Self::Result {
+        CompatReply {
+            msg: LuaMessage::from(msg.0),
+            type_name: "i64",
+            convert: |res| match res {
+                LuaMessage::Integer(n) => Ok(n),
+                res => Err(res),
+            },
+        }
+    }
+}
+
+impl Handler<CompatMessage<bool>> for LuaActor {
+    type Result = CompatReply<bool>;
+
+    fn handle(&mut self, msg: CompatMessage<bool>, _: &mut Context<Self>) -> Self::Result {
+        CompatReply {
+            msg: LuaMessage::from(msg.0),
+            type_name: "bool",
+            convert: |res| match res {
+                LuaMessage::Boolean(b) => Ok(b),
+                res => Err(res),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::actor::AddRecipient;
+    use crate::builder::LuaActorBuilder;
+    use crate::pause::{PauseProcessing, ResumeProcessing};
+    use crate::ping::Ping;
+    use crate::rate_limit::{GetInboundStats, ShedPolicy};
+
+    #[test]
+    fn compat_messages() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "table" then return {} end
+                if type(ctx.msg) == "string" then return ctx.msg .. "!" end
+                if type(ctx.msg) == "number" then return ctx.msg + 1 end
+                if ctx.msg == true then return nil end
+                return not ctx.msg
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let s = addr.send(CompatMessage("hi".to_string()));
+        let table = addr.send(CompatMessage("table".to_string()));
+        let n = addr.send(CompatMessage(41));
+        let b = addr.send(CompatMessage(false));
+        let nil = addr.send(CompatMessage(true));
+        Arbiter::spawn(
+            s.join5(table, n, b, nil)
+                .map(|(s, table, n, b, nil)| {
+                    assert_eq!(s, Some("hi!".to_string()));
+                    // lossy: a table can't be converted to String
+                    assert_eq!(table, None);
+                    assert_eq!(n, Some(42));
+                    assert_eq!(b, Some(true));
+                    assert_eq!(nil, None);
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn compat_waits_for_started() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .require_recipients(&["db"], Duration::from_secs(5))
+            .on_started_with_lua(r#"ctx.state.started = true"#)
+            .on_handle_with_lua(r#"return tostring(ctx.state.started)"#)
+            .spawn()
+            .unwrap();
+
+        let l = addr.send(CompatMessage("started?".to_string()));
+        addr.do_send(AddRecipient {
+            name: "db".to_string(),
+            recipient: LuaActorBuilder::new().build().unwrap().start().recipient(),
+        });
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res, Some("true".to_string()));
+                System::current().stop();
+            })
+            .map_err(|e| panic!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn compat_held_while_paused() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return ctx.msg + 1"#)
+            .build()
+            .unwrap()
+            .start();
+
+        addr.do_send(PauseProcessing {
+            drain_current: false,
+            hold_timers: false,
+        });
+        let held = addr.send(CompatMessage(41));
+        let l = addr
+            .send(Ping)
+            .and_then(move |ping| {
+                assert_eq!(ping.get("handled"), Some(&LuaMessage::from(0)));
+                addr.do_send(ResumeProcessing);
+                held
+            })
+            .map(|res| {
+                assert_eq!(res, Some(42));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn compat_rate_limited() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_inbound_rate_limit(1.0, 1.0, ShedPolicy::Reject)
+            .on_handle_with_lua(r#"return ctx.msg + 1"#)
+            .build()
+            .unwrap()
+            .start();
+
+        let first = addr.send(CompatMessage(1));
+        let second = addr.send(CompatMessage(2));
+        let l = first
+            .join(second)
+            .and_then(move |(first, second)| {
+                assert_eq!(first, Some(2));
+                // the `rate_limited` error can't be converted to i64
+                assert_eq!(second, None);
+                addr.send(GetInboundStats)
+            })
+            .map(|stats| {
+                assert_eq!(stats.get("rejected"), Some(&LuaMessage::from(1)));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn compat_reply_expected() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "get" then
+                    return ctx.state.reply_expected
+                end
+                ctx.state.reply_expected = tostring(ctx.reply_expected)
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        addr.do_send(CompatMessage("set".to_string()));
+        let addr2 = addr.clone();
+        let l = addr
+            .send(CompatMessage("get".to_string()))
+            .and_then(move |res| {
+                assert_eq!(res, Some("false".to_string()));
+                addr2
+                    .send(CompatMessage("set".to_string()))
+                    .and_then(move |_| addr2.send(CompatMessage("get".to_string())))
+            })
+            .map(|res| {
+                assert_eq!(res, Some("true".to_string()));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+}
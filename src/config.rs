@@ -0,0 +1,546 @@
+use ::actix::prelude::*;
+
+use crate::actor::{LuaActor, MissingHandler};
+use crate::ids::IdSource;
+use crate::message::{LuaMessage, ZeroBased};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Read the configuration in force in a `LuaActor`.
+///
+/// The values are read from the actor rather than from its builder, e.g. the limits reflect
+/// the defaults of the options which weren't set. The reply is a table, durations are in
+/// milliseconds and disabled options are `nil` (`ctx.null` in Lua):
+///
+/// ```lua
+/// {
+///     name = "actor name",
+///     -- see `LuaActorBuilder::with_build_info`, nil without
+///     build = {version = "1.2.3"},
+///     limits = {
+///         max_depth = 128, max_table_entries = 100000, overflow_errors = false,
+///         zero_based = "table", max_array_gap = 0,
+///         memory_limit = nil, gc_watermark = nil, max_duration_ms = ...,
+///         max_recipient_name_len = 128, max_correlated = ..., coroutine_pool_size = ...,
+///         stopped_deadline_ms = 5000, send_timeout_ms = nil, handle_timeout_ms = nil,
+///         state_quota = nil, pause_buffer = 1024, max_receipts = 1024,
+///         max_export_size = 1048576,
+///     },
+///     features = {
+///         strict_globals = false, rich_send_results = false, state_diffing = false,
+///         read_only_state = false, frozen_msg = false, lazy_tables = false, compat_shims = false,
+///         seeded_ids = false, deep_ping = false,
+///         isolated_hooks = false, sandbox = false, idempotency = false, circuit_breaker = false,
+///         inbound_rate_limit = false, notify_coalescing = false, watchdog_ms = nil,
+///         missing_handler = "return_nil" | "error" | "forward:<name>",
+///         terminal_reply = nil, unprocessed_sink = nil,
+///         state_quota_policy = nil | "warn" | "reject_writes" | "stop",
+///         pause_overflow = "drop_newest" | "drop_oldest",
+///         package_path = nil, package_cpath = nil,
+///     },
+///     -- the names of the `ctx` functions, as listed by `ctx.help()`
+///     api = {"notify", "send", ...},
+///     -- the hooks loaded, with their script name and version if they come from a
+///     -- `ScriptSource`
+///     scripts = {handle = {source = "script_source", name = "handle.lua", version = "3"},
+///                started = {source = "builder"}},
+///     restartable = true,
+///     -- the children of `with_child`, they share nothing with the actor and each answers
+///     -- `GetEffectiveConfig` with its own configuration
+///     children = {worker = {running = true, replacing = false}},
+/// }
+/// ```
+///
+/// The suspicious combinations of options are reported as warnings when the actor starts,
+/// see `LuaActorBuilder::with_warning_callback`.
+pub struct GetEffectiveConfig;
+
+impl Message for GetEffectiveConfig {
+    type Result = LuaMessage;
+}
+
+impl Handler<GetEffectiveConfig> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, _: GetEffectiveConfig, _: &mut Context<Self>) -> LuaMessage {
+        self.effective_config()
+    }
+}
+
+fn millis(d: Duration) -> LuaMessage {
+    LuaMessage::from(d.as_millis() as i64)
+}
+
+fn optional<T: Into<LuaMessage>>(value: Option<T>) -> LuaMessage {
+    value.map(Into::into).unwrap_or(LuaMessage::Nil)
+}
+
+impl LuaActor {
+    pub(crate) fn effective_config(&self) -> LuaMessage {
+        let limits = &self.conversion_limits;
+        let mut l = HashMap::new();
+        l.insert("max_depth".to_string(), LuaMessage::from(limits.max_depth));
+        l.insert(
+            "max_table_entries".to_string(),
+            optional(limits.max_table_entries),
+        );
+        l.insert(
+            "overflow_errors".to_string(),
+            LuaMessage::from(limits.overflow_errors),
+        );
+        let zero_based = match limits.options.zero_based {
+            ZeroBased::Table => "table",
+            ZeroBased::Shift => "shift",
+            ZeroBased::Reject => "reject",
+        };
+        l.insert("zero_based".to_string(), LuaMessage::from(zero_based));
+        l.insert(
+            "max_array_gap".to_string(),
+            LuaMessage::from(limits.options.max_gap),
+        );
+        l.insert(
+            "memory_limit".to_string(),
+            optional(self.runtime.memory_limit),
+        );
+        l.insert(
+            "gc_watermark".to_string(),
+            optional(self.gc_watermark.as_ref().map(|gc| gc.fraction)),
+        );
+        l.insert(
+            "max_duration_ms".to_string(),
+            millis(self.runtime.max_duration),
+        );
+        l.insert(
+            "max_recipient_name_len".to_string(),
+            LuaMessage::from(self.runtime.max_recipient_name_len),
+        );
+        l.insert(
+            "max_correlated".to_string(),
+            LuaMessage::from(self.max_correlated),
+        );
+        l.insert(
+            "max_export_size".to_string(),
+            LuaMessage::from(self.max_export_size),
+        );
+        l.insert(
+            "coroutine_pool_size".to_string(),
+            LuaMessage::from(self.coroutine_pool_size),
+        );
+        l.insert(
+            "stopped_deadline_ms".to_string(),
+            millis(self.stopped_deadline),
+        );
+        l.insert(
+            "send_timeout_ms".to_string(),
+            optional(self.send_timeout.map(millis)),
+        );
+        l.insert(
+            "handle_timeout_ms".to_string(),
+            optional(self.runtime.handle_timeout.map(millis)),
+        );
+        l.insert(
+            "state_quota".to_string(),
+            optional(self.state_quota.as_ref().map(|q| q.bytes)),
+        );
+        l.insert(
+            "pause_buffer".to_string(),
+            LuaMessage::from(self.pause.max_buffered),
+        );
+        l.insert(
+            "max_receipts".to_string(),
+            LuaMessage::from(self.receipts.max_outstanding),
+        );
+
+        let missing_handler = match self.missing_handler {
+            MissingHandler::ReturnNil => "return_nil".to_string(),
+            MissingHandler::Error => "error".to_string(),
+            MissingHandler::Forward(ref name) => format!("forward:{}", name),
+        };
+        let mut f = HashMap::new();
+        for (name, enabled) in [
+            ("strict_globals", self.strict_globals),
+            ("rich_send_results", self.rich_send_results),
+            ("state_diffing", self.state_diffing),
+            ("read_only_state", self.read_only_state),
+            ("frozen_msg", self.frozen_msg),
+            ("lazy_tables", self.runtime.lazy_tables),
+            ("compat_shims", self.runtime.compat_shims),
+            ("seeded_ids", matches!(self.ids, IdSource::Seeded(_))),
+            ("deep_ping", self.deep_ping),
+            ("isolated_hooks", self.runtime.isolated_hooks),
+            ("sandbox", self.runtime.sandboxed),
+            ("idempotency", self.idempotency.is_some()),
+            ("circuit_breaker", self.circuit_breaker.is_some()),
+            ("inbound_rate_limit", self.inbound.is_some()),
+            ("notify_coalescing", self.notify_coalescing.is_some()),
+        ] {
+            f.insert(name.to_string(), LuaMessage::from(enabled));
+        }
+        f.insert(
+            "watchdog_ms".to_string(),
+            optional(self.watchdog.as_ref().map(|w| millis(w.threshold))),
+        );
+        f.insert(
+            "missing_handler".to_string(),
+            LuaMessage::from(missing_handler),
+        );
+        f.insert("terminal_reply".to_string(), self.terminal_reply.clone());
+        f.insert(
+            "unprocessed_sink".to_string(),
+            optional(self.unprocessed_sink.as_deref()),
+        );
+        f.insert(
+            "state_quota_policy".to_string(),
+            optional(self.state_quota.as_ref().map(|q| q.policy.name())),
+        );
+        f.insert(
+            "pause_overflow".to_string(),
+            LuaMessage::from(self.pause.overflow.name()),
+        );
+        f.insert(
+            "package_path".to_string(),
+            optional(self.runtime.package_path.as_deref()),
+        );
+        f.insert(
+            "package_cpath".to_string(),
+            optional(self.runtime.package_cpath.as_deref()),
+        );
+
+        let api = self
+            .api_manifest()
+            .iter()
+            .map(|entry| LuaMessage::from(entry.name))
+            .collect();
+
+        let mut config = HashMap::new();
+        config.insert("name".to_string(), LuaMessage::from(self.name.as_str()));
+        config.insert("build".to_string(), self.build_info.clone());
+        config.insert("limits".to_string(), LuaMessage::from(l));
+        config.insert("features".to_string(), LuaMessage::from(f));
+        config.insert("api".to_string(), LuaMessage::Array(api));
+        config.insert(
+            "scripts".to_string(),
+            LuaMessage::from(self.loaded_scripts()),
+        );
+        config.insert(
+            "restartable".to_string(),
+            LuaMessage::from(self.restartable()),
+        );
+        config.insert(
+            "children".to_string(),
+            LuaMessage::from(self.children.describe()),
+        );
+        LuaMessage::from(config)
+    }
+
+    // The suspicious combinations of options, by warning key.
+    pub(crate) fn config_warnings(&self) -> Vec<(&'static str, String)> {
+        let mut warnings = Vec::new();
+        if let (Some(bytes), None) = (
+            self.runtime.memory_limit,
+            self.conversion_limits.max_table_entries,
+        ) {
+            warnings.push((
+                "unbounded_messages",
+                format!(
+                    "memory limit of {} bytes without a table entries limit, converting a \
+                     large message can use up the memory of the VM, see with_max_table_entries",
+                    bytes
+                ),
+            ));
+        }
+        if self.read_only_state && self.state_diffing {
+            warnings.push((
+                "read_only_state_diffing",
+                "state diffing with a read-only state, the state is converted twice per \
+                 message for diffs which are always empty"
+                    .to_string(),
+            ));
+        }
+        warnings
+    }
+
+    // Emit the warnings of `config_warnings`, when the actor starts.
+    pub(crate) fn warn_config(&mut self) {
+        let now = Instant::now();
+        for (key, message) in self.config_warnings() {
+            self.warnings.warn_once(&self.name, key, &message, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::correlation::DEFAULT_MAX_CORRELATED;
+    use crate::runtime::{DEFAULT_COROUTINE_POOL_SIZE, DEFAULT_MAX_DURATION};
+    use crate::{lua_msg, ConversionOptions, LuaActorBuilder, PauseOverflow, StateQuotaPolicy};
+    use futures::Future;
+    use std::sync::{Arc, Mutex};
+
+    // The configuration without the `api` list, checked separately.
+    fn config(mut config: LuaMessage) -> (LuaMessage, Vec<String>) {
+        let api = match config {
+            LuaMessage::Table(ref mut t) => t.remove("api"),
+            _ => None,
+        };
+        let names = match api {
+            Some(LuaMessage::Array(api)) => api
+                .into_iter()
+                .map(|name| match name {
+                    LuaMessage::String(name) => name,
+                    name => panic!("unexpected api name {:?}", name),
+                })
+                .collect(),
+            api => panic!("unexpected api {:?}", api),
+        };
+        (config, names)
+    }
+
+    #[test]
+    fn effective_config_defaults() {
+        let actor = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return ctx.msg"#)
+            .build()
+            .unwrap();
+        let (config, api) = config(actor.effective_config());
+        let mut expected = lua_msg!({
+            name: "LuaActor",
+            limits: {
+                max_depth: 128,
+                max_table_entries: 100_000,
+                overflow_errors: false,
+                zero_based: "table",
+                max_array_gap: 0,
+                memory_limit: nil,
+                gc_watermark: nil,
+                max_recipient_name_len: 128,
+                stopped_deadline_ms: 5000,
+                send_timeout_ms: nil,
+                handle_timeout_ms: nil,
+                state_quota: nil,
+                pause_buffer: 1024,
+                max_receipts: 1024,
+                max_export_size: 1048576,
+            },
+            features: {
+                strict_globals: false,
+                rich_send_results: false,
+                state_diffing: false,
+                read_only_state: false,
+                frozen_msg: false,
+                lazy_tables: false,
+                compat_shims: false,
+                seeded_ids: false,
+                deep_ping: false,
+                isolated_hooks: false,
+                sandbox: false,
+                idempotency: false,
+                circuit_breaker: false,
+                inbound_rate_limit: false,
+                notify_coalescing: false,
+                watchdog_ms: nil,
+                missing_handler: "return_nil",
+                terminal_reply: nil,
+                unprocessed_sink: nil,
+                state_quota_policy: nil,
+                pause_overflow: "drop_newest",
+                package_path: nil,
+                package_cpath: nil,
+            },
+            // `started` and `stopped` default to no-op scripts
+            scripts: {
+                started: { source: "builder" },
+                handle: { source: "builder" },
+                stopped: { source: "builder" },
+            },
+            restartable: true,
+            children: {},
+            build: nil,
+        });
+        if let LuaMessage::Table(ref mut t) = expected {
+            if let Some(LuaMessage::Table(limits)) = t.get_mut("limits") {
+                limits.insert("max_duration_ms".to_string(), millis(DEFAULT_MAX_DURATION));
+                limits.insert(
+                    "max_correlated".to_string(),
+                    LuaMessage::from(DEFAULT_MAX_CORRELATED),
+                );
+                limits.insert(
+                    "coroutine_pool_size".to_string(),
+                    LuaMessage::from(DEFAULT_COROUTINE_POOL_SIZE),
+                );
+            }
+        }
+        assert_eq!(config, expected);
+        // in the order of `ctx.help()`
+        let names: Vec<_> = actor.api_manifest().iter().map(|e| e.name).collect();
+        assert_eq!(api, names);
+        assert!(!api.contains(&"breaker_state".to_string()));
+        assert!(actor.config_warnings().is_empty());
+    }
+
+    #[test]
+    fn effective_config_configured() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_name("configured")
+            .on_started_with_lua(r#"ctx.state.ready = true"#)
+            .with_memory_limit(1 << 24)
+            .with_gc_watermark(0.5)
+            .with_max_table_entries(Some(10))
+            .with_overflow_errors(true)
+            .with_conversion_options(ConversionOptions {
+                zero_based: ZeroBased::Shift,
+                max_gap: 2,
+            })
+            .with_lazy_tables(true)
+            .with_compat_shims(true)
+            .with_id_seed(7)
+            .with_deep_ping(true)
+            .with_frozen_msg(true)
+            .with_circuit_breaker(3, Duration::from_secs(1), Duration::from_secs(2))
+            .with_watchdog(Duration::from_secs(2))
+            .with_send_timeout(Duration::from_millis(1500))
+            .with_handle_timeout(Duration::from_millis(250))
+            .with_missing_handler(MissingHandler::Error)
+            .with_terminal_reply(lua_msg!({ error: "stopping" }))
+            .with_unprocessed_sink("dead_letters")
+            .with_state_quota(4096, StateQuotaPolicy::RejectWrites)
+            .with_pause_buffer(16, PauseOverflow::DropOldest)
+            .with_max_receipts(8)
+            .with_max_export_size(4096)
+            .with_build_info(lua_msg!({ version: "1.2.3" }))
+            .with_package_path("./scripts/?.lua")
+            .with_child(
+                "worker",
+                LuaActorBuilder::new().on_handle_with_lua(r#"return ctx.msg"#),
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr.send(GetEffectiveConfig).map(|res| {
+            let (config, api) = config(res);
+            assert_eq!(config.get("name"), Some(&LuaMessage::from("configured")));
+            assert_eq!(config.get("build"), Some(&lua_msg!({ version: "1.2.3" })));
+            let limits = config.get("limits").unwrap();
+            assert_eq!(limits.get("memory_limit"), Some(&LuaMessage::from(1 << 24)));
+            assert_eq!(limits.get("gc_watermark"), Some(&LuaMessage::from(0.5)));
+            assert_eq!(limits.get("max_table_entries"), Some(&LuaMessage::from(10)));
+            assert_eq!(limits.get("overflow_errors"), Some(&LuaMessage::from(true)));
+            assert_eq!(limits.get("zero_based"), Some(&LuaMessage::from("shift")));
+            assert_eq!(limits.get("max_array_gap"), Some(&LuaMessage::from(2)));
+            assert_eq!(limits.get("send_timeout_ms"), Some(&LuaMessage::from(1500)));
+            assert_eq!(
+                limits.get("handle_timeout_ms"),
+                Some(&LuaMessage::from(250))
+            );
+            assert_eq!(limits.get("state_quota"), Some(&LuaMessage::from(4096)));
+            assert_eq!(limits.get("pause_buffer"), Some(&LuaMessage::from(16)));
+            assert_eq!(limits.get("max_receipts"), Some(&LuaMessage::from(8)));
+            assert_eq!(limits.get("max_export_size"), Some(&LuaMessage::from(4096)));
+            let features = config.get("features").unwrap();
+            for enabled in &[
+                "lazy_tables",
+                "compat_shims",
+                "seeded_ids",
+                "deep_ping",
+                "frozen_msg",
+                "circuit_breaker",
+            ] {
+                assert_eq!(features.get(enabled), Some(&LuaMessage::from(true)));
+            }
+            assert_eq!(
+                features.get("state_diffing"),
+                Some(&LuaMessage::from(false))
+            );
+            assert_eq!(features.get("watchdog_ms"), Some(&LuaMessage::from(2000)));
+            assert_eq!(
+                features.get("missing_handler"),
+                Some(&LuaMessage::from("error"))
+            );
+            assert_eq!(
+                features.get("terminal_reply"),
+                Some(&lua_msg!({ error: "stopping" }))
+            );
+            assert_eq!(
+                features.get("unprocessed_sink"),
+                Some(&LuaMessage::from("dead_letters"))
+            );
+            assert_eq!(
+                features.get("state_quota_policy"),
+                Some(&LuaMessage::from("reject_writes"))
+            );
+            assert_eq!(
+                features.get("pause_overflow"),
+                Some(&LuaMessage::from("drop_oldest"))
+            );
+            assert_eq!(
+                features.get("package_path"),
+                Some(&LuaMessage::from("./scripts/?.lua"))
+            );
+            // no `handle` script with `MissingHandler::Error`
+            assert_eq!(
+                config.get("scripts"),
+                Some(&lua_msg!({
+                    started: { source: "builder" },
+                    stopped: { source: "builder" },
+                }))
+            );
+            assert_eq!(
+                config.get("children"),
+                Some(&lua_msg!({ worker: { running: true, replacing: false } }))
+            );
+            // enabled by the circuit breaker
+            assert!(api.contains(&"breaker_state".to_string()));
+            System::current().stop();
+        });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+        system.run();
+    }
+
+    #[test]
+    fn effective_config_warnings() {
+        let system = System::new("test");
+
+        let captured = Arc::new(Mutex::new(vec![]));
+        let captured2 = captured.clone();
+        let addr = LuaActorBuilder::new()
+            .with_name("suspicious")
+            .with_memory_limit(1 << 24)
+            .with_max_table_entries(None)
+            .with_warning_callback(move |w| captured2.lock().unwrap().push(w.clone()))
+            .on_handle_with_lua(r#"return ctx.msg"#)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr.send(LuaMessage::Nil).map(move |_| {
+            let captured = captured.lock().unwrap();
+            let keys: Vec<_> = captured.iter().map(|w| w.key.as_str()).collect();
+            assert_eq!(keys, vec!["unbounded_messages"]);
+            assert_eq!(captured[0].actor, "suspicious");
+            assert!(
+                captured[0].message.contains("with_max_table_entries"),
+                "{}",
+                captured[0].message
+            );
+            System::current().stop();
+        });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+        system.run();
+
+        let actor = LuaActorBuilder::new()
+            .with_read_only_state(true)
+            .with_state_diffing(true)
+            .build()
+            .unwrap();
+        let keys: Vec<_> = actor
+            .config_warnings()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec!["read_only_state_diffing"]);
+    }
+}
@@ -0,0 +1,63 @@
+use ::actix::prelude::*;
+
+use crate::actor::{send_error, LuaActor};
+use crate::message::LuaMessage;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+// Pending `ctx.send_correlated` per actor, see `LuaActorBuilder::with_max_correlated`.
+pub(crate) const DEFAULT_MAX_CORRELATED: usize = 1024;
+
+// Ids are unique in the process, a late reply can't match a request of another VM.
+static NEXT_ID: AtomicI64 = AtomicI64::new(1);
+
+pub(crate) fn next_correlation_id() -> i64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// The timeouts of the pending `ctx.send_correlated` by correlation id. The matchers themselves
+// live in the VM, see `__correlated` in the prelude.
+#[derive(Default)]
+pub(crate) struct Correlations {
+    timeouts: HashMap<i64, SpawnHandle>,
+}
+
+impl Correlations {
+    pub fn schedule(&mut self, ctx: &mut Context<LuaActor>, id: i64, after: Duration) {
+        let handle = ctx.run_later(after, move |act, ctx| act.correlation_timed_out(ctx, id));
+        self.timeouts.insert(id, handle);
+    }
+
+    // The reply arrived.
+    pub fn cancel(&mut self, ctx: &mut Context<LuaActor>, id: i64) {
+        if let Some(handle) = self.timeouts.remove(&id) {
+            ctx.cancel_future(handle);
+        }
+    }
+
+    // The matchers were dropped, e.g. with the VM.
+    pub fn clear(&mut self, ctx: &mut Context<LuaActor>) {
+        for (_, handle) in self.timeouts.drain() {
+            ctx.cancel_future(handle);
+        }
+    }
+}
+
+impl LuaActor {
+    fn correlation_timed_out(&mut self, ctx: &mut Context<Self>, id: i64) {
+        self.correlations.timeouts.remove(&id);
+        let error = send_error("timeout", None, self.rich_send_results);
+        let res = self.invoke_hook(
+            ctx,
+            "resume",
+            "__correlation_timeout",
+            vec![LuaMessage::from(id), error],
+            false,
+        );
+        // nobody waits for the result of a resumed coroutine
+        if let Err(e) = res {
+            self.record_error("handle", &e.to_string(), ctx);
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use rlua::Error as LuaError;
+
+// The search for the next fire time stops after 400 years, the Gregorian calendar repeats
+// itself after that.
+const MAX_DAYS: i64 = 400 * 366;
+
+const SECS_PER_DAY: i64 = 86_400;
+
+// A field of a cron expression: name, minimum and maximum value.
+struct Field(&'static str, u32, u32);
+
+const MINUTE: Field = Field("minute", 0, 59);
+const HOUR: Field = Field("hour", 0, 23);
+const DAY_OF_MONTH: Field = Field("day-of-month", 1, 31);
+const MONTH: Field = Field("month", 1, 12);
+// 0 and 7 are Sunday
+const DAY_OF_WEEK: Field = Field("day-of-week", 0, 7);
+
+// A cron expression of `ctx.schedule`: minute, hour, day-of-month, month and day-of-week,
+// evaluated at a fixed offset from UTC.
+//
+// Each field is `*` or a comma separated list of values `n`, ranges `a-b` and steps `*/s`,
+// `a-b/s` or `a/s`. Like cron, a day matches if either the day-of-month or the day-of-week
+// matches when both are restricted.
+//
+// There is no daylight saving time at a fixed offset: every minute exists exactly once, so no
+// fire time is skipped or repeated.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CronExpr {
+    // bit `n` is set if the value `n` matches
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // `*` day fields don't take part in the day-of-month or day-of-week rule
+    any_day: bool,
+    any_weekday: bool,
+    // seconds east of UTC
+    offset: i64,
+}
+
+impl CronExpr {
+    pub fn parse(expr: &str, utc_offset_minutes: i64) -> Result<CronExpr, LuaError> {
+        let error = |reason: String| {
+            LuaError::RuntimeError(format!("invalid cron expression {:?}: {}", expr, reason))
+        };
+        if utc_offset_minutes.abs() >= 24 * 60 {
+            return Err(error(format!(
+                "UTC offset of {} minutes out of range, expected less than 24 hours",
+                utc_offset_minutes
+            )));
+        }
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(error(format!(
+                "expected 5 fields (minute hour day-of-month month day-of-week), got {}",
+                fields.len()
+            )));
+        }
+        let parse = |field: &Field, value: &str| parse_field(field, value).map_err(error);
+        let mut weekdays = parse(&DAY_OF_WEEK, fields[4])?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        let cron = CronExpr {
+            minutes: parse(&MINUTE, fields[0])?,
+            hours: parse(&HOUR, fields[1])?,
+            days: parse(&DAY_OF_MONTH, fields[2])?,
+            months: parse(&MONTH, fields[3])?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+            offset: utc_offset_minutes * 60,
+        };
+        if cron.next_after(0).is_none() {
+            return Err(error("never matches a date".to_string()));
+        }
+        Ok(cron)
+    }
+
+    fn matches_day(&self, days: i64) -> bool {
+        let (_, month, day) = civil_from_days(days);
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 4).rem_euclid(7);
+        let day_matches = self.days & (1 << day) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day_matches || weekday_matches,
+            _ => day_matches && weekday_matches,
+        }
+    }
+
+    // The first matching minute strictly after the Unix time `after`, in seconds.
+    pub fn next_after(&self, after: i64) -> Option<i64> {
+        let local = after + self.offset;
+        let start = local.div_euclid(60) * 60 + 60;
+        let first_day = start.div_euclid(SECS_PER_DAY);
+        let mut minute_of_day = start.rem_euclid(SECS_PER_DAY) / 60;
+        for days in first_day..first_day + MAX_DAYS {
+            if self.matches_day(days) {
+                for minute in minute_of_day..24 * 60 {
+                    if self.hours & (1 << (minute / 60)) != 0
+                        && self.minutes & (1 << (minute % 60)) != 0
+                    {
+                        return Some(days * SECS_PER_DAY + minute * 60 - self.offset);
+                    }
+                }
+            }
+            minute_of_day = 0;
+        }
+        None
+    }
+}
+
+fn parse_field(field: &Field, value: &str) -> Result<u64, String> {
+    let Field(name, min, max) = *field;
+    let number = |s: &str| -> Result<u32, String> {
+        let n: u32 = s
+            .parse()
+            .map_err(|_| format!("{} field {:?}: {:?} is not a number", name, value, s))?;
+        if n < min || n > max {
+            return Err(format!(
+                "{} field {:?}: {} out of range {}-{}",
+                name, value, n, min, max
+            ));
+        }
+        Ok(n)
+    };
+    let mut bits = 0;
+    for item in value.split(',') {
+        let (range, step) = match item.find('/') {
+            Some(i) => {
+                let step: u32 = item[i + 1..].parse().map_err(|_| {
+                    format!("{} field {:?}: invalid step in {:?}", name, value, item)
+                })?;
+                if step == 0 {
+                    return Err(format!(
+                        "{} field {:?}: the step must be positive",
+                        name, value
+                    ));
+                }
+                (&item[..i], Some(step))
+            }
+            None => (item, None),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            _ => match range.find('-') {
+                Some(i) => (number(&range[..i])?, number(&range[i + 1..])?),
+                // `a/s` runs up to the maximum
+                None if step.is_some() => (number(range)?, max),
+                None => {
+                    let n = number(range)?;
+                    (n, n)
+                }
+            },
+        };
+        if first > last {
+            return Err(format!(
+                "{} field {:?}: the range {}-{} is reversed",
+                name, value, first, last
+            ));
+        }
+        let mut n = first;
+        while n <= last {
+            bits |= 1 << n;
+            n += step.unwrap_or(1);
+        }
+    }
+    Ok(bits)
+}
+
+// Year, month and day of the day `days` after 1970-01-01, see
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Unix time of a UTC date, the inverse of `civil_from_days`.
+    fn at(year: i64, month: i64, day: i64, hour: i64, minute: i64) -> i64 {
+        let y = if month <= 2 { year - 1 } else { year };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let mp = (month + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146_097 + doe - 719_468;
+        days * SECS_PER_DAY + hour * 3600 + minute * 60
+    }
+
+    fn next(expr: &str, after: i64) -> i64 {
+        CronExpr::parse(expr, 0).unwrap().next_after(after).unwrap()
+    }
+
+    fn error(expr: &str) -> String {
+        CronExpr::parse(expr, 0).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn cron_parse_errors() {
+        assert!(error("* * * *").contains("expected 5 fields"));
+        assert!(error("60 * * * *").contains("minute field \"60\": 60 out of range 0-59"));
+        assert!(error("* 24 * * *").contains("hour field"));
+        assert!(error("* * 0 * *").contains("day-of-month field \"0\": 0 out of range 1-31"));
+        assert!(error("* * * 13 * *").contains("expected 5 fields"));
+        assert!(error("* * * 13 *").contains("month field"));
+        assert!(error("* * * * 8").contains("day-of-week field"));
+        assert!(error("x * * * *").contains("\"x\" is not a number"));
+        assert!(error("*/0 * * * *").contains("the step must be positive"));
+        assert!(error("*/x * * * *").contains("invalid step"));
+        assert!(error("30-10 * * * *").contains("the range 30-10 is reversed"));
+        assert!(error("0 0 30 2 *").contains("never matches a date"));
+        assert!(CronExpr::parse("* * * * *", 24 * 60)
+            .unwrap_err()
+            .to_string()
+            .contains("UTC offset"));
+
+        let cron = CronExpr::parse("0,30 9-17/4 * 1-6 1-5", 0).unwrap();
+        assert_eq!(cron.minutes, 1 | 1 << 30);
+        assert_eq!(cron.hours, 1 << 9 | 1 << 13 | 1 << 17);
+        assert_eq!(cron.months, 0b111_1110);
+        // Sunday is 0 or 7
+        assert_eq!(CronExpr::parse("0 0 * * 7", 0).unwrap().weekdays, 1);
+        assert_eq!(
+            CronExpr::parse("0 0 * * 5/1", 0).unwrap().weekdays,
+            1 | 1 << 5 | 1 << 6
+        );
+    }
+
+    #[test]
+    fn cron_next_after() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(at(2024, 2, 29, 0, 0) / SECS_PER_DAY, 19_782);
+
+        // the next minute, not the current one
+        let t = at(2024, 1, 31, 12, 0);
+        assert_eq!(next("* * * * *", t), t + 60);
+        assert_eq!(next("* * * * *", t + 59), t + 60);
+        // across the end of the month
+        assert_eq!(next("0 0 1 * *", t), at(2024, 2, 1, 0, 0));
+        assert_eq!(next("15 3 31 * *", t), at(2024, 3, 31, 3, 15));
+        assert_eq!(next("0 0 29 2 *", t), at(2024, 2, 29, 0, 0));
+        assert_eq!(
+            next("0 0 29 2 *", at(2024, 3, 1, 0, 0)),
+            at(2028, 2, 29, 0, 0)
+        );
+        // across the end of the year
+        assert_eq!(
+            next("30 23 * * *", at(2023, 12, 31, 23, 30)),
+            at(2024, 1, 1, 23, 30)
+        );
+        // 2024-02-01 is a Thursday
+        assert_eq!(next("0 8 * * 1", t), at(2024, 2, 5, 8, 0));
+        // either the day-of-month or the day-of-week
+        assert_eq!(next("0 8 15 * 1", t), at(2024, 2, 5, 8, 0));
+        assert_eq!(next("0 8 2 * 1", t), at(2024, 2, 2, 8, 0));
+    }
+
+    #[test]
+    fn cron_utc_offset() {
+        // 02:00 at UTC+05:30 is 20:30 UTC the day before
+        let cron = CronExpr::parse("0 2 * * *", 330).unwrap();
+        let t = at(2024, 1, 31, 12, 0);
+        assert_eq!(cron.next_after(t), Some(at(2024, 1, 31, 20, 30)));
+        // the first of the month at UTC-01:00
+        let cron = CronExpr::parse("0 0 1 * *", -60).unwrap();
+        assert_eq!(cron.next_after(t), Some(at(2024, 2, 1, 1, 0)));
+    }
+}
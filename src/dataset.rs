@@ -0,0 +1,322 @@
+use ::actix::prelude::*;
+use rlua::{Context as LuaContext, Error as LuaError, Table, Value};
+
+use crate::actor::LuaActor;
+use crate::codec::{StateCodec, StateError, StateSubsystem};
+use crate::message::{vm_limits, LuaMessage};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// A read-only table shared by many actors, see `LuaActorBuilder::with_dataset`.
+///
+/// Cloning a dataset doesn't copy its entries. Scripts read it with
+/// `ctx.data.<name>.get(key)` and `ctx.data.<name>.iter_prefix(prefix)`, which convert only
+/// the entries they return.
+#[derive(Clone, Debug)]
+pub struct SharedDataset {
+    entries: Arc<BTreeMap<String, LuaMessage>>,
+}
+
+impl SharedDataset {
+    /// Create a dataset with the entries of a `LuaMessage::Table`.
+    pub fn from_message(msg: LuaMessage) -> Result<SharedDataset, LuaError> {
+        match msg {
+            LuaMessage::Table(entries) => Ok(SharedDataset {
+                entries: Arc::new(entries.into_iter().collect()),
+            }),
+            _ => Err(LuaError::RuntimeError(
+                "a dataset must be a table".to_string(),
+            )),
+        }
+    }
+
+    /// Create a dataset from a table encoded with `codec`, e.g. `JsonCodec`.
+    pub fn decode(codec: &dyn StateCodec, bytes: &[u8]) -> Result<SharedDataset, StateError> {
+        let msg = codec
+            .decode(bytes)
+            .map_err(StateError::codec(StateSubsystem::Dataset))?;
+        Ok(SharedDataset::from_message(msg)?)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&LuaMessage> {
+        self.entries.get(key)
+    }
+}
+
+// The datasets of an actor by name, shared with the functions of `ctx.data` which look them
+// up on each call.
+#[derive(Clone, Default)]
+pub(crate) struct Datasets(Arc<RwLock<HashMap<String, SharedDataset>>>);
+
+impl Datasets {
+    pub fn insert(&self, name: &str, dataset: SharedDataset) -> Option<SharedDataset> {
+        self.0.write().unwrap().insert(name.to_string(), dataset)
+    }
+
+    // Set up `ctx.data` in a new VM, e.g. on restart.
+    pub fn install_all(&self, ctx: LuaContext) -> Result<(), LuaError> {
+        let names: Vec<String> = self.0.read().unwrap().keys().cloned().collect();
+        for name in names {
+            self.install(ctx, &name)?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Result<SharedDataset, LuaError> {
+        self.0
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| LuaError::RuntimeError(format!("no dataset named {}", name)))
+    }
+
+    // Set `ctx.data[name]` to the functions reading the dataset `name`.
+    pub fn install(&self, ctx: LuaContext, name: &str) -> Result<(), LuaError> {
+        let lua_ctx: Table = ctx.globals().get("ctx")?;
+        let data: Table = lua_ctx.get("data")?;
+        let functions = ctx.create_table()?;
+
+        let (datasets, dataset) = (self.clone(), name.to_string());
+        functions.set(
+            "get",
+            ctx.create_function(
+                move |ctx, key: String| match datasets.get(&dataset)?.get(&key) {
+                    Some(value) => value.to_lua_value(ctx, Some(&vm_limits(ctx))),
+                    None => Ok(Value::Nil),
+                },
+            )?,
+        )?;
+
+        let (datasets, dataset) = (self.clone(), name.to_string());
+        functions.set(
+            "iter_prefix",
+            ctx.create_function(move |ctx, prefix: String| {
+                // later replacements of the dataset don't affect the iteration
+                let entries = datasets.get(&dataset)?.entries;
+                let last: Mutex<Option<String>> = Mutex::new(None);
+                ctx.create_function(move |ctx, ()| {
+                    let mut last = last.lock().unwrap();
+                    let start = match *last {
+                        Some(ref key) => Bound::Excluded(key.clone()),
+                        None => Bound::Included(prefix.clone()),
+                    };
+                    let next = entries
+                        .range((start, Bound::Unbounded))
+                        .next()
+                        .filter(|(key, _)| key.starts_with(&prefix));
+                    match next {
+                        Some((key, value)) => {
+                            *last = Some(key.clone());
+                            let value = value.to_lua_value(ctx, Some(&vm_limits(ctx)))?;
+                            Ok((Value::String(ctx.create_string(key)?), value))
+                        }
+                        None => Ok((Value::Nil, Value::Nil)),
+                    }
+                })
+            })?,
+        )?;
+
+        data.set(name, functions)
+    }
+}
+
+/// Add or replace a dataset of a running `LuaActor`, see `LuaActorBuilder::with_dataset`.
+///
+/// The next lookups of the scripts read the new dataset. Returns the previous dataset with
+/// the same name.
+pub struct SetDataset {
+    pub name: String,
+    pub dataset: SharedDataset,
+}
+
+impl Message for SetDataset {
+    type Result = Result<Option<SharedDataset>, LuaError>;
+}
+
+impl Handler<SetDataset> for LuaActor {
+    type Result = Result<Option<SharedDataset>, LuaError>;
+
+    fn handle(&mut self, msg: SetDataset, _: &mut Context<Self>) -> Self::Result {
+        self.set_dataset(&msg.name, msg.dataset)
+    }
+}
+
+impl LuaActor {
+    pub(crate) fn set_dataset(
+        &mut self,
+        name: &str,
+        dataset: SharedDataset,
+    ) -> Result<Option<SharedDataset>, LuaError> {
+        let previous = self.datasets.insert(name, dataset);
+        if previous.is_none() {
+            let datasets = &self.datasets;
+            self.runtime.vm.context(|ctx| datasets.install(ctx, name))?;
+        }
+        Ok(previous)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LuaActorBuilder;
+    use crate::lua_msg;
+    use crate::testing::ScriptVm;
+    use futures::Future;
+
+    fn routes() -> SharedDataset {
+        let entries: HashMap<String, LuaMessage> = (0..10_000)
+            .map(|i| {
+                (
+                    format!("eu/{:05}", i),
+                    lua_msg!({ host: "10.0.0.1", port: 8080 }),
+                )
+            })
+            .chain(vec![
+                ("us/a".to_string(), lua_msg!({ host: "10.1.0.1" })),
+                ("us/b".to_string(), lua_msg!({ host: "10.1.0.2" })),
+                ("v".to_string(), LuaMessage::from(1)),
+            ])
+            .collect();
+        SharedDataset::from_message(LuaMessage::from(entries)).unwrap()
+    }
+
+    #[test]
+    fn dataset_lookups() {
+        let builder = LuaActorBuilder::new()
+            .with_dataset("routes", routes())
+            .on_handle_with_lua(
+                r#"
+                local found = {}
+                for key, route in ctx.data.routes.iter_prefix(ctx.msg) do
+                    found[#found + 1] = key .. "=" .. route.host
+                end
+                return { get = ctx.data.routes.get("us/b"), missing = ctx.data.routes.get("x"),
+                    found = table.concat(found, ",") }
+                "#,
+            );
+        let mut vm = ScriptVm::new(&builder).unwrap();
+        let res = vm.handle(LuaMessage::from("us/")).unwrap();
+        assert_eq!(
+            res,
+            lua_msg!({ get: { host: "10.1.0.2" }, found: "us/a=10.1.0.1,us/b=10.1.0.2" })
+        );
+        let res = vm.handle(LuaMessage::from("nope/")).unwrap();
+        assert_eq!(res.get("found"), Some(&LuaMessage::from("")));
+
+        assert!(SharedDataset::from_message(LuaMessage::from(1)).is_err());
+    }
+
+    #[test]
+    fn dataset_decode_error() {
+        struct Corrupt;
+
+        impl StateCodec for Corrupt {
+            fn encode(&self, _: &LuaMessage) -> Result<Vec<u8>, LuaError> {
+                Ok(Vec::new())
+            }
+
+            fn decode(&self, _: &[u8]) -> Result<LuaMessage, LuaError> {
+                Err(LuaError::RuntimeError("corrupt".to_string()))
+            }
+        }
+
+        match SharedDataset::decode(&Corrupt, b"") {
+            Err(e @ StateError::Codec { .. }) => {
+                assert_eq!(
+                    e.to_string(),
+                    "dataset: state codec failed: runtime error: corrupt"
+                );
+                assert!(matches!(
+                    e,
+                    StateError::Codec {
+                        subsystem: StateSubsystem::Dataset,
+                        ..
+                    }
+                ));
+                // still a Lua error for `?`
+                assert!(matches!(LuaError::from(e), LuaError::RuntimeError(_)));
+            }
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("a corrupt dataset should fail"),
+        }
+    }
+
+    #[test]
+    fn datasets_are_shared() {
+        let routes = routes();
+        let memory = |builder: LuaActorBuilder| builder.build().unwrap().runtime.used_memory();
+        let without = memory(LuaActorBuilder::new());
+        for _ in 0..3 {
+            let with = memory(LuaActorBuilder::new().with_dataset("routes", routes.clone()));
+            // 10000 entries would take more than 1MB in the VM
+            assert!(with < without + 16 * 1024, "{} vs {}", with, without);
+        }
+        // the actors were dropped
+        assert_eq!(Arc::strong_count(&routes.entries), 1);
+    }
+
+    #[test]
+    fn set_dataset() {
+        let system = System::new("test");
+
+        let routes = routes();
+        let start = || {
+            LuaActorBuilder::new()
+                .with_dataset("routes", routes.clone())
+                .on_handle_with_lua(
+                    r#"
+                    local iter = ctx.data.routes.iter_prefix("v")
+                    return { ctx.data.routes.get("v"), ctx.data.other and ctx.data.other.get("k") }
+                    "#,
+                )
+                .build()
+                .unwrap()
+                .start()
+        };
+        let (a, b) = (start(), start());
+        let v2 = SharedDataset::from_message(lua_msg!({ v: 2 })).unwrap();
+        let other = SharedDataset::from_message(lua_msg!({ k: "v" })).unwrap();
+
+        let l = a
+            .send(LuaMessage::Nil)
+            .and_then({
+                let a = a.clone();
+                move |before| {
+                    assert_eq!(before, lua_msg!([1]));
+                    a.send(SetDataset {
+                        name: "routes".to_string(),
+                        dataset: v2,
+                    })
+                    .join(a.send(SetDataset {
+                        name: "other".to_string(),
+                        dataset: other,
+                    }))
+                }
+            })
+            .and_then(move |(previous, added)| {
+                assert_eq!(previous.unwrap().unwrap().len(), 10_003);
+                assert!(added.unwrap().is_none());
+                a.send(LuaMessage::Nil).join(b.send(LuaMessage::Nil))
+            })
+            .map(|(a, b)| {
+                assert_eq!(a, lua_msg!([2, "v"]));
+                // the other actors keep their dataset
+                assert_eq!(b, lua_msg!([1]));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+}
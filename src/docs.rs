@@ -0,0 +1,145 @@
+//! Lua annotations of the `ctx` API, for editors.
+//!
+//! [`emit_lua_stubs`] writes a [LuaLS] (EmmyLua style) definition file of the `ctx` functions
+//! available to the actors of a builder, e.g. from a build script:
+//!
+//! ```
+//! use actix_lua::LuaActorBuilder;
+//!
+//! let builder = LuaActorBuilder::new().with_rich_send_results(true);
+//! let mut stubs = Vec::new();
+//! actix_lua::docs::emit_lua_stubs(&builder, &mut stubs).unwrap();
+//! assert!(String::from_utf8(stubs).unwrap().contains("function ctx.send_ok(recipient, msg) end"));
+//! ```
+//!
+//! [`emit_lua_stubs`]: fn.emit_lua_stubs.html
+//! [LuaLS]: https://luals.github.io/wiki/annotations/
+use std::io::{self, Write};
+
+use crate::builder::LuaActorBuilder;
+use crate::runtime::{api_manifest, ApiEntry};
+
+// The fields of `ctx` which aren't functions: name, type and description.
+const CTX_FIELDS: &[(&str, &str, &str)] = &[
+    (
+        "state",
+        "table",
+        "state of the actor, kept between messages",
+    ),
+    ("msg", "any", "message being handled"),
+    (
+        "reply_expected",
+        "boolean",
+        "whether the sender waits for the result of the script",
+    ),
+    (
+        "scratch",
+        "table",
+        "table cleared after each invocation, must not be stored in `ctx.state`",
+    ),
+    ("null", "lightuserdata", "`nil` values of tables"),
+    (
+        "state_version",
+        "integer",
+        "version of `ctx.state` expected by the scripts, set by the `migrations` hook",
+    ),
+    (
+        "shared",
+        "table",
+        "values shared by the hooks, e.g. helpers with `with_isolated_hooks(true)`",
+    ),
+    (
+        "data",
+        "table<string, { get: fun(key: string): any, iter_prefix: fun(prefix: string): fun(): string, any }>",
+        "datasets shared with other actors, see `LuaActorBuilder::with_dataset`",
+    ),
+    (
+        "build",
+        "table<string, any>",
+        "read-only build info of the host application, see `LuaActorBuilder::with_build_info`",
+    ),
+    (
+        "runtime",
+        "{ dependencies: table<string, boolean>, gc: { collections: integer, reclaimed: integer }? }",
+        "status of the required recipients and of the GC watermark",
+    ),
+];
+
+/// Write the annotations of the `ctx` API available to the actors built by `builder`.
+///
+/// Only the functions enabled by the configuration of `builder` are listed, e.g.
+/// `ctx.send_ok` with `with_rich_send_results(true)`. Actors built with `build_with_vm`
+/// don't have `ctx.restart`, which is listed anyway.
+pub fn emit_lua_stubs<W: Write>(builder: &LuaActorBuilder, mut writer: W) -> io::Result<()> {
+    let entries = api_manifest(&builder.api_config());
+
+    writeln!(writer, "---@meta")?;
+    writeln!(
+        writer,
+        "-- generated by actix_lua::docs::emit_lua_stubs, do not edit"
+    )?;
+    writeln!(writer)?;
+    writeln!(writer, "---@class ctx")?;
+    for (name, ty, description) in CTX_FIELDS {
+        writeln!(writer, "---@field {} {} {}", name, ty, description)?;
+    }
+    writeln!(writer, "ctx = {{}}")?;
+
+    for entry in &entries {
+        writeln!(writer)?;
+        if entry.fields.is_empty() {
+            write_function(&mut writer, "ctx", entry)?;
+            continue;
+        }
+        let table = format!("ctx.{}", entry.name);
+        write_description(&mut writer, entry)?;
+        writeln!(writer, "---@class {}", table)?;
+        writeln!(writer, "{} = {{}}", table)?;
+        for field in entry.fields {
+            writeln!(writer)?;
+            write_function(&mut writer, &table, field)?;
+        }
+    }
+
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "--- declare a global variable, required to assign globals with strict globals"
+    )?;
+    writeln!(writer, "---@param name string")?;
+    writeln!(writer, "---@param value any")?;
+    writeln!(writer, "function declare_global(name, value) end")
+}
+
+fn write_description<W: Write>(writer: &mut W, entry: &ApiEntry) -> io::Result<()> {
+    writeln!(writer, "--- {}", entry.description)?;
+    if !entry.availability.is_empty() {
+        writeln!(writer, "---")?;
+        writeln!(writer, "--- Available to {}.", entry.availability)?;
+    }
+    Ok(())
+}
+
+fn write_function<W: Write>(writer: &mut W, table: &str, entry: &ApiEntry) -> io::Result<()> {
+    write_description(writer, entry)?;
+    for param in entry.params {
+        writeln!(
+            writer,
+            "---@param {}{} {}",
+            param.name,
+            if param.optional { "?" } else { "" },
+            param.ty
+        )?;
+    }
+    for ret in entry.returns {
+        writeln!(writer, "---@return {} {}", ret.ty, ret.name)?;
+    }
+    let params: Vec<_> = entry.params.iter().map(|p| p.name).collect();
+    writeln!(
+        writer,
+        "function {}.{}({}) end",
+        table,
+        entry.name,
+        params.join(", ")
+    )
+}
@@ -0,0 +1,143 @@
+use crate::actor::DEFAULT_NAME;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Identical errors of a hook within the coalescing window, see
+/// `LuaActorBuilder::with_error_coalescing`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorBurst {
+    /// name of the actor, see `LuaActorBuilder::with_name`
+    pub actor: String,
+    /// the hook which failed, e.g. `handle`
+    pub hook: String,
+    /// the error, including the chunk and line where it was raised
+    pub error: String,
+    /// number of times the error occurred within the window
+    pub count: u64,
+    pub window: Duration,
+}
+
+/// Callback invoked for each burst of identical errors, when its window ends.
+pub type ErrorBurstCallback = Arc<dyn Fn(&ErrorBurst) + Send + Sync>;
+
+pub(crate) const DEFAULT_ERROR_WINDOW: Duration = Duration::from_secs(10);
+
+// The errors of an actor, counted by hook and error until their window ends.
+#[derive(Clone)]
+pub(crate) struct ErrorBursts {
+    pub window: Duration,
+    pub callback: Option<ErrorBurstCallback>,
+    // see `LuaActorBuilder::with_log_prefix`
+    pub log_prefix: String,
+    // when the window of each error started and the number of occurrences
+    open: HashMap<(String, String), (Instant, u64)>,
+}
+
+impl Default for ErrorBursts {
+    fn default() -> ErrorBursts {
+        ErrorBursts {
+            window: DEFAULT_ERROR_WINDOW,
+            callback: None,
+            log_prefix: format!("LuaActor {}", DEFAULT_NAME),
+            open: HashMap::new(),
+        }
+    }
+}
+
+impl ErrorBursts {
+    // Count an error, logging it if it opens a window. Returns whether it did.
+    pub fn record(&mut self, hook: &str, error: &str, now: Instant) -> bool {
+        let key = (hook.to_string(), error.to_string());
+        if let Some((_, count)) = self.open.get_mut(&key) {
+            *count += 1;
+            return false;
+        }
+        self.open.insert(key, (now, 1));
+        log::error!("{} `{}` hook failed: {}", self.log_prefix, hook, error);
+        true
+    }
+
+    // Close the windows which ended at `now`, or all of them if `now` is `None`, logging a
+    // summary of the repeated errors and passing every burst to the callback.
+    pub fn flush(&mut self, actor: &str, now: Option<Instant>) {
+        let window = self.window;
+        let mut ended = vec![];
+        self.open.retain(|(hook, error), (start, count)| {
+            if let Some(now) = now {
+                if now.duration_since(*start) < window {
+                    return true;
+                }
+            }
+            ended.push(ErrorBurst {
+                actor: actor.to_string(),
+                hook: hook.clone(),
+                error: error.clone(),
+                count: *count,
+                window,
+            });
+            false
+        });
+        ended.sort_by(|a, b| (&a.hook, &a.error).cmp(&(&b.hook, &b.error)));
+
+        for burst in ended {
+            if burst.count > 1 {
+                log::error!(
+                    "{} `{}` error occurred {} times in the last {:?}: {}",
+                    self.log_prefix,
+                    burst.hook,
+                    burst.count,
+                    window,
+                    burst.error
+                );
+            }
+            if let Some(ref callback) = self.callback {
+                callback(&burst);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn error_bursts_per_window() {
+        let captured = Arc::new(Mutex::new(vec![]));
+        let captured2 = captured.clone();
+        let mut bursts = ErrorBursts {
+            window: Duration::from_secs(10),
+            callback: Some(Arc::new(move |b: &ErrorBurst| {
+                captured2.lock().unwrap().push((b.error.clone(), b.count))
+            })),
+            ..ErrorBursts::default()
+        };
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(bursts.record("handle", "a", at(0)));
+        assert!(!bursts.record("handle", "a", at(1)));
+        assert!(bursts.record("handle", "b", at(5)));
+        assert!(!bursts.record("handle", "a", at(9)));
+
+        bursts.flush("actor", Some(at(9)));
+        assert!(captured.lock().unwrap().is_empty());
+        bursts.flush("actor", Some(at(10)));
+        // a new window starts
+        assert!(bursts.record("handle", "a", at(11)));
+        bursts.flush("actor", Some(at(15)));
+        // the actor stops
+        bursts.flush("actor", None);
+
+        assert_eq!(
+            *captured.lock().unwrap(),
+            vec![
+                ("a".to_string(), 3),
+                ("b".to_string(), 1),
+                ("a".to_string(), 1)
+            ]
+        );
+    }
+}
@@ -0,0 +1,372 @@
+use rlua::{Context, Error as LuaError, Result as LuaResult, Value};
+
+use crate::message::LuaMessage;
+use crate::query::parse_path;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+// The version of the blobs of `ctx.export_state`, checked by `ctx.import_state`.
+const BLOB_VERSION: u8 = 1;
+const BLOB_MAGIC: &[u8] = b"ALS";
+// Deeper values can't be exported, like in `LuaMessage::to_lua_literal`.
+const MAX_DEPTH: usize = 128;
+
+pub(crate) const DEFAULT_MAX_EXPORT_SIZE: usize = 1 << 20;
+
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const INTEGER: u8 = 3;
+const NUMBER: u8 = 4;
+const STRING: u8 = 5;
+const ARRAY: u8 = 6;
+const TABLE: u8 = 7;
+const ERROR: u8 = 8;
+
+// Encode `value` and the version of the state it comes from as the text of a blob: a header,
+// then the values tagged with their type, lengths and numbers in little endian, in base64 so
+// the blob can be sent in a `LuaMessage`.
+pub(crate) fn encode_blob(value: &LuaMessage, state_version: Option<i64>) -> LuaResult<String> {
+    let mut out = BLOB_MAGIC.to_vec();
+    out.push(BLOB_VERSION);
+    match state_version {
+        Some(version) => {
+            out.push(1);
+            out.extend_from_slice(&version.to_le_bytes());
+        }
+        None => out.push(0),
+    }
+    write_value(&mut out, value, 0)?;
+    Ok(base64_encode(&out))
+}
+
+// The value and the state version of a blob created by `encode_blob`.
+pub(crate) fn decode_blob(blob: &str) -> LuaResult<(LuaMessage, Option<i64>)> {
+    let bytes = base64_decode(blob).ok_or_else(|| invalid("not base64"))?;
+    let mut reader = Reader {
+        bytes: &bytes,
+        pos: 0,
+    };
+    if reader.take(BLOB_MAGIC.len())? != BLOB_MAGIC {
+        return Err(invalid("not a state export"));
+    }
+    let version = reader.byte()?;
+    if version != BLOB_VERSION {
+        return Err(LuaError::RuntimeError(format!(
+            "unsupported state export version {}, expected {}",
+            version, BLOB_VERSION
+        )));
+    }
+    let state_version = match reader.byte()? {
+        0 => None,
+        _ => Some(reader.integer()?),
+    };
+    let value = reader.value(0)?;
+    if reader.pos != bytes.len() {
+        return Err(invalid("trailing bytes"));
+    }
+    Ok((value, state_version))
+}
+
+fn invalid(reason: &str) -> LuaError {
+    LuaError::RuntimeError(format!("invalid state export: {}", reason))
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize) -> LuaResult<()> {
+    let len = u32::try_from(len)
+        .map_err(|_| LuaError::RuntimeError("can't export more than 2^32 items".to_string()))?;
+    out.extend_from_slice(&len.to_le_bytes());
+    Ok(())
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) -> LuaResult<()> {
+    write_len(out, s.len())?;
+    out.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+fn write_value(out: &mut Vec<u8>, value: &LuaMessage, depth: usize) -> LuaResult<()> {
+    if depth >= MAX_DEPTH {
+        return Err(LuaError::RuntimeError(format!(
+            "can't export values nested deeper than {}",
+            MAX_DEPTH
+        )));
+    }
+    match value {
+        LuaMessage::Nil => out.push(NIL),
+        LuaMessage::Boolean(false) => out.push(FALSE),
+        LuaMessage::Boolean(true) => out.push(TRUE),
+        LuaMessage::Integer(n) => {
+            out.push(INTEGER);
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+        LuaMessage::Number(n) => {
+            out.push(NUMBER);
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+        LuaMessage::String(s) => {
+            out.push(STRING);
+            write_str(out, s)?;
+        }
+        LuaMessage::Array(a) => {
+            out.push(ARRAY);
+            write_len(out, a.len())?;
+            for v in a {
+                write_value(out, v, depth + 1)?;
+            }
+        }
+        LuaMessage::Table(t) => {
+            out.push(TABLE);
+            write_len(out, t.len())?;
+            for (k, v) in t {
+                write_str(out, k)?;
+                write_value(out, v, depth + 1)?;
+            }
+        }
+        LuaMessage::Error { message, traceback } => {
+            out.push(ERROR);
+            write_str(out, message)?;
+            match traceback {
+                Some(traceback) => {
+                    out.push(1);
+                    write_str(out, traceback)?;
+                }
+                None => out.push(0),
+            }
+        }
+        LuaMessage::Opaque(_) => {
+            return Err(LuaError::RuntimeError(
+                "can't export an opaque handle".to_string(),
+            ))
+        }
+        #[cfg(feature = "legacy-thread-yield")]
+        #[allow(deprecated)]
+        LuaMessage::ThreadYield(_) => {
+            return Err(LuaError::RuntimeError(
+                "can't export a ThreadYield".to_string(),
+            ))
+        }
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> LuaResult<&'a [u8]> {
+        if self.bytes.len() - self.pos < n {
+            return Err(invalid("truncated"));
+        }
+        self.pos += n;
+        Ok(&self.bytes[self.pos - n..self.pos])
+    }
+
+    fn byte(&mut self) -> LuaResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn eight(&mut self) -> LuaResult<[u8; 8]> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(buf)
+    }
+
+    fn integer(&mut self) -> LuaResult<i64> {
+        Ok(i64::from_le_bytes(self.eight()?))
+    }
+
+    fn len(&mut self) -> LuaResult<usize> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        let len = u32::from_le_bytes(buf) as usize;
+        // every item takes at least a byte, don't allocate for a corrupt length
+        if len > self.bytes.len() - self.pos {
+            return Err(invalid("truncated"));
+        }
+        Ok(len)
+    }
+
+    fn string(&mut self) -> LuaResult<String> {
+        let len = self.len()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| invalid("string isn't UTF-8"))
+    }
+
+    fn value(&mut self, depth: usize) -> LuaResult<LuaMessage> {
+        if depth >= MAX_DEPTH {
+            return Err(invalid("nested too deep"));
+        }
+        Ok(match self.byte()? {
+            NIL => LuaMessage::Nil,
+            FALSE => LuaMessage::Boolean(false),
+            TRUE => LuaMessage::Boolean(true),
+            INTEGER => LuaMessage::Integer(self.integer()?),
+            NUMBER => LuaMessage::Number(f64::from_le_bytes(self.eight()?)),
+            STRING => LuaMessage::String(self.string()?),
+            ARRAY => {
+                let len = self.len()?;
+                let mut a = Vec::with_capacity(len);
+                for _ in 0..len {
+                    a.push(self.value(depth + 1)?);
+                }
+                LuaMessage::Array(a)
+            }
+            TABLE => {
+                let len = self.len()?;
+                let mut t = HashMap::with_capacity(len);
+                for _ in 0..len {
+                    let k = self.string()?;
+                    t.insert(k, self.value(depth + 1)?);
+                }
+                LuaMessage::Table(t)
+            }
+            ERROR => {
+                let message = self.string()?;
+                let traceback = match self.byte()? {
+                    0 => None,
+                    _ => Some(self.string()?),
+                };
+                LuaMessage::Error { message, traceback }
+            }
+            tag => return Err(invalid(&format!("unknown tag {}", tag))),
+        })
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    for (i, chunk) in s.chunks(4).enumerate() {
+        let last = i == s.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut n = 0u32;
+        for (j, &c) in chunk[..4 - padding].iter().enumerate() {
+            let v = BASE64.iter().position(|&b| b == c)? as u32;
+            n |= v << (18 - 6 * j);
+        }
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(out)
+}
+
+// Set the functions used by `ctx.export_state` and `ctx.import_state`.
+pub(crate) fn install(ctx: Context) -> LuaResult<()> {
+    let encode =
+        ctx.create_function(|ctx, (value, state_version): (LuaMessage, Option<i64>)| {
+            let blob = encode_blob(&value, state_version)?;
+            let max: usize = ctx.globals().raw_get("__max_export_size")?;
+            if blob.len() > max {
+                return Err(LuaError::RuntimeError(format!(
+                    "the export is {} bytes, above the limit of {} bytes",
+                    blob.len(),
+                    max
+                )));
+            }
+            Ok(blob)
+        })?;
+    ctx.globals().raw_set("__encode_state", encode)?;
+
+    let decode = ctx.create_function(|ctx, blob: String| {
+        let max: usize = ctx.globals().raw_get("__max_export_size")?;
+        if blob.len() > max {
+            return Err(LuaError::RuntimeError(format!(
+                "the export is {} bytes, above the limit of {} bytes",
+                blob.len(),
+                max
+            )));
+        }
+        decode_blob(&blob)
+    })?;
+    ctx.globals().raw_set("__decode_state", decode)?;
+
+    // the keys of a path of `ctx.state`, those made of digits being array indexes
+    let state_path = ctx.create_function(|ctx, path: String| {
+        let keys = parse_path(&path).map_err(|e| {
+            LuaError::RuntimeError(format!("invalid path of ctx.state {}: {}", path, e))
+        })?;
+        let keys = keys
+            .into_iter()
+            .map(|key| match key.parse::<i64>() {
+                Ok(index) if key.bytes().all(|b| b.is_ascii_digit()) => Ok(Value::Integer(index)),
+                _ => ctx.create_string(&key).map(Value::String),
+            })
+            .collect::<LuaResult<Vec<_>>>()?;
+        ctx.create_sequence_from(keys)
+    })?;
+    ctx.globals().raw_set("__state_path", state_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lua_msg;
+
+    #[test]
+    fn state_blob_round_trip() {
+        let value = lua_msg!({
+            s: "héllo",
+            i: -42,
+            n: 0.5,
+            b: true,
+            none: false,
+            list: [1, "two", [3]],
+            nested: { deep: { deeper: i64::MIN } },
+        });
+        let error = LuaMessage::Error {
+            message: "boom".to_string(),
+            traceback: Some("stack".to_string()),
+        };
+        for (value, version) in [
+            (value, Some(3)),
+            (error, None),
+            (LuaMessage::Nil, None),
+            (lua_msg!({}), Some(-1)),
+        ] {
+            let blob = encode_blob(&value, version).unwrap();
+            assert_eq!(decode_blob(&blob).unwrap(), (value, version));
+        }
+        for n in 0..6 {
+            let bytes: Vec<u8> = (0..n).map(|i| i * 51).collect();
+            assert_eq!(base64_decode(&base64_encode(&bytes)), Some(bytes));
+        }
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+
+        let blob = encode_blob(&LuaMessage::from(1), None).unwrap();
+        let mut bytes = base64_decode(&blob).unwrap();
+        bytes[3] = 9;
+        let err = decode_blob(&base64_encode(&bytes)).unwrap_err();
+        assert!(err.to_string().contains("version 9"), "{}", err);
+        bytes[3] = BLOB_VERSION;
+        bytes.pop();
+        assert!(decode_blob(&base64_encode(&bytes)).is_err());
+        assert!(decode_blob("not a blob").is_err());
+    }
+}
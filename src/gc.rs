@@ -0,0 +1,141 @@
+use ::actix::prelude::*;
+
+use crate::actor::LuaActor;
+use crate::message::LuaMessage;
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Full collections leaving the memory above the watermark before it's reported as a leak.
+pub(crate) const DEFAULT_GC_ATTEMPTS: u32 = 3;
+
+// Configured by `LuaActorBuilder::with_gc_watermark`.
+#[derive(Clone)]
+pub(crate) struct GcWatermark {
+    // fraction of the memory limit
+    pub fraction: f64,
+    pub max_attempts: u32,
+    scheduled: bool,
+    // consecutive collections which left the memory above the watermark
+    ineffective: u32,
+    collections: u64,
+    reclaimed: u64,
+}
+
+impl GcWatermark {
+    pub fn new(fraction: f64) -> GcWatermark {
+        GcWatermark {
+            fraction,
+            max_attempts: DEFAULT_GC_ATTEMPTS,
+            scheduled: false,
+            ineffective: 0,
+            collections: 0,
+            reclaimed: 0,
+        }
+    }
+
+    fn threshold(&self, limit: usize) -> usize {
+        (limit as f64 * self.fraction) as usize
+    }
+}
+
+/// Read the memory used by the Lua VM of a `LuaActor`, see `LuaActorBuilder::with_memory_limit`.
+///
+/// The reply is a table:
+///
+/// ```lua
+/// {
+///     used = 123456,    -- bytes allocated by the VM
+///     limit = 1048576,  -- nil without `with_memory_limit`
+/// }
+/// ```
+pub struct GetLuaMemory;
+
+impl Message for GetLuaMemory {
+    type Result = LuaMessage;
+}
+
+impl Handler<GetLuaMemory> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, _: GetLuaMemory, _: &mut Context<Self>) -> LuaMessage {
+        let mut memory = HashMap::new();
+        memory.insert(
+            "used".to_string(),
+            LuaMessage::from(self.runtime.used_memory()),
+        );
+        if let Some(limit) = self.runtime.memory_limit {
+            memory.insert("limit".to_string(), LuaMessage::from(limit));
+        }
+        LuaMessage::from(memory)
+    }
+}
+
+impl LuaActor {
+    // Schedule a full collection if the VM uses more memory than the watermark. It runs on
+    // the next turn of the event loop, once the current invocation replied.
+    pub(crate) fn check_gc_watermark(&mut self, ctx: &mut Context<Self>) {
+        let (gc, limit) = match (self.gc_watermark.as_mut(), self.runtime.memory_limit) {
+            (Some(gc), Some(limit)) => (gc, limit),
+            _ => return,
+        };
+        if gc.scheduled || self.runtime.used_memory() <= gc.threshold(limit) {
+            return;
+        }
+        gc.scheduled = true;
+        ctx.run_later(Duration::from_secs(0), |act, ctx| act.collect_garbage(ctx));
+    }
+
+    fn collect_garbage(&mut self, ctx: &mut Context<Self>) {
+        let (gc, limit) = match (self.gc_watermark.as_mut(), self.runtime.memory_limit) {
+            (Some(gc), Some(limit)) => (gc, limit),
+            _ => return,
+        };
+        gc.scheduled = false;
+        let reclaimed = match self.runtime.collect_garbage() {
+            Ok(reclaimed) => reclaimed,
+            Err(e) => {
+                log::error!("{} failed to collect garbage: {}", self.log_prefix, e);
+                return;
+            }
+        };
+        gc.collections += 1;
+        gc.reclaimed += reclaimed as u64;
+        let used = self.runtime.used_memory();
+        log::debug!(
+            "{} collected garbage above the watermark: {} bytes reclaimed, {} bytes used",
+            self.log_prefix,
+            reclaimed,
+            used
+        );
+        if let Err(e) = self.runtime.set_gc_stats(gc.collections, gc.reclaimed) {
+            log::error!("{} failed to update ctx.runtime.gc: {}", self.log_prefix, e);
+        }
+
+        if used <= gc.threshold(limit) {
+            gc.ineffective = 0;
+            return;
+        }
+        gc.ineffective += 1;
+        if gc.ineffective < gc.max_attempts {
+            return;
+        }
+        let attempts = gc.ineffective;
+        gc.ineffective = 0;
+        let mut detail = HashMap::new();
+        detail.insert("used".to_string(), LuaMessage::from(used as i64));
+        detail.insert("limit".to_string(), LuaMessage::from(limit as i64));
+        detail.insert(
+            "attempts".to_string(),
+            LuaMessage::from(i64::from(attempts)),
+        );
+        self.report_internal_error(
+            ctx,
+            "gc",
+            format!(
+                "still uses {} of {} bytes after {} full garbage collections, is it leaking memory?",
+                used, limit, attempts
+            ),
+            detail,
+        );
+    }
+}
@@ -0,0 +1,448 @@
+use ::actix::prelude::*;
+use rlua::Error as LuaError;
+
+use crate::actor::{deliver, send_error, LuaActor, SendAttempt};
+use crate::builder::LuaActorBuilder;
+use crate::message::LuaMessage;
+use crate::recipients::Recipients;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// Defaults of the options of `ctx.replace_actor`.
+pub(crate) const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+pub(crate) const DEFAULT_MAX_QUEUED: usize = 1024;
+
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+// A child added with `LuaActorBuilder::with_child`.
+struct Child {
+    // the configuration inherited by the replacements
+    builder: LuaActorBuilder,
+    // built with the parent, started with it
+    unstarted: Option<LuaActor>,
+    addr: Option<Addr<LuaActor>>,
+}
+
+// A message for a child being replaced, delivered to the replacement after the switch.
+pub(crate) enum Held {
+    DoSend(String, LuaMessage),
+    Send(SendAttempt),
+}
+
+struct Handoff {
+    replacement: Addr<LuaActor>,
+    held: VecDeque<Held>,
+    max_queued: usize,
+    deadline: Instant,
+    // replies of the old child received since the handoff started
+    drained: usize,
+    cb_thread_id: i64,
+    vm_generation: u64,
+}
+
+// The children of an actor by name and their replacements in progress.
+#[derive(Default)]
+pub(crate) struct Children {
+    children: HashMap<String, Child>,
+    handoffs: HashMap<String, Handoff>,
+}
+
+impl Children {
+    // Build the child now so that its scripts are checked with the parent's.
+    pub fn add(&mut self, name: String, builder: LuaActorBuilder) -> Result<(), LuaError> {
+        let actor = builder.clone().build()?;
+        self.children.insert(
+            name,
+            Child {
+                builder,
+                unstarted: Some(actor),
+                addr: None,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn start(&mut self, recipients: &mut Recipients) {
+        for (name, child) in &mut self.children {
+            if let Some(actor) = child.unstarted.take() {
+                let addr = actor.start();
+                recipients.set_lua(name.clone(), &addr);
+                child.addr = Some(addr);
+            }
+        }
+    }
+
+    // Start the replacement of the child `name` with the `handle` script `script_path`. The
+    // messages for the child are held until the switch.
+    pub fn replace(
+        &mut self,
+        name: &str,
+        script_path: &str,
+        drain_timeout: Duration,
+        max_queued: usize,
+        cb_thread_id: i64,
+        vm_generation: u64,
+    ) -> Result<(), LuaError> {
+        let child = self.children.get(name).ok_or_else(|| {
+            LuaError::RuntimeError(format!(
+                "ctx.replace_actor: {} is not a child of the actor, see with_child",
+                name
+            ))
+        })?;
+        if self.handoffs.contains_key(name) {
+            return Err(LuaError::RuntimeError(format!(
+                "ctx.replace_actor: {} is already being replaced",
+                name
+            )));
+        }
+        let replacement = child
+            .builder
+            .clone()
+            .on_handle(script_path)
+            .build()?
+            .start();
+        self.handoffs.insert(
+            name.to_string(),
+            Handoff {
+                replacement,
+                held: VecDeque::new(),
+                max_queued,
+                deadline: Instant::now() + drain_timeout,
+                drained: 0,
+                cb_thread_id,
+                vm_generation,
+            },
+        );
+        Ok(())
+    }
+
+    // Whether each child is running and being replaced.
+    pub fn describe(&self) -> HashMap<String, LuaMessage> {
+        self.children
+            .iter()
+            .map(|(name, child)| {
+                let mut state = HashMap::new();
+                state.insert(
+                    "running".to_string(),
+                    LuaMessage::from(child.addr.as_ref().is_some_and(|a| a.connected())),
+                );
+                state.insert(
+                    "replacing".to_string(),
+                    LuaMessage::from(self.handoffs.contains_key(name)),
+                );
+                (name.clone(), LuaMessage::from(state))
+            })
+            .collect()
+    }
+
+    pub fn replacing(&self, name: &str) -> bool {
+        self.handoffs.contains_key(name)
+    }
+
+    // Hold `held` until the child `name` is switched, it is given back if the queue is full.
+    pub fn hold(&mut self, name: &str, held: Held) -> Result<(), Held> {
+        match self.handoffs.get_mut(name) {
+            Some(handoff) if handoff.held.len() < handoff.max_queued => {
+                handoff.held.push_back(held);
+                Ok(())
+            }
+            _ => Err(held),
+        }
+    }
+
+    // The old child `name` replied to a `ctx.send`.
+    pub fn drained(&mut self, name: &str) {
+        if let Some(handoff) = self.handoffs.get_mut(name) {
+            handoff.drained += 1;
+        }
+    }
+}
+
+// Stops a child once it was replaced.
+struct StopChild;
+
+impl Message for StopChild {
+    type Result = ();
+}
+
+impl Handler<StopChild> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, _: StopChild, ctx: &mut Context<Self>) {
+        ctx.stop();
+    }
+}
+
+impl LuaActor {
+    // Wait until the old child replied to all the `ctx.send` of this actor, or the drain timeout.
+    pub(crate) fn check_handoff(&mut self, ctx: &mut Context<Self>, name: String) {
+        self.resume_sends(ctx);
+        let deadline = match self.children.handoffs.get(&name) {
+            Some(handoff) => handoff.deadline,
+            None => return,
+        };
+        let drained = !self.awaits_reply_from(&name);
+        if !drained && Instant::now() < deadline {
+            ctx.run_later(DRAIN_CHECK_INTERVAL, move |act, ctx| {
+                act.check_handoff(ctx, name)
+            });
+            return;
+        }
+        self.switch_child(ctx, &name, drained);
+    }
+
+    // Route `name` to the replacement, flush the held messages to it and stop the old child
+    // unless it still owes replies, which are still resumed if they arrive.
+    fn switch_child(&mut self, ctx: &mut Context<Self>, name: &str, drained: bool) {
+        let handoff = match self.children.handoffs.remove(name) {
+            Some(handoff) => handoff,
+            None => return,
+        };
+        let child = self.children.children.get_mut(name).unwrap();
+        let old = child.addr.replace(handoff.replacement.clone());
+        self.recipients
+            .set_lua(name.to_string(), &handoff.replacement);
+
+        let queued = handoff.held.len();
+        for held in handoff.held {
+            match held {
+                Held::DoSend(recipient_name, msg) => deliver(
+                    &self.recipients,
+                    &self.aliases,
+                    &mut self.outboxes,
+                    &self.log_prefix,
+                    &recipient_name,
+                    msg,
+                    ctx,
+                ),
+                Held::Send(attempt) => {
+                    Handler::<SendAttempt>::handle(self, attempt, ctx);
+                }
+            }
+        }
+
+        let old_stopped = drained && old.is_some();
+        if old_stopped {
+            if let Some(old) = old {
+                old.do_send(StopChild);
+            }
+        }
+
+        if handoff.vm_generation != self.vm_generation {
+            return;
+        }
+        let mut summary = HashMap::new();
+        summary.insert(
+            "drained".to_string(),
+            LuaMessage::from(handoff.drained as i64),
+        );
+        summary.insert("queued".to_string(), LuaMessage::from(queued as i64));
+        summary.insert("old_stopped".to_string(), LuaMessage::from(old_stopped));
+        self.resume(ctx, LuaMessage::from(summary), handoff.cb_thread_id);
+    }
+
+    // A `ctx.send` to a child being replaced didn't fit in the queue.
+    pub(crate) fn reject_held(&mut self, ctx: &mut Context<Self>, held: Held) {
+        self.warnings.warn_once(
+            &self.name,
+            "handoff_queue_full",
+            "messages for a child being replaced were rejected, its queue is full",
+            Instant::now(),
+        );
+        if let Held::Send(attempt) = held {
+            if attempt.vm_generation == self.vm_generation {
+                let msg = send_error("queue_full", None, self.rich_send_results);
+                self.resume_sends(ctx);
+                self.resume(ctx, msg, attempt.cb_thread_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor::MissingHandler;
+    use crate::lua_msg;
+    use actix::dev::{MessageResponse, ResponseChannel};
+    use futures::Future;
+    use futures_timer::Delay;
+
+    // Replies with the message after `delay`.
+    struct Sleeper {
+        delay: Duration,
+    }
+
+    impl Actor for Sleeper {
+        type Context = Context<Self>;
+    }
+
+    struct Later(LuaMessage, Duration);
+
+    impl MessageResponse<Sleeper, LuaMessage> for Later {
+        fn handle<R: ResponseChannel<LuaMessage>>(self, ctx: &mut Context<Sleeper>, tx: Option<R>) {
+            let Later(msg, delay) = self;
+            ctx.run_later(delay, move |_, _| {
+                if let Some(tx) = tx {
+                    tx.send(msg);
+                }
+            });
+        }
+    }
+
+    impl Handler<LuaMessage> for Sleeper {
+        type Result = Later;
+
+        fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> Later {
+            Later(msg, self.delay)
+        }
+    }
+
+    // The first child forwards the requests to a sleeper, its replacement runs handoff_v2.lua.
+    fn parent(sleeper_delay: Duration, drain_timeout: f64) -> Addr<LuaActor> {
+        let sleeper = Sleeper {
+            delay: sleeper_delay,
+        }
+        .start();
+        let child = LuaActorBuilder::new()
+            .with_recipient("sleeper", sleeper.recipient())
+            .with_missing_handler(MissingHandler::Forward("sleeper".to_string()));
+        LuaActorBuilder::new()
+            .with_child("worker", child)
+            .on_started_with_lua(&format!(
+                "ctx.state.results = {{}} drain_timeout = {}",
+                drain_timeout
+            ))
+            .on_handle_with_lua(
+                r#"
+                local results = ctx.state.results
+                if ctx.msg.op == "get" then
+                    return results
+                elseif ctx.msg.op == "replace" then
+                    ctx.alias("w", "worker")
+                    results.summary = ctx.replace_actor("w", "src/lua/test/handoff_v2.lua",
+                        { drain_timeout = drain_timeout })
+                elseif ctx.msg.op == "tell" then
+                    ctx.do_send("worker", ctx.msg.n)
+                elseif ctx.msg.op == "seen" then
+                    results.seen = ctx.send("worker", "seen")
+                else
+                    results["r" .. ctx.msg.n] = ctx.send("worker", ctx.msg.n)
+                end
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start()
+    }
+
+    fn delay(ms: u64) -> impl Future<Item = (), Error = MailboxError> {
+        Delay::new(Duration::from_millis(ms)).map_err(|e| panic!("{}", e))
+    }
+
+    #[test]
+    fn replace_actor() {
+        let system = System::new("test");
+
+        let addr = parent(Duration::from_millis(50), 1.0);
+        let send = {
+            let addr = addr.clone();
+            move |msg| addr.do_send(msg)
+        };
+        send(lua_msg!({ op: "req", n: 1 }));
+        let l = delay(10)
+            .and_then({
+                let send = send.clone();
+                move |_| {
+                    // held until the old child replied to the first request
+                    send(lua_msg!({ op: "replace" }));
+                    send(lua_msg!({ op: "req", n: 2 }));
+                    send(lua_msg!({ op: "tell", n: 3 }));
+                    send(lua_msg!({ op: "req", n: 4 }));
+                    delay(100)
+                }
+            })
+            .and_then(move |_| {
+                send(lua_msg!({ op: "req", n: 5 }));
+                send(lua_msg!({ op: "seen" }));
+                delay(20)
+            })
+            .and_then(move |_| addr.send(lua_msg!({ op: "get" })))
+            .map(|results| {
+                assert_eq!(
+                    results,
+                    lua_msg!({
+                        r1: 1,
+                        summary: { drained: 1, queued: 3, old_stopped: true },
+                        r2: "v2:2",
+                        r4: "v2:4",
+                        r5: "v2:5",
+                        // in the order the messages would have reached the old child, a
+                        // `ctx.send` goes through the mailbox of the actor first
+                        seen: [3, 2, 4, 5]
+                    })
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn replace_actor_drain_timeout() {
+        let system = System::new("test");
+
+        // the old child doesn't reply in time
+        let addr = parent(Duration::from_secs(60), 0.05);
+        let send = {
+            let addr = addr.clone();
+            move |msg| addr.do_send(msg)
+        };
+        send(lua_msg!({ op: "req", n: 1 }));
+        let l = delay(10)
+            .and_then(move |_| {
+                send(lua_msg!({ op: "replace" }));
+                send(lua_msg!({ op: "req", n: 2 }));
+                delay(150)
+            })
+            .and_then(move |_| addr.send(lua_msg!({ op: "get" })))
+            .map(|results| {
+                assert_eq!(
+                    results,
+                    lua_msg!({
+                        summary: { drained: 0, queued: 1, old_stopped: false },
+                        r2: "v2:2"
+                    })
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn replace_actor_errors() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_child("worker", LuaActorBuilder::new())
+            .on_handle_with_lua(
+                r#"
+                local ok, err = pcall(ctx.replace_actor, ctx.msg, "src/lua/test/handoff_v2.lua")
+                return tostring(err)
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+        let l = addr.send(LuaMessage::from("other")).map(|res| {
+            assert!(format!("{:?}", res).contains("other is not a child of the actor"));
+            System::current().stop();
+        });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,121 @@
+use crate::message::LuaMessage;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// Configured by `LuaActorBuilder::with_idempotency`.
+#[derive(Clone)]
+pub(crate) struct Idempotency {
+    // path of the key in table messages
+    path: Vec<String>,
+    window: Duration,
+    cache_results: bool,
+    // result of the first message with each key, `None` if results aren't cached
+    seen: HashMap<String, Option<LuaMessage>>,
+    // keys in the order they were seen
+    expiry: VecDeque<(Instant, String)>,
+}
+
+impl Idempotency {
+    pub fn new(key_path: &str, window: Duration, cache_results: bool) -> Idempotency {
+        Idempotency {
+            path: key_path.split('.').map(str::to_string).collect(),
+            window,
+            cache_results,
+            seen: HashMap::new(),
+            expiry: VecDeque::new(),
+        }
+    }
+
+    // Key of `msg`, `None` if it has no string or number at the key path.
+    pub fn key(&self, msg: &LuaMessage) -> Option<String> {
+        let mut value = msg;
+        for part in &self.path {
+            value = value.get(part)?;
+        }
+        match value {
+            // tagged so `1` and `"1"` are different keys
+            LuaMessage::String(s) => Some(format!("s:{}", s)),
+            LuaMessage::Integer(n) => Some(format!("i:{}", n)),
+            LuaMessage::Number(n) => Some(format!("n:{}", n)),
+            _ => None,
+        }
+    }
+
+    // The reply to a duplicate of a message seen within the window, `None` if `key` is new.
+    pub fn duplicate(&mut self, key: &str, now: Instant) -> Option<LuaMessage> {
+        self.purge(now);
+        self.seen.get(key).map(|result| match result {
+            Some(result) => result.clone(),
+            None => {
+                let mut reply = HashMap::new();
+                reply.insert("duplicate".to_string(), LuaMessage::from(true));
+                LuaMessage::from(reply)
+            }
+        })
+    }
+
+    // Remember that the message with `key` was handled.
+    pub fn record(&mut self, key: String, result: &LuaMessage, now: Instant) {
+        let result = if self.cache_results {
+            Some(result.clone())
+        } else {
+            None
+        };
+        self.expiry.push_back((now, key.clone()));
+        self.seen.insert(key, result);
+    }
+
+    fn purge(&mut self, now: Instant) {
+        while let Some((at, _)) = self.expiry.front() {
+            if now.duration_since(*at) < self.window {
+                break;
+            }
+            let (_, key) = self.expiry.pop_front().unwrap();
+            self.seen.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(id: LuaMessage) -> LuaMessage {
+        let mut meta = HashMap::new();
+        meta.insert("msg_id".to_string(), id);
+        let mut msg = HashMap::new();
+        msg.insert("meta".to_string(), LuaMessage::from(meta));
+        LuaMessage::from(msg)
+    }
+
+    #[test]
+    fn idempotency_keys() {
+        let i = Idempotency::new("meta.msg_id", Duration::from_secs(1), true);
+        assert_eq!(i.key(&msg(LuaMessage::from("a"))), Some("s:a".to_string()));
+        assert_ne!(
+            i.key(&msg(LuaMessage::from(1))),
+            i.key(&msg(LuaMessage::from("1")))
+        );
+        assert_eq!(i.key(&msg(LuaMessage::from(true))), None);
+        assert_eq!(i.key(&LuaMessage::from("meta")), None);
+        assert_eq!(i.key(&LuaMessage::Nil), None);
+    }
+
+    #[test]
+    fn idempotency_window() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        let mut i = Idempotency::new("id", Duration::from_secs(1), true);
+        assert_eq!(i.duplicate("a", at(0)), None);
+        i.record("a".to_string(), &LuaMessage::from(42), at(0));
+        assert_eq!(i.duplicate("a", at(999)), Some(LuaMessage::from(42)));
+        assert_eq!(i.duplicate("a", at(1000)), None);
+        assert!(i.seen.is_empty());
+
+        let mut i = Idempotency::new("id", Duration::from_secs(1), false);
+        i.record("a".to_string(), &LuaMessage::from(42), at(0));
+        let reply = i.duplicate("a", at(500)).unwrap();
+        assert_eq!(reply.get("duplicate"), Some(&LuaMessage::from(true)));
+    }
+}
@@ -0,0 +1,186 @@
+use uuid::Uuid;
+
+use crate::correlation::next_correlation_id;
+
+// The identifiers generated by an actor: `ctx.uuid()`, the correlation ids of
+// `ctx.send_correlated` and `ctx.wait_for`, and the seeds of its children, see
+// `LuaActorBuilder::with_id_seed`.
+//
+// Seeded, the ids are a splitmix64 sequence, the same in every run. Otherwise the uuids are
+// random and the correlation ids unique in the process.
+#[derive(Debug, Clone, Default)]
+pub(crate) enum IdSource {
+    #[default]
+    Random,
+    Seeded(u64),
+}
+
+impl IdSource {
+    pub fn new(seed: Option<u64>) -> IdSource {
+        match seed {
+            Some(seed) => IdSource::Seeded(seed),
+            None => IdSource::Random,
+        }
+    }
+
+    // The next value of the seeded sequence, `None` if the source is random.
+    fn next_u64(&mut self) -> Option<u64> {
+        match self {
+            IdSource::Random => None,
+            IdSource::Seeded(state) => {
+                *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                let mut z = *state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                Some(z ^ (z >> 31))
+            }
+        }
+    }
+
+    // A version 4 uuid, e.g. "67e55044-10b1-426f-9247-bb680e5fe0c8".
+    pub fn uuid(&mut self) -> String {
+        let uuid = match (self.next_u64(), self.next_u64()) {
+            (Some(high), Some(low)) => {
+                let mut bytes = [0; 16];
+                bytes[..8].copy_from_slice(&high.to_be_bytes());
+                bytes[8..].copy_from_slice(&low.to_be_bytes());
+                Uuid::from_random_bytes(bytes)
+            }
+            _ => Uuid::new_v4(),
+        };
+        uuid.hyphenated().to_string()
+    }
+
+    // A positive correlation id.
+    pub fn correlation_id(&mut self) -> i64 {
+        match self.next_u64() {
+            Some(n) => (n >> 1) as i64 | 1,
+            None => next_correlation_id(),
+        }
+    }
+
+    // The seed of a child, so that a seeded tree is reproducible. `None` if the source is
+    // random.
+    pub fn child_seed(&mut self) -> Option<u64> {
+        self.next_u64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LuaActorBuilder;
+    use crate::message::LuaMessage;
+    use actix::prelude::*;
+    use futures::{future, Future};
+    use futures_timer::Delay;
+    use std::time::Duration;
+
+    // Generates two uuids, gets one from its child, then sends a correlated request to the
+    // child, which keeps the correlation id. Replies to "ids" with the ids, in order.
+    fn scenario(seed: Option<u64>) -> LuaActorBuilder {
+        let child = LuaActorBuilder::new().on_handle_with_lua(
+            r#"
+            if type(ctx.msg) == "table" then
+                ctx.state.seen = ctx.msg.correlation_id
+            elseif ctx.msg == "seen" then
+                return ctx.state.seen
+            else
+                return ctx.uuid()
+            end
+            "#,
+        );
+        let builder = LuaActorBuilder::new()
+            .with_child("child", child)
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "run" then
+                    ctx.state.ids = { ctx.uuid(), ctx.uuid(), ctx.send("child", "uuid") }
+                    ctx.send_correlated("child", {})
+                elseif ctx.msg == "collect" then
+                    local seen = ctx.send("child", "seen")
+                    table.insert(ctx.state.ids, seen)
+                else
+                    return ctx.state.ids
+                end
+                "#,
+            );
+        match seed {
+            Some(seed) => builder.with_id_seed(seed),
+            None => builder,
+        }
+    }
+
+    fn run(builder: LuaActorBuilder) -> impl Future<Item = LuaMessage, Error = MailboxError> {
+        let addr = builder.build().unwrap().start();
+        addr.do_send(LuaMessage::from("run"));
+        Delay::new(Duration::from_millis(50))
+            .map_err(|e| panic!("{}", e))
+            .and_then(move |_| {
+                addr.do_send(LuaMessage::from("collect"));
+                Delay::new(Duration::from_millis(50))
+                    .map_err(|e| panic!("{}", e))
+                    .and_then(move |_| addr.send(LuaMessage::from("ids")))
+            })
+    }
+
+    #[test]
+    fn seeded_scenario() {
+        let system = System::new("test");
+
+        let l = future::join_all(vec![
+            run(scenario(Some(5))),
+            run(scenario(Some(5))),
+            run(scenario(None)),
+            run(scenario(None)),
+        ])
+        .map(|ids| {
+            match ids[0] {
+                LuaMessage::Array(ref seeded) => {
+                    assert_eq!(seeded.len(), 4);
+                    // the child's uuid comes from a seed of its own
+                    assert_ne!(seeded[2], seeded[0]);
+                    assert!(matches!(seeded[3], LuaMessage::Integer(id) if id > 0));
+                }
+                ref ids => panic!("expected the ids, got {}", ids),
+            }
+            assert_eq!(ids[0], ids[1]);
+            assert_ne!(ids[2], ids[3]);
+            System::current().stop();
+        });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn seeded_ids() {
+        let ids = |seed| {
+            let mut source = IdSource::new(seed);
+            (
+                source.uuid(),
+                source.correlation_id(),
+                source.child_seed(),
+                source.uuid(),
+            )
+        };
+
+        let (uuid, correlation_id, child_seed, next) = ids(Some(42));
+        assert_eq!(
+            ids(Some(42)),
+            (uuid.clone(), correlation_id, child_seed, next.clone())
+        );
+        assert_ne!(ids(Some(43)).0, uuid);
+        assert_ne!(uuid, next);
+        assert!(correlation_id > 0);
+        assert!(child_seed.is_some());
+        // a version 4 uuid
+        let parsed = Uuid::parse_str(&uuid).unwrap();
+        assert_eq!(parsed.get_version_num(), 4);
+        assert_eq!(parsed.hyphenated().to_string(), uuid);
+
+        let (uuid, _, child_seed, _) = ids(None);
+        assert_ne!(ids(None).0, uuid);
+        assert_eq!(child_seed, None);
+    }
+}
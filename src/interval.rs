@@ -0,0 +1,227 @@
+use ::actix::prelude::*;
+use rlua::Error as LuaError;
+
+use crate::actor::LuaActor;
+use crate::message::LuaMessage;
+use crate::pause::Buffered;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// What happens to the ticks of a `ctx.run_interval` which are due while the actor is busy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TickPolicy {
+    // at most one pending tick, the skipped ones are counted in `missed`
+    Coalesce,
+    // deliver every tick
+    All,
+    // drop the ticks due while one is pending
+    Skip,
+}
+
+impl TickPolicy {
+    pub fn parse(policy: Option<&str>) -> Result<TickPolicy, LuaError> {
+        match policy {
+            None | Some("coalesce") => Ok(TickPolicy::Coalesce),
+            Some("all") => Ok(TickPolicy::All),
+            Some("skip") => Ok(TickPolicy::Skip),
+            Some(policy) => Err(LuaError::RuntimeError(format!(
+                "invalid interval policy {:?}, expected \"coalesce\", \"all\" or \"skip\"",
+                policy
+            ))),
+        }
+    }
+}
+
+// An interval created with `ctx.run_interval`.
+//
+// Time is passed in by the caller so the policies can be tested without waiting.
+pub(crate) struct Interval {
+    period: Duration,
+    msg: HashMap<String, LuaMessage>,
+    policy: TickPolicy,
+    // next deadline
+    next: Instant,
+    // a tick was delivered and not handled yet
+    pending: bool,
+    missed: i64,
+}
+
+impl Interval {
+    pub fn new(
+        period: Duration,
+        msg: HashMap<String, LuaMessage>,
+        policy: TickPolicy,
+        now: Instant,
+    ) -> Interval {
+        Interval {
+            period,
+            msg,
+            policy,
+            next: now + period,
+            pending: false,
+            missed: 0,
+        }
+    }
+
+    // Advance past the deadlines due at `now`, returning how many ticks to deliver.
+    pub fn fire(&mut self, now: Instant) -> usize {
+        let late = now.saturating_duration_since(self.next);
+        let due = 1 + (late.as_nanos() / self.period.as_nanos().max(1)) as u32;
+        self.next += self.period * due;
+
+        match self.policy {
+            TickPolicy::All => due as usize,
+            TickPolicy::Coalesce if self.pending => {
+                self.missed += i64::from(due);
+                0
+            }
+            TickPolicy::Coalesce => {
+                self.missed += i64::from(due - 1);
+                self.pending = true;
+                1
+            }
+            TickPolicy::Skip if self.pending => 0,
+            TickPolicy::Skip => {
+                self.pending = true;
+                1
+            }
+        }
+    }
+
+    // Time until the next deadline.
+    pub fn delay(&self, now: Instant) -> Duration {
+        self.next.saturating_duration_since(now)
+    }
+
+    // Message of a delivered tick, with the number of ticks it replaces in `missed`.
+    pub fn tick(&mut self) -> LuaMessage {
+        self.pending = false;
+        let mut msg = self.msg.clone();
+        msg.insert(
+            "missed".to_string(),
+            LuaMessage::from(std::mem::replace(&mut self.missed, 0)),
+        );
+        LuaMessage::from(msg)
+    }
+}
+
+// The intervals of an actor by id.
+#[derive(Default)]
+pub(crate) struct Intervals {
+    next_id: i64,
+    intervals: HashMap<i64, Interval>,
+}
+
+impl Intervals {
+    pub fn insert(&mut self, interval: Interval) -> i64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.intervals.insert(id, interval);
+        id
+    }
+
+    pub fn remove(&mut self, id: i64) {
+        self.intervals.remove(&id);
+    }
+
+    pub fn clear(&mut self) {
+        self.intervals.clear();
+    }
+}
+
+// A tick of the interval `id` to handle.
+pub(crate) struct IntervalTick {
+    id: i64,
+}
+
+impl Message for IntervalTick {
+    type Result = ();
+}
+
+impl Handler<IntervalTick> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, tick: IntervalTick, ctx: &mut Context<Self>) {
+        // cancelled intervals drop their pending ticks
+        let msg = match self.intervals.intervals.get_mut(&tick.id) {
+            Some(interval) => interval.tick(),
+            None => return,
+        };
+        if self.pause.holds(true) {
+            self.hold(Buffered::Timer(msg));
+            return;
+        }
+        self.handle_message(msg, false, ctx);
+    }
+}
+
+impl LuaActor {
+    // Start delivering the ticks of the interval `id`.
+    pub(crate) fn schedule_interval(id: i64, delay: Duration, ctx: &mut Context<Self>) {
+        ctx.run_later(delay, move |act, ctx| {
+            let now = Instant::now();
+            let (ticks, delay) = match act.intervals.intervals.get_mut(&id) {
+                Some(interval) => (interval.fire(now), interval.delay(now)),
+                None => return,
+            };
+            for _ in 0..ticks {
+                ctx.notify(IntervalTick { id });
+            }
+            LuaActor::schedule_interval(id, delay, ctx);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interval(policy: TickPolicy, start: Instant) -> Interval {
+        Interval::new(Duration::from_secs(1), HashMap::new(), policy, start)
+    }
+
+    fn missed(msg: LuaMessage) -> Option<LuaMessage> {
+        msg.get("missed").cloned()
+    }
+
+    #[test]
+    fn interval_policies() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        // on time
+        let mut i = interval(TickPolicy::Coalesce, start);
+        assert_eq!(i.fire(at(1000)), 1);
+        assert_eq!(i.delay(at(1000)), Duration::from_secs(1));
+        assert_eq!(missed(i.tick()), Some(LuaMessage::from(0)));
+
+        // the timer fired 3 periods late
+        let mut all = interval(TickPolicy::All, start);
+        assert_eq!(all.fire(at(4500)), 4);
+        assert_eq!(all.delay(at(4500)), Duration::from_millis(500));
+        assert_eq!(missed(all.tick()), Some(LuaMessage::from(0)));
+
+        let mut coalesce = interval(TickPolicy::Coalesce, start);
+        assert_eq!(coalesce.fire(at(4500)), 1);
+        assert_eq!(coalesce.delay(at(4500)), Duration::from_millis(500));
+        // the tick is still pending at the next deadline
+        assert_eq!(coalesce.fire(at(5000)), 0);
+        assert_eq!(missed(coalesce.tick()), Some(LuaMessage::from(4)));
+        assert_eq!(coalesce.fire(at(6000)), 1);
+        assert_eq!(missed(coalesce.tick()), Some(LuaMessage::from(0)));
+
+        let mut skip = interval(TickPolicy::Skip, start);
+        assert_eq!(skip.fire(at(4500)), 1);
+        assert_eq!(skip.fire(at(5000)), 0);
+        assert_eq!(missed(skip.tick()), Some(LuaMessage::from(0)));
+        assert_eq!(skip.fire(at(6000)), 1);
+    }
+
+    #[test]
+    fn interval_policy_names() {
+        assert_eq!(TickPolicy::parse(None).unwrap(), TickPolicy::Coalesce);
+        assert_eq!(TickPolicy::parse(Some("all")).unwrap(), TickPolicy::All);
+        assert_eq!(TickPolicy::parse(Some("skip")).unwrap(), TickPolicy::Skip);
+        assert!(TickPolicy::parse(Some("burst")).is_err());
+    }
+}
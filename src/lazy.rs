@@ -0,0 +1,386 @@
+use rlua::Result as LuaResult;
+use rlua::{AnyUserData, Context, Function, Table, UserData, UserDataMethods, Value};
+
+use crate::message::{class, null_sentinel, vm_limits, LuaMessage, Walk};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// A table of a message passed as `ctx.msg`, converted to Lua on access, see
+// `LuaActorBuilder::with_lazy_tables`. Nested tables share the message and are found by
+// their path.
+#[derive(Clone)]
+pub(crate) struct LazyTable {
+    root: Arc<LuaMessage>,
+    path: Vec<String>,
+}
+
+impl LazyTable {
+    fn table(&self) -> &HashMap<String, LuaMessage> {
+        let mut msg = &*self.root;
+        for k in &self.path {
+            msg = msg.get(k).expect("the path of a lazy table exists");
+        }
+        match msg {
+            LuaMessage::Table(t) => t,
+            _ => unreachable!("lazy tables are tables"),
+        }
+    }
+
+    // The Lua value of the entry `key`, and whether it's a nested lazy table.
+    fn value<'lua>(
+        &self,
+        ctx: Context<'lua>,
+        key: &str,
+        msg: &LuaMessage,
+    ) -> LuaResult<(Value<'lua>, bool)> {
+        match msg {
+            LuaMessage::Nil => Ok((null_sentinel(), false)),
+            LuaMessage::Table(_) if !is_instance(ctx, msg)? => {
+                let mut path = self.path.clone();
+                path.push(key.to_string());
+                let nested = LazyTable {
+                    root: self.root.clone(),
+                    path,
+                };
+                Ok((Value::UserData(ctx.create_userdata(nested)?), true))
+            }
+            msg => Ok((msg.to_lua_value(ctx, Some(&vm_limits(ctx)))?, false)),
+        }
+    }
+}
+
+impl UserData for LazyTable {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        // the keys of the tables of messages are strings, like in the converted tables
+        methods.add_method("get", |ctx, this, key: Value| {
+            let key = match key {
+                Value::String(key) => key,
+                _ => return Ok((Value::Nil, false)),
+            };
+            let key = match key.to_str() {
+                Ok(key) => key,
+                Err(_) => return Ok((Value::Nil, false)),
+            };
+            match this.table().get(key) {
+                Some(msg) => this.value(ctx, key, msg),
+                None => Ok((Value::Nil, false)),
+            }
+        });
+        // all the entries, and the set of the keys of nested lazy tables
+        methods.add_method("entries", |ctx, this, ()| {
+            let values = ctx.create_table()?;
+            let nested = ctx.create_table()?;
+            for (k, msg) in this.table() {
+                let (v, lazy) = this.value(ctx, k, msg)?;
+                if lazy {
+                    nested.raw_set(k.as_str(), true)?;
+                }
+                values.raw_set(k.as_str(), v)?;
+            }
+            Ok((values, nested))
+        });
+    }
+}
+
+// Whether `msg` is an instance of a class registered with `ctx.register_class`, converted
+// eagerly to attach its metatable.
+fn is_instance(ctx: Context, msg: &LuaMessage) -> LuaResult<bool> {
+    match msg.get("__class") {
+        Some(LuaMessage::String(name)) => Ok(class(ctx, name)?.is_some()),
+        _ => Ok(false),
+    }
+}
+
+// Apply the conversion limits to `msg`, counting like the conversion to Lua values so that
+// lazy tables fail like the converted ones.
+fn check_limits(msg: &LuaMessage, walk: &mut Walk) -> LuaResult<()> {
+    match msg {
+        LuaMessage::Number(n) => walk.check_number(*n),
+        LuaMessage::Table(t) => {
+            walk.check_depth()?;
+            walk.add_entries(t.len())?;
+            for (k, v) in t {
+                check_entry_limits(k.clone(), v, walk)?;
+            }
+            Ok(())
+        }
+        LuaMessage::Array(a) => {
+            walk.check_depth()?;
+            walk.add_entries(a.len())?;
+            for (i, v) in a.iter().enumerate() {
+                check_entry_limits((i + 1).to_string(), v, walk)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_entry_limits(key: String, v: &LuaMessage, walk: &mut Walk) -> LuaResult<()> {
+    match v {
+        LuaMessage::Table(_) | LuaMessage::Array(_) => {
+            walk.path.push(key);
+            check_limits(v, walk)?;
+            walk.path.pop();
+            Ok(())
+        }
+        v => check_limits(v, walk),
+    }
+}
+
+// Convert the arguments of `__run`, with the message as a lazy table.
+pub(crate) fn run_args_to_lua<'lua>(
+    ctx: Context<'lua>,
+    args: Vec<LuaMessage>,
+) -> LuaResult<Vec<Value<'lua>>> {
+    args.into_iter()
+        .enumerate()
+        .map(|(i, msg)| match msg {
+            LuaMessage::Table(_) if i == 1 && !is_instance(ctx, &msg)? => {
+                let limits = vm_limits(ctx);
+                check_limits(&msg, &mut Walk::new(&limits))?;
+                let handle = LazyTable {
+                    root: Arc::new(msg),
+                    path: Vec::new(),
+                };
+                let lazy_table: Function = ctx.globals().raw_get("__lazy_table")?;
+                lazy_table.call(handle)
+            }
+            msg => msg.to_lua_value(ctx, Some(&vm_limits(ctx))),
+        })
+        .collect()
+}
+
+// The message of `t` if it's a lazy table which wasn't converted yet. The entries read by the
+// scripts are converted back, in case they were modified, the others are copied.
+pub(crate) fn lazy_message<'lua>(
+    ctx: Context<'lua>,
+    t: &Table<'lua>,
+    walk: &mut Walk,
+) -> LuaResult<Option<LuaMessage>> {
+    if t.get_metatable().is_none() {
+        return Ok(None);
+    }
+    let lazy = match ctx.globals().raw_get::<_, Value>("__lazy")? {
+        Value::Table(tables) => match tables.raw_get::<_, Value>(t.clone())? {
+            Value::Table(lazy) => lazy,
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    let handle: AnyUserData = lazy.raw_get("handle")?;
+    let handle = handle.borrow::<LazyTable>()?;
+    let values: Table = lazy.raw_get("values")?;
+    let table = handle.table();
+    let mut map = HashMap::with_capacity(table.len());
+    for (k, msg) in table {
+        walk.add_entries(1)?;
+        let v = match msg {
+            // arrays are converted when read, they may have been modified too
+            LuaMessage::Table(_) | LuaMessage::Array(_) => {
+                walk.path.push(k.clone());
+                let v = match values.raw_get::<_, Value>(k.as_str())? {
+                    Value::Table(read) => {
+                        LuaMessage::from_lua_value_at(Value::Table(read), ctx, walk)?
+                    }
+                    _ => {
+                        check_limits(msg, walk)?;
+                        msg.clone()
+                    }
+                };
+                walk.path.pop();
+                v
+            }
+            msg => {
+                check_limits(msg, walk)?;
+                msg.clone()
+            }
+        };
+        map.insert(k.clone(), v);
+    }
+    Ok(Some(LuaMessage::Table(map)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::ScriptVm;
+    use crate::{lua_msg, LuaActorBuilder, LuaMessage};
+
+    // `ctx.msg.data` probed by `ctx.msg.probe`
+    const PROBES: &str = r#"
+        if Point == nil then
+            Point = {}
+            ctx.register_class("Point", Point)
+        end
+        local function dump(v)
+            if type(v) ~= "table" then
+                return tostring(v == ctx.null and "null" or v)
+            end
+            local keys = {}
+            for k in pairs(v) do
+                keys[#keys + 1] = tostring(k)
+            end
+            table.sort(keys)
+            local out = {}
+            for _, k in ipairs(keys) do
+                out[#out + 1] = k .. "=" .. dump(v[k])
+            end
+            return "{" .. table.concat(out, ",") .. "}"
+        end
+        local d = ctx.msg.data
+        local probe = ctx.msg.probe
+        if probe == "echo" then
+            return ctx.msg
+        elseif probe == "read" then
+            return {
+                a = d.a,
+                leaf = d.nested and d.nested.inner and d.nested.inner.leaf,
+                null = d.none == ctx.null,
+                nested = type(d.nested),
+                one = d[1],
+                one_str = d["1"],
+                missing = d.missing,
+            }
+        elseif probe == "dump" then
+            return dump(d)
+        elseif probe == "len" then
+            return { len = #d, raw = rawlen(d) }
+        elseif probe == "raw" then
+            local k = next(d)
+            return { empty = k == nil, a = rawget(d, "a"), meta = getmetatable(d) == nil }
+        elseif probe == "identity" then
+            return { same = d.nested == d.nested, point = getmetatable(d.point) == Point }
+        elseif probe == "mutate" then
+            d.a = 10
+            d.b = nil
+            if type(d.nested) == "table" then
+                local nested = d.nested
+                nested.x = 5
+                d.nested.inner = nil
+            end
+            return ctx.msg
+        elseif probe == "delete_read" then
+            local _ = d.a
+            d.a = nil
+            return { a = d.a, dump = dump(d) }
+        elseif probe == "insert" then
+            table.insert(d, "x")
+            return dump(d)
+        elseif probe == "rawset" then
+            local _ = d.a
+            rawset(d, "a", "raw")
+            return dump(d)
+        elseif probe == "setmetatable" then
+            setmetatable(d, { __index = function () return "default" end })
+            return { missing = d.missing, a = d.a }
+        elseif probe == "ipairs" then
+            local n = 0
+            for _ in ipairs(d) do
+                n = n + 1
+            end
+            return n
+        elseif probe == "store" then
+            ctx.state.saved = d
+            return ctx.state.saved
+        end
+    "#;
+
+    fn shapes() -> Vec<LuaMessage> {
+        vec![
+            lua_msg!({}),
+            lua_msg!({ a: 1, b: "x", c: 2.5, d: true }),
+            lua_msg!({ a: "top", nested: { inner: { leaf: "y" }, n: 1 } }),
+            lua_msg!({ none: nil, nested: { none: nil } }),
+            lua_msg!(["first", "second", "third"]),
+            lua_msg!({ point: { __class: "Point", x: 1, y: 2 }, a: 1 }),
+            lua_msg!({ other: { __class: "Nope", x: 1 } }),
+            lua_msg!({ a: { b: { c: { d: { e: {} } } } } }),
+            lua_msg!(42),
+            lua_msg!("string"),
+            lua_msg!(nil),
+        ]
+    }
+
+    fn run(vm: &mut ScriptVm, probe: &str, data: &LuaMessage) -> Result<LuaMessage, String> {
+        let mut msg = lua_msg!({ probe: probe });
+        if let LuaMessage::Table(ref mut t) = msg {
+            t.insert("data".to_string(), data.clone());
+        }
+        // the tracebacks differ
+        vm.handle(msg)
+            .map_err(|e| e.to_string().lines().next().unwrap_or("").to_string())
+    }
+
+    #[test]
+    fn lazy_tables_equivalence() {
+        let probes = [
+            "echo",
+            "read",
+            "dump",
+            "len",
+            "raw",
+            "identity",
+            "mutate",
+            "delete_read",
+            "insert",
+            "rawset",
+            "setmetatable",
+            "ipairs",
+            "store",
+        ];
+        for frozen in [false, true] {
+            let builder = LuaActorBuilder::new()
+                .on_handle_with_lua(PROBES)
+                .with_frozen_msg(frozen);
+            let mut eager = ScriptVm::new(&builder).unwrap();
+            let mut lazy = ScriptVm::new(&builder.with_lazy_tables(true)).unwrap();
+            for data in shapes() {
+                for probe in &probes {
+                    assert_eq!(
+                        run(&mut eager, probe, &data),
+                        run(&mut lazy, probe, &data),
+                        "probe {} of {:?}, frozen: {}",
+                        probe,
+                        data,
+                        frozen
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lazy_tables_limits() {
+        let data = lua_msg!({ nested: { a: 1, b: 2, c: 3 }, d: 4 });
+        for lazy in [false, true] {
+            let builder = LuaActorBuilder::new()
+                .on_handle_with_lua(r#"return ctx.msg.probe"#)
+                .with_max_table_entries(Some(4))
+                .with_lazy_tables(lazy);
+            let mut vm = ScriptVm::new(&builder).unwrap();
+            // fails before running the handler, without reading the nested table
+            let err = run(&mut vm, "read", &data).unwrap_err();
+            assert!(err.contains("table has more than 4 entries"), "{}", err);
+        }
+
+        // the values read are converted back with the limits
+        let builder = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                local nested = ctx.msg.nested
+                for i = 1, 10 do
+                    nested[i] = i
+                end
+                return ctx.msg
+                "#,
+            )
+            .with_max_table_entries(Some(10))
+            .with_lazy_tables(true);
+        let mut vm = ScriptVm::new(&builder).unwrap();
+        let err = vm
+            .handle(lua_msg!({ nested: { a: 1 } }))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("table has more than 10 entries"), "{}", err);
+    }
+}
@@ -36,15 +36,98 @@
 extern crate futures_timer;
 
 mod actor;
+mod breaker;
+mod build_error;
+mod build_info;
 mod builder;
+mod call;
+mod codec;
+#[cfg(feature = "compat-handlers")]
+mod compat;
+mod config;
+mod correlation;
+mod cron;
+mod dataset;
+mod error_bursts;
+mod export;
+mod gc;
+mod handoff;
+mod idempotency;
+mod ids;
+mod interval;
+mod lazy;
+mod math;
 mod message;
+mod opaque;
+mod outbox;
+mod pause;
+mod ping;
+mod pool;
+mod rate_limit;
+mod receipts;
+mod recipients;
+mod runtime;
+mod schedule;
+mod source;
+mod state_diff;
+mod state_quota;
+mod strings;
+mod sync_actor;
+mod warnings;
+mod watch;
+mod watchdog;
+mod workflow;
 
-pub use crate::actor::LuaActor;
+pub use crate::actor::{
+    AddRecipient, HandleError, LuaActor, MissingHandler, SetAlias, TryLuaMessage,
+};
+pub use crate::build_error::{LuaActorBuildError, ScriptError};
 pub use crate::builder::LuaActorBuilder;
-pub use crate::message::LuaMessage;
+pub use crate::call::{CallError, CallFuture, LuaAddrExt};
+#[cfg(feature = "bincode")]
+pub use crate::codec::BincodeCodec;
+#[cfg(feature = "json")]
+pub use crate::codec::JsonCodec;
+pub use crate::codec::{StateCodec, StateError, StateSubsystem};
+#[cfg(feature = "compat-handlers")]
+pub use crate::compat::{CompatMessage, CompatReply};
+pub use crate::config::GetEffectiveConfig;
+pub use crate::dataset::{SetDataset, SharedDataset};
+pub use crate::error_bursts::{ErrorBurst, ErrorBurstCallback};
+pub use crate::gc::GetLuaMemory;
+pub use crate::message::{ConversionOptions, LuaMessage, ZeroBased};
+pub use crate::opaque::{OpaqueHandle, OpaqueRegistry};
+pub use crate::pause::{GetPauseStatus, PauseOverflow, PauseProcessing, ResumeProcessing};
+pub use crate::ping::Ping;
+pub use crate::pool::{LuaActorPool, PoolReply};
+pub use crate::rate_limit::{GetInboundStats, ShedPolicy};
+pub use crate::receipts::GetDeliveryStats;
+pub use crate::recipients::{Recipients, RecipientsIter};
+pub use crate::runtime::{ApiEntry, ApiValue};
+pub use crate::source::{FileSource, ScriptChunk, ScriptSource};
+pub use crate::state_quota::{GetStateSize, StateQuotaPolicy};
+pub use crate::sync_actor::SyncLuaActor;
+pub use crate::topology::{shutdown_tree, GetTopology, ShutdownReport};
+pub use crate::warnings::{Warning, WarningCallback};
+pub use crate::watchdog::{StuckInvocation, WatchdogCallback};
+pub use crate::workflow::GetWorkflowStats;
+
+pub mod docs;
+pub mod query;
+pub mod testing;
+pub mod topology;
+pub mod update;
 
 /// Re-export `rlua` interface for library developers
+///
+/// Rust functions added to a VM passed to `LuaActorBuilder::build_with_vm` can convert their
+/// arguments and results with `FromLua` and `ToLua`, which apply the [`ConversionLimits`] of
+/// the actor, or with `LuaMessage::from_lua_value` and `LuaMessage::to_lua_value`.
+///
+/// [`ConversionLimits`]: struct.ConversionLimits.html
 pub mod dev {
+    pub use crate::message::ConversionLimits;
+
     pub mod rlua {
         pub use rlua::*;
     }
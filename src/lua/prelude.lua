@@ -1,58 +1,1399 @@
 __threads = {}
 __thread_id_seq = 0
 __scripts = {}
+__declared_globals = {}
+__rich_send_results = false
+-- cleared `ctx.scratch` tables, reused by the next invocations
+__scratch_pool = {}
+-- marks `ctx.scratch` tables, to detect the ones stored in `ctx.state`
+__scratch_mt = {}
+-- set in debug builds
+__check_scratch = false
+-- coroutines which finished their script, reused by the next invocations
+__coroutine_pool = {}
+-- set by the runtime, see `LuaActorBuilder::with_coroutine_pool_size`
+__coroutine_pool_size = 0
+-- paths of `ctx.state` skipped by state diffs
+__no_diff = {}
+-- the tables of `ctx.state` and `ctx.msg` by their read-only views, see `__freeze_state`
+__read_only = setmetatable({}, { __mode = "k" })
+-- set by the runtime, see `LuaActorBuilder::with_frozen_msg`
+__frozen_msg = false
+-- set by the runtime, see `LuaActorBuilder::with_isolated_hooks`
+__isolated_hooks = false
+-- the ctx functions raising an error, with the reason, set by the runtime for a `SyncLuaActor`
+__disabled_ctx = {}
+-- set by `__strict_globals`
+__strict = false
+-- the coroutines suspended in `ctx.send_correlated` by correlation id, with the field
+-- carrying the id in the replies
+__correlated = {}
+__correlated_count = 0
+-- the fields of the pending correlation ids, with their number of ids
+__correlation_fields = {}
+-- set by the runtime, see `LuaActorBuilder::with_max_correlated`
+__max_correlated = 0
+-- the coroutines suspended in `ctx.wait_for` by wait id, with the types they wait for
+__waiting = {}
+__waiting_count = 0
+-- the ids of the pending `ctx.wait_for` by message type, oldest first
+__waiters = {}
+-- the groups of `ctx.spawn_group` with unfinished members
+__groups = {}
+-- the started members of `ctx.spawn_group` which didn't finish, by thread id
+__members = {}
+-- members to start and coroutines to resume once the current coroutine yields or returns,
+-- see `drain_ready`
+__ready = {}
+__draining = false
+-- the groups whose `g:join` waits with a timeout, by correlation id
+__joining = {}
 
-ctx = { state = {} }
+ctx = { state = {}, shared = {}, data = {}, runtime = { dependencies = {} } }
+
+-- state of the namespace `name`, created in `ctx.state.__ns` on first use
+function ctx.ns(name)
+    local namespaces = ctx.state.__ns
+    if namespaces == nil then
+        namespaces = {}
+        ctx.state.__ns = namespaces
+    end
+    local state = namespaces[name]
+    if state == nil then
+        state = {}
+        namespaces[name] = state
+    end
+    return state
+end
+
+-- true for nil and `ctx.null`, which is truthy
+function ctx.is_null(v)
+    return v == nil or v == ctx.null
+end
+
+-- the order of `ctx.util.opairs`: booleans, numbers, then strings, each in ascending order, and
+-- the other keys by their `tostring`
+local key_ranks = { boolean = 1, number = 2, string = 3 }
+
+local function key_less(a, b)
+    local ra, rb = key_ranks[type(a)] or 4, key_ranks[type(b)] or 4
+    if ra ~= rb then
+        return ra < rb
+    elseif ra == 1 then
+        return not a and b
+    elseif ra == 4 then
+        return tostring(a) < tostring(b)
+    end
+    return a < b
+end
+
+ctx.util = {}
+
+-- `pairs(t)` in a deterministic order, the same in every run. The tables of a `LuaMessage` are
+-- hash maps without an order of their own, so the keys are sorted
+function ctx.util.opairs(t)
+    local keys = {}
+    for k in pairs(t) do
+        keys[#keys + 1] = k
+    end
+    table.sort(keys, key_less)
+    local i = 0
+    return function ()
+        i = i + 1
+        local k = keys[i]
+        if k ~= nil then
+            return k, t[k]
+        end
+    end
+end
+
+-- skip `path` of `ctx.state`, e.g. "cache" or "__ns.name.key", and everything under it in
+-- state diffs
+function ctx.no_diff(path)
+    __no_diff[path] = true
+end
+
+-- changes of `ctx.state` made by the last message, set by the runtime with state diffing
+function ctx.last_diff()
+    return rawget(_G, "__last_diff")
+end
+
+-- classes registered with `ctx.register_class`, by name and by metatable
+__classes = {}
+__class_names = {}
+
+-- attach the metatable of `name` to the tables of `t` tagged with it and stripped of it
+local function attach_class(t, name, class, seen)
+    seen[t] = true
+    if rawget(t, "__class") == name and getmetatable(t) == nil then
+        t.__class = nil
+        setmetatable(t, class)
+    end
+    for _, v in pairs(t) do
+        if type(v) == "table" and not seen[v] then
+            attach_class(v, name, class, seen)
+        end
+    end
+end
+
+-- convert the instances of `class`, i.e. the tables with `class` as metatable, to tables tagged
+-- with `__class = name`, and attach `class` to the tagged tables converted back
+function ctx.register_class(name, class)
+    if type(name) ~= "string" or type(class) ~= "table" then
+        error("ctx.register_class expects a name and a table", 2)
+    end
+    __classes[name] = class
+    __class_names[class] = name
+    -- restored before the class was registered, e.g. on restart
+    attach_class(ctx.state, name, class, {})
+end
+
+-- declare a global variable, required to assign globals when strict globals are enabled
+function declare_global(name, value)
+    __declared_globals[name] = true
+    rawset(_G, name, value)
+end
+
+-- make reads and writes of undeclared globals raise errors
+function __strict_globals()
+    __strict = true
+    for name, _ in pairs(_G) do
+        __declared_globals[name] = true
+    end
+    setmetatable(_G, {
+        __newindex = function (t, name, value)
+            if not __declared_globals[name] then
+                error("assignment to undeclared global '" .. tostring(name) .. "'", 2)
+            end
+            rawset(t, name, value)
+        end,
+        __index = function (_, name)
+            if not __declared_globals[name] then
+                error("undefined global '" .. tostring(name) .. "'", 2)
+            end
+        end,
+    })
+end
+
+-- the lazy tables of `ctx.msg` with their Rust `handle` and the values read so far, see
+-- `LuaActorBuilder::with_lazy_tables`
+__lazy = setmetatable({}, { __mode = "k" })
+
+local raw_set, raw_get, raw_len, raw_next = rawset, rawget, rawlen, next
+local get_metatable, set_metatable = getmetatable, setmetatable
+
+local lazy_mt = {}
+
+local function lazy_table(handle)
+    local t = set_metatable({}, lazy_mt)
+    __lazy[t] = { handle = handle, values = {} }
+    return t
+end
+
+-- called by the runtime to pass the message
+__lazy_table = lazy_table
+
+-- convert the entries of the lazy table `t`, which becomes a plain table
+local function materialize(t)
+    local lazy = __lazy[t]
+    if lazy == nil then
+        return
+    end
+    __lazy[t] = nil
+    set_metatable(t, nil)
+    local entries, nested = lazy.handle:entries()
+    for k, v in raw_next, entries do
+        local read = lazy.values[k]
+        if read ~= nil then
+            v = read
+        elseif nested[k] then
+            v = lazy_table(v)
+        end
+        raw_set(t, k, v)
+    end
+end
+
+-- the lazy tables stay empty until materialized, so that every write goes through
+-- `__newindex`
+lazy_mt.__index = function (t, k)
+    local lazy = __lazy[t]
+    local v = lazy.values[k]
+    if v == nil then
+        local nested
+        v, nested = lazy.handle:get(k)
+        if nested then
+            v = lazy_table(v)
+        end
+        if v ~= nil then
+            lazy.values[k] = v
+        end
+    end
+    return v
+end
+lazy_mt.__newindex = function (t, k, v)
+    materialize(t)
+    t[k] = v
+end
+lazy_mt.__len = function (t)
+    materialize(t)
+    return #t
+end
+lazy_mt.__pairs = function (t)
+    materialize(t)
+    return raw_next, t, nil
+end
+
+-- raise the error of the raw function `name` called with a non-table `t`, at the caller
+local function check_table(t, name)
+    if type(t) ~= "table" then
+        -- `ctx.null` is the only light userdata
+        local got = t == ctx.null and "light userdata" or type(t)
+        error("bad argument #1 to '" .. name .. "' (table expected, got " .. got .. ")", 3)
+    end
+end
+
+-- called by the runtime, the raw functions and the metatable would tell lazy tables apart
+function __enable_lazy_tables()
+    local read_only_rawset = rawset
+    raw_set(_G, "rawset", function (t, k, v)
+        materialize(t)
+        return read_only_rawset(t, k, v)
+    end)
+    raw_set(_G, "rawget", function (t, k)
+        check_table(t, "rawget")
+        materialize(t)
+        return raw_get(t, k)
+    end)
+    raw_set(_G, "rawlen", function (t)
+        materialize(t)
+        return raw_len(t)
+    end)
+    raw_set(_G, "next", function (t, k)
+        check_table(t, "next")
+        materialize(t)
+        return raw_next(t, k)
+    end)
+    raw_set(_G, "getmetatable", function (t)
+        if __lazy[t] ~= nil then
+            return nil
+        end
+        return get_metatable(t)
+    end)
+    raw_set(_G, "setmetatable", function (t, mt)
+        check_table(t, "setmetatable")
+        if mt ~= nil and type(mt) ~= "table" then
+            error("bad argument #2 to 'setmetatable' (nil or table expected)", 2)
+        end
+        materialize(t)
+        -- protected metatables
+        local ok, err = pcall(set_metatable, t, mt)
+        if not ok then
+            error(err, 2)
+        end
+        return t
+    end)
+end
+
+-- the errors raised by the writes to the read-only views, by view
+local read_only_errors = setmetatable({}, { __mode = "k" })
+
+local STATE_READ_ONLY = "state is read-only in this actor"
+local MSG_READ_ONLY = "ctx.msg is read-only in this actor"
+
+-- the views of `ctx.state` rejecting new keys, see `__limit_state_growth`
+local no_growth = setmetatable({}, { __mode = "k" })
+
+local STATE_OVER_QUOTA = "ctx.state is over its quota, new keys are rejected"
+
+-- `rawset` would bypass the read-only views
+function rawset(t, k, v)
+    if no_growth[t] then
+        t[k] = v
+        return t
+    end
+    if __read_only[t] ~= nil then
+        error(read_only_errors[t], 2)
+    end
+    return raw_set(t, k, v)
+end
+
+-- read-only views of tables raising `message` on writes, by message and table, created on
+-- first access
+local read_only_views = {}
+
+local function read_only_view(t, message)
+    local views = read_only_views[message]
+    if views == nil then
+        views = setmetatable({}, { __mode = "k" })
+        read_only_views[message] = views
+    end
+    local view = views[t]
+    if view ~= nil then
+        return view
+    end
+    local function wrap(v)
+        if type(v) == "table" then
+            return read_only_view(v, message)
+        end
+        return v
+    end
+    view = setmetatable({}, {
+        __index = function (_, k)
+            return wrap(t[k])
+        end,
+        __newindex = function ()
+            error(message, 2)
+        end,
+        __len = function ()
+            return #t
+        end,
+        __pairs = function ()
+            return function (_, k)
+                local key, v = next(t, k)
+                return key, wrap(v)
+            end, view, nil
+        end,
+        __metatable = false,
+    })
+    views[t] = view
+    __read_only[view] = t
+    read_only_errors[view] = message
+    return view
+end
+
+-- replace `ctx.state` by a read-only view, nested tables included
+function __freeze_state()
+    __unlimit_state_growth()
+    if __read_only[ctx.state] == nil then
+        ctx.state = read_only_view(ctx.state, STATE_READ_ONLY)
+    end
+end
+
+local BUILD_READ_ONLY = "ctx.build is read-only"
+
+-- set `ctx.build` to a read-only view of `build`, see `LuaActorBuilder::with_build_info`
+function __set_build_info(build)
+    ctx.build = read_only_view(build or {}, BUILD_READ_ONLY)
+end
+
+__set_build_info()
+
+-- views of tables rejecting the writes of new keys, by table, created on first access
+local no_growth_views = setmetatable({}, { __mode = "k" })
+
+local function no_growth_view(t)
+    local view = no_growth_views[t]
+    if view ~= nil then
+        return view
+    end
+    view = setmetatable({}, {
+        __index = function (_, k)
+            local v = t[k]
+            if type(v) == "table" and __read_only[v] == nil then
+                return no_growth_view(v)
+            end
+            return v
+        end,
+        __newindex = function (_, k, v)
+            if v ~= nil and t[k] == nil then
+                error(STATE_OVER_QUOTA, 2)
+            end
+            -- store the tables behind the views
+            if no_growth[v] then
+                v = __read_only[v]
+            end
+            t[k] = v
+        end,
+        __len = function ()
+            return #t
+        end,
+        __pairs = function ()
+            return function (_, k)
+                local key, v = next(t, k)
+                if type(v) == "table" and __read_only[v] == nil then
+                    v = no_growth_view(v)
+                end
+                return key, v
+            end, view, nil
+        end,
+        __metatable = false,
+    })
+    no_growth_views[t] = view
+    no_growth[view] = true
+    __read_only[view] = t
+    read_only_errors[view] = STATE_OVER_QUOTA
+    return view
+end
+
+-- replace `ctx.state` by a view rejecting new keys, nested tables included, until
+-- `__unlimit_state_growth`, see `LuaActorBuilder::with_state_quota`
+function __limit_state_growth()
+    if __read_only[ctx.state] == nil then
+        ctx.state = no_growth_view(ctx.state)
+    end
+end
+
+function __unlimit_state_growth()
+    if no_growth[ctx.state] then
+        ctx.state = __read_only[ctx.state]
+    end
+end
+
+-- estimated size in bytes of `v`, see `LuaActorBuilder::with_state_quota` for the rules
+local function value_size(v, seen)
+    local kind = type(v)
+    if kind == "string" then
+        return #v
+    elseif kind == "boolean" then
+        return 1
+    elseif kind ~= "table" then
+        return 8
+    end
+    v = __read_only[v] or v
+    if seen[v] then
+        return 0
+    end
+    seen[v] = true
+    materialize(v)
+    local size = 40
+    for key, value in raw_next, v do
+        size = size + 16 + value_size(key, seen) + value_size(value, seen)
+    end
+    return size
+end
+
+function __state_size()
+    return value_size(ctx.state, {})
+end
+
+-- estimated size in bytes of `ctx.state`
+function ctx.state_size()
+    return __state_size()
+end
+
+-- whether the actor was paused with `PauseProcessing`, set by `__set_paused`
+local paused = false
+
+function __set_paused(p)
+    paused = p
+end
+
+function ctx.is_paused()
+    return paused
+end
+
+-- an empty scratch table, recycled if possible
+function __acquire_scratch()
+    local scratch = table.remove(__scratch_pool)
+    if scratch == nil then
+        scratch = setmetatable({}, __scratch_mt)
+    end
+    return scratch
+end
+
+-- clear `scratch` and keep it for the next invocations
+function __release_scratch(scratch)
+    for k in pairs(scratch) do
+        scratch[k] = nil
+    end
+    table.insert(__scratch_pool, scratch)
+end
+
+-- path of a scratch table in `t`, nil if there is none
+function __find_scratch(t, path, seen)
+    if getmetatable(t) == __scratch_mt then
+        return path
+    end
+    seen[t] = true
+    for k, v in pairs(t) do
+        if type(v) == "table" and not seen[v] then
+            local found = __find_scratch(v, path .. "." .. tostring(k), seen)
+            if found ~= nil then
+                return found
+            end
+        end
+    end
+    return nil
+end
+
+-- yielded by the coroutines which finished their script
+local finished = {}
+
+-- run the scripts passed to `coroutine.resume` one after the other, the locals of a script
+-- don't outlive it
+local function coroutine_body(f)
+    while true do
+        f = coroutine.yield(finished, (f()))
+    end
+end
+
+-- a coroutine waiting for a script, recycled if possible
+local function acquire_coroutine()
+    local thread = table.remove(__coroutine_pool)
+    if thread == nil then
+        thread = coroutine.create(coroutine_body)
+    end
+    return thread
+end
+
+-- set by `__enable_compat_shims`
+__compat_shims = false
+
+-- the Lua 5.1 functions defined by `__enable_compat_shims`, by their name in the errors of
+-- their calls
+local compat_globals = { unpack = "unpack", loadstring = "loadstring" }
+local compat_fields = {
+    getn = "table.getn",
+    maxn = "table.maxn",
+    pow = "math.pow",
+    mod = "math.mod",
+    log10 = "math.log10",
+    gfind = "string.gfind",
+}
+
+-- `err`, with a hint if it comes from calling a Lua 5.1 function without the compat shims
+local function compat_hint(err)
+    if __compat_shims or type(err) ~= "string" then
+        return err
+    end
+    local name = compat_globals[err:match("attempt to call a nil value %(global '(%w+)'%)") or ""]
+        or compat_fields[err:match("attempt to call a nil value %(field '(%w+)'%)") or ""]
+    if name == nil then
+        return err
+    end
+    return err .. " (" .. name .. " is a Lua 5.1 function, enable compat shims with "
+        .. "LuaActorBuilder::with_compat_shims(true))"
+end
+
+-- resume `thread`, returning whether it finished its script and the results of
+-- `coroutine.resume`. The coroutines which finished are kept for the next invocations, the
+-- ones which raised an error are dead and dropped
+local function resume_coroutine(thread, ...)
+    local ok, ret, value = coroutine.resume(thread, ...)
+    if ok and ret == finished then
+        if #__coroutine_pool < __coroutine_pool_size then
+            table.insert(__coroutine_pool, thread)
+        end
+        return true, true, value
+    end
+    if not ok then
+        ret = compat_hint(ret)
+    end
+    return false, ok, ret
+end
+
+-- unregister the pending `ctx.send_correlated` `id`, returning its matcher
+local function uncorrelated(id)
+    local matcher = __correlated[id]
+    __correlated[id] = nil
+    __correlated_count = __correlated_count - 1
+    local n = __correlation_fields[matcher.field] - 1
+    __correlation_fields[matcher.field] = n > 0 and n or nil
+    return matcher
+end
+
+-- unregister the pending `ctx.wait_for` `id`, returning its waiter
+local function unwaited(id)
+    local waiter = __waiting[id]
+    __waiting[id] = nil
+    __waiting_count = __waiting_count - 1
+    for _, t in ipairs(waiter.types) do
+        local queue = __waiters[t]
+        for i, queued in ipairs(queue) do
+            if queued == id then
+                table.remove(queue, i)
+                break
+            end
+        end
+        if #queue == 0 then
+            __waiters[t] = nil
+        end
+    end
+    return waiter
+end
+
+-- resumes the coroutines of cancelled `ctx.spawn_group` members
+local CANCELLED = {}
+
+-- resumes the coroutines ended by `ctx.restart`
+local RESTARTED = {}
+
+-- raise a "cancelled" error if the current coroutine is a cancelled `ctx.spawn_group` member
+local function check_cancelled()
+    local member = __members[ctx.thread_id]
+    if member ~= nil and member.cancelled then
+        error("cancelled", 0)
+    end
+end
+
+-- yield the current coroutine until it's resumed. If it's a `ctx.spawn_group` member cancelled
+-- meanwhile, run `on_cancel` and raise a "cancelled" error. Raise an "actor restarted" error if
+-- the actor restarted meanwhile
+local function suspend(on_cancel)
+    local ret, err = coroutine.yield("__suspended__" .. ctx.thread_id)
+    if ret == RESTARTED then
+        error("actor restarted", 0)
+    end
+    if ret == CANCELLED then
+        if on_cancel ~= nil then
+            on_cancel()
+        end
+        error("cancelled", 0)
+    end
+    return ret, err
+end
+
+local Group = {}
+Group.__index = Group
+
+-- the results of the members of `group` in spawn order, `missing` for the unfinished ones
+local function group_results(group, missing)
+    local results = {}
+    for i, member in ipairs(group.members) do
+        results[i] = member.result or missing
+    end
+    return results
+end
+
+local function finish_member(member, result)
+    -- errors raised by Rust functions
+    if type(result.error) == "userdata" then
+        result.error = tostring(result.error)
+    end
+    member.result = result
+    local group = member.group
+    group.pending = group.pending - 1
+    if group.pending > 0 then
+        return
+    end
+    __groups[group] = nil
+    local joiner = group.joiner
+    if joiner ~= nil then
+        group.joiner = nil
+        __joining[joiner.id] = nil
+        uncorrelate(joiner.id)
+        table.insert(__ready, { thread_id = joiner.thread_id, value = group_results(group) })
+    end
+end
+
+-- start `fn` in a new coroutine once the current one yields or returns, returning its index
+function Group:spawn(fn)
+    if type(fn) ~= "function" then
+        error("g:spawn expects a function", 2)
+    end
+    local member = { group = self, fn = fn, cancelled = self.cancelled }
+    table.insert(self.members, member)
+    self.pending = self.pending + 1
+    __groups[self] = true
+    table.insert(__ready, { start = member })
+    return #self.members
+end
+
+-- wait until all the members finished, or `timeout` seconds, returning their results
+function Group:join(timeout)
+    check_cancelled()
+    if self.pending == 0 then
+        return group_results(self)
+    end
+    if self.joiner ~= nil then
+        error("g:join is already waiting for this group", 2)
+    end
+    local id = correlate(timeout, "g:join")
+    self.joiner = { id = id, thread_id = ctx.thread_id }
+    __joining[id] = self
+    return suspend(function ()
+        __joining[id] = nil
+        self.joiner = nil
+        uncorrelate(id)
+    end)
+end
+
+-- cancel the unfinished members, the suspended ones resume with a "cancelled" error
+function Group:cancel()
+    self.cancelled = true
+    for _, member in ipairs(self.members) do
+        if member.result == nil and not member.cancelled then
+            member.cancelled = true
+            local thread_id = member.thread_id
+            if thread_id ~= nil and thread_id ~= ctx.thread_id and __threads[thread_id] ~= nil then
+                table.insert(__ready, { thread_id = thread_id, value = CANCELLED })
+            end
+        end
+    end
+end
+
+-- `pcall` which can yield, e.g. in `ctx.send`: `f` runs in a nested coroutine whose yields
+-- suspend the current one
+function ctx.try(f, ...)
+    local thread = coroutine.create(f)
+    local args = table.pack(...)
+    while true do
+        local res = table.pack(coroutine.resume(thread, table.unpack(args, 1, args.n)))
+        if not res[1] or coroutine.status(thread) == "dead" then
+            return table.unpack(res, 1, res.n)
+        end
+        args = table.pack(coroutine.yield(table.unpack(res, 2, res.n)))
+    end
+end
+
+-- a group of coroutines started with `g:spawn(fn)`
+function ctx.spawn_group()
+    return setmetatable({ members = {}, pending = 0 }, Group)
+end
+
+-- define the Lua 5.1 functions removed since, for scripts written for Lua 5.1 and LuaJIT, see
+-- `LuaActorBuilder::with_compat_shims`
+function __enable_compat_shims()
+    __compat_shims = true
+    rawset(_G, "unpack", table.unpack)
+    -- the `load` of the sandbox if there is one
+    rawset(_G, "loadstring", function (chunk, name)
+        return load(chunk, name)
+    end)
+    table.getn = function (t)
+        return #t
+    end
+    table.maxn = function (t)
+        local max = 0
+        for k in pairs(t) do
+            if type(k) == "number" and k > max then
+                max = k
+            end
+        end
+        return max
+    end
+    math.pow = function (x, y)
+        return x ^ y
+    end
+    math.mod = math.fmod
+    math.log10 = function (x)
+        return math.log(x, 10)
+    end
+    string.gfind = string.gmatch
+end
+
+-- remove what reaches outside of the VM, see `LuaActorBuilder::with_sandbox`
+function __sandbox()
+    dofile = nil
+    loadfile = nil
+    -- precompiled chunks can corrupt the VM
+    local load_text = load
+    load = function (chunk, name, mode, ...)
+        return load_text(chunk, name, "t", ...)
+    end
+    -- `require` only finds `package.preload` and the modules of a script source
+    package.loadlib = nil
+    package.searchpath = nil
+    package.path = ""
+    package.cpath = ""
+    package.searchers = { package.searchers[1] }
+end
 
 function __load(script, name)
-    local f, err = load(script, name, "bt")
+    local env = _G
+    if __isolated_hooks then
+        -- globals assigned by the hook stay in its environment, unless they were declared
+        env = setmetatable({}, {
+            __index = _G,
+            __newindex = function (t, name, value)
+                if __declared_globals[name] then
+                    _G[name] = value
+                elseif __strict then
+                    error("assignment to undeclared global '" .. tostring(name) .. "'", 2)
+                else
+                    rawset(t, name, value)
+                end
+            end,
+        })
+    end
+    local f, err = load(script, name, "bt", env)
     if f == nil then
         error(err)
     end
     __scripts[name] = f
 end
 
--- create a new coroutine from given script
-function __run(script_name, msg, thread_id)
-    ctx.thread_id = __thread_id_seq
-    __thread_id_seq = __thread_id_seq + 1
-
+-- bind the ctx API registered for the current invocation
+function __bind_ctx()
     ctx.notify = notify
     ctx.notify_later = notify_later
+    ctx.run_interval = run_interval
+    ctx.cancel_interval = cancel_interval
+    ctx.schedule = schedule
+    ctx.unschedule = unschedule
     ctx.send = function (recipient_name, msg)
+        check_cancelled()
         send(recipient_name, msg, ctx.thread_id)
-        return coroutine.yield("__suspended__" .. ctx.thread_id)
+        return suspend()
+    end
+    if __rich_send_results then
+        -- unwrap the result of a send, raising an error if it failed
+        ctx.send_ok = function (recipient_name, msg)
+            local res = ctx.send(recipient_name, msg)
+            if res.ok then
+                return res.value
+            end
+            local reason = res.kind
+            if res.detail ~= nil then
+                reason = reason .. ": " .. res.detail
+            end
+            error("ctx.send to " .. recipient_name .. " failed: " .. reason, 2)
+        end
+    end
+    ctx.send_correlated = function (recipient_name, msg, opts)
+        if type(msg) ~= "table" then
+            error("ctx.send_correlated expects a table message", 2)
+        end
+        check_cancelled()
+        local field, timeout = "correlation_id", nil
+        if opts ~= nil then
+            field = opts.id_field or field
+            timeout = opts.timeout
+        end
+        if __correlated_count >= __max_correlated then
+            error("too many pending ctx.send_correlated, at most " .. __max_correlated, 2)
+        end
+        local id = correlate(timeout)
+        local out = {}
+        for k, v in pairs(msg) do
+            out[k] = v
+        end
+        out[field] = id
+        local ok, err = pcall(do_send, recipient_name, out)
+        if not ok then
+            uncorrelate(id)
+            error(err, 2)
+        end
+        __correlated[id] = { thread_id = ctx.thread_id, field = field }
+        __correlated_count = __correlated_count + 1
+        __correlation_fields[field] = (__correlation_fields[field] or 0) + 1
+        return suspend(function ()
+            uncorrelated(id)
+            uncorrelate(id)
+        end)
+    end
+    ctx.wait_for = function (types, timeout)
+        if type(types) ~= "table" or #types == 0 then
+            error("ctx.wait_for expects a list of message types", 2)
+        end
+        local awaited = {}
+        for _, t in ipairs(types) do
+            if type(t) ~= "string" then
+                error("ctx.wait_for message types must be strings, got " .. type(t), 2)
+            end
+            if not awaited[t] then
+                awaited[t] = true
+                awaited[#awaited + 1] = t
+            end
+        end
+        check_cancelled()
+        local id = correlate(timeout, "ctx.wait_for")
+        __waiting[id] = { thread_id = ctx.thread_id, types = awaited }
+        __waiting_count = __waiting_count + 1
+        for _, t in ipairs(awaited) do
+            local queue = __waiters[t]
+            if queue == nil then
+                queue = {}
+                __waiters[t] = queue
+            end
+            queue[#queue + 1] = id
+        end
+        return suspend(function ()
+            unwaited(id)
+            uncorrelate(id)
+        end)
+    end
+    ctx.replace_actor = function (name, script_path, opts)
+        check_cancelled()
+        replace_actor(name, script_path, opts, ctx.thread_id)
+        return suspend()
     end
     ctx.do_send = do_send
+    ctx.do_send_confirmed = do_send_confirmed
+    ctx.set_outbox = set_outbox
+    ctx.outbox_depth = outbox_depth
     ctx.terminate = terminate
+    ctx.restart = restart
+    ctx.breaker_state = breaker_state
+    ctx.watch = watch
+    ctx.unwatch = unwatch
+    ctx.alias = alias
+    ctx.unalias = unalias
+    ctx.resolve = resolve
+    ctx.uuid = uuid
+    ctx.deprecated = function (msg)
+        warn("deprecated: " .. msg, msg)
+    end
+    for name, reason in pairs(__disabled_ctx) do
+        if ctx[name] ~= nil then
+            ctx[name] = function ()
+                error("ctx." .. name .. " " .. reason, 2)
+            end
+        end
+    end
+end
 
-    ctx.msg = msg
+-- status change of a recipient watched with `ctx.watch`, delivered to the
+-- `on_recipient_status(name, connected)` function if defined, to `handle` otherwise
+__scripts.__watch = function ()
+    local event = ctx.msg.__watch
+    local hook = rawget(_G, "on_recipient_status")
+    if hook ~= nil then
+        return hook(event.name, event.connected)
+    end
+    if __scripts.handle ~= nil then
+        return __scripts.handle()
+    end
+end
 
-    local thread = coroutine.create(__scripts[script_name])
+-- the result of the `health()` function if defined, see `Ping`
+__scripts.__health = function ()
+    local health = rawget(_G, "health")
+    if health ~= nil then
+        return health()
+    end
+end
+
+-- receipt of a `ctx.do_send_confirmed`, delivered to the
+-- `on_delivery_receipt(receipt, status)` function
+__scripts.__delivery_receipt = function ()
+    return on_delivery_receipt(ctx.msg.receipt, ctx.msg.status)
+end
+
+-- migrations of `ctx.state` by the version they migrate from, see `ctx.migration`
+__migrations = {}
+
+-- register the migration of `ctx.state` from `from_version` to the next version
+function ctx.migration(from_version, f)
+    if math.type(from_version) ~= "integer" or type(f) ~= "function" then
+        error("ctx.migration expects an integer version and a function", 2)
+    end
+    __migrations[from_version] = f
+end
 
-    local ok, ret = coroutine.resume(thread)
+-- migrate `state` from `version` to `target`, one version at a time
+local function migrate(state, version, target)
+    if version > target then
+        error(string.format("ctx.state version %d is newer than ctx.state_version %d", version, target))
+    end
+    while version < target do
+        local step = __migrations[version]
+        if step == nil then
+            error(string.format("no migration of ctx.state from version %d to %d", version, version + 1))
+        end
+        state = step(state)
+        if type(state) ~= "table" then
+            error(string.format("the migration from version %d must return the new state", version))
+        end
+        version = version + 1
+        state.__state_version = version
+    end
+    return state
+end
+
+-- run the `migrations` hook, then migrate `ctx.state` from its stored version to
+-- `ctx.state_version`, one version at a time
+__scripts.__migrate = function ()
+    __migrations = {}
+    ctx.state_version = nil
+    __scripts.migrations()
+    local target = ctx.state_version
+    if math.type(target) ~= "integer" then
+        error("the migrations hook must set ctx.state_version to an integer")
+    end
+    local state = ctx.state
+    local version = rawget(state, "__state_version")
+    if version == nil then
+        if next(state) == nil then
+            state.__state_version = target
+            return
+        end
+        version = 1
+    end
+    state = migrate(state, version, target)
+    -- keep the same table, scripts must not replace `ctx.state`
+    if state ~= ctx.state then
+        for k in pairs(ctx.state) do
+            ctx.state[k] = nil
+        end
+        for k, v in pairs(state) do
+            ctx.state[k] = v
+        end
+    end
+end
+
+-- the keys of `path` in `ctx.state`, none for the whole state
+local function state_keys(path)
+    if path == nil then
+        return {}
+    end
+    if type(path) ~= "string" then
+        error("the path of ctx.state must be a string, got " .. type(path), 3)
+    end
+    return __state_path(path)
+end
+
+-- copy the tables of `from` into `into`, the values of `from` win
+local function merge_into(into, from)
+    for k, v in pairs(from) do
+        if type(v) == "table" and type(into[k]) == "table" then
+            merge_into(into[k], v)
+        else
+            into[k] = v
+        end
+    end
+end
+
+-- encode the value at `path` of `ctx.state`, the whole state if nil, with the version of the
+-- state
+function ctx.export_state(path)
+    local value = ctx.state
+    for _, key in ipairs(state_keys(path)) do
+        if type(value) ~= "table" then
+            error("ctx.export_state: " .. path .. " goes through a " .. type(value), 2)
+        end
+        value = value[key]
+    end
+    local ok, blob = pcall(__encode_state, value, rawget(ctx.state, "__state_version"))
     if not ok then
-        error(ret)
+        error("ctx.export_state: " .. tostring(blob), 2)
     end
+    return blob
+end
+
+-- set the value of a blob of `ctx.export_state` at `path` of `ctx.state`, migrated to
+-- `ctx.state_version` if it comes from an older state
+function ctx.import_state(path, blob, opts)
+    local mode = opts ~= nil and opts.merge or "replace"
+    if mode ~= "replace" and mode ~= "merge" then
+        error("ctx.import_state: merge must be \"replace\" or \"merge\", got " .. tostring(mode), 2)
+    end
+    local keys = state_keys(path)
+    local ok, value, version = pcall(__decode_state, blob)
+    if not ok then
+        error("ctx.import_state: " .. tostring(value), 2)
+    end
+    local target = ctx.state_version
+    if version ~= nil and math.type(target) == "integer" and version ~= target then
+        -- the migrations see a state holding only the imported value
+        local state = { __state_version = version }
+        if #keys == 0 then
+            state = value
+        else
+            local t = state
+            for i = 1, #keys - 1 do
+                t[keys[i]] = {}
+                t = t[keys[i]]
+            end
+            t[keys[#keys]] = value
+        end
+        state = migrate(state, version, target)
+        value = state
+        for _, key in ipairs(keys) do
+            value = type(value) == "table" and value[key] or nil
+        end
+    end
+    if #keys == 0 then
+        if type(value) ~= "table" then
+            error("ctx.import_state: the state must be a table, got " .. type(value), 2)
+        end
+        if mode == "replace" then
+            for k in pairs(ctx.state) do
+                ctx.state[k] = nil
+            end
+        end
+        merge_into(ctx.state, value)
+        return
+    end
+    local t = ctx.state
+    for i = 1, #keys - 1 do
+        local key = keys[i]
+        if t[key] == nil then
+            t[key] = {}
+        elseif type(t[key]) ~= "table" then
+            error("ctx.import_state: " .. path .. " goes through a " .. type(t[key]), 2)
+        end
+        t = t[key]
+    end
+    local last = keys[#keys]
+    if mode == "merge" and type(value) == "table" and type(t[last]) == "table" then
+        merge_into(t[last], value)
+    else
+        t[last] = value
+    end
+end
+
+local function check_state(state)
+    if ctx.state ~= state then
+        if __read_only[state] ~= nil and not no_growth[state] then
+            ctx.state = state
+            warn("state_replaced", "ctx.state is read-only in this actor, its replacement was dropped")
+            return
+        end
+        warn("state_replaced", "ctx.state was replaced, assign its fields instead")
+    end
+end
+
+-- the id of the pending `ctx.send_correlated` `msg` replies to, if any
+local function correlation_id(msg)
+    if type(msg) ~= "table" then
+        return nil
+    end
+    for field in pairs(__correlation_fields) do
+        local id = msg[field]
+        local matcher = id ~= nil and __correlated[id]
+        if matcher and matcher.field == field then
+            return id
+        end
+    end
+    return nil
+end
+
+-- resume the coroutine of the timed out `ctx.send_correlated` or `ctx.wait_for` `id` with `err`
+function __correlation_timeout(id, err)
+    if __waiting[id] ~= nil then
+        return __resume(unwaited(id).thread_id, err)
+    end
+    local group = __joining[id]
+    if group ~= nil then
+        __joining[id] = nil
+        local joiner = group.joiner
+        group.joiner = nil
+        return __resume(joiner.thread_id, group_results(group, { ok = false, error = "timeout" }))
+    end
+    -- the reply arrived first
+    if __correlated[id] == nil then
+        return nil
+    end
+    return __resume(uncorrelated(id).thread_id, err)
+end
+
+-- drop the pending `ctx.send_correlated` and `ctx.wait_for` and their coroutines
+-- resume the suspended coroutines with an "actor restarted" error, in the order they started,
+-- before `ctx.restart` drops the VM
+function __fail_threads()
+    local ids = {}
+    for id in pairs(__threads) do
+        table.insert(ids, id)
+    end
+    table.sort(ids)
+    for _, id in ipairs(ids) do
+        -- the coroutines are expected to fail with the error
+        pcall(__resume, id, RESTARTED)
+    end
+end
+
+function __clear_correlations()
+    for _, matcher in pairs(__correlated) do
+        __threads[matcher.thread_id] = nil
+    end
+    __correlated = {}
+    __correlated_count = 0
+    __correlation_fields = {}
+    for _, waiter in pairs(__waiting) do
+        __threads[waiter.thread_id] = nil
+    end
+    __waiting = {}
+    __waiting_count = 0
+    __waiters = {}
+    for _, group in pairs(__joining) do
+        __threads[group.joiner.thread_id] = nil
+    end
+    __joining = {}
+end
+
+-- the workflows which finished since Rust last read them, see `LuaRuntime::take_finished_workflows`
+__finished_workflows = { completed = 0, failed = 0 }
+
+-- run `f` in a coroutine, returning whether it succeeded and its result. The coroutine is a
+-- workflow if `workflow`, i.e. nobody reads its result, see `__resume`
+local function run_thread(f, msg, reply_expected, workflow)
+    if __check_scratch then
+        local path = __find_scratch(ctx.state, "ctx.state", {})
+        if path ~= nil then
+            error("ctx.scratch must not be stored in ctx.state, found in " .. path)
+        end
+    end
+    ctx.thread_id = __thread_id_seq
+    __thread_id_seq = __thread_id_seq + 1
+
+    __bind_ctx()
+
+    if __frozen_msg and type(msg) == "table" then
+        msg = read_only_view(msg, MSG_READ_ONLY)
+    end
+    ctx.msg = msg
+    ctx.reply_expected = reply_expected
+    local scratch = __acquire_scratch()
+    ctx.scratch = scratch
+
+    local thread = acquire_coroutine()
+
+    local state = ctx.state
+    local done, ok, ret = resume_coroutine(thread, f)
+    check_state(state)
     -- save the thread and its context if the thread yielded
-    if coroutine.status(thread) == "suspended" then
-        __threads[ctx.thread_id] = { thread = thread, msg = msg }
+    if ok and not done then
+        __threads[ctx.thread_id] = {
+            thread = thread, msg = msg, scratch = scratch, workflow = workflow
+        }
+    else
+        __release_scratch(scratch)
     end
     ctx.msg = nil
+    ctx.reply_expected = nil
     ctx.thread_id = nil
+    ctx.scratch = nil
+    return ok, ret
+end
+
+local function start_member(member)
+    if member.cancelled then
+        finish_member(member, { ok = false, error = "cancelled" })
+        return
+    end
+    local ok, err = run_thread(function ()
+        member.thread_id = ctx.thread_id
+        __members[ctx.thread_id] = member
+        local value = member.fn()
+        __members[member.thread_id] = nil
+        finish_member(member, { ok = true, value = value })
+    end, nil, false)
+    -- the errors of members are their result, see also `__resume`
+    if not ok then
+        __members[member.thread_id] = nil
+        finish_member(member, { ok = false, error = err })
+    end
+end
+
+-- start the `ctx.spawn_group` members and resume the coroutines queued in `__ready`, one after
+-- the other
+local function drain_ready()
+    if __draining then
+        return
+    end
+    __draining = true
+    local ok, err = pcall(function ()
+        while #__ready > 0 do
+            local item = table.remove(__ready, 1)
+            if item.start ~= nil then
+                start_member(item.start)
+            else
+                __resume(item.thread_id, item.value)
+            end
+        end
+    end)
+    __draining = false
+    if not ok then
+        error(err, 0)
+    end
+end
+
+-- cancel the groups of `ctx.spawn_group` when the actor stops, returning the number of
+-- members which didn't finish
+function __cancel_groups()
+    local abandoned = 0
+    for group in pairs(__groups) do
+        abandoned = abandoned + group.pending
+        group:cancel()
+    end
+    drain_ready()
+    return abandoned
+end
+
+-- drop the suspended coroutines, e.g. when a supervisor restarts the actor in the same VM
+function __clear_threads()
+    for _, thread in pairs(__threads) do
+        __release_scratch(thread.scratch)
+    end
+    __threads = {}
+    __ready = {}
+end
+
+-- run given script in a coroutine
+function __run(script_name, msg, reply_expected)
+    -- replies of `ctx.send_correlated` resume their coroutine instead of running `handle`
+    if script_name == "handle" and __correlated_count > 0 then
+        local id = correlation_id(msg)
+        if id ~= nil then
+            local matcher = uncorrelated(id)
+            uncorrelate(id)
+            if __rich_send_results then
+                msg = { ok = true, value = msg }
+            end
+            return __resume(matcher.thread_id, msg)
+        end
+    end
+    -- messages awaited by `ctx.wait_for` resume the oldest waiter instead of running `handle`
+    if script_name == "handle" and __waiting_count > 0 and type(msg) == "table" then
+        local queue = __waiters[msg.type]
+        if queue ~= nil then
+            local id = queue[1]
+            local waiter = unwaited(id)
+            uncorrelate(id)
+            if __rich_send_results then
+                msg = { ok = true, value = msg }
+            end
+            return __resume(waiter.thread_id, msg)
+        end
+    end
+    -- hooks are optional
+    if __scripts[script_name] == nil then
+        return nil
+    end
+    local workflow = script_name == "handle" and not reply_expected
+    local ok, ret = run_thread(__scripts[script_name], msg, reply_expected, workflow)
+    drain_ready()
+    if not ok then
+        -- the error already has the position in the script
+        error(ret, 0)
+    end
     return ret
 end
 
--- resume a existing coroutine
-function __resume(thread_id, args)
+-- resume a existing coroutine, `err` is the error of a failed `ctx.send`
+function __resume(thread_id, args, err)
     local thread = __threads[thread_id]
+    -- the thread was dropped, e.g. the actor restarted, or it's running, e.g. resumed twice
+    if thread == nil or coroutine.status(thread.thread) ~= "suspended" then
+        return nil
+    end
     ctx.thread_id = thread_id
     ctx.msg = thread.msg
-    local ok, ret = coroutine.resume(thread.thread, args)
-    if coroutine.status(thread.thread) == "dead" then
+    -- the reply of the original message was sent when the thread yielded
+    ctx.reply_expected = false
+    ctx.scratch = thread.scratch
+    __bind_ctx()
+    local state = ctx.state
+    local done, ok, ret = resume_coroutine(thread.thread, args, err)
+    check_state(state)
+    if done or not ok then
         __threads[ctx.thread_id] = nil
+        __release_scratch(thread.scratch)
+    end
+    local member = __members[thread_id]
+    if not ok and member ~= nil then
+        __members[thread_id] = nil
+        finish_member(member, { ok = false, error = ret })
+        ok, ret = true, nil
     end
     ctx.msg = nil
+    ctx.reply_expected = nil
     ctx.thread_id = nil
+    ctx.scratch = nil
+    if thread.workflow and not ok then
+        __finished_workflows.failed = __finished_workflows.failed + 1
+    elseif thread.workflow and done then
+        __finished_workflows.completed = __finished_workflows.completed + 1
+        local hook = rawget(_G, "on_workflow_complete")
+        if type(hook) == "function" then
+            local value = ret
+            ok, ret = run_thread(function () return hook(value) end, thread.msg, false)
+        end
+    end
+    drain_ready()
+    if not ok then
+        error(ret, 0)
+    end
     return ret
 end
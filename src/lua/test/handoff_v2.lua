@@ -0,0 +1,6 @@
+ctx.state.seen = ctx.state.seen or {}
+if ctx.msg == "seen" then
+    return ctx.state.seen
+end
+table.insert(ctx.state.seen, ctx.msg)
+return "v2:" .. ctx.msg
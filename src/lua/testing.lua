@@ -0,0 +1,69 @@
+-- run the `handle` script like a message sent with `send`, `ctx.send` gets nil replies
+declare_global("__test_handle", function (msg, reply_expected)
+    local ret = __run("handle", msg, reply_expected)
+    while type(ret) == "string" and string.sub(ret, 1, 13) == "__suspended__" do
+        ret = __resume(tonumber(string.sub(ret, 14)), nil)
+    end
+    return ret
+end)
+
+-- handle the pending notifications, including the ones sent while draining
+declare_global("__test_drain", function ()
+    while true do
+        local found, msg = __test_next_notification()
+        if not found then
+            return
+        end
+        __test_handle(msg, false)
+    end
+end)
+
+declare_global("__test_start", function ()
+    local ret = __run("started", nil, false)
+    while type(ret) == "string" and string.sub(ret, 1, 13) == "__suspended__" do
+        ret = __resume(tonumber(string.sub(ret, 14)), nil)
+    end
+    __test_drain()
+end)
+
+declare_global("__test_call", function (name)
+    __test_env[name]()
+end)
+
+local function equal(a, b)
+    if a == b then
+        return true
+    end
+    if type(a) ~= "table" or type(b) ~= "table" then
+        return false
+    end
+    for k, v in pairs(a) do
+        if not equal(v, b[k]) then
+            return false
+        end
+    end
+    for k, _ in pairs(b) do
+        if a[k] == nil then
+            return false
+        end
+    end
+    return true
+end
+
+function ctx.assert_eq(actual, expected, msg)
+    if not equal(actual, expected) then
+        error((msg or "assertion failed") .. ": expected " .. tostring(expected) ..
+            ", got " .. tostring(actual), 2)
+    end
+end
+
+function ctx.send_self(msg)
+    local ret = __test_handle(msg, true)
+    __test_drain()
+    return ret
+end
+
+function ctx.advance_time(seconds)
+    __test_advance(seconds)
+    __test_drain()
+end
@@ -0,0 +1,175 @@
+use rlua::{Context, Error as LuaError, Table, Value};
+
+use crate::runtime::{ApiEntry, ApiValue, CtxFn, Requires};
+
+const fn value(name: &'static str, ty: &'static str) -> ApiValue {
+    ApiValue {
+        name,
+        ty,
+        optional: false,
+    }
+}
+
+const CHECKED_PARAMS: &[ApiValue] = &[value("a", "integer"), value("b", "integer")];
+const CHECKED_RETURNS: &[ApiValue] = &[value("value", "integer|nil"), value("overflow", "boolean")];
+
+const fn checked(name: &'static str, signature: &'static str) -> ApiEntry {
+    ApiEntry {
+        name,
+        signature,
+        description: "`value, false`, or `nil, true` on overflow",
+        availability: "",
+        params: CHECKED_PARAMS,
+        returns: CHECKED_RETURNS,
+        fields: &[],
+    }
+}
+
+// The functions of `ctx.math`, see `CTX_MATH`.
+pub(crate) const MATH_API: &[ApiEntry] = &[
+    checked(
+        "checked_add",
+        "local sum, overflow = ctx.math.checked_add(a, b)",
+    ),
+    checked(
+        "checked_sub",
+        "local difference, overflow = ctx.math.checked_sub(a, b)",
+    ),
+    checked(
+        "checked_mul",
+        "local product, overflow = ctx.math.checked_mul(a, b)",
+    ),
+    ApiEntry {
+        name: "clamp",
+        signature: "local y = ctx.math.clamp(x, lo, hi)",
+        description: "`x` within `[lo, hi]`, an integer if all the arguments are",
+        availability: "",
+        params: &[
+            value("x", "number"),
+            value("lo", "number"),
+            value("hi", "number"),
+        ],
+        returns: &[value("y", "number")],
+        fields: &[],
+    },
+];
+
+pub(crate) const CTX_MATH: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "math",
+        signature: "ctx.math.checked_add/checked_sub/checked_mul(a, b), ctx.math.clamp(x, lo, hi)",
+        description: "integer arithmetic returning `value, false` or `nil, true` on overflow",
+        availability: "",
+        params: &[],
+        returns: &[],
+        fields: MATH_API,
+    },
+    requires: Requires::Always,
+};
+
+// `ctx.math`: arithmetic which doesn't silently overflow.
+pub(crate) fn math_table(ctx: Context) -> Result<Table, LuaError> {
+    let math = ctx.create_table()?;
+    // `value, false`, or `nil, true` on overflow
+    let checked = |op: fn(i64, i64) -> Option<i64>| {
+        ctx.create_function(move |_, (a, b): (i64, i64)| {
+            Ok(match op(a, b) {
+                Some(value) => (Some(value), false),
+                None => (None, true),
+            })
+        })
+    };
+    math.set("checked_add", checked(i64::checked_add)?)?;
+    math.set("checked_sub", checked(i64::checked_sub)?)?;
+    math.set("checked_mul", checked(i64::checked_mul)?)?;
+    math.set("clamp", ctx.create_function(clamp)?)?;
+    Ok(math)
+}
+
+// Integers if all the arguments are, floats otherwise.
+fn clamp<'lua>(
+    _: Context<'lua>,
+    (x, lo, hi): (Value<'lua>, Value<'lua>, Value<'lua>),
+) -> Result<Value<'lua>, LuaError> {
+    if let (Value::Integer(x), Value::Integer(lo), Value::Integer(hi)) = (&x, &lo, &hi) {
+        check_bounds(lo, hi)?;
+        return Ok(Value::Integer(*x.max(lo).min(hi)));
+    }
+    let (x, lo, hi) = (number(x)?, number(lo)?, number(hi)?);
+    check_bounds(&lo, &hi)?;
+    Ok(Value::Number(x.max(lo).min(hi)))
+}
+
+fn check_bounds<T: PartialOrd + std::fmt::Display>(lo: &T, hi: &T) -> Result<(), LuaError> {
+    if lo > hi {
+        return Err(LuaError::RuntimeError(format!(
+            "ctx.math.clamp: lower bound {} is greater than upper bound {}",
+            lo, hi
+        )));
+    }
+    Ok(())
+}
+
+fn number(v: Value) -> Result<f64, LuaError> {
+    match v {
+        Value::Integer(n) => Ok(n as f64),
+        Value::Number(n) => Ok(n),
+        _ => Err(LuaError::RuntimeError(
+            "ctx.math.clamp expects numbers".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlua::Lua;
+
+    fn eval<R: for<'lua> rlua::FromLuaMulti<'lua>>(script: &str) -> Result<R, LuaError> {
+        Lua::new().context(|ctx| {
+            ctx.globals().set("math2", math_table(ctx)?)?;
+            ctx.load(script).eval()
+        })
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        let ok: (i64, bool) = eval("return math2.checked_add(math.maxinteger - 1, 1)").unwrap();
+        assert_eq!(ok, (i64::MAX, false));
+        let overflow: (Option<i64>, bool) =
+            eval("return math2.checked_add(math.maxinteger, 1)").unwrap();
+        assert_eq!(overflow, (None, true));
+
+        let ok: (i64, bool) = eval("return math2.checked_sub(math.mininteger + 1, 1)").unwrap();
+        assert_eq!(ok, (i64::MIN, false));
+        let overflow: (Option<i64>, bool) =
+            eval("return math2.checked_sub(math.mininteger, 1)").unwrap();
+        assert_eq!(overflow, (None, true));
+
+        let ok: (i64, bool) = eval("return math2.checked_mul(-1, math.maxinteger)").unwrap();
+        assert_eq!(ok, (-i64::MAX, false));
+        let overflow: (Option<i64>, bool) =
+            eval("return math2.checked_mul(-1, math.mininteger)").unwrap();
+        assert_eq!(overflow, (None, true));
+        let overflow: (Option<i64>, bool) =
+            eval("return math2.checked_mul(4294967296, 2147483648)").unwrap();
+        assert_eq!(overflow, (None, true));
+    }
+
+    #[test]
+    fn clamp() {
+        let n: i64 = eval("return math2.clamp(math.maxinteger, 0, 100)").unwrap();
+        assert_eq!(n, 100);
+        let n: i64 = eval("return math2.clamp(math.mininteger, 0, 100)").unwrap();
+        assert_eq!(n, 0);
+        let n: i64 = eval("return math2.clamp(42, math.mininteger, math.maxinteger)").unwrap();
+        assert_eq!(n, 42);
+        let (n, t): (f64, String) =
+            eval("local n = math2.clamp(1.5, 0, 1) return n, math.type(n)").unwrap();
+        assert_eq!((n, t.as_str()), (1.0, "float"));
+
+        let err = eval::<i64>("return math2.clamp(1, 10, 0)").unwrap_err();
+        assert!(format!("{:?}", err).contains("lower bound 10"), "{:?}", err);
+        assert!(eval::<i64>("return math2.clamp('1', 0, 10)").is_err());
+    }
+}
@@ -1,12 +1,17 @@
 use ::actix::dev::{MessageResponse, ResponseChannel};
 use ::actix::prelude::*;
-use regex::Regex;
 use rlua::Result as LuaResult;
-use rlua::{Context, FromLua, ToLua, Value};
+use rlua::{Context, Error as LuaError, FromLua, LightUserData, Table, ToLua, UserData, Value};
 
+use crate::lazy::lazy_message;
+use crate::opaque::OpaqueHandle;
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::fmt;
+use std::os::raw::c_void;
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum LuaMessage {
     String(String),
     Integer(i64),
@@ -14,7 +19,282 @@ pub enum LuaMessage {
     Boolean(bool),
     Nil,
     Table(HashMap<String, LuaMessage>),
+    /// The reply to a message whose `handle` script suspended in `ctx.send`, with the id of
+    /// the coroutine. Only with the `legacy-thread-yield` feature, without it the reply is `Nil`.
+    #[cfg(feature = "legacy-thread-yield")]
+    #[deprecated(note = "suspended scripts are handled inside LuaActor, expect `Nil` instead")]
     ThreadYield(String),
+    /// Handle of a Rust value in the actor's `OpaqueRegistry`.
+    Opaque(u64),
+    /// A Lua sequence `{v1, v2, ...}`. Tables whose keys are exactly the integers `1` to `n`
+    /// are converted to `Array`, other tables to `Table`, see `ConversionOptions`. `Nil` elements are converted to
+    /// `ctx.null`, like the `Nil` values of a `Table`.
+    Array(Vec<LuaMessage>),
+    /// The reply to a message whose `handle` script raised an error, with the Lua stack
+    /// traceback if there is one. Converted to a table `{message = ..., traceback = ...}` in
+    /// Lua.
+    Error {
+        message: String,
+        traceback: Option<String>,
+    },
+}
+
+// Address of this static identifies the `ctx.null` sentinel in every VM.
+static NULL_SENTINEL: u8 = 0;
+
+/// Lua value of `ctx.null`, which represents `LuaMessage::Nil` values in tables.
+///
+/// Lua tables can't store `nil`, so `Nil` values of a `Table` are converted to `ctx.null` and
+/// back to `Nil`, which keeps the key present.
+pub(crate) fn null_sentinel<'lua>() -> Value<'lua> {
+    Value::LightUserData(LightUserData(&NULL_SENTINEL as *const u8 as *mut c_void))
+}
+
+fn is_null_sentinel(v: &Value) -> bool {
+    match (v, null_sentinel()) {
+        (Value::LightUserData(a), Value::LightUserData(b)) => *a == b,
+        _ => false,
+    }
+}
+
+impl LuaMessage {
+    /// Get the value of `key` if `self` is a `Table`, or the element at the index `key`,
+    /// starting at `"1"`, if `self` is an `Array`.
+    ///
+    /// Returns `None` if the key is absent and `Some(&LuaMessage::Nil)` if it's present with
+    /// a `Nil` value.
+    pub fn get(&self, key: &str) -> Option<&LuaMessage> {
+        match self {
+            LuaMessage::Table(t) => t.get(key),
+            LuaMessage::Array(a) => match key.parse::<usize>() {
+                Ok(i) if i > 0 => a.get(i - 1),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether `self` is the `Error` reply of a failed script.
+    pub fn is_error(&self) -> bool {
+        matches!(self, LuaMessage::Error { .. })
+    }
+
+    // The `Error` reply for a script error, separating the message from the traceback.
+    pub(crate) fn from_script_error(e: &LuaError) -> LuaMessage {
+        let (message, traceback) = match e {
+            LuaError::RuntimeError(e) => match e.split_once("\nstack traceback:\n") {
+                Some((message, traceback)) => (message.to_string(), Some(traceback.to_string())),
+                None => (e.clone(), None),
+            },
+            LuaError::CallbackError { traceback, cause } => (
+                cause.to_string(),
+                Some(
+                    traceback
+                        .strip_prefix("stack traceback:\n")
+                        .unwrap_or(traceback)
+                        .to_string(),
+                ),
+            ),
+            e => (e.to_string(), None),
+        };
+        LuaMessage::Error { message, traceback }
+    }
+
+    /// Whether `self` is an `Opaque` handle or a `Table` or `Array` containing one.
+    pub fn contains_opaque(&self) -> bool {
+        match self {
+            LuaMessage::Opaque(_) => true,
+            LuaMessage::Table(t) => t.values().any(LuaMessage::contains_opaque),
+            LuaMessage::Array(a) => a.iter().any(LuaMessage::contains_opaque),
+            _ => false,
+        }
+    }
+}
+
+// Keys which can be written `key = value` in a Lua table constructor.
+fn is_identifier(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Sort table keys in a stable order, numeric keys first by value.
+pub(crate) fn sort_keys(keys: &mut [&String]) {
+    keys.sort_by(|a, b| match (a.parse::<i64>(), b.parse::<i64>()) {
+        (Ok(x), Ok(y)) => x.cmp(&y).then(a.cmp(b)),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    });
+}
+
+// Words which can't be table keys in `key = value` form.
+const LUA_KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+// Write `s` as a double quoted Lua string, escaping the quotes, backslashes, control
+// characters and non-ASCII bytes.
+fn write_lua_string(s: &str, out: &mut String) {
+    out.push('"');
+    for b in s.bytes() {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7e => out.push(b as char),
+            b => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    out.push('"');
+}
+
+impl LuaMessage {
+    /// Write `self` as the source of a Lua expression which evaluates to an equivalent value.
+    ///
+    /// Strings are escaped to printable ASCII, integers and floats keep their type and exact
+    /// value, including `-0.0`, infinities and NaN, and the keys of tables are sorted like
+    /// with `Display`. `Nil` values of tables are written `ctx.null`, which the scripts of an
+    /// actor have. An `Error` is written as the table the scripts see, and an empty `Array`
+    /// reads back as an empty `Table`.
+    ///
+    /// Fails for `Opaque` handles, which only exist in their registry, and for tables nested
+    /// deeper than the default `ConversionLimits::max_depth`.
+    pub fn to_lua_literal(&self) -> LuaResult<String> {
+        let mut out = String::new();
+        self.write_lua_literal(&mut out, 0)?;
+        Ok(out)
+    }
+
+    fn write_lua_literal(&self, out: &mut String, depth: usize) -> LuaResult<()> {
+        let max_depth = ConversionLimits::default().max_depth;
+        match self {
+            LuaMessage::String(s) => write_lua_string(s, out),
+            // the literal would be read as a float, its negation too
+            LuaMessage::Integer(i64::MIN) => out.push_str("(-9223372036854775807 - 1)"),
+            LuaMessage::Integer(n) => out.push_str(&n.to_string()),
+            LuaMessage::Number(n) if n.is_nan() => out.push_str("(0/0)"),
+            LuaMessage::Number(n) if n.is_infinite() => {
+                out.push_str(if *n > 0.0 { "(1/0)" } else { "(-1/0)" })
+            }
+            // the shortest representation which reads back exactly, always with a `.` or an
+            // exponent so that it stays a float
+            LuaMessage::Number(n) => out.push_str(&format!("{:?}", n)),
+            LuaMessage::Boolean(b) => out.push_str(&b.to_string()),
+            LuaMessage::Nil if depth > 0 => out.push_str("ctx.null"),
+            LuaMessage::Nil => out.push_str("nil"),
+            LuaMessage::Table(_) | LuaMessage::Array(_) | LuaMessage::Error { .. }
+                if depth >= max_depth =>
+            {
+                return Err(LuaError::RuntimeError(format!(
+                    "table nested deeper than {} levels, can't write it as a Lua literal",
+                    max_depth
+                )));
+            }
+            LuaMessage::Table(t) => {
+                let mut keys: Vec<_> = t.keys().collect();
+                sort_keys(&mut keys);
+                out.push('{');
+                for (i, k) in keys.into_iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    if is_identifier(k) && !LUA_KEYWORDS.contains(&k.as_str()) {
+                        out.push_str(k);
+                    } else {
+                        out.push('[');
+                        write_lua_string(k, out);
+                        out.push(']');
+                    }
+                    out.push_str(" = ");
+                    t[k].write_lua_literal(out, depth + 1)?;
+                }
+                out.push('}');
+            }
+            LuaMessage::Array(a) => {
+                out.push('{');
+                for (i, v) in a.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    v.write_lua_literal(out, depth + 1)?;
+                }
+                out.push('}');
+            }
+            LuaMessage::Error { message, traceback } => {
+                out.push_str("{message = ");
+                write_lua_string(message, out);
+                if let Some(traceback) = traceback {
+                    out.push_str(", traceback = ");
+                    write_lua_string(traceback, out);
+                }
+                out.push('}');
+            }
+            LuaMessage::Opaque(id) => {
+                return Err(LuaError::RuntimeError(format!(
+                    "opaque handle {} can't be written as a Lua literal",
+                    id
+                )));
+            }
+            #[cfg(feature = "legacy-thread-yield")]
+            #[allow(deprecated)]
+            LuaMessage::ThreadYield(_) => {
+                return Err(LuaError::RuntimeError(
+                    "ThreadYield can't be written as a Lua literal".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats messages like Lua literals, with the keys of tables sorted.
+impl fmt::Display for LuaMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LuaMessage::String(s) => write!(f, "{:?}", s),
+            LuaMessage::Integer(n) => write!(f, "{}", n),
+            LuaMessage::Number(n) => write!(f, "{:?}", n),
+            LuaMessage::Boolean(b) => write!(f, "{}", b),
+            LuaMessage::Nil => write!(f, "nil"),
+            LuaMessage::Table(t) => {
+                let mut keys: Vec<_> = t.keys().collect();
+                sort_keys(&mut keys);
+                write!(f, "{{")?;
+                for (i, k) in keys.into_iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    if is_identifier(k) {
+                        write!(f, "{} = {}", k, t[k])?;
+                    } else {
+                        write!(f, "[{:?}] = {}", k, t[k])?;
+                    }
+                }
+                write!(f, "}}")
+            }
+            #[cfg(feature = "legacy-thread-yield")]
+            #[allow(deprecated)]
+            LuaMessage::ThreadYield(id) => write!(f, "<thread yield {}>", id),
+            LuaMessage::Opaque(id) => write!(f, "<opaque {}>", id),
+            LuaMessage::Error { message, .. } => write!(f, "<error {:?}>", message),
+            LuaMessage::Array(a) => {
+                write!(f, "{{")?;
+                for (i, v) in a.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
 }
 
 impl<A, M> MessageResponse<A, M> for LuaMessage
@@ -87,6 +367,12 @@ impl From<HashMap<String, LuaMessage>> for LuaMessage {
     }
 }
 
+impl<T: Into<LuaMessage>> From<Vec<T>> for LuaMessage {
+    fn from(s: Vec<T>) -> Self {
+        LuaMessage::Array(s.into_iter().map(Into::into).collect())
+    }
+}
+
 macro_rules! lua_message_convert_float {
     ($x:ty) => {
         impl From<$x> for LuaMessage {
@@ -100,48 +386,429 @@ macro_rules! lua_message_convert_float {
 lua_message_convert_float!(f32);
 lua_message_convert_float!(f64);
 
-impl<'lua> FromLua<'lua> for LuaMessage {
-    fn from_lua(v: Value<'lua>, ctx: Context<'lua>) -> LuaResult<LuaMessage> {
+/// Limits applied when converting between `LuaMessage` and Lua values.
+///
+/// The limits of a `LuaActor` apply to every message passed to or returned by it, they're the
+/// default limits unless configured with `LuaActorBuilder::with_max_table_entries` or
+/// `LuaActorBuilder::with_overflow_errors`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionLimits {
+    /// maximum nesting of tables, exceeding it is an error. Cyclic tables always exceed it.
+    pub max_depth: usize,
+    /// maximum number of entries of a table, counting the entries of nested tables. `None`
+    /// disables the limit.
+    ///
+    /// Converting a huge table blocks the actor's thread, send large data in several messages
+    /// instead.
+    pub max_table_entries: Option<usize>,
+    /// fail to convert integral floats beyond 2^53, which can't be represented exactly. They
+    /// usually come from integer arithmetic which overflowed to floats.
+    pub overflow_errors: bool,
+    /// convert the floats with an integral value up to 2^53 like integers, including table
+    /// keys, for scripts written for Lua builds where every number is a float, e.g. Lua 5.1
+    /// and LuaJIT: `{1.0, 2.0}` becomes the `Array` `[1, 2]` instead of a `Table` keyed by
+    /// `"1.0"` and `"2.0"`. Integers are passed to Lua as integers either way. Enabled by
+    /// `LuaActorBuilder::with_compat_shims`.
+    pub integral_floats: bool,
+    /// which tables are converted to `Array`, see `LuaActorBuilder::with_conversion_options`
+    pub options: ConversionOptions,
+}
+
+impl Default for ConversionLimits {
+    fn default() -> ConversionLimits {
+        ConversionLimits {
+            max_depth: 128,
+            max_table_entries: Some(100_000),
+            overflow_errors: false,
+            integral_floats: false,
+            options: ConversionOptions::default(),
+        }
+    }
+}
+
+/// Which Lua tables are converted to `LuaMessage::Array`.
+///
+/// By default, only the tables keyed by exactly the integers `1` to `n` are. Lua 5.3 stores
+/// a float key with an integral value as the integer key, `t[1.0]` is `t[1]`, so such keys
+/// always count as integer keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionOptions {
+    /// what to do with the tables keyed by the integers `0` to `n`
+    pub zero_based: ZeroBased,
+    /// the longest run of missing integer keys of a table converted to `Array`, the missing
+    /// elements being `Nil`. A table with a longer gap is converted to `Table`. Default to 0,
+    /// e.g. 2 converts `{[1] = "a", [4] = "b"}` to `["a", Nil, Nil, "b"]`.
+    pub max_gap: usize,
+}
+
+impl Default for ConversionOptions {
+    fn default() -> ConversionOptions {
+        ConversionOptions {
+            zero_based: ZeroBased::Table,
+            max_gap: 0,
+        }
+    }
+}
+
+/// What to do with a Lua table keyed by the integers `0` to `n`, see `ConversionOptions`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZeroBased {
+    /// convert it to a `Table` keyed by `"0"` to `"n"`, the default
+    Table,
+    /// convert it to an `Array` starting with the element `0`. The offset isn't kept, the
+    /// `Array` is indexed from 1 in Lua.
+    Shift,
+    /// fail the conversion with an error
+    Reject,
+}
+
+impl UserData for ConversionLimits {}
+
+// Key of the limits of a VM in its registry.
+pub(crate) const LIMITS_KEY: &str = "__conversion_limits";
+
+// Limits of the VM of `ctx`, the default limits if none were set.
+pub(crate) fn vm_limits(ctx: Context) -> ConversionLimits {
+    ctx.named_registry_value(LIMITS_KEY).unwrap_or_default()
+}
+
+// Name of the class registered with `ctx.register_class` whose instance is `t`.
+fn class_name<'lua>(ctx: Context<'lua>, t: &Table<'lua>) -> LuaResult<Option<String>> {
+    let meta = match t.get_metatable() {
+        Some(meta) => meta,
+        None => return Ok(None),
+    };
+    match ctx.globals().raw_get::<_, Option<Table>>("__class_names")? {
+        Some(names) => names.raw_get(meta),
+        None => Ok(None),
+    }
+}
+
+// Metatable of the class `name` registered with `ctx.register_class`.
+pub(crate) fn class<'lua>(ctx: Context<'lua>, name: &str) -> LuaResult<Option<Table<'lua>>> {
+    match ctx.globals().raw_get::<_, Option<Table>>("__classes")? {
+        Some(classes) => classes.raw_get(name),
+        None => Ok(None),
+    }
+}
+
+// Integers above are not all representable as f64.
+const MAX_EXACT: f64 = 9_007_199_254_740_992.0;
+
+// State of a conversion: the tables being converted and the number of entries so far.
+pub(crate) struct Walk<'a> {
+    limits: &'a ConversionLimits,
+    entries: usize,
+    pub path: Vec<String>,
+}
+
+impl<'a> Walk<'a> {
+    pub fn new(limits: &'a ConversionLimits) -> Walk<'a> {
+        Walk {
+            limits,
+            entries: 0,
+            path: Vec::new(),
+        }
+    }
+
+    pub fn check_number(&self, n: f64) -> LuaResult<()> {
+        if self.limits.overflow_errors && n.fract() == 0.0 && n.abs() > MAX_EXACT {
+            return Err(LuaError::RuntimeError(format!(
+                "number {} is too large to be represented exactly, did an integer overflow?",
+                n
+            )));
+        }
+        Ok(())
+    }
+
+    // `v`, as an integer if it's an integral float and `integral_floats` is set.
+    pub fn integral<'lua>(&self, v: Value<'lua>) -> Value<'lua> {
         match v {
-            Value::String(x) => {
-                let re = Regex::new(r"__suspended__(.+)").unwrap();
-                let s = Value::String(x);
-                if let Some(cap) = re.captures(&String::from_lua(s.clone(), ctx)?) {
-                    let tid = cap.get(1).unwrap().as_str();
-                    Ok(LuaMessage::ThreadYield(tid.to_string()))
-                } else {
-                    Ok(LuaMessage::String(String::from_lua(s.clone(), ctx)?))
-                }
-            }
-            Value::Integer(n) => Ok(LuaMessage::Integer(n as i64)),
-            Value::Number(n) => Ok(LuaMessage::Number(n as f64)),
+            Value::Number(n)
+                if self.limits.integral_floats && n.fract() == 0.0 && n.abs() <= MAX_EXACT =>
+            {
+                Value::Integer(n as i64)
+            }
+            v => v,
+        }
+    }
+
+    pub fn check_depth(&self) -> LuaResult<()> {
+        if self.path.len() >= self.limits.max_depth {
+            return Err(LuaError::RuntimeError(format!(
+                "table nested deeper than {} levels, is it cyclic?",
+                self.limits.max_depth
+            )));
+        }
+        Ok(())
+    }
+
+    // The values of a table keyed by integers, in order, or the table if its keys aren't a
+    // sequence allowed by the `ConversionOptions`.
+    pub fn sequence(
+        &self,
+        map: HashMap<String, LuaMessage>,
+    ) -> LuaResult<Result<Vec<LuaMessage>, HashMap<String, LuaMessage>>> {
+        let options = &self.limits.options;
+        let mut indices = match map
+            .keys()
+            .map(|k| k.parse::<usize>().ok())
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(indices) if !indices.is_empty() => indices,
+            _ => return Ok(Err(map)),
+        };
+        indices.sort_unstable();
+        let base = if indices[0] == 0 { 0 } else { 1 };
+        let mut next = base;
+        for &i in &indices {
+            if i - next > options.max_gap {
+                return Ok(Err(map));
+            }
+            next = i + 1;
+        }
+        if base == 0 {
+            match options.zero_based {
+                ZeroBased::Table => return Ok(Err(map)),
+                ZeroBased::Reject => {
+                    return Err(LuaError::RuntimeError(format!(
+                        "{} is indexed from 0, see ConversionOptions::zero_based",
+                        self.table_path()
+                    )));
+                }
+                ZeroBased::Shift => {}
+            }
+        }
+        let mut elements = vec![LuaMessage::Nil; next - base];
+        for (k, v) in map {
+            // the keys are distinct integers, so each index is set once
+            elements[k.parse::<usize>().unwrap() - base] = v;
+        }
+        Ok(Ok(elements))
+    }
+
+    // Count `n` more entries.
+    pub fn add_entries(&mut self, n: usize) -> LuaResult<()> {
+        self.entries += n;
+        match self.limits.max_table_entries {
+            Some(max) if self.entries > max => Err(LuaError::RuntimeError(format!(
+                "table has more than {} entries ({} counted when reaching {})",
+                max,
+                self.entries,
+                self.table_path()
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    // The path of the table being converted, for errors.
+    fn table_path(&self) -> String {
+        if self.path.is_empty() {
+            "the top-level table".to_string()
+        } else {
+            self.path.join(".")
+        }
+    }
+}
+
+// The table behind a read-only view of `ctx.state` or `ctx.msg`, see
+// `LuaActorBuilder::with_read_only_state`, or `t` itself.
+fn read_only_target<'lua>(ctx: Context<'lua>, t: Table<'lua>) -> LuaResult<Table<'lua>> {
+    if t.get_metatable().is_none() {
+        return Ok(t);
+    }
+    if let Value::Table(views) = ctx.globals().raw_get::<_, Value>("__read_only")? {
+        if let Value::Table(target) = views.raw_get(t.clone())? {
+            return Ok(target);
+        }
+    }
+    Ok(t)
+}
+
+impl LuaMessage {
+    /// Convert a Lua value to `LuaMessage`, with the default limits if `limits` is `None`.
+    ///
+    /// This is the conversion used for messages returned by scripts, for Rust functions
+    /// registered with `build_with_vm`.
+    pub fn from_lua_value<'lua>(
+        value: Value<'lua>,
+        ctx: Context<'lua>,
+        limits: Option<&ConversionLimits>,
+    ) -> LuaResult<LuaMessage> {
+        let default = ConversionLimits::default();
+        Self::from_lua_value_at(value, ctx, &mut Walk::new(limits.unwrap_or(&default)))
+    }
+
+    pub(crate) fn from_lua_value_at<'lua>(
+        v: Value<'lua>,
+        ctx: Context<'lua>,
+        walk: &mut Walk,
+    ) -> LuaResult<LuaMessage> {
+        match walk.integral(v) {
+            ref v if is_null_sentinel(v) => Ok(LuaMessage::Nil),
+            Value::String(x) => Ok(LuaMessage::String(String::from_lua(Value::String(x), ctx)?)),
+            Value::Integer(n) => Ok(LuaMessage::Integer(n)),
+            Value::Number(n) => {
+                walk.check_number(n)?;
+                Ok(LuaMessage::Number(n))
+            }
             Value::Boolean(b) => Ok(LuaMessage::Boolean(b)),
             Value::Nil => Ok(LuaMessage::Nil),
-            Value::Table(t) => Ok(LuaMessage::Table(HashMap::from_lua(Value::Table(t), ctx)?)),
-            Value::Error(err) => {
-                panic!("Lua error: {:?}", err);
+            Value::Table(t) => {
+                walk.check_depth()?;
+                let t = read_only_target(ctx, t)?;
+                if let Some(msg) = lazy_message(ctx, &t, walk)? {
+                    return Ok(msg);
+                }
+                let mut map = HashMap::new();
+                // whether all the keys are non-negative integers so far
+                let mut sequence = true;
+                for pair in t.clone().pairs::<Value, Value>() {
+                    let (k, v) = pair?;
+                    let k = walk.integral(k);
+                    walk.add_entries(1)?;
+                    sequence &= matches!(k, Value::Integer(i) if i >= 0);
+                    let k = String::from_lua(k, ctx)?;
+                    let v = match v {
+                        Value::Table(_) => {
+                            walk.path.push(k.clone());
+                            let v = Self::from_lua_value_at(v, ctx, walk)?;
+                            walk.path.pop();
+                            v
+                        }
+                        v => Self::from_lua_value_at(v, ctx, walk)?,
+                    };
+                    map.insert(k, v);
+                }
+                if let Some(name) = class_name(ctx, &t)? {
+                    map.insert("__class".to_string(), LuaMessage::String(name));
+                    return Ok(LuaMessage::Table(map));
+                }
+                if sequence && !map.is_empty() {
+                    return Ok(match walk.sequence(map)? {
+                        Ok(elements) => LuaMessage::Array(elements),
+                        Err(map) => LuaMessage::Table(map),
+                    });
+                }
+                Ok(LuaMessage::Table(map))
+            }
+            Value::UserData(ref ud) if ud.is::<OpaqueHandle>() => {
+                Ok(LuaMessage::Opaque(ud.borrow::<OpaqueHandle>()?.0))
+            }
+            v => {
+                let from = match v {
+                    Value::Function(_) => "function",
+                    Value::Thread(_) => "thread",
+                    Value::LightUserData(_) => "lightuserdata",
+                    Value::Error(_) => "error",
+                    _ => "userdata",
+                };
+                Err(LuaError::FromLuaConversionError {
+                    from,
+                    to: "LuaMessage",
+                    message: Some(format!("a {} can't be sent in a message", from)),
+                })
             }
-            _ => unimplemented!(),
         }
     }
-}
 
-impl<'lua> ToLua<'lua> for LuaMessage {
-    fn to_lua(self, ctx: Context<'lua>) -> LuaResult<Value<'lua>> {
+    /// Convert `LuaMessage` to a Lua value, with the default limits if `limits` is `None`.
+    pub fn to_lua_value<'lua>(
+        &self,
+        ctx: Context<'lua>,
+        limits: Option<&ConversionLimits>,
+    ) -> LuaResult<Value<'lua>> {
+        let default = ConversionLimits::default();
+        self.to_lua_value_at(ctx, &mut Walk::new(limits.unwrap_or(&default)))
+    }
+
+    fn to_lua_value_at<'lua>(&self, ctx: Context<'lua>, walk: &mut Walk) -> LuaResult<Value<'lua>> {
         match self {
-            LuaMessage::String(x) => Ok(Value::String(ctx.create_string(&x)?)),
-            LuaMessage::Integer(x) => Ok(Value::Integer(x)),
-            LuaMessage::Number(x) => Ok(Value::Number(x)),
-            LuaMessage::Boolean(x) => Ok(Value::Boolean(x)),
+            LuaMessage::String(x) => Ok(Value::String(ctx.create_string(x)?)),
+            LuaMessage::Integer(x) => Ok(Value::Integer(*x)),
+            LuaMessage::Number(x) => {
+                walk.check_number(*x)?;
+                Ok(Value::Number(*x))
+            }
+            LuaMessage::Boolean(x) => Ok(Value::Boolean(*x)),
             LuaMessage::Nil => Ok(Value::Nil),
-            LuaMessage::Table(x) => Ok(Value::Table(ctx.create_table_from(x)?)),
+            LuaMessage::Table(x) => {
+                walk.check_depth()?;
+                // fail before allocating anything for the entries of this table
+                walk.add_entries(x.len())?;
+                let t = ctx.create_table()?;
+                let class = match x.get("__class") {
+                    Some(LuaMessage::String(name)) => class(ctx, name)?,
+                    _ => None,
+                };
+                for (k, v) in x {
+                    if class.is_some() && k == "__class" {
+                        continue;
+                    }
+                    let v = match v {
+                        LuaMessage::Nil => null_sentinel(),
+                        LuaMessage::Table(_) | LuaMessage::Array(_) => {
+                            walk.path.push(k.clone());
+                            let v = v.to_lua_value_at(ctx, walk)?;
+                            walk.path.pop();
+                            v
+                        }
+                        v => v.to_lua_value_at(ctx, walk)?,
+                    };
+                    t.set(k.as_str(), v)?;
+                }
+                t.set_metatable(class);
+                Ok(Value::Table(t))
+            }
+            LuaMessage::Opaque(id) => Ok(Value::UserData(ctx.create_userdata(OpaqueHandle(*id))?)),
+            LuaMessage::Error { message, traceback } => {
+                walk.check_depth()?;
+                walk.add_entries(2)?;
+                let t = ctx.create_table()?;
+                t.set("message", message.as_str())?;
+                t.set("traceback", traceback.as_deref())?;
+                Ok(Value::Table(t))
+            }
+            LuaMessage::Array(x) => {
+                walk.check_depth()?;
+                walk.add_entries(x.len())?;
+                let t = ctx.create_table()?;
+                for (i, v) in x.iter().enumerate() {
+                    let v = match v {
+                        LuaMessage::Nil => null_sentinel(),
+                        LuaMessage::Table(_) | LuaMessage::Array(_) => {
+                            walk.path.push((i + 1).to_string());
+                            let v = v.to_lua_value_at(ctx, walk)?;
+                            walk.path.pop();
+                            v
+                        }
+                        v => v.to_lua_value_at(ctx, walk)?,
+                    };
+                    t.set(i + 1, v)?;
+                }
+                Ok(Value::Table(t))
+            }
 
             // TODO: passing rust error to lua error?
-            _ => unimplemented!(),
+            #[cfg(feature = "legacy-thread-yield")]
+            #[allow(deprecated)]
+            LuaMessage::ThreadYield(_) => unimplemented!(),
         }
     }
 }
 
+impl<'lua> FromLua<'lua> for LuaMessage {
+    fn from_lua(v: Value<'lua>, ctx: Context<'lua>) -> LuaResult<LuaMessage> {
+        LuaMessage::from_lua_value(v, ctx, Some(&vm_limits(ctx)))
+    }
+}
+
+impl<'lua> ToLua<'lua> for LuaMessage {
+    fn to_lua(self, ctx: Context<'lua>) -> LuaResult<Value<'lua>> {
+        self.to_lua_value(ctx, Some(&vm_limits(ctx)))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -201,6 +868,48 @@ mod tests {
         })
     }
 
+    // The Lua type of a value which can't be converted to `LuaMessage`.
+    fn unsupported<'lua>(value: Value<'lua>, ctx: Context<'lua>) -> &'static str {
+        match LuaMessage::from_lua_value(value, ctx, None) {
+            Err(LuaError::FromLuaConversionError { from, to, .. }) => {
+                assert_eq!(to, "LuaMessage");
+                from
+            }
+            res => panic!("expected a conversion error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn from_lua_unsupported() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let f = ctx.load("return function() end").eval::<Value>().unwrap();
+            assert_eq!(unsupported(f, ctx), "function");
+            let thread = ctx
+                .load("return coroutine.create(function() end)")
+                .eval::<Value>()
+                .unwrap();
+            assert_eq!(unsupported(thread, ctx), "thread");
+            let userdata = ctx.create_userdata(Unsupported).unwrap();
+            assert_eq!(unsupported(Value::UserData(userdata), ctx), "userdata");
+            let light = LightUserData(std::ptr::null_mut());
+            assert_eq!(
+                unsupported(Value::LightUserData(light), ctx),
+                "lightuserdata"
+            );
+            let error = Value::Error(LuaError::RuntimeError("boom".to_string()));
+            assert_eq!(unsupported(error, ctx), "error");
+
+            // inside a table too
+            let t = ctx.load("return {f = print}").eval::<Value>().unwrap();
+            assert_eq!(unsupported(t, ctx), "function");
+        })
+    }
+
+    struct Unsupported;
+
+    impl UserData for Unsupported {}
+
     #[test]
     fn from_lua() {
         // we only check if they have the correct variant
@@ -241,6 +950,105 @@ mod tests {
         })
     }
 
+    #[test]
+    fn sequences() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let from = |script: &str| {
+                let v: Value = ctx.load(script).eval().unwrap();
+                LuaMessage::from_lua(v, ctx).unwrap()
+            };
+            assert_eq!(
+                from("return {1, 'a'}"),
+                LuaMessage::from(vec![LuaMessage::from(1), LuaMessage::from("a")])
+            );
+            assert_eq!(
+                from("return {{1, 2}, {x = {3}}}"),
+                LuaMessage::Array(vec![
+                    LuaMessage::from(vec![1, 2]),
+                    LuaMessage::from(
+                        vec![("x".to_string(), LuaMessage::from(vec![3]))]
+                            .into_iter()
+                            .collect::<HashMap<_, _>>()
+                    ),
+                ])
+            );
+            // mixed, sparse and empty tables stay tables
+            for script in &[
+                "return {1, x = 2}",
+                "return {1, nil, 3}",
+                "return {[2] = 1}",
+            ] {
+                match from(script) {
+                    LuaMessage::Table(_) => {}
+                    msg => panic!("{} should be a table, got {:?}", script, msg),
+                }
+            }
+            assert_eq!(from("return {}"), LuaMessage::Table(HashMap::new()));
+
+            let msg = LuaMessage::from(vec![vec!["a", "b"], vec![]]);
+            let value = msg.to_lua(ctx).unwrap();
+            match &value {
+                Value::Table(t) => assert_eq!(t.len().unwrap(), 2),
+                v => panic!("expected a table, got {:?}", v),
+            }
+            // the empty array comes back as a table
+            assert_eq!(
+                LuaMessage::from_lua(value, ctx).unwrap(),
+                LuaMessage::Array(vec![
+                    LuaMessage::from(vec!["a", "b"]),
+                    LuaMessage::Table(HashMap::new()),
+                ])
+            );
+        })
+    }
+
+    #[test]
+    fn script_errors() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let e = ctx
+                .load(r#"error("boom")"#)
+                .set_name("handle")
+                .unwrap()
+                .exec()
+                .unwrap_err();
+            let msg = LuaMessage::from_script_error(&e);
+            assert!(msg.is_error());
+            assert!(!LuaMessage::Nil.is_error());
+            let (message, traceback) = match msg {
+                LuaMessage::Error {
+                    ref message,
+                    ref traceback,
+                } => (message.clone(), traceback.clone()),
+                ref msg => panic!("expected an error, got {:?}", msg),
+            };
+            assert_eq!(message, r#"[string "handle"]:1: boom"#);
+            assert!(!traceback.unwrap().contains("boom"));
+
+            // a table in Lua
+            let value = msg.to_lua(ctx).unwrap();
+            let t = match value {
+                Value::Table(t) => t,
+                v => panic!("expected a table, got {:?}", v),
+            };
+            assert_eq!(t.get::<_, String>("message").unwrap(), message);
+            assert!(t.get::<_, Option<String>>("traceback").unwrap().is_some());
+        })
+    }
+
+    #[test]
+    fn suspension_marker_is_a_string() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let v = Value::String(ctx.create_string("__suspended__1").unwrap());
+            assert_eq!(
+                LuaMessage::from_lua(v, ctx).unwrap(),
+                LuaMessage::from("__suspended__1")
+            );
+        })
+    }
+
     #[should_panic]
     #[test]
     fn from_lua_error() {
@@ -248,8 +1056,363 @@ mod tests {
 
         let lua = Lua::new();
         lua.context(|ctx| {
-            &LuaMessage::from_lua(Value::Error(Error::RuntimeError("foo".to_string())), ctx)
+            LuaMessage::from_lua(Value::Error(Error::RuntimeError("foo".to_string())), ctx)
+                .unwrap();
+        })
+    }
+
+    #[test]
+    fn conversion_limits() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let cyclic: Value = ctx.load("local t = {}; t.t = t; return t").eval().unwrap();
+            match LuaMessage::from_lua(cyclic, ctx) {
+                Err(LuaError::RuntimeError(e)) => assert!(e.contains("deeper than 128"), "{}", e),
+                res => panic!("cyclic table should fail, got {:?}", res),
+            }
+
+            let limits = ConversionLimits {
+                max_depth: 2,
+                ..ConversionLimits::default()
+            };
+            let nested: Value = ctx.load("return {a = {b = 1}}").eval().unwrap();
+            assert!(LuaMessage::from_lua_value(nested.clone(), ctx, Some(&limits)).is_ok());
+            let limits = ConversionLimits {
+                max_depth: 1,
+                ..ConversionLimits::default()
+            };
+            assert!(LuaMessage::from_lua_value(nested, ctx, Some(&limits)).is_err());
+
+            let mut inner = HashMap::new();
+            inner.insert("b".to_string(), LuaMessage::from(1));
+            let mut outer = HashMap::new();
+            outer.insert("a".to_string(), LuaMessage::from(inner));
+            let msg = LuaMessage::from(outer);
+            assert!(msg.to_lua_value(ctx, Some(&limits)).is_err());
+            let value = msg.to_lua_value(ctx, None).unwrap();
+            assert_eq!(LuaMessage::from_lua_value(value, ctx, None).unwrap(), msg);
+        })
+    }
+
+    #[test]
+    fn integral_floats() {
+        let integral = ConversionLimits {
+            integral_floats: true,
+            ..ConversionLimits::default()
+        };
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            // Lua 5.3 numbers, then numbers as on builds where every number is a float
+            let value: Value = ctx
+                .load("return {1.0, 2.5, 2^60, {n = 4 / 2, i = 3}}")
+                .eval()
+                .unwrap();
+            assert_eq!(
+                LuaMessage::from_lua_value(value.clone(), ctx, None).unwrap(),
+                crate::lua_msg!([1.0, 2.5, 2f64.powi(60), { n: 2.0, i: 3 }])
+            );
+            assert_eq!(
+                LuaMessage::from_lua_value(value, ctx, Some(&integral)).unwrap(),
+                crate::lua_msg!([1, 2.5, 2f64.powi(60), { n: 2, i: 3 }])
+            );
+            // float keys of an integral value are integer keys on Lua 5.3
+            let value: Value = ctx
+                .load("return {[1.0] = 'a', [2.0] = 'b'}")
+                .eval()
+                .unwrap();
+            assert_eq!(
+                LuaMessage::from_lua_value(value, ctx, Some(&integral)).unwrap(),
+                crate::lua_msg!(["a", "b"])
+            );
+            // integers are passed as integers
+            let back = LuaMessage::from(1)
+                .to_lua_value(ctx, Some(&integral))
+                .unwrap();
+            assert!(matches!(back, Value::Integer(1)));
+        })
+    }
+
+    #[test]
+    fn conversion_options() {
+        let options = |zero_based, max_gap| ConversionLimits {
+            options: ConversionOptions {
+                zero_based,
+                max_gap,
+            },
+            ..ConversionLimits::default()
+        };
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let eval = |script: &str| -> Value { ctx.load(script).eval().unwrap() };
+            let convert = |script: &str, limits: &ConversionLimits| {
+                LuaMessage::from_lua_value(eval(script), ctx, Some(limits))
+            };
+
+            // strict by default
+            let zero = "return {[0] = 'a', [1] = 'b'}";
+            let mut t = HashMap::new();
+            t.insert("0".to_string(), LuaMessage::from("a"));
+            t.insert("1".to_string(), LuaMessage::from("b"));
+            let default = ConversionLimits::default();
+            assert_eq!(convert(zero, &default).unwrap(), LuaMessage::from(t));
+            let gap = "return {[1] = 'a', [4] = 'b'}";
+            assert!(matches!(convert(gap, &default), Ok(LuaMessage::Table(_))));
+
+            // 0-based tables
+            let shift = options(ZeroBased::Shift, 0);
+            assert_eq!(convert(zero, &shift).unwrap(), crate::lua_msg!(["a", "b"]));
+            let reject = options(ZeroBased::Reject, 0);
+            match convert("return {t = {[0] = 'a'}}", &reject) {
+                Err(LuaError::RuntimeError(e)) => {
+                    assert!(e.contains("t is indexed from 0"), "{}", e)
+                }
+                res => panic!("a 0-based table should fail, got {:?}", res),
+            }
+            // only sequences are rejected
+            assert!(convert("return {[0] = 'a', x = 1}", &reject).is_ok());
+
+            // gaps up to `max_gap`, including before the first key, are `Nil` elements
+            let gaps = options(ZeroBased::Table, 2);
+            assert_eq!(
+                convert(gap, &gaps).unwrap(),
+                LuaMessage::Array(vec![
+                    LuaMessage::from("a"),
+                    LuaMessage::Nil,
+                    LuaMessage::Nil,
+                    LuaMessage::from("b"),
+                ])
+            );
+            assert_eq!(
+                convert("return {[3] = 'a'}", &gaps).unwrap(),
+                LuaMessage::Array(vec![
+                    LuaMessage::Nil,
+                    LuaMessage::Nil,
+                    LuaMessage::from("a")
+                ])
+            );
+            assert!(matches!(
+                convert("return {[1] = 'a', [5] = 'b'}", &gaps),
+                Ok(LuaMessage::Table(_))
+            ));
+
+            // integral float keys are integer keys on Lua 5.3 anyway
+            assert_eq!(
+                convert("return {[1.0] = 'a', [2.0] = 'b'}", &default).unwrap(),
+                crate::lua_msg!(["a", "b"])
+            );
+        })
+    }
+
+    #[test]
+    fn max_table_entries() {
+        let limits = ConversionLimits {
+            max_table_entries: Some(4),
+            ..ConversionLimits::default()
+        };
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            // nested entries are counted
+            let nested: Value = ctx
+                .load("return {a = 1, b = {c = {1, 2, 3}}}")
+                .eval()
                 .unwrap();
+            match LuaMessage::from_lua_value(nested, ctx, Some(&limits)) {
+                Err(LuaError::RuntimeError(e)) => {
+                    assert!(e.contains("more than 4 entries"), "{}", e);
+                    assert!(e.contains("when reaching b.c"), "{}", e);
+                }
+                res => panic!("expected an error, got {:?}", res),
+            }
+            let small: Value = ctx.load("return {a = 1, b = {c = {1}}}").eval().unwrap();
+            let msg = LuaMessage::from_lua_value(small, ctx, Some(&limits)).unwrap();
+            assert!(msg.to_lua_value(ctx, Some(&limits)).is_ok());
+
+            let mut inner = HashMap::new();
+            for i in 0..4 {
+                inner.insert(i.to_string(), LuaMessage::from(i));
+            }
+            let mut outer = HashMap::new();
+            outer.insert("inner".to_string(), LuaMessage::from(inner));
+            match LuaMessage::from(outer.clone()).to_lua_value(ctx, Some(&limits)) {
+                Err(LuaError::RuntimeError(e)) => {
+                    assert!(e.contains("when reaching inner"), "{}", e)
+                }
+                res => panic!("expected an error, got {:?}", res),
+            }
+
+            let unlimited = ConversionLimits {
+                max_table_entries: None,
+                ..ConversionLimits::default()
+            };
+            assert!(LuaMessage::from(outer)
+                .to_lua_value(ctx, Some(&unlimited))
+                .is_ok());
+        })
+    }
+
+    #[test]
+    fn overflow_errors() {
+        let checked = ConversionLimits {
+            overflow_errors: true,
+            ..ConversionLimits::default()
+        };
+        let default = ConversionLimits::default();
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let from = |script: &str, limits| {
+                let v: Value = ctx.load(script).eval().unwrap();
+                LuaMessage::from_lua_value(v, ctx, Some(limits))
+            };
+            // integers are exact at the boundaries
+            assert_eq!(
+                from("return math.maxinteger", &checked).unwrap(),
+                LuaMessage::Integer(i64::MAX)
+            );
+            assert_eq!(
+                from("return math.mininteger", &checked).unwrap(),
+                LuaMessage::Integer(i64::MIN)
+            );
+            assert!(LuaMessage::Integer(i64::MIN)
+                .to_lua_value(ctx, Some(&checked))
+                .is_ok());
+
+            // overflowed to a float
+            let overflowed = "return {n = math.maxinteger * 2.0}";
+            match from(overflowed, &checked) {
+                Err(LuaError::RuntimeError(e)) => assert!(e.contains("too large"), "{}", e),
+                res => panic!("expected an error, got {:?}", res),
+            }
+            assert!(from(overflowed, &default).is_ok());
+            assert!(from("return 2.0 ^ 53", &checked).is_ok());
+            assert!(from("return -(2.0 ^ 53) - 2", &checked).is_err());
+            // not integral
+            assert!(from("return 0.5", &checked).is_ok());
+
+            assert!(LuaMessage::Number(1e300)
+                .to_lua_value(ctx, Some(&checked))
+                .is_err());
+            assert!(LuaMessage::Number(1e300)
+                .to_lua_value(ctx, Some(&default))
+                .is_ok());
         })
     }
+
+    #[test]
+    fn nil_table_values() {
+        let mut t = HashMap::new();
+        t.insert("present".to_string(), LuaMessage::Nil);
+        t.insert("x".to_string(), LuaMessage::from(1));
+        let msg = LuaMessage::from(t);
+
+        assert_eq!(msg.get("present"), Some(&LuaMessage::Nil));
+        assert_eq!(msg.get("absent"), None);
+        assert_eq!(msg.get("x"), Some(&LuaMessage::from(1)));
+        assert_eq!(LuaMessage::from(1).get("x"), None);
+
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let value = msg.clone().to_lua(ctx).unwrap();
+            let round_trip = LuaMessage::from_lua(value, ctx).unwrap();
+            assert_eq!(round_trip, msg);
+            assert_eq!(round_trip.get("present"), Some(&LuaMessage::Nil));
+
+            // `ctx.null` itself converts to `Nil`
+            assert_eq!(
+                LuaMessage::from_lua(null_sentinel(), ctx).unwrap(),
+                LuaMessage::Nil
+            );
+        })
+    }
+
+    #[test]
+    fn lua_literals() {
+        let table = |entries: &[(&str, LuaMessage)]| {
+            LuaMessage::from(
+                entries
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect::<HashMap<_, _>>(),
+            )
+        };
+        let mut deep = LuaMessage::from("bottom");
+        for _ in 0..100 {
+            deep = LuaMessage::from(vec![deep]);
+        }
+        let values = vec![
+            LuaMessage::from(r#"say "hi" and 'bye'"#),
+            LuaMessage::from("back\\slash\n\r\t]]"),
+            LuaMessage::from("nul\0byte\x07\x7f"),
+            LuaMessage::from("héllo ✓"),
+            LuaMessage::from(""),
+            LuaMessage::from(0),
+            LuaMessage::from(-42),
+            LuaMessage::Integer(i64::MAX),
+            LuaMessage::Integer(i64::MIN),
+            LuaMessage::from(3.0),
+            LuaMessage::from(0.1),
+            LuaMessage::from(-2.5e-8),
+            LuaMessage::from(1e300),
+            LuaMessage::from(5e-324),
+            LuaMessage::from(9_007_199_254_740_993_f64),
+            LuaMessage::from(f64::MAX),
+            LuaMessage::from(f64::INFINITY),
+            LuaMessage::from(f64::NEG_INFINITY),
+            LuaMessage::from(true),
+            LuaMessage::Nil,
+            table(&[
+                ("end", LuaMessage::from(1)),
+                ("with space", LuaMessage::from(2)),
+                ("1", LuaMessage::from(3)),
+                ("_ok", LuaMessage::Nil),
+                ("\"]", table(&[])),
+            ]),
+            LuaMessage::from(vec![
+                LuaMessage::from(1),
+                LuaMessage::Nil,
+                LuaMessage::from(vec![LuaMessage::from("x")]),
+            ]),
+            deep,
+        ];
+
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let null = ctx.create_table().unwrap();
+            null.set("null", null_sentinel()).unwrap();
+            ctx.globals().set("ctx", null).unwrap();
+            let eval = |msg: &LuaMessage| {
+                let literal = msg.to_lua_literal().unwrap();
+                ctx.load(&format!("return {}", literal))
+                    .eval::<LuaMessage>()
+                    .unwrap_or_else(|e| panic!("{} doesn't evaluate: {}", literal, e))
+            };
+            for msg in &values {
+                assert_eq!(&eval(msg), msg, "{}", msg.to_lua_literal().unwrap());
+            }
+            match eval(&LuaMessage::from(-0.0)) {
+                LuaMessage::Number(n) => assert!(n == 0.0 && n.is_sign_negative()),
+                other => panic!("-0.0 evaluated to {:?}", other),
+            }
+            match eval(&LuaMessage::from(f64::NAN)) {
+                LuaMessage::Number(n) => assert!(n.is_nan()),
+                other => panic!("NaN evaluated to {:?}", other),
+            }
+        });
+
+        assert_eq!(
+            table(&[("b", LuaMessage::from(1)), ("a", LuaMessage::from("x"))])
+                .to_lua_literal()
+                .unwrap(),
+            r#"{a = "x", b = 1}"#
+        );
+        assert_eq!(
+            LuaMessage::from("tab\tnul\0é").to_lua_literal().unwrap(),
+            r#""tab\tnul\x00\xc3\xa9""#
+        );
+        assert!(LuaMessage::Opaque(1).to_lua_literal().is_err());
+        let mut too_deep = LuaMessage::from(1);
+        for _ in 0..200 {
+            too_deep = LuaMessage::from(vec![too_deep]);
+        }
+        assert!(too_deep.to_lua_literal().is_err());
+    }
 }
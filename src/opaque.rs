@@ -0,0 +1,121 @@
+use rlua::{Context, Error as LuaError, Table, UserData};
+
+use crate::message::LuaMessage;
+use crate::runtime::{arg, ApiEntry, CtxFn, Requires};
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Lua value of a `LuaMessage::Opaque` handle.
+///
+/// Rust functions added to the VM can take it as an argument to look up the value with
+/// [`OpaqueRegistry::with`](struct.OpaqueRegistry.html#method.with).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpaqueHandle(pub u64);
+
+impl UserData for OpaqueHandle {}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    values: HashMap<u64, Box<dyn Any + Send>>,
+}
+
+/// Rust values which can't be converted to `LuaMessage`, referenced by `LuaMessage::Opaque`
+/// handles.
+///
+/// Handles can be stored in `ctx.state` and sent to self, but not to other actors. Values are
+/// dropped by `ctx.release(handle)` and when the actor stops.
+///
+/// Rust functions called by the actor's scripts get the registry of the actor with
+/// [`OpaqueRegistry::from_context`](#method.from_context). Clones share the same values.
+#[derive(Clone, Default)]
+pub struct OpaqueRegistry {
+    inner: Arc<Mutex<Registry>>,
+}
+
+impl UserData for OpaqueRegistry {}
+
+const REGISTRY_KEY: &str = "__opaque_registry";
+
+pub(crate) const CTX_RELEASE: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "release",
+        signature: "ctx.release(handle)",
+        description: "drop the Rust value of the opaque `handle`",
+        availability: "",
+        params: &[arg("handle", "lightuserdata")],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+impl OpaqueRegistry {
+    pub fn new() -> OpaqueRegistry {
+        OpaqueRegistry::default()
+    }
+
+    /// Registry of the actor running in the VM of `ctx`.
+    pub fn from_context(ctx: Context) -> Result<OpaqueRegistry, LuaError> {
+        ctx.named_registry_value(REGISTRY_KEY)
+    }
+
+    /// Store `value` and return its handle.
+    pub fn register<T: Any + Send>(&self, value: T) -> OpaqueHandle {
+        let mut registry = self.inner.lock().unwrap();
+        registry.next_id += 1;
+        let id = registry.next_id;
+        registry.values.insert(id, Box::new(value));
+        OpaqueHandle(id)
+    }
+
+    /// Call `f` with the value of `handle`, `None` if it was released or is not a `T`.
+    pub fn with<T: Any, R, F: FnOnce(&mut T) -> R>(&self, handle: OpaqueHandle, f: F) -> Option<R> {
+        let mut registry = self.inner.lock().unwrap();
+        registry
+            .values
+            .get_mut(&handle.0)
+            .and_then(|v| v.downcast_mut::<T>())
+            .map(f)
+    }
+
+    /// Remove the value of `handle` from the registry.
+    pub fn take(&self, handle: OpaqueHandle) -> Option<Box<dyn Any + Send>> {
+        self.inner.lock().unwrap().values.remove(&handle.0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn clear(&self) {
+        self.inner.lock().unwrap().values.clear();
+    }
+
+    // Make the registry available to `from_context` and set `ctx.release`.
+    pub(crate) fn install(&self, ctx: Context) -> Result<(), LuaError> {
+        ctx.set_named_registry_value(REGISTRY_KEY, self.clone())?;
+        let registry = self.clone();
+        let release = ctx.create_function(move |_, handle: OpaqueHandle| {
+            registry.take(handle);
+            Ok(())
+        })?;
+        let lua_ctx: Table = ctx.globals().get("ctx")?;
+        lua_ctx.set(CTX_RELEASE.entry.name, release)
+    }
+}
+
+// Error of a message with opaque handles sent to another actor.
+pub(crate) fn check_not_opaque(msg: &LuaMessage) -> Result<(), LuaError> {
+    if msg.contains_opaque() {
+        return Err(LuaError::RuntimeError(
+            "opaque handles can't be sent to other actors".to_string(),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use ::actix::prelude::*;
+
+use crate::actor::LuaActor;
+use crate::message::LuaMessage;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+pub(crate) const DEFAULT_OUTBOX_CAPACITY: usize = 1000;
+pub(crate) const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Messages of `ctx.do_send` kept while their recipient is disconnected or missing.
+struct Outbox {
+    capacity: usize,
+    retry_interval: Duration,
+    queue: VecDeque<LuaMessage>,
+    retry: Option<SpawnHandle>,
+}
+
+// Outboxes set with `ctx.set_outbox`, by recipient name as passed to `ctx.do_send`.
+#[derive(Default)]
+pub(crate) struct Outboxes {
+    outboxes: HashMap<String, Outbox>,
+}
+
+impl Outboxes {
+    // Create the outbox of `name`, or change its settings keeping the buffered messages.
+    pub fn set(&mut self, name: &str, capacity: usize, retry_interval: Duration) {
+        let outbox = self
+            .outboxes
+            .entry(name.to_string())
+            .or_insert_with(|| Outbox {
+                capacity,
+                retry_interval,
+                queue: VecDeque::new(),
+                retry: None,
+            });
+        outbox.capacity = capacity;
+        outbox.retry_interval = retry_interval;
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.outboxes.contains_key(name)
+    }
+
+    // Number of messages buffered for `name`.
+    pub fn depth(&self, name: &str) -> usize {
+        self.outboxes.get(name).map_or(0, |o| o.queue.len())
+    }
+
+    // Send `msg` to the recipient `rec` of the outbox `name`, buffering it if the recipient
+    // is disconnected or missing, or if earlier messages are still buffered.
+    pub fn send(
+        &mut self,
+        name: &str,
+        rec: Option<&Recipient<LuaMessage>>,
+        msg: LuaMessage,
+        log_prefix: &str,
+        ctx: &mut Context<LuaActor>,
+    ) {
+        let outbox = match self.outboxes.get_mut(name) {
+            Some(outbox) => outbox,
+            None => return,
+        };
+        let msg = match rec {
+            Some(rec) if outbox.queue.is_empty() => match rec.do_send(msg) {
+                Ok(()) => return,
+                Err(e) => e.into_inner(),
+            },
+            _ => msg,
+        };
+        if outbox.queue.len() >= outbox.capacity {
+            log::error!(
+                "{} dropped a message to {}: outbox full ({} messages)",
+                log_prefix,
+                name,
+                outbox.capacity
+            );
+            return;
+        }
+        outbox.queue.push_back(msg);
+        if outbox.retry.is_none() {
+            let name = name.to_string();
+            outbox.retry = Some(ctx.run_interval(outbox.retry_interval, move |act, ctx| {
+                act.flush_outbox(&name, ctx)
+            }));
+        }
+    }
+
+    // Deliver the buffered messages of `name` in order, until the recipient fails.
+    pub fn flush(
+        &mut self,
+        name: &str,
+        rec: Option<&Recipient<LuaMessage>>,
+        log_prefix: &str,
+        ctx: &mut Context<LuaActor>,
+    ) {
+        let outbox = match self.outboxes.get_mut(name) {
+            Some(outbox) => outbox,
+            None => return,
+        };
+        let rec = match rec {
+            Some(rec) => rec,
+            None => return,
+        };
+        let mut delivered = 0;
+        while let Some(msg) = outbox.queue.pop_front() {
+            if let Err(e) = rec.do_send(msg) {
+                outbox.queue.push_front(e.into_inner());
+                break;
+            }
+            delivered += 1;
+        }
+        if delivered > 0 {
+            log::info!(
+                "{} delivered {} buffered messages to {}",
+                log_prefix,
+                delivered,
+                name
+            );
+        }
+        if outbox.queue.is_empty() {
+            if let Some(retry) = outbox.retry.take() {
+                ctx.cancel_future(retry);
+            }
+        }
+    }
+
+    // Number of messages buffered in all the outboxes.
+    pub fn buffered(&self) -> usize {
+        self.outboxes.values().map(|o| o.queue.len()).sum()
+    }
+
+    // Names of the outboxes with buffered messages.
+    pub fn pending(&self) -> Vec<String> {
+        let mut names: Vec<_> = self
+            .outboxes
+            .iter()
+            .filter(|(_, o)| !o.queue.is_empty())
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+}
+
+impl LuaActor {
+    pub(crate) fn flush_outbox(&mut self, name: &str, ctx: &mut Context<Self>) {
+        let rec = self.recipients.get(self.aliases.resolve(name));
+        self.outboxes.flush(name, rec, &self.log_prefix, ctx);
+    }
+
+    // Flush every outbox, e.g. once a recipient was replaced.
+    pub(crate) fn flush_outboxes(&mut self, ctx: &mut Context<Self>) {
+        for name in self.outboxes.pending() {
+            self.flush_outbox(&name, ctx);
+        }
+    }
+}
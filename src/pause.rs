@@ -0,0 +1,516 @@
+use ::actix::prelude::*;
+
+use crate::actor::{DeferredReply, LuaActor, ReplyTx};
+use crate::message::LuaMessage;
+use std::collections::{HashMap, VecDeque};
+
+/// Stop handling the messages of a `LuaActor` without stopping it, until `ResumeProcessing`.
+///
+/// The `LuaMessage`s and `TryLuaMessage`s received meanwhile are buffered, up to the limit set
+/// with `LuaActorBuilder::with_pause_buffer`, and handled in order on resume. Queries and control
+/// messages such as `GetStateSize` or `AddRecipient` keep working. Scripts can check
+/// `ctx.is_paused()`, e.g. in a long running coroutine.
+pub struct PauseProcessing {
+    /// Let the coroutines suspended in `ctx.send` resume when their reply arrives. Otherwise
+    /// their replies are buffered with the messages.
+    pub drain_current: bool,
+    /// Also buffer `ctx.notify`, `ctx.notify_later`, `ctx.run_interval` and `ctx.schedule`
+    /// messages, which are handled while paused otherwise.
+    pub hold_timers: bool,
+}
+
+impl Message for PauseProcessing {
+    type Result = ();
+}
+
+/// Handle the messages buffered since `PauseProcessing` in order, then the next ones as usual.
+pub struct ResumeProcessing;
+
+impl Message for ResumeProcessing {
+    type Result = ();
+}
+
+/// What a paused `LuaActor` does with the messages beyond its buffer, see
+/// `LuaActorBuilder::with_pause_buffer`.
+///
+/// The dropped message is passed to the unprocessed sink of the actor, if it has one, and its
+/// sender gets `{error = "paused"}`, or `{ok = false, kind = "paused"}` with rich send results.
+/// A dropped `TryLuaMessage` fails with `HandleError::Error("paused")`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PauseOverflow {
+    /// Drop the message received.
+    DropNewest,
+    /// Drop the oldest buffered message to make room.
+    DropOldest,
+}
+
+impl PauseOverflow {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            PauseOverflow::DropNewest => "drop_newest",
+            PauseOverflow::DropOldest => "drop_oldest",
+        }
+    }
+}
+
+pub(crate) const DEFAULT_PAUSE_BUFFER: usize = 1024;
+
+// Something to handle once the actor resumes.
+pub(crate) enum Buffered {
+    // a message of the mailbox or a notification, with the channel of the reply if one is
+    // expected
+    Message(LuaMessage, Option<DeferredReply>),
+    // the message of an interval tick or a schedule
+    Timer(LuaMessage),
+    // the reply to a `ctx.send`, never dropped since the coroutine would wait forever
+    Resume(i64, Vec<LuaMessage>),
+}
+
+// Whether an actor is paused and what it holds, see `PauseProcessing`.
+pub(crate) struct Pause {
+    pub max_buffered: usize,
+    pub overflow: PauseOverflow,
+    // `None` while the messages are handled
+    paused: Option<PauseProcessing>,
+    held: VecDeque<Buffered>,
+    // held messages and timers, which count against `max_buffered`
+    buffered: usize,
+    dropped: u64,
+}
+
+impl Pause {
+    pub fn new(max_buffered: usize, overflow: PauseOverflow) -> Pause {
+        Pause {
+            max_buffered,
+            overflow,
+            paused: None,
+            held: VecDeque::new(),
+            buffered: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    // Whether a message received now is held, `timer` for notifications and timers.
+    pub fn holds(&self, timer: bool) -> bool {
+        match self.paused {
+            Some(ref pause) => !timer || pause.hold_timers,
+            None => false,
+        }
+    }
+
+    pub fn holds_resumes(&self) -> bool {
+        match self.paused {
+            Some(ref pause) => !pause.drain_current,
+            None => false,
+        }
+    }
+
+    pub fn pause(&mut self, pause: PauseProcessing) {
+        self.paused = Some(pause);
+    }
+
+    // Hold `held` until the actor resumes, returning what was dropped if the buffer is full.
+    pub fn push(&mut self, held: Buffered) -> Option<Buffered> {
+        if let Buffered::Resume(..) = held {
+            self.held.push_back(held);
+            return None;
+        }
+        let mut dropped = None;
+        if self.buffered >= self.max_buffered {
+            self.dropped += 1;
+            let oldest = match self.overflow {
+                PauseOverflow::DropNewest => None,
+                PauseOverflow::DropOldest => self
+                    .held
+                    .iter()
+                    .position(|held| !matches!(held, Buffered::Resume(..))),
+            };
+            match oldest {
+                Some(i) => dropped = self.held.remove(i),
+                // nothing older to drop
+                None => return Some(held),
+            }
+        } else {
+            self.buffered += 1;
+        }
+        self.held.push_back(held);
+        dropped
+    }
+
+    // Stop pausing, returning what was held in order.
+    pub fn resume(&mut self) -> VecDeque<Buffered> {
+        self.paused = None;
+        self.buffered = 0;
+        std::mem::take(&mut self.held)
+    }
+
+    // Buffered messages and timers.
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    pub fn stats(&self) -> LuaMessage {
+        let mut stats = HashMap::new();
+        stats.insert("paused".to_string(), LuaMessage::from(self.is_paused()));
+        stats.insert(
+            "buffered".to_string(),
+            LuaMessage::from(self.buffered as i64),
+        );
+        stats.insert("dropped".to_string(), LuaMessage::from(self.dropped as i64));
+        LuaMessage::from(stats)
+    }
+}
+
+/// Read whether a `LuaActor` is paused, see `PauseProcessing`.
+///
+/// The reply is a table:
+///
+/// ```lua
+/// {
+///     paused = true,
+///     buffered = 3,  -- messages waiting for `ResumeProcessing`
+///     dropped = 0,   -- beyond the buffer since the actor started
+/// }
+/// ```
+pub struct GetPauseStatus;
+
+impl Message for GetPauseStatus {
+    type Result = LuaMessage;
+}
+
+impl Handler<GetPauseStatus> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, _: GetPauseStatus, _: &mut Context<Self>) -> LuaMessage {
+        self.pause.stats()
+    }
+}
+
+impl Handler<PauseProcessing> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, pause: PauseProcessing, _: &mut Context<Self>) {
+        if let Err(e) = self.runtime.set_paused(true) {
+            log::error!("{} failed to set ctx.is_paused(): {}", self.log_prefix, e);
+        }
+        self.pause.pause(pause);
+    }
+}
+
+impl Handler<ResumeProcessing> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, _: ResumeProcessing, ctx: &mut Context<Self>) {
+        if !self.pause.is_paused() {
+            return;
+        }
+        if let Err(e) = self.runtime.set_paused(false) {
+            log::error!("{} failed to set ctx.is_paused(): {}", self.log_prefix, e);
+        }
+        for held in self.pause.resume() {
+            match held {
+                Buffered::Message(msg, tx) => self.receive(msg, false, tx, ctx),
+                Buffered::Timer(msg) => {
+                    self.handle_message(msg, false, ctx);
+                }
+                Buffered::Resume(cb_thread_id, values) => {
+                    self.resume_with(ctx, cb_thread_id, values)
+                }
+            }
+        }
+    }
+}
+
+impl LuaActor {
+    // Hold `held` until the actor resumes. The message dropped if the buffer is full is passed
+    // to the unprocessed sink.
+    pub(crate) fn hold(&mut self, held: Buffered) {
+        match self.pause.push(held) {
+            Some(Buffered::Message(msg, tx)) => {
+                self.pass_unprocessed(msg);
+                if let Some(tx) = tx {
+                    tx.reject("paused", None, self.rich_send_results);
+                }
+            }
+            Some(Buffered::Timer(msg)) => self.pass_unprocessed(msg),
+            Some(Buffered::Resume(..)) | None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor::{HandleError, TryLuaMessage};
+    use crate::builder::LuaActorBuilder;
+    use crate::lua_msg;
+    use crate::state_quota::GetStateSize;
+    use futures::Future;
+    use futures_timer::Delay;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    struct Sink(Arc<Mutex<Vec<LuaMessage>>>);
+
+    impl Actor for Sink {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<LuaMessage> for Sink {
+        type Result = LuaMessage;
+
+        fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> LuaMessage {
+            self.0.lock().unwrap().push(msg);
+            LuaMessage::Nil
+        }
+    }
+
+    // Logs the messages it handles and replies with them.
+    fn logging_actor() -> LuaActorBuilder {
+        LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.state.log = {}"#)
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "log" then
+                    return ctx.state.log
+                end
+                table.insert(ctx.state.log, ctx.msg)
+                return ctx.msg
+                "#,
+            )
+    }
+
+    #[test]
+    fn pause_buffer_overflow() {
+        let mut pause = Pause::new(2, PauseOverflow::DropOldest);
+        pause.pause(PauseProcessing {
+            drain_current: false,
+            hold_timers: false,
+        });
+        assert!(pause.holds(false));
+        assert!(!pause.holds(true));
+        assert!(pause.holds_resumes());
+        assert!(pause.push(Buffered::Timer(LuaMessage::from(1))).is_none());
+        assert!(pause.push(Buffered::Resume(7, vec![])).is_none());
+        assert!(pause
+            .push(Buffered::Message(LuaMessage::from(2), None))
+            .is_none());
+        // the resume doesn't count nor is dropped
+        match pause.push(Buffered::Message(LuaMessage::from(3), None)) {
+            Some(Buffered::Timer(msg)) => assert_eq!(msg, LuaMessage::from(1)),
+            _ => panic!("the oldest message wasn't dropped"),
+        }
+        assert_eq!(
+            pause.stats(),
+            lua_msg!({ paused: true, buffered: 2, dropped: 1 })
+        );
+        let held: Vec<_> = pause
+            .resume()
+            .into_iter()
+            .map(|held| match held {
+                Buffered::Message(msg, _) | Buffered::Timer(msg) => msg,
+                Buffered::Resume(id, _) => LuaMessage::from(id),
+            })
+            .collect();
+        assert_eq!(
+            held,
+            vec![
+                LuaMessage::from(7),
+                LuaMessage::from(2),
+                LuaMessage::from(3)
+            ]
+        );
+        assert!(!pause.holds(false));
+
+        let mut pause = Pause::new(1, PauseOverflow::DropNewest);
+        pause.pause(PauseProcessing {
+            drain_current: true,
+            hold_timers: true,
+        });
+        assert!(pause.holds(true));
+        assert!(!pause.holds_resumes());
+        assert!(pause
+            .push(Buffered::Message(LuaMessage::from(1), None))
+            .is_none());
+        match pause.push(Buffered::Message(LuaMessage::from(2), None)) {
+            Some(Buffered::Message(msg, _)) => assert_eq!(msg, LuaMessage::from(2)),
+            _ => panic!("the newest message wasn't dropped"),
+        }
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let system = System::new("test");
+
+        let addr = logging_actor().build().unwrap().start();
+        let paused = addr.clone();
+        let resumed = addr.clone();
+        let l = addr
+            .send(PauseProcessing {
+                drain_current: true,
+                hold_timers: false,
+            })
+            .and_then(move |_| {
+                for i in 0..3 {
+                    paused.do_send(LuaMessage::from(i));
+                }
+                // answered once resumed
+                let reply = paused.send(LuaMessage::from(3));
+                Delay::new(Duration::from_millis(50))
+                    .then(move |_| paused.send(GetPauseStatus).join(paused.send(GetStateSize)))
+                    .map(move |status| (status, reply))
+            })
+            .and_then(move |((status, size), reply)| {
+                assert_eq!(status, lua_msg!({ paused: true, buffered: 4, dropped: 0 }));
+                // queries keep working while paused
+                assert!(size.get("bytes").is_some());
+                resumed
+                    .send(ResumeProcessing)
+                    .and_then(move |_| reply.join(resumed.send(LuaMessage::from("log"))))
+                    .join(addr.send(GetPauseStatus))
+            })
+            .map(|((reply, log), status)| {
+                assert_eq!(reply, LuaMessage::from(3));
+                assert_eq!(log, lua_msg!([0, 1, 2, 3]));
+                assert_eq!(status, lua_msg!({ paused: false, buffered: 0, dropped: 0 }));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn pause_overflow_to_unprocessed_sink() {
+        let system = System::new("test");
+
+        let dead_letters = Arc::new(Mutex::new(vec![]));
+        let addr = logging_actor()
+            .with_pause_buffer(2, PauseOverflow::DropNewest)
+            .with_unprocessed_sink("dead_letters")
+            .with_recipient(
+                "dead_letters",
+                Sink(dead_letters.clone()).start().recipient(),
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        addr.do_send(PauseProcessing {
+            drain_current: true,
+            hold_timers: false,
+        });
+        addr.do_send(LuaMessage::from(1));
+        addr.do_send(LuaMessage::from(2));
+        let rejected = addr.send(LuaMessage::from(3));
+        let resumed = addr.clone();
+        let l = rejected
+            .and_then(move |reply| {
+                assert_eq!(reply, lua_msg!({ error: "paused" }));
+                resumed
+                    .send(ResumeProcessing)
+                    .and_then(move |_| resumed.send(LuaMessage::from("log")))
+            })
+            .join(Delay::new(Duration::from_millis(50)).map_err(|_| MailboxError::Closed))
+            .map(move |(log, _)| {
+                assert_eq!(log, lua_msg!([1, 2]));
+                assert_eq!(*dead_letters.lock().unwrap(), vec![LuaMessage::from(3)]);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn pause_try_messages() {
+        let system = System::new("test");
+
+        let addr = logging_actor()
+            .with_pause_buffer(1, PauseOverflow::DropNewest)
+            .build()
+            .unwrap()
+            .start();
+
+        addr.do_send(PauseProcessing {
+            drain_current: true,
+            hold_timers: false,
+        });
+        // answered once resumed
+        let held = addr.send(TryLuaMessage(LuaMessage::from(1)));
+        let rejected = addr.send(TryLuaMessage(LuaMessage::from(2)));
+        let resumed = addr.clone();
+        let l = rejected
+            .and_then(move |res| {
+                assert_eq!(res, Err(HandleError::Error("paused".to_string())));
+                resumed.send(GetStateSize).and_then(move |_| {
+                    resumed.do_send(ResumeProcessing);
+                    held.join(resumed.send(LuaMessage::from("log")))
+                })
+            })
+            .map(|(held, log)| {
+                assert_eq!(held, Ok(LuaMessage::from(1)));
+                assert_eq!(log, lua_msg!([1]));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn pause_timers_and_resumes() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.state.log = {}"#)
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "log" then
+                    return ctx.state.log
+                elseif ctx.msg == "start" then
+                    ctx.notify_later("timer", 0.02)
+                    local reply = ctx.send("echo", "reply")
+                    table.insert(ctx.state.log, reply .. (ctx.is_paused() and " paused" or ""))
+                    return
+                end
+                table.insert(ctx.state.log, ctx.msg)
+                "#,
+            )
+            .with_child(
+                "echo",
+                LuaActorBuilder::new().on_handle_with_lua(r#"return ctx.msg"#),
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        addr.do_send(LuaMessage::from("start"));
+        addr.do_send(PauseProcessing {
+            drain_current: false,
+            hold_timers: true,
+        });
+        let resumed = addr.clone();
+        let l = Delay::new(Duration::from_millis(50))
+            .then(move |_| addr.send(GetPauseStatus))
+            .and_then(move |status| {
+                // the reply is held but not counted, the timer is
+                assert_eq!(status, lua_msg!({ paused: true, buffered: 1, dropped: 0 }));
+                resumed
+                    .send(ResumeProcessing)
+                    .and_then(move |_| resumed.send(LuaMessage::from("log")))
+            })
+            .map(|log| {
+                // not resumed while paused
+                assert_eq!(log, lua_msg!(["reply", "timer"]));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,254 @@
+use ::actix::prelude::*;
+
+use crate::actor::LuaActor;
+use crate::message::LuaMessage;
+use crate::schedule::unix_millis;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime};
+
+// How long the `health()` function of a deep ping may run, see `LuaActorBuilder::with_deep_ping`.
+pub(crate) const HEALTH_DEADLINE: Duration = Duration::from_millis(100);
+
+// What a `Ping` reports, updated as the actor handles messages.
+#[derive(Debug)]
+pub(crate) struct Liveness {
+    started: Instant,
+    handled: u64,
+    errors: u64,
+    last_error: Option<SystemTime>,
+}
+
+impl Liveness {
+    pub fn new() -> Liveness {
+        Liveness {
+            started: Instant::now(),
+            handled: 0,
+            errors: 0,
+            last_error: None,
+        }
+    }
+
+    pub fn start(&mut self) {
+        self.started = Instant::now();
+    }
+
+    pub fn handled(&mut self) {
+        self.handled += 1;
+    }
+
+    pub fn error(&mut self) {
+        self.errors += 1;
+        self.last_error = Some(SystemTime::now());
+    }
+}
+
+/// A liveness probe of a `LuaActor`, answered without running the scripts.
+///
+/// The probe works while the actor is paused or its `handle` script fails, e.g. for a load
+/// balancer or a supervisor. The reply is a table:
+///
+/// ```lua
+/// {
+///     uptime_ms = 120000,
+///     handled = 42,  -- messages and notifications handled by `handle`
+///     errors = 1,  -- `handle` invocations which failed
+///     last_error_at_ms = 1700000000000,  -- Unix time of the last failure, nil without
+///     paused = false,
+///     pending_coroutines = 0,  -- suspended in `ctx.send` and similar
+/// }
+/// ```
+///
+/// With `LuaActorBuilder::with_deep_ping(true)`, the global function `health()` is also
+/// called, if the scripts defined it, and its result is added as `health`. It's aborted after
+/// 100ms, a failure being reported as `health_error` instead.
+pub struct Ping;
+
+impl Message for Ping {
+    type Result = LuaMessage;
+}
+
+impl Handler<Ping> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, _: Ping, ctx: &mut Context<Self>) -> LuaMessage {
+        let liveness = &self.liveness;
+        let mut reply = HashMap::new();
+        reply.insert(
+            "uptime_ms".to_string(),
+            LuaMessage::from(liveness.started.elapsed().as_millis() as i64),
+        );
+        reply.insert(
+            "handled".to_string(),
+            LuaMessage::from(liveness.handled as i64),
+        );
+        reply.insert(
+            "errors".to_string(),
+            LuaMessage::from(liveness.errors as i64),
+        );
+        reply.insert(
+            "last_error_at_ms".to_string(),
+            liveness
+                .last_error
+                .map_or(LuaMessage::Nil, |at| LuaMessage::from(unix_millis(at))),
+        );
+        reply.insert(
+            "paused".to_string(),
+            LuaMessage::from(self.pause.is_paused()),
+        );
+        reply.insert(
+            "pending_coroutines".to_string(),
+            LuaMessage::from(self.runtime.pending_threads().unwrap_or(0) as i64),
+        );
+        if self.deep_ping && !self.starting {
+            match self.check_health(ctx) {
+                Ok(health) => {
+                    reply.insert("health".to_string(), health);
+                }
+                Err(e) => {
+                    reply.insert("health_error".to_string(), LuaMessage::from(e));
+                }
+            }
+        }
+        LuaMessage::from(reply)
+    }
+}
+
+impl LuaActor {
+    // Run the `health()` function of the scripts within `HEALTH_DEADLINE`.
+    fn check_health(&mut self, ctx: &mut Context<Self>) -> Result<LuaMessage, String> {
+        let aborted = self.runtime.set_deadline(Instant::now() + HEALTH_DEADLINE);
+        let res = self.invoke_hook(
+            ctx,
+            "health",
+            "__run",
+            vec![
+                LuaMessage::from("__health"),
+                LuaMessage::Nil,
+                LuaMessage::from(true),
+            ],
+            true,
+        );
+        self.runtime.clear_deadline();
+        // the deadline replaced the hook of the handle timeout
+        let timeout = self.runtime.handle_timeout;
+        self.runtime.set_handle_timeout(timeout);
+        res.map_err(|e| {
+            if aborted.load(Ordering::SeqCst) {
+                format!("health() aborted after {:?}", HEALTH_DEADLINE)
+            } else {
+                e.to_string()
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LuaActorBuilder;
+    use crate::lua_msg;
+    use crate::pause::PauseProcessing;
+    use futures::Future;
+
+    fn field<'a>(reply: &'a LuaMessage, name: &str) -> &'a LuaMessage {
+        match reply {
+            LuaMessage::Table(t) => t.get(name).unwrap_or(&LuaMessage::Nil),
+            other => panic!("expected a table, got {}", other),
+        }
+    }
+
+    #[test]
+    fn ping_paused_and_failing() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"error("broken")"#)
+            .build()
+            .unwrap()
+            .start();
+        let sends = (0..10).map(|_| addr.send(LuaMessage::from(1)));
+        let l = futures::future::join_all(sends.collect::<Vec<_>>())
+            .and_then(move |_| {
+                addr.do_send(PauseProcessing {
+                    drain_current: false,
+                    hold_timers: false,
+                });
+                addr.do_send(LuaMessage::from(1));
+                addr.send(Ping)
+            })
+            .map(|reply| {
+                assert_eq!(field(&reply, "handled"), &LuaMessage::from(10));
+                assert_eq!(field(&reply, "errors"), &LuaMessage::from(10));
+                assert_eq!(field(&reply, "paused"), &LuaMessage::from(true));
+                assert_eq!(field(&reply, "pending_coroutines"), &LuaMessage::from(0));
+                let now = unix_millis(SystemTime::now());
+                match field(&reply, "last_error_at_ms") {
+                    LuaMessage::Integer(at) => assert!(*at <= now && *at > now - 5000),
+                    other => panic!("expected the time of the last error, got {}", other),
+                }
+                assert!(
+                    matches!(field(&reply, "uptime_ms"), LuaMessage::Integer(ms) if *ms < 5000)
+                );
+                assert_eq!(field(&reply, "health"), &LuaMessage::Nil);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn deep_ping() {
+        let system = System::new("test");
+
+        let healthy = LuaActorBuilder::new()
+            .with_deep_ping(true)
+            .on_started_with_lua(
+                r#"
+                function health()
+                    return { db = "ok" }
+                end
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+        let hung = LuaActorBuilder::new()
+            .with_deep_ping(true)
+            .on_started_with_lua(
+                r#"
+                function health()
+                    while true do end
+                end
+                "#,
+            )
+            .on_handle_with_lua(r#"return ctx.msg + 1"#)
+            .build()
+            .unwrap()
+            .start();
+
+        let start = Instant::now();
+        let l = healthy
+            .send(Ping)
+            .join(hung.send(Ping))
+            .and_then(move |(healthy, hung_reply)| {
+                assert_eq!(field(&healthy, "health"), &lua_msg!({ db: "ok" }));
+                assert_eq!(field(&hung_reply, "health"), &LuaMessage::Nil);
+                match field(&hung_reply, "health_error") {
+                    LuaMessage::String(e) => assert!(e.contains("aborted"), "{}", e),
+                    other => panic!("expected a health error, got {}", other),
+                }
+                assert!(start.elapsed() < Duration::from_secs(2));
+                // the actor still handles messages
+                hung.send(LuaMessage::from(1))
+            })
+            .map(|res| {
+                assert_eq!(res, LuaMessage::from(2));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,236 @@
+use ::actix::dev::{MessageResponse, ResponseChannel};
+use ::actix::prelude::*;
+use futures::Future;
+use rlua::Error as LuaError;
+
+use crate::actor::{send_error, LuaActor};
+use crate::build_error::LuaActorBuildError;
+use crate::builder::LuaActorBuilder;
+use crate::message::LuaMessage;
+
+/// A router over identical `LuaActor`s, to run one script in several VMs in parallel.
+///
+/// Built with [`LuaActorBuilder::build_pool`] or [`build_pool_on_arbiters`], which start the
+/// members right away. Each `LuaMessage` sent to the pool goes to the next member in
+/// round-robin order, and the reply of the member is forwarded to the sender. A message sent
+/// with `do_send` is passed on with `do_send`, so `ctx.reply_expected` is the same as if the
+/// member got it directly.
+///
+/// The members share nothing, each has its own `ctx.state`. A member which stopped, e.g. with
+/// `ctx.terminate()`, is skipped. The sender gets `{error = "mailbox_closed"}` if the member
+/// stopped before replying, or once all of them stopped.
+///
+/// ```
+/// # extern crate actix;
+/// # extern crate actix_lua;
+/// # extern crate futures;
+/// use actix::prelude::*;
+/// use actix_lua::{LuaActorBuilder, LuaMessage};
+/// use futures::Future;
+///
+/// let system = System::new("pool");
+/// let pool = LuaActorBuilder::new()
+///     .on_handle_with_lua(r#"return ctx.msg * 2"#)
+///     .build_pool(4)
+///     .unwrap()
+///     .start();
+/// // e.g. for `LuaActorBuilder::with_recipient` of another actor
+/// let recipient = pool.recipient();
+/// let double = recipient.send(LuaMessage::from(21)).map(|res| {
+///     assert_eq!(res, LuaMessage::from(42));
+///     System::current().stop();
+/// });
+/// Arbiter::spawn(double.map_err(|e| panic!("{}", e)));
+/// system.run();
+/// ```
+///
+/// [`LuaActorBuilder::build_pool`]: struct.LuaActorBuilder.html#method.build_pool
+/// [`build_pool_on_arbiters`]: struct.LuaActorBuilder.html#method.build_pool_on_arbiters
+pub struct LuaActorPool {
+    members: Vec<Addr<LuaActor>>,
+    next: usize,
+}
+
+impl LuaActorPool {
+    // Build `size` actors with `builder` and start them, each on its own arbiter if
+    // `on_arbiters`.
+    pub(crate) fn new(
+        builder: &LuaActorBuilder,
+        size: usize,
+        on_arbiters: bool,
+    ) -> Result<LuaActorPool, LuaActorBuildError> {
+        if size == 0 {
+            return Err(LuaError::RuntimeError(
+                "a LuaActorPool needs at least one member".to_string(),
+            )
+            .into());
+        }
+        // build them all first, so no member is started if one fails
+        let actors = (0..size)
+            .map(|_| builder.clone().build())
+            .collect::<Result<Vec<_>, _>>()?;
+        let members = actors
+            .into_iter()
+            .map(|actor| {
+                if on_arbiters {
+                    Arbiter::start(move |_| actor)
+                } else {
+                    actor.start()
+                }
+            })
+            .collect();
+        Ok(LuaActorPool { members, next: 0 })
+    }
+
+    /// The addresses of the members, in routing order.
+    pub fn members(&self) -> &[Addr<LuaActor>] {
+        &self.members
+    }
+
+    // The next member still running, in round-robin order.
+    fn route(&mut self) -> Option<Addr<LuaActor>> {
+        for _ in 0..self.members.len() {
+            let member = &self.members[self.next];
+            self.next = (self.next + 1) % self.members.len();
+            if member.connected() {
+                return Some(member.clone());
+            }
+        }
+        None
+    }
+}
+
+impl Actor for LuaActorPool {
+    type Context = Context<Self>;
+}
+
+impl Handler<LuaMessage> for LuaActorPool {
+    type Result = PoolReply;
+
+    fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> PoolReply {
+        PoolReply {
+            member: self.route(),
+            msg,
+        }
+    }
+}
+
+/// The reply of a `LuaActorPool`, forwarded from the member which handled the message.
+///
+/// The message is sent to the member in `MessageResponse::handle`, once it's known whether
+/// the sender waits for the reply.
+pub struct PoolReply {
+    member: Option<Addr<LuaActor>>,
+    msg: LuaMessage,
+}
+
+impl MessageResponse<LuaActorPool, LuaMessage> for PoolReply {
+    fn handle<R: ResponseChannel<LuaMessage>>(self, _: &mut Context<LuaActorPool>, tx: Option<R>) {
+        let member = match self.member {
+            Some(member) => member,
+            None => {
+                if let Some(tx) = tx {
+                    tx.send(send_error("mailbox_closed", None, false));
+                }
+                return;
+            }
+        };
+        match tx {
+            Some(tx) => Arbiter::spawn(member.send(self.msg).then(move |res| {
+                tx.send(res.unwrap_or_else(|_| send_error("mailbox_closed", None, false)));
+                Ok(())
+            })),
+            None => member.do_send(self.msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use futures_timer::Delay;
+    use std::time::Duration;
+
+    fn counter() -> LuaActorBuilder {
+        LuaActorBuilder::new().on_handle_with_lua(
+            r#"
+            if ctx.msg == "count" then
+                return ctx.state.count or 0
+            elseif ctx.msg == "stop" then
+                ctx.terminate()
+                return
+            end
+            ctx.state.count = (ctx.state.count or 0) + 1
+            return ctx.msg
+            "#,
+        )
+    }
+
+    // The `count` of each member.
+    fn counts(
+        members: Vec<Addr<LuaActor>>,
+    ) -> impl Future<Item = Vec<LuaMessage>, Error = MailboxError> {
+        future::join_all(
+            members
+                .into_iter()
+                .map(|m| m.send(LuaMessage::from("count")))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn run_pool(on_arbiters: bool) {
+        let system = System::new("test");
+
+        let pool = LuaActorPool::new(&counter(), 3, on_arbiters).unwrap();
+        let members = pool.members().to_vec();
+        let addr = pool.start();
+        let recipient = addr.clone().recipient();
+        let sends = (0..6).map(|n| recipient.send(LuaMessage::from(n)));
+        let l = future::join_all(sends.collect::<Vec<_>>())
+            .and_then(move |replies| {
+                let expected = (0..6).map(LuaMessage::from).collect::<Vec<_>>();
+                assert_eq!(replies, expected);
+                counts(members.clone()).map(move |counts| (members, counts))
+            })
+            .and_then(move |(members, counts)| {
+                assert_eq!(counts, vec![LuaMessage::from(2); 3]);
+                // the next member stops, the others take its messages
+                addr.do_send(LuaMessage::from("stop"));
+                Delay::new(Duration::from_millis(50))
+                    .map_err(|e| panic!("{}", e))
+                    .and_then(move |_| {
+                        let sends = (0..4).map(|n| addr.send(LuaMessage::from(n)));
+                        future::join_all(sends.collect::<Vec<_>>())
+                    })
+                    .map(move |replies| (members, replies))
+            })
+            .and_then(|(members, replies)| {
+                let expected = (0..4).map(LuaMessage::from).collect::<Vec<_>>();
+                assert_eq!(replies, expected);
+                counts(members[1..].to_vec())
+            })
+            .map(|counts| {
+                assert_eq!(counts, vec![LuaMessage::from(4); 2]);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_pool() {
+        run_pool(false);
+    }
+
+    #[test]
+    fn lua_actor_pool_on_arbiters() {
+        run_pool(true);
+    }
+
+    #[test]
+    fn lua_actor_pool_empty() {
+        assert!(LuaActorPool::new(&counter(), 0, false).is_err());
+    }
+}
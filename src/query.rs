@@ -0,0 +1,562 @@
+//! Query the state of a running `LuaActor` without running Lua.
+//!
+//! [`QueryLuaState`] selects values of `ctx.state` with a small path language, evaluated in
+//! Rust over a snapshot of the state:
+//!
+//! * `sessions.alice.idle`, `sessions['a key']`, `items[1]`: keys of tables
+//! * `sessions[*]` or `sessions.*`: every value of a table, in key order
+//! * `sessions[?(@.idle > 300)]`: the values of a table matching a predicate. `@` is the value,
+//!   followed by an optional path, e.g. `@.user.name`. Values are compared with `==`, `!=`,
+//!   `<`, `<=`, `>`, `>=` to a number, a quoted string, `true`, `false` or `nil`. `@.idle`
+//!   alone is true if the value is present.
+//! * `.count()`: the number of selected values, last step only
+//!
+//! The selector may start with `$`, the root of `ctx.state`. The reply is a list of the
+//! selected values, or their number with `count()`.
+//!
+//! [`QueryLuaState`]: struct.QueryLuaState.html
+use ::actix::prelude::*;
+
+use crate::actor::LuaActor;
+use crate::message::{sort_keys, LuaMessage};
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Select values of `ctx.state`, see the [module documentation](index.html).
+///
+/// ```
+/// # extern crate actix;
+/// # extern crate actix_lua;
+/// # extern crate futures;
+/// use actix::prelude::*;
+/// use actix_lua::query::QueryLuaState;
+/// use actix_lua::{LuaActorBuilder, LuaMessage};
+/// use futures::Future;
+///
+/// let system = System::new("query");
+/// let addr = LuaActorBuilder::new()
+///     .on_started_with_lua(r#"ctx.state.sessions = {a = {idle = 10}, b = {idle = 500}}"#)
+///     .build()
+///     .unwrap()
+///     .start();
+/// let idle = addr
+///     .send(QueryLuaState::new("sessions[?(@.idle > 300)].count()"))
+///     .map(|res| {
+///         assert_eq!(res.unwrap(), LuaMessage::from(1));
+///         System::current().stop();
+///     });
+/// Arbiter::spawn(idle.map_err(|e| panic!("{}", e)));
+/// system.run();
+/// ```
+pub struct QueryLuaState {
+    pub selector: String,
+}
+
+impl QueryLuaState {
+    pub fn new(selector: &str) -> QueryLuaState {
+        QueryLuaState {
+            selector: selector.to_string(),
+        }
+    }
+}
+
+impl Message for QueryLuaState {
+    type Result = Result<LuaMessage, QueryError>;
+}
+
+impl Handler<QueryLuaState> for LuaActor {
+    type Result = Result<LuaMessage, QueryError>;
+
+    fn handle(&mut self, msg: QueryLuaState, ctx: &mut Context<Self>) -> Self::Result {
+        self.resume_sends(ctx);
+        let selector = Selector::parse(&msg.selector)?;
+        let state = self
+            .runtime
+            .get_state()
+            .map_err(|e| QueryError::State(e.to_string()))?;
+        Ok(selector.eval(&state))
+    }
+}
+
+/// Why a `QueryLuaState` failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryError {
+    /// the selector is malformed, `position` is the byte offset of the error in it
+    Parse { position: usize, message: String },
+    /// `ctx.state` couldn't be converted
+    State(String),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::Parse { position, message } => {
+                write!(f, "invalid selector at position {}: {}", position, message)
+            }
+            QueryError::State(e) => write!(f, "failed to convert ctx.state: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+// `@.path op value`, or `@.path` to test that the value is present.
+#[derive(Debug, Clone, PartialEq)]
+struct Predicate {
+    path: Vec<String>,
+    test: Option<(Op, LuaMessage)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Key(String),
+    All,
+    Filter(Predicate),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Selector {
+    steps: Vec<Step>,
+    count: bool,
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, QueryError> {
+        Err(QueryError::Parse {
+            position: self.pos,
+            message: message.to_string(),
+        })
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), QueryError> {
+        if self.eat(s) {
+            Ok(())
+        } else {
+            self.error(&format!("expected `{}`", s))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.pos = self.src.len() - trimmed.len();
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let end = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.pos += end;
+        &rest[..end]
+    }
+
+    fn ident(&mut self) -> Result<String, QueryError> {
+        let ident = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        if ident.is_empty() {
+            return self.error("expected a key");
+        }
+        Ok(ident.to_string())
+    }
+
+    fn quoted(&mut self) -> Result<String, QueryError> {
+        let start = self.pos;
+        let quote = match self.peek() {
+            Some(c @ '\'') | Some(c @ '"') => c,
+            _ => return self.error("expected a quoted string"),
+        };
+        self.pos += 1;
+        let mut s = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, c)) => s.push(c),
+                    None => break,
+                },
+                c if c == quote => {
+                    self.pos += i + 1;
+                    return Ok(s);
+                }
+                c => s.push(c),
+            }
+        }
+        self.pos = start;
+        self.error("unterminated string")
+    }
+
+    fn literal(&mut self) -> Result<LuaMessage, QueryError> {
+        match self.peek() {
+            Some('\'') | Some('"') => return self.quoted().map(LuaMessage::from),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let start = self.pos;
+                self.eat("-");
+                self.take_while(|c| {
+                    c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || c == '+' || c == '-'
+                });
+                let number = &self.src[start..self.pos];
+                if let Ok(n) = number.parse::<i64>() {
+                    return Ok(LuaMessage::from(n));
+                }
+                if let Ok(n) = number.parse::<f64>() {
+                    return Ok(LuaMessage::from(n));
+                }
+                self.pos = start;
+                return self.error("invalid number");
+            }
+            _ => {}
+        }
+        for (word, value) in &[
+            ("true", LuaMessage::from(true)),
+            ("false", LuaMessage::from(false)),
+            ("nil", LuaMessage::Nil),
+        ] {
+            if self.eat(word) {
+                return Ok(value.clone());
+            }
+        }
+        self.error("expected a number, a string, true, false or nil")
+    }
+
+    fn predicate(&mut self) -> Result<Predicate, QueryError> {
+        self.skip_whitespace();
+        self.expect("@")?;
+        let mut path = vec![];
+        while self.eat(".") {
+            path.push(self.ident()?);
+        }
+        self.skip_whitespace();
+        let ops = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ];
+        let op = ops.iter().find(|(s, _)| self.eat(s)).map(|(_, op)| *op);
+        let test = match op {
+            Some(op) => {
+                self.skip_whitespace();
+                let value = self.literal()?;
+                self.skip_whitespace();
+                Some((op, value))
+            }
+            None => None,
+        };
+        Ok(Predicate { path, test })
+    }
+
+    fn bracket(&mut self) -> Result<Step, QueryError> {
+        let step = if self.eat("*") {
+            Step::All
+        } else if self.eat("?(") {
+            let predicate = self.predicate()?;
+            self.expect(")")?;
+            Step::Filter(predicate)
+        } else {
+            match self.peek() {
+                Some('\'') | Some('"') => Step::Key(self.quoted()?),
+                Some(c) if c.is_ascii_digit() => {
+                    Step::Key(self.take_while(|c| c.is_ascii_digit()).to_string())
+                }
+                _ => return self.error("expected `*`, `?(`, a quoted key or an index"),
+            }
+        };
+        self.expect("]")?;
+        Ok(step)
+    }
+}
+
+impl Selector {
+    fn parse(src: &str) -> Result<Selector, QueryError> {
+        let mut p = Parser { src, pos: 0 };
+        let mut steps = vec![];
+        let mut count = false;
+        p.skip_whitespace();
+        let rooted = p.eat("$");
+        while p.pos < src.len() {
+            if p.eat(".count()") {
+                count = true;
+                p.skip_whitespace();
+                if p.pos < src.len() {
+                    return p.error("count() must be the last step");
+                }
+                break;
+            }
+            if p.eat(".") {
+                if p.eat("*") {
+                    steps.push(Step::All);
+                } else {
+                    steps.push(Step::Key(p.ident()?));
+                }
+            } else if p.eat("[") {
+                steps.push(p.bracket()?);
+            } else if steps.is_empty() && !rooted {
+                steps.push(Step::Key(p.ident()?));
+            } else {
+                return p.error("expected `.` or `[`");
+            }
+        }
+        Ok(Selector { steps, count })
+    }
+
+    fn eval(&self, root: &LuaMessage) -> LuaMessage {
+        let mut selected = vec![root];
+        for step in &self.steps {
+            selected = selected
+                .into_iter()
+                .flat_map(|value| match step {
+                    Step::Key(key) => value.get(key).into_iter().collect(),
+                    Step::All => children(value),
+                    Step::Filter(predicate) => children(value)
+                        .into_iter()
+                        .filter(|child| predicate.matches(child))
+                        .collect(),
+                })
+                .collect();
+        }
+        if self.count {
+            return LuaMessage::from(selected.len() as i64);
+        }
+        LuaMessage::Array(selected.into_iter().cloned().collect())
+    }
+}
+
+// The keys of a path such as `a.b['c'][1]`, see `UpdateLuaState`.
+pub(crate) fn parse_path(src: &str) -> Result<Vec<String>, QueryError> {
+    let selector = Selector::parse(src)?;
+    if selector.count || selector.steps.is_empty() {
+        return Err(QueryError::Parse {
+            position: 0,
+            message: "expected a path of keys".to_string(),
+        });
+    }
+    selector
+        .steps
+        .into_iter()
+        .map(|step| match step {
+            Step::Key(key) => Ok(key),
+            _ => Err(QueryError::Parse {
+                position: 0,
+                message: "expected a path of keys, not `*` or a filter".to_string(),
+            }),
+        })
+        .collect()
+}
+
+// The values of a table in key order or the elements of an array, nothing for the other
+// values.
+fn children(value: &LuaMessage) -> Vec<&LuaMessage> {
+    match value {
+        LuaMessage::Table(t) => {
+            let mut keys: Vec<_> = t.keys().collect();
+            sort_keys(&mut keys);
+            keys.into_iter().map(|k| &t[k]).collect()
+        }
+        LuaMessage::Array(a) => a.iter().collect(),
+        _ => vec![],
+    }
+}
+
+impl Predicate {
+    fn matches(&self, value: &LuaMessage) -> bool {
+        let value = self
+            .path
+            .iter()
+            .try_fold(value, |value, key| value.get(key))
+            .unwrap_or(&LuaMessage::Nil);
+        match self.test {
+            None => *value != LuaMessage::Nil,
+            Some((op, ref expected)) => match compare(value, expected) {
+                Some(ordering) => match op {
+                    Op::Eq => ordering == Ordering::Equal,
+                    Op::Ne => ordering != Ordering::Equal,
+                    Op::Lt => ordering == Ordering::Less,
+                    Op::Le => ordering != Ordering::Greater,
+                    Op::Gt => ordering == Ordering::Greater,
+                    Op::Ge => ordering != Ordering::Less,
+                },
+                // values of different types are only unequal
+                None => op == Op::Ne,
+            },
+        }
+    }
+}
+
+// Numbers and strings are ordered, the other values are only equal to themselves.
+pub(crate) fn compare(a: &LuaMessage, b: &LuaMessage) -> Option<Ordering> {
+    match (a, b) {
+        (LuaMessage::Integer(a), LuaMessage::Integer(b)) => Some(a.cmp(b)),
+        (LuaMessage::Integer(a), LuaMessage::Number(b)) => (*a as f64).partial_cmp(b),
+        (LuaMessage::Number(a), LuaMessage::Integer(b)) => a.partial_cmp(&(*b as f64)),
+        (LuaMessage::Number(a), LuaMessage::Number(b)) => a.partial_cmp(b),
+        (LuaMessage::String(a), LuaMessage::String(b)) => Some(a.cmp(b)),
+        (a, b) if a == b => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LuaActorBuilder;
+    use crate::lua_msg;
+    use futures::Future;
+
+    fn query(selector: &str, state: &LuaMessage) -> LuaMessage {
+        Selector::parse(selector).unwrap().eval(state)
+    }
+
+    fn sessions() -> LuaMessage {
+        lua_msg!({
+            sessions: {
+                alice: { idle: 400, user: { role: "admin" } },
+                bob: { idle: 20, user: { role: "user" } },
+                carol: { idle: 301.5 },
+            },
+            items: [ "a", "b", "c" ],
+        })
+    }
+
+    #[test]
+    fn query_nested_selection() {
+        let state = sessions();
+        assert_eq!(query("sessions.alice.idle", &state), lua_msg!([400]));
+        assert_eq!(query("$.sessions['bob'].idle", &state), lua_msg!([20]));
+        assert_eq!(query("items[2]", &state), lua_msg!(["b"]));
+        assert_eq!(
+            query("sessions[*].idle", &state),
+            lua_msg!([400, 20, 301.5])
+        );
+        assert_eq!(
+            query("sessions.*.user.role", &state),
+            lua_msg!(["admin", "user"])
+        );
+        assert_eq!(query("sessions.dave.idle", &state), lua_msg!([]));
+        assert_eq!(query("$", &state), lua_msg!([state.clone()]));
+    }
+
+    #[test]
+    fn query_predicates() {
+        let state = sessions();
+        assert_eq!(
+            query("sessions[?(@.idle > 300)].idle", &state),
+            lua_msg!([400, 301.5])
+        );
+        assert_eq!(
+            query("sessions[?(@.user.role == 'admin')].idle", &state),
+            lua_msg!([400])
+        );
+        assert_eq!(
+            query("sessions[?(@.user)].idle", &state),
+            lua_msg!([400, 20])
+        );
+        assert_eq!(
+            query("sessions[?(@.user.role != \"admin\")].idle", &state),
+            lua_msg!([20, 301.5])
+        );
+        assert_eq!(query("items[?(@ >= 'b')]", &state), lua_msg!(["b", "c"]));
+        // values of other types don't match comparisons
+        assert_eq!(query("sessions[?(@.idle < 'x')]", &state), lua_msg!([]));
+    }
+
+    #[test]
+    fn query_count() {
+        let state = sessions();
+        assert_eq!(
+            query("sessions[?(@.idle > 300)].count()", &state),
+            LuaMessage::from(2)
+        );
+        assert_eq!(query("items.count()", &state), LuaMessage::from(1));
+        assert_eq!(query("items[*].count()", &state), LuaMessage::from(3));
+    }
+
+    #[test]
+    fn query_parse_errors() {
+        let error = |selector: &str| match Selector::parse(selector) {
+            Err(QueryError::Parse { position, message }) => (position, message),
+            res => panic!("{} should be rejected, got {:?}", selector, res),
+        };
+        assert_eq!(
+            error("sessions[?(@.idle > )]"),
+            (
+                20,
+                "expected a number, a string, true, false or nil".to_string()
+            )
+        );
+        assert_eq!(error("sessions[*"), (10, "expected `]`".to_string()));
+        assert_eq!(
+            error("sessions.count().idle"),
+            (16, "count() must be the last step".to_string())
+        );
+        assert_eq!(error("a['b]"), (2, "unterminated string".to_string()));
+        assert_eq!(
+            QueryError::Parse {
+                position: 10,
+                message: "expected `]`".to_string()
+            }
+            .to_string(),
+            "invalid selector at position 10: expected `]`"
+        );
+    }
+
+    #[test]
+    fn query_lua_state() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+                ctx.state.sessions = {}
+                for i = 1, 10 do
+                    ctx.state.sessions["s" .. i] = { idle = i * 100 }
+                end
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(QueryLuaState::new("sessions[?(@.idle > 300)].count()"))
+            .join(addr.send(QueryLuaState::new("sessions[")))
+            .map(|(count, error)| {
+                assert_eq!(count, Ok(LuaMessage::from(7)));
+                assert!(matches!(error, Err(QueryError::Parse { position: 9, .. })));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,599 @@
+use ::actix::prelude::*;
+
+use crate::actor::{DeferredReply, LuaActor, ReplyTx};
+use crate::message::LuaMessage;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// What a `LuaActor` does with the messages above its inbound rate limit, see
+/// `LuaActorBuilder::with_inbound_rate_limit`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShedPolicy {
+    /// Reply `{error = "rate_limited", retry_after_ms = ...}` without running `handle`.
+    Reject,
+    /// Queue up to `max_queued` messages, the oldest queued message is dropped to make room.
+    DropOldest { max_queued: usize },
+    /// Queue up to `max_queued` messages, the others are rejected like with `Reject`.
+    Delay { max_queued: usize },
+}
+
+// Configured by `LuaActorBuilder::with_inbound_rate_limit`.
+#[derive(Clone)]
+pub(crate) struct InboundConfig {
+    pub rate: f64,
+    pub burst: f64,
+    pub policy: ShedPolicy,
+    pub cost_field: Option<String>,
+}
+
+// Token bucket refilled with `rate` tokens per second, up to `burst`.
+//
+// Time is passed in by the caller so the arithmetic can be tested without waiting.
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.updated {
+            let elapsed = now.duration_since(self.updated).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+            self.updated = now;
+        }
+    }
+
+    pub fn tokens(&mut self, now: Instant) -> f64 {
+        self.refill(now);
+        self.tokens
+    }
+
+    // Time until `cost` more tokens than the bucket holds are available, rounded up to the
+    // millisecond so that retrying after it succeeds.
+    fn wait_for(&self, cost: f64) -> Duration {
+        let missing = (cost - self.tokens).max(0.0);
+        Duration::from_millis((missing / self.rate * 1000.0).ceil() as u64)
+    }
+
+    // Take `cost` tokens at `now`, returning how long to wait for them if there are not enough.
+    pub fn try_take(&mut self, cost: f64, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            Ok(())
+        } else {
+            Err(self.wait_for(cost))
+        }
+    }
+}
+
+// A message waiting for tokens, with the channel of the reply if one is expected.
+pub(crate) struct Queued {
+    pub msg: LuaMessage,
+    cost: f64,
+    pub reply: Option<DeferredReply>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Decision {
+    Handle,
+    // how long to wait before retrying
+    Reject(Duration),
+    Queue,
+}
+
+// The inbound rate limit of an actor and the messages waiting for tokens.
+pub(crate) struct InboundLimit {
+    bucket: TokenBucket,
+    policy: ShedPolicy,
+    cost_field: Option<String>,
+    queue: VecDeque<Queued>,
+    // sum of the costs of the queued messages
+    queued_cost: f64,
+    pub drain_scheduled: bool,
+    rejected: u64,
+    dropped: u64,
+    delayed: u64,
+}
+
+impl InboundLimit {
+    pub fn new(config: InboundConfig, now: Instant) -> InboundLimit {
+        InboundLimit {
+            bucket: TokenBucket::new(config.rate, config.burst, now),
+            policy: config.policy,
+            cost_field: config.cost_field,
+            queue: VecDeque::new(),
+            queued_cost: 0.0,
+            drain_scheduled: false,
+            rejected: 0,
+            dropped: 0,
+            delayed: 0,
+        }
+    }
+
+    // Cost of `msg`: the number in its cost field, or 1. A message can't cost more than the
+    // burst, it would never be handled otherwise.
+    pub fn cost(&self, msg: &LuaMessage) -> f64 {
+        let cost = match self.cost_field.as_ref().and_then(|field| msg.get(field)) {
+            Some(LuaMessage::Integer(n)) => *n as f64,
+            Some(LuaMessage::Number(n)) => *n,
+            _ => 1.0,
+        };
+        cost.max(0.0).min(self.bucket.burst)
+    }
+
+    // Whether a message costing `cost` received at `now` is handled now, rejected or queued.
+    // Queued messages go first, later messages are queued behind them.
+    pub fn check(&mut self, cost: f64, now: Instant) -> Decision {
+        let max_queued = match self.policy {
+            ShedPolicy::Reject => {
+                return match self.bucket.try_take(cost, now) {
+                    Ok(()) => Decision::Handle,
+                    Err(wait) => {
+                        self.rejected += 1;
+                        Decision::Reject(wait)
+                    }
+                };
+            }
+            ShedPolicy::DropOldest { max_queued } | ShedPolicy::Delay { max_queued } => max_queued,
+        };
+        if self.queue.is_empty() && self.bucket.try_take(cost, now).is_ok() {
+            return Decision::Handle;
+        }
+        match self.policy {
+            ShedPolicy::Delay { .. } if self.queue.len() >= max_queued => {
+                self.rejected += 1;
+                // once the queued messages were handled
+                Decision::Reject(self.bucket.wait_for(self.queued_cost + cost))
+            }
+            _ => Decision::Queue,
+        }
+    }
+
+    // Queue a message, returning the oldest queued message if it was dropped to make room.
+    pub fn push(
+        &mut self,
+        msg: LuaMessage,
+        cost: f64,
+        reply: Option<DeferredReply>,
+    ) -> Option<Queued> {
+        let dropped = match self.policy {
+            ShedPolicy::DropOldest { max_queued } if self.queue.len() >= max_queued => {
+                self.dropped += 1;
+                self.pop()
+            }
+            _ => None,
+        };
+        self.delayed += 1;
+        self.queued_cost += cost;
+        self.queue.push_back(Queued { msg, cost, reply });
+        dropped
+    }
+
+    fn pop(&mut self) -> Option<Queued> {
+        let queued = self.queue.pop_front()?;
+        self.queued_cost -= queued.cost;
+        Some(queued)
+    }
+
+    // The first queued message if there are tokens for it at `now`, `Err` gives how long to
+    // wait for them.
+    pub fn next_ready(&mut self, now: Instant) -> Result<Option<Queued>, Duration> {
+        let cost = match self.queue.front() {
+            Some(queued) => queued.cost,
+            None => return Ok(None),
+        };
+        self.bucket.try_take(cost, now)?;
+        Ok(self.pop())
+    }
+
+    pub fn stats(&mut self, now: Instant) -> LuaMessage {
+        let mut stats = HashMap::new();
+        stats.insert(
+            "tokens".to_string(),
+            LuaMessage::Number(self.bucket.tokens(now)),
+        );
+        stats.insert(
+            "queued".to_string(),
+            LuaMessage::from(self.queue.len() as i64),
+        );
+        stats.insert(
+            "rejected".to_string(),
+            LuaMessage::from(self.rejected as i64),
+        );
+        stats.insert("dropped".to_string(), LuaMessage::from(self.dropped as i64));
+        stats.insert("delayed".to_string(), LuaMessage::from(self.delayed as i64));
+        LuaMessage::from(stats)
+    }
+}
+
+/// Read the inbound rate limit of a `LuaActor`, see `LuaActorBuilder::with_inbound_rate_limit`.
+///
+/// The reply is `nil` without a rate limit, or a table:
+///
+/// ```lua
+/// {
+///     tokens = 2.5,  -- available now
+///     queued = 0,    -- waiting for tokens
+///     rejected = 0,  -- replied with a `rate_limited` error
+///     dropped = 0,   -- removed from the queue by `DropOldest`
+///     delayed = 0,   -- queued since the actor started
+/// }
+/// ```
+pub struct GetInboundStats;
+
+impl Message for GetInboundStats {
+    type Result = LuaMessage;
+}
+
+impl Handler<GetInboundStats> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, _: GetInboundStats, _: &mut Context<Self>) -> LuaMessage {
+        match self.inbound {
+            Some(ref mut limit) => limit.stats(Instant::now()),
+            None => LuaMessage::Nil,
+        }
+    }
+}
+
+impl LuaActor {
+    // Apply the inbound rate limit to a message of the mailbox, giving it back with the
+    // channel of its reply if it can be handled now.
+    pub(crate) fn limit_inbound<R: ReplyTx>(
+        &mut self,
+        msg: LuaMessage,
+        tx: Option<R>,
+        ctx: &mut Context<Self>,
+    ) -> Option<(LuaMessage, Option<R>)> {
+        let rich = self.rich_send_results;
+        let limit = match self.inbound {
+            Some(ref mut limit) => limit,
+            None => return Some((msg, tx)),
+        };
+        let cost = limit.cost(&msg);
+        match limit.check(cost, Instant::now()) {
+            Decision::Handle => Some((msg, tx)),
+            Decision::Reject(wait) => {
+                if let Some(tx) = tx {
+                    tx.reject("rate_limited", Some(wait), rich);
+                }
+                None
+            }
+            Decision::Queue => {
+                if let Some(dropped) = limit.push(msg, cost, tx.map(ReplyTx::defer)) {
+                    if let Some(tx) = dropped.reply {
+                        tx.reject("rate_limited", Some(Duration::from_secs(0)), rich);
+                    }
+                }
+                self.drain_inbound(ctx);
+                None
+            }
+        }
+    }
+
+    // Handle the queued messages as the tokens allow, in order.
+    fn drain_inbound(&mut self, ctx: &mut Context<Self>) {
+        loop {
+            let next = match self.inbound {
+                Some(ref mut limit) => limit.next_ready(Instant::now()),
+                None => return,
+            };
+            match next {
+                Ok(Some(Queued { msg, reply, .. })) => {
+                    self.dispatch(msg, reply.is_some(), ctx, move |res| {
+                        if let Some(tx) = reply {
+                            tx.reply(res);
+                        }
+                    });
+                }
+                Ok(None) => return,
+                Err(wait) => {
+                    let limit = self.inbound.as_mut().unwrap();
+                    if !limit.drain_scheduled {
+                        limit.drain_scheduled = true;
+                        ctx.run_later(wait, |act, ctx| {
+                            if let Some(ref mut limit) = act.inbound {
+                                limit.drain_scheduled = false;
+                            }
+                            act.drain_inbound(ctx)
+                        });
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor::{HandleError, TryLuaMessage};
+    use crate::builder::LuaActorBuilder;
+    use crate::lua_msg;
+    use futures::Future;
+    use futures_timer::Delay;
+
+    fn limit(policy: ShedPolicy) -> (InboundLimit, Instant) {
+        let start = Instant::now();
+        let config = InboundConfig {
+            rate: 4.0,
+            burst: 3.0,
+            policy,
+            cost_field: Some("cost".to_string()),
+        };
+        (InboundLimit::new(config, start), start)
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(4.0, 3.0, start);
+        for _ in 0..3 {
+            assert_eq!(bucket.try_take(1.0, start), Ok(()));
+        }
+        assert_eq!(bucket.try_take(1.0, start), Err(ms(250)));
+        assert_eq!(bucket.try_take(2.0, start + ms(125)), Err(ms(375)));
+        assert_eq!(bucket.try_take(1.0, start + ms(250)), Ok(()));
+        // refilled up to the burst
+        assert_eq!(bucket.tokens(start + ms(10_000)), 3.0);
+        assert_eq!(bucket.try_take(3.0, start + ms(10_000)), Ok(()));
+    }
+
+    #[test]
+    fn message_cost() {
+        let (limit, _) = limit(ShedPolicy::Reject);
+        assert_eq!(limit.cost(&lua_msg!({ cost: 2 })), 2.0);
+        assert_eq!(limit.cost(&lua_msg!({ cost: 0.5 })), 0.5);
+        assert_eq!(limit.cost(&lua_msg!({ other: 2 })), 1.0);
+        assert_eq!(limit.cost(&LuaMessage::from("cost")), 1.0);
+        // capped to the burst
+        assert_eq!(limit.cost(&lua_msg!({ cost: 100 })), 3.0);
+    }
+
+    #[test]
+    fn reject_policy() {
+        let (mut limit, start) = limit(ShedPolicy::Reject);
+        assert_eq!(limit.check(2.0, start), Decision::Handle);
+        assert_eq!(limit.check(1.0, start), Decision::Handle);
+        assert_eq!(limit.check(1.0, start + ms(125)), Decision::Reject(ms(125)));
+        assert_eq!(limit.check(2.0, start + ms(125)), Decision::Reject(ms(375)));
+        assert_eq!(limit.check(1.0, start + ms(250)), Decision::Handle);
+        assert_eq!(
+            limit.stats(start + ms(250)),
+            lua_msg!({ tokens: 0.0, queued: 0, rejected: 2, dropped: 0, delayed: 0 })
+        );
+    }
+
+    #[test]
+    fn delay_policy() {
+        let (mut limit, start) = limit(ShedPolicy::Delay { max_queued: 2 });
+        for _ in 0..3 {
+            assert_eq!(limit.check(1.0, start), Decision::Handle);
+        }
+        for n in 0..2 {
+            assert_eq!(limit.check(1.0, start), Decision::Queue);
+            assert!(limit.push(LuaMessage::from(n), 1.0, None).is_none());
+        }
+        // after the 2 queued messages
+        assert_eq!(limit.check(1.0, start), Decision::Reject(ms(750)));
+
+        assert_eq!(limit.next_ready(start + ms(125)).err(), Some(ms(125)));
+        let first = limit.next_ready(start + ms(250)).unwrap().unwrap();
+        assert_eq!(first.msg, LuaMessage::from(0));
+        // the messages received meanwhile wait behind the queue
+        assert_eq!(limit.check(1.0, start + ms(625)), Decision::Queue);
+        limit.push(LuaMessage::from(2), 1.0, None);
+        let next: Vec<_> = (0..2)
+            .map(|_| limit.next_ready(start + ms(750)).unwrap().unwrap().msg)
+            .collect();
+        assert_eq!(next, vec![LuaMessage::from(1), LuaMessage::from(2)]);
+        assert!(limit.next_ready(start + ms(750)).unwrap().is_none());
+        assert_eq!(
+            limit.stats(start + ms(875)),
+            lua_msg!({ tokens: 0.5, queued: 0, rejected: 1, dropped: 0, delayed: 3 })
+        );
+    }
+
+    #[test]
+    fn drop_oldest_policy() {
+        let (mut limit, start) = limit(ShedPolicy::DropOldest { max_queued: 2 });
+        for _ in 0..3 {
+            assert_eq!(limit.check(1.0, start), Decision::Handle);
+        }
+        let dropped: Vec<_> = (0..4)
+            .map(|n| {
+                assert_eq!(limit.check(1.0, start), Decision::Queue);
+                limit
+                    .push(LuaMessage::from(n), 1.0, None)
+                    .map(|queued| queued.msg)
+            })
+            .collect();
+        assert_eq!(
+            dropped,
+            vec![
+                None,
+                None,
+                Some(LuaMessage::from(0)),
+                Some(LuaMessage::from(1))
+            ]
+        );
+        let next: Vec<_> = (0..2)
+            .map(|_| limit.next_ready(start + ms(500)).unwrap().unwrap().msg)
+            .collect();
+        assert_eq!(next, vec![LuaMessage::from(2), LuaMessage::from(3)]);
+        assert_eq!(
+            limit.stats(start + ms(500)),
+            lua_msg!({ tokens: 0.0, queued: 0, rejected: 0, dropped: 2, delayed: 4 })
+        );
+    }
+
+    fn limited(rate: f64, burst: f64, policy: ShedPolicy) -> Addr<LuaActor> {
+        LuaActorBuilder::new()
+            .with_inbound_rate_limit(rate, burst, policy)
+            .on_started_with_lua("ctx.state.handled = {}")
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "handled" then
+                    return ctx.state.handled
+                end
+                table.insert(ctx.state.handled, ctx.msg)
+                return ctx.msg
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start()
+    }
+
+    fn stats(addr: &Addr<LuaActor>) -> impl Future<Item = LuaMessage, Error = MailboxError> {
+        addr.send(GetInboundStats)
+    }
+
+    fn retry_after(res: &LuaMessage) -> i64 {
+        assert_eq!(res.get("error"), Some(&LuaMessage::from("rate_limited")));
+        match res.get("retry_after_ms") {
+            Some(LuaMessage::Integer(ms)) => *ms,
+            res => panic!("unexpected retry_after_ms {:?}", res),
+        }
+    }
+
+    #[test]
+    fn inbound_reject() {
+        let system = System::new("test");
+
+        let addr = limited(1.0, 2.0, ShedPolicy::Reject);
+        let sends: Vec<_> = (1..=3).map(|n| addr.send(LuaMessage::from(n))).collect();
+        let l = futures::future::join_all(sends)
+            .and_then(move |res| {
+                assert_eq!(res[..2], [LuaMessage::from(1), LuaMessage::from(2)]);
+                let ms = retry_after(&res[2]);
+                assert!(ms > 900 && ms <= 1000, "{}", ms);
+                stats(&addr)
+            })
+            .map(|stats| {
+                assert_eq!(stats.get("rejected"), Some(&LuaMessage::from(1)));
+                assert_eq!(stats.get("delayed"), Some(&LuaMessage::from(0)));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn inbound_try_messages() {
+        let system = System::new("test");
+
+        let addr = limited(1.0, 1.0, ShedPolicy::Reject);
+        let sends: Vec<_> = (1..=2)
+            .map(|n| addr.send(TryLuaMessage(LuaMessage::from(n))))
+            .collect();
+        let l = futures::future::join_all(sends)
+            .and_then(move |res| {
+                assert_eq!(
+                    res,
+                    vec![
+                        Ok(LuaMessage::from(1)),
+                        Err(HandleError::Error("rate_limited".to_string()))
+                    ]
+                );
+                stats(&addr)
+            })
+            .map(|stats| {
+                assert_eq!(stats.get("rejected"), Some(&LuaMessage::from(1)));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn inbound_delay() {
+        let system = System::new("test");
+
+        // a token every 50ms
+        let addr = limited(20.0, 1.0, ShedPolicy::Delay { max_queued: 2 });
+        let start = Instant::now();
+        let sends: Vec<_> = (1..=4).map(|n| addr.send(LuaMessage::from(n))).collect();
+        let l = futures::future::join_all(sends)
+            .and_then(move |res| {
+                assert!(start.elapsed() >= Duration::from_millis(100));
+                assert_eq!(res[..3], [1, 2, 3].map(LuaMessage::from));
+                // after the queued messages
+                let ms = retry_after(&res[3]);
+                assert!(ms > 50 && ms <= 150, "{}", ms);
+                stats(&addr).join(addr.send(LuaMessage::from("handled")))
+            })
+            .map(|(stats, handled)| {
+                assert_eq!(handled, lua_msg!([1, 2, 3]));
+                assert_eq!(stats.get("rejected"), Some(&LuaMessage::from(1)));
+                assert_eq!(stats.get("delayed"), Some(&LuaMessage::from(2)));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn inbound_drop_oldest() {
+        let system = System::new("test");
+
+        let addr = limited(20.0, 1.0, ShedPolicy::DropOldest { max_queued: 1 });
+        for n in 1..=3 {
+            addr.do_send(LuaMessage::from(n));
+        }
+        let l = Delay::new(Duration::from_millis(100))
+            .map_err(|e| -> MailboxError { panic!("{}", e) })
+            .and_then(move |_| stats(&addr).join(addr.send(LuaMessage::from("handled"))))
+            .map(|(stats, handled)| {
+                // 2 was dropped for 3
+                assert_eq!(handled, lua_msg!([1, 3]));
+                assert_eq!(stats.get("dropped"), Some(&LuaMessage::from(1)));
+                assert_eq!(stats.get("queued"), Some(&LuaMessage::from(0)));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn inbound_under_limit() {
+        let system = System::new("test");
+
+        let addr = limited(1000.0, 10.0, ShedPolicy::Delay { max_queued: 1 });
+        let sends: Vec<_> = (1..=10).map(|n| addr.send(LuaMessage::from(n))).collect();
+        let l = futures::future::join_all(sends)
+            .and_then(move |res| {
+                assert_eq!(res, (1..=10).map(LuaMessage::from).collect::<Vec<_>>());
+                stats(&addr)
+            })
+            .map(|stats| {
+                assert_eq!(stats.get("rejected"), Some(&LuaMessage::from(0)));
+                assert_eq!(stats.get("delayed"), Some(&LuaMessage::from(0)));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+}
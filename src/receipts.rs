@@ -0,0 +1,366 @@
+use ::actix::prelude::*;
+
+use crate::actor::LuaActor;
+use crate::message::LuaMessage;
+use std::collections::{HashMap, VecDeque};
+
+pub(crate) const DEFAULT_MAX_RECEIPTS: usize = 1024;
+
+// Whether a message sent with `ctx.do_send_confirmed` reached the mailbox of its recipient.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum DeliveryStatus {
+    Accepted,
+    MailboxFull,
+    // the recipient stopped, or there is no such recipient
+    Closed,
+}
+
+impl DeliveryStatus {
+    pub fn of<M>(res: Option<Result<(), SendError<M>>>) -> DeliveryStatus {
+        match res {
+            Some(Ok(())) => DeliveryStatus::Accepted,
+            Some(Err(SendError::Full(_))) => DeliveryStatus::MailboxFull,
+            Some(Err(SendError::Closed(_))) | None => DeliveryStatus::Closed,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DeliveryStatus::Accepted => "accepted",
+            DeliveryStatus::MailboxFull => "mailbox_full",
+            DeliveryStatus::Closed => "closed",
+        }
+    }
+}
+
+// The receipts of `ctx.do_send_confirmed`, see `LuaActor`.
+pub(crate) struct Receipts {
+    pub max_outstanding: usize,
+    next_id: i64,
+    // receipts waiting for `on_delivery_receipt`, in order
+    outstanding: VecDeque<(i64, DeliveryStatus)>,
+    accepted: u64,
+    mailbox_full: u64,
+    closed: u64,
+    overflowed: u64,
+}
+
+impl Receipts {
+    pub fn new(max_outstanding: usize) -> Receipts {
+        Receipts {
+            max_outstanding,
+            next_id: 0,
+            outstanding: VecDeque::new(),
+            accepted: 0,
+            mailbox_full: 0,
+            closed: 0,
+            overflowed: 0,
+        }
+    }
+
+    // Count the delivery of a message and keep its receipt for `on_delivery_receipt`, returning
+    // the id of the receipt and:
+    // * `Issued::First` if no receipt was waiting, `DeliverReceipts` must be sent,
+    // * `Issued::Queued` if the receipt waits with others,
+    // * `Issued::Overflowed` if too many receipts are waiting, it isn't kept.
+    pub fn issue(&mut self, status: DeliveryStatus) -> (i64, Issued) {
+        self.next_id += 1;
+        match status {
+            DeliveryStatus::Accepted => self.accepted += 1,
+            DeliveryStatus::MailboxFull => self.mailbox_full += 1,
+            DeliveryStatus::Closed => self.closed += 1,
+        }
+        if self.outstanding.len() >= self.max_outstanding {
+            self.overflowed += 1;
+            return (self.next_id, Issued::Overflowed);
+        }
+        self.outstanding.push_back((self.next_id, status));
+        if self.outstanding.len() == 1 {
+            (self.next_id, Issued::First)
+        } else {
+            (self.next_id, Issued::Queued)
+        }
+    }
+
+    // The receipts waiting for `on_delivery_receipt`.
+    pub fn take(&mut self) -> VecDeque<(i64, DeliveryStatus)> {
+        std::mem::take(&mut self.outstanding)
+    }
+
+    pub fn stats(&self) -> LuaMessage {
+        let mut stats = HashMap::new();
+        for (name, count) in [
+            ("outstanding", self.outstanding.len() as u64),
+            ("accepted", self.accepted),
+            ("mailbox_full", self.mailbox_full),
+            ("closed", self.closed),
+            ("overflowed", self.overflowed),
+        ] {
+            stats.insert(name.to_string(), LuaMessage::from(count as i64));
+        }
+        LuaMessage::from(stats)
+    }
+}
+
+// The receipt passed to `on_delivery_receipt`, or to the unprocessed sink if it overflows.
+pub(crate) fn receipt(id: i64, status: DeliveryStatus) -> LuaMessage {
+    let mut receipt = HashMap::new();
+    receipt.insert("receipt".to_string(), LuaMessage::from(id));
+    receipt.insert("status".to_string(), LuaMessage::from(status.name()));
+    LuaMessage::from(receipt)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Issued {
+    First,
+    Queued,
+    Overflowed,
+}
+
+// Call `on_delivery_receipt` with the waiting receipts, in order, once the invocation which sent
+// the messages returned.
+pub(crate) struct DeliverReceipts;
+
+impl Message for DeliverReceipts {
+    type Result = ();
+}
+
+impl Handler<DeliverReceipts> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, _: DeliverReceipts, ctx: &mut Context<Self>) {
+        let receipts = self.receipts.take();
+        // the receipts are only counted
+        if !self.runtime.has_function("on_delivery_receipt") {
+            return;
+        }
+        for (id, status) in receipts {
+            if Self::terminal(ctx) {
+                return;
+            }
+            let res = self.invoke_hook(
+                ctx,
+                "handle",
+                "__run",
+                vec![
+                    LuaMessage::from("__delivery_receipt"),
+                    receipt(id, status),
+                    LuaMessage::from(false),
+                ],
+                false,
+            );
+            if let Err(e) = res {
+                log::error!("{} on_delivery_receipt failed: {}", self.log_prefix, e);
+            }
+        }
+    }
+}
+
+/// Read the delivery receipts of the `ctx.do_send_confirmed` of a `LuaActor`.
+///
+/// The reply is a table of counts since the actor started:
+///
+/// ```lua
+/// {
+///     accepted = 10,
+///     mailbox_full = 1,
+///     closed = 0,       -- including the unknown recipients
+///     outstanding = 2,  -- waiting for `on_delivery_receipt`
+///     overflowed = 0,   -- beyond `with_max_receipts`, passed to the unprocessed sink
+/// }
+/// ```
+pub struct GetDeliveryStats;
+
+impl Message for GetDeliveryStats {
+    type Result = LuaMessage;
+}
+
+impl Handler<GetDeliveryStats> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, _: GetDeliveryStats, _: &mut Context<Self>) -> LuaMessage {
+        self.receipts.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LuaActorBuilder;
+    use crate::lua_msg;
+    use futures::Future;
+    use futures_timer::Delay;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    // Logs the messages it handles, with a mailbox of `capacity` messages if set.
+    struct Sink(Arc<Mutex<Vec<LuaMessage>>>, Option<usize>);
+
+    impl Actor for Sink {
+        type Context = Context<Self>;
+
+        fn started(&mut self, ctx: &mut Context<Self>) {
+            if let Some(capacity) = self.1 {
+                ctx.set_mailbox_capacity(capacity);
+            }
+        }
+    }
+
+    impl Handler<LuaMessage> for Sink {
+        type Result = LuaMessage;
+
+        fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> LuaMessage {
+            self.0.lock().unwrap().push(msg);
+            LuaMessage::Nil
+        }
+    }
+
+    struct Stopped;
+
+    impl Actor for Stopped {
+        type Context = Context<Self>;
+
+        fn started(&mut self, ctx: &mut Context<Self>) {
+            ctx.stop();
+        }
+    }
+
+    impl Handler<LuaMessage> for Stopped {
+        type Result = LuaMessage;
+
+        fn handle(&mut self, _: LuaMessage, _: &mut Context<Self>) -> LuaMessage {
+            LuaMessage::Nil
+        }
+    }
+
+    // Sends its index to each recipient of the list `ctx.msg`, returning the receipts, and
+    // replies to "receipts" with the receipts passed to `on_delivery_receipt`.
+    fn confirming_actor() -> LuaActorBuilder {
+        LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+                ctx.state.receipts = {}
+                function on_delivery_receipt(receipt, status)
+                    table.insert(ctx.state.receipts, { receipt = receipt, status = status })
+                end
+                "#,
+            )
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "receipts" then
+                    return ctx.state.receipts
+                end
+                local ids = {}
+                for i, name in ipairs(ctx.msg) do
+                    ids[i] = ctx.do_send_confirmed(name, i)
+                end
+                return ids
+                "#,
+            )
+    }
+
+    #[test]
+    fn delivery_receipts() {
+        let system = System::new("test");
+
+        let delivered = Arc::new(Mutex::new(vec![]));
+        let addr = confirming_actor()
+            .with_recipient("sink", Sink(delivered.clone(), Some(1)).start().recipient())
+            .with_recipient("stopped", Stopped.start().recipient())
+            .build()
+            .unwrap()
+            .start();
+
+        // let `stopped` stop
+        let l = Delay::new(Duration::from_millis(100))
+            .map_err(|e| panic!("{}", e))
+            .and_then(move |_| {
+                addr.send(lua_msg!(["sink", "sink", "stopped", "missing"]))
+                    .and_then(move |ids| {
+                        assert_eq!(ids, lua_msg!([1, 2, 3, 4]));
+                        addr.send(LuaMessage::from("receipts"))
+                            .join(addr.send(GetDeliveryStats))
+                    })
+            })
+            .map(move |(receipts, stats)| {
+                assert_eq!(
+                    receipts,
+                    lua_msg!([
+                        { receipt: 1, status: "accepted" },
+                        { receipt: 2, status: "mailbox_full" },
+                        { receipt: 3, status: "closed" },
+                        { receipt: 4, status: "closed" },
+                    ])
+                );
+                assert_eq!(
+                    stats,
+                    lua_msg!({
+                        accepted: 1,
+                        mailbox_full: 1,
+                        closed: 2,
+                        outstanding: 0,
+                        overflowed: 0,
+                    })
+                );
+                assert_eq!(*delivered.lock().unwrap(), vec![LuaMessage::from(1)]);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn delivery_receipts_overflow() {
+        let system = System::new("test");
+
+        let delivered = Arc::new(Mutex::new(vec![]));
+        let dead_letters = Arc::new(Mutex::new(vec![]));
+        let addr = confirming_actor()
+            .with_max_receipts(1)
+            .with_unprocessed_sink("dead_letters")
+            .with_recipient("sink", Sink(delivered.clone(), None).start().recipient())
+            .with_recipient(
+                "dead_letters",
+                Sink(dead_letters.clone(), None).start().recipient(),
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(lua_msg!(["sink", "sink", "sink"]))
+            .and_then(move |ids| {
+                assert_eq!(ids, lua_msg!([1, 2, 3]));
+                // the hook ran, making room for a receipt
+                addr.send(lua_msg!(["sink"])).and_then(move |_| {
+                    addr.send(LuaMessage::from("receipts"))
+                        .join(addr.send(GetDeliveryStats))
+                })
+            })
+            .map(move |(receipts, stats)| {
+                assert_eq!(
+                    receipts,
+                    lua_msg!([
+                        { receipt: 1, status: "accepted" },
+                        { receipt: 4, status: "accepted" },
+                    ])
+                );
+                assert_eq!(stats.get("accepted"), Some(&LuaMessage::from(4)));
+                assert_eq!(stats.get("overflowed"), Some(&LuaMessage::from(2)));
+                // the messages were sent, only the receipts overflowed
+                assert_eq!(delivered.lock().unwrap().len(), 4);
+                assert_eq!(
+                    *dead_letters.lock().unwrap(),
+                    vec![
+                        lua_msg!({ receipt: 2, status: "accepted" }),
+                        lua_msg!({ receipt: 3, status: "accepted" }),
+                    ]
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,397 @@
+use ::actix::prelude::*;
+use rlua::Error as LuaError;
+
+use crate::actor::{
+    validate_recipient_name, LuaActor, TryLuaMessage, DEFAULT_MAX_RECIPIENT_NAME_LEN,
+};
+use crate::message::LuaMessage;
+use std::collections::HashMap;
+use std::ops::Index;
+
+/// Recipients of a `LuaActor` by name.
+///
+/// Iteration follows insertion order. Replacing a recipient keeps its position and removing one
+/// doesn't change the relative order of the others.
+pub struct Recipients {
+    entries: Vec<Entry>,
+    // position of each name in `entries`
+    index: HashMap<String, usize>,
+    // see `LuaActorBuilder::with_max_recipient_name_len`
+    max_name_len: usize,
+}
+
+struct Entry {
+    name: String,
+    rec: Recipient<LuaMessage>,
+    // set for `LuaActor` recipients, reports handler errors instead of panicking them
+    try_rec: Option<Recipient<TryLuaMessage>>,
+    // set for `LuaActor` recipients, tells if the actor is still running
+    addr: Option<Addr<LuaActor>>,
+}
+
+impl Default for Recipients {
+    fn default() -> Recipients {
+        Recipients {
+            entries: Vec::new(),
+            index: HashMap::new(),
+            max_name_len: DEFAULT_MAX_RECIPIENT_NAME_LEN,
+        }
+    }
+}
+
+impl Recipients {
+    pub fn new() -> Recipients {
+        Recipients::default()
+    }
+
+    pub(crate) fn set_max_name_len(&mut self, len: usize) {
+        self.max_name_len = len;
+    }
+
+    /// Insert a recipient, returning the previous recipient with the same name.
+    ///
+    /// Fails if `name` is not a valid recipient name, see `LuaActor::try_add_recipients`.
+    pub fn try_insert(
+        &mut self,
+        name: String,
+        rec: Recipient<LuaMessage>,
+    ) -> Result<Option<Recipient<LuaMessage>>, LuaError> {
+        validate_recipient_name(&name, self.max_name_len)?;
+        Ok(self.insert_entry(name, rec, None, None))
+    }
+
+    /// Insert a recipient like `HashMap::insert`, which `LuaActor::recipients` used to be.
+    ///
+    /// Panics if `name` is not a valid recipient name, see [`try_insert`](#method.try_insert).
+    #[deprecated(note = "panics on invalid names, use try_insert")]
+    pub fn insert(
+        &mut self,
+        name: String,
+        rec: Recipient<LuaMessage>,
+    ) -> Option<Recipient<LuaMessage>> {
+        match self.try_insert(name, rec) {
+            Ok(prev) => prev,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Insert a `LuaActor`, which can report why it failed to handle a message.
+    ///
+    /// Fails if `name` is not a valid recipient name, see `LuaActor::try_add_recipients`.
+    pub fn insert_lua(
+        &mut self,
+        name: String,
+        addr: &Addr<LuaActor>,
+    ) -> Result<Option<Recipient<LuaMessage>>, LuaError> {
+        validate_recipient_name(&name, self.max_name_len)?;
+        Ok(self.set_lua(name, addr))
+    }
+
+    // Insert a `LuaActor` under a name validated when the actor was built, e.g. a child of
+    // `LuaActorBuilder::with_child`.
+    pub(crate) fn set_lua(
+        &mut self,
+        name: String,
+        addr: &Addr<LuaActor>,
+    ) -> Option<Recipient<LuaMessage>> {
+        self.insert_entry(
+            name,
+            addr.clone().recipient(),
+            Some(addr.clone().recipient()),
+            Some(addr.clone()),
+        )
+    }
+
+    fn insert_entry(
+        &mut self,
+        name: String,
+        rec: Recipient<LuaMessage>,
+        try_rec: Option<Recipient<TryLuaMessage>>,
+        addr: Option<Addr<LuaActor>>,
+    ) -> Option<Recipient<LuaMessage>> {
+        match self.index.get(&name) {
+            Some(&i) => {
+                let entry = &mut self.entries[i];
+                entry.try_rec = try_rec;
+                entry.addr = addr;
+                Some(std::mem::replace(&mut entry.rec, rec))
+            }
+            None => {
+                self.index.insert(name.clone(), self.entries.len());
+                self.entries.push(Entry {
+                    name,
+                    rec,
+                    try_rec,
+                    addr,
+                });
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Recipient<LuaMessage>> {
+        let i = self.index.remove(name)?;
+        let entry = self.entries.remove(i);
+        for e in &self.entries[i..] {
+            *self.index.get_mut(&e.name).unwrap() -= 1;
+        }
+        Some(entry.rec)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Recipient<LuaMessage>> {
+        self.index.get(name).map(|&i| &self.entries[i].rec)
+    }
+
+    pub(crate) fn get_try(&self, name: &str) -> Option<&Recipient<TryLuaMessage>> {
+        self.index
+            .get(name)
+            .and_then(|&i| self.entries[i].try_rec.as_ref())
+    }
+
+    // Whether the recipient `name` is running, `None` if there is no such recipient.
+    //
+    // Only `LuaActor` recipients can be checked, the others are assumed to be running.
+    pub(crate) fn connected(&self, name: &str) -> Option<bool> {
+        self.index
+            .get(name)
+            .map(|&i| self.entries[i].addr.as_ref().is_none_or(Addr::connected))
+    }
+
+    // The `LuaActor` added as `name` with `insert_lua`.
+    pub(crate) fn get_lua(&self, name: &str) -> Option<&Addr<LuaActor>> {
+        self.index
+            .get(name)
+            .and_then(|&i| self.entries[i].addr.as_ref())
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.index.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterate over the recipients in insertion order.
+    pub fn iter(&self) -> RecipientsIter<'_> {
+        RecipientsIter(self.entries.iter())
+    }
+
+    /// Names of the recipients in insertion order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|e| e.name.as_str())
+    }
+}
+
+/// Iterator over the names and recipients of `Recipients`, see `Recipients::iter`.
+pub struct RecipientsIter<'a>(std::slice::Iter<'a, Entry>);
+
+impl<'a> Iterator for RecipientsIter<'a> {
+    type Item = (&'a String, &'a Recipient<LuaMessage>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|e| (&e.name, &e.rec))
+    }
+}
+
+impl<'a> IntoIterator for &'a Recipients {
+    type Item = (&'a String, &'a Recipient<LuaMessage>);
+    type IntoIter = RecipientsIter<'a>;
+
+    fn into_iter(self) -> RecipientsIter<'a> {
+        self.iter()
+    }
+}
+
+// Aliases of recipient names, consulted before the recipients by `ctx.send` and `ctx.do_send`.
+//
+// An alias refers to a recipient name, never to another alias.
+#[derive(Default)]
+pub(crate) struct Aliases {
+    targets: HashMap<String, String>,
+}
+
+impl Aliases {
+    // Point `alias` to `target`, returning the previous target.
+    pub fn set(&mut self, alias: String, target: String) -> Result<Option<String>, LuaError> {
+        let invalid = |reason: String| Err(LuaError::RuntimeError(reason));
+        if alias == target {
+            return invalid(format!("alias {:?} can't refer to itself", alias));
+        }
+        if self.targets.contains_key(&target) {
+            return invalid(format!(
+                "{:?} is an alias, aliases can't refer to aliases",
+                target
+            ));
+        }
+        if let Some((other, _)) = self.targets.iter().find(|(_, t)| **t == alias) {
+            return invalid(format!(
+                "{:?} is the target of alias {:?}, aliases can't refer to aliases",
+                alias, other
+            ));
+        }
+        Ok(self.targets.insert(alias, target))
+    }
+
+    pub fn remove(&mut self, alias: &str) -> Option<String> {
+        self.targets.remove(alias)
+    }
+
+    pub fn get(&self, alias: &str) -> Option<&str> {
+        self.targets.get(alias).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.targets.iter().map(|(a, t)| (a.as_str(), t.as_str()))
+    }
+
+    // The target of `name` if it's an alias, `name` otherwise.
+    pub fn resolve<'a>(&'a self, name: &'a str) -> &'a str {
+        self.get(name).unwrap_or(name)
+    }
+}
+
+impl Index<&str> for Recipients {
+    type Output = Recipient<LuaMessage>;
+
+    fn index(&self, name: &str) -> &Recipient<LuaMessage> {
+        self.get(name).expect("no recipient with this name")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Sink;
+
+    impl Actor for Sink {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<LuaMessage> for Sink {
+        type Result = LuaMessage;
+
+        fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> Self::Result {
+            msg
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        let mut aliases = Aliases::default();
+        assert_eq!(
+            aliases.set("db".to_string(), "db-1".to_string()).unwrap(),
+            None
+        );
+        assert_eq!(aliases.resolve("db"), "db-1");
+        assert_eq!(aliases.resolve("db-1"), "db-1");
+        assert_eq!(
+            aliases.set("db".to_string(), "db-2".to_string()).unwrap(),
+            Some("db-1".to_string())
+        );
+
+        // one level only
+        assert!(aliases
+            .set("primary".to_string(), "db".to_string())
+            .is_err());
+        assert!(aliases.set("db-2".to_string(), "db-3".to_string()).is_err());
+        assert!(aliases
+            .set("self-ref".to_string(), "self-ref".to_string())
+            .is_err());
+
+        assert_eq!(aliases.remove("db"), Some("db-2".to_string()));
+        assert_eq!(aliases.get("db"), None);
+        assert!(aliases.set("primary".to_string(), "db".to_string()).is_ok());
+    }
+
+    #[test]
+    fn recipients_insertion_order() {
+        let system = System::new("test");
+        let rec = || Sink.start().recipient();
+
+        let mut recipients = Recipients::new();
+        for name in &["c", "a", "d", "b", "e"] {
+            assert!(recipients
+                .try_insert(name.to_string(), rec())
+                .unwrap()
+                .is_none());
+        }
+        assert_eq!(
+            recipients.names().collect::<Vec<_>>(),
+            vec!["c", "a", "d", "b", "e"]
+        );
+
+        // replacing keeps the position
+        assert!(recipients
+            .try_insert("d".to_string(), rec())
+            .unwrap()
+            .is_some());
+        assert!(recipients.remove("a").is_some());
+        assert!(recipients.remove("a").is_none());
+        assert_eq!(
+            recipients.names().collect::<Vec<_>>(),
+            vec!["c", "d", "b", "e"]
+        );
+        assert_eq!(
+            recipients.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            vec!["c", "d", "b", "e"]
+        );
+
+        // the index follows the removal
+        assert!(recipients.remove("c").is_some());
+        assert!(recipients.contains_key("e"));
+        assert!(recipients.get("e").is_some());
+        recipients.try_insert("a".to_string(), rec()).unwrap();
+        assert_eq!(
+            recipients.names().collect::<Vec<_>>(),
+            vec!["d", "b", "e", "a"]
+        );
+        assert_eq!(recipients.len(), 4);
+
+        // names are validated like in `LuaActor::try_add_recipients`
+        for name in &["", "self", "a b", "log\ninjected"] {
+            assert!(
+                recipients.try_insert(name.to_string(), rec()).is_err(),
+                "{:?}",
+                name
+            );
+        }
+        recipients.set_max_name_len(4);
+        assert!(recipients.try_insert("long".to_string(), rec()).is_ok());
+        assert!(recipients.try_insert("longer".to_string(), rec()).is_err());
+        assert_eq!(recipients.len(), 5);
+
+        System::current().stop();
+        system.run();
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn recipients_hashmap_api() {
+        let system = System::new("test");
+
+        // code written when `LuaActor::recipients` was a `HashMap`
+        let mut recipients = Recipients::new();
+        assert!(recipients
+            .insert("a".to_string(), Sink.start().recipient())
+            .is_none());
+        assert!(recipients
+            .insert("a".to_string(), Sink.start().recipient())
+            .is_some());
+        recipients.insert("b".to_string(), Sink.start().recipient());
+        assert!(recipients.get("b").is_some());
+        let mut names = vec![];
+        for (name, _) in &recipients {
+            names.push(name.clone());
+        }
+        assert_eq!(names, vec!["a".to_string(), "b".to_string()]);
+
+        System::current().stop();
+        system.run();
+    }
+}
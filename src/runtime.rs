@@ -0,0 +1,2609 @@
+use rlua::Error as LuaError;
+use rlua::{
+    Context, FromLua, Function, HookTriggers, Lua, MultiValue, Scope, StdLib, Table, ToLua, Value,
+    Variadic,
+};
+
+use crate::actor::{validate_recipient_name, DEFAULT_MAX_RECIPIENT_NAME_LEN};
+use crate::build_error::{LuaActorBuildError, ScriptError};
+use crate::correlation::DEFAULT_MAX_CORRELATED;
+use crate::cron::CronExpr;
+use crate::export::{self, DEFAULT_MAX_EXPORT_SIZE};
+use crate::handoff::{DEFAULT_DRAIN_TIMEOUT, DEFAULT_MAX_QUEUED};
+use crate::interval::TickPolicy;
+use crate::lazy::run_args_to_lua;
+use crate::math::{math_table, CTX_MATH};
+use crate::message::{null_sentinel, ConversionLimits, LuaMessage, LIMITS_KEY};
+use crate::opaque::{check_not_opaque, OpaqueRegistry, CTX_RELEASE};
+use crate::outbox::{DEFAULT_OUTBOX_CAPACITY, DEFAULT_RETRY_INTERVAL};
+use crate::source::{install_searcher, ScriptSource};
+use crate::strings::{str_table, CTX_STR};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// how often scripts running under a deadline check the clock
+const DEADLINE_CHECK_INSTRUCTIONS: u32 = 1000;
+
+pub(crate) const DEFAULT_COROUTINE_POOL_SIZE: usize = 16;
+
+// A Rust function callable from the scripts, see `LuaActorBuilder::with_function`.
+pub(crate) type RustFunction =
+    Arc<dyn Fn(Vec<LuaMessage>) -> Result<LuaMessage, String> + Send + Sync>;
+
+#[cfg(test)]
+thread_local! {
+    // number of hook results converted from Lua on the current thread
+    pub(crate) static RETURN_CONVERSIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Prefix of the value yielded by `ctx.send`, followed by the id of the suspended coroutine.
+const SUSPENDED_PREFIX: &str = "__suspended__";
+
+// The functions of `ctx.util`, see `CTX_UTIL`.
+const UTIL_API: &[ApiEntry] = &[ApiEntry {
+    name: "opairs",
+    signature: "for k, v in ctx.util.opairs(t) do ... end",
+    description:
+        "`pairs(t)` in a deterministic order: booleans, numbers, then strings, each sorted",
+    availability: "",
+    params: &[arg("t", "table")],
+    returns: &[],
+    fields: &[],
+}];
+
+const CTX_UTIL: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "util",
+        signature: "for k, v in ctx.util.opairs(t) do ... end",
+        description: "helpers for scripts whose results must not depend on the order of `pairs`",
+        availability: "",
+        params: &[],
+        returns: &[],
+        fields: UTIL_API,
+    },
+    requires: Requires::Always,
+};
+
+/// A function of the `ctx` API, as listed by `ctx.help()` and `LuaActor::api_manifest`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiEntry {
+    pub name: &'static str,
+    pub signature: &'static str,
+    /// one-line description
+    pub description: &'static str,
+    /// conditions under which the function is available, empty if it always is
+    pub availability: &'static str,
+    pub params: &'static [ApiValue],
+    pub returns: &'static [ApiValue],
+    /// the functions of a table of functions such as `ctx.math`, empty for functions
+    pub fields: &'static [ApiEntry],
+}
+
+/// A parameter or a return value of a `ctx` function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiValue {
+    pub name: &'static str,
+    /// type in the LuaLS annotation syntax, e.g. `string` or `integer|nil`
+    pub ty: &'static str,
+    pub optional: bool,
+}
+
+pub(crate) const fn arg(name: &'static str, ty: &'static str) -> ApiValue {
+    ApiValue {
+        name,
+        ty,
+        optional: false,
+    }
+}
+
+pub(crate) const fn optional(name: &'static str, ty: &'static str) -> ApiValue {
+    ApiValue {
+        name,
+        ty,
+        optional: true,
+    }
+}
+
+// Configuration a `ctx` function depends on.
+pub(crate) enum Requires {
+    Always,
+    // the VM can be rebuilt, i.e. the actor was not built with `build_with_vm`
+    Restartable,
+    CircuitBreaker,
+    RichSendResults,
+}
+
+// Configuration of an actor which changes the `ctx` API.
+#[derive(Default)]
+pub(crate) struct ApiConfig {
+    pub restartable: bool,
+    pub circuit_breaker: bool,
+    pub rich_send_results: bool,
+}
+
+// A function of the `ctx` API and the configuration it depends on.
+pub(crate) struct CtxFn {
+    pub entry: ApiEntry,
+    pub requires: Requires,
+}
+
+// Every function of the `ctx` API, in the order of `ctx.help()`. Each entry is declared next to
+// the implementation of the function, `lua_actor_api_manifest_matches_ctx` checks that none is
+// missing.
+const CTX_API: &[&CtxFn] = &[
+    &CTX_NOTIFY,
+    &CTX_NOTIFY_LATER,
+    &CTX_RUN_INTERVAL,
+    &CTX_CANCEL_INTERVAL,
+    &CTX_SCHEDULE,
+    &CTX_UNSCHEDULE,
+    &CTX_SEND,
+    &CTX_SEND_OK,
+    &CTX_SEND_CORRELATED,
+    &CTX_WAIT_FOR,
+    &CTX_SPAWN_GROUP,
+    &CTX_TRY,
+    &CTX_DO_SEND,
+    &CTX_DO_SEND_CONFIRMED,
+    &CTX_SET_OUTBOX,
+    &CTX_OUTBOX_DEPTH,
+    &CTX_WATCH,
+    &CTX_UNWATCH,
+    &CTX_REPLACE_ACTOR,
+    &CTX_TERMINATE,
+    &CTX_RESTART,
+    &CTX_BREAKER_STATE,
+    &CTX_ALIAS,
+    &CTX_UNALIAS,
+    &CTX_RESOLVE,
+    &CTX_IS_NULL,
+    &CTX_NS,
+    &CTX_NO_DIFF,
+    &CTX_LAST_DIFF,
+    &CTX_STATE_SIZE,
+    &CTX_EXPORT_STATE,
+    &CTX_IMPORT_STATE,
+    &CTX_IS_PAUSED,
+    &CTX_MIGRATION,
+    &CTX_REGISTER_CLASS,
+    &CTX_UTIL,
+    &CTX_MATH,
+    &CTX_STR,
+    &CTX_RELEASE,
+    &CTX_UUID,
+    &CTX_DEPRECATED,
+    &CTX_HELP,
+];
+
+// The `ctx` functions available for an actor.
+pub(crate) fn api_manifest(config: &ApiConfig) -> Vec<ApiEntry> {
+    CTX_API
+        .iter()
+        .filter(|f| match f.requires {
+            Requires::Always => true,
+            Requires::Restartable => config.restartable,
+            Requires::CircuitBreaker => config.circuit_breaker,
+            Requires::RichSendResults => config.rich_send_results,
+        })
+        .map(|f| f.entry.clone())
+        .collect()
+}
+
+const CTX_HELP: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "help",
+        signature: "ctx.help([name])",
+        description: "describe the available `ctx` functions, or the function `name`",
+        availability: "",
+        params: &[optional("name", "string")],
+        returns: &[arg("entries", "table")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+fn entry_table<'lua>(ctx: Context<'lua>, entry: &ApiEntry) -> Result<Table<'lua>, LuaError> {
+    let t = ctx.create_table()?;
+    t.set("signature", entry.signature)?;
+    t.set("description", entry.description)?;
+    t.set("availability", entry.availability)?;
+    Ok(t)
+}
+
+/// Result of a hook invocation.
+#[derive(Debug, PartialEq)]
+pub(crate) enum HookOutcome {
+    /// The hook returned. The value is `Nil` if no reply is expected.
+    Completed(LuaMessage),
+    /// The hook yielded in `ctx.send` and waits for the response to resume the coroutine.
+    Suspended(i64),
+}
+
+impl HookOutcome {
+    // The reply sent to the caller of the hook, `Nil` if it suspended.
+    pub fn into_message(self) -> LuaMessage {
+        match self {
+            HookOutcome::Completed(msg) => msg,
+            #[cfg(feature = "legacy-thread-yield")]
+            #[allow(deprecated)]
+            HookOutcome::Suspended(thread_id) => LuaMessage::ThreadYield(thread_id.to_string()),
+            #[cfg(not(feature = "legacy-thread-yield"))]
+            HookOutcome::Suspended(_) => LuaMessage::Nil,
+        }
+    }
+}
+
+/// The part of the `ctx` API which reaches outside of the VM.
+///
+/// Recipient names are validated before they are passed to the implementation.
+pub(crate) trait ActorApi {
+    fn notify(&mut self, msg: LuaMessage);
+    fn notify_later(&mut self, msg: LuaMessage, after: Duration);
+    fn run_interval(
+        &mut self,
+        period: Duration,
+        msg: HashMap<String, LuaMessage>,
+        policy: TickPolicy,
+    ) -> i64;
+    fn cancel_interval(&mut self, id: i64);
+    // add or replace the schedule `name`, a generated one if `None`, returning the name
+    fn schedule(&mut self, name: Option<String>, cron: CronExpr, msg: LuaMessage) -> String;
+    fn unschedule(&mut self, name: &str) -> bool;
+    fn watch(&mut self, recipient_name: &str) -> Result<(), LuaError>;
+    fn unwatch(&mut self, recipient_name: &str);
+    fn do_send(&mut self, recipient_name: &str, msg: LuaMessage);
+    // `do_send` with the result of the delivery passed to `on_delivery_receipt` later,
+    // returning the id of the receipt
+    fn do_send_confirmed(&mut self, recipient_name: &str, msg: LuaMessage) -> i64;
+    fn set_outbox(&mut self, recipient_name: &str, capacity: usize, retry_interval: Duration);
+    fn outbox_depth(&mut self, recipient_name: &str) -> usize;
+    fn send(&mut self, recipient_name: String, msg: LuaMessage, cb_thread_id: i64);
+    // a new id for `ctx.send_correlated`, whose coroutine is resumed with a timeout error
+    // after `timeout` unless `uncorrelate` is called
+    fn correlate(&mut self, timeout: Option<Duration>) -> i64;
+    fn uncorrelate(&mut self, id: i64);
+    // start replacing the child `name`, the coroutine `cb_thread_id` is resumed once the
+    // replacement receives its messages
+    fn replace_actor(
+        &mut self,
+        name: &str,
+        script_path: &str,
+        drain_timeout: Duration,
+        max_queued: usize,
+        cb_thread_id: i64,
+    ) -> Result<(), LuaError>;
+    fn terminate(&mut self);
+    fn restart(&mut self, keep_state: bool) -> Result<(), LuaError>;
+    // `None` if circuit breakers are not enabled
+    fn breaker_state(&mut self, recipient_name: &str) -> Option<&'static str>;
+    fn alias(&mut self, alias: String, target: String) -> Result<(), LuaError>;
+    fn unalias(&mut self, alias: &str);
+    fn resolve(&mut self, alias: &str) -> Option<String>;
+    fn warn(&mut self, key: &str, message: &str);
+    // a version 4 uuid, reproducible if the ids are seeded, see `LuaActorBuilder::with_id_seed`
+    fn uuid(&mut self) -> String;
+}
+
+/// A Lua VM running the prelude and the lifecycle scripts of an actor.
+pub(crate) struct LuaRuntime {
+    pub vm: Lua,
+    pub max_recipient_name_len: usize,
+    pub max_duration: Duration,
+    pub memory_limit: Option<usize>,
+    pub isolated_hooks: bool,
+    // built by `new_vm(true)`, see `LuaActorBuilder::with_sandbox`
+    pub sandboxed: bool,
+    // set before the prelude loads, see `LuaActorBuilder::with_package_path`
+    pub package_path: Option<String>,
+    pub package_cpath: Option<String>,
+    // module names and sources set in `package.preload`, see `LuaActorBuilder::preload_module`
+    pub preloaded: Vec<(String, String)>,
+    // set before the prelude loads, see `LuaActorBuilder::with_global`
+    pub globals: Vec<(String, LuaMessage)>,
+    // set before the prelude loads, see `LuaActorBuilder::with_function`
+    pub functions: Vec<(String, RustFunction)>,
+    pub lazy_tables: bool,
+    // see `LuaActorBuilder::with_compat_shims`
+    pub compat_shims: bool,
+    pub handle_timeout: Option<Duration>,
+    // the deadline of the running invocation, checked by the hook of `set_handle_timeout`
+    invocation_deadline: Arc<Mutex<Option<Instant>>>,
+}
+
+// A VM with the standard libraries except `debug`, or if `sandboxed` without `io` and `os`
+// either, see `LuaActorBuilder::with_sandbox`.
+pub(crate) fn new_vm(sandboxed: bool) -> Lua {
+    if !sandboxed {
+        return Lua::new();
+    }
+    Lua::new_with(
+        StdLib::BASE
+            | StdLib::COROUTINE
+            | StdLib::TABLE
+            | StdLib::STRING
+            | StdLib::UTF8
+            | StdLib::MATH
+            | StdLib::PACKAGE,
+    )
+}
+
+// The entries of the functions implemented in the prelude, see `LuaRuntime::load_scripts`.
+const CTX_SEND_OK: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "send_ok",
+        signature: "local value = ctx.send_ok(recipient, msg)",
+        description: "send `msg` to `recipient`, return the value or raise an error if it failed",
+        availability: "actors built with `with_rich_send_results`",
+        params: &[arg("recipient", "string"), arg("msg", "any")],
+        returns: &[arg("value", "any")],
+        fields: &[],
+    },
+    requires: Requires::RichSendResults,
+};
+
+const CTX_SEND_CORRELATED: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "send_correlated",
+        signature: "local reply = ctx.send_correlated(recipient, msg[, {id_field = \"correlation_id\", timeout = seconds}])",
+        description: "`ctx.do_send` a copy of the table `msg` with a new id in `id_field` and wait for the first message carrying the id back",
+        availability: "",
+        params: &[
+            arg("recipient", "string"),
+            arg("msg", "table"),
+            optional("options", "{id_field: string?, timeout: number?}"),
+        ],
+        returns: &[arg("reply", "any")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_WAIT_FOR: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "wait_for",
+        signature: "local msg = ctx.wait_for(types[, timeout])",
+        description: "wait for the next table message whose `type` field is one of `types`, which resumes the coroutine instead of running `handle`",
+        availability: "",
+        params: &[arg("types", "string[]"), optional("timeout", "number")],
+        returns: &[arg("msg", "any")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_SPAWN_GROUP: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "spawn_group",
+        signature: "local g = ctx.spawn_group()",
+        description: "a group of coroutines started with `g:spawn(fn)`, awaited with `g:join([timeout])` and cancelled with `g:cancel()`",
+        availability: "",
+        params: &[],
+        returns: &[arg(
+            "g",
+            "{spawn: fun(self, fn: function): integer, join: fun(self, timeout: number?): table[], cancel: fun(self)}",
+        )],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_TRY: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "try",
+        signature: "local ok, ... = ctx.try(f, ...)",
+        description: "`pcall` for functions which suspend the script, e.g. in `ctx.send`",
+        availability: "",
+        params: &[arg("f", "function"), arg("...", "any")],
+        returns: &[arg("ok", "boolean"), arg("...", "any")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_IS_NULL: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "is_null",
+        signature: "local null = ctx.is_null(v)",
+        description: "true if `v` is nil or `ctx.null`, which is truthy",
+        availability: "",
+        params: &[arg("v", "any")],
+        returns: &[arg("null", "boolean")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_NS: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "ns",
+        signature: "local state = ctx.ns(name)",
+        description: "state of the namespace `name`, kept in `ctx.state.__ns`",
+        availability: "",
+        params: &[arg("name", "string")],
+        returns: &[arg("state", "table")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_NO_DIFF: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "no_diff",
+        signature: "ctx.no_diff(path)",
+        description: "skip `path` of `ctx.state` and everything under it in state diffs",
+        availability: "",
+        params: &[arg("path", "string")],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_LAST_DIFF: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "last_diff",
+        signature: "local changes = ctx.last_diff()",
+        description: "changes of `ctx.state` made by the last message",
+        availability: "actors built with `with_state_diffing`, nil otherwise",
+        params: &[],
+        returns: &[arg("changes", "table|nil")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_STATE_SIZE: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "state_size",
+        signature: "local bytes = ctx.state_size()",
+        description: "estimated size of `ctx.state` in bytes",
+        availability: "",
+        params: &[],
+        returns: &[arg("bytes", "integer")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_EXPORT_STATE: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "export_state",
+        signature: "local blob = ctx.export_state([path])",
+        description:
+            "encode the value at `path` of `ctx.state`, e.g. `users.alice`, or the whole state, for `ctx.import_state` in another actor",
+        availability: "",
+        params: &[optional("path", "string")],
+        returns: &[arg("blob", "string")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_IMPORT_STATE: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "import_state",
+        signature: "ctx.import_state(path, blob[, {merge = \"replace\"}])",
+        description:
+            "set the value of a blob of `ctx.export_state` at `path` of `ctx.state`, replacing it or merging tables into it, migrated to `ctx.state_version`",
+        availability: "",
+        params: &[
+            arg("path", "string|nil"),
+            arg("blob", "string"),
+            optional("options", "{merge: string?}"),
+        ],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_IS_PAUSED: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "is_paused",
+        signature: "local paused = ctx.is_paused()",
+        description: "whether message processing is paused with `PauseProcessing`",
+        availability: "",
+        params: &[],
+        returns: &[arg("paused", "boolean")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_MIGRATION: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "migration",
+        signature: "ctx.migration(from_version, function (old_state) return new_state end)",
+        description: "register the migration of `ctx.state` from `from_version` to the next",
+        availability: "the `migrations` hook",
+        params: &[
+            arg("from_version", "integer"),
+            arg("migrate", "fun(old_state: table): table"),
+        ],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_REGISTER_CLASS: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "register_class",
+        signature: "ctx.register_class(name, class)",
+        description: "keep the metatable `class` of tables converted to messages",
+        availability: "",
+        params: &[arg("name", "string"), arg("class", "table")],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_DEPRECATED: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "deprecated",
+        signature: "ctx.deprecated(msg)",
+        description: "warn that a deprecated API is used, at most once per interval",
+        availability: "",
+        params: &[arg("msg", "string")],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+impl LuaRuntime {
+    pub fn new(vm: Lua) -> LuaRuntime {
+        LuaRuntime {
+            vm,
+            max_recipient_name_len: DEFAULT_MAX_RECIPIENT_NAME_LEN,
+            max_duration: DEFAULT_MAX_DURATION,
+            memory_limit: None,
+            isolated_hooks: false,
+            sandboxed: false,
+            package_path: None,
+            package_cpath: None,
+            preloaded: Vec::new(),
+            globals: Vec::new(),
+            functions: Vec::new(),
+            lazy_tables: false,
+            compat_shims: false,
+            handle_timeout: None,
+            invocation_deadline: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Load the prelude and the lifecycle scripts, Lua source or bytecode, into the VM. Each
+    // script is loaded even if another one failed, so all the broken ones are reported.
+    pub fn load_scripts(
+        &self,
+        started: Option<Vec<u8>>,
+        handle: Option<Vec<u8>>,
+        stopped: Option<Vec<u8>>,
+    ) -> Result<(), LuaActorBuildError> {
+        let prelude = include_str!("lua/prelude.lua");
+        let errors = self.vm.context(|ctx| {
+            let package: Table = ctx.globals().get("package")?;
+            if let Some(ref path) = self.package_path {
+                package.set("path", path.as_str())?;
+            }
+            if let Some(ref cpath) = self.package_cpath {
+                package.set("cpath", cpath.as_str())?;
+            }
+            for (name, value) in &self.globals {
+                ctx.globals().raw_set(name.as_str(), value.clone())?;
+            }
+            for (name, f) in &self.functions {
+                let f = f.clone();
+                let function = ctx.create_function(move |_, args: Variadic<LuaMessage>| {
+                    f(args.into_iter().collect()).map_err(LuaError::RuntimeError)
+                })?;
+                ctx.globals().raw_set(name.as_str(), function)?;
+            }
+            ctx.load(prelude).set_name("Prelude")?.exec()?;
+            if self.sandboxed {
+                let sandbox: Function = ctx.globals().get("__sandbox")?;
+                sandbox.call::<_, ()>(())?;
+            }
+            // compiled now to report syntax errors, run by the first `require`
+            let preload: Table = package.get("preload")?;
+            for (name, source) in &self.preloaded {
+                let loader = ctx
+                    .load(source)
+                    .set_name(name)?
+                    .into_function()
+                    .map_err(|e| {
+                        LuaError::RuntimeError(format!(
+                            "failed to load preloaded module {}: {}",
+                            name, e
+                        ))
+                    })?;
+                preload.set(name.as_str(), loader)?;
+            }
+            let lua_ctx: Table = ctx.globals().get("ctx")?;
+            lua_ctx.set("null", null_sentinel())?;
+            lua_ctx.set("math", math_table(ctx)?)?;
+            lua_ctx.set("str", str_table(ctx)?)?;
+            ctx.globals()
+                .raw_set("__check_scratch", cfg!(debug_assertions))?;
+            ctx.globals()
+                .raw_set("__coroutine_pool_size", DEFAULT_COROUTINE_POOL_SIZE)?;
+            ctx.globals()
+                .raw_set("__isolated_hooks", self.isolated_hooks)?;
+            ctx.globals()
+                .raw_set("__max_correlated", DEFAULT_MAX_CORRELATED)?;
+            ctx.globals()
+                .raw_set("__max_export_size", DEFAULT_MAX_EXPORT_SIZE)?;
+            export::install(ctx)?;
+            if self.lazy_tables {
+                let enable: Function = ctx.globals().get("__enable_lazy_tables")?;
+                enable.call::<_, ()>(())?;
+            }
+            if self.compat_shims {
+                let enable: Function = ctx.globals().get("__enable_compat_shims")?;
+                enable.call::<_, ()>(())?;
+            }
+
+            let load: Function = ctx.globals().get("__load")?;
+            let mut errors = Vec::new();
+            for (name, script) in [
+                ("started", started),
+                ("handle", handle),
+                ("stopped", stopped),
+            ] {
+                if let Some(script) = script {
+                    if let Err(error) = load.call::<_, ()>((ctx.create_string(&script)?, name)) {
+                        errors.push(ScriptError {
+                            hook: name,
+                            chunk: name.to_string(),
+                            error,
+                        });
+                    }
+                }
+            }
+            Ok::<_, LuaError>(errors)
+        })?;
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(LuaActorBuildError::Scripts(errors))
+        }
+    }
+
+    // Load the next hooks in their own environment, see `LuaActorBuilder::with_isolated_hooks`.
+    pub fn set_isolated_hooks(&mut self, isolated: bool) -> Result<(), LuaError> {
+        self.isolated_hooks = isolated;
+        self.vm
+            .context(|ctx| ctx.globals().raw_set("__isolated_hooks", isolated))
+    }
+
+    // Pass the tables of `ctx.msg` as lazy tables, see `LuaActorBuilder::with_lazy_tables`.
+    pub fn set_lazy_tables(&mut self, lazy: bool) -> Result<(), LuaError> {
+        if lazy && !self.lazy_tables {
+            self.vm.context(|ctx| {
+                let enable: Function = ctx.globals().get("__enable_lazy_tables")?;
+                enable.call::<_, ()>(())
+            })?;
+        }
+        self.lazy_tables = lazy;
+        Ok(())
+    }
+
+    // Load `script` as the hook `name`, run with `__run(name, ...)`.
+    pub fn load_script(&self, name: &str, script: impl Into<Vec<u8>>) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let load: Function = ctx.globals().get("__load")?;
+            load.call::<_, ()>((ctx.create_string(&script.into())?, name))
+        })
+    }
+
+    /// Call the global function `func_name` with the `ctx` API backed by `api`.
+    ///
+    /// Returns `Completed(Nil)` if `func_name` is not defined. Errors raised by the script are
+    /// returned as they are.
+    pub fn call_hook(
+        &mut self,
+        api: &mut dyn ActorApi,
+        func_name: &str,
+        args: Vec<LuaMessage>,
+        reply_expected: bool,
+    ) -> Result<HookOutcome, LuaError> {
+        let max_recipient_name_len = self.max_recipient_name_len;
+        let max_duration = self.max_duration;
+        let lazy_msg = self.lazy_tables && func_name == "__run";
+        // `api` is used in multiple closures in the lua scope.
+        // to create multiple borrow in closures, we use RefCell to move the borrow-checking to runtime.
+        // Voliating the check will result in panic. Which shouldn't happend(I think) since lua is single-threaded.
+        let api = RefCell::new(api);
+
+        self.vm.context(|lua_ctx| {
+            let args = if lazy_msg {
+                MultiValue::from_vec(run_args_to_lua(lua_ctx, args)?)
+            } else {
+                args_to_lua(lua_ctx, args)?
+            };
+            // We can't create a function with references to `self` and is 'static since `self` already owns Lua.
+            // A function within Lua owning `self` creates self-borrowing cycle.
+            //
+            // Also, Lua requires all values passed to it is 'static because we can't know when will Lua GC our value.
+            // Therefore, we use scope to make sure these APIs are temporary and don't have to deal with 'static lifetime.
+            //
+            // (Quote from: https://github.com/kyren/rlua/issues/56#issuecomment-363928738
+            // When the scope ends, the Lua function is 100% guaranteed (afaict!) to be "invalidated".
+            // This means that calling the function will cause an immediate Lua error with a message like "error, call of invalidated function".)
+            //
+            // for reference, check https://github.com/kyren/rlua/issues/73#issuecomment-370222198
+            lua_ctx.scope(|scope| {
+                register_ctx_api(lua_ctx, scope, &api, max_recipient_name_len, max_duration)?;
+
+                match lua_ctx.globals().get::<_, Function>(func_name) {
+                    Ok(f) => {
+                        let ret = f.call::<MultiValue, Value>(args)?;
+                        classify_return(lua_ctx, ret, reply_expected)
+                    }
+                    // return nil if handle is not defined
+                    Err(_) => Ok(HookOutcome::Completed(LuaMessage::Nil)),
+                }
+            })
+        })
+    }
+
+    pub fn install_opaque(&self, registry: &OpaqueRegistry) -> Result<(), LuaError> {
+        self.vm.context(|ctx| registry.install(ctx))
+    }
+
+    // Load the modules passed to `require` from `source`.
+    pub fn install_script_source(&self, source: Arc<dyn ScriptSource>) -> Result<(), LuaError> {
+        self.vm.context(|ctx| install_searcher(ctx, source))
+    }
+
+    // Set `ctx.help` describing the functions of `manifest`.
+    pub fn install_help(&self, manifest: Vec<ApiEntry>) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let help = ctx.create_function(move |ctx, name: Option<String>| match name {
+                Some(name) => match manifest.iter().find(|e| e.name == name) {
+                    Some(entry) => Ok(Value::Table(entry_table(ctx, entry)?)),
+                    None => Ok(Value::Nil),
+                },
+                None => {
+                    let t = ctx.create_table()?;
+                    for entry in &manifest {
+                        t.set(entry.name, entry_table(ctx, entry)?)?;
+                    }
+                    Ok(Value::Table(t))
+                }
+            })?;
+            let lua_ctx: Table = ctx.globals().get("ctx")?;
+            lua_ctx.set(CTX_HELP.entry.name, help)
+        })
+    }
+
+    // Fail the allocations above `limit` bytes, see `LuaActorBuilder::with_memory_limit`.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+        self.vm.set_memory_limit(limit);
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.vm.used_memory()
+    }
+
+    // Run a full garbage collection, returning the number of bytes reclaimed.
+    pub fn collect_garbage(&self) -> Result<usize, LuaError> {
+        let before = self.vm.used_memory();
+        self.vm.gc_collect()?;
+        Ok(before.saturating_sub(self.vm.used_memory()))
+    }
+
+    // Set `ctx.runtime.gc`, see `LuaActorBuilder::with_gc_watermark`.
+    pub fn set_gc_stats(&self, collections: u64, reclaimed: u64) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let gc = ctx.create_table()?;
+            gc.set("collections", collections)?;
+            gc.set("reclaimed", reclaimed)?;
+            let lua_ctx: Table = ctx.globals().get("ctx")?;
+            let runtime: Table = lua_ctx.get("runtime")?;
+            runtime.set("gc", gc)
+        })
+    }
+
+    // Whether the scripts defined the global function `name`, e.g. `on_delivery_receipt`.
+    pub fn has_function(&self, name: &str) -> bool {
+        self.vm
+            .context(|ctx| {
+                let value: Value = ctx.globals().raw_get(name)?;
+                Ok::<_, LuaError>(matches!(value, Value::Function(_)))
+            })
+            .unwrap_or(false)
+    }
+
+    // The numbers of workflows which completed and failed since the last call, see
+    // `GetWorkflowStats`.
+    pub fn take_finished_workflows(&self) -> Result<(u64, u64), LuaError> {
+        self.vm.context(|ctx| {
+            let finished: Table = ctx.globals().raw_get("__finished_workflows")?;
+            let completed = finished.raw_get("completed")?;
+            let failed = finished.raw_get("failed")?;
+            finished.raw_set("completed", 0)?;
+            finished.raw_set("failed", 0)?;
+            Ok((completed, failed))
+        })
+    }
+
+    pub fn has_script(&self, name: &str) -> bool {
+        self.vm
+            .context(|ctx| {
+                let scripts: Table = ctx.globals().get("__scripts")?;
+                scripts.contains_key(name)
+            })
+            .unwrap_or(false)
+    }
+
+    // Make `ctx.msg` read-only, see `LuaActorBuilder::with_frozen_msg`.
+    pub fn set_frozen_msg(&self, frozen: bool) -> Result<(), LuaError> {
+        self.vm
+            .context(|ctx| ctx.globals().raw_set("__frozen_msg", frozen))
+    }
+
+    // Make `ctx.send` return envelopes and define `ctx.send_ok`.
+    pub fn set_rich_send_results(&self, rich: bool) -> Result<(), LuaError> {
+        self.vm
+            .context(|ctx| ctx.globals().raw_set("__rich_send_results", rich))
+    }
+
+    // Make the ctx functions `names` raise an error explaining `reason`, see `SyncLuaActor`.
+    pub fn disable_ctx_functions(&self, names: &[&str], reason: &str) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let disabled: Table = ctx.globals().get("__disabled_ctx")?;
+            for name in names {
+                disabled.set(*name, reason)?;
+            }
+            Ok(())
+        })
+    }
+
+    // Limits of the conversions of the messages of this VM.
+    pub fn set_conversion_limits(&self, limits: &ConversionLimits) -> Result<(), LuaError> {
+        self.vm
+            .context(|ctx| ctx.set_named_registry_value(LIMITS_KEY, limits.clone()))
+    }
+
+    // Paths of `ctx.state` marked with `ctx.no_diff`.
+    pub fn no_diff_paths(&self) -> Result<Vec<String>, LuaError> {
+        self.vm.context(|ctx| {
+            let paths: Table = ctx.globals().raw_get("__no_diff")?;
+            paths
+                .pairs::<String, bool>()
+                .map(|pair| pair.map(|(path, _)| path))
+                .collect()
+        })
+    }
+
+    // Set the result of `ctx.last_diff()`.
+    pub fn set_last_diff(&self, diff: LuaMessage) -> Result<(), LuaError> {
+        self.vm
+            .context(|ctx| ctx.globals().raw_set("__last_diff", diff))
+    }
+
+    // Number of finished coroutines kept to run the next invocations.
+    pub fn set_coroutine_pool_size(&self, size: usize) -> Result<(), LuaError> {
+        self.vm
+            .context(|ctx| ctx.globals().raw_set("__coroutine_pool_size", size))
+    }
+
+    // Pending `ctx.send_correlated` above which it raises an error.
+    pub fn set_max_correlated(&self, max: usize) -> Result<(), LuaError> {
+        self.vm
+            .context(|ctx| ctx.globals().raw_set("__max_correlated", max))
+    }
+
+    // Limit the blobs of `ctx.export_state`, see `LuaActorBuilder::with_max_export_size`.
+    pub fn set_max_export_size(&self, bytes: usize) -> Result<(), LuaError> {
+        self.vm
+            .context(|ctx| ctx.globals().raw_set("__max_export_size", bytes))
+    }
+
+    // Number of pending `ctx.send_correlated`.
+    #[cfg(test)]
+    pub fn pending_correlations(&self) -> Result<usize, LuaError> {
+        self.vm
+            .context(|ctx| ctx.globals().raw_get("__correlated_count"))
+    }
+
+    // Drop the pending `ctx.send_correlated` and `ctx.wait_for` and their coroutines, e.g. when
+    // the actor stops.
+    pub fn clear_correlations(&self) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let clear: Function = ctx.globals().get("__clear_correlations")?;
+            clear.call(())
+        })
+    }
+
+    // Number of suspended coroutines, see `Ping`.
+    pub fn pending_threads(&self) -> Result<usize, LuaError> {
+        self.vm.context(|ctx| {
+            let threads: Table = ctx.globals().raw_get("__threads")?;
+            let mut count = 0;
+            for pair in threads.pairs::<Value, Value>() {
+                pair?;
+                count += 1;
+            }
+            Ok(count)
+        })
+    }
+
+    // Drop the suspended coroutines, see `Supervised for LuaActor`.
+    pub fn clear_threads(&self) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let clear: Function = ctx.globals().get("__clear_threads")?;
+            clear.call(())
+        })
+    }
+
+    // Coroutines keep the hook of the VM at the time they were created, so the pooled ones are
+    // dropped when it changes.
+    fn drop_coroutine_pool(&self) {
+        let dropped = self.vm.context(|ctx| {
+            ctx.globals()
+                .raw_set("__coroutine_pool", ctx.create_table()?)
+        });
+        if let Err(e) = dropped {
+            log::error!("failed to drop the coroutine pool: {}", e);
+        }
+    }
+
+    // Abort the scripts still running at `deadline` until `clear_deadline`. The returned flag is
+    // set if they were aborted.
+    pub fn set_deadline(&self, deadline: Instant) -> Arc<AtomicBool> {
+        self.drop_coroutine_pool();
+        let aborted = Arc::new(AtomicBool::new(false));
+        let flag = aborted.clone();
+        self.vm.set_hook(
+            HookTriggers {
+                every_nth_instruction: Some(DEADLINE_CHECK_INSTRUCTIONS),
+                ..Default::default()
+            },
+            move |_, _| {
+                if Instant::now() < deadline {
+                    return Ok(());
+                }
+                flag.store(true, Ordering::SeqCst);
+                Err(LuaError::RuntimeError("deadline exceeded".to_string()))
+            },
+        );
+        aborted
+    }
+
+    pub fn clear_deadline(&self) {
+        self.vm.remove_hook();
+        self.drop_coroutine_pool();
+    }
+
+    // Abort the invocations running longer than `timeout` once armed with
+    // `arm_handle_timeout`, see `LuaActorBuilder::with_handle_timeout`.
+    pub fn set_handle_timeout(&mut self, timeout: Option<Duration>) {
+        self.handle_timeout = timeout;
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        // the coroutines only get the hook when they're created
+        self.drop_coroutine_pool();
+        let deadline = self.invocation_deadline.clone();
+        self.vm.set_hook(
+            HookTriggers {
+                every_nth_instruction: Some(DEADLINE_CHECK_INSTRUCTIONS),
+                ..Default::default()
+            },
+            move |_, _| match *deadline.lock().unwrap() {
+                Some(deadline) if Instant::now() >= deadline => Err(LuaError::RuntimeError(
+                    format!("handle timeout of {:?} exceeded", timeout),
+                )),
+                _ => Ok(()),
+            },
+        );
+    }
+
+    // Start the handle timeout of an invocation, until `disarm_handle_timeout`.
+    pub fn arm_handle_timeout(&self) {
+        if let Some(timeout) = self.handle_timeout {
+            *self.invocation_deadline.lock().unwrap() = Some(Instant::now() + timeout);
+        }
+    }
+
+    pub fn disarm_handle_timeout(&self) {
+        *self.invocation_deadline.lock().unwrap() = None;
+    }
+
+    // Set `ctx.runtime.dependencies` to the status of each dependency.
+    pub fn set_dependencies(&self, status: &[(String, bool)]) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let dependencies = ctx.create_table()?;
+            for (name, connected) in status {
+                dependencies.set(name.as_str(), *connected)?;
+            }
+            let lua_ctx: Table = ctx.globals().get("ctx")?;
+            let runtime: Table = lua_ctx.get("runtime")?;
+            runtime.set("dependencies", dependencies)
+        })
+    }
+
+    // Make reads and writes of undeclared globals raise errors.
+    pub fn install_strict_globals(&self) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let f: Function = ctx.globals().get("__strict_globals")?;
+            f.call::<_, ()>(())
+        })
+    }
+
+    // Set `ctx.build`, see `LuaActorBuilder::with_build_info`.
+    pub fn set_build_info(&self, build: &LuaMessage) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let f: Function = ctx.globals().get("__set_build_info")?;
+            f.call::<_, ()>(build.clone())
+        })
+    }
+
+    // Replace `ctx.state` by a read-only view, see `LuaActorBuilder::with_read_only_state`.
+    pub fn freeze_state(&self) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let f: Function = ctx.globals().get("__freeze_state")?;
+            f.call::<_, ()>(())
+        })
+    }
+
+    // Estimated size in bytes of `ctx.state`, see `LuaActorBuilder::with_state_quota`.
+    pub fn state_size(&self) -> Result<usize, LuaError> {
+        self.vm.context(|ctx| {
+            let f: Function = ctx.globals().get("__state_size")?;
+            f.call::<_, usize>(())
+        })
+    }
+
+    // Set the result of `ctx.is_paused()`, see `PauseProcessing`.
+    pub fn set_paused(&self, paused: bool) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let f: Function = ctx.globals().get("__set_paused")?;
+            f.call::<_, ()>(paused)
+        })
+    }
+
+    // Make `ctx.state` reject new keys, or accept them again.
+    pub fn limit_state_growth(&self, limited: bool) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let name = if limited {
+                "__limit_state_growth"
+            } else {
+                "__unlimit_state_growth"
+            };
+            let f: Function = ctx.globals().get(name)?;
+            f.call::<_, ()>(())
+        })
+    }
+
+    pub fn get_state(&self) -> Result<LuaMessage, LuaError> {
+        self.vm.context(|ctx| {
+            let lua_ctx: Table = ctx.globals().get("ctx")?;
+            let state: Value = lua_ctx.get("state")?;
+            // the tables behind a read-only view
+            let read_only: Table = ctx.globals().raw_get("__read_only")?;
+            let state = match read_only.raw_get(state.clone())? {
+                Value::Nil => state,
+                frozen => frozen,
+            };
+            LuaMessage::from_lua(state, ctx)
+        })
+    }
+
+    pub fn set_state(&self, state: LuaMessage) -> Result<(), LuaError> {
+        self.vm.context(|ctx| {
+            let lua_ctx: Table = ctx.globals().get("ctx")?;
+            match state {
+                LuaMessage::Nil => lua_ctx.set("state", ctx.create_table()?),
+                state => lua_ctx.set("state", state),
+            }
+        })
+    }
+}
+
+pub(crate) const DEFAULT_MAX_DURATION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// Convert the seconds passed to the `ctx` API to a duration. Zero means as soon as possible,
+// i.e. the next turn of the event loop. Negative, NaN and infinite values, and values above
+// `max`, raise an error naming `what`, e.g. `ctx.notify_later delay`.
+fn parse_duration(what: &str, secs: f64, max: Duration) -> Result<Duration, LuaError> {
+    if secs.is_nan() || secs.is_infinite() {
+        return Err(LuaError::RuntimeError(format!(
+            "{} must be a finite number of seconds, got {}",
+            what, secs
+        )));
+    }
+    if secs < 0.0 {
+        return Err(LuaError::RuntimeError(format!(
+            "{} must not be negative, got {}",
+            what, secs
+        )));
+    }
+    if secs > max.as_secs_f64() {
+        return Err(LuaError::RuntimeError(format!(
+            "{} must be at most {} seconds, got {}",
+            what,
+            max.as_secs_f64(),
+            secs
+        )));
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
+// The entries of the functions registered by `register_ctx_api`.
+const CTX_NOTIFY: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "notify",
+        signature: "ctx.notify(msg)",
+        description: "send `msg` to self",
+        availability: "",
+        params: &[arg("msg", "any")],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_NOTIFY_LATER: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "notify_later",
+        signature: "ctx.notify_later(msg, seconds)",
+        description: "send `msg` to self after `seconds`, 0 for the next turn",
+        availability: "",
+        params: &[arg("msg", "any"), arg("seconds", "number")],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_RUN_INTERVAL: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "run_interval",
+        signature: "local id = ctx.run_interval(seconds, msg[, policy])",
+        description: "send the table `msg` to self every `seconds`, see the tick policies",
+        availability: "",
+        params: &[
+            arg("seconds", "number"),
+            arg("msg", "table"),
+            optional("policy", "\"coalesce\"|\"all\"|\"skip\""),
+        ],
+        returns: &[arg("id", "integer")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_CANCEL_INTERVAL: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "cancel_interval",
+        signature: "ctx.cancel_interval(id)",
+        description: "stop the interval `id`, dropping its pending tick",
+        availability: "",
+        params: &[arg("id", "integer")],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_SCHEDULE: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "schedule",
+        signature: "local name = ctx.schedule(cron_expr, msg[, {name = nil, utc_offset = 0}])",
+        description: "send `msg` to self at the times matching the cron expression `cron_expr`, replacing the schedule `name`",
+        availability: "",
+        params: &[
+            arg("cron_expr", "string"),
+            arg("msg", "any"),
+            optional("options", "{name: string?, utc_offset: integer?}"),
+        ],
+        returns: &[arg("name", "string")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_UNSCHEDULE: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "unschedule",
+        signature: "local removed = ctx.unschedule(name)",
+        description: "remove the schedule `name`, returning whether it existed",
+        availability: "",
+        params: &[arg("name", "string")],
+        returns: &[arg("removed", "boolean")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_WATCH: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "watch",
+        signature: "ctx.watch(recipient)",
+        description: "call `on_recipient_status(recipient, connected)` when `recipient` stops",
+        availability: "",
+        params: &[arg("recipient", "string")],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_UNWATCH: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "unwatch",
+        signature: "ctx.unwatch(recipient)",
+        description: "stop watching `recipient`",
+        availability: "",
+        params: &[arg("recipient", "string")],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_DO_SEND: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "do_send",
+        signature: "ctx.do_send(recipient, msg)",
+        description: "send `msg` to `recipient` without waiting for the response",
+        availability: "",
+        params: &[arg("recipient", "string"), arg("msg", "any")],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_DO_SEND_CONFIRMED: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "do_send_confirmed",
+        signature: "ctx.do_send_confirmed(recipient, msg)",
+        description:
+            "`ctx.do_send` `msg` to `recipient`, then call `on_delivery_receipt(receipt, status)` with whether it reached the mailbox",
+        availability: "",
+        params: &[arg("recipient", "string"), arg("msg", "any")],
+        returns: &[arg("receipt", "integer")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_SET_OUTBOX: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "set_outbox",
+        signature: "ctx.set_outbox(recipient[, {capacity = 1000, retry_interval = 1}])",
+        description:
+            "buffer the `ctx.do_send` to `recipient` while it's disconnected, retrying them in order",
+        availability: "",
+        params: &[
+            arg("recipient", "string"),
+            optional("opts", "{ capacity: integer?, retry_interval: number? }"),
+        ],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_OUTBOX_DEPTH: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "outbox_depth",
+        signature: "local n = ctx.outbox_depth(recipient)",
+        description: "number of messages buffered for `recipient`, see `ctx.set_outbox`",
+        availability: "",
+        params: &[arg("recipient", "string")],
+        returns: &[arg("n", "integer")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_SEND: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "send",
+        signature: "local result, err = ctx.send(recipient, msg)",
+        description: "send `msg` to `recipient` and wait for the response, or return nil \
+                      and `{kind = ..., message = ...}` if it can't be delivered, `kind` \
+                      being `closed`, `timeout` or `unknown_recipient`",
+        availability: "",
+        params: &[arg("recipient", "string"), arg("msg", "any")],
+        returns: &[arg("result", "any"), arg("err", "table|nil")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_REPLACE_ACTOR: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "replace_actor",
+        signature: "local summary = ctx.replace_actor(name, script_path[, {drain_timeout = 5, max_queued = 1024}])",
+        description: "replace the child `name` with one running the `handle` script `script_path` once it replied to the pending sends, holding its messages meanwhile",
+        availability: "children added with `with_child`",
+        params: &[
+            arg("name", "string"),
+            arg("script_path", "string"),
+            optional("options", "{drain_timeout: number?, max_queued: integer?}"),
+        ],
+        returns: &[arg(
+            "summary",
+            "{drained: integer, queued: integer, old_stopped: boolean}",
+        )],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_TERMINATE: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "terminate",
+        signature: "ctx.terminate()",
+        description: "stop the actor",
+        availability: "",
+        params: &[],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_RESTART: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "restart",
+        signature: "ctx.restart([keep_state])",
+        description: "rebuild the VM from the scripts once the current invocation returns, \
+                      raising an error if the changed scripts can't be reloaded",
+        availability: "actors not built with `build_with_vm`",
+        params: &[optional("keep_state", "boolean")],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Restartable,
+};
+
+const CTX_BREAKER_STATE: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "breaker_state",
+        signature: "ctx.breaker_state(recipient)",
+        description: "state of the circuit breaker of `recipient`: closed, open or half_open",
+        availability: "actors built with `with_circuit_breaker`",
+        params: &[arg("recipient", "string")],
+        returns: &[arg("state", "\"closed\"|\"open\"|\"half_open\"|nil")],
+        fields: &[],
+    },
+    requires: Requires::CircuitBreaker,
+};
+
+const CTX_ALIAS: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "alias",
+        signature: "ctx.alias(alias, target)",
+        description: "make `ctx.send` and `ctx.do_send` to `alias` go to the recipient `target`",
+        availability: "",
+        params: &[arg("alias", "string"), arg("target", "string")],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_UNALIAS: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "unalias",
+        signature: "ctx.unalias(alias)",
+        description: "remove `alias`",
+        availability: "",
+        params: &[arg("alias", "string")],
+        returns: &[],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_RESOLVE: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "resolve",
+        signature: "local target = ctx.resolve(alias)",
+        description: "the recipient `alias` refers to, nil if it's not an alias",
+        availability: "",
+        params: &[arg("alias", "string")],
+        returns: &[arg("target", "string|nil")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+const CTX_UUID: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "uuid",
+        signature: "local id = ctx.uuid()",
+        description: "a new version 4 uuid, the same sequence in every run with `LuaActorBuilder::with_id_seed`",
+        availability: "",
+        params: &[],
+        returns: &[arg("id", "string")],
+        fields: &[],
+    },
+    requires: Requires::Always,
+};
+
+// Register the Rust side of the `ctx` API as globals, the prelude copies them into `ctx`.
+fn register_ctx_api<'lua, 'scope, 'api: 'scope>(
+    lua_ctx: Context<'lua>,
+    scope: &Scope<'lua, 'scope>,
+    api: &'scope RefCell<&'api mut dyn ActorApi>,
+    max_recipient_name_len: usize,
+    max_duration: Duration,
+) -> Result<(), LuaError> {
+    let globals = lua_ctx.globals();
+
+    let notify = scope.create_function_mut(move |_, msg: LuaMessage| {
+        api.borrow_mut().notify(msg);
+        Ok(())
+    })?;
+    globals.raw_set(CTX_NOTIFY.entry.name, notify)?;
+
+    let notify_later = scope.create_function_mut(move |_, (msg, secs): (LuaMessage, f64)| {
+        let after = parse_duration("ctx.notify_later delay", secs, max_duration)?;
+        api.borrow_mut().notify_later(msg, after);
+        Ok(())
+    })?;
+    globals.raw_set(CTX_NOTIFY_LATER.entry.name, notify_later)?;
+
+    let run_interval = scope.create_function_mut(
+        move |_, (secs, msg, policy): (f64, LuaMessage, Option<String>)| {
+            let period = parse_duration("ctx.run_interval period", secs, max_duration)?;
+            // ticking as soon as possible would starve the mailbox
+            if period == Duration::from_secs(0) {
+                return Err(LuaError::RuntimeError(format!(
+                    "ctx.run_interval period must be positive, got {}",
+                    secs
+                )));
+            }
+            check_not_opaque(&msg)?;
+            let msg = match msg {
+                LuaMessage::Nil => HashMap::new(),
+                LuaMessage::Table(msg) => msg,
+                _ => {
+                    return Err(LuaError::RuntimeError(
+                        "ctx.run_interval message must be a table".to_string(),
+                    ))
+                }
+            };
+            let policy = TickPolicy::parse(policy.as_deref())?;
+            Ok(api.borrow_mut().run_interval(period, msg, policy))
+        },
+    )?;
+    globals.raw_set(CTX_RUN_INTERVAL.entry.name, run_interval)?;
+
+    let cancel_interval = scope.create_function_mut(move |_, id: i64| {
+        api.borrow_mut().cancel_interval(id);
+        Ok(())
+    })?;
+    globals.raw_set(CTX_CANCEL_INTERVAL.entry.name, cancel_interval)?;
+
+    let schedule = scope.create_function_mut(
+        move |_, (expr, msg, opts): (String, LuaMessage, Option<Table>)| {
+            check_not_opaque(&msg)?;
+            let (name, utc_offset) = match opts {
+                Some(opts) => (
+                    opts.get::<_, Option<String>>("name")?,
+                    opts.get::<_, Option<i64>>("utc_offset")?,
+                ),
+                None => (None, None),
+            };
+            let cron = CronExpr::parse(&expr, utc_offset.unwrap_or(0))?;
+            Ok(api.borrow_mut().schedule(name, cron, msg))
+        },
+    )?;
+    globals.raw_set(CTX_SCHEDULE.entry.name, schedule)?;
+
+    let unschedule =
+        scope.create_function_mut(move |_, name: String| Ok(api.borrow_mut().unschedule(&name)))?;
+    globals.raw_set(CTX_UNSCHEDULE.entry.name, unschedule)?;
+
+    let watch = scope.create_function_mut(move |_, recipient_name: String| {
+        validate_recipient_name(&recipient_name, max_recipient_name_len)?;
+        api.borrow_mut().watch(&recipient_name)
+    })?;
+    globals.raw_set(CTX_WATCH.entry.name, watch)?;
+
+    let unwatch = scope.create_function_mut(move |_, recipient_name: String| {
+        api.borrow_mut().unwatch(&recipient_name);
+        Ok(())
+    })?;
+    globals.raw_set(CTX_UNWATCH.entry.name, unwatch)?;
+
+    let do_send =
+        scope.create_function_mut(move |_, (recipient_name, msg): (String, LuaMessage)| {
+            validate_recipient_name(&recipient_name, max_recipient_name_len)?;
+            check_not_opaque(&msg)?;
+            api.borrow_mut().do_send(&recipient_name, msg);
+            Ok(())
+        })?;
+    globals.raw_set(CTX_DO_SEND.entry.name, do_send)?;
+
+    let do_send_confirmed =
+        scope.create_function_mut(move |_, (recipient_name, msg): (String, LuaMessage)| {
+            validate_recipient_name(&recipient_name, max_recipient_name_len)?;
+            check_not_opaque(&msg)?;
+            Ok(api.borrow_mut().do_send_confirmed(&recipient_name, msg))
+        })?;
+    globals.raw_set(CTX_DO_SEND_CONFIRMED.entry.name, do_send_confirmed)?;
+
+    let set_outbox =
+        scope.create_function_mut(move |_, (recipient_name, opts): (String, Option<Table>)| {
+            validate_recipient_name(&recipient_name, max_recipient_name_len)?;
+            let (capacity, retry_interval) = match opts {
+                Some(opts) => (
+                    opts.get::<_, Option<i64>>("capacity")?,
+                    opts.get::<_, Option<f64>>("retry_interval")?,
+                ),
+                None => (None, None),
+            };
+            let capacity = match capacity {
+                None => DEFAULT_OUTBOX_CAPACITY,
+                Some(n) if n > 0 => n as usize,
+                Some(n) => {
+                    return Err(LuaError::RuntimeError(format!(
+                        "ctx.set_outbox capacity must be positive, got {}",
+                        n
+                    )))
+                }
+            };
+            let retry_interval = match retry_interval {
+                None => DEFAULT_RETRY_INTERVAL,
+                Some(secs) => {
+                    let interval =
+                        parse_duration("ctx.set_outbox retry_interval", secs, max_duration)?;
+                    if interval == Duration::from_secs(0) {
+                        return Err(LuaError::RuntimeError(
+                            "ctx.set_outbox retry_interval must be positive, got 0".to_string(),
+                        ));
+                    }
+                    interval
+                }
+            };
+            api.borrow_mut()
+                .set_outbox(&recipient_name, capacity, retry_interval);
+            Ok(())
+        })?;
+    globals.raw_set(CTX_SET_OUTBOX.entry.name, set_outbox)?;
+
+    let outbox_depth = scope.create_function_mut(move |_, recipient_name: String| {
+        Ok(api.borrow_mut().outbox_depth(&recipient_name))
+    })?;
+    globals.raw_set(CTX_OUTBOX_DEPTH.entry.name, outbox_depth)?;
+
+    let send = scope.create_function_mut(
+        move |_, (recipient_name, msg, cb_thread_id): (String, LuaMessage, i64)| {
+            validate_recipient_name(&recipient_name, max_recipient_name_len)?;
+            check_not_opaque(&msg)?;
+            api.borrow_mut().send(recipient_name, msg, cb_thread_id);
+            Ok(())
+        },
+    )?;
+    globals.raw_set(CTX_SEND.entry.name, send)?;
+
+    let correlate =
+        scope.create_function_mut(move |_, (timeout, caller): (Option<f64>, Option<String>)| {
+            let timeout = match timeout {
+                Some(secs) => Some(parse_duration(
+                    &format!(
+                        "{} timeout",
+                        caller.as_deref().unwrap_or("ctx.send_correlated")
+                    ),
+                    secs,
+                    max_duration,
+                )?),
+                None => None,
+            };
+            Ok(api.borrow_mut().correlate(timeout))
+        })?;
+    globals.raw_set("correlate", correlate)?;
+
+    let uncorrelate = scope.create_function_mut(move |_, id: i64| {
+        api.borrow_mut().uncorrelate(id);
+        Ok(())
+    })?;
+    globals.raw_set("uncorrelate", uncorrelate)?;
+
+    let replace_actor = scope.create_function_mut(
+        move |_, (name, script_path, opts, cb_thread_id): (String, String, Option<Table>, i64)| {
+            validate_recipient_name(&name, max_recipient_name_len)?;
+            let (drain_timeout, max_queued) = match opts {
+                Some(opts) => (
+                    opts.get::<_, Option<f64>>("drain_timeout")?,
+                    opts.get::<_, Option<i64>>("max_queued")?,
+                ),
+                None => (None, None),
+            };
+            let drain_timeout = match drain_timeout {
+                None => DEFAULT_DRAIN_TIMEOUT,
+                Some(secs) => {
+                    parse_duration("ctx.replace_actor drain_timeout", secs, max_duration)?
+                }
+            };
+            let max_queued = match max_queued {
+                None => DEFAULT_MAX_QUEUED,
+                Some(n) if n > 0 => n as usize,
+                Some(n) => {
+                    return Err(LuaError::RuntimeError(format!(
+                        "ctx.replace_actor max_queued must be positive, got {}",
+                        n
+                    )))
+                }
+            };
+            api.borrow_mut().replace_actor(
+                &name,
+                &script_path,
+                drain_timeout,
+                max_queued,
+                cb_thread_id,
+            )
+        },
+    )?;
+    globals.raw_set(CTX_REPLACE_ACTOR.entry.name, replace_actor)?;
+
+    let terminate = scope.create_function_mut(move |_, _: LuaMessage| {
+        api.borrow_mut().terminate();
+        Ok(())
+    })?;
+    globals.raw_set(CTX_TERMINATE.entry.name, terminate)?;
+
+    let restart = scope.create_function_mut(move |_, keep_state: Option<bool>| {
+        api.borrow_mut().restart(keep_state.unwrap_or(false))
+    })?;
+    globals.raw_set(CTX_RESTART.entry.name, restart)?;
+
+    let breaker_state = scope.create_function_mut(move |_, recipient_name: String| {
+        Ok(api.borrow_mut().breaker_state(&recipient_name))
+    })?;
+    globals.raw_set(CTX_BREAKER_STATE.entry.name, breaker_state)?;
+
+    let alias = scope.create_function_mut(move |_, (alias, target): (String, String)| {
+        validate_recipient_name(&alias, max_recipient_name_len)?;
+        validate_recipient_name(&target, max_recipient_name_len)?;
+        api.borrow_mut().alias(alias, target)
+    })?;
+    globals.raw_set(CTX_ALIAS.entry.name, alias)?;
+
+    let unalias = scope.create_function_mut(move |_, alias: String| {
+        api.borrow_mut().unalias(&alias);
+        Ok(())
+    })?;
+    globals.raw_set(CTX_UNALIAS.entry.name, unalias)?;
+
+    let resolve =
+        scope.create_function_mut(move |_, alias: String| Ok(api.borrow_mut().resolve(&alias)))?;
+    globals.raw_set(CTX_RESOLVE.entry.name, resolve)?;
+
+    let warn = scope.create_function_mut(move |_, (key, message): (String, String)| {
+        api.borrow_mut().warn(&key, &message);
+        Ok(())
+    })?;
+    globals.raw_set("warn", warn)?;
+
+    let uuid = scope.create_function_mut(move |_, ()| Ok(api.borrow_mut().uuid()))?;
+    globals.raw_set(CTX_UUID.entry.name, uuid)?;
+
+    Ok(())
+}
+
+// Convert hook arguments to Lua values.
+pub(crate) fn args_to_lua<'lua>(
+    lua_ctx: Context<'lua>,
+    args: Vec<LuaMessage>,
+) -> Result<MultiValue<'lua>, LuaError> {
+    let values = args
+        .into_iter()
+        .map(|msg| msg.to_lua(lua_ctx))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(MultiValue::from_vec(values))
+}
+
+// Tell a suspended coroutine from a returned value, converting the latter only if a reply is expected.
+pub(crate) fn classify_return<'lua>(
+    lua_ctx: Context<'lua>,
+    ret: Value<'lua>,
+    reply_expected: bool,
+) -> Result<HookOutcome, LuaError> {
+    if let Value::String(ref s) = ret {
+        let thread_id = s
+            .to_str()
+            .ok()
+            .and_then(|s| s.strip_prefix(SUSPENDED_PREFIX))
+            .and_then(|id| id.parse().ok());
+        if let Some(thread_id) = thread_id {
+            return Ok(HookOutcome::Suspended(thread_id));
+        }
+    }
+    // nobody will read the result, skip the conversion
+    if !reply_expected {
+        return Ok(HookOutcome::Completed(LuaMessage::Nil));
+    }
+    #[cfg(test)]
+    RETURN_CONVERSIONS.with(|c| c.set(c.get() + 1));
+    LuaMessage::from_lua(ret, lua_ctx).map(HookOutcome::Completed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::correlation::next_correlation_id;
+
+    #[derive(Debug, PartialEq)]
+    enum Call {
+        Notify(LuaMessage),
+        NotifyLater(LuaMessage, Duration),
+        DoSend(String, LuaMessage),
+        Send(String, LuaMessage, i64),
+        Terminate,
+        Restart(bool),
+    }
+
+    #[derive(Default)]
+    struct MockApi {
+        calls: Vec<Call>,
+    }
+
+    impl ActorApi for MockApi {
+        fn notify(&mut self, msg: LuaMessage) {
+            self.calls.push(Call::Notify(msg));
+        }
+
+        fn notify_later(&mut self, msg: LuaMessage, after: Duration) {
+            self.calls.push(Call::NotifyLater(msg, after));
+        }
+
+        fn run_interval(
+            &mut self,
+            _: Duration,
+            _: HashMap<String, LuaMessage>,
+            _: TickPolicy,
+        ) -> i64 {
+            0
+        }
+
+        fn cancel_interval(&mut self, _: i64) {}
+
+        fn schedule(&mut self, name: Option<String>, _: CronExpr, _: LuaMessage) -> String {
+            name.unwrap_or_default()
+        }
+
+        fn unschedule(&mut self, _: &str) -> bool {
+            false
+        }
+
+        fn watch(&mut self, _: &str) -> Result<(), LuaError> {
+            Ok(())
+        }
+
+        fn unwatch(&mut self, _: &str) {}
+
+        fn do_send(&mut self, recipient_name: &str, msg: LuaMessage) {
+            self.calls
+                .push(Call::DoSend(recipient_name.to_string(), msg));
+        }
+
+        fn do_send_confirmed(&mut self, recipient_name: &str, msg: LuaMessage) -> i64 {
+            self.do_send(recipient_name, msg);
+            self.calls.len() as i64
+        }
+
+        fn send(&mut self, recipient_name: String, msg: LuaMessage, cb_thread_id: i64) {
+            self.calls
+                .push(Call::Send(recipient_name, msg, cb_thread_id));
+        }
+
+        fn correlate(&mut self, _: Option<Duration>) -> i64 {
+            next_correlation_id()
+        }
+
+        fn uncorrelate(&mut self, _: i64) {}
+
+        fn replace_actor(
+            &mut self,
+            _: &str,
+            _: &str,
+            _: Duration,
+            _: usize,
+            _: i64,
+        ) -> Result<(), LuaError> {
+            Ok(())
+        }
+
+        fn terminate(&mut self) {
+            self.calls.push(Call::Terminate);
+        }
+
+        fn restart(&mut self, keep_state: bool) -> Result<(), LuaError> {
+            self.calls.push(Call::Restart(keep_state));
+            Ok(())
+        }
+
+        fn breaker_state(&mut self, _: &str) -> Option<&'static str> {
+            None
+        }
+
+        fn alias(&mut self, _: String, _: String) -> Result<(), LuaError> {
+            Ok(())
+        }
+
+        fn unalias(&mut self, _: &str) {}
+
+        fn resolve(&mut self, _: &str) -> Option<String> {
+            None
+        }
+
+        fn set_outbox(&mut self, _: &str, _: usize, _: Duration) {}
+
+        fn outbox_depth(&mut self, _: &str) -> usize {
+            0
+        }
+
+        fn warn(&mut self, _: &str, _: &str) {}
+
+        fn uuid(&mut self) -> String {
+            "00000000-0000-4000-8000-000000000000".to_string()
+        }
+    }
+
+    fn runtime_with_handle(script: &str) -> LuaRuntime {
+        let runtime = LuaRuntime::new(Lua::new());
+        runtime
+            .load_scripts(None, Some(script.into()), None)
+            .unwrap();
+        runtime
+    }
+
+    fn handle(
+        runtime: &mut LuaRuntime,
+        api: &mut MockApi,
+        msg: LuaMessage,
+        reply_expected: bool,
+    ) -> Result<HookOutcome, LuaError> {
+        runtime.call_hook(
+            api,
+            "__run",
+            vec![
+                LuaMessage::from("handle"),
+                msg,
+                LuaMessage::from(reply_expected),
+            ],
+            reply_expected,
+        )
+    }
+
+    #[test]
+    fn classify_return_values() {
+        let vm = Lua::new();
+        vm.context(|ctx| {
+            let ret = ctx.create_string("__suspended__3").unwrap();
+            assert_eq!(
+                classify_return(ctx, Value::String(ret.clone()), true).unwrap(),
+                HookOutcome::Suspended(3)
+            );
+            // the thread id is kept even if nobody reads the reply
+            assert_eq!(
+                classify_return(ctx, Value::String(ret), false).unwrap(),
+                HookOutcome::Suspended(3)
+            );
+
+            assert_eq!(
+                classify_return(ctx, Value::Integer(42), true).unwrap(),
+                HookOutcome::Completed(LuaMessage::from(42))
+            );
+            assert_eq!(
+                classify_return(ctx, Value::Integer(42), false).unwrap(),
+                HookOutcome::Completed(LuaMessage::Nil)
+            );
+
+            let ret = ctx.create_string("hello").unwrap();
+            assert_eq!(
+                classify_return(ctx, Value::String(ret), true).unwrap(),
+                HookOutcome::Completed(LuaMessage::from("hello"))
+            );
+        });
+    }
+
+    #[test]
+    fn args_to_lua_values() {
+        let vm = Lua::new();
+        vm.context(|ctx| {
+            let args = args_to_lua(ctx, vec![LuaMessage::from(1), LuaMessage::from("a")]).unwrap();
+            let args = args.into_vec();
+            assert_eq!(args.len(), 2);
+            assert_eq!(i64::from_lua(args[0].clone(), ctx).unwrap(), 1);
+            assert_eq!(String::from_lua(args[1].clone(), ctx).unwrap(), "a");
+        });
+    }
+
+    #[test]
+    fn call_hook_completed() {
+        let mut runtime = runtime_with_handle("return ctx.msg + 1");
+        let mut api = MockApi::default();
+
+        let res = handle(&mut runtime, &mut api, LuaMessage::from(1), true).unwrap();
+        assert_eq!(res, HookOutcome::Completed(LuaMessage::from(2)));
+        assert!(api.calls.is_empty());
+    }
+
+    #[test]
+    fn call_hook_suspended() {
+        let mut runtime = runtime_with_handle(
+            r#"
+            result = ctx.send("other", ctx.msg)
+            "#,
+        );
+        let mut api = MockApi::default();
+
+        let res = handle(&mut runtime, &mut api, LuaMessage::from(7), true).unwrap();
+        assert_eq!(res, HookOutcome::Suspended(0));
+        assert_eq!(
+            api.calls,
+            vec![Call::Send("other".to_string(), LuaMessage::from(7), 0)]
+        );
+
+        let res = runtime
+            .call_hook(
+                &mut api,
+                "__resume",
+                vec![LuaMessage::from(0), LuaMessage::from("pong")],
+                false,
+            )
+            .unwrap();
+        assert_eq!(res, HookOutcome::Completed(LuaMessage::Nil));
+        let result = runtime
+            .vm
+            .context(|ctx| ctx.globals().get::<_, String>("result"))
+            .unwrap();
+        assert_eq!(result, "pong");
+    }
+
+    fn run_hook(runtime: &mut LuaRuntime, api: &mut MockApi, name: &str) -> HookOutcome {
+        runtime
+            .call_hook(
+                api,
+                "__run",
+                vec![
+                    LuaMessage::from(name),
+                    LuaMessage::Nil,
+                    LuaMessage::from(true),
+                ],
+                true,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn prelude_loads_each_hook() {
+        let runtime = LuaRuntime::new(Lua::new());
+        runtime
+            .load_scripts(
+                Some("return 'started'".into()),
+                Some("return 'handle'".into()),
+                Some("return 'stopped'".into()),
+            )
+            .unwrap();
+        for name in &["internal_error", "migrations", "state_quota_exceeded"] {
+            runtime
+                .load_script(name, format!("return '{}'", name))
+                .unwrap();
+        }
+        let mut runtime = runtime;
+        let mut api = MockApi::default();
+        for name in &[
+            "started",
+            "handle",
+            "stopped",
+            "internal_error",
+            "migrations",
+            "state_quota_exceeded",
+        ] {
+            assert!(runtime.has_script(name));
+            assert_eq!(
+                run_hook(&mut runtime, &mut api, name),
+                HookOutcome::Completed(LuaMessage::from(*name))
+            );
+        }
+        // hooks are optional
+        assert_eq!(
+            run_hook(&mut runtime, &mut api, "missing"),
+            HookOutcome::Completed(LuaMessage::Nil)
+        );
+
+        // syntax errors name the hook
+        match runtime.load_script("handle", "return 1 +".to_string()) {
+            Err(e) => assert!(e.to_string().contains(r#"[string "handle"]:1:"#), "{}", e),
+            res => panic!("expected a syntax error, got {:?}", res),
+        }
+        assert_eq!(
+            run_hook(&mut runtime, &mut api, "handle"),
+            HookOutcome::Completed(LuaMessage::from("handle"))
+        );
+        assert!(api.calls.is_empty());
+    }
+
+    #[test]
+    fn prelude_resumes_with_values() {
+        let mut runtime = runtime_with_handle(
+            r#"
+            local a = ctx.send("other", 1)
+            local b = ctx.send("other", a + 1)
+            return a + b
+            "#,
+        );
+        let mut api = MockApi::default();
+
+        let res = handle(&mut runtime, &mut api, LuaMessage::Nil, true).unwrap();
+        assert_eq!(res, HookOutcome::Suspended(0));
+        // the thread yields again with the same id
+        let res = resume(&mut runtime, &mut api, 0, LuaMessage::from(10));
+        assert_eq!(res, HookOutcome::Suspended(0));
+        let res = resume(&mut runtime, &mut api, 0, LuaMessage::from(20));
+        assert_eq!(res, HookOutcome::Completed(LuaMessage::from(30)));
+        assert_eq!(
+            api.calls,
+            vec![
+                Call::Send("other".to_string(), LuaMessage::from(1), 0),
+                Call::Send("other".to_string(), LuaMessage::from(11), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn prelude_resume_unknown_thread() {
+        let mut runtime = runtime_with_handle(r#"return ctx.send("other", 1)"#);
+        let mut api = MockApi::default();
+
+        let res = resume(&mut runtime, &mut api, 42, LuaMessage::from(1));
+        assert_eq!(res, HookOutcome::Completed(LuaMessage::Nil));
+        // the pending thread is not affected
+        let res = handle(&mut runtime, &mut api, LuaMessage::Nil, true).unwrap();
+        assert_eq!(res, HookOutcome::Suspended(0));
+        let res = resume(&mut runtime, &mut api, 1, LuaMessage::from(1));
+        assert_eq!(res, HookOutcome::Completed(LuaMessage::Nil));
+        let res = resume(&mut runtime, &mut api, 0, LuaMessage::from("pong"));
+        assert_eq!(res, HookOutcome::Completed(LuaMessage::from("pong")));
+    }
+
+    #[test]
+    fn prelude_double_resume() {
+        let mut runtime = runtime_with_handle(
+            r#"
+            local first = ctx.send("other", 1)
+            -- a running thread can't be resumed
+            local nested = __resume(ctx.thread_id, "nested")
+            local second = ctx.send("other", 2)
+            return first .. " " .. second .. " " .. tostring(nested)
+            "#,
+        );
+        let mut api = MockApi::default();
+
+        let res = handle(&mut runtime, &mut api, LuaMessage::Nil, true).unwrap();
+        assert_eq!(res, HookOutcome::Suspended(0));
+        let res = resume(&mut runtime, &mut api, 0, LuaMessage::from("a"));
+        assert_eq!(res, HookOutcome::Suspended(0));
+        let res = resume(&mut runtime, &mut api, 0, LuaMessage::from("b"));
+        assert_eq!(res, HookOutcome::Completed(LuaMessage::from("a b nil")));
+        // the finished thread doesn't run again
+        let res = resume(&mut runtime, &mut api, 0, LuaMessage::from("c"));
+        assert_eq!(res, HookOutcome::Completed(LuaMessage::Nil));
+        assert_eq!(
+            api.calls,
+            vec![
+                Call::Send("other".to_string(), LuaMessage::from(1), 0),
+                Call::Send("other".to_string(), LuaMessage::from(2), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn prelude_error_location() {
+        let mut runtime = runtime_with_handle(
+            r#"
+            local function fail()
+                error("boom")
+            end
+            if ctx.msg == "resumed" then
+                ctx.send("other", 1)
+            end
+            fail()
+            "#,
+        );
+        let mut api = MockApi::default();
+        let location = |res: Result<HookOutcome, LuaError>| match res {
+            Err(LuaError::RuntimeError(e)) => e,
+            res => panic!("script error should be returned, got {:?}", res),
+        };
+
+        // the error points at the script, not at the prelude
+        let e = location(handle(&mut runtime, &mut api, LuaMessage::Nil, true));
+        assert!(e.starts_with(r#"[string "handle"]:3: boom"#), "{}", e);
+        assert!(e.contains("stack traceback:"), "{}", e);
+
+        let res = handle(&mut runtime, &mut api, LuaMessage::from("resumed"), true).unwrap();
+        assert_eq!(res, HookOutcome::Suspended(1));
+        let e = location(runtime.call_hook(
+            &mut api,
+            "__resume",
+            vec![LuaMessage::from(1), LuaMessage::Nil],
+            true,
+        ));
+        assert!(e.starts_with(r#"[string "handle"]:3: boom"#), "{}", e);
+
+        // the failed thread is dropped, the runtime keeps working
+        let res = resume(&mut runtime, &mut api, 1, LuaMessage::Nil);
+        assert_eq!(res, HookOutcome::Completed(LuaMessage::Nil));
+        let res = handle(&mut runtime, &mut api, LuaMessage::from("resumed"), true).unwrap();
+        assert_eq!(res, HookOutcome::Suspended(2));
+    }
+
+    #[test]
+    fn call_hook_records_api_calls() {
+        let mut runtime = runtime_with_handle(
+            r#"
+            ctx.notify(1)
+            ctx.notify_later(2, 5)
+            ctx.do_send("other", 3)
+            ctx.terminate()
+            ctx.restart(true)
+            "#,
+        );
+        let mut api = MockApi::default();
+
+        handle(&mut runtime, &mut api, LuaMessage::Nil, false).unwrap();
+        assert_eq!(
+            api.calls,
+            vec![
+                Call::Notify(LuaMessage::from(1)),
+                Call::NotifyLater(LuaMessage::from(2), Duration::from_secs(5)),
+                Call::DoSend("other".to_string(), LuaMessage::from(3)),
+                Call::Terminate,
+                Call::Restart(true),
+            ]
+        );
+    }
+
+    #[test]
+    fn notify_later_durations() {
+        let mut runtime = runtime_with_handle(
+            r#"
+            local ok, err = pcall(ctx.notify_later, "x", ctx.msg)
+            return ok or tostring(err)
+            "#,
+        );
+        runtime.max_duration = Duration::from_secs(60);
+        let mut api = MockApi::default();
+        let mut run = |secs: f64| {
+            handle(&mut runtime, &mut api, LuaMessage::from(secs), true)
+                .unwrap()
+                .into_message()
+        };
+        let error = |res: LuaMessage| match res {
+            LuaMessage::String(e) => e,
+            res => panic!("expected an error, got {:?}", res),
+        };
+
+        assert_eq!(run(0.0), LuaMessage::from(true));
+        assert_eq!(run(1.5), LuaMessage::from(true));
+        assert_eq!(run(60.0), LuaMessage::from(true));
+        // used to wrap around to a delay of 584 billion years
+        assert!(error(run(-1.0)).contains("ctx.notify_later delay must not be negative, got -1"));
+        assert!(error(run(f64::NAN)).contains("must be a finite number of seconds"));
+        assert!(error(run(f64::INFINITY)).contains("must be a finite number of seconds"));
+        assert!(error(run(61.0)).contains("must be at most 60 seconds, got 61"));
+        assert_eq!(
+            api.calls,
+            vec![
+                Call::NotifyLater(LuaMessage::from("x"), Duration::from_secs(0)),
+                Call::NotifyLater(LuaMessage::from("x"), Duration::from_millis(1500)),
+                Call::NotifyLater(LuaMessage::from("x"), Duration::from_secs(60)),
+            ]
+        );
+    }
+
+    #[test]
+    fn run_interval_durations() {
+        let mut runtime = runtime_with_handle(
+            r#"
+            local ok, err = pcall(ctx.run_interval, ctx.msg, {})
+            return ok or tostring(err)
+            "#,
+        );
+        let mut api = MockApi::default();
+        let mut run = |secs: f64| {
+            handle(&mut runtime, &mut api, LuaMessage::from(secs), true)
+                .unwrap()
+                .into_message()
+        };
+
+        assert_eq!(run(0.5), LuaMessage::from(true));
+        for &(secs, expected) in &[
+            (0.0, "period must be positive, got 0"),
+            (-1.0, "period must not be negative"),
+            (f64::NAN, "must be a finite number of seconds"),
+            (31.0 * 24.0 * 3600.0, "must be at most 2592000 seconds"),
+        ] {
+            match run(secs) {
+                LuaMessage::String(e) => assert!(e.contains(expected), "{}", e),
+                res => panic!("expected an error, got {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn call_hook_script_error() {
+        let mut runtime = runtime_with_handle(r#"error("boom")"#);
+        let mut api = MockApi::default();
+
+        match handle(&mut runtime, &mut api, LuaMessage::Nil, true) {
+            Err(LuaError::RuntimeError(e)) => assert!(e.contains("boom"), "{}", e),
+            res => panic!("script error should be returned, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn call_hook_invalid_recipient_name() {
+        let mut runtime = runtime_with_handle(r#"ctx.do_send("self", 1)"#);
+        let mut api = MockApi::default();
+
+        match handle(&mut runtime, &mut api, LuaMessage::Nil, false) {
+            Err(e) => assert!(
+                format!("{:?}", e).contains("invalid recipient name"),
+                "{:?}",
+                e
+            ),
+            res => panic!("invalid name should fail, got {:?}", res),
+        }
+        assert!(api.calls.is_empty());
+    }
+
+    #[test]
+    fn call_hook_undefined_function() {
+        let mut runtime = runtime_with_handle("return 1");
+        let mut api = MockApi::default();
+
+        let res = runtime
+            .call_hook(&mut api, "__missing", vec![], true)
+            .unwrap();
+        assert_eq!(res, HookOutcome::Completed(LuaMessage::Nil));
+    }
+
+    #[test]
+    fn scratch_cleared_between_invocations() {
+        let mut runtime = runtime_with_handle(
+            r#"
+            local n = 0
+            for _ in pairs(ctx.scratch) do n = n + 1 end
+            ctx.scratch.msg = ctx.msg
+            ctx.scratch[1] = {}
+            return { keys = n, scratch = tostring(ctx.scratch) }
+            "#,
+        );
+        let mut api = MockApi::default();
+
+        let mut first = None;
+        for i in 0..3 {
+            let res = handle(&mut runtime, &mut api, LuaMessage::from(i), true)
+                .unwrap()
+                .into_message();
+            assert_eq!(res.get("keys"), Some(&LuaMessage::from(0)));
+            // the table is recycled
+            let scratch = res.get("scratch").cloned();
+            assert_eq!(*first.get_or_insert(scratch.clone()), scratch);
+        }
+    }
+
+    #[test]
+    fn scratch_kept_while_suspended() {
+        let mut runtime = runtime_with_handle(
+            r#"
+            ctx.scratch.msg = ctx.msg
+            local reply = ctx.send("other", ctx.msg)
+            return ctx.scratch.msg .. reply
+            "#,
+        );
+        let mut api = MockApi::default();
+
+        let a = handle(&mut runtime, &mut api, LuaMessage::from("a"), true).unwrap();
+        let b = handle(&mut runtime, &mut api, LuaMessage::from("b"), true).unwrap();
+        assert_eq!(a, HookOutcome::Suspended(0));
+        assert_eq!(b, HookOutcome::Suspended(1));
+
+        let resume = |runtime: &mut LuaRuntime, api: &mut MockApi, thread_id: i64| {
+            runtime
+                .call_hook(
+                    api,
+                    "__resume",
+                    vec![LuaMessage::from(thread_id), LuaMessage::from("!")],
+                    true,
+                )
+                .unwrap()
+        };
+        assert_eq!(
+            resume(&mut runtime, &mut api, 1),
+            HookOutcome::Completed(LuaMessage::from("b!"))
+        );
+        assert_eq!(
+            resume(&mut runtime, &mut api, 0),
+            HookOutcome::Completed(LuaMessage::from("a!"))
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn scratch_stored_in_state() {
+        let mut runtime = runtime_with_handle(
+            r#"
+            ctx.state.kept = { scratch = ctx.scratch }
+            "#,
+        );
+        let mut api = MockApi::default();
+
+        assert!(handle(&mut runtime, &mut api, LuaMessage::Nil, false).is_ok());
+        let err = handle(&mut runtime, &mut api, LuaMessage::Nil, false).unwrap_err();
+        assert!(
+            err.to_string().contains(
+                "ctx.scratch must not be stored in ctx.state, found in ctx.state.kept.scratch"
+            ),
+            "{}",
+            err
+        );
+    }
+
+    fn resume(
+        runtime: &mut LuaRuntime,
+        api: &mut MockApi,
+        thread_id: i64,
+        reply: LuaMessage,
+    ) -> HookOutcome {
+        runtime
+            .call_hook(
+                api,
+                "__resume",
+                vec![LuaMessage::from(thread_id), reply],
+                true,
+            )
+            .unwrap()
+    }
+
+    fn coroutine_pool_len(runtime: &LuaRuntime) -> usize {
+        runtime.vm.context(|ctx| {
+            let pool: Table = ctx.globals().get("__coroutine_pool").unwrap();
+            pool.len().unwrap() as usize
+        })
+    }
+
+    #[test]
+    fn send_correlated_matchers() {
+        let mut runtime = runtime_with_handle(
+            r#"
+            if ctx.msg.op == "request" then
+                local reply = ctx.send_correlated("legacy", { n = ctx.msg.n }, { id_field = "req_id" })
+                return { n = ctx.msg.n, reply = reply.value }
+            end
+            return "handled"
+            "#,
+        );
+        runtime.set_max_correlated(2).unwrap();
+        let mut api = MockApi::default();
+        for n in 1..=2 {
+            let res = handle(
+                &mut runtime,
+                &mut api,
+                crate::lua_msg!({ op: "request", n: n }),
+                true,
+            );
+            assert!(matches!(res, Ok(HookOutcome::Suspended(_))), "{:?}", res);
+        }
+        let err = handle(
+            &mut runtime,
+            &mut api,
+            crate::lua_msg!({ op: "request", n: 3 }),
+            true,
+        )
+        .unwrap_err();
+        assert!(
+            format!("{:?}", err).contains("too many pending ctx.send_correlated, at most 2"),
+            "{:?}",
+            err
+        );
+        assert_eq!(runtime.pending_correlations().unwrap(), 2);
+
+        // the requests are copies carrying their id
+        let ids: Vec<i64> = api
+            .calls
+            .iter()
+            .map(|call| match call {
+                Call::DoSend(name, msg) if name == "legacy" => match msg.get("req_id") {
+                    Some(LuaMessage::Integer(id)) => *id,
+                    id => panic!("unexpected id {:?}", id),
+                },
+                call => panic!("unexpected {:?}", call),
+            })
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+
+        // a message with the id resumes the request instead of running `handle`
+        let reply = crate::lua_msg!({ req_id: (ids[1]), value: "b" });
+        let res = handle(&mut runtime, &mut api, reply.clone(), true)
+            .unwrap()
+            .into_message();
+        assert_eq!(res, crate::lua_msg!({ n: 2, reply: "b" }));
+        assert_eq!(runtime.pending_correlations().unwrap(), 1);
+        // only once
+        let res = handle(&mut runtime, &mut api, reply, true)
+            .unwrap()
+            .into_message();
+        assert_eq!(res, LuaMessage::from("handled"));
+        // the id in another field doesn't match
+        let res = handle(
+            &mut runtime,
+            &mut api,
+            crate::lua_msg!({ correlation_id: (ids[0]) }),
+            true,
+        )
+        .unwrap()
+        .into_message();
+        assert_eq!(res, LuaMessage::from("handled"));
+
+        runtime.clear_correlations().unwrap();
+        assert_eq!(runtime.pending_correlations().unwrap(), 0);
+        let threads = runtime.vm.context(|ctx| {
+            let threads: Table = ctx.globals().get("__threads").unwrap();
+            threads.pairs::<Value, Value>().count()
+        });
+        assert_eq!(threads, 0);
+        let res = handle(
+            &mut runtime,
+            &mut api,
+            crate::lua_msg!({ req_id: (ids[0]) }),
+            true,
+        )
+        .unwrap()
+        .into_message();
+        assert_eq!(res, LuaMessage::from("handled"));
+    }
+
+    // a handle invocation suspended by `ctx.send`, then resumed with the reply
+    fn send_round_trip(runtime: &mut LuaRuntime, api: &mut MockApi, msg: LuaMessage) -> LuaMessage {
+        match handle(runtime, api, msg, true).unwrap() {
+            HookOutcome::Suspended(thread_id) => {
+                resume(runtime, api, thread_id, LuaMessage::from("reply")).into_message()
+            }
+            outcome => panic!("unexpected {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn coroutine_pool_reuses_coroutines() {
+        let mut runtime = runtime_with_handle(
+            r##"
+            local seen
+            if ctx.msg == "set" then
+                seen = "secret"
+            elseif ctx.msg == "fail" then
+                ctx.state.failed = tostring(coroutine.running())
+                -- kept alive, a new coroutine could reuse its address otherwise
+                failed_thread = coroutine.running()
+                error("boom")
+            elseif ctx.msg == "send" then
+                seen = ctx.send("other", 1)
+            end
+            return { thread = tostring(coroutine.running()), seen = seen, args = select("#", ...) }
+            "##,
+        );
+        let mut api = MockApi::default();
+        let run = |runtime: &mut LuaRuntime, api: &mut MockApi, msg: &str| {
+            handle(runtime, api, LuaMessage::from(msg), true)
+                .unwrap()
+                .into_message()
+        };
+
+        let set = run(&mut runtime, &mut api, "set");
+        assert_eq!(set.get("seen"), Some(&LuaMessage::from("secret")));
+        assert_eq!(coroutine_pool_len(&runtime), 1);
+        // the locals of the previous script are gone
+        let get = run(&mut runtime, &mut api, "get");
+        assert_eq!(get.get("thread"), set.get("thread"));
+        assert_eq!(get.get("seen"), None);
+        assert_eq!(get.get("args"), Some(&LuaMessage::from(0)));
+
+        // the coroutine which raised the error isn't reused
+        assert!(handle(&mut runtime, &mut api, LuaMessage::from("fail"), true).is_err());
+        let state = runtime.get_state().unwrap();
+        assert_eq!(state.get("failed"), set.get("thread"));
+        assert_eq!(coroutine_pool_len(&runtime), 0);
+        let get = run(&mut runtime, &mut api, "get");
+        assert_ne!(get.get("thread"), set.get("thread"));
+
+        // the coroutine of a send goes back to the pool once resumed
+        let sent = send_round_trip(&mut runtime, &mut api, LuaMessage::from("send"));
+        assert_eq!(sent.get("thread"), get.get("thread"));
+        assert_eq!(sent.get("seen"), Some(&LuaMessage::from("reply")));
+        assert_eq!(coroutine_pool_len(&runtime), 1);
+    }
+
+    #[test]
+    fn frozen_state_conversion() {
+        let mut runtime = runtime_with_handle(r#"return ctx.state.nested.x"#);
+        let mut api = MockApi::default();
+        runtime
+            .set_state(crate::lua_msg!({ count: 1, nested: { x: "a" } }))
+            .unwrap();
+        runtime.freeze_state().unwrap();
+        // frozen twice, e.g. on restart
+        runtime.freeze_state().unwrap();
+
+        let res = handle(&mut runtime, &mut api, LuaMessage::Nil, true)
+            .unwrap()
+            .into_message();
+        assert_eq!(res, LuaMessage::from("a"));
+        crate::assert_lua_eq!(
+            runtime.get_state().unwrap(),
+            crate::lua_msg!({ count: 1, nested: { x: "a" } })
+        );
+    }
+
+    #[test]
+    fn coroutine_pool_size() {
+        let mut runtime = runtime_with_handle(r#"return ctx.send("other", ctx.msg)"#);
+        runtime.set_coroutine_pool_size(1).unwrap();
+        let mut api = MockApi::default();
+
+        let a = handle(&mut runtime, &mut api, LuaMessage::from("a"), true).unwrap();
+        let b = handle(&mut runtime, &mut api, LuaMessage::from("b"), true).unwrap();
+        assert_eq!(a, HookOutcome::Suspended(0));
+        assert_eq!(b, HookOutcome::Suspended(1));
+        resume(&mut runtime, &mut api, 0, LuaMessage::Nil);
+        resume(&mut runtime, &mut api, 1, LuaMessage::Nil);
+        assert_eq!(coroutine_pool_len(&runtime), 1);
+
+        runtime.set_coroutine_pool_size(0).unwrap();
+        runtime.set_deadline(Instant::now() + Duration::from_secs(60));
+        // dropped along with the coroutines created without the deadline
+        assert_eq!(coroutine_pool_len(&runtime), 0);
+        send_round_trip(&mut runtime, &mut api, LuaMessage::from("c"));
+        assert_eq!(coroutine_pool_len(&runtime), 0);
+    }
+
+    #[test]
+    fn coroutine_pool_memory_stable() {
+        let runtime = runtime_with_handle(r#"return ctx.send("other", { msg = ctx.msg })"#);
+        // round trips run from Lua, `call_hook` is too slow for 100k of them in debug builds
+        let growth = runtime.vm.context(|ctx| {
+            ctx.load(
+                r#"
+                send = function () end
+                local function round_trips(n)
+                    for i = 1, n do
+                        __run("handle", i, true)
+                        __resume(__thread_id_seq - 1, "reply")
+                    end
+                end
+                round_trips(1000)
+                collectgarbage()
+                local before = collectgarbage("count")
+                round_trips(100000)
+                collectgarbage()
+                return collectgarbage("count") - before
+                "#,
+            )
+            .eval::<f64>()
+            .unwrap()
+        });
+        assert_eq!(coroutine_pool_len(&runtime), 1);
+        assert!(growth < 16.0, "grew by {}KB", growth);
+    }
+}
@@ -0,0 +1,198 @@
+use ::actix::prelude::*;
+
+use crate::actor::LuaActor;
+use crate::cron::CronExpr;
+use crate::message::LuaMessage;
+use crate::pause::Buffered;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The wall clock is checked at least this often while waiting for a fire time, so a clock
+// adjustment delays a schedule by at most this long.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+// Unix time in milliseconds.
+pub(crate) fn unix_millis(now: SystemTime) -> i64 {
+    match now.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+// A schedule created with `ctx.schedule`.
+pub(crate) struct Schedule {
+    cron: CronExpr,
+    msg: LuaMessage,
+    // next fire time, Unix time in seconds
+    next: i64,
+    // distinguishes the timers of a schedule replaced under the same name
+    id: u64,
+}
+
+// The schedules of an actor by name. They survive `ctx.restart` and end with the actor.
+#[derive(Default)]
+pub(crate) struct Schedules {
+    next_id: u64,
+    schedules: HashMap<String, Schedule>,
+}
+
+impl Schedules {
+    // A name for a schedule created without one.
+    pub fn generate_name(&mut self) -> String {
+        format!("schedule-{}", self.next_id)
+    }
+
+    // Add or replace the schedule `name`, returning its id and the delay until it fires.
+    pub fn insert(
+        &mut self,
+        name: String,
+        cron: CronExpr,
+        msg: LuaMessage,
+        now_millis: i64,
+    ) -> (u64, Duration) {
+        let id = self.next_id;
+        self.next_id += 1;
+        // `parse` checked that the expression matches
+        let next = cron.next_after(now_millis.div_euclid(1000)).unwrap();
+        self.schedules.insert(
+            name,
+            Schedule {
+                cron,
+                msg,
+                next,
+                id,
+            },
+        );
+        (id, delay(next, now_millis))
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.schedules.remove(name).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.schedules.clear();
+    }
+
+    // The timer of the schedule `name` fired at `now_millis`: whether it is due, and the delay
+    // until the timer fires again. `None` if the schedule was removed or replaced.
+    pub fn fire(&mut self, name: &str, id: u64, now_millis: i64) -> Option<(bool, Duration)> {
+        let schedule = match self.schedules.get_mut(name) {
+            Some(schedule) if schedule.id == id => schedule,
+            _ => return None,
+        };
+        if now_millis < schedule.next * 1000 {
+            return Some((false, delay(schedule.next, now_millis)));
+        }
+        // fire times missed while the actor was busy or the clock jumped are skipped
+        schedule.next = schedule
+            .cron
+            .next_after(now_millis.div_euclid(1000))
+            .unwrap();
+        Some((true, delay(schedule.next, now_millis)))
+    }
+}
+
+fn delay(next: i64, now_millis: i64) -> Duration {
+    let millis = (next * 1000 - now_millis).max(0) as u64;
+    Duration::from_millis(millis).min(MAX_SLEEP)
+}
+
+// A fire time of the schedule `name` to handle.
+pub(crate) struct ScheduleFire {
+    name: String,
+    id: u64,
+}
+
+impl Message for ScheduleFire {
+    type Result = ();
+}
+
+impl Handler<ScheduleFire> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, fire: ScheduleFire, ctx: &mut Context<Self>) {
+        let msg = match self.schedules.schedules.get(&fire.name) {
+            Some(schedule) if schedule.id == fire.id => schedule.msg.clone(),
+            _ => return,
+        };
+        if self.pause.holds(true) {
+            self.hold(Buffered::Timer(msg));
+            return;
+        }
+        self.handle_message(msg, false, ctx);
+    }
+}
+
+impl LuaActor {
+    // Wait for the next fire time of the schedule `name`.
+    pub(crate) fn arm_schedule(name: String, id: u64, delay: Duration, ctx: &mut Context<Self>) {
+        ctx.run_later(delay, move |act, ctx| {
+            let now = unix_millis(SystemTime::now());
+            let (due, delay) = match act.schedules.fire(&name, id, now) {
+                Some(fired) => fired,
+                None => return,
+            };
+            if due {
+                ctx.notify(ScheduleFire {
+                    name: name.clone(),
+                    id,
+                });
+            }
+            LuaActor::arm_schedule(name, id, delay, ctx);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedule_fire() {
+        let mut schedules = Schedules::default();
+        let cron = CronExpr::parse("*/5 * * * *", 0).unwrap();
+        // 00:01:30
+        let start = 90_000;
+        let (id, delay) = schedules.insert("a".to_string(), cron, LuaMessage::Nil, start);
+        assert_eq!(delay, Duration::from_secs(60));
+        // early, the clock is checked again at most a minute later
+        assert_eq!(
+            schedules.fire("a", id, start + 60_000),
+            Some((false, Duration::from_secs(60)))
+        );
+        assert_eq!(
+            schedules.fire("a", id, 270_000),
+            Some((false, Duration::from_secs(30)))
+        );
+        assert_eq!(
+            schedules.fire("a", id, 300_000),
+            Some((true, Duration::from_secs(60)))
+        );
+        // 00:31:00, late for 00:10: 00:15 to 00:30 are skipped and the next is 00:35
+        assert_eq!(
+            schedules.fire("a", id, 1_860_000),
+            Some((true, Duration::from_secs(60)))
+        );
+        assert_eq!(
+            schedules.fire("a", id, 2_040_000),
+            Some((false, Duration::from_secs(60)))
+        );
+        assert_eq!(
+            schedules.fire("a", id, 2_100_000),
+            Some((true, Duration::from_secs(60)))
+        );
+
+        // replaced under the same name
+        let cron = CronExpr::parse("0 * * * *", 0).unwrap();
+        let (replaced, _) = schedules.insert("a".to_string(), cron, LuaMessage::Nil, 2_100_000);
+        assert_eq!(schedules.fire("a", id, 3_600_000), None);
+        assert_eq!(
+            schedules.fire("a", replaced, 3_600_000),
+            Some((true, Duration::from_secs(60)))
+        );
+        assert!(schedules.remove("a"));
+        assert!(!schedules.remove("a"));
+        assert_eq!(schedules.fire("a", replaced, 7_200_000), None);
+    }
+}
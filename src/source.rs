@@ -0,0 +1,291 @@
+use rlua::{Context, Error as LuaError, Function, Table, Value};
+
+use std::fs;
+use std::sync::Arc;
+
+/// A script loaded from a `ScriptSource`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptChunk {
+    pub source: String,
+    /// name of the chunk in Lua error messages and tracebacks
+    pub name: String,
+}
+
+/// Where the scripts of an actor are loaded from, see `LuaActorBuilder::with_script_source`.
+///
+/// Names are the ones passed to `LuaActorBuilder::on_handle` and the other hook setters, and the
+/// module names passed to `require`.
+pub trait ScriptSource: Send + Sync {
+    fn load(&self, name: &str) -> Result<ScriptChunk, LuaError>;
+
+    /// Version of the script `name`, `None` if the source doesn't track versions.
+    ///
+    /// When the actor restarts, the hooks whose version changed are loaded again.
+    fn version(&self, _name: &str) -> Option<String> {
+        None
+    }
+}
+
+/// Load scripts from files, names are file paths. The default source.
+pub struct FileSource;
+
+impl ScriptSource for FileSource {
+    fn load(&self, name: &str) -> Result<ScriptChunk, LuaError> {
+        let source = fs::read_to_string(name).map_err(|e| {
+            LuaError::RuntimeError(format!("failed to read script {}: {}", name, e))
+        })?;
+        Ok(ScriptChunk {
+            source,
+            name: name.to_string(),
+        })
+    }
+}
+
+// Make `require` load modules from `source` instead of files, keeping `package.preload`.
+pub(crate) fn install_searcher(
+    ctx: Context,
+    source: Arc<dyn ScriptSource>,
+) -> Result<(), LuaError> {
+    let searcher = ctx.create_function(move |ctx, name: String| match source.load(&name) {
+        Ok(chunk) => {
+            let loader = ctx
+                .load(&chunk.source)
+                .set_name(&chunk.name)?
+                .into_function()?;
+            Ok((
+                Value::Function(loader),
+                Value::String(ctx.create_string(&chunk.name)?),
+            ))
+        }
+        // `require` lists the messages of the searchers which failed
+        Err(e) => Ok((
+            Value::String(ctx.create_string(&format!("\n\tno script source module: {}", e))?),
+            Value::Nil,
+        )),
+    })?;
+    let package: Table = ctx.globals().get("package")?;
+    let searchers: Table = package.get("searchers")?;
+    let preload: Function = searchers.get(1)?;
+    let replaced = ctx.create_table()?;
+    replaced.set(1, preload)?;
+    replaced.set(2, searcher)?;
+    package.set("searchers", replaced)
+}
+
+// Hooks of an actor loaded from a `ScriptSource`, with the version they were loaded at.
+#[derive(Clone)]
+pub(crate) struct SourcedHooks {
+    pub source: Arc<dyn ScriptSource>,
+    // hook, script name and version
+    pub hooks: Vec<(&'static str, String, Option<String>)>,
+}
+
+impl SourcedHooks {
+    // Load the hooks whose version changed, returning them with their new source text.
+    // Versions are kept unless all the changed hooks could be loaded.
+    pub fn refresh(&mut self) -> Result<Vec<(&'static str, String)>, LuaError> {
+        let mut changed = Vec::new();
+        for (i, (hook, name, version)) in self.hooks.iter().enumerate() {
+            if version.is_none() {
+                continue;
+            }
+            let current = self.source.version(name);
+            if current != *version {
+                changed.push((i, *hook, self.source.load(name)?.source, current));
+            }
+        }
+        Ok(changed
+            .into_iter()
+            .map(|(i, hook, source, version)| {
+                self.hooks[i].2 = version;
+                (hook, source)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LuaActorBuilder;
+    use crate::message::LuaMessage;
+    use ::actix::prelude::*;
+    use futures::Future;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    // Scripts by name, versioned by the number of times they were set.
+    #[derive(Default)]
+    struct MemorySource {
+        scripts: Mutex<HashMap<String, (String, usize)>>,
+    }
+
+    impl MemorySource {
+        fn set(&self, name: &str, script: &str) {
+            let mut scripts = self.scripts.lock().unwrap();
+            let version = scripts.get(name).map_or(0, |s| s.1 + 1);
+            scripts.insert(name.to_string(), (script.to_string(), version));
+        }
+
+        fn remove(&self, name: &str) {
+            self.scripts.lock().unwrap().remove(name);
+        }
+    }
+
+    impl ScriptSource for MemorySource {
+        fn load(&self, name: &str) -> Result<ScriptChunk, LuaError> {
+            match self.scripts.lock().unwrap().get(name) {
+                Some((source, _)) => Ok(ScriptChunk {
+                    source: source.clone(),
+                    name: format!("memory:{}", name),
+                }),
+                None => Err(LuaError::RuntimeError(format!(
+                    "no script named {} in memory",
+                    name
+                ))),
+            }
+        }
+
+        fn version(&self, name: &str) -> Option<String> {
+            let scripts = self.scripts.lock().unwrap();
+            scripts.get(name).map(|s| s.1.to_string())
+        }
+    }
+
+    #[test]
+    fn script_source_hooks_and_modules() {
+        let system = System::new("test");
+
+        let source = Arc::new(MemorySource::default());
+        source.set("started", r#"ctx.state.base = require("base").value"#);
+        source.set(
+            "handle",
+            r#"
+            if ctx.msg == "restart" then
+                ctx.restart(true)
+                return
+            elseif ctx.msg == "missing" then
+                local ok, err = pcall(require, "missing")
+                return err
+            end
+            return ctx.state.base + ctx.msg
+            "#,
+        );
+        source.set("base", r#"return { value = 10 }"#);
+
+        let addr = LuaActorBuilder::new()
+            .with_script_source(source.clone())
+            .on_started("started")
+            .on_handle("handle")
+            .build()
+            .unwrap()
+            .start();
+
+        let changed = source.clone();
+        let l = addr
+            .send(LuaMessage::from(1))
+            .join(addr.send(LuaMessage::from("missing")))
+            .and_then(move |(res, missing)| {
+                assert_eq!(res, LuaMessage::from(11));
+                match missing {
+                    LuaMessage::String(err) => {
+                        assert!(err.contains("no script named missing in memory"), "{}", err)
+                    }
+                    other => panic!("unexpected {:?}", other),
+                }
+                changed.set("handle", r#"return ctx.state.base * ctx.msg"#);
+                addr.send(LuaMessage::from("restart"))
+                    .and_then(move |_| addr.send(LuaMessage::from(2)))
+            })
+            .map(|res| {
+                // the changed `handle` was loaded on restart
+                assert_eq!(res, LuaMessage::from(20));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn script_source_reload_error_raised() {
+        let system = System::new("test");
+
+        let source = Arc::new(MemorySource::default());
+        source.set(
+            "handle",
+            r#"
+            if ctx.msg == "restart" then
+                local ok, err = pcall(ctx.restart, true)
+                ctx.state.restart_error = tostring(err)
+                return
+            elseif ctx.msg == "error" then
+                return ctx.state.restart_error
+            end
+            return ctx.msg + 1
+            "#,
+        );
+        let addr = LuaActorBuilder::new()
+            .with_script_source(source.clone())
+            .on_handle("handle")
+            .build()
+            .unwrap()
+            .start();
+
+        // the changed `handle` can't be loaded on restart
+        source.remove("handle");
+        let l = addr
+            .send(LuaMessage::from("restart"))
+            .and_then(move |_| {
+                addr.send(LuaMessage::from("error"))
+                    .join(addr.send(LuaMessage::from(1)))
+            })
+            .map(|(err, res)| {
+                match err {
+                    LuaMessage::String(err) => {
+                        assert!(
+                            err.contains("ctx.restart can't reload the scripts"),
+                            "{}",
+                            err
+                        );
+                        assert!(err.contains("no script named handle in memory"), "{}", err);
+                    }
+                    other => panic!("unexpected {:?}", other),
+                }
+                // not restarted
+                assert_eq!(res, LuaMessage::from(2));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn script_source_missing_script() {
+        let source = Arc::new(MemorySource::default());
+        let err = LuaActorBuilder::new()
+            .with_script_source(source)
+            .on_handle("handle")
+            .build()
+            .err()
+            .expect("should return error");
+        assert!(
+            err.to_string().contains("no script named handle in memory"),
+            "{}",
+            err
+        );
+
+        let err = LuaActorBuilder::new()
+            .on_handle("src/lua/test/missing.lua")
+            .build()
+            .err()
+            .expect("should return error");
+        assert!(
+            err.to_string()
+                .contains("failed to read script src/lua/test/missing.lua"),
+            "{}",
+            err
+        );
+    }
+}
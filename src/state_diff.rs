@@ -0,0 +1,227 @@
+use crate::message::{sort_keys, LuaMessage};
+use std::collections::HashMap;
+use std::fmt;
+
+// Changes kept per diff, the others are only counted.
+const MAX_CHANGES: usize = 32;
+
+// Length of the values shown in the logs.
+const MAX_LOGGED_VALUE_LEN: usize = 80;
+
+// A change of `ctx.state` at a dotted path.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Change {
+    Added(String, LuaMessage),
+    Removed(String, LuaMessage),
+    Changed(String, LuaMessage, LuaMessage),
+}
+
+// The changes made to `ctx.state` by a message, see `LuaActorBuilder::with_state_diffing`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct StateDiff {
+    pub changes: Vec<Change>,
+    // changes beyond `MAX_CHANGES`
+    pub omitted: usize,
+}
+
+impl StateDiff {
+    // Compare the states, skipping the paths of `skip` and everything under them.
+    pub fn new(before: &LuaMessage, after: &LuaMessage, skip: &[String]) -> StateDiff {
+        let mut diff = StateDiff::default();
+        let mut path = Vec::new();
+        diff.walk(before, after, &mut path, skip);
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.omitted == 0
+    }
+
+    fn walk(
+        &mut self,
+        before: &LuaMessage,
+        after: &LuaMessage,
+        path: &mut Vec<String>,
+        skip: &[String],
+    ) {
+        match (before, after) {
+            (LuaMessage::Table(b), LuaMessage::Table(a)) => {
+                let mut keys: Vec<_> = b
+                    .keys()
+                    .chain(a.keys().filter(|k| !b.contains_key(*k)))
+                    .collect();
+                sort_keys(&mut keys);
+                for key in keys {
+                    path.push(key.clone());
+                    let joined = path.join(".");
+                    if !skip.contains(&joined) {
+                        match (b.get(key), a.get(key)) {
+                            (Some(b), Some(a)) => self.walk(b, a, path, skip),
+                            (None, Some(a)) => self.push(Change::Added(joined, a.clone())),
+                            (Some(b), None) => self.push(Change::Removed(joined, b.clone())),
+                            (None, None) => {}
+                        }
+                    }
+                    path.pop();
+                }
+            }
+            (LuaMessage::Array(b), LuaMessage::Array(a)) => {
+                for i in 0..b.len().max(a.len()) {
+                    path.push((i + 1).to_string());
+                    let joined = path.join(".");
+                    if !skip.contains(&joined) {
+                        match (b.get(i), a.get(i)) {
+                            (Some(b), Some(a)) => self.walk(b, a, path, skip),
+                            (None, Some(a)) => self.push(Change::Added(joined, a.clone())),
+                            (Some(b), None) => self.push(Change::Removed(joined, b.clone())),
+                            (None, None) => {}
+                        }
+                    }
+                    path.pop();
+                }
+            }
+            (LuaMessage::Integer(b), LuaMessage::Number(a)) if *b as f64 == *a => {}
+            (LuaMessage::Number(b), LuaMessage::Integer(a)) if *b == *a as f64 => {}
+            (b, a) if b == a => {}
+            (b, a) => self.push(Change::Changed(path.join("."), b.clone(), a.clone())),
+        }
+    }
+
+    fn push(&mut self, change: Change) {
+        if self.changes.len() < MAX_CHANGES {
+            self.changes.push(change);
+        } else {
+            self.omitted += 1;
+        }
+    }
+
+    // `{{op = "added", path = ..., new = ...}, {op = "changed", path = ..., old = ..., new = ...},
+    // {op = "removed", path = ..., old = ...}, omitted = n}` as returned by `ctx.last_diff()`.
+    pub fn to_message(&self) -> LuaMessage {
+        let changes: Vec<LuaMessage> = self
+            .changes
+            .iter()
+            .map(|change| {
+                let mut entry = HashMap::new();
+                let (op, path, old, new) = match change {
+                    Change::Added(path, new) => ("added", path, None, Some(new)),
+                    Change::Removed(path, old) => ("removed", path, Some(old), None),
+                    Change::Changed(path, old, new) => ("changed", path, Some(old), Some(new)),
+                };
+                entry.insert("op".to_string(), LuaMessage::from(op));
+                entry.insert("path".to_string(), LuaMessage::from(path.as_str()));
+                if let Some(old) = old {
+                    entry.insert("old".to_string(), old.clone());
+                }
+                if let Some(new) = new {
+                    entry.insert("new".to_string(), new.clone());
+                }
+                LuaMessage::from(entry)
+            })
+            .collect();
+        if self.omitted == 0 {
+            return LuaMessage::Array(changes);
+        }
+        let mut t: HashMap<String, LuaMessage> = changes
+            .into_iter()
+            .enumerate()
+            .map(|(i, change)| ((i + 1).to_string(), change))
+            .collect();
+        t.insert("omitted".to_string(), LuaMessage::from(self.omitted as i64));
+        LuaMessage::from(t)
+    }
+}
+
+// `value` as a Lua literal, cut to `MAX_LOGGED_VALUE_LEN` characters.
+pub(crate) fn truncated(value: &LuaMessage) -> String {
+    let s = value.to_string();
+    match s.char_indices().nth(MAX_LOGGED_VALUE_LEN) {
+        Some((end, _)) => format!("{}...", &s[..end]),
+        None => s,
+    }
+}
+
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, change) in self.changes.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match change {
+                Change::Added(path, new) => write!(f, "added {} = {}", path, truncated(new))?,
+                Change::Removed(path, old) => {
+                    write!(f, "removed {} (was {})", path, truncated(old))?
+                }
+                Change::Changed(path, old, new) => write!(
+                    f,
+                    "changed {} from {} to {}",
+                    path,
+                    truncated(old),
+                    truncated(new)
+                )?,
+            }
+        }
+        if self.omitted > 0 {
+            write!(f, " and {} more", self.omitted)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lua_msg;
+
+    #[test]
+    fn state_diff_changes() {
+        let before = lua_msg!({ count: 1, name: "a", nested: { x: 1, y: 2 }, gone: true });
+        let after = lua_msg!({ count: 2, name: "a", nested: { x: 1.0, z: 3 }, added: nil });
+        let diff = StateDiff::new(&before, &after, &[]);
+        assert_eq!(
+            diff.changes,
+            vec![
+                Change::Added("added".to_string(), LuaMessage::Nil),
+                Change::Changed(
+                    "count".to_string(),
+                    LuaMessage::from(1),
+                    LuaMessage::from(2)
+                ),
+                Change::Removed("gone".to_string(), LuaMessage::from(true)),
+                Change::Removed("nested.y".to_string(), LuaMessage::from(2)),
+                Change::Added("nested.z".to_string(), LuaMessage::from(3)),
+            ]
+        );
+        assert_eq!(
+            diff.to_string(),
+            "added added = nil, changed count from 1 to 2, removed gone (was true), \
+             removed nested.y (was 2), added nested.z = 3"
+        );
+        assert!(StateDiff::new(&before, &before, &[]).is_empty());
+    }
+
+    #[test]
+    fn state_diff_skip_and_limits() {
+        let before = lua_msg!({ cache: { a: 1 }, __ns: { metrics: { hits: 1 }, other: { n: 1 } } });
+        let after = lua_msg!({ cache: { a: 2 }, __ns: { metrics: { hits: 2 }, other: { n: 2 } } });
+        let skip = vec!["cache".to_string(), "__ns.metrics".to_string()];
+        let diff = StateDiff::new(&before, &after, &skip);
+        assert_eq!(
+            diff.changes,
+            vec![Change::Changed(
+                "__ns.other.n".to_string(),
+                LuaMessage::from(1),
+                LuaMessage::from(2)
+            )]
+        );
+
+        let many: HashMap<String, LuaMessage> = (0..40)
+            .map(|i| (format!("k{}", i), LuaMessage::from("x".repeat(100))))
+            .collect();
+        let diff = StateDiff::new(&lua_msg!({}), &LuaMessage::from(many), &[]);
+        assert_eq!(diff.changes.len(), MAX_CHANGES);
+        assert_eq!(diff.omitted, 8);
+        assert!(diff.to_string().ends_with("x... and 8 more"));
+        assert_eq!(diff.to_message().get("omitted"), Some(&LuaMessage::from(8)));
+    }
+}
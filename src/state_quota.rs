@@ -0,0 +1,409 @@
+use ::actix::prelude::*;
+
+use crate::actor::LuaActor;
+use crate::message::LuaMessage;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// What a `LuaActor` does when `ctx.state` stays above its quota, see
+/// `LuaActorBuilder::with_state_quota`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StateQuotaPolicy {
+    /// Log a warning, once per warning window.
+    Warn,
+    /// Reject the writes of new keys to `ctx.state` until it's back under the quota, updates
+    /// and deletes of the existing keys are allowed.
+    RejectWrites,
+    /// Stop the actor.
+    Stop,
+}
+
+impl StateQuotaPolicy {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            StateQuotaPolicy::Warn => "warn",
+            StateQuotaPolicy::RejectWrites => "reject_writes",
+            StateQuotaPolicy::Stop => "stop",
+        }
+    }
+}
+
+// Configured by `LuaActorBuilder::with_state_quota`.
+#[derive(Clone)]
+pub(crate) struct StateQuota {
+    pub bytes: usize,
+    pub policy: StateQuotaPolicy,
+    // set while the `state_quota_exceeded` hook runs
+    checking: bool,
+    // `ctx.state` may reject new keys
+    limited: bool,
+    // invocations which left the state above the quota
+    exceeded: u64,
+}
+
+impl StateQuota {
+    pub fn new(bytes: usize, policy: StateQuotaPolicy) -> StateQuota {
+        StateQuota {
+            bytes,
+            policy,
+            checking: false,
+            limited: false,
+            exceeded: 0,
+        }
+    }
+}
+
+/// Read the estimated size of `ctx.state` in a `LuaActor`.
+///
+/// The estimate is computed when the message is handled, see
+/// `LuaActorBuilder::with_state_quota` for the rules. The reply is a table:
+///
+/// ```lua
+/// {
+///     bytes = 1234,
+///     quota = 4096,   -- nil without `with_state_quota`
+///     exceeded = 0,   -- invocations which left the state above the quota
+///     limited = false, -- new keys are rejected, see `StateQuotaPolicy::RejectWrites`
+/// }
+/// ```
+///
+/// The reply is `nil` if the state can't be walked, e.g. the VM ran out of memory.
+pub struct GetStateSize;
+
+impl Message for GetStateSize {
+    type Result = LuaMessage;
+}
+
+impl Handler<GetStateSize> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, _: GetStateSize, _: &mut Context<Self>) -> LuaMessage {
+        let bytes = match self.runtime.state_size() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                log::error!("{} failed to size ctx.state: {}", self.log_prefix, e);
+                return LuaMessage::Nil;
+            }
+        };
+        let mut stats = HashMap::new();
+        stats.insert("bytes".to_string(), LuaMessage::from(bytes));
+        let (exceeded, limited) = match self.state_quota {
+            Some(ref quota) => {
+                stats.insert("quota".to_string(), LuaMessage::from(quota.bytes));
+                (quota.exceeded as i64, quota.limited)
+            }
+            None => (0, false),
+        };
+        stats.insert("exceeded".to_string(), LuaMessage::from(exceeded));
+        stats.insert("limited".to_string(), LuaMessage::from(limited));
+        LuaMessage::from(stats)
+    }
+}
+
+impl LuaActor {
+    // Compare the size of `ctx.state` to the quota after an invocation. Above it, the
+    // `state_quota_exceeded` hook can prune the state, then the policy applies if it's still
+    // above.
+    pub(crate) fn check_state_quota(&mut self, ctx: &mut Context<Self>) {
+        let (bytes, policy) = match self.state_quota {
+            Some(ref quota) if !quota.checking => (quota.bytes, quota.policy),
+            _ => return,
+        };
+        let mut size = match self.sized_state() {
+            Some(size) => size,
+            None => return,
+        };
+        if size > bytes && self.runtime.has_script("state_quota_exceeded") {
+            let mut event = HashMap::new();
+            event.insert("size".to_string(), LuaMessage::from(size));
+            event.insert("quota".to_string(), LuaMessage::from(bytes));
+            self.set_checking_state_quota(true);
+            let res = self.invoke_hook(
+                ctx,
+                "state_quota_exceeded",
+                "__run",
+                vec![
+                    LuaMessage::from("state_quota_exceeded"),
+                    LuaMessage::from(event),
+                    LuaMessage::from(false),
+                ],
+                false,
+            );
+            self.set_checking_state_quota(false);
+            if let Err(e) = res {
+                log::error!(
+                    "{} state_quota_exceeded hook failed: {}",
+                    self.log_prefix,
+                    e
+                );
+            }
+            size = match self.sized_state() {
+                Some(size) => size,
+                None => return,
+            };
+        }
+
+        let limited = self.state_quota.as_ref().is_some_and(|q| q.limited);
+        if size <= bytes {
+            if limited {
+                self.limit_state_growth(false);
+            }
+            return;
+        }
+        if let Some(ref mut quota) = self.state_quota {
+            quota.exceeded += 1;
+        }
+        let message = format!(
+            "ctx.state uses about {} bytes, above its quota of {} bytes",
+            size, bytes
+        );
+        match policy {
+            StateQuotaPolicy::Warn => {}
+            // the VM may have been rebuilt since the state was limited
+            StateQuotaPolicy::RejectWrites => self.limit_state_growth(true),
+            StateQuotaPolicy::Stop => {
+                log::error!("{} {}, stopping", self.log_prefix, message);
+                ctx.stop();
+                return;
+            }
+        }
+        self.warnings
+            .warn_once(&self.name, "state_quota", &message, Instant::now());
+    }
+
+    fn sized_state(&mut self) -> Option<usize> {
+        match self.runtime.state_size() {
+            Ok(size) => Some(size),
+            Err(e) => {
+                self.warnings.warn_once(
+                    &self.name,
+                    "state_quota",
+                    &format!("failed to size ctx.state: {}", e),
+                    Instant::now(),
+                );
+                None
+            }
+        }
+    }
+
+    fn set_checking_state_quota(&mut self, checking: bool) {
+        if let Some(ref mut quota) = self.state_quota {
+            quota.checking = checking;
+        }
+    }
+
+    fn limit_state_growth(&mut self, limited: bool) {
+        match self.runtime.limit_state_growth(limited) {
+            Ok(()) => {
+                if let Some(ref mut quota) = self.state_quota {
+                    quota.limited = limited;
+                }
+            }
+            Err(e) => log::error!(
+                "{} failed to limit the growth of ctx.state: {}",
+                self.log_prefix,
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lua_msg, LuaActorBuilder};
+    use futures::Future;
+
+    // `op` "add" or "set" stores 50 bytes under `key`, "del" removes it, "sizes" returns the
+    // sizes passed to the `state_quota_exceeded` hook
+    const HANDLE: &str = r#"
+        if ctx.msg.op == "sizes" then
+            return ctx.shared.sizes or {}
+        elseif ctx.msg.op == "del" then
+            ctx.state[ctx.msg.key] = nil
+        else
+            ctx.state[ctx.msg.key] = string.rep("x", 50)
+        end
+        return ctx.state_size()
+    "#;
+
+    fn op(op: &str, key: &str) -> LuaMessage {
+        lua_msg!({ op: op, key: key })
+    }
+
+    #[test]
+    fn state_size_estimate() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "payload" then
+                    -- 100 strings of 100 bytes, under integer keys
+                    for i = 1, 100 do
+                        ctx.state[i] = string.rep("x", 100)
+                    end
+                elseif ctx.msg == "shared" then
+                    local t = { n = true }
+                    ctx.state = { a = t, b = t }
+                end
+                return ctx.state_size()
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::Nil)
+            .join(addr.send(LuaMessage::from("payload")))
+            .join(addr.send(GetStateSize))
+            .and_then(move |((empty, payload), stats)| {
+                assert_eq!(empty, LuaMessage::from(40));
+                assert_eq!(payload, LuaMessage::from(40 + 100 * (16 + 8 + 100)));
+                assert_eq!(
+                    stats,
+                    lua_msg!({ bytes: 40 + 100 * (16 + 8 + 100), exceeded: 0, limited: false })
+                );
+                addr.send(LuaMessage::from("shared"))
+            })
+            .map(|shared| {
+                // the table under `b` is counted with `a`
+                assert_eq!(
+                    shared,
+                    LuaMessage::from(40 + 2 * (16 + 1) + 40 + 16 + 1 + 1)
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn state_quota_hook_threshold() {
+        let system = System::new("test");
+
+        // each key adds 16 + 1 + 50 bytes to the 40 of the empty state
+        let addr = LuaActorBuilder::new()
+            .with_state_quota(200, StateQuotaPolicy::Warn)
+            .on_state_quota_exceeded_with_lua(
+                r#"
+                ctx.shared.sizes = ctx.shared.sizes or {}
+                table.insert(ctx.shared.sizes, ctx.msg.size)
+                "#,
+            )
+            .on_handle_with_lua(HANDLE)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(op("add", "a"))
+            .join(addr.send(op("add", "b")))
+            .join(addr.send(op("sizes", "")))
+            .and_then(move |((_, b), sizes)| {
+                assert_eq!(b, LuaMessage::from(40 + 2 * 67));
+                assert_eq!(sizes, lua_msg!({}));
+                addr.send(op("add", "c"))
+                    .join(addr.send(op("sizes", "")))
+                    .join(addr.send(GetStateSize))
+            })
+            .map(|((c, sizes), stats)| {
+                assert_eq!(c, LuaMessage::from(40 + 3 * 67));
+                assert_eq!(sizes, lua_msg!([241]));
+                // after `c` and `sizes`, without pruning
+                assert_eq!(stats.get("exceeded"), Some(&LuaMessage::from(2)));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn state_quota_reject_writes() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_state_quota(200, StateQuotaPolicy::RejectWrites)
+            .on_handle_with_lua(HANDLE)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(op("add", "a"))
+            .join(addr.send(op("add", "b")))
+            .join(addr.send(op("add", "c")))
+            .join(addr.send(op("add", "d")))
+            .join(addr.send(GetStateSize))
+            .and_then(move |((((_, _), c), d), stats)| {
+                assert_eq!(c, LuaMessage::from(241));
+                match d {
+                    LuaMessage::Error { ref message, .. } => {
+                        assert!(message.contains("new keys are rejected"), "{}", message)
+                    }
+                    d => panic!("unexpected reply {:?}", d),
+                }
+                assert_eq!(stats.get("limited"), Some(&LuaMessage::from(true)));
+                // existing keys can be updated and deleted
+                addr.send(op("set", "a"))
+                    .join(addr.send(op("del", "c")))
+                    .join(addr.send(op("add", "d")))
+            })
+            .map(|((a, c), d)| {
+                assert_eq!(a, LuaMessage::from(241));
+                assert_eq!(c, LuaMessage::from(174));
+                // back under the quota
+                assert_eq!(d, LuaMessage::from(241));
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn state_quota_stop() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .with_state_quota(200, StateQuotaPolicy::Stop)
+            // keys starting with `cache` can be dropped
+            .on_state_quota_exceeded_with_lua(
+                r#"
+                for k in pairs(ctx.state) do
+                    if k:sub(1, 5) == "cache" then
+                        ctx.state[k] = nil
+                    end
+                end
+                "#,
+            )
+            .on_handle_with_lua(HANDLE)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(op("add", "a"))
+            .join(addr.send(op("add", "cache1")))
+            .join(addr.send(op("add", "cache2")))
+            .join(addr.send(GetStateSize))
+            .and_then(move |(_, stats)| {
+                // pruned by the hook
+                assert_eq!(stats.get("bytes"), Some(&LuaMessage::from(107)));
+                addr.send(op("add", "b"))
+                    .join(addr.send(op("add", "c")))
+                    .and_then(move |_| addr.send(op("add", "d")))
+                    .then(|res| {
+                        assert!(res.is_err(), "{:?}", res);
+                        System::current().stop();
+                        Ok(())
+                    })
+            });
+        Arbiter::spawn(l.map_err(|e: MailboxError| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,253 @@
+use rlua::{Context, Error as LuaError, String as LuaString, Table};
+
+use crate::runtime::{ApiEntry, ApiValue, CtxFn, Requires};
+
+const fn value(name: &'static str, ty: &'static str) -> ApiValue {
+    ApiValue {
+        name,
+        ty,
+        optional: false,
+    }
+}
+
+// The functions of `ctx.str`, see `CTX_STR`.
+pub(crate) const STR_API: &[ApiEntry] = &[
+    ApiEntry {
+        name: "lower",
+        signature: "local lower = ctx.str.lower(s)",
+        description: "`s` in lower case, only ASCII letters change if `s` isn't UTF-8",
+        availability: "",
+        params: &[value("s", "string")],
+        returns: &[value("lower", "string")],
+        fields: &[],
+    },
+    ApiEntry {
+        name: "upper",
+        signature: "local upper = ctx.str.upper(s)",
+        description: "`s` in upper case, only ASCII letters change if `s` isn't UTF-8",
+        availability: "",
+        params: &[value("s", "string")],
+        returns: &[value("upper", "string")],
+        fields: &[],
+    },
+    ApiEntry {
+        name: "casefold_eq",
+        signature: "local equal = ctx.str.casefold_eq(a, b)",
+        description: "whether `a` and `b` are equal ignoring case",
+        availability: "",
+        params: &[value("a", "string"), value("b", "string")],
+        returns: &[value("equal", "boolean")],
+        fields: &[],
+    },
+    ApiEntry {
+        name: "sort",
+        signature: "local sorted = ctx.str.sort(strings [, {case_insensitive = true}])",
+        description: "a sorted copy of an array of strings, by code point, keeping the order of equal strings",
+        availability: "",
+        params: &[
+            value("strings", "string[]"),
+            ApiValue {
+                name: "options",
+                ty: "{case_insensitive: boolean?}",
+                optional: true,
+            },
+        ],
+        returns: &[value("sorted", "string[]")],
+        fields: &[],
+    },
+    ApiEntry {
+        name: "len",
+        signature: "local n = ctx.str.len(s)",
+        description: "number of characters of `s`, raises an error if `s` isn't UTF-8",
+        availability: "",
+        params: &[value("s", "string")],
+        returns: &[value("n", "integer")],
+        fields: &[],
+    },
+];
+
+pub(crate) const CTX_STR: CtxFn = CtxFn {
+    entry: ApiEntry {
+        name: "str",
+        signature: "ctx.str.lower/upper/len(s), ctx.str.casefold_eq(a, b), ctx.str.sort(strings [, options])",
+        description: "Unicode-aware case conversion, comparison and sorting of strings",
+        availability: "",
+        params: &[],
+        returns: &[],
+        fields: STR_API,
+    },
+    requires: Requires::Always,
+};
+
+// `ctx.str`: Unicode-aware versions of the byte-based string functions. Lua strings which
+// aren't UTF-8, e.g. binary data, are handled as bytes where it makes sense.
+pub(crate) fn str_table(ctx: Context) -> Result<Table, LuaError> {
+    let str = ctx.create_table()?;
+    str.set(
+        "lower",
+        ctx.create_function(|ctx, s: LuaString| {
+            convert(ctx, &s, str::to_lowercase, <[u8]>::to_ascii_lowercase)
+        })?,
+    )?;
+    str.set(
+        "upper",
+        ctx.create_function(|ctx, s: LuaString| {
+            convert(ctx, &s, str::to_uppercase, <[u8]>::to_ascii_uppercase)
+        })?,
+    )?;
+    str.set(
+        "casefold_eq",
+        ctx.create_function(|_, (a, b): (LuaString, LuaString)| Ok(fold(&a) == fold(&b)))?,
+    )?;
+    str.set("sort", ctx.create_function(sort)?)?;
+    str.set(
+        "len",
+        ctx.create_function(|_, s: LuaString| match s.to_str() {
+            Ok(s) => Ok(s.chars().count() as i64),
+            Err(_) => Err(LuaError::RuntimeError(
+                "ctx.str.len expects a UTF-8 string".to_string(),
+            )),
+        })?,
+    )?;
+    Ok(str)
+}
+
+fn convert<'lua>(
+    ctx: Context<'lua>,
+    s: &LuaString<'lua>,
+    unicode: fn(&str) -> String,
+    bytes: fn(&[u8]) -> Vec<u8>,
+) -> Result<LuaString<'lua>, LuaError> {
+    match s.to_str() {
+        Ok(s) => ctx.create_string(&unicode(s)),
+        Err(_) => ctx.create_string(&bytes(s.as_bytes())),
+    }
+}
+
+// The key comparing equal for strings which only differ by case. Upper casing first folds
+// e.g. "ß" and "ss" together, which lower casing alone doesn't.
+fn fold(s: &LuaString) -> Vec<u8> {
+    match s.to_str() {
+        Ok(s) => s.to_uppercase().to_lowercase().into_bytes(),
+        Err(_) => s.as_bytes().to_ascii_lowercase(),
+    }
+}
+
+fn sort<'lua>(
+    ctx: Context<'lua>,
+    (strings, options): (Table<'lua>, Option<Table<'lua>>),
+) -> Result<Table<'lua>, LuaError> {
+    let case_insensitive = match options {
+        Some(options) => options
+            .get::<_, Option<bool>>("case_insensitive")?
+            .unwrap_or(false),
+        None => false,
+    };
+    let mut strings = strings
+        .sequence_values::<LuaString>()
+        .map(|s| {
+            let s = s.map_err(|_| {
+                LuaError::RuntimeError("ctx.str.sort expects an array of strings".to_string())
+            })?;
+            let key = if case_insensitive {
+                fold(&s)
+            } else {
+                s.as_bytes().to_vec()
+            };
+            Ok((key, s))
+        })
+        .collect::<Result<Vec<_>, LuaError>>()?;
+    // stable, and the byte order of UTF-8 strings is the order of their code points
+    strings.sort_by(|a, b| a.0.cmp(&b.0));
+    ctx.create_sequence_from(strings.into_iter().map(|(_, s)| s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlua::Lua;
+
+    fn eval<R: for<'lua> rlua::FromLuaMulti<'lua>>(script: &str) -> Result<R, LuaError> {
+        Lua::new().context(|ctx| {
+            ctx.globals().set("str", str_table(ctx)?)?;
+            ctx.load(script).eval()
+        })
+    }
+
+    #[test]
+    fn case_conversion() {
+        let (lower, upper): (String, String) =
+            eval(r#"return str.lower("ÉCOLE Ça"), str.upper("straße ÿ")"#).unwrap();
+        assert_eq!(lower, "école ça");
+        assert_eq!(upper, "STRASSE Ÿ");
+
+        let equal: (bool, bool, bool, bool) = eval(
+            r#"return str.casefold_eq("Élan", "éLAN"), str.casefold_eq("straße", "STRASSE"),
+                str.casefold_eq("a", "b"), str.casefold_eq("é", "e")"#,
+        )
+        .unwrap();
+        assert_eq!(equal, (true, true, false, false));
+    }
+
+    #[test]
+    fn character_count() {
+        let n: (i64, i64, i64) = eval(r#"return str.len("héllo"), #"héllo", str.len("")"#).unwrap();
+        assert_eq!(n, (5, 6, 0));
+    }
+
+    #[test]
+    fn sorting() {
+        let sorted: Vec<String> =
+            eval(r#"return str.sort({"b", "é", "B", "a", "e", "A"})"#).unwrap();
+        assert_eq!(sorted, vec!["A", "B", "a", "b", "e", "é"]);
+
+        // equal strings keep their order
+        let sorted: Vec<String> =
+            eval(r#"return str.sort({"b", "Á", "B", "á", "a", "A"}, {case_insensitive = true})"#)
+                .unwrap();
+        assert_eq!(sorted, vec!["a", "A", "b", "B", "Á", "á"]);
+
+        // a copy is returned
+        let unchanged: String = eval(r#"local t = {"b", "a"}; str.sort(t); return t[1]"#).unwrap();
+        assert_eq!(unchanged, "b");
+
+        let err = eval::<()>(r#"str.sort({"a", {}})"#).unwrap_err();
+        assert!(
+            format!("{:?}", err).contains("expects an array of strings"),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn binary_strings() {
+        let (lower, upper, equal): (LuaBytes, LuaBytes, bool) = eval(
+            r#"local s = "AB\xff\xfeé"
+            return str.lower(s), str.upper(s), str.casefold_eq(s, "ab\xff\xfeé")"#,
+        )
+        .unwrap();
+        assert_eq!(lower.0, b"ab\xff\xfe\xc3\xa9");
+        assert_eq!(upper.0, b"AB\xff\xfe\xc3\xa9");
+        assert!(equal);
+
+        let sorted: Vec<LuaBytes> = eval(r#"return str.sort({"\xff", "b", "\xfe"})"#).unwrap();
+        let sorted: Vec<&[u8]> = sorted.iter().map(|s| &s.0[..]).collect();
+        assert_eq!(sorted, vec![&b"b"[..], b"\xfe", b"\xff"]);
+
+        let err = eval::<i64>(r#"return str.len("\xff")"#).unwrap_err();
+        assert!(
+            format!("{:?}", err).contains("expects a UTF-8 string"),
+            "{:?}",
+            err
+        );
+    }
+
+    struct LuaBytes(Vec<u8>);
+
+    impl<'lua> rlua::FromLua<'lua> for LuaBytes {
+        fn from_lua(value: rlua::Value<'lua>, ctx: Context<'lua>) -> Result<Self, LuaError> {
+            let s: LuaString = rlua::FromLua::from_lua(value, ctx)?;
+            Ok(LuaBytes(s.as_bytes().to_vec()))
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use ::actix::prelude::*;
+use rlua::Error as LuaError;
+
+use crate::actor::LuaActor;
+use crate::cron::CronExpr;
+use crate::ids::IdSource;
+use crate::interval::TickPolicy;
+use crate::message::LuaMessage;
+use crate::recipients::{Aliases, Recipients};
+use crate::runtime::ActorApi;
+use crate::warnings::Warnings;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// The ctx functions which need the event loop of an arbiter, raising an error in a
+// `SyncLuaActor`.
+const SYNC_DISABLED: &[&str] = &[
+    "notify_later",
+    "run_interval",
+    "schedule",
+    "send",
+    "send_ok",
+    "send_correlated",
+    "wait_for",
+    "replace_actor",
+    "watch",
+    "do_send_confirmed",
+    "set_outbox",
+    "restart",
+];
+
+/// A `LuaActor` for a `SyncArbiter`, to run CPU-heavy scripts on a pool of threads without
+/// blocking an arbiter.
+///
+/// Built with [`LuaActorBuilder::build_sync`], in the thread of the `SyncArbiter`:
+///
+/// ```
+/// # extern crate actix;
+/// # extern crate actix_lua;
+/// # extern crate futures;
+/// use actix::prelude::*;
+/// use actix_lua::{LuaActorBuilder, LuaMessage};
+/// use futures::Future;
+///
+/// let system = System::new("sync");
+/// let addr = SyncArbiter::start(2, || {
+///     LuaActorBuilder::new()
+///         .on_handle_with_lua(r#"return ctx.msg * 2"#)
+///         .build_sync()
+///         .unwrap()
+/// });
+/// let double = addr.send(LuaMessage::from(21)).map(|res| {
+///     assert_eq!(res, LuaMessage::from(42));
+///     System::current().stop();
+/// });
+/// Arbiter::spawn(double.map_err(|e| panic!("{}", e)));
+/// system.run();
+/// ```
+///
+/// The scripts run like in a `LuaActor`, except that there is no event loop to wait on:
+///
+/// * `ctx.notify(msg)` messages are handled right after the message which sent them, before
+///   its reply.
+/// * `ctx.do_send`, `ctx.terminate`, `ctx.alias`, `ctx.uuid` and the functions which only use
+///   the VM work as usual.
+/// * `ctx.send`, `ctx.send_ok`, `ctx.send_correlated`, `ctx.wait_for`, `ctx.notify_later`,
+///   `ctx.run_interval`, `ctx.schedule`, `ctx.watch`, `ctx.replace_actor`,
+///   `ctx.do_send_confirmed`, `ctx.set_outbox` and `ctx.restart` raise a Lua error.
+///
+/// The options of the builder which are applied by the mailbox of a `LuaActor`, e.g.
+/// `with_inbound_rate_limit` or `with_idempotency`, have no effect.
+///
+/// [`LuaActorBuilder::build_sync`]: struct.LuaActorBuilder.html#method.build_sync
+pub struct SyncLuaActor {
+    actor: LuaActor,
+    notifications: VecDeque<LuaMessage>,
+}
+
+impl SyncLuaActor {
+    pub(crate) fn new(actor: LuaActor) -> Result<SyncLuaActor, LuaError> {
+        actor
+            .runtime
+            .disable_ctx_functions(SYNC_DISABLED, "is not available in a SyncLuaActor")?;
+        Ok(SyncLuaActor {
+            actor,
+            notifications: VecDeque::new(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        self.actor.name()
+    }
+
+    // Run `hook`, then the handle script for the notifications it sent.
+    fn invoke(
+        &mut self,
+        ctx: &mut SyncContext<Self>,
+        hook: &str,
+        msg: LuaMessage,
+        reply_expected: bool,
+    ) -> Result<LuaMessage, LuaError> {
+        let res = self.run(ctx, hook, msg, reply_expected);
+        while let Some(msg) = self.notifications.pop_front() {
+            if let Err(e) = self.run(ctx, "handle", msg, false) {
+                log::error!("{} `handle` hook failed: {}", self.actor.log_prefix, e);
+            }
+        }
+        res
+    }
+
+    fn run(
+        &mut self,
+        ctx: &mut SyncContext<Self>,
+        hook: &str,
+        msg: LuaMessage,
+        reply_expected: bool,
+    ) -> Result<LuaMessage, LuaError> {
+        let actor = &mut self.actor;
+        let mut api = SyncApi {
+            ctx,
+            actor_name: &actor.name,
+            log_prefix: &actor.log_prefix,
+            recipients: &actor.recipients,
+            aliases: &mut actor.aliases,
+            warnings: &mut actor.warnings,
+            ids: &mut actor.ids,
+            notifications: &mut self.notifications,
+        };
+        if hook != "stopped" {
+            actor.runtime.arm_handle_timeout();
+        }
+        let res = actor
+            .runtime
+            .call_hook(
+                &mut api,
+                "__run",
+                vec![
+                    LuaMessage::from(hook),
+                    msg,
+                    LuaMessage::from(reply_expected),
+                ],
+                reply_expected,
+            )
+            .map(|outcome| outcome.into_message());
+        actor.runtime.disarm_handle_timeout();
+        res
+    }
+}
+
+impl Actor for SyncLuaActor {
+    type Context = SyncContext<Self>;
+
+    fn started(&mut self, ctx: &mut SyncContext<Self>) {
+        if self.actor.runtime.has_script("migrations") {
+            if let Err(e) = self.invoke(ctx, "__migrate", LuaMessage::Nil, false) {
+                log::error!(
+                    "{} state migration failed, stopping: {}",
+                    self.actor.log_prefix,
+                    e
+                );
+                ctx.stop();
+                return;
+            }
+        }
+        if let Err(e) = self.invoke(ctx, "started", LuaMessage::Nil, false) {
+            log::error!("{} started failed, stopping: {}", self.actor.log_prefix, e);
+            ctx.stop();
+        }
+    }
+
+    fn stopped(&mut self, ctx: &mut SyncContext<Self>) {
+        if let Err(e) = self.invoke(ctx, "stopped", LuaMessage::Nil, false) {
+            log::error!("{} stopped failed: {}", self.actor.log_prefix, e);
+        }
+        self.actor.opaque_registry().clear();
+    }
+}
+
+impl Handler<LuaMessage> for SyncLuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, msg: LuaMessage, ctx: &mut SyncContext<Self>) -> LuaMessage {
+        // a message sent with `do_send` has no reply to wait for, but it's handled in the same
+        // way, the caller of a `SyncArbiter` can't tell them apart
+        match self.invoke(ctx, "handle", msg, true) {
+            Ok(res) => res,
+            Err(e) => {
+                log::error!("{} `handle` hook failed: {}", self.actor.log_prefix, e);
+                LuaMessage::from_script_error(&e)
+            }
+        }
+    }
+}
+
+// `ActorApi` of a `SyncLuaActor`, the functions of `SYNC_DISABLED` aren't called.
+struct SyncApi<'a> {
+    ctx: &'a mut SyncContext<SyncLuaActor>,
+    actor_name: &'a str,
+    log_prefix: &'a str,
+    recipients: &'a Recipients,
+    aliases: &'a mut Aliases,
+    warnings: &'a mut Warnings,
+    ids: &'a mut IdSource,
+    notifications: &'a mut VecDeque<LuaMessage>,
+}
+
+impl<'a> ActorApi for SyncApi<'a> {
+    fn notify(&mut self, msg: LuaMessage) {
+        self.notifications.push_back(msg);
+    }
+
+    fn notify_later(&mut self, _: LuaMessage, _: Duration) {}
+
+    fn run_interval(&mut self, _: Duration, _: HashMap<String, LuaMessage>, _: TickPolicy) -> i64 {
+        0
+    }
+
+    fn cancel_interval(&mut self, _: i64) {}
+
+    fn schedule(&mut self, name: Option<String>, _: CronExpr, _: LuaMessage) -> String {
+        name.unwrap_or_default()
+    }
+
+    fn unschedule(&mut self, _: &str) -> bool {
+        false
+    }
+
+    fn watch(&mut self, _: &str) -> Result<(), LuaError> {
+        Ok(())
+    }
+
+    fn unwatch(&mut self, _: &str) {}
+
+    fn do_send(&mut self, recipient_name: &str, msg: LuaMessage) {
+        match self.recipients.get(self.aliases.resolve(recipient_name)) {
+            Some(rec) => {
+                if let Err(e) = rec.do_send(msg) {
+                    log::warn!(
+                        "{} can't send a message to {}: {}",
+                        self.log_prefix,
+                        recipient_name,
+                        e
+                    );
+                }
+            }
+            None => log::warn!(
+                "{} can't send a message to unknown recipient {}",
+                self.log_prefix,
+                recipient_name
+            ),
+        }
+    }
+
+    fn do_send_confirmed(&mut self, _: &str, _: LuaMessage) -> i64 {
+        0
+    }
+
+    fn set_outbox(&mut self, _: &str, _: usize, _: Duration) {}
+
+    fn outbox_depth(&mut self, _: &str) -> usize {
+        0
+    }
+
+    fn send(&mut self, _: String, _: LuaMessage, _: i64) {}
+
+    fn correlate(&mut self, _: Option<Duration>) -> i64 {
+        self.ids.correlation_id()
+    }
+
+    fn uncorrelate(&mut self, _: i64) {}
+
+    fn replace_actor(
+        &mut self,
+        _: &str,
+        _: &str,
+        _: Duration,
+        _: usize,
+        _: i64,
+    ) -> Result<(), LuaError> {
+        Ok(())
+    }
+
+    fn terminate(&mut self) {
+        self.ctx.terminate();
+    }
+
+    fn restart(&mut self, _: bool) -> Result<(), LuaError> {
+        Ok(())
+    }
+
+    fn breaker_state(&mut self, _: &str) -> Option<&'static str> {
+        None
+    }
+
+    fn alias(&mut self, alias: String, target: String) -> Result<(), LuaError> {
+        self.aliases.set(alias, target).map(|_| ())
+    }
+
+    fn unalias(&mut self, alias: &str) {
+        self.aliases.remove(alias);
+    }
+
+    fn resolve(&mut self, alias: &str) -> Option<String> {
+        self.aliases.get(alias).map(str::to_string)
+    }
+
+    fn warn(&mut self, key: &str, message: &str) {
+        self.warnings
+            .warn_once(self.actor_name, key, message, Instant::now());
+    }
+
+    fn uuid(&mut self) -> String {
+        self.ids.uuid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LuaActorBuilder;
+    use crate::lua_msg;
+    use futures::{future, Future};
+    use std::sync::Mutex;
+
+    #[test]
+    fn sync_lua_actor() {
+        let system = System::new("test");
+
+        let builder = LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.notify("notified")"#)
+            .on_handle_with_lua(
+                r#"
+                    if ctx.msg == "notified" then
+                        ctx.state.notified = true
+                        return
+                    elseif ctx.msg == "notified?" then
+                        return ctx.state.notified
+                    elseif ctx.msg == "send" then
+                        local ok, err = pcall(ctx.send, "other", 1)
+                        return tostring(err)
+                    end
+                    -- busy, so the messages spread over the threads
+                    local sum = 0
+                    for i = 1, ctx.msg * 1000 do
+                        sum = sum + i % 7
+                    end
+                    return { n = ctx.msg, double = ctx.msg * 2 }
+                    "#,
+            );
+        // the recipients of a builder can't be shared between threads
+        let builder = Mutex::new(builder);
+        let addr = SyncArbiter::start(4, move || {
+            let builder = builder.lock().unwrap().clone();
+            builder.build_sync().unwrap()
+        });
+
+        let sends = (1..=20).map(|n| addr.send(LuaMessage::from(n)));
+        let l = future::join_all(sends.collect::<Vec<_>>())
+            .and_then(move |replies| {
+                for (n, reply) in (1..=20).zip(replies) {
+                    assert_eq!(reply, lua_msg!({ n: n, double: n * 2 }));
+                }
+                addr.send(LuaMessage::from("notified?"))
+                    .join(addr.send(LuaMessage::from("send")))
+            })
+            .map(|(notified, sent)| {
+                // the notification of `started` was handled before the first message
+                assert_eq!(notified, LuaMessage::from(true));
+                match sent {
+                    LuaMessage::String(s) => assert!(
+                        s.contains("ctx.send is not available in a SyncLuaActor"),
+                        "{}",
+                        s
+                    ),
+                    other => panic!("expected an error, got {}", other),
+                }
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,610 @@
+//! Run unit tests of Lua scripts without starting actors.
+//!
+//! [`run_script_test`] builds a fresh actor for every `test_*` function of a test script and
+//! runs it synchronously. The test functions use the `ctx` API of the actor plus:
+//!
+//! * `ctx.assert_eq(actual, expected[, msg])`: raise an error if the values differ. Tables are
+//!   compared by value.
+//! * `ctx.send_self(msg)`: run the `handle` script with `msg` and return its result.
+//!   Notifications sent by the script are handled before `send_self` returns.
+//! * `ctx.advance_time(seconds)`: advance the virtual clock of the actor, handling the
+//!   messages of `ctx.notify_later` and `ctx.schedule` which are due. The clock starts at
+//!   2000-01-01T00:00:00Z, a Saturday, for the cron expressions of `ctx.schedule`.
+//!
+//! `ctx.send` gets `nil` replies and messages sent to other recipients are dropped, without a
+//! call to `on_delivery_receipt` for `ctx.do_send_confirmed`. The intervals of
+//! `ctx.run_interval` don't tick.
+//!
+//! [`assert_lua_eq!`] compares `LuaMessage`s in Rust tests, showing where they differ, and
+//! [`lua_msg!`] builds the expected messages. With the `json` feature, [`fixture_from_json`]
+//! loads them from JSON.
+//!
+//! [`ScriptVm`] runs the `handle` script and conversions of an actor without an actix `System`,
+//! e.g. in benchmarks.
+//!
+//! [`run_script_test`]: fn.run_script_test.html
+//! [`assert_lua_eq!`]: ../macro.assert_lua_eq.html
+//! [`lua_msg!`]: ../macro.lua_msg.html
+//! [`fixture_from_json`]: fn.fixture_from_json.html
+//! [`ScriptVm`]: struct.ScriptVm.html
+use rlua::{Error as LuaError, Function, Value};
+
+use crate::actor::LuaActor;
+use crate::builder::LuaActorBuilder;
+#[cfg(feature = "json")]
+use crate::codec::JsonCodec;
+use crate::correlation::next_correlation_id;
+use crate::cron::CronExpr;
+use crate::ids::IdSource;
+use crate::interval::TickPolicy;
+use crate::message::{sort_keys, LuaMessage};
+use crate::recipients::Aliases;
+use crate::runtime::{ActorApi, LuaRuntime};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A failed test function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestFailure {
+    pub name: String,
+    pub message: String,
+}
+
+/// The failed test functions of a test script, in the order they ran.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestFailures(pub Vec<TestFailure>);
+
+impl fmt::Display for TestFailures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} Lua test(s) failed", self.0.len())?;
+        for failure in &self.0 {
+            write!(f, "\n{}: {}", failure.name, failure.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run the `test_*` functions defined by `test_source`, in alphabetical order, on actors built
+/// by `builder`.
+///
+/// Every test gets a new actor whose `started` hook already ran.
+///
+/// ```
+/// use actix_lua::testing::run_script_test;
+/// use actix_lua::LuaActorBuilder;
+///
+/// let builder = LuaActorBuilder::new().on_handle_with_lua(r#"return ctx.msg + 1"#);
+/// run_script_test(
+///     &builder,
+///     r#"
+///     function test_increments()
+///         ctx.assert_eq(ctx.send_self(1), 2)
+///     end
+///     "#,
+/// )
+/// .unwrap();
+/// ```
+pub fn run_script_test(builder: &LuaActorBuilder, test_source: &str) -> Result<(), TestFailures> {
+    let failure = |name: &str, e: LuaError| {
+        TestFailures(vec![TestFailure {
+            name: name.to_string(),
+            message: e.to_string(),
+        }])
+    };
+    let names = builder
+        .clone()
+        .build()
+        .map_err(LuaError::from)
+        .and_then(|actor| load_tests(&actor.runtime, test_source))
+        .map_err(|e| failure("<load>", e))?;
+
+    let mut failures = Vec::new();
+    for name in names {
+        if let Err(e) = run_test(builder, test_source, &name) {
+            failures.push(TestFailure {
+                name,
+                message: e.to_string(),
+            });
+        }
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(TestFailures(failures))
+    }
+}
+
+fn run_test(builder: &LuaActorBuilder, test_source: &str, name: &str) -> Result<(), LuaError> {
+    let mut actor = builder.clone().build()?;
+    let driver = Arc::new(Mutex::new(Driver::default()));
+    install_harness(&actor.runtime, &driver)?;
+    load_tests(&actor.runtime, test_source)?;
+
+    let mut api = TestApi {
+        driver,
+        aliases: Aliases::default(),
+        ids: actor.ids.clone(),
+    };
+    actor
+        .runtime
+        .call_hook(&mut api, "__test_start", vec![], false)?;
+    actor
+        .runtime
+        .call_hook(&mut api, "__test_call", vec![LuaMessage::from(name)], false)?;
+    Ok(())
+}
+
+/// The VM of an actor built by a `LuaActorBuilder`, driven without an actix `System`, e.g. to
+/// measure conversions and scripts in benchmarks.
+///
+/// The `ctx` API behaves like in [`run_script_test`](fn.run_script_test.html), except that
+/// notifications are dropped.
+///
+/// ```
+/// use actix_lua::testing::ScriptVm;
+/// use actix_lua::{LuaActorBuilder, LuaMessage};
+///
+/// let builder = LuaActorBuilder::new().on_handle_with_lua(r#"return ctx.msg + 1"#);
+/// let mut vm = ScriptVm::new(&builder).unwrap();
+/// assert_eq!(vm.handle(LuaMessage::from(1)).unwrap(), LuaMessage::from(2));
+/// ```
+pub struct ScriptVm {
+    actor: LuaActor,
+    api: TestApi,
+}
+
+impl ScriptVm {
+    /// Build the actor of `builder`, without running its `started` hook.
+    pub fn new(builder: &LuaActorBuilder) -> Result<ScriptVm, LuaError> {
+        let actor = builder.clone().build()?;
+        let ids = actor.ids.clone();
+        Ok(ScriptVm {
+            actor,
+            api: TestApi {
+                driver: Arc::new(Mutex::new(Driver::default())),
+                aliases: Aliases::default(),
+                ids,
+            },
+        })
+    }
+
+    /// Run the `handle` script with `msg` and return its result.
+    pub fn handle(&mut self, msg: LuaMessage) -> Result<LuaMessage, LuaError> {
+        let res = self.actor.runtime.call_hook(
+            &mut self.api,
+            "__run",
+            vec![LuaMessage::from("handle"), msg, LuaMessage::from(true)],
+            true,
+        )?;
+        self.api.driver.lock().unwrap().notifications.clear();
+        Ok(res.into_message())
+    }
+
+    /// Convert `msg` to a Lua value and back, with the conversion limits of the actor.
+    pub fn round_trip(&self, msg: &LuaMessage) -> Result<LuaMessage, LuaError> {
+        let limits = &self.actor.conversion_limits;
+        self.actor.runtime.vm.context(|ctx| {
+            let value = msg.to_lua_value(ctx, Some(limits))?;
+            LuaMessage::from_lua_value(value, ctx, Some(limits))
+        })
+    }
+}
+
+// Load the test script in its own environment, returning the names of the test functions.
+fn load_tests(runtime: &LuaRuntime, test_source: &str) -> Result<Vec<String>, LuaError> {
+    runtime.vm.context(|ctx| {
+        let globals = ctx.globals();
+        let env = ctx.create_table()?;
+        let meta = ctx.create_table()?;
+        meta.set("__index", globals.clone())?;
+        env.set_metatable(Some(meta));
+        ctx.load(test_source)
+            .set_name("test")?
+            .set_environment(env.clone())?
+            .exec()?;
+
+        let mut names = Vec::new();
+        for pair in env.clone().pairs::<Value, Value>() {
+            if let (Value::String(name), Value::Function(_)) = pair? {
+                let name = name.to_str()?;
+                if name.starts_with("test_") {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+
+        let declare: Function = globals.get("declare_global")?;
+        declare.call::<_, ()>(("__test_env", env))?;
+        Ok(names)
+    })
+}
+
+fn install_harness(runtime: &LuaRuntime, driver: &Arc<Mutex<Driver>>) -> Result<(), LuaError> {
+    runtime.vm.context(|ctx| {
+        let globals = ctx.globals();
+        let d = driver.clone();
+        let next = ctx.create_function(move |_, ()| {
+            Ok(match d.lock().unwrap().notifications.pop_front() {
+                Some(msg) => (true, msg),
+                None => (false, LuaMessage::Nil),
+            })
+        })?;
+        globals.raw_set("__test_next_notification", next)?;
+        let d = driver.clone();
+        let advance = ctx.create_function(move |_, seconds: f64| {
+            d.lock()
+                .unwrap()
+                .advance(Duration::from_millis((seconds * 1000.0) as u64));
+            Ok(())
+        })?;
+        globals.raw_set("__test_advance", advance)?;
+
+        ctx.load(include_str!("lua/testing.lua"))
+            .set_name("Testing")?
+            .exec()
+    })
+}
+
+// Unix time of the start of the virtual clock, 2000-01-01T00:00:00Z.
+const TEST_EPOCH: i64 = 946_684_800;
+
+// Virtual clock and mailbox of the actor under test.
+#[derive(Default)]
+struct Driver {
+    now: Duration,
+    // messages of `ctx.notify_later` and when they are due
+    timers: Vec<(Duration, LuaMessage)>,
+    // schedules of `ctx.schedule` by name, with their next fire time in Unix time
+    schedules: BTreeMap<String, (CronExpr, LuaMessage, i64)>,
+    next_schedule: u64,
+    notifications: VecDeque<LuaMessage>,
+}
+
+impl Driver {
+    // Unix time of the virtual clock, in seconds.
+    fn unix_now(&self) -> i64 {
+        TEST_EPOCH + self.now.as_secs() as i64
+    }
+
+    fn advance(&mut self, by: Duration) {
+        self.now += by;
+        let now = self.now;
+        let (mut due, later): (Vec<_>, Vec<_>) =
+            self.timers.drain(..).partition(|(at, _)| *at <= now);
+        self.timers = later;
+        // the actor is idle while the clock advances, no fire time is skipped
+        let unix_now = self.unix_now();
+        for (cron, msg, next) in self.schedules.values_mut() {
+            while *next <= unix_now {
+                let at = Duration::from_secs((*next - TEST_EPOCH) as u64);
+                due.push((at, msg.clone()));
+                *next = cron.next_after(*next).unwrap();
+            }
+        }
+        // stable, timers due at the same time keep their order
+        due.sort_by_key(|(at, _)| *at);
+        self.notifications
+            .extend(due.into_iter().map(|(_, msg)| msg));
+    }
+}
+
+struct TestApi {
+    driver: Arc<Mutex<Driver>>,
+    aliases: Aliases,
+    ids: IdSource,
+}
+
+impl ActorApi for TestApi {
+    fn notify(&mut self, msg: LuaMessage) {
+        self.driver.lock().unwrap().notifications.push_back(msg);
+    }
+
+    fn notify_later(&mut self, msg: LuaMessage, after: Duration) {
+        let mut driver = self.driver.lock().unwrap();
+        let at = driver.now + after;
+        driver.timers.push((at, msg));
+    }
+
+    fn run_interval(&mut self, _: Duration, _: HashMap<String, LuaMessage>, _: TickPolicy) -> i64 {
+        0
+    }
+
+    fn cancel_interval(&mut self, _: i64) {}
+
+    fn schedule(&mut self, name: Option<String>, cron: CronExpr, msg: LuaMessage) -> String {
+        let mut driver = self.driver.lock().unwrap();
+        let name = name.unwrap_or_else(|| format!("schedule-{}", driver.next_schedule));
+        driver.next_schedule += 1;
+        // `parse` checked that the expression matches
+        let next = cron.next_after(driver.unix_now()).unwrap();
+        driver.schedules.insert(name.clone(), (cron, msg, next));
+        name
+    }
+
+    fn unschedule(&mut self, name: &str) -> bool {
+        self.driver.lock().unwrap().schedules.remove(name).is_some()
+    }
+
+    fn watch(&mut self, _: &str) -> Result<(), LuaError> {
+        Ok(())
+    }
+
+    fn unwatch(&mut self, _: &str) {}
+
+    fn do_send(&mut self, _: &str, _: LuaMessage) {}
+
+    // the message is dropped and `on_delivery_receipt` isn't called
+    fn do_send_confirmed(&mut self, _: &str, _: LuaMessage) -> i64 {
+        next_correlation_id()
+    }
+
+    fn set_outbox(&mut self, _: &str, _: usize, _: Duration) {}
+
+    fn outbox_depth(&mut self, _: &str) -> usize {
+        0
+    }
+
+    fn send(&mut self, _: String, _: LuaMessage, _: i64) {}
+
+    // replies are sent with `ctx.send_self`, the timeouts don't fire
+    fn correlate(&mut self, _: Option<Duration>) -> i64 {
+        self.ids.correlation_id()
+    }
+
+    fn uncorrelate(&mut self, _: i64) {}
+
+    fn replace_actor(
+        &mut self,
+        _: &str,
+        _: &str,
+        _: Duration,
+        _: usize,
+        _: i64,
+    ) -> Result<(), LuaError> {
+        Err(LuaError::RuntimeError(
+            "ctx.replace_actor is not available in script tests".to_string(),
+        ))
+    }
+
+    fn terminate(&mut self) {}
+
+    fn restart(&mut self, _: bool) -> Result<(), LuaError> {
+        Err(LuaError::RuntimeError(
+            "ctx.restart is not available in script tests".to_string(),
+        ))
+    }
+
+    fn breaker_state(&mut self, _: &str) -> Option<&'static str> {
+        None
+    }
+
+    fn alias(&mut self, alias: String, target: String) -> Result<(), LuaError> {
+        self.aliases.set(alias, target).map(|_| ())
+    }
+
+    fn unalias(&mut self, alias: &str) {
+        self.aliases.remove(alias);
+    }
+
+    fn resolve(&mut self, alias: &str) -> Option<String> {
+        self.aliases.get(alias).map(str::to_string)
+    }
+
+    fn warn(&mut self, _: &str, message: &str) {
+        log::warn!("Lua test: {}", message);
+    }
+
+    fn uuid(&mut self) -> String {
+        self.ids.uuid()
+    }
+}
+
+/// The first difference found by [`diff`](fn.diff.html).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    /// keys leading to the difference, empty if the messages themselves differ
+    pub path: Vec<String>,
+    /// `None` if the key is missing
+    pub actual: Option<LuaMessage>,
+    /// `None` if the key is unexpected
+    pub expected: Option<LuaMessage>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = |v: &Option<LuaMessage>| match v {
+            Some(v) => v.to_string(),
+            None => "<missing>".to_string(),
+        };
+        if self.path.is_empty() {
+            write!(f, "messages differ")?;
+        } else {
+            write!(f, "messages differ at {}", self.path.join("."))?;
+        }
+        write!(
+            f,
+            "\n  actual: {}\nexpected: {}",
+            value(&self.actual),
+            value(&self.expected)
+        )
+    }
+}
+
+/// Compare `actual` to `expected` like Lua would after converting them, returning the first
+/// difference.
+///
+/// `Integer` and `Number` are equal if they have the same value, like `1 == 1.0` in Lua.
+/// Keys of tables are compared in order, numeric keys first, and a key with a `Nil` value
+/// differs from a missing key, since it's converted to `ctx.null`.
+pub fn diff(actual: &LuaMessage, expected: &LuaMessage) -> Option<Difference> {
+    let mut path = Vec::new();
+    diff_at(actual, expected, &mut path).map(|(actual, expected)| Difference {
+        path,
+        actual,
+        expected,
+    })
+}
+
+type Values = (Option<LuaMessage>, Option<LuaMessage>);
+
+// Leaves `path` at the difference.
+fn diff_at(actual: &LuaMessage, expected: &LuaMessage, path: &mut Vec<String>) -> Option<Values> {
+    match (actual, expected) {
+        (LuaMessage::Table(a), LuaMessage::Table(e)) => {
+            let mut keys: Vec<_> = a
+                .keys()
+                .chain(e.keys().filter(|k| !a.contains_key(*k)))
+                .collect();
+            sort_keys(&mut keys);
+            for key in keys {
+                path.push(key.clone());
+                match (a.get(key), e.get(key)) {
+                    (Some(a), Some(e)) => {
+                        if let Some(values) = diff_at(a, e, path) {
+                            return Some(values);
+                        }
+                    }
+                    (a, e) => return Some((a.cloned(), e.cloned())),
+                }
+                path.pop();
+            }
+            None
+        }
+        (LuaMessage::Array(a), LuaMessage::Array(e)) => {
+            for i in 0..a.len().max(e.len()) {
+                path.push((i + 1).to_string());
+                match (a.get(i), e.get(i)) {
+                    (Some(a), Some(e)) => {
+                        if let Some(values) = diff_at(a, e, path) {
+                            return Some(values);
+                        }
+                    }
+                    (a, e) => return Some((a.cloned(), e.cloned())),
+                }
+                path.pop();
+            }
+            None
+        }
+        (LuaMessage::Integer(a), LuaMessage::Number(e)) if *a as f64 == *e => None,
+        (LuaMessage::Number(a), LuaMessage::Integer(e)) if *a == *e as f64 => None,
+        (a, e) if a == e => None,
+        (a, e) => Some((Some(a.clone()), Some(e.clone()))),
+    }
+}
+
+/// Load an expected value from JSON, converted like [`JsonCodec`](../struct.JsonCodec.html)
+/// does: arrays become `Array`, `null` becomes `Nil`.
+#[cfg(feature = "json")]
+pub fn fixture_from_json(json: &str) -> Result<LuaMessage, LuaError> {
+    let v = serde_json::from_str(json).map_err(LuaError::external)?;
+    Ok(JsonCodec::from_json(v))
+}
+
+/// Assert that two `LuaMessage`s are equal, see [`testing::diff`].
+///
+/// On failure, the panic message shows the path to the first difference and both values.
+///
+/// ```should_panic
+/// # #[macro_use] extern crate actix_lua;
+/// # fn main() {
+/// assert_lua_eq!(lua_msg!({x: 1, tags: ["a", "b"]}), lua_msg!({x: 1.0, tags: ["a", "c"]}));
+/// # }
+/// ```
+///
+/// [`testing::diff`]: testing/fn.diff.html
+#[macro_export]
+macro_rules! assert_lua_eq {
+    ($actual:expr, $expected:expr $(,)?) => {
+        if let Some(difference) = $crate::testing::diff(&$actual, &$expected) {
+            panic!("assertion failed: `actual == expected`\n{}", difference);
+        }
+    };
+    ($actual:expr, $expected:expr, $($arg:tt)+) => {
+        if let Some(difference) = $crate::testing::diff(&$actual, &$expected) {
+            panic!(
+                "assertion failed: `actual == expected`: {}\n{}",
+                format_args!($($arg)+),
+                difference
+            );
+        }
+    };
+}
+
+/// Build a `LuaMessage` with a syntax close to Lua table constructors.
+///
+/// `{key: value, ...}` builds a table, keys are identifiers or literals. `[value, ...]` builds
+/// an `Array`, like a Lua sequence converted to `LuaMessage`; the empty Lua table converts to
+/// an empty table, `{}`. `nil` is `LuaMessage::Nil` and keeps its key or index present. Other
+/// values are converted with `LuaMessage::from`.
+///
+/// ```
+/// # #[macro_use] extern crate actix_lua;
+/// # use actix_lua::LuaMessage;
+/// # fn main() {
+/// let msg = lua_msg!({x: 1, tags: ["a", "b"], "content-type": nil});
+/// assert_eq!(msg.get("x"), Some(&LuaMessage::from(1)));
+/// assert_eq!(msg.get("tags").unwrap().get("2"), Some(&LuaMessage::from("b")));
+/// assert_eq!(msg.get("content-type"), Some(&LuaMessage::Nil));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! lua_msg {
+    (nil) => {
+        $crate::LuaMessage::Nil
+    };
+    ({ $($body:tt)* }) => {{
+        #[allow(unused_mut)]
+        let mut table = ::std::collections::HashMap::new();
+        $crate::__lua_msg_table!(table; $($body)*);
+        $crate::LuaMessage::Table(table)
+    }};
+    ([ $($body:tt)* ]) => {{
+        #[allow(unused_mut)]
+        let mut items = ::std::vec::Vec::new();
+        $crate::__lua_msg_array!(items; []; $($body)*);
+        $crate::LuaMessage::Array(items)
+    }};
+    ($value:expr) => {
+        $crate::LuaMessage::from($value)
+    };
+}
+
+// Entries of a `lua_msg!` table, the tokens of each value are gathered up to the next comma.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __lua_msg_table {
+    ($table:ident;) => {};
+    ($table:ident; $key:ident : $($rest:tt)*) => {
+        $crate::__lua_msg_table!(@value $table, stringify!($key); []; $($rest)*)
+    };
+    ($table:ident; $key:literal : $($rest:tt)*) => {
+        $crate::__lua_msg_table!(@value $table, $key; []; $($rest)*)
+    };
+    (@value $table:ident, $key:expr; [$($value:tt)+]; $(, $($rest:tt)*)?) => {
+        $table.insert(
+            ::std::string::ToString::to_string(&$key),
+            $crate::lua_msg!($($value)+),
+        );
+        $crate::__lua_msg_table!($table; $($($rest)*)?)
+    };
+    (@value $table:ident, $key:expr; [$($value:tt)*]; $next:tt $($rest:tt)*) => {
+        $crate::__lua_msg_table!(@value $table, $key; [$($value)* $next]; $($rest)*)
+    };
+}
+
+// Items of a `lua_msg!` array, the tokens of each value are gathered up to the next comma.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __lua_msg_array {
+    ($items:ident; [];) => {};
+    ($items:ident; [$($value:tt)+]; $(, $($rest:tt)*)?) => {
+        ::std::vec::Vec::push(&mut $items, $crate::lua_msg!($($value)+));
+        $crate::__lua_msg_array!($items; []; $($($rest)*)?)
+    };
+    ($items:ident; [$($value:tt)*]; $next:tt $($rest:tt)*) => {
+        $crate::__lua_msg_array!($items; [$($value)* $next]; $($rest)*)
+    };
+}
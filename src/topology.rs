@@ -0,0 +1,629 @@
+//! Inspect how a tree of `LuaActor`s is connected.
+//!
+//! [`GetTopology`] describes the recipients of one actor, [`collect`] walks the `LuaActor`
+//! recipients of a set of actors to build a [`TopologyGraph`], and [`shutdown_tree`] stops the
+//! actors reachable from one.
+//!
+//! [`GetTopology`]: struct.GetTopology.html
+//! [`collect`]: fn.collect.html
+//! [`TopologyGraph`]: struct.TopologyGraph.html
+//! [`shutdown_tree`]: fn.shutdown_tree.html
+use ::actix::prelude::*;
+use futures::future::{self, Either, Loop};
+use futures::Future;
+use tokio::timer::Delay;
+
+use crate::actor::LuaActor;
+use crate::message::LuaMessage;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+const HOOKS: &[&str] = &["started", "handle", "stopped"];
+
+/// Describe the recipients of a `LuaActor`.
+///
+/// The reply is a table:
+///
+/// ```lua
+/// {
+///     name = "actor name",
+///     hooks = {started = true, handle = true},
+///     -- kind of each recipient, see `EdgeKind`
+///     recipients = {worker = "lua", logger = "rust"},
+///     aliases = {primary = "worker"},
+/// }
+/// ```
+pub struct GetTopology;
+
+impl Message for GetTopology {
+    type Result = LuaMessage;
+}
+
+impl Handler<GetTopology> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, _: GetTopology, _: &mut Context<Self>) -> LuaMessage {
+        let hooks: HashMap<_, _> = HOOKS
+            .iter()
+            .filter(|hook| self.runtime.has_script(hook))
+            .map(|hook| (hook.to_string(), LuaMessage::from(true)))
+            .collect();
+        let recipients: HashMap<_, _> = self
+            .recipients
+            .names()
+            .map(|name| {
+                let kind = if self.recipients.get_lua(name).is_some() {
+                    EdgeKind::Lua
+                } else {
+                    EdgeKind::Rust
+                };
+                (name.to_string(), LuaMessage::from(kind.as_str()))
+            })
+            .collect();
+        let aliases: HashMap<_, _> = self
+            .aliases
+            .iter()
+            .map(|(alias, target)| (alias.to_string(), LuaMessage::from(target)))
+            .collect();
+
+        let mut topology = HashMap::new();
+        topology.insert("name".to_string(), LuaMessage::from(self.name.as_str()));
+        topology.insert("hooks".to_string(), LuaMessage::from(hooks));
+        topology.insert("recipients".to_string(), LuaMessage::from(recipients));
+        topology.insert("aliases".to_string(), LuaMessage::from(aliases));
+        LuaMessage::from(topology)
+    }
+}
+
+// The recipients of an actor in insertion order, with the address of the `LuaActor` ones to
+// follow them.
+struct GetRecipients;
+
+impl Message for GetRecipients {
+    type Result = Vec<(String, Option<Addr<LuaActor>>)>;
+}
+
+impl Handler<GetRecipients> for LuaActor {
+    type Result = MessageResult<GetRecipients>;
+
+    fn handle(&mut self, _: GetRecipients, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(
+            self.recipients
+                .names()
+                .map(|name| (name.to_string(), self.recipients.get_lua(name).cloned()))
+                .collect(),
+        )
+    }
+}
+
+/// How an actor refers to another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EdgeKind {
+    /// a `LuaActor` added with `LuaActor::add_lua_recipient`
+    Lua,
+    /// any other recipient
+    Rust,
+    /// an alias of a recipient, see `ctx.alias`
+    Alias,
+}
+
+impl EdgeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EdgeKind::Lua => "lua",
+            EdgeKind::Rust => "rust",
+            EdgeKind::Alias => "alias",
+        }
+    }
+}
+
+/// An actor of a `TopologyGraph`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TopologyNode {
+    /// index of the node in `TopologyGraph::nodes`
+    pub id: usize,
+    pub name: String,
+    /// the hooks defined by the scripts: `started`, `handle` and `stopped`
+    pub hooks: Vec<String>,
+}
+
+/// A recipient or an alias of an actor of a `TopologyGraph`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TopologyEdge {
+    pub from: usize,
+    /// name of the recipient or alias
+    pub name: String,
+    pub kind: EdgeKind,
+    /// the node of the recipient, `None` if it's not a `LuaActor` added with `add_lua_recipient`
+    pub to: Option<usize>,
+    /// for aliases, the name of the recipient it refers to
+    pub target: Option<String>,
+}
+
+/// The actors reachable from the roots passed to `collect`.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TopologyGraph {
+    pub nodes: Vec<TopologyNode>,
+    /// edges of each node in the order of the nodes, recipients in insertion order and aliases
+    /// sorted by name
+    pub edges: Vec<TopologyEdge>,
+}
+
+// State of `collect`.
+#[derive(Default)]
+struct Walk {
+    graph: TopologyGraph,
+    // id of each discovered actor
+    ids: HashMap<Addr<LuaActor>, usize>,
+    // discovered actors to visit
+    queue: VecDeque<Addr<LuaActor>>,
+}
+
+impl Walk {
+    fn discover(&mut self, addr: Addr<LuaActor>) -> usize {
+        if let Some(&id) = self.ids.get(&addr) {
+            return id;
+        }
+        let id = self.ids.len();
+        self.ids.insert(addr.clone(), id);
+        self.queue.push_back(addr);
+        id
+    }
+
+    fn visit(
+        &mut self,
+        id: usize,
+        topology: LuaMessage,
+        recipients: Vec<(String, Option<Addr<LuaActor>>)>,
+    ) {
+        let name = match topology.get("name") {
+            Some(LuaMessage::String(name)) => name.clone(),
+            _ => String::new(),
+        };
+        let hooks = topology.get("hooks");
+        self.graph.nodes.push(TopologyNode {
+            id,
+            name,
+            hooks: HOOKS
+                .iter()
+                .filter(|hook| hooks.and_then(|h| h.get(hook)).is_some())
+                .map(|hook| hook.to_string())
+                .collect(),
+        });
+
+        let mut lua = HashMap::new();
+        for (name, addr) in recipients {
+            let to = addr.map(|addr| self.discover(addr));
+            if let Some(to) = to {
+                lua.insert(name.clone(), to);
+            }
+            self.graph.edges.push(TopologyEdge {
+                from: id,
+                name,
+                kind: if to.is_some() {
+                    EdgeKind::Lua
+                } else {
+                    EdgeKind::Rust
+                },
+                to,
+                target: None,
+            });
+        }
+
+        let mut aliases: Vec<_> = match topology.get("aliases") {
+            Some(LuaMessage::Table(aliases)) => aliases.iter().collect(),
+            _ => Vec::new(),
+        };
+        aliases.sort_by(|a, b| a.0.cmp(b.0));
+        for (alias, target) in aliases {
+            if let LuaMessage::String(target) = target {
+                self.graph.edges.push(TopologyEdge {
+                    from: id,
+                    name: alias.clone(),
+                    kind: EdgeKind::Alias,
+                    to: lua.get(target).copied(),
+                    target: Some(target.clone()),
+                });
+            }
+        }
+    }
+}
+
+/// Walk the `LuaActor` recipients of `roots`, and theirs, building the graph of the actors
+/// reached.
+///
+/// Every actor is visited once, so cycles are fine. Nodes are numbered in the order they are
+/// discovered, starting with the roots. Fails if one of the actors is stopped.
+pub fn collect(
+    roots: Vec<Addr<LuaActor>>,
+) -> impl Future<Item = TopologyGraph, Error = MailboxError> {
+    let mut walk = Walk::default();
+    for root in roots {
+        walk.discover(root);
+    }
+    future::loop_fn(walk, |mut walk| match walk.queue.pop_front() {
+        None => {
+            walk.graph.nodes.sort_by_key(|node| node.id);
+            Either::A(future::ok(Loop::Break(walk.graph)))
+        }
+        Some(addr) => {
+            let id = walk.ids[&addr];
+            Either::B(addr.send(GetTopology).join(addr.send(GetRecipients)).map(
+                move |(topology, recipients)| {
+                    walk.visit(id, topology, recipients);
+                    Loop::Continue(walk)
+                },
+            ))
+        }
+    })
+}
+
+// How often `shutdown_tree` checks whether an actor stopped.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+// Stop a `LuaActor`, running its `stopped` hook.
+struct StopActor;
+
+impl Message for StopActor {
+    type Result = ();
+}
+
+impl Handler<StopActor> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, _: StopActor, ctx: &mut Context<Self>) {
+        ctx.stop();
+    }
+}
+
+/// The outcome of `shutdown_tree`, with the names of the actors.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShutdownReport {
+    /// the actors which stopped within the timeout, in the order they were stopped
+    pub stopped: Vec<String>,
+    /// the actors still running after the timeout, e.g. in a long `stopped` hook
+    pub timed_out: Vec<String>,
+    /// the actors which had already stopped, named after the recipient they were found as
+    pub unreachable: Vec<String>,
+}
+
+// An actor found by `shutdown_tree`.
+struct TreeNode {
+    addr: Addr<LuaActor>,
+    name: String,
+    // ids of its `LuaActor` recipients
+    recipients: Vec<usize>,
+    reachable: bool,
+}
+
+// The actors reachable from the root, the root being 0.
+#[derive(Default)]
+struct Tree {
+    nodes: Vec<TreeNode>,
+    ids: HashMap<Addr<LuaActor>, usize>,
+    queue: VecDeque<usize>,
+}
+
+impl Tree {
+    fn discover(&mut self, name: String, addr: Addr<LuaActor>) -> usize {
+        if let Some(&id) = self.ids.get(&addr) {
+            return id;
+        }
+        let id = self.nodes.len();
+        self.ids.insert(addr.clone(), id);
+        self.nodes.push(TreeNode {
+            addr,
+            name,
+            recipients: Vec::new(),
+            reachable: true,
+        });
+        self.queue.push_back(id);
+        id
+    }
+
+    // The actors after all their recipients, skipping the recipients which lead back to an
+    // actor being ordered.
+    fn leaves_first(&self) -> Vec<usize> {
+        let mut order = Vec::with_capacity(self.nodes.len());
+        let mut seen = HashSet::new();
+        // the actor and the index of its next recipient
+        let mut stack = vec![(0, 0)];
+        seen.insert(0);
+        while let Some((id, next)) = stack.pop() {
+            match self.nodes[id].recipients.get(next) {
+                Some(&recipient) => {
+                    stack.push((id, next + 1));
+                    if seen.insert(recipient) {
+                        stack.push((recipient, 0));
+                    }
+                }
+                None => order.push(id),
+            }
+        }
+        order
+    }
+}
+
+// Wait until `addr` stops, `false` if it's still running at `deadline`.
+fn stopped_by(addr: Addr<LuaActor>, deadline: Instant) -> impl Future<Item = bool, Error = ()> {
+    future::loop_fn(addr, move |addr| {
+        if !addr.connected() {
+            return Either::A(future::ok(Loop::Break(true)));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Either::A(future::ok(Loop::Break(false)));
+        }
+        let wait = deadline.min(now + STOP_CHECK_INTERVAL);
+        Either::B(
+            Delay::new(wait)
+                .map_err(|e| log::error!("shutdown_tree timer failed: {}", e))
+                .map(|_| Loop::Continue(addr)),
+        )
+    })
+}
+
+/// Stop `root` and the `LuaActor` recipients reachable from it, each actor after its
+/// recipients.
+///
+/// The actors are found like in [`collect`](fn.collect.html), so cycles are fine: a recipient
+/// leading back to an actor being stopped is ignored, and every actor is stopped once. They
+/// are stopped one at a time, leaves first, so the `stopped` hook of an actor runs once its
+/// recipients stopped and the root stops last. Each actor gets `timeout` to stop before the
+/// next one is stopped, and is reported as timed out otherwise. Actors which had already
+/// stopped are reported as unreachable.
+pub fn shutdown_tree(
+    root: Addr<LuaActor>,
+    timeout: Duration,
+) -> impl Future<Item = ShutdownReport, Error = ()> {
+    let mut tree = Tree::default();
+    tree.discover(String::new(), root);
+    let found = future::loop_fn(tree, |mut tree| match tree.queue.pop_front() {
+        None => Either::A(future::ok(Loop::Break(tree))),
+        Some(id) => {
+            let addr = tree.nodes[id].addr.clone();
+            Either::B(
+                addr.send(GetTopology)
+                    .join(addr.send(GetRecipients))
+                    .then(move |res| {
+                        match res {
+                            Ok((topology, recipients)) => {
+                                if let Some(LuaMessage::String(name)) = topology.get("name") {
+                                    tree.nodes[id].name = name.clone();
+                                }
+                                for (name, addr) in recipients {
+                                    if let Some(addr) = addr {
+                                        let recipient = tree.discover(name, addr);
+                                        tree.nodes[id].recipients.push(recipient);
+                                    }
+                                }
+                            }
+                            Err(_) => tree.nodes[id].reachable = false,
+                        }
+                        Ok(Loop::Continue(tree))
+                    }),
+            )
+        }
+    });
+    found.and_then(move |tree| {
+        let order = tree.leaves_first();
+        let mut nodes: Vec<_> = tree.nodes.into_iter().map(Some).collect();
+        let order: VecDeque<_> = order
+            .into_iter()
+            .filter_map(|id| nodes[id].take())
+            .collect();
+        future::loop_fn(
+            (order, ShutdownReport::default()),
+            move |(mut order, mut report)| match order.pop_front() {
+                None => Either::A(future::ok(Loop::Break(report))),
+                Some(node) => {
+                    if !node.reachable || !node.addr.connected() {
+                        report.unreachable.push(node.name);
+                        return Either::A(future::ok(Loop::Continue((order, report))));
+                    }
+                    let TreeNode { addr, name, .. } = node;
+                    addr.do_send(StopActor);
+                    Either::B(
+                        stopped_by(addr, Instant::now() + timeout).map(move |stopped| {
+                            if stopped {
+                                report.stopped.push(name);
+                            } else {
+                                report.timed_out.push(name);
+                            }
+                            Loop::Continue((order, report))
+                        }),
+                    )
+                }
+            },
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LuaActorBuilder;
+    use std::sync::{Arc, Mutex};
+
+    struct Sink;
+
+    impl Actor for Sink {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<LuaMessage> for Sink {
+        type Result = LuaMessage;
+
+        fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> LuaMessage {
+            msg
+        }
+    }
+
+    // Records the names sent by the `stopped` hooks.
+    struct Log(Arc<Mutex<Vec<LuaMessage>>>);
+
+    impl Actor for Log {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<LuaMessage> for Log {
+        type Result = LuaMessage;
+
+        fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> LuaMessage {
+            self.0.lock().unwrap().push(msg);
+            LuaMessage::Nil
+        }
+    }
+
+    fn edge(from: usize, name: &str, kind: EdgeKind, to: Option<usize>) -> TopologyEdge {
+        TopologyEdge {
+            from,
+            name: name.to_string(),
+            kind,
+            to,
+            target: None,
+        }
+    }
+
+    #[test]
+    fn collect_topology() {
+        let system = System::new("test");
+
+        // root -> mid -> leaf -> root
+        let root = LuaActor::create(|ctx| {
+            let mut leaf = LuaActorBuilder::new().with_name("leaf").build().unwrap();
+            leaf.add_lua_recipient("root", &ctx.address()).unwrap();
+            let leaf = leaf.start();
+
+            let mut mid = LuaActorBuilder::new().with_name("mid").build().unwrap();
+            mid.add_lua_recipient("leaf", &leaf).unwrap();
+            let mid = mid.start();
+
+            let mut root = LuaActorBuilder::new()
+                .with_name("root")
+                .on_started_with_lua(r#"ctx.alias("primary", "mid")"#)
+                .build()
+                .unwrap();
+            root.add_lua_recipient("mid", &mid).unwrap();
+            root.try_add_recipients("logger", Sink.start().recipient())
+                .unwrap();
+            root
+        });
+
+        let l = root
+            .send(GetTopology)
+            .join(collect(vec![root.clone(), root.clone()]));
+        Arbiter::spawn(
+            l.map(|(topology, graph)| {
+                let recipients = topology.get("recipients").unwrap();
+                assert_eq!(recipients.get("mid"), Some(&LuaMessage::from("lua")));
+                assert_eq!(recipients.get("logger"), Some(&LuaMessage::from("rust")));
+                assert_eq!(
+                    topology.get("aliases").unwrap().get("primary"),
+                    Some(&LuaMessage::from("mid"))
+                );
+
+                let names: Vec<_> = graph.nodes.iter().map(|n| n.name.as_str()).collect();
+                assert_eq!(names, vec!["root", "mid", "leaf"]);
+                assert_eq!(graph.nodes[0].hooks, vec!["started", "handle", "stopped"]);
+                assert_eq!(
+                    graph.edges,
+                    vec![
+                        edge(0, "mid", EdgeKind::Lua, Some(1)),
+                        edge(0, "logger", EdgeKind::Rust, None),
+                        TopologyEdge {
+                            target: Some("mid".to_string()),
+                            ..edge(0, "primary", EdgeKind::Alias, Some(1))
+                        },
+                        edge(1, "leaf", EdgeKind::Lua, Some(2)),
+                        // back to the root
+                        edge(2, "root", EdgeKind::Lua, Some(0)),
+                    ]
+                );
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn shutdown_tree_leaves_first() {
+        let system = System::new("test");
+
+        let stopped = Arc::new(Mutex::new(vec![]));
+        let log = Log(stopped.clone()).start();
+        // logs its name when it stops
+        let node = move |name: &str| {
+            LuaActorBuilder::new()
+                .with_name(name)
+                .with_global("me", LuaMessage::from(name))
+                .with_recipient("log", log.clone().recipient())
+                .on_stopped_with_lua(r#"ctx.do_send("log", me)"#)
+        };
+
+        // root -> mid_a -> leaf -> root, root -> mid_b -> slow and gone
+        let slow = node("slow")
+            .on_stopped_with_lua(
+                r#"
+                ctx.do_send("log", me)
+                local start = os.clock()
+                while os.clock() - start < 0.3 do end
+                "#,
+            )
+            .spawn_on(&Arbiter::new("slow"))
+            .unwrap();
+        let gone = LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.terminate()"#)
+            .build()
+            .unwrap()
+            .start();
+        let root = LuaActor::create(move |ctx| {
+            let mut leaf = node("leaf").build().unwrap();
+            leaf.add_lua_recipient("root", &ctx.address()).unwrap();
+            let mut mid_a = node("mid_a").build().unwrap();
+            mid_a.add_lua_recipient("leaf", &leaf.start()).unwrap();
+            let mut mid_b = node("mid_b").build().unwrap();
+            mid_b.add_lua_recipient("slow", &slow).unwrap();
+            mid_b.add_lua_recipient("gone", &gone).unwrap();
+
+            let mut root = node("root").build().unwrap();
+            root.add_lua_recipient("mid_a", &mid_a.start()).unwrap();
+            root.add_lua_recipient("mid_b", &mid_b.start()).unwrap();
+            root
+        });
+
+        // let `gone` stop
+        let l = Delay::new(Instant::now() + Duration::from_millis(50))
+            .map_err(|e| panic!("{}", e))
+            .and_then(move |_| shutdown_tree(root, Duration::from_millis(100)))
+            .map(move |report| {
+                let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect();
+                assert_eq!(
+                    report,
+                    ShutdownReport {
+                        stopped: names(&["leaf", "mid_a", "mid_b", "root"]),
+                        timed_out: names(&["slow"]),
+                        unreachable: names(&["gone"]),
+                    }
+                );
+                let stopped: Vec<_> = stopped.lock().unwrap().drain(..).collect();
+                assert_eq!(
+                    stopped,
+                    ["leaf", "mid_a", "slow", "mid_b", "root"]
+                        .iter()
+                        .map(|&n| LuaMessage::from(n))
+                        .collect::<Vec<_>>()
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l);
+
+        system.run();
+    }
+}
@@ -0,0 +1,463 @@
+//! Update the state of a running `LuaActor` from Rust.
+//!
+//! [`UpdateLuaState`] applies a list of [`StateOp`] to `ctx.state`, in order, between two
+//! invocations of the scripts: like any message, it's queued in the mailbox of the actor, so
+//! the scripts never see some of the operations without the others. Each operation names a
+//! value with a path of keys, e.g. `rollout.percent`, `quotas['eu-west']` or `items[1]`,
+//! using the syntax of [`QueryLuaState`] without `*`, filters and `count()`. Keys made of
+//! digits address array elements.
+//!
+//! Each operation has its own [`OpResult`]: a failed operation, e.g. a compare-and-set which
+//! found another value, doesn't prevent the next ones.
+//!
+//! [`UpdateLuaState`]: struct.UpdateLuaState.html
+//! [`StateOp`]: enum.StateOp.html
+//! [`OpResult`]: enum.OpResult.html
+//! [`QueryLuaState`]: ../query/struct.QueryLuaState.html
+
+use ::actix::prelude::*;
+
+use crate::actor::LuaActor;
+use crate::message::{ConversionLimits, LuaMessage};
+use crate::query::{compare, parse_path};
+use rlua::{Context as LuaContext, Error as LuaError, Table, Value};
+use std::cmp::Ordering;
+
+/// An operation on `ctx.state`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateOp {
+    /// set the value at `path`
+    Set { path: String, value: LuaMessage },
+    /// add `by`, an integer or a number, to the number at `path`
+    Increment { path: String, by: LuaMessage },
+    /// set the value at `path` if it's equal to `expect`, `Nil` if it's missing
+    CompareAndSet {
+        path: String,
+        expect: LuaMessage,
+        value: LuaMessage,
+    },
+    /// remove the value at `path`, if any
+    Delete { path: String },
+}
+
+impl StateOp {
+    fn path(&self) -> &str {
+        match self {
+            StateOp::Set { path, .. }
+            | StateOp::Increment { path, .. }
+            | StateOp::CompareAndSet { path, .. }
+            | StateOp::Delete { path } => path,
+        }
+    }
+}
+
+/// The result of a `StateOp`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OpResult {
+    /// the value at the path before and after the operation, `Nil` if it's missing
+    Applied {
+        previous: LuaMessage,
+        current: LuaMessage,
+    },
+    /// the value of a `CompareAndSet` wasn't the expected one, nothing changed
+    Conflict { actual: LuaMessage },
+    /// the operation couldn't be applied, e.g. the path is malformed or goes through a value
+    /// which isn't a table
+    Failed(String),
+}
+
+/// Apply operations to `ctx.state`, see the [module documentation](index.html).
+///
+/// The reply has the result of each operation, in order.
+///
+/// ```
+/// # extern crate actix;
+/// # extern crate actix_lua;
+/// # extern crate futures;
+/// use actix::prelude::*;
+/// use actix_lua::update::{OpResult, StateOp, UpdateLuaState};
+/// use actix_lua::{LuaActorBuilder, LuaMessage};
+/// use futures::Future;
+///
+/// let system = System::new("update");
+/// let addr = LuaActorBuilder::new()
+///     .on_started_with_lua(r#"ctx.state.rollout = {percent = 10}"#)
+///     .build()
+///     .unwrap()
+///     .start();
+/// let update = addr
+///     .send(UpdateLuaState::new(vec![StateOp::CompareAndSet {
+///         path: "rollout.percent".to_string(),
+///         expect: LuaMessage::from(10),
+///         value: LuaMessage::from(50),
+///     }]))
+///     .map(|results| {
+///         assert_eq!(
+///             results,
+///             vec![OpResult::Applied {
+///                 previous: LuaMessage::from(10),
+///                 current: LuaMessage::from(50),
+///             }]
+///         );
+///         System::current().stop();
+///     });
+/// Arbiter::spawn(update.map_err(|e| panic!("{}", e)));
+/// system.run();
+/// ```
+pub struct UpdateLuaState {
+    pub ops: Vec<StateOp>,
+    /// create the missing tables of the paths, and increment missing values from 0, default
+    /// to `false`: such operations fail
+    pub create_missing: bool,
+}
+
+impl UpdateLuaState {
+    pub fn new(ops: Vec<StateOp>) -> UpdateLuaState {
+        UpdateLuaState {
+            ops,
+            create_missing: false,
+        }
+    }
+
+    /// see `create_missing`
+    pub fn create_missing(mut self, create: bool) -> UpdateLuaState {
+        self.create_missing = create;
+        self
+    }
+}
+
+impl Message for UpdateLuaState {
+    type Result = Vec<OpResult>;
+}
+
+impl Handler<UpdateLuaState> for LuaActor {
+    type Result = MessageResult<UpdateLuaState>;
+
+    fn handle(&mut self, msg: UpdateLuaState, ctx: &mut Context<Self>) -> Self::Result {
+        self.resume_sends(ctx);
+        let limits = &self.conversion_limits;
+        let create_missing = msg.create_missing;
+        let ops = msg.ops;
+        MessageResult(self.runtime.vm.context(|ctx| {
+            let state = match writable_state(ctx) {
+                Ok(state) => state,
+                Err(e) => {
+                    let e = reason(e);
+                    return ops.iter().map(|_| OpResult::Failed(e.clone())).collect();
+                }
+            };
+            ops.into_iter()
+                .map(|op| {
+                    apply(ctx, &state, op, create_missing, limits)
+                        .unwrap_or_else(|e| OpResult::Failed(reason(e)))
+                })
+                .collect()
+        }))
+    }
+}
+
+// The message of the errors raised here, without the `runtime error: ` prefix.
+fn reason(e: LuaError) -> String {
+    match e {
+        LuaError::RuntimeError(e) => e,
+        e => e.to_string(),
+    }
+}
+
+fn writable_state(ctx: LuaContext) -> Result<Table, LuaError> {
+    let lua_ctx: Table = ctx.globals().get("ctx")?;
+    let state: Table = lua_ctx.get("state")?;
+    let read_only: Table = ctx.globals().raw_get("__read_only")?;
+    if read_only.contains_key(state.clone())? {
+        return Err(LuaError::RuntimeError(
+            "state is read-only in this actor".to_string(),
+        ));
+    }
+    Ok(state)
+}
+
+fn apply<'lua>(
+    ctx: LuaContext<'lua>,
+    state: &Table<'lua>,
+    op: StateOp,
+    create_missing: bool,
+    limits: &ConversionLimits,
+) -> Result<OpResult, LuaError> {
+    let path = op.path().to_string();
+    let keys = parse_path(&path).map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+    let (key, parents) = keys.split_last().expect("paths have a key");
+    let create = create_missing && !matches!(op, StateOp::Delete { .. });
+    let parent = match parent_table(ctx, state, parents, create)? {
+        Some(parent) => parent,
+        None if matches!(op, StateOp::Delete { .. }) => {
+            return Ok(OpResult::Applied {
+                previous: LuaMessage::Nil,
+                current: LuaMessage::Nil,
+            });
+        }
+        None => return Err(missing(&parents.join("."))),
+    };
+    let key = lua_key(ctx, key)?;
+    let previous = LuaMessage::from_lua_value(parent.get(key.clone())?, ctx, Some(limits))?;
+
+    let current = match op {
+        StateOp::Set { value, .. } => value,
+        StateOp::Increment { by, .. } => {
+            let previous = match previous {
+                LuaMessage::Nil if create_missing => LuaMessage::Integer(0),
+                LuaMessage::Nil => return Err(missing(&path)),
+                ref previous => previous.clone(),
+            };
+            increment(&path, &previous, &by)?
+        }
+        StateOp::CompareAndSet { expect, value, .. } => {
+            if compare(&previous, &expect) != Some(Ordering::Equal) {
+                return Ok(OpResult::Conflict { actual: previous });
+            }
+            value
+        }
+        StateOp::Delete { .. } => LuaMessage::Nil,
+    };
+    parent.set(key, current.to_lua_value(ctx, Some(limits))?)?;
+    Ok(OpResult::Applied { previous, current })
+}
+
+// The table at `keys`, creating the missing ones if `create` is set.
+fn parent_table<'lua>(
+    ctx: LuaContext<'lua>,
+    state: &Table<'lua>,
+    keys: &[String],
+    create: bool,
+) -> Result<Option<Table<'lua>>, LuaError> {
+    let mut table = state.clone();
+    for (i, key) in keys.iter().enumerate() {
+        let lua_key = lua_key(ctx, key)?;
+        table = match table.get(lua_key.clone())? {
+            Value::Table(t) => t,
+            Value::Nil if create => {
+                let t = ctx.create_table()?;
+                table.set(lua_key, t.clone())?;
+                t
+            }
+            Value::Nil => return Ok(None),
+            _ => {
+                return Err(LuaError::RuntimeError(format!(
+                    "`{}` is not a table",
+                    keys[..=i].join(".")
+                )))
+            }
+        };
+    }
+    Ok(Some(table))
+}
+
+fn lua_key<'lua>(ctx: LuaContext<'lua>, key: &str) -> Result<Value<'lua>, LuaError> {
+    match key.parse::<i64>() {
+        Ok(index) if key.bytes().all(|b| b.is_ascii_digit()) => Ok(Value::Integer(index)),
+        _ => Ok(Value::String(ctx.create_string(key)?)),
+    }
+}
+
+fn missing(path: &str) -> LuaError {
+    LuaError::RuntimeError(format!("`{}` is missing", path))
+}
+
+// Integers stay integers unless they overflow, like `ctx.math.checked_add`.
+fn increment(path: &str, value: &LuaMessage, by: &LuaMessage) -> Result<LuaMessage, LuaError> {
+    let number = |v: &LuaMessage| match v {
+        LuaMessage::Integer(n) => Some(*n as f64),
+        LuaMessage::Number(n) => Some(*n),
+        _ => None,
+    };
+    match (value, by) {
+        (LuaMessage::Integer(a), LuaMessage::Integer(b)) => a
+            .checked_add(*b)
+            .map(LuaMessage::Integer)
+            .ok_or_else(|| LuaError::RuntimeError(format!("`{}` would overflow", path))),
+        _ => match (number(value), number(by)) {
+            (Some(a), Some(b)) => Ok(LuaMessage::Number(a + b)),
+            (None, _) => Err(LuaError::RuntimeError(format!(
+                "`{}` is not a number",
+                path
+            ))),
+            (_, None) => Err(LuaError::RuntimeError(
+                "the increment is not a number".to_string(),
+            )),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LuaActorBuilder;
+    use crate::lua_msg;
+    use crate::query::QueryLuaState;
+    use futures::Future;
+
+    fn path(s: &str) -> String {
+        s.to_string()
+    }
+
+    #[test]
+    fn update_lua_state() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"ctx.state.rollout = { percent = 10 }; ctx.state.items = { "a" }"#,
+            )
+            .on_handle_with_lua(
+                r#"ctx.state.rollout.percent = ctx.state.rollout.percent + ctx.msg"#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::from(5))
+            .and_then({
+                let addr = addr.clone();
+                move |_| {
+                    addr.send(UpdateLuaState::new(vec![
+                        // the handler changed it
+                        StateOp::CompareAndSet {
+                            path: path("rollout.percent"),
+                            expect: LuaMessage::from(10),
+                            value: LuaMessage::from(50),
+                        },
+                        StateOp::CompareAndSet {
+                            path: path("$.rollout['percent']"),
+                            expect: LuaMessage::from(15.0),
+                            value: LuaMessage::from(20),
+                        },
+                        StateOp::Increment {
+                            path: path("rollout.percent"),
+                            by: LuaMessage::from(1),
+                        },
+                        StateOp::Set {
+                            path: path("items[2]"),
+                            value: LuaMessage::from("b"),
+                        },
+                        StateOp::Delete {
+                            path: path("rollout.missing.key"),
+                        },
+                        StateOp::Set {
+                            path: path("items[*]"),
+                            value: LuaMessage::Nil,
+                        },
+                    ]))
+                }
+            })
+            .and_then(move |results| {
+                assert_eq!(
+                    results[0],
+                    OpResult::Conflict {
+                        actual: LuaMessage::from(15)
+                    }
+                );
+                assert_eq!(
+                    results[1],
+                    OpResult::Applied {
+                        previous: LuaMessage::from(15),
+                        current: LuaMessage::from(20)
+                    }
+                );
+                assert_eq!(
+                    results[2],
+                    OpResult::Applied {
+                        previous: LuaMessage::from(20),
+                        current: LuaMessage::from(21)
+                    }
+                );
+                assert_eq!(
+                    results[4],
+                    OpResult::Applied {
+                        previous: LuaMessage::Nil,
+                        current: LuaMessage::Nil
+                    }
+                );
+                match results[5] {
+                    OpResult::Failed(ref e) => {
+                        assert!(e.contains("expected a path of keys"), "{}", e)
+                    }
+                    ref res => panic!("unexpected result {:?}", res),
+                }
+                addr.send(QueryLuaState::new("$"))
+            })
+            .map(|state| {
+                assert_eq!(
+                    state,
+                    Ok(lua_msg!([{ rollout: { percent: 21 }, items: ["a", "b"] }]))
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn update_lua_state_missing_paths() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(r#"ctx.state.quotas = { eu = "none" }"#)
+            .build()
+            .unwrap()
+            .start();
+
+        let ops = || {
+            vec![
+                StateOp::Increment {
+                    path: path("quotas.us"),
+                    by: LuaMessage::from(2),
+                },
+                StateOp::Set {
+                    path: path("limits.us"),
+                    value: LuaMessage::from(1),
+                },
+                StateOp::Increment {
+                    path: path("quotas.eu"),
+                    by: LuaMessage::from(1),
+                },
+                StateOp::Set {
+                    path: path("quotas.eu.x"),
+                    value: LuaMessage::from(1),
+                },
+            ]
+        };
+        let failed = |res: &OpResult| match res {
+            OpResult::Failed(e) => e.clone(),
+            res => panic!("unexpected result {:?}", res),
+        };
+        let l = addr
+            .send(UpdateLuaState::new(ops()))
+            .join(addr.send(UpdateLuaState::new(ops()).create_missing(true)))
+            .map(move |(strict, created)| {
+                assert_eq!(failed(&strict[0]), "`quotas.us` is missing");
+                assert_eq!(failed(&strict[1]), "`limits` is missing");
+                assert_eq!(failed(&strict[2]), "`quotas.eu` is not a number");
+                assert_eq!(failed(&strict[3]), "`quotas.eu` is not a table");
+
+                assert_eq!(
+                    created[0],
+                    OpResult::Applied {
+                        previous: LuaMessage::Nil,
+                        current: LuaMessage::from(2)
+                    }
+                );
+                assert_eq!(
+                    created[1],
+                    OpResult::Applied {
+                        previous: LuaMessage::Nil,
+                        current: LuaMessage::from(1)
+                    }
+                );
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,107 @@
+use crate::actor::DEFAULT_NAME;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A warning about a deprecated or misused API.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    /// name of the actor, see `LuaActorBuilder::with_name`
+    pub actor: String,
+    /// identifies the warning, e.g. `state_replaced`
+    pub key: String,
+    pub message: String,
+}
+
+/// Callback invoked for each warning emitted by an actor.
+pub type WarningCallback = Arc<dyn Fn(&Warning) + Send + Sync>;
+
+pub(crate) const DEFAULT_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+// Warnings of an actor, each key is emitted at most once per interval.
+#[derive(Clone)]
+pub(crate) struct Warnings {
+    pub interval: Duration,
+    pub callback: Option<WarningCallback>,
+    // see `LuaActorBuilder::with_log_prefix`
+    pub log_prefix: String,
+    // when each key was last emitted
+    emitted: HashMap<String, Instant>,
+}
+
+impl Default for Warnings {
+    fn default() -> Warnings {
+        Warnings {
+            interval: DEFAULT_WARNING_INTERVAL,
+            callback: None,
+            log_prefix: format!("LuaActor {}", DEFAULT_NAME),
+            emitted: HashMap::new(),
+        }
+    }
+}
+
+impl Warnings {
+    // Log the warning `key` and pass it to the callback, unless it was emitted less than an
+    // interval before `now`. Returns whether it was emitted.
+    pub fn warn_once(&mut self, actor: &str, key: &str, message: &str, now: Instant) -> bool {
+        if let Some(&last) = self.emitted.get(key) {
+            if now.duration_since(last) < self.interval {
+                return false;
+            }
+        }
+        self.emitted.insert(key.to_string(), now);
+
+        log::warn!("{}: {}", self.log_prefix, message);
+        if let Some(ref callback) = self.callback {
+            callback(&Warning {
+                actor: actor.to_string(),
+                key: key.to_string(),
+                message: message.to_string(),
+            });
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn warn_once_per_interval() {
+        let captured = Arc::new(Mutex::new(vec![]));
+        let captured2 = captured.clone();
+        let mut warnings = Warnings {
+            interval: Duration::from_secs(10),
+            callback: Some(Arc::new(move |w: &Warning| {
+                captured2.lock().unwrap().push(w.clone())
+            })),
+            ..Warnings::default()
+        };
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(warnings.warn_once("actor", "a", "first a", at(0)));
+        assert!(!warnings.warn_once("actor", "a", "second a", at(5)));
+        // keys are throttled independently
+        assert!(warnings.warn_once("actor", "b", "first b", at(5)));
+        assert!(warnings.warn_once("actor", "a", "third a", at(10)));
+        assert!(!warnings.warn_once("actor", "b", "second b", at(14)));
+
+        let messages: Vec<_> = captured
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|w| (w.key.clone(), w.message.clone()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                ("a".to_string(), "first a".to_string()),
+                ("b".to_string(), "first b".to_string()),
+                ("a".to_string(), "third a".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use ::actix::prelude::*;
+
+use crate::actor::LuaActor;
+use crate::message::LuaMessage;
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub(crate) const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Recipients watched with `ctx.watch`, all checked by the same interval.
+pub(crate) struct Watches {
+    pub interval: Duration,
+    // last status of each watched recipient
+    status: HashMap<String, bool>,
+    check: Option<SpawnHandle>,
+}
+
+impl Default for Watches {
+    fn default() -> Watches {
+        Watches {
+            interval: DEFAULT_WATCH_INTERVAL,
+            status: HashMap::new(),
+            check: None,
+        }
+    }
+}
+
+impl Watches {
+    pub fn watch(&mut self, name: &str, connected: bool, ctx: &mut Context<LuaActor>) {
+        self.status.insert(name.to_string(), connected);
+        if self.check.is_none() {
+            self.check = Some(ctx.run_interval(self.interval, |act, ctx| act.check_watches(ctx)));
+        }
+    }
+
+    pub fn unwatch(&mut self, name: &str, ctx: &mut Context<LuaActor>) {
+        self.status.remove(name);
+        if self.status.is_empty() {
+            if let Some(check) = self.check.take() {
+                ctx.cancel_future(check);
+            }
+        }
+    }
+
+    pub fn clear(&mut self, ctx: &mut Context<LuaActor>) {
+        self.status.clear();
+        if let Some(check) = self.check.take() {
+            ctx.cancel_future(check);
+        }
+    }
+}
+
+impl LuaActor {
+    // Deliver the status changes of the watched recipients, forgetting the removed ones.
+    fn check_watches(&mut self, ctx: &mut Context<Self>) {
+        let mut changed = Vec::new();
+        let mut removed = Vec::new();
+        for (name, connected) in &mut self.watches.status {
+            match self.recipients.connected(self.aliases.resolve(name)) {
+                Some(now) if now != *connected => {
+                    *connected = now;
+                    changed.push((name.clone(), now));
+                }
+                Some(_) => {}
+                None => removed.push(name.clone()),
+            }
+        }
+        for name in removed {
+            self.watches.unwatch(&name, ctx);
+        }
+
+        changed.sort();
+        for (name, connected) in changed {
+            let mut event = HashMap::new();
+            event.insert("name".to_string(), LuaMessage::from(name));
+            event.insert("connected".to_string(), LuaMessage::from(connected));
+            let mut msg = HashMap::new();
+            msg.insert("__watch".to_string(), LuaMessage::from(event));
+            if let Err(e) = self.invoke_hook(
+                ctx,
+                "handle",
+                "__run",
+                vec![
+                    LuaMessage::from("__watch"),
+                    LuaMessage::from(msg),
+                    LuaMessage::from(false),
+                ],
+                false,
+            ) {
+                // like the failed invocations of `do_send`, the actor keeps running
+                self.record_error("handle", &e.to_string(), ctx);
+            }
+        }
+    }
+}
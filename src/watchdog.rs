@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A hook invocation which didn't return within the watchdog threshold.
+#[derive(Debug, Clone)]
+pub struct StuckInvocation {
+    /// name of the actor, see `LuaActorBuilder::with_name`
+    pub actor: String,
+    /// `started`, `handle`, `stopped` or `resume`
+    pub hook: String,
+    /// time since the invocation started
+    pub elapsed: Duration,
+}
+
+/// Callback invoked from the watchdog thread for each stuck invocation.
+pub type WatchdogCallback = Arc<dyn Fn(&StuckInvocation) + Send + Sync>;
+
+// how often the watchdog thread scans running invocations
+const SCAN_INTERVAL: Duration = Duration::from_millis(20);
+
+struct Entry {
+    actor: String,
+    log_prefix: String,
+    hook: String,
+    started_at: Instant,
+    threshold: Duration,
+    callback: Option<WatchdogCallback>,
+    reported: bool,
+}
+
+struct Registry {
+    entries: Mutex<HashMap<usize, Entry>>,
+    next_id: AtomicUsize,
+}
+
+fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+    REGISTRY.get_or_init(|| {
+        // the watchdog thread is started once per process, with the registry
+        thread::Builder::new()
+            .name("actix-lua-watchdog".to_string())
+            .spawn(|| loop {
+                thread::sleep(SCAN_INTERVAL);
+                scan(registry());
+            })
+            .expect("failed to start the watchdog thread");
+        Registry {
+            entries: Mutex::new(HashMap::new()),
+            next_id: AtomicUsize::new(0),
+        }
+    })
+}
+
+fn scan(registry: &Registry) {
+    let mut stuck = vec![];
+    {
+        let mut entries = registry.entries.lock().unwrap();
+        for entry in entries.values_mut() {
+            let elapsed = entry.started_at.elapsed();
+            if !entry.reported && elapsed >= entry.threshold {
+                entry.reported = true;
+                stuck.push((
+                    entry.log_prefix.clone(),
+                    StuckInvocation {
+                        actor: entry.actor.clone(),
+                        hook: entry.hook.clone(),
+                        elapsed,
+                    },
+                    entry.callback.clone(),
+                ));
+            }
+        }
+    }
+
+    // callbacks run without holding the lock
+    for (log_prefix, invocation, callback) in stuck {
+        log::warn!(
+            "{} is stuck in the `{}` hook for {:?}, a Rust function called from Lua may be blocking",
+            log_prefix,
+            invocation.hook,
+            invocation.elapsed
+        );
+        if let Some(callback) = callback {
+            callback(&invocation);
+        }
+    }
+}
+
+/// Watchdog configuration of an actor.
+#[derive(Clone)]
+pub(crate) struct Watchdog {
+    pub threshold: Duration,
+    pub callback: Option<WatchdogCallback>,
+}
+
+impl Watchdog {
+    // Register a running invocation, it's deregistered when the guard is dropped.
+    pub fn register(&self, actor: &str, log_prefix: &str, hook: &str) -> WatchdogGuard {
+        let registry = registry();
+        let id = registry.next_id.fetch_add(1, Ordering::SeqCst);
+        registry.entries.lock().unwrap().insert(
+            id,
+            Entry {
+                actor: actor.to_string(),
+                log_prefix: log_prefix.to_string(),
+                hook: hook.to_string(),
+                started_at: Instant::now(),
+                threshold: self.threshold,
+                callback: self.callback.clone(),
+                reported: false,
+            },
+        );
+        WatchdogGuard { id }
+    }
+}
+
+pub(crate) struct WatchdogGuard {
+    id: usize,
+}
+
+impl Drop for WatchdogGuard {
+    fn drop(&mut self) {
+        registry().entries.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn is_registered(actor: &str) -> bool {
+    registry()
+        .entries
+        .lock()
+        .unwrap()
+        .values()
+        .any(|e| e.actor == actor)
+}
@@ -0,0 +1,123 @@
+use ::actix::prelude::*;
+
+use crate::actor::LuaActor;
+use crate::message::LuaMessage;
+use std::collections::HashMap;
+
+// The workflows of a `LuaActor` which finished since it started, see `GetWorkflowStats`.
+#[derive(Debug, Default)]
+pub(crate) struct Workflows {
+    completed: u64,
+    failed: u64,
+}
+
+impl Workflows {
+    pub fn record(&mut self, completed: u64, failed: u64) {
+        self.completed += completed;
+        self.failed += failed;
+    }
+
+    pub fn stats(&self) -> LuaMessage {
+        let mut stats = HashMap::new();
+        stats.insert(
+            "completed".to_string(),
+            LuaMessage::from(self.completed as i64),
+        );
+        stats.insert("failed".to_string(), LuaMessage::from(self.failed as i64));
+        LuaMessage::from(stats)
+    }
+}
+
+/// Read the number of workflows of a `LuaActor` which finished, see `on_workflow_complete`.
+///
+/// A workflow is a `handle` invocation for a message sent without waiting for the reply, e.g.
+/// with `do_send`, which suspended in `ctx.send`. The reply is a table of counts since the actor
+/// started:
+///
+/// ```lua
+/// {
+///     completed = 10,
+///     failed = 1,  -- raised an error after suspending
+/// }
+/// ```
+pub struct GetWorkflowStats;
+
+impl Message for GetWorkflowStats {
+    type Result = LuaMessage;
+}
+
+impl Handler<GetWorkflowStats> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, _: GetWorkflowStats, _: &mut Context<Self>) -> LuaMessage {
+        self.workflows.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LuaActorBuilder;
+    use crate::lua_msg;
+    use crate::runtime::RETURN_CONVERSIONS;
+    use futures::Future;
+    use futures_timer::Delay;
+    use std::time::Duration;
+
+    #[test]
+    fn workflow_completion() {
+        let system = System::new("test");
+
+        let doubler = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return ctx.msg * 2"#)
+            .build()
+            .unwrap()
+            .start();
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+                function on_workflow_complete(value)
+                    ctx.state.completed = { msg = ctx.msg, value = value }
+                end
+                "#,
+            )
+            .on_handle_with_lua(
+                r#"
+                if ctx.msg == "completed" then
+                    return ctx.state.completed
+                end
+                local a = ctx.send("doubler", ctx.msg)
+                if ctx.msg == 0 then
+                    error("failed")
+                end
+                local b = ctx.send("doubler", a)
+                return { a = a, b = b }
+                "#,
+            )
+            .with_recipient("doubler", doubler.recipient())
+            .build()
+            .unwrap()
+            .start();
+
+        let before = RETURN_CONVERSIONS.with(|c| c.get());
+        addr.do_send(LuaMessage::from(3));
+        addr.do_send(LuaMessage::from(0));
+        let l = Delay::new(Duration::from_millis(100))
+            .map_err(|e| panic!("{}", e))
+            .and_then(move |_| {
+                addr.send(LuaMessage::from("completed"))
+                    .join(addr.send(GetWorkflowStats))
+            })
+            .map(move |(completed, stats)| {
+                assert_eq!(completed, lua_msg!({ msg: 3, value: { a: 6, b: 12 } }));
+                assert_eq!(stats, lua_msg!({ completed: 1, failed: 1 }));
+                // the 3 replies of `doubler` and the reply to "completed", the results of the
+                // workflows are passed to the hook as Lua values
+                assert_eq!(RETURN_CONVERSIONS.with(|c| c.get()) - before, 4);
+                System::current().stop();
+            });
+        Arbiter::spawn(l.map_err(|e| panic!("actor dead {}", e)));
+
+        system.run();
+    }
+}
@@ -0,0 +1,120 @@
+#[macro_use]
+extern crate actix_lua;
+
+use actix_lua::testing::diff;
+use actix_lua::LuaMessage;
+use std::collections::HashMap;
+
+fn table(entries: Vec<(&str, LuaMessage)>) -> LuaMessage {
+    LuaMessage::Table(
+        entries
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect::<HashMap<_, _>>(),
+    )
+}
+
+#[test]
+fn lua_msg_syntax() {
+    assert_eq!(lua_msg!(nil), LuaMessage::Nil);
+    assert_eq!(lua_msg!(-1), LuaMessage::Integer(-1));
+    assert_eq!(lua_msg!(1 + 1), LuaMessage::Integer(2));
+    assert_eq!(lua_msg!({}), table(vec![]));
+    assert_eq!(lua_msg!([]), LuaMessage::Array(vec![]));
+
+    let name = "x".to_string();
+    let msg = lua_msg!({
+        id: 1,
+        name: name.as_str(),
+        "content-type": "text",
+        missing: nil,
+        // nested arrays of tables, with trailing commas
+        points: [{x: 1, y: 2.5}, {x: -3, y: nil},],
+        matrix: [[1, 2], [], [nil, 4]],
+    });
+    assert_eq!(
+        msg,
+        table(vec![
+            ("id", LuaMessage::from(1)),
+            ("name", LuaMessage::from("x")),
+            ("content-type", LuaMessage::from("text")),
+            ("missing", LuaMessage::Nil),
+            (
+                "points",
+                LuaMessage::Array(vec![
+                    table(vec![
+                        ("x", LuaMessage::from(1)),
+                        ("y", LuaMessage::from(2.5))
+                    ]),
+                    table(vec![("x", LuaMessage::from(-3)), ("y", LuaMessage::Nil)]),
+                ])
+            ),
+            (
+                "matrix",
+                LuaMessage::Array(vec![
+                    LuaMessage::from(vec![1, 2]),
+                    LuaMessage::Array(vec![]),
+                    // nil keeps its position
+                    LuaMessage::Array(vec![LuaMessage::Nil, LuaMessage::from(4)]),
+                ])
+            ),
+        ])
+    );
+}
+
+#[test]
+fn diff_messages() {
+    assert_eq!(diff(&lua_msg!({x: 1}), &lua_msg!({x: 1.0})), None);
+    assert_lua_eq!(lua_msg!([1, {a: true}]), lua_msg!([1.0, {a: true}]));
+
+    // numeric keys are compared by value: "2" comes before "10"
+    let mut actual = HashMap::new();
+    let mut expected = HashMap::new();
+    for i in 1..=10 {
+        actual.insert(i.to_string(), LuaMessage::from(i));
+        expected.insert(i.to_string(), LuaMessage::from(i));
+    }
+    actual.insert("2".to_string(), LuaMessage::from("two"));
+    actual.insert("10".to_string(), LuaMessage::from("ten"));
+    let d = diff(&LuaMessage::Table(actual), &LuaMessage::Table(expected)).unwrap();
+    assert_eq!(d.path, vec!["2"]);
+
+    let d = diff(&lua_msg!({a: nil}), &lua_msg!({})).unwrap();
+    assert_eq!((d.actual, d.expected), (Some(LuaMessage::Nil), None));
+    let d = diff(&lua_msg!(1), &lua_msg!("1")).unwrap();
+    assert!(d.path.is_empty());
+}
+
+#[test]
+fn diff_output() {
+    let actual = lua_msg!({user: {name: "ann", tags: ["a", "b"]}, id: 1});
+    let expected = lua_msg!({user: {name: "ann", tags: ["a", {kind: "c"}]}, id: 1});
+    let d = diff(&actual, &expected).unwrap();
+    assert_eq!(
+        d.to_string(),
+        "messages differ at user.tags.2\n  actual: \"b\"\nexpected: {kind = \"c\"}"
+    );
+
+    let d = diff(&lua_msg!({"a b": [1]}), &lua_msg!({})).unwrap();
+    assert_eq!(
+        d.to_string(),
+        "messages differ at a b\n  actual: {1}\nexpected: <missing>"
+    );
+}
+
+#[test]
+#[should_panic(expected = "id 7\nmessages differ at user.tags.2")]
+fn assert_lua_eq_fails() {
+    let actual = lua_msg!({user: {tags: ["a", "b"]}});
+    assert_lua_eq!(actual, lua_msg!({user: {tags: ["a", "c"]}}), "id {}", 7);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn fixture() {
+    use actix_lua::testing::fixture_from_json;
+
+    let fixture = fixture_from_json(r#"{"x": 1, "tags": ["a", "b"], "none": null}"#).unwrap();
+    assert_lua_eq!(fixture, lua_msg!({x: 1, tags: ["a", "b"], none: nil}));
+    assert!(fixture_from_json("{").is_err());
+}
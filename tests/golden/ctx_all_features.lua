@@ -0,0 +1,298 @@
+---@meta
+-- generated by actix_lua::docs::emit_lua_stubs, do not edit
+
+---@class ctx
+---@field state table state of the actor, kept between messages
+---@field msg any message being handled
+---@field reply_expected boolean whether the sender waits for the result of the script
+---@field scratch table table cleared after each invocation, must not be stored in `ctx.state`
+---@field null lightuserdata `nil` values of tables
+---@field state_version integer version of `ctx.state` expected by the scripts, set by the `migrations` hook
+---@field shared table values shared by the hooks, e.g. helpers with `with_isolated_hooks(true)`
+---@field data table<string, { get: fun(key: string): any, iter_prefix: fun(prefix: string): fun(): string, any }> datasets shared with other actors, see `LuaActorBuilder::with_dataset`
+---@field build table<string, any> read-only build info of the host application, see `LuaActorBuilder::with_build_info`
+---@field runtime { dependencies: table<string, boolean>, gc: { collections: integer, reclaimed: integer }? } status of the required recipients and of the GC watermark
+ctx = {}
+
+--- send `msg` to self
+---@param msg any
+function ctx.notify(msg) end
+
+--- send `msg` to self after `seconds`, 0 for the next turn
+---@param msg any
+---@param seconds number
+function ctx.notify_later(msg, seconds) end
+
+--- send the table `msg` to self every `seconds`, see the tick policies
+---@param seconds number
+---@param msg table
+---@param policy? "coalesce"|"all"|"skip"
+---@return integer id
+function ctx.run_interval(seconds, msg, policy) end
+
+--- stop the interval `id`, dropping its pending tick
+---@param id integer
+function ctx.cancel_interval(id) end
+
+--- send `msg` to self at the times matching the cron expression `cron_expr`, replacing the schedule `name`
+---@param cron_expr string
+---@param msg any
+---@param options? {name: string?, utc_offset: integer?}
+---@return string name
+function ctx.schedule(cron_expr, msg, options) end
+
+--- remove the schedule `name`, returning whether it existed
+---@param name string
+---@return boolean removed
+function ctx.unschedule(name) end
+
+--- send `msg` to `recipient` and wait for the response, or return nil and `{kind = ..., message = ...}` if it can't be delivered, `kind` being `closed`, `timeout` or `unknown_recipient`
+---@param recipient string
+---@param msg any
+---@return any result
+---@return table|nil err
+function ctx.send(recipient, msg) end
+
+--- send `msg` to `recipient`, return the value or raise an error if it failed
+---
+--- Available to actors built with `with_rich_send_results`.
+---@param recipient string
+---@param msg any
+---@return any value
+function ctx.send_ok(recipient, msg) end
+
+--- `ctx.do_send` a copy of the table `msg` with a new id in `id_field` and wait for the first message carrying the id back
+---@param recipient string
+---@param msg table
+---@param options? {id_field: string?, timeout: number?}
+---@return any reply
+function ctx.send_correlated(recipient, msg, options) end
+
+--- wait for the next table message whose `type` field is one of `types`, which resumes the coroutine instead of running `handle`
+---@param types string[]
+---@param timeout? number
+---@return any msg
+function ctx.wait_for(types, timeout) end
+
+--- a group of coroutines started with `g:spawn(fn)`, awaited with `g:join([timeout])` and cancelled with `g:cancel()`
+---@return {spawn: fun(self, fn: function): integer, join: fun(self, timeout: number?): table[], cancel: fun(self)} g
+function ctx.spawn_group() end
+
+--- `pcall` for functions which suspend the script, e.g. in `ctx.send`
+---@param f function
+---@param ... any
+---@return boolean ok
+---@return any ...
+function ctx.try(f, ...) end
+
+--- send `msg` to `recipient` without waiting for the response
+---@param recipient string
+---@param msg any
+function ctx.do_send(recipient, msg) end
+
+--- `ctx.do_send` `msg` to `recipient`, then call `on_delivery_receipt(receipt, status)` with whether it reached the mailbox
+---@param recipient string
+---@param msg any
+---@return integer receipt
+function ctx.do_send_confirmed(recipient, msg) end
+
+--- buffer the `ctx.do_send` to `recipient` while it's disconnected, retrying them in order
+---@param recipient string
+---@param opts? { capacity: integer?, retry_interval: number? }
+function ctx.set_outbox(recipient, opts) end
+
+--- number of messages buffered for `recipient`, see `ctx.set_outbox`
+---@param recipient string
+---@return integer n
+function ctx.outbox_depth(recipient) end
+
+--- call `on_recipient_status(recipient, connected)` when `recipient` stops
+---@param recipient string
+function ctx.watch(recipient) end
+
+--- stop watching `recipient`
+---@param recipient string
+function ctx.unwatch(recipient) end
+
+--- replace the child `name` with one running the `handle` script `script_path` once it replied to the pending sends, holding its messages meanwhile
+---
+--- Available to children added with `with_child`.
+---@param name string
+---@param script_path string
+---@param options? {drain_timeout: number?, max_queued: integer?}
+---@return {drained: integer, queued: integer, old_stopped: boolean} summary
+function ctx.replace_actor(name, script_path, options) end
+
+--- stop the actor
+function ctx.terminate() end
+
+--- rebuild the VM from the scripts once the current invocation returns, raising an error if the changed scripts can't be reloaded
+---
+--- Available to actors not built with `build_with_vm`.
+---@param keep_state? boolean
+function ctx.restart(keep_state) end
+
+--- state of the circuit breaker of `recipient`: closed, open or half_open
+---
+--- Available to actors built with `with_circuit_breaker`.
+---@param recipient string
+---@return "closed"|"open"|"half_open"|nil state
+function ctx.breaker_state(recipient) end
+
+--- make `ctx.send` and `ctx.do_send` to `alias` go to the recipient `target`
+---@param alias string
+---@param target string
+function ctx.alias(alias, target) end
+
+--- remove `alias`
+---@param alias string
+function ctx.unalias(alias) end
+
+--- the recipient `alias` refers to, nil if it's not an alias
+---@param alias string
+---@return string|nil target
+function ctx.resolve(alias) end
+
+--- true if `v` is nil or `ctx.null`, which is truthy
+---@param v any
+---@return boolean null
+function ctx.is_null(v) end
+
+--- state of the namespace `name`, kept in `ctx.state.__ns`
+---@param name string
+---@return table state
+function ctx.ns(name) end
+
+--- skip `path` of `ctx.state` and everything under it in state diffs
+---@param path string
+function ctx.no_diff(path) end
+
+--- changes of `ctx.state` made by the last message
+---
+--- Available to actors built with `with_state_diffing`, nil otherwise.
+---@return table|nil changes
+function ctx.last_diff() end
+
+--- estimated size of `ctx.state` in bytes
+---@return integer bytes
+function ctx.state_size() end
+
+--- encode the value at `path` of `ctx.state`, e.g. `users.alice`, or the whole state, for `ctx.import_state` in another actor
+---@param path? string
+---@return string blob
+function ctx.export_state(path) end
+
+--- set the value of a blob of `ctx.export_state` at `path` of `ctx.state`, replacing it or merging tables into it, migrated to `ctx.state_version`
+---@param path string|nil
+---@param blob string
+---@param options? {merge: string?}
+function ctx.import_state(path, blob, options) end
+
+--- whether message processing is paused with `PauseProcessing`
+---@return boolean paused
+function ctx.is_paused() end
+
+--- register the migration of `ctx.state` from `from_version` to the next
+---
+--- Available to the `migrations` hook.
+---@param from_version integer
+---@param migrate fun(old_state: table): table
+function ctx.migration(from_version, migrate) end
+
+--- keep the metatable `class` of tables converted to messages
+---@param name string
+---@param class table
+function ctx.register_class(name, class) end
+
+--- helpers for scripts whose results must not depend on the order of `pairs`
+---@class ctx.util
+ctx.util = {}
+
+--- `pairs(t)` in a deterministic order: booleans, numbers, then strings, each sorted
+---@param t table
+function ctx.util.opairs(t) end
+
+--- integer arithmetic returning `value, false` or `nil, true` on overflow
+---@class ctx.math
+ctx.math = {}
+
+--- `value, false`, or `nil, true` on overflow
+---@param a integer
+---@param b integer
+---@return integer|nil value
+---@return boolean overflow
+function ctx.math.checked_add(a, b) end
+
+--- `value, false`, or `nil, true` on overflow
+---@param a integer
+---@param b integer
+---@return integer|nil value
+---@return boolean overflow
+function ctx.math.checked_sub(a, b) end
+
+--- `value, false`, or `nil, true` on overflow
+---@param a integer
+---@param b integer
+---@return integer|nil value
+---@return boolean overflow
+function ctx.math.checked_mul(a, b) end
+
+--- `x` within `[lo, hi]`, an integer if all the arguments are
+---@param x number
+---@param lo number
+---@param hi number
+---@return number y
+function ctx.math.clamp(x, lo, hi) end
+
+--- Unicode-aware case conversion, comparison and sorting of strings
+---@class ctx.str
+ctx.str = {}
+
+--- `s` in lower case, only ASCII letters change if `s` isn't UTF-8
+---@param s string
+---@return string lower
+function ctx.str.lower(s) end
+
+--- `s` in upper case, only ASCII letters change if `s` isn't UTF-8
+---@param s string
+---@return string upper
+function ctx.str.upper(s) end
+
+--- whether `a` and `b` are equal ignoring case
+---@param a string
+---@param b string
+---@return boolean equal
+function ctx.str.casefold_eq(a, b) end
+
+--- a sorted copy of an array of strings, by code point, keeping the order of equal strings
+---@param strings string[]
+---@param options? {case_insensitive: boolean?}
+---@return string[] sorted
+function ctx.str.sort(strings, options) end
+
+--- number of characters of `s`, raises an error if `s` isn't UTF-8
+---@param s string
+---@return integer n
+function ctx.str.len(s) end
+
+--- drop the Rust value of the opaque `handle`
+---@param handle lightuserdata
+function ctx.release(handle) end
+
+--- a new version 4 uuid, the same sequence in every run with `LuaActorBuilder::with_id_seed`
+---@return string id
+function ctx.uuid() end
+
+--- warn that a deprecated API is used, at most once per interval
+---@param msg string
+function ctx.deprecated(msg) end
+
+--- describe the available `ctx` functions, or the function `name`
+---@param name? string
+---@return table entries
+function ctx.help(name) end
+
+--- declare a global variable, required to assign globals with strict globals
+---@param name string
+---@param value any
+function declare_global(name, value) end
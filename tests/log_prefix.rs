@@ -0,0 +1,58 @@
+// The lines logged by an actor start with its log prefix, which can include the build info
+// of the host application. The logger is global, so this test has its own binary.
+use actix::prelude::*;
+use actix_lua::{lua_msg, LuaActorBuilder};
+use futures::Future;
+use futures_timer::Delay;
+use std::sync::Mutex;
+use std::time::Duration;
+
+struct Capture(Mutex<Vec<String>>);
+
+impl log::Log for Capture {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.0.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGS: Capture = Capture(Mutex::new(Vec::new()));
+
+#[test]
+fn log_prefix_with_build_info() {
+    log::set_logger(&LOGS).unwrap();
+    log::set_max_level(log::LevelFilter::Warn);
+    let system = System::new("test");
+
+    LuaActorBuilder::new()
+        .with_name("worker")
+        .with_build_info(lua_msg!({ version: "1.2.3" }))
+        .with_log_prefix("[{actor}@{build.version}]")
+        .on_started_with_lua(r#"error("boom")"#)
+        .build()
+        .unwrap()
+        .start();
+
+    let l = Delay::new(Duration::from_millis(100)).then(|_| {
+        System::current().stop();
+        Ok::<(), ()>(())
+    });
+    Arbiter::spawn(l);
+    system.run();
+
+    let logs = LOGS.0.lock().unwrap();
+    let line = logs
+        .iter()
+        .find(|line| line.contains("boom"))
+        .unwrap_or_else(|| panic!("no error logged: {:?}", *logs));
+    assert!(
+        line.starts_with("[worker@1.2.3] started failed, stopping:"),
+        "{}",
+        line
+    );
+}
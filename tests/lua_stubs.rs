@@ -0,0 +1,86 @@
+extern crate actix_lua;
+
+use actix_lua::docs::emit_lua_stubs;
+use actix_lua::testing::ScriptVm;
+use actix_lua::{LuaActorBuilder, LuaMessage};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+fn stubs(builder: &LuaActorBuilder) -> String {
+    let mut out = Vec::new();
+    emit_lua_stubs(builder, &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+fn all_features() -> LuaActorBuilder {
+    LuaActorBuilder::new()
+        .with_rich_send_results(true)
+        .with_circuit_breaker(3, Duration::from_secs(10), Duration::from_secs(30))
+}
+
+// Compare with `tests/golden/<name>`, rewritten instead with `UPDATE_GOLDEN=1`.
+fn assert_golden(name: &str, actual: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap();
+    assert!(
+        actual == expected,
+        "{} is out of date, run the tests with UPDATE_GOLDEN=1 and review the diff:\n{}",
+        name,
+        actual
+    );
+}
+
+#[test]
+fn lua_stubs_golden() {
+    assert_golden("ctx_default.lua", &stubs(&LuaActorBuilder::new()));
+    assert_golden("ctx_all_features.lua", &stubs(&all_features()));
+}
+
+#[test]
+fn lua_stubs_cover_every_ctx_function() {
+    let builder = all_features().on_handle_with_lua(
+        r#"
+        local names = {}
+        for name, value in pairs(ctx) do
+            if type(value) == "function" then
+                table.insert(names, "ctx." .. name)
+            end
+        end
+        for name, value in pairs(ctx.math) do
+            table.insert(names, "ctx.math." .. name)
+        end
+        for name, value in pairs(ctx.str) do
+            table.insert(names, "ctx.str." .. name)
+        end
+        return names
+        "#,
+    );
+    let stubs = stubs(&builder);
+    let names = match ScriptVm::new(&builder)
+        .unwrap()
+        .handle(LuaMessage::Nil)
+        .unwrap()
+    {
+        LuaMessage::Array(names) => names,
+        names => panic!("expected an array, got {:?}", names),
+    };
+    assert!(names.len() > 20);
+    for name in &names {
+        let name = match name {
+            LuaMessage::String(name) => name,
+            name => panic!("expected a string, got {:?}", name),
+        };
+        assert!(
+            stubs.contains(&format!("function {}(", name)),
+            "{} is missing from the stubs",
+            name
+        );
+    }
+}
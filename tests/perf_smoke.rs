@@ -0,0 +1,28 @@
+// Catch catastrophic slowdowns of conversions without running the benchmarks, see
+// benches/conversion.rs. The thresholds are generous to pass in debug builds on slow machines.
+use actix_lua::testing::ScriptVm;
+use actix_lua::{LuaActorBuilder, LuaMessage};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[test]
+fn conversion_smoke() {
+    let table: HashMap<String, LuaMessage> = (0..1000)
+        .map(|i| (format!("key{}", i), LuaMessage::from(i)))
+        .collect();
+    let msg = LuaMessage::from(table);
+    let builder = LuaActorBuilder::new().on_handle_with_lua(r#"return ctx.msg"#);
+    let mut vm = ScriptVm::new(&builder).unwrap();
+
+    let start = Instant::now();
+    for _ in 0..100 {
+        assert_eq!(vm.round_trip(&msg).unwrap(), msg);
+        assert_eq!(vm.handle(msg.clone()).unwrap(), msg);
+    }
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed < Duration::from_secs(10),
+        "200 conversions of a 1k keys table took {:?}",
+        elapsed
+    );
+}
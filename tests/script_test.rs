@@ -0,0 +1,146 @@
+extern crate actix_lua;
+
+use actix_lua::testing::run_script_test;
+use actix_lua::LuaActorBuilder;
+
+fn counter() -> LuaActorBuilder {
+    LuaActorBuilder::new()
+        .on_started_with_lua(r#"ctx.state.count = 0"#)
+        .on_handle_with_lua(
+            r#"
+            if ctx.msg == "incr" then
+                ctx.state.count = ctx.state.count + 1
+            elseif ctx.msg == "incr_later" then
+                ctx.notify_later("incr", 5)
+            elseif ctx.msg == "incr_twice" then
+                ctx.notify("incr")
+                ctx.notify("incr")
+            end
+            return ctx.state.count
+            "#,
+        )
+}
+
+const TESTS: &str = r#"
+function test_increments()
+    ctx.assert_eq(ctx.send_self("incr"), 1)
+    ctx.assert_eq(ctx.send_self("incr"), 2)
+end
+
+function test_notifications()
+    ctx.send_self("incr_twice")
+    ctx.assert_eq(ctx.send_self("get"), 2, "notifications are handled")
+end
+
+function test_timers()
+    ctx.send_self("incr_later")
+    ctx.advance_time(4)
+    ctx.assert_eq(ctx.send_self("get"), 0, "not due yet")
+    ctx.advance_time(1)
+    ctx.assert_eq(ctx.send_self("get"), 1, "due")
+end
+"#;
+
+#[test]
+fn counter_script() {
+    if let Err(failures) = run_script_test(&counter(), TESTS) {
+        panic!("{}", failures);
+    }
+}
+
+#[test]
+fn counter_script_failure() {
+    let tests = format!(
+        "{}{}",
+        TESTS,
+        r#"
+        function test_starts_at_one()
+            ctx.assert_eq(ctx.send_self("get"), 1, "initial count")
+        end
+        "#
+    );
+    let failures = run_script_test(&counter(), &tests).unwrap_err();
+    assert_eq!(failures.0.len(), 1);
+    assert_eq!(failures.0[0].name, "test_starts_at_one");
+    assert!(
+        failures.0[0]
+            .message
+            .contains("initial count: expected 1, got 0"),
+        "{}",
+        failures.0[0].message
+    );
+}
+
+// The virtual clock starts on Saturday 2000-01-01 at midnight UTC.
+fn scheduled() -> LuaActorBuilder {
+    LuaActorBuilder::new()
+        .on_started_with_lua(
+            r#"
+            ctx.state.ticks = 0
+            ctx.state.months = 0
+            ctx.schedule("0 9 * * 1-5", "tick", {name = "weekdays"})
+            ctx.state.monthly = ctx.schedule("0 0 1 * *", "month")
+            "#,
+        )
+        .on_handle_with_lua(
+            r#"
+            if ctx.msg == "tick" then
+                ctx.state.ticks = ctx.state.ticks + 1
+            elseif ctx.msg == "month" then
+                ctx.state.months = ctx.state.months + 1
+            elseif ctx.msg == "unschedule" then
+                return ctx.unschedule("weekdays")
+            elseif ctx.msg == "invalid" then
+                local ok, err = pcall(ctx.schedule, "0 24 * * *", "tick")
+                return tostring(err)
+            end
+            return ctx.state
+            "#,
+        )
+}
+
+#[test]
+fn schedule_script() {
+    let tests = r#"
+    local DAY = 24 * 3600
+
+    function test_fires_on_weekdays()
+        -- Monday at 09:00
+        ctx.advance_time(2 * DAY + 9 * 3600 - 1)
+        ctx.assert_eq(ctx.send_self("get").ticks, 0, "not due yet")
+        ctx.advance_time(1)
+        ctx.assert_eq(ctx.send_self("get").ticks, 1, "Monday")
+        ctx.advance_time(7 * DAY)
+        ctx.assert_eq(ctx.send_self("get").ticks, 6, "Tuesday to Monday")
+    end
+
+    function test_fires_across_months()
+        ctx.assert_eq(ctx.send_self("get").monthly, "schedule-1")
+        ctx.advance_time(31 * DAY - 1)
+        ctx.assert_eq(ctx.send_self("get").months, 0, "January")
+        ctx.advance_time(1)
+        ctx.assert_eq(ctx.send_self("get").months, 1, "February 1st")
+        -- 2000 is a leap year
+        ctx.advance_time(29 * DAY)
+        ctx.assert_eq(ctx.send_self("get").months, 2, "March 1st")
+    end
+
+    function test_unschedule()
+        ctx.assert_eq(ctx.send_self("unschedule"), true)
+        ctx.assert_eq(ctx.send_self("unschedule"), false)
+        ctx.advance_time(7 * DAY)
+        ctx.assert_eq(ctx.send_self("get").ticks, 0)
+        ctx.assert_eq(ctx.send_self("get").months, 0)
+    end
+
+    function test_invalid_expression()
+        ctx.assert_eq(
+            ctx.send_self("invalid"),
+            'runtime error: invalid cron expression "0 24 * * *": hour field "24": 24 out of range 0-23'
+        )
+    end
+    "#;
+    if let Err(failures) = run_script_test(&scheduled(), tests) {
+        panic!("{}", failures);
+    }
+}
@@ -0,0 +1,206 @@
+// Stress the ordering contract of `ctx.send`: a coroutine resumes as soon as its reply
+// arrives, before the messages received after the reply, and exactly once. The requests,
+// recipients and delays are random with a fixed seed so that failures can be replayed.
+use actix::dev::{MessageResponse, ResponseChannel};
+use actix::prelude::*;
+use actix_lua::{LuaActorBuilder, LuaMessage};
+use futures::{Future, Stream};
+use futures_timer::Delay;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const SEED: u64 = 0x5eed_0492;
+const ORIGINS: usize = 8;
+const ECHOES: usize = 4;
+const REQUESTS: usize = 2000;
+
+// Linear congruential generator, good enough to shuffle the interleavings.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (self.0 >> 33) % n
+    }
+}
+
+type Origins = Arc<Mutex<Vec<Recipient<LuaMessage>>>>;
+
+// Replies with the request id after its delay, then sends a probe for the same id to the
+// origin, which must not see the request pending anymore.
+struct Echo {
+    origins: Origins,
+}
+
+impl Actor for Echo {
+    type Context = Context<Self>;
+}
+
+struct Delayed {
+    id: LuaMessage,
+    origin: Recipient<LuaMessage>,
+    delay: Duration,
+}
+
+impl MessageResponse<Echo, LuaMessage> for Delayed {
+    fn handle<R: ResponseChannel<LuaMessage>>(self, ctx: &mut Context<Echo>, tx: Option<R>) {
+        ctx.run_later(self.delay, move |_, _| {
+            let mut probe = HashMap::new();
+            probe.insert("probe".to_string(), self.id.clone());
+            if let Some(tx) = tx {
+                tx.send(self.id);
+            }
+            self.origin.do_send(LuaMessage::from(probe)).unwrap();
+        });
+    }
+}
+
+impl Handler<LuaMessage> for Echo {
+    type Result = Delayed;
+
+    fn handle(&mut self, msg: LuaMessage, _: &mut Context<Self>) -> Self::Result {
+        let int = |key| match msg.get(key) {
+            Some(LuaMessage::Integer(n)) => *n,
+            res => panic!("unexpected {} {:?}", key, res),
+        };
+        Delayed {
+            id: LuaMessage::from(int("id")),
+            origin: self.origins.lock().unwrap()[int("origin") as usize].clone(),
+            delay: Duration::from_millis(int("delay") as u64),
+        }
+    }
+}
+
+#[test]
+fn send_results_resume_before_later_messages() {
+    let system = System::new("test");
+
+    let origins: Origins = Arc::default();
+    let echoes: Vec<_> = (0..ECHOES)
+        .map(|_| {
+            Echo {
+                origins: origins.clone(),
+            }
+            .start()
+            .recipient()
+        })
+        .collect();
+    let addrs: Vec<_> = (0..ORIGINS)
+        .map(|_| {
+            let builder = echoes
+                .iter()
+                .enumerate()
+                .fold(LuaActorBuilder::new(), |builder, (i, echo)| {
+                    builder.with_recipient(&format!("echo{}", i), echo.clone())
+                });
+            builder
+                .on_started_with_lua(
+                    r#"
+                    ctx.state = { pending = {}, resumed = {}, done = 0, violations = {} }
+                    "#,
+                )
+                .on_handle_with_lua(
+                    r#"
+                    local state = ctx.state
+                    local function violation(what)
+                        state.violations[#state.violations + 1] = what
+                    end
+                    if ctx.msg == "get" then
+                        return state
+                    end
+                    if ctx.msg.probe ~= nil then
+                        -- sent after the reply of the request
+                        if state.pending["r" .. ctx.msg.probe] then
+                            violation("probe before the reply of " .. ctx.msg.probe)
+                        end
+                        return
+                    end
+                    local key = "r" .. ctx.msg.id
+                    state.pending[key] = true
+                    local reply = ctx.send(ctx.msg.echo, ctx.msg.request)
+                    if reply ~= ctx.msg.id then
+                        violation("reply " .. tostring(reply) .. " to " .. key)
+                    end
+                    if not state.pending[key] then
+                        violation(key .. " not pending on reply")
+                    end
+                    state.pending[key] = nil
+                    state.resumed[key] = (state.resumed[key] or 0) + 1
+                    if state.resumed[key] > 1 then
+                        violation(key .. " resumed twice")
+                    end
+                    state.done = state.done + 1
+                    "#,
+                )
+                .build()
+                .unwrap()
+                .start()
+        })
+        .collect();
+    *origins.lock().unwrap() = addrs.iter().map(|a| a.clone().recipient()).collect();
+
+    let mut rng = Rng(SEED);
+    let mut expected = vec![0; ORIGINS];
+    let requests: Vec<_> = (0..REQUESTS)
+        .map(|id| {
+            let origin = rng.below(ORIGINS as u64) as usize;
+            expected[origin] += 1;
+            let mut request = HashMap::new();
+            request.insert("id".to_string(), LuaMessage::from(id as i64));
+            request.insert("origin".to_string(), LuaMessage::from(origin as i64));
+            request.insert("delay".to_string(), LuaMessage::from(rng.below(5) as i64));
+            let mut msg = HashMap::new();
+            msg.insert("id".to_string(), LuaMessage::from(id as i64));
+            msg.insert(
+                "echo".to_string(),
+                LuaMessage::from(format!("echo{}", rng.below(ECHOES as u64))),
+            );
+            msg.insert("request".to_string(), LuaMessage::from(request));
+            (origin, LuaMessage::from(msg))
+        })
+        .collect();
+
+    // in bursts, actix asserts that a mailbox doesn't handle more than 256 messages at once
+    let senders = addrs.clone();
+    let sent = futures::stream::iter_ok::<_, ()>(requests)
+        .chunks(REQUESTS / 20)
+        .for_each(move |burst| {
+            for (origin, msg) in burst {
+                senders[origin].do_send(msg);
+            }
+            Delay::new(Duration::from_millis(5)).map_err(|e| panic!("{}", e))
+        });
+
+    let l = sent
+        .and_then(|_| Delay::new(Duration::from_millis(500)).map_err(|e| panic!("{}", e)))
+        .and_then(move |_| {
+            futures::future::join_all(
+                addrs
+                    .iter()
+                    .map(|a| a.send(LuaMessage::from("get")))
+                    .collect::<Vec<_>>(),
+            )
+            .map_err(|e| panic!("actor dead {}", e))
+        })
+        .map(move |states| {
+            for (state, expected) in states.iter().zip(expected) {
+                assert_eq!(
+                    state.get("violations"),
+                    Some(&LuaMessage::Table(HashMap::new()))
+                );
+                assert_eq!(state.get("done"), Some(&LuaMessage::from(expected as i64)));
+                assert_eq!(
+                    state.get("pending"),
+                    Some(&LuaMessage::Table(HashMap::new()))
+                );
+            }
+            System::current().stop();
+        });
+    Arbiter::spawn(l);
+
+    system.run();
+}